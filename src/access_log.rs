@@ -0,0 +1,355 @@
+//! Opt-in access log: one line per HTTP request in Combined Log Format or
+//! JSON, written by a dedicated thread so slow disks never stall requests.
+
+use crate::auth::Identity;
+use crate::config::{AccessLogConfig, AccessLogFormat, ClientIpConfig, Priority};
+use crate::dispatch::ForcedTarget;
+use crate::rpc::{RequestMethod, ServedBy};
+use crate::shedding::RequestPriority;
+use axum::{
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Lines queued for the writer; beyond this lines are dropped.
+const QUEUE_LEN: usize = 10_000;
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sample_rate: f64,
+    client_ip: ClientIpConfig,
+    request_id_header: HeaderName,
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    /// Open the destination and start the writer thread.
+    pub fn open(
+        settings: &AccessLogConfig,
+        client_ip: ClientIpConfig,
+        request_id_header: HeaderName,
+    ) -> io::Result<Self> {
+        let sink = Sink::open(settings)?;
+        let (lines, receiver) = sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || sink.run(receiver, "Access log"))?;
+        Ok(Self {
+            format: settings.format,
+            sample_rate: settings.sample_rate,
+            client_ip,
+            request_id_header,
+            lines,
+        })
+    }
+
+    fn format(&self, entry: &Entry) -> String {
+        match self.format {
+            AccessLogFormat::Combined => format!(
+                "{} - {} [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {} {} {} {} {} {}",
+                entry.client,
+                entry.key.as_deref().unwrap_or("-"),
+                crate::clock::clf(entry.unix_ms / 1000),
+                entry.http_method,
+                entry.path,
+                entry.version,
+                entry.status,
+                entry.bytes.map_or("-".to_string(), |b| b.to_string()),
+                escape(entry.referer.as_deref().unwrap_or("-")),
+                escape(entry.user_agent.as_deref().unwrap_or("-")),
+                entry.rpc_method.as_deref().unwrap_or("-"),
+                entry.duration_ms,
+                entry.upstream.as_deref().unwrap_or("-"),
+                entry.request_id.as_deref().unwrap_or("-"),
+                entry.target.as_deref().unwrap_or("-"),
+                entry.priority.map_or("-", Priority::as_str),
+            ),
+            AccessLogFormat::Json => json!({
+                "ts": crate::clock::rfc3339(entry.unix_ms),
+                "client": entry.client,
+                "key": entry.key,
+                "http_method": entry.http_method,
+                "path": entry.path,
+                "rpc_method": entry.rpc_method,
+                "status": entry.status,
+                "bytes": entry.bytes,
+                "duration_ms": entry.duration_ms,
+                "upstream": entry.upstream,
+                "request_id": entry.request_id,
+                "target": entry.target,
+                "priority": entry.priority.map(Priority::as_str),
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string(),
+        }
+    }
+}
+
+struct Entry {
+    unix_ms: u64,
+    client: String,
+    /// Name of the API key used, never the key itself.
+    key: Option<String>,
+    http_method: String,
+    path: String,
+    version: axum::http::Version,
+    status: u16,
+    bytes: Option<u64>,
+    duration_ms: f64,
+    rpc_method: Option<String>,
+    upstream: Option<String>,
+    request_id: Option<String>,
+    /// Upstream an admin forced the request to.
+    target: Option<String>,
+    /// Class of a proxied request.
+    priority: Option<Priority>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+fn header_string(headers: &HeaderMap, name: impl header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Keep quoted CLF fields parseable.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Middleware logging every request, whether proxied or answered locally.
+pub async fn middleware(
+    State(log): State<Arc<AccessLog>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !crate::random::chance(log.sample_rate) {
+        return next.run(request).await;
+    }
+    let started = Instant::now();
+    let unix_ms = crate::clock::unix_ms();
+    let client = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(peer)) => {
+            crate::client::client_ip(*peer, request.headers(), &log.client_ip).to_string()
+        }
+        None => "-".to_string(),
+    };
+    let http_method = request.method().to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/".to_string(), |p| p.to_string());
+    let version = request.version();
+    let referer = header_string(request.headers(), header::REFERER);
+    let user_agent = header_string(request.headers(), header::USER_AGENT);
+    // set by the request id layer around this one
+    let request_id = header_string(request.headers(), &log.request_id_header);
+
+    let response = next.run(request).await;
+
+    let entry = Entry {
+        unix_ms,
+        client,
+        key: response.extensions().get::<Identity>().map(|k| k.0.clone()),
+        http_method,
+        path,
+        version,
+        status: response.status().as_u16(),
+        bytes: response.body().size_hint().exact(),
+        duration_ms: (started.elapsed().as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0,
+        rpc_method: response
+            .extensions()
+            .get::<RequestMethod>()
+            .map(|m| m.0.clone()),
+        upstream: response.extensions().get::<ServedBy>().map(|s| s.0.clone()),
+        request_id,
+        target: response
+            .extensions()
+            .get::<ForcedTarget>()
+            .map(|t| t.0.clone()),
+        priority: response.extensions().get::<RequestPriority>().map(|p| p.0),
+        referer,
+        user_agent,
+    };
+    // a full queue means the writer cannot keep up; drop rather than block
+    let _ = log.lines.try_send(log.format(&entry));
+    response
+}
+
+/// Destination of the log lines, rotated by size when it is a file.
+pub enum Sink {
+    Stdout,
+    File {
+        path: String,
+        file: File,
+        size: u64,
+        max_size: u64,
+        max_files: usize,
+    },
+}
+
+impl Sink {
+    fn open(settings: &AccessLogConfig) -> io::Result<Self> {
+        if settings.path == "stdout" {
+            return Ok(Sink::Stdout);
+        }
+        Self::file(&settings.path, settings.max_size, settings.max_files)
+    }
+
+    /// Append to `path`, rotated past `max_size` bytes into `max_files`
+    /// numbered files.
+    pub fn file(path: &str, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Sink::File {
+            path: path.to_string(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Write every line received, reporting failures as those of `what`.
+    pub fn run(mut self, lines: Receiver<String>, what: &str) {
+        for line in lines {
+            if let Err(err) = self.write(&line) {
+                eprintln!("+ {} write failed: {}", what, err);
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Sink::File {
+                path,
+                file,
+                size,
+                max_size,
+                max_files,
+            } => {
+                if *max_size > 0 && *size > 0 && *size + line.len() as u64 + 1 > *max_size {
+                    // path.1 is the newest rotated file, path.<max_files> the oldest
+                    for n in (1..*max_files).rev() {
+                        let _ = std::fs::rename(
+                            format!("{}.{}", path, n),
+                            format!("{}.{}", path, n + 1),
+                        );
+                    }
+                    if *max_files > 0 {
+                        std::fs::rename(&*path, format!("{}.1", path))?;
+                    }
+                    *file = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .truncate(true)
+                        .open(&*path)?;
+                    *size = 0;
+                }
+                writeln!(file, "{}", line)?;
+                *size += line.len() as u64 + 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, upstream};
+    use serde_json::Value;
+
+    fn temp_path() -> String {
+        let name = format!("quarantier-access-{}.log", crate::random::u64());
+        std::env::temp_dir()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The lines of `path` once it has `count` of them, waiting a second
+    /// at most for the writer thread.
+    async fn lines(path: &str, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let text = std::fs::read_to_string(path).unwrap_or_default();
+            if text.lines().count() >= count {
+                return text.lines().map(str::to_string).collect();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} never had {} lines", path, count);
+    }
+
+    #[tokio::test]
+    async fn proxied_and_rejected_requests_are_logged() {
+        let path = temp_path();
+        let url = upstream(crate::mock::Behavior::default()).await;
+        let (_, url) = proxy(&format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n[access_log]\npath = \"{}\"\nformat = \"json\"\n[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n",
+            url, path
+        ))
+        .await;
+        let get_slot = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" });
+        let served = reqwest::Client::new()
+            .post(&url)
+            .header("x-api-key", "secret-app")
+            .header("x-request-id", "r1")
+            .json(&get_slot)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(served.status(), 200);
+        served.bytes().await.unwrap();
+        assert_eq!(call(&url, get_slot).await.status(), 401);
+        let lines = lines(&path, 2).await;
+        let entries: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries[0]["client"], "127.0.0.1");
+        assert_eq!(entries[0]["key"], "app");
+        assert_eq!(entries[0]["status"], 200);
+        assert_eq!(entries[0]["rpc_method"], "getSlot");
+        assert_eq!(entries[0]["upstream"], "a");
+        assert_eq!(entries[0]["request_id"], "r1");
+        // never the key itself
+        assert!(!lines[0].contains("secret-app"));
+        assert_eq!(entries[1]["key"], Value::Null);
+        assert_eq!(entries[1]["status"], 401);
+        assert_eq!(entries[1]["request_id"].as_str().unwrap().len(), 32);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn files_are_rotated_past_their_size() {
+        let path = temp_path();
+        let mut sink = Sink::file(&path, 20, 2).unwrap();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            sink.write(line).unwrap();
+        }
+        let read = |path: &str| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "fourth line\n");
+        assert_eq!(read(&format!("{}.1", path)), "third line\n");
+        assert_eq!(read(&format!("{}.2", path)), "second line\n");
+        // beyond max_files, the oldest is gone
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+        for rotated in ["", ".1", ".2"] {
+            std::fs::remove_file(format!("{}{}", path, rotated)).unwrap();
+        }
+    }
+}
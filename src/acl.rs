@@ -0,0 +1,183 @@
+//! Allow and deny lists of client networks, checked before anything else.
+
+use crate::config::AclConfig;
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+pub struct AccessControl {
+    /// Replaced on config reload.
+    lists: RwLock<Arc<AclConfig>>,
+    pub rejected: AtomicU64,
+}
+
+impl AccessControl {
+    pub fn new(lists: AclConfig) -> Self {
+        Self {
+            lists: RwLock::new(Arc::new(lists)),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn replace(&self, lists: AclConfig) {
+        *self.lists.write().unwrap() = Arc::new(lists);
+    }
+
+    pub fn lists(&self) -> Arc<AclConfig> {
+        self.lists.read().unwrap().clone()
+    }
+
+    /// Denied networks win; an empty allowlist allows everyone else.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let lists = self.lists.read().unwrap();
+        let contains = |nets: &[ipnet::IpNet]| nets.iter().any(|net| net.contains(&ip));
+        !contains(&lists.deny) && (lists.allow.is_empty() || contains(&lists.allow))
+    }
+}
+
+/// Reject requests from disallowed addresses with 403 before their body is
+/// read.
+pub async fn middleware(
+    State(config): State<Arc<ServerConfig>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = crate::client::client_ip(*peer, request.headers(), &config.settings.client_ip);
+        if !config.acl.allows(ip) {
+            config.acl.rejected.fetch_add(1, Ordering::Relaxed);
+            return crate::rpc::error_response(
+                StatusCode::FORBIDDEN,
+                crate::rpc::FORBIDDEN,
+                "client address not allowed",
+                None,
+            );
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::{routing::post, Router};
+
+    const UPSTREAM: &str = "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n";
+
+    fn lists(acl: &str) -> AccessControl {
+        let settings = Config::parse(&format!("{}[acl]\n{}", UPSTREAM, acl)).unwrap();
+        AccessControl::new(settings.acl)
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn empty_lists_allow_everyone() {
+        let acl = lists("");
+        assert!(acl.allows(ip("203.0.113.9")));
+        assert!(acl.allows(ip("::1")));
+    }
+
+    #[test]
+    fn denied_networks_win_over_allowed_ones() {
+        let acl = lists(
+            r#"allow = ["10.0.0.0/8", "2001:db8::/32", "192.0.2.7"]
+               deny = ["10.6.0.0/16"]"#,
+        );
+        assert!(acl.allows(ip("10.1.2.3")));
+        assert!(acl.allows(ip("2001:db8::42")));
+        // a bare address is a network of one
+        assert!(acl.allows(ip("192.0.2.7")));
+        assert!(!acl.allows(ip("192.0.2.8")));
+        assert!(!acl.allows(ip("10.6.0.1")));
+        assert!(!acl.allows(ip("172.16.0.1")));
+        let deny_only = lists(r#"deny = ["::1/128", "127.0.0.0/8"]"#);
+        assert!(!deny_only.allows(ip("127.0.0.1")));
+        assert!(!deny_only.allows(ip("::1")));
+        assert!(deny_only.allows(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn reloaded_lists_replace_the_old_ones() {
+        let acl = lists(r#"deny = ["198.51.100.0/24"]"#);
+        assert!(!acl.allows(ip("198.51.100.1")));
+        let text = format!("{}[acl]\nallow = [\"198.51.100.0/24\"]\n", UPSTREAM);
+        acl.replace(Config::parse(&text).unwrap().acl);
+        assert!(acl.allows(ip("198.51.100.1")));
+        assert!(!acl.allows(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn invalid_networks_are_config_errors() {
+        let text = format!("{}[acl]\nallow = [\"10.0.0.0/33\"]\n", UPSTREAM);
+        let err = Config::parse(&text).err().unwrap().to_string();
+        assert!(err.contains("acl.allow"), "{}", err);
+        assert!(err.contains("invalid CIDR '10.0.0.0/33'"), "{}", err);
+    }
+
+    async fn serve(text: &str) -> String {
+        let config = crate::testing::config(text);
+        let router = Router::new()
+            .route("/", post(|| async { "answered" }))
+            .layer(axum::middleware::from_fn_with_state(config, middleware));
+        crate::testing::serve(router).await
+    }
+
+    #[tokio::test]
+    async fn disallowed_clients_are_rejected() {
+        let url = serve(&format!("{}[acl]\ndeny = [\"127.0.0.1\"]\n", UPSTREAM)).await;
+        let response = reqwest::Client::new().post(&url).send().await.unwrap();
+        assert_eq!(response.status(), 403);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], crate::rpc::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn clients_behind_trusted_proxies_are_checked() {
+        let text = format!(
+            "trusted_proxies = [\"127.0.0.1\"]\n{}[acl]\nallow = [\"192.0.2.0/24\"]\n",
+            UPSTREAM
+        );
+        let url = serve(&text).await;
+        let client = reqwest::Client::new();
+        let from = |forwarded: &str| {
+            client
+                .post(&url)
+                .header("x-forwarded-for", forwarded)
+                .send()
+        };
+        assert_eq!(from("192.0.2.10").await.unwrap().status(), 200);
+        assert_eq!(from("203.0.113.5").await.unwrap().status(), 403);
+        // the client's own entries are not believed, only the proxy's
+        assert_eq!(from("192.0.2.10, 203.0.113.5").await.unwrap().status(), 403);
+        // the proxy's own address is not on the allowlist
+        let direct = client.post(&url).send().await.unwrap();
+        assert_eq!(direct.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn probes_and_scrapers_are_not_checked() {
+        let text = format!("{}[acl]\ndeny = [\"127.0.0.1\"]\n", UPSTREAM);
+        let (_, url) = crate::testing::proxy(&text).await;
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{}{}", url, path)).send();
+        assert_eq!(get("/healthz").await.unwrap().status(), 200);
+        assert_eq!(get("/metrics").await.unwrap().status(), 200);
+        assert_ne!(get("/readyz").await.unwrap().status(), 403);
+        assert_eq!(get("/status").await.unwrap().status(), 403);
+        assert_eq!(get("/admin/usage").await.unwrap().status(), 403);
+        let proxied = client.post(&url).body("{}").send().await.unwrap();
+        assert_eq!(proxied.status(), 403);
+    }
+}
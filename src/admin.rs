@@ -0,0 +1,41 @@
+use crate::ServerConfig;
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// `GET /admin/usage?top=N`: the heaviest clients by upstream cost, and the
+/// quota consumption of every key with a quota.
+pub async fn usage_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let top = params
+        .get("top")
+        .and_then(|top| top.parse().ok())
+        .unwrap_or(config.settings.usage.top);
+    let mut report = config.usage.report(top);
+    let keys = config.api_keys();
+    let quotas = keys
+        .keys()
+        .into_iter()
+        .filter_map(|key| Some((key.name.as_str(), key.quota.as_ref()?)));
+    report["quotas"] = Value::Array(config.quotas.report(quotas));
+    Json(report)
+}
+
+/// `DELETE /admin/usage`: start a new accounting period.
+pub async fn reset_usage_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    config.usage.reset();
+    println!("+ Usage counters reset");
+    Json(json!({ "reset": true }))
+}
+
+/// `GET /admin/divergence`: per-method mismatch rates between upstreams and
+/// recent examples of disagreeing answers.
+pub async fn divergence_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    Json(config.divergence.report())
+}
@@ -0,0 +1,31 @@
+//! Operator notifications: always logged, and posted as JSON to the
+//! configured webhook.
+
+use crate::ServerConfig;
+use serde_json::Value;
+use std::time::Duration;
+
+/// Log `message` and, when a webhook is configured, post `event` to it
+/// without waiting for the answer.
+pub fn notify(config: &ServerConfig, message: &str, mut event: Value) {
+    println!("+ ALERT {}", message);
+    config.events.record(format!("alert: {}", message));
+    let Some(url) = config.settings.alerts.webhook_url.clone() else {
+        return;
+    };
+    event["message"] = Value::String(message.to_string());
+    event["timestamp"] = crate::clock::unix_secs().into();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let result = client
+            .post(&url)
+            .timeout(Duration::from_secs(5))
+            .json(&event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            println!("+ Failed to deliver alert to {}: {}", url, err);
+        }
+    });
+}
@@ -0,0 +1,548 @@
+//! API key authentication of proxied requests. Keys are reloadable, and are
+//! only ever referred to by their names outside this module.
+
+use crate::config::{AuthConfig, Priority, Quota};
+use crate::methods::MethodPolicy;
+use crate::quota::Outcome;
+use crate::ratelimit::{Limit, TokenBucket};
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Request and response extension naming the authenticated client: the API
+/// key name, or the identity claim of a JWT.
+#[derive(Clone)]
+pub struct Identity(pub String);
+
+/// A configured key. The key of an authenticated request is attached to it
+/// as an extension, for checks that need the body.
+pub struct ApiKey {
+    pub name: String,
+    /// `None` for unlimited keys.
+    limiter: Option<Arc<TokenBucket>>,
+    /// Restrictions on top of the global `[methods]` policy.
+    pub methods: MethodPolicy,
+    pub throttled: Arc<AtomicU64>,
+    /// Calls rejected by a method restriction.
+    pub denied: Arc<AtomicU64>,
+    pub quota: Option<Quota>,
+    pub max_concurrent: Option<usize>,
+    pub admin: bool,
+    pub priority: Option<Priority>,
+}
+
+/// The key table in effect, swapped wholesale on reload.
+pub struct ApiKeys {
+    header: HeaderName,
+    /// Secret to key.
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl ApiKey {
+    /// `None` for unlimited keys.
+    pub fn limit(&self) -> Option<Limit> {
+        self.limiter.as_ref().map(|bucket| bucket.limit())
+    }
+}
+
+impl ApiKeys {
+    /// Build the table for `settings`. Keys that keep their name carry over
+    /// their throttle counters, and their buckets when the limit is unchanged.
+    pub fn new(settings: &AuthConfig, previous: Option<&ApiKeys>) -> Self {
+        let previous: HashMap<&str, &Arc<ApiKey>> = previous
+            .into_iter()
+            .flat_map(|keys| keys.keys.values())
+            .map(|key| (key.name.as_str(), key))
+            .collect();
+        let keys = settings
+            .keys
+            .iter()
+            .map(|key| {
+                let old = previous.get(key.name.as_str());
+                let limit = if key.unlimited {
+                    None
+                } else {
+                    key.rate_limit.or(settings.rate_limit)
+                };
+                let limiter = limit.map(|limit| match old.and_then(|old| old.limiter.as_ref()) {
+                    Some(bucket) if bucket.limit() == limit => bucket.clone(),
+                    _ => Arc::new(TokenBucket::new(limit)),
+                });
+                let api_key = ApiKey {
+                    name: key.name.clone(),
+                    limiter,
+                    methods: key.methods.clone(),
+                    throttled: old.map_or_else(Default::default, |old| old.throttled.clone()),
+                    denied: old.map_or_else(Default::default, |old| old.denied.clone()),
+                    quota: key.quota,
+                    max_concurrent: key.max_concurrent,
+                    admin: key.admin,
+                    priority: key.priority,
+                };
+                (key.key.clone(), Arc::new(api_key))
+            })
+            .collect();
+        Self {
+            header: settings.header.clone(),
+            keys,
+        }
+    }
+
+    /// Keys sorted by name.
+    pub fn keys(&self) -> Vec<&ApiKey> {
+        let mut keys: Vec<&ApiKey> = self.keys.values().map(|key| &**key).collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    /// Keys sorted by name, with their secrets, for the effective config.
+    pub fn secrets(&self) -> Vec<(&str, &ApiKey)> {
+        let mut keys: Vec<(&str, &ApiKey)> = self
+            .keys
+            .iter()
+            .map(|(secret, key)| (secret.as_str(), &**key))
+            .collect();
+        keys.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        keys
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// Without configured keys the proxy is open.
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Name of the key presented in `headers`, if it is a known one.
+    /// `Authorization` headers carry the key as a bearer token.
+    fn authenticate(&self, headers: &HeaderMap) -> Option<&Arc<ApiKey>> {
+        let secret = if self.header == AUTHORIZATION {
+            bearer(headers)?
+        } else {
+            headers.get(&self.header)?.to_str().ok()?.trim()
+        };
+        self.keys.get(secret)
+    }
+}
+
+/// The bearer token of an `Authorization` header.
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let token = value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))?;
+    Some(token.trim())
+}
+
+fn unauthorized(config: &ServerConfig, request: &Request<Body>, reason: &str) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>();
+    let client = peer.map(|ConnectInfo(peer)| {
+        crate::client::client_ip(*peer, request.headers(), &config.settings.client_ip)
+    });
+    println!(
+        "+ Rejected request from {} without valid credentials ({})",
+        client.map_or("unknown".to_string(), |ip| ip.to_string()),
+        reason
+    );
+    crate::rpc::error_response(
+        StatusCode::UNAUTHORIZED,
+        crate::rpc::UNAUTHORIZED,
+        "missing or invalid credentials",
+        Some(json!({ "reason": reason })),
+    )
+}
+
+/// Reject requests to the admin endpoints without an admin API key: 401
+/// without a known key, 403 with a key that is not an admin's. Rate limits
+/// and quotas do not apply to them. Handlers find the admin's key name in
+/// the `Identity` extension.
+///
+/// Without any keys configured nobody could hold an admin key, so the
+/// endpoints only answer clients on the proxy's own host, as "localhost".
+pub async fn admin_middleware(
+    State(config): State<Arc<ServerConfig>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let keys = config.api_keys();
+    let name = if !keys.enabled() {
+        let peer = request.extensions().get::<ConnectInfo<SocketAddr>>();
+        let client = peer.map(|ConnectInfo(peer)| {
+            crate::client::client_ip(*peer, request.headers(), &config.settings.client_ip)
+        });
+        if !client.is_some_and(|ip| ip.is_loopback()) {
+            return crate::rpc::error_response(
+                StatusCode::FORBIDDEN,
+                crate::rpc::FORBIDDEN,
+                "admin endpoints only answer localhost without API keys",
+                None,
+            );
+        }
+        "localhost".to_string()
+    } else {
+        match keys.authenticate(request.headers()) {
+            Some(key) if key.admin => key.name.clone(),
+            Some(key) => {
+                // the path without the `/admin` prefix the nest strips
+                let path = match request.extensions().get::<OriginalUri>() {
+                    Some(OriginalUri(uri)) => uri.path(),
+                    None => request.uri().path(),
+                };
+                println!(
+                    "+ Rejected {} from key {}: not an admin key",
+                    path, key.name
+                );
+                return crate::rpc::error_response(
+                    StatusCode::FORBIDDEN,
+                    crate::rpc::FORBIDDEN,
+                    "admin endpoints require an admin API key",
+                    None,
+                );
+            }
+            None => {
+                let reason = match request.headers().contains_key(&keys.header) {
+                    true => "unknown_key",
+                    false => "missing_credentials",
+                };
+                return unauthorized(&config, &request, reason);
+            }
+        }
+    };
+    let identity = Identity(name);
+    request.extensions_mut().insert(identity.clone());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(identity);
+    response
+}
+
+/// Reject requests without a known API key or a valid JWT, or over their
+/// identity's rate limit or quota, before their body is read.
+pub async fn middleware(
+    State(config): State<Arc<ServerConfig>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let keys = config.api_keys();
+    if !keys.enabled() && config.jwt.is_none() {
+        return next.run(request).await;
+    }
+    // tokens shaped like a JWT go to the validator, anything else must be
+    // an API key
+    let token = bearer(request.headers()).filter(|token| token.matches('.').count() == 2);
+    let mut api_key = None;
+    let (identity, limited) = match (&config.jwt, token) {
+        (Some(jwt), Some(token)) => match jwt.validate(token) {
+            Ok(identity) => {
+                let limited = jwt.limiter.as_ref().map(|l| l.try_acquire(&identity));
+                (identity, limited)
+            }
+            Err(reason) => return unauthorized(&config, &request, reason),
+        },
+        _ => match keys.authenticate(request.headers()) {
+            Some(key) => {
+                let limited = key.limiter.as_ref().map(|bucket| bucket.try_acquire());
+                if let Some(Err(_)) = limited {
+                    key.throttled.fetch_add(1, Ordering::Relaxed);
+                }
+                api_key = Some(key.clone());
+                (key.name.clone(), limited)
+            }
+            None => {
+                let has_credentials = request.headers().contains_key(&keys.header);
+                let reason = if has_credentials {
+                    "unknown_key"
+                } else {
+                    "missing_credentials"
+                };
+                return unauthorized(&config, &request, reason);
+            }
+        },
+    };
+    let identity = Identity(identity);
+    if let Some(Err(wait)) = limited {
+        config.usage.record_throttled(&identity.0);
+        let mut response = crate::rpc::rate_limited(wait);
+        response.extensions_mut().insert(identity);
+        return response;
+    }
+    if let Some((key, quota)) = api_key.as_ref().and_then(|k| Some((k, k.quota.as_ref()?))) {
+        match config.quotas.consume(&key.name, quota) {
+            Outcome::Allowed => {}
+            Outcome::SoftLimit { used } => crate::alerts::notify(
+                &config,
+                &format!(
+                    "API key {} has used {} of its {} {} requests",
+                    key.name,
+                    used,
+                    quota.limit,
+                    quota.window.as_str()
+                ),
+                json!({
+                    "event": "quota_soft_limit",
+                    "key": key.name,
+                    "used": used,
+                    "limit": quota.limit,
+                    "window": quota.window.as_str(),
+                }),
+            ),
+            Outcome::Exhausted { resets_at } => {
+                let mut response = crate::rpc::quota_exceeded(resets_at);
+                response.extensions_mut().insert(identity);
+                return response;
+            }
+        }
+    }
+    request.extensions_mut().insert(identity.clone());
+    if let Some(key) = api_key {
+        request.extensions_mut().insert(key);
+    }
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(identity);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+
+    const KEYS: &str = r#"
+        [[upstreams]]
+        url = "http://127.0.0.1:1"
+
+        [auth]
+        header = "x-api-key"
+        rps = 1000
+
+        [[auth.keys]]
+        name = "team-a"
+        key = "secret-a"
+        rps = 1
+        burst = 1
+
+        [[auth.keys]]
+        name = "ops"
+        key = "secret-ops"
+        admin = true
+        unlimited = true
+    "#;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn keys(text: &str) -> ApiKeys {
+        let config = crate::testing::config(text);
+        ApiKeys::new(&config.settings.auth, None)
+    }
+
+    #[test]
+    fn keys_are_known_by_their_secret() {
+        let keys = keys(KEYS);
+        assert!(keys.enabled());
+        assert_eq!(keys.len(), 2);
+        let name = |pairs| keys.authenticate(&headers(pairs)).map(|k| k.name.clone());
+        assert_eq!(
+            name(&[("x-api-key", "secret-a")]),
+            Some("team-a".to_string())
+        );
+        assert_eq!(
+            name(&[("x-api-key", " secret-a ")]),
+            Some("team-a".to_string())
+        );
+        assert_eq!(name(&[("x-api-key", "secret-b")]), None);
+        assert_eq!(name(&[("authorization", "Bearer secret-a")]), None);
+        assert_eq!(name(&[]), None);
+    }
+
+    #[test]
+    fn only_admin_keys_open_the_admin_endpoints() {
+        let admin =
+            |keys: &ApiKeys, pairs| keys.authenticate(&headers(pairs)).is_some_and(|k| k.admin);
+        let keys = keys(KEYS);
+        assert!(admin(&keys, &[("x-api-key", "secret-ops")]));
+        assert!(!admin(&keys, &[("x-api-key", "secret-a")]));
+        assert!(!admin(&keys, &[]));
+        let open = self::keys("[[upstreams]]\nurl = \"http://127.0.0.1:1\"");
+        assert!(!admin(&open, &[("x-api-key", "secret-ops")]));
+    }
+
+    #[test]
+    fn authorization_headers_carry_bearer_tokens() {
+        let keys = keys(&KEYS.replace("\"x-api-key\"", "\"authorization\""));
+        let name = |value| {
+            let headers = headers(&[("authorization", value)]);
+            keys.authenticate(&headers).map(|k| k.name.clone())
+        };
+        assert_eq!(name("Bearer secret-a"), Some("team-a".to_string()));
+        assert_eq!(name("bearer  secret-a"), Some("team-a".to_string()));
+        assert_eq!(name("Basic secret-a"), None);
+        assert_eq!(name("secret-a"), None);
+    }
+
+    #[test]
+    fn limits_fall_back_to_the_default_unless_unlimited() {
+        let keys = keys(KEYS);
+        let limit = |name: &str| {
+            let key = keys
+                .keys()
+                .into_iter()
+                .find(|key| key.name == name)
+                .unwrap();
+            key.limiter.as_ref().map(|bucket| bucket.limit())
+        };
+        assert!(limit("team-a").is_some());
+        assert_eq!(limit("ops"), None);
+        let defaulted = self::keys(&KEYS.replace("rps = 1\n", ""));
+        let key = defaulted
+            .keys()
+            .into_iter()
+            .find(|k| k.name == "team-a")
+            .unwrap();
+        assert_ne!(
+            key.limiter.as_ref().map(|bucket| bucket.limit()),
+            limit("team-a")
+        );
+    }
+
+    #[test]
+    fn reloaded_keys_keep_their_counters() {
+        let config = crate::testing::config(KEYS);
+        let before = ApiKeys::new(&config.settings.auth, None);
+        let headers = headers(&[("x-api-key", "secret-a")]);
+        before
+            .authenticate(&headers)
+            .unwrap()
+            .throttled
+            .fetch_add(3, Ordering::Relaxed);
+        let after = ApiKeys::new(&config.settings.auth, Some(&before));
+        let team = after.authenticate(&headers).unwrap();
+        assert_eq!(team.throttled.load(Ordering::Relaxed), 3);
+        // an unchanged limit keeps its bucket
+        let old = before.authenticate(&headers).unwrap();
+        assert!(Arc::ptr_eq(
+            old.limiter.as_ref().unwrap(),
+            team.limiter.as_ref().unwrap()
+        ));
+    }
+
+    async fn serve(text: &str) -> String {
+        let config = crate::testing::config(text);
+        let router = Router::new().route(
+            "/",
+            post(|| async { "answered" })
+                .layer(axum::middleware::from_fn_with_state(config, middleware)),
+        );
+        crate::testing::serve(router).await
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_known_key_are_rejected() {
+        let url = serve(KEYS).await;
+        let client = reqwest::Client::new();
+        let send = |key: Option<&str>| {
+            let mut request = client.post(&url);
+            if let Some(key) = key {
+                request = request.header("x-api-key", key);
+            }
+            request.send()
+        };
+        let missing = send(None).await.unwrap();
+        assert_eq!(missing.status(), 401);
+        let body: serde_json::Value = missing.json().await.unwrap();
+        assert_eq!(body["error"]["code"], crate::rpc::UNAUTHORIZED);
+        let unknown = send(Some("nope")).await.unwrap();
+        assert_eq!(unknown.status(), 401);
+        let known = send(Some("secret-ops")).await.unwrap();
+        assert_eq!(known.status(), 200);
+        assert_eq!(known.text().await.unwrap(), "answered");
+    }
+
+    #[tokio::test]
+    async fn keys_over_their_limit_are_throttled() {
+        let url = serve(KEYS).await;
+        let client = reqwest::Client::new();
+        let send = |key: &'static str| client.post(&url).header("x-api-key", key).send();
+        assert_eq!(send("secret-a").await.unwrap().status(), 200);
+        let throttled = send("secret-a").await.unwrap();
+        assert_eq!(throttled.status(), 429);
+        assert!(throttled.headers().contains_key("retry-after"));
+        // unlimited keys are never throttled
+        for _ in 0..5 {
+            assert_eq!(send("secret-ops").await.unwrap().status(), 200);
+        }
+    }
+
+    async fn serve_admin(text: &str) -> String {
+        let config = crate::testing::config(text);
+        let router = Router::new().route(
+            "/",
+            post(|axum::Extension(Identity(name))| async move { name }).layer(
+                axum::middleware::from_fn_with_state(config, admin_middleware),
+            ),
+        );
+        crate::testing::serve(router).await
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_require_an_admin_key() {
+        let url = serve_admin(KEYS).await;
+        let client = reqwest::Client::new();
+        let send = |key: &'static str| client.post(&url).header("x-api-key", key).send();
+        let admin = send("secret-ops").await.unwrap();
+        assert_eq!(admin.status(), 200);
+        assert_eq!(admin.text().await.unwrap(), "ops");
+        assert_eq!(send("secret-a").await.unwrap().status(), 403);
+        assert_eq!(send("nope").await.unwrap().status(), 401);
+        // localhost is no exception once keys are configured
+        let anonymous = client.post(&url).send().await.unwrap();
+        assert_eq!(anonymous.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn without_keys_admin_endpoints_only_answer_localhost() {
+        let text =
+            "trusted_proxies = [\"127.0.0.1\"]\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n";
+        let url = serve_admin(text).await;
+        let client = reqwest::Client::new();
+        let local = client.post(&url).send().await.unwrap();
+        assert_eq!(local.status(), 200);
+        assert_eq!(local.text().await.unwrap(), "localhost");
+        let forwarded = client
+            .post(&url)
+            .header("x-forwarded-for", "203.0.113.5")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(forwarded.status(), 403);
+        let body: serde_json::Value = forwarded.json().await.unwrap();
+        assert_eq!(body["error"]["code"], crate::rpc::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn an_open_proxy_lets_everyone_through() {
+        let url = serve("[[upstreams]]\nurl = \"http://127.0.0.1:1\"").await;
+        let response = reqwest::Client::new().post(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+}
@@ -0,0 +1,84 @@
+//! Base58 with the Bitcoin alphabet, which Solana uses for signatures and
+//! keys, and by default for transactions passed to `sendTransaction`.
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    // little-endian digits in base 256
+    let mut bytes: Vec<u8> = Vec::with_capacity(text.len() * 3 / 4);
+    for c in text.bytes() {
+        let mut carry = ALPHABET.iter().position(|&a| a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    // every leading '1' is a leading zero byte
+    let zeros = text.bytes().take_while(|&c| c == b'1').count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 138 / 100 + 1);
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&d| ALPHABET[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VECTORS: [(&[u8], &str); 6] = [
+        (b"", ""),
+        (&[0], "1"),
+        (&[0, 0, 0x28, 0x7f, 0xb4, 0xcd], "11233QC4"),
+        (b"Hello World!", "2NEpo7TZRRrLZSi2U"),
+        (&[0xff; 4], "7YXq9G"),
+        (&[0; 32], "11111111111111111111111111111111"),
+    ];
+
+    #[test]
+    fn known_values_round_trip() {
+        for (bytes, text) in VECTORS {
+            assert_eq!(encode(bytes), text);
+            assert_eq!(decode(text).as_deref(), Some(bytes), "{}", text);
+        }
+    }
+
+    #[test]
+    fn signatures_decode_to_64_bytes() {
+        let signature: Vec<u8> = (0..64).map(|i| i * 3 + 1).collect();
+        let text = encode(&signature);
+        assert!((86..=88).contains(&text.len()), "{}", text);
+        assert_eq!(decode(&text), Some(signature));
+    }
+
+    #[test]
+    fn characters_outside_the_alphabet_are_rejected() {
+        for text in ["0", "O", "I", "l", "abc+", "11 1", "é"] {
+            assert_eq!(decode(text), None, "{}", text);
+        }
+    }
+}
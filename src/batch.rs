@@ -0,0 +1,280 @@
+//! Batches partly answered by the proxy. Calls it answers from its own
+//! state when sent alone, `getSlot` and the cached epoch info and leader
+//! schedule, are answered the same way inside batches; only the other calls
+//! are forwarded, as a smaller batch, and the answers are put back together
+//! in the order of the calls. Notifications, calls without an id, get no
+//! answer wherever they are served.
+
+use crate::ServerConfig;
+use axum::body::Bytes;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+
+/// A batch split into the calls answered locally and those forwarded.
+pub struct Split {
+    /// The forwarded calls, as a batch.
+    pub forwarded: Vec<u8>,
+    /// Whether each call, in order, was answered locally.
+    local: Vec<bool>,
+    /// Ids of the forwarded calls, in order, `None` for notifications.
+    ids: Vec<Option<Value>>,
+    /// The local answers, in order.
+    answers: Vec<Value>,
+}
+
+impl Split {
+    pub fn counts(&self) -> (usize, usize) {
+        (self.answers.len(), self.ids.len())
+    }
+
+    /// The local answers alone, when no call is left to forward.
+    pub fn answered(&self) -> Option<Bytes> {
+        self.ids
+            .is_empty()
+            .then(|| Value::Array(self.answers.clone()).to_string().into())
+    }
+}
+
+/// Whether `call` is a notification, to which no answer is due.
+fn is_notification(call: &Value) -> bool {
+    call.as_object()
+        .is_some_and(|call| !call.contains_key("id"))
+}
+
+/// The answer to `call` from the proxy's own state, if it has one.
+fn local_answer(config: &ServerConfig, call: &Value) -> Option<Value> {
+    let method = call["method"].as_str()?;
+    let answer = match method {
+        "getSlot" => crate::local_slot::answer(config, call.to_string().as_bytes()),
+        "getEpochInfo" | "getLeaderSchedule" => {
+            crate::epoch::answer(config, method, call.to_string().as_bytes())
+        }
+        _ => return None,
+    };
+    serde_json::from_str(&answer?).ok()
+}
+
+/// Split `body` when it is a batch with calls the proxy can answer;
+/// `None` leaves it to be forwarded whole.
+pub fn split(config: &ServerConfig, body: &[u8]) -> Option<Split> {
+    let Ok(Value::Array(calls)) = serde_json::from_slice::<Value>(body) else {
+        return None;
+    };
+    let mut split = Split {
+        forwarded: Vec::new(),
+        local: Vec::with_capacity(calls.len()),
+        ids: Vec::new(),
+        answers: Vec::new(),
+    };
+    let mut forwarded = Vec::new();
+    for call in calls {
+        let notification = is_notification(&call);
+        match local_answer(config, &call) {
+            Some(_) if notification => {}
+            Some(answer) => {
+                split.local.push(true);
+                split.answers.push(answer);
+            }
+            None => {
+                if !notification {
+                    split.local.push(false);
+                }
+                split.ids.push((!notification).then(|| call["id"].clone()));
+                forwarded.push(call);
+            }
+        }
+    }
+    let stats = &config.stats;
+    let (local, remote) = split.counts();
+    stats.batch_local.fetch_add(local as u64, Ordering::Relaxed);
+    stats
+        .batch_forwarded
+        .fetch_add(remote as u64, Ordering::Relaxed);
+    if split.answers.is_empty() {
+        return None;
+    }
+    split.forwarded = Value::Array(forwarded).to_string().into_bytes();
+    Some(split)
+}
+
+/// The upstream's answer to the forwarded calls with the local answers put
+/// back in place. Answers are matched to calls by id, or by position when
+/// ids repeat or answers are missing; a body that is no batch answer, such
+/// as an error for the whole request, is returned as it is, unless only
+/// notifications were forwarded.
+pub fn merge(body: Bytes, split: Split) -> Bytes {
+    let ids: Vec<&Value> = split.ids.iter().flatten().collect();
+    let answers = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(answers)) => answers,
+        _ if ids.is_empty() => Vec::new(),
+        _ => return body,
+    };
+    let distinct = ids.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+    let mut by_id: HashMap<String, Value> = HashMap::new();
+    let mut in_order = Vec::new();
+    if distinct.len() == ids.len() && answers.len() == ids.len() {
+        for answer in answers {
+            by_id.insert(answer["id"].to_string(), answer);
+        }
+    } else {
+        in_order = answers;
+    }
+    let mut forwarded = ids.into_iter();
+    let mut local = split.answers.into_iter();
+    let mut in_order = in_order.into_iter();
+    let mut merged = Vec::with_capacity(split.local.len());
+    for is_local in split.local {
+        let answer = match is_local {
+            true => local.next(),
+            false => match forwarded.next() {
+                Some(id) if !by_id.is_empty() => by_id.remove(&id.to_string()),
+                _ => in_order.next(),
+            },
+        };
+        merged.extend(answer);
+    }
+    // whatever did not match a call is kept
+    merged.extend(by_id.into_values());
+    merged.extend(in_order);
+    Value::Array(merged).to_string().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+    use serde_json::json;
+
+    fn config() -> std::sync::Arc<ServerConfig> {
+        let config = crate::testing::config(
+            "[local_get_slot]\nenabled = true\n\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n",
+        );
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config
+    }
+
+    fn get_slot(id: Option<u64>) -> Value {
+        let mut call = json!({ "jsonrpc": "2.0", "method": "getSlot" });
+        if let Some(id) = id {
+            call["id"] = json!(id);
+        }
+        call
+    }
+
+    fn get_balance(id: Option<u64>) -> Value {
+        let mut call = json!({ "jsonrpc": "2.0", "method": "getBalance", "params": ["x"] });
+        if let Some(id) = id {
+            call["id"] = json!(id);
+        }
+        call
+    }
+
+    fn split_calls(config: &ServerConfig, calls: Value) -> Option<Split> {
+        split(config, calls.to_string().as_bytes())
+    }
+
+    fn parse(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    fn ids(answers: &Value) -> Vec<Value> {
+        answers
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|answer| answer["id"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn batches_without_local_calls_are_forwarded_whole() {
+        let config = config();
+        assert!(split_calls(&config, json!([])).is_none());
+        assert!(split_calls(&config, json!([get_balance(Some(1))])).is_none());
+        assert!(split_calls(&config, get_slot(Some(1))).is_none());
+        assert_eq!(config.stats.batch_forwarded.load(Ordering::Relaxed), 1);
+        assert_eq!(config.stats.batch_local.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn a_batch_of_local_calls_is_answered_without_an_upstream() {
+        let config = config();
+        let split = split_calls(&config, json!([get_slot(Some(7))])).unwrap();
+        let answers = parse(&split.answered().unwrap());
+        assert_eq!(
+            answers,
+            json!([{ "jsonrpc": "2.0", "id": 7, "result": 1000 }])
+        );
+        assert_eq!(config.stats.batch_local.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn forwarded_answers_go_back_in_place_by_id() {
+        let config = config();
+        let calls = json!([
+            get_balance(Some(1)),
+            get_slot(Some(2)),
+            get_balance(Some(3))
+        ]);
+        let split = split_calls(&config, calls).unwrap();
+        assert!(split.answered().is_none());
+        assert_eq!(
+            parse(&split.forwarded),
+            json!([get_balance(Some(1)), get_balance(Some(3))])
+        );
+        // out of order, one failed
+        let upstream = json!([
+            { "jsonrpc": "2.0", "id": 3, "error": { "code": -32602, "message": "bad" } },
+            { "jsonrpc": "2.0", "id": 1, "result": 5 },
+        ]);
+        let merged = parse(&merge(upstream.to_string().into(), split));
+        assert_eq!(ids(&merged), [1, 2, 3]);
+        assert_eq!(merged[2]["error"]["code"], -32602);
+        // an error for the whole batch is left as it is
+        let split = split_calls(&config, json!([get_balance(Some(1)), get_slot(Some(2))]));
+        let error = r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32005,"message":"busy"}}"#;
+        assert_eq!(&merge(error.into(), split.unwrap())[..], error.as_bytes());
+    }
+
+    #[test]
+    fn elements_that_are_no_calls_are_left_to_the_upstream() {
+        let config = config();
+        let split = split_calls(&config, json!([1, get_slot(Some(2))])).unwrap();
+        assert_eq!(parse(&split.forwarded), json!([1]));
+        let upstream = json!([
+            { "jsonrpc": "2.0", "id": null, "error": { "code": -32600, "message": "Invalid request" } },
+        ]);
+        let merged = parse(&merge(upstream.to_string().into(), split));
+        assert_eq!(ids(&merged), [json!(null), json!(2)]);
+    }
+
+    #[test]
+    fn notifications_are_served_but_not_answered() {
+        let config = config();
+        let calls = json!([
+            get_balance(None),
+            get_slot(Some(1)),
+            get_slot(None),
+            get_balance(Some(2)),
+        ]);
+        let split = split_calls(&config, calls).unwrap();
+        assert_eq!(
+            parse(&split.forwarded),
+            json!([get_balance(None), get_balance(Some(2))])
+        );
+        let upstream = json!([{ "jsonrpc": "2.0", "id": 2, "result": 5 }]);
+        let merged = parse(&merge(upstream.to_string().into(), split));
+        assert_eq!(ids(&merged), [1, 2]);
+        // an upstream answers a batch of notifications with nothing at all
+        let calls = json!([get_slot(Some(1)), get_balance(None)]);
+        let split = split_calls(&config, calls).unwrap();
+        assert!(split.answered().is_none());
+        let merged = parse(&merge(Bytes::new(), split));
+        assert_eq!(ids(&merged), [1]);
+        // nothing is due for local notifications
+        let calls = json!([get_slot(Some(1)), get_slot(None)]);
+        let split = split_calls(&config, calls).unwrap();
+        assert_eq!(ids(&parse(&split.answered().unwrap())), [1]);
+    }
+}
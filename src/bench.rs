@@ -0,0 +1,428 @@
+//! `bench` subcommand: load generation for capacity testing, against a
+//! running proxy or against a proxy started in front of mock upstreams so
+//! no provider quota is consumed.
+
+use crate::classify::{classify_response, classify_transport};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str = "Usage: quarantier bench (--url <URL> | --mock <N>) [options]
+  --url <URL>            proxy to drive
+  --mock <N>             start a proxy in front of N mock upstreams instead
+  --mock-latency-ms <MS> latency of the mock upstreams (default 5)
+  --methods <MIX>        weighted method mix, e.g. getSlot=3,getBalance=1
+                         (default: every template once)
+  --concurrency <N>      requests in flight (default 32)
+  --duration <SECS>      length of the run (default 10)
+  --json <FILE>          also write the results as JSON, - for stdout";
+
+/// Parameters of the methods the bench and the mock upstreams know about.
+pub const TEMPLATES: &[(&str, &str)] = &[
+    ("getSlot", "[]"),
+    ("getHealth", "[]"),
+    ("getLatestBlockhash", r#"[{"commitment":"confirmed"}]"#),
+    (
+        "getBalance",
+        r#"["9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM"]"#,
+    ),
+    (
+        "getAccountInfo",
+        r#"["9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM",{"encoding":"base64"}]"#,
+    ),
+];
+
+struct Options {
+    url: Option<String>,
+    mocks: usize,
+    mock_latency: Duration,
+    mix: Vec<(String, u64)>,
+    concurrency: usize,
+    duration: Duration,
+    json: Option<String>,
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        url: None,
+        mocks: 0,
+        mock_latency: Duration::from_millis(5),
+        mix: TEMPLATES.iter().map(|(m, _)| (m.to_string(), 1)).collect(),
+        concurrency: 32,
+        duration: Duration::from_secs(10),
+        json: None,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+        };
+        match flag.as_str() {
+            "--url" => options.url = Some(value.clone()),
+            "--mock" => options.mocks = number()? as usize,
+            "--mock-latency-ms" => options.mock_latency = Duration::from_millis(number()?),
+            "--concurrency" => options.concurrency = number()?.max(1) as usize,
+            "--duration" => options.duration = Duration::from_secs(number()?.max(1)),
+            "--json" => options.json = Some(value.clone()),
+            "--methods" => {
+                options.mix = value
+                    .split(',')
+                    .map(|entry| match entry.split_once('=') {
+                        Some((method, weight)) => weight
+                            .parse()
+                            .map(|weight| (method.to_string(), weight))
+                            .map_err(|_| format!("invalid weight in '{}'", entry)),
+                        None => Ok((entry.to_string(), 1)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                if options.mix.iter().all(|(_, weight)| *weight == 0) {
+                    return Err("--methods needs a method with a positive weight".into());
+                }
+            }
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    if options.url.is_some() == (options.mocks > 0) {
+        return Err("exactly one of --url and --mock is required".into());
+    }
+    Ok(options)
+}
+
+fn request_body(method: &str, id: u64) -> String {
+    let params = TEMPLATES
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map_or("[]", |(_, params)| params);
+    format!(
+        r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#,
+        id, method, params
+    )
+}
+
+/// Run this binary as the proxy in front of `upstreams`, its log discarded
+/// so it does not compete with the results.
+async fn start_proxy(upstreams: &[String]) -> Result<(Child, String), Box<dyn std::error::Error>> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg(port.to_string())
+        .args(upstreams)
+        .stdout(Stdio::null())
+        .spawn()?;
+    let url = format!("http://127.0.0.1:{}", port);
+    let client = reqwest::Client::new();
+    for _ in 0..100 {
+        let ready = client.get(format!("{}/healthz", url)).send().await;
+        if ready.is_ok_and(|response| response.status().is_success()) {
+            return Ok((child, url));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let _ = child.kill();
+    Err("the proxy did not start listening within 10s".into())
+}
+
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    methods: BTreeMap<String, u64>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (method, n) in other.methods {
+            *self.methods.entry(method).or_default() += n;
+        }
+        for (class, n) in other.errors {
+            *self.errors.entry(class).or_default() += n;
+        }
+    }
+}
+
+async fn worker(
+    client: reqwest::Client,
+    url: String,
+    mix: Arc<Vec<(String, u64)>>,
+    until: Instant,
+) -> Results {
+    let total: u64 = mix.iter().map(|(_, weight)| weight).sum();
+    let mut results = Results::default();
+    let mut id = 0;
+    while Instant::now() < until {
+        let mut pick = crate::random::u64() % total;
+        let (method, _) = mix
+            .iter()
+            .find(|(_, weight)| {
+                let hit = pick < *weight;
+                pick = pick.saturating_sub(*weight);
+                hit
+            })
+            .expect("weights add up to the total");
+        id += 1;
+        let started = Instant::now();
+        let response = client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(request_body(method, id))
+            .send()
+            .await;
+        let class = match response {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                match response.bytes().await {
+                    Ok(body) => {
+                        let json = serde_json::from_slice::<Value>(&body).ok();
+                        classify_response(status, &body, json.as_ref())
+                    }
+                    Err(err) => Some(classify_transport(&err)),
+                }
+            }
+            Err(err) => Some(classify_transport(&err)),
+        };
+        results.latencies.push(started.elapsed());
+        *results.methods.entry(method.clone()).or_default() += 1;
+        if let Some(class) = class {
+            *results
+                .errors
+                .entry(class.as_str().to_string())
+                .or_default() += 1;
+        }
+    }
+    results
+}
+
+fn report(options: &Options, url: &str, mut results: Results, elapsed: Duration) -> Value {
+    results.latencies.sort();
+    let percentile = |q: f64| {
+        let index = ((results.latencies.len() as f64 * q).ceil() as usize).saturating_sub(1);
+        results
+            .latencies
+            .get(index)
+            .map(|latency| (latency.as_secs_f64() * 1_000_000.0).round() / 1000.0)
+    };
+    let requests = results.latencies.len() as u64;
+    let errors: u64 = results.errors.values().sum();
+    json!({
+        "target": url,
+        "mock_upstreams": options.mocks,
+        "concurrency": options.concurrency,
+        "duration_secs": elapsed.as_secs_f64(),
+        "requests": requests,
+        "rps": requests as f64 / elapsed.as_secs_f64(),
+        "errors": errors,
+        "error_rate": if requests == 0 { 0.0 } else { errors as f64 / requests as f64 },
+        "latency_ms": {
+            "p50": percentile(0.5),
+            "p90": percentile(0.9),
+            "p99": percentile(0.99),
+            "max": percentile(1.0),
+        },
+        "methods": results.methods,
+        "error_classes": results.errors,
+    })
+}
+
+fn print_report(report: &Value) {
+    let ms = |q: &str| {
+        report["latency_ms"][q]
+            .as_f64()
+            .map_or("-".to_string(), |ms| format!("{:.2}ms", ms))
+    };
+    println!(
+        "{} requests in {:.1}s against {} with concurrency {}",
+        report["requests"],
+        report["duration_secs"].as_f64().unwrap_or_default(),
+        report["target"].as_str().unwrap_or_default(),
+        report["concurrency"]
+    );
+    println!(
+        "throughput {:.1} rps, errors {} ({:.2}%)",
+        report["rps"].as_f64().unwrap_or_default(),
+        report["errors"],
+        report["error_rate"].as_f64().unwrap_or_default() * 100.0
+    );
+    println!(
+        "latency p50={} p90={} p99={} max={}",
+        ms("p50"),
+        ms("p90"),
+        ms("p99"),
+        ms("max")
+    );
+    for (method, n) in report["methods"].as_object().into_iter().flatten() {
+        println!("  {:<24} {}", method, n);
+    }
+    for (class, n) in report["error_classes"].as_object().into_iter().flatten() {
+        println!("  error {:<18} {}", class, n);
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let options = match parse(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+    let mut proxy = None;
+    let url = match &options.url {
+        Some(url) => url.clone(),
+        None => {
+            let mut upstreams = Vec::new();
+            for _ in 0..options.mocks {
+                upstreams.push(
+                    crate::mock::spawn(
+                        crate::mock::Behavior {
+                            latency: options.mock_latency,
+                            ..Default::default()
+                        },
+                        Vec::new(),
+                    )
+                    .await?,
+                );
+            }
+            let (child, url) = start_proxy(&upstreams).await?;
+            proxy = Some(child);
+            url
+        }
+    };
+
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(options.concurrency)
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let mix = Arc::new(options.mix.clone());
+    let started = Instant::now();
+    let until = started + options.duration;
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| tokio::spawn(worker(client.clone(), url.clone(), mix.clone(), until)))
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        results.merge(worker.await?);
+    }
+    let report = report(&options, &url, results, started.elapsed());
+    if let Some(mut child) = proxy {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    match options.json.as_deref() {
+        Some("-") => println!("{}", report),
+        Some(path) => {
+            print_report(&report);
+            std::fs::write(path, format!("{:#}\n", report))?;
+        }
+        None => print_report(&report),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn options_are_parsed_and_checked() {
+        let options = parse(&args(
+            "--mock 2 --methods getSlot=3,getBalance --duration 0",
+        ))
+        .unwrap();
+        assert_eq!(options.mocks, 2);
+        assert_eq!(
+            options.mix,
+            vec![("getSlot".to_string(), 3), ("getBalance".to_string(), 1)]
+        );
+        assert_eq!(options.duration, Duration::from_secs(1));
+        assert_eq!(options.concurrency, 32);
+
+        for (bad, message) in [
+            ("", "exactly one of --url and --mock is required"),
+            (
+                "--url http://x --mock 1",
+                "exactly one of --url and --mock is required",
+            ),
+            ("--mock two", "--mock expects a number, got 'two'"),
+            (
+                "--mock 1 --methods getSlot=0",
+                "--methods needs a method with a positive weight",
+            ),
+            (
+                "--mock 1 --methods getSlot=x",
+                "invalid weight in 'getSlot=x'",
+            ),
+            ("--mock 1 --json", "--json needs a value"),
+            ("--mock 1 --fast yes", "unknown option --fast"),
+        ] {
+            assert_eq!(parse(&args(bad)).err().as_deref(), Some(message), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn requests_use_the_template_params() {
+        let body: Value = serde_json::from_str(&request_body("getBalance", 7)).unwrap();
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["method"], "getBalance");
+        assert_eq!(
+            body["params"][0],
+            "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM"
+        );
+        let body: Value = serde_json::from_str(&request_body("getVersion", 1)).unwrap();
+        assert_eq!(body["params"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn workers_report_the_mix_they_sent() {
+        let upstream = crate::testing::upstream(Default::default()).await;
+        let (_, url) =
+            crate::testing::proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", upstream)).await;
+        let options = parse(&args(&format!(
+            "--url {} --methods getSlot=1,getBalance=1 --concurrency 2",
+            url
+        )))
+        .unwrap();
+        let mix = Arc::new(options.mix.clone());
+        let until = Instant::now() + Duration::from_millis(300);
+        let client = reqwest::Client::new();
+        let mut results = Results::default();
+        let workers: Vec<_> = (0..options.concurrency)
+            .map(|_| tokio::spawn(worker(client.clone(), url.clone(), mix.clone(), until)))
+            .collect();
+        for worker in workers {
+            results.merge(worker.await.unwrap());
+        }
+        let report = report(&options, &url, results, Duration::from_millis(300));
+
+        let requests = report["requests"].as_u64().unwrap();
+        assert!(requests > 0, "{}", report);
+        let sent: u64 = report["methods"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(method, n)| {
+                assert!(method == "getSlot" || method == "getBalance", "{}", method);
+                n.as_u64().unwrap()
+            })
+            .sum();
+        assert_eq!(sent, requests);
+        assert_eq!(report["errors"], 0, "{}", report);
+        assert!(
+            report["latency_ms"]["p50"].as_f64().unwrap()
+                <= report["latency_ms"]["max"].as_f64().unwrap()
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Detection of upstreams serving stale blockhashes. A node can report a
+//! current slot while its `getLatestBlockhash` is hundreds of blocks behind,
+//! and every transaction built from it then expires at once. Each answer's
+//! `lastValidBlockHeight`, from client traffic or the optional probe, is
+//! compared with the freshest one the other upstreams gave lately; an
+//! upstream behind by more than `[blockhash] margin_blocks` several times
+//! in a row is flagged, and quarantined with `quarantine = true`.
+
+use crate::config::BlockhashConfig;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long an answer counts as a recent one to compare against. Short
+/// enough that the height moving on meanwhile stays well under the margin.
+const RECENT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+struct Sample {
+    last_valid: u64,
+    at: Instant,
+}
+
+#[derive(Default)]
+pub struct BlockhashHealth {
+    last: Mutex<Option<Sample>>,
+    /// Blocks behind the freshest recent answer, at the last comparison.
+    behind: AtomicU64,
+    /// Stale answers in a row.
+    strikes: AtomicU64,
+    stale: AtomicBool,
+    /// Whether being stale quarantines the upstream.
+    quarantines: bool,
+}
+
+impl BlockhashHealth {
+    pub fn new(settings: Option<&BlockhashConfig>) -> Self {
+        Self {
+            quarantines: settings.is_some_and(|settings| settings.quarantine),
+            ..Self::default()
+        }
+    }
+
+    fn recent(&self) -> Option<u64> {
+        let last = (*self.last.lock().unwrap())?;
+        (last.at.elapsed() < RECENT).then_some(last.last_valid)
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    /// Whether the upstream is quarantined for its stale blockhashes.
+    pub fn quarantines(&self) -> bool {
+        self.quarantines && self.is_stale()
+    }
+
+    pub fn behind(&self) -> Option<u64> {
+        self.last
+            .lock()
+            .unwrap()
+            .map(|_| self.behind.load(Ordering::Relaxed))
+    }
+
+    /// The last answer and how it compared, for `/status`.
+    pub fn report(&self) -> Value {
+        let last = *self.last.lock().unwrap();
+        json!({
+            "last_valid_block_height": last.map(|last| last.last_valid),
+            "age_ms": last.map(|last| last.at.elapsed().as_millis() as u64),
+            "behind_blocks": self.behind(),
+            "stale": self.is_stale(),
+        })
+    }
+}
+
+/// The `lastValidBlockHeight` of a `getLatestBlockhash` answer.
+pub fn last_valid_block_height(answer: &Value) -> Option<u64> {
+    answer["result"]["value"]["lastValidBlockHeight"].as_u64()
+}
+
+/// Record the `lastValidBlockHeight` upstream `index` answered and compare
+/// it with the recent answers of the others, logging when the upstream
+/// becomes stale or current again.
+pub fn observe(config: &ServerConfig, settings: &BlockhashConfig, index: usize, last_valid: u64) {
+    let upstream = &config.servers[index];
+    let freshest = config
+        .servers
+        .iter()
+        .filter(|other| other.index != index)
+        .filter_map(|other| other.blockhash.recent())
+        .fold(last_valid, u64::max);
+    let health = &upstream.blockhash;
+    *health.last.lock().unwrap() = Some(Sample {
+        last_valid,
+        at: Instant::now(),
+    });
+    let behind = freshest - last_valid;
+    health.behind.store(behind, Ordering::Relaxed);
+    let strikes = if behind > settings.margin_blocks {
+        health.strikes.fetch_add(1, Ordering::Relaxed) + 1
+    } else {
+        health.strikes.store(0, Ordering::Relaxed);
+        0
+    };
+    let stale = strikes >= settings.strikes;
+    if health.stale.swap(stale, Ordering::Relaxed) == stale {
+        return;
+    }
+    let message = match (stale, settings.quarantine) {
+        (true, true) => format!(
+            "{} serves stale blockhashes, {} blocks behind, quarantining it",
+            upstream.name, behind
+        ),
+        (true, false) => format!(
+            "{} serves stale blockhashes, {} blocks behind",
+            upstream.name, behind
+        ),
+        (false, _) => format!("{} serves current blockhashes again", upstream.name),
+    };
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Ask every upstream for `getLatestBlockhash` every `interval`, so stale
+/// ones are found without client traffic, and quarantined ones recover.
+pub async fn probe(config: Arc<ServerConfig>, settings: BlockhashConfig, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let body = r#"{"jsonrpc":"2.0","id":1,"method":"getLatestBlockhash"}"#;
+    loop {
+        ticker.tick().await;
+        // hosts pulled for failures only get their circuit breaker's probes
+        let upstreams = config.servers.iter().filter(|u| u.circuit.is_closed());
+        let upstreams = upstreams.filter(|u| !u.is_last_resort());
+        let upstreams = upstreams.filter(|u| u.take_token());
+        let answers = futures::future::join_all(upstreams.map(|upstream| {
+            crate::costs::charge(&config, upstream.index, ["getLatestBlockhash"]);
+            let request = upstream
+                .post()
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send();
+            async move {
+                let response = request.await.and_then(|r| r.error_for_status());
+                let answer: Value = response.ok()?.json().await.ok()?;
+                Some((upstream.index, last_valid_block_height(&answer)?))
+            }
+        }))
+        .await;
+        for (index, last_valid) in answers.into_iter().flatten() {
+            observe(&config, &settings, index, last_valid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Behavior, Phase};
+    use crate::testing::{call, proxy, upstream};
+
+    /// Wait up to two seconds for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    fn stuck() -> Behavior {
+        Behavior {
+            blockhash_lag: 500,
+            ..Behavior::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn client_traffic_flags_a_stale_upstream() {
+        let stuck = upstream(stuck()).await;
+        let good = upstream(Behavior::default()).await;
+        let (config, url) = proxy(&format!(
+            "[blockhash]\nstrikes = 2\n\n[[upstreams]]\nname = \"stuck\"\nurl = \"{}\"\n\n[[upstreams]]\nname = \"good\"\nurl = \"{}\"\n",
+            stuck, good
+        ))
+        .await;
+        let (stuck, good) = (&config.servers[0], &config.servers[1]);
+        for id in 0..3 {
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": "getLatestBlockhash" });
+            assert_eq!(call(&url, request).await.status(), 200);
+        }
+        assert!(eventually(|| stuck.blockhash.is_stale()).await);
+        assert!(!good.blockhash.is_stale());
+        assert!(stuck.blockhash.behind().unwrap() >= 400);
+        assert_eq!(good.blockhash.behind(), Some(0));
+        // flagged only, without `quarantine = true`
+        assert_eq!(stuck.quarantine_reason(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn the_probe_quarantines_until_the_blockhash_is_current() {
+        let recovering = crate::mock::spawn(
+            stuck(),
+            vec![Phase {
+                at: Duration::from_millis(800),
+                behavior: Behavior::default(),
+            }],
+        )
+        .await
+        .unwrap();
+        let good = upstream(Behavior::default()).await;
+        let config = crate::testing::config(&format!(
+            "[blockhash]\nstrikes = 2\nquarantine = true\nprobe_interval_secs = 1\n\n[[upstreams]]\nurl = \"{}\"\n\n[[upstreams]]\nurl = \"{}\"\n",
+            recovering, good
+        ));
+        let settings = config.settings.blockhash.clone().unwrap();
+        tokio::spawn(probe(config.clone(), settings, Duration::from_millis(50)));
+        let upstream = &config.servers[0];
+        assert!(eventually(|| upstream.quarantine_reason(&[]) == Some("stale blockhash")).await);
+        let events = config.events.recent();
+        assert!(
+            events.iter().any(|(_, e)| e.ends_with("quarantining it")),
+            "{:?}",
+            events
+        );
+        assert!(eventually(|| upstream.quarantine_reason(&[]).is_none()).await);
+        assert!(!upstream.blockhash.is_stale());
+    }
+}
@@ -0,0 +1,259 @@
+//! Global budget for the response bodies held in memory. Winners are
+//! streamed, but their bodies are still kept for slot tracking, divergence
+//! comparison and merging, as are the bodies of the other upstreams and of
+//! buffered requests; enough large answers at once would exhaust memory.
+//! Keeping a body reserves its size on a semaphore of bytes until the last
+//! clone of it is dropped. Out of budget, a streamed body goes straight
+//! through uninspected and the others are dropped.
+
+use crate::config::BodyBudgetConfig;
+use axum::body::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Budget assumed when the memory available cannot be read.
+const FALLBACK_LIMIT: usize = 256 << 20;
+
+/// What a body that did not fit became.
+#[derive(Clone, Copy)]
+pub enum Degraded {
+    /// Streamed to the client without being kept or inspected.
+    StreamedThrough,
+    /// A body only kept for inspection, or for a buffered answer, dropped.
+    Dropped,
+    /// A request sent as it is instead of being buffered for
+    /// `minContextSlot`, see `min_context`.
+    Unbuffered,
+}
+
+impl Degraded {
+    pub const ALL: [Degraded; 3] = [
+        Degraded::StreamedThrough,
+        Degraded::Dropped,
+        Degraded::Unbuffered,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Degraded::StreamedThrough => "streamed_through",
+            Degraded::Dropped => "dropped",
+            Degraded::Unbuffered => "unbuffered",
+        }
+    }
+}
+
+pub struct BodyBudget {
+    bytes: Arc<Semaphore>,
+    limit: usize,
+    /// How long a body not streamed to a client may wait for room.
+    wait: Duration,
+    degraded: [AtomicU64; 3],
+}
+
+/// The bytes reserved for one body so far.
+#[derive(Default)]
+pub struct Reservation {
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A kept body with its reservation, released along with the bytes.
+struct Held<T> {
+    data: T,
+    _reservation: Reservation,
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Held<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+}
+
+impl BodyBudget {
+    pub fn new(settings: &BodyBudgetConfig) -> Self {
+        let limit = settings.limit.min(Semaphore::MAX_PERMITS);
+        Self {
+            bytes: Arc::new(Semaphore::new(limit)),
+            limit,
+            wait: settings.wait,
+            degraded: Default::default(),
+        }
+    }
+
+    /// Reserve `bytes` more for `reservation`. A body `waits` for others to
+    /// be released for a short while at most.
+    pub async fn grow(&self, reservation: &mut Reservation, bytes: usize, waits: bool) -> bool {
+        let Ok(bytes) = u32::try_from(bytes) else {
+            return false;
+        };
+        let permit = match self.bytes.clone().try_acquire_many_owned(bytes) {
+            Ok(permit) => permit,
+            Err(_) if waits => {
+                let acquire = self.bytes.clone().acquire_many_owned(bytes);
+                match tokio::time::timeout(self.wait, acquire).await {
+                    Ok(Ok(permit)) => permit,
+                    _ => return false,
+                }
+            }
+            Err(_) => return false,
+        };
+        match &mut reservation.permit {
+            Some(held) => held.merge(permit),
+            None => reservation.permit = Some(permit),
+        }
+        true
+    }
+
+    /// Whether less than an eighth of the budget is left.
+    pub fn is_low(&self) -> bool {
+        self.bytes.available_permits() < self.limit / 8
+    }
+
+    pub fn record(&self, degraded: Degraded) {
+        self.degraded[degraded as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn degraded(&self, degraded: Degraded) -> u64 {
+        self.degraded[degraded as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.limit - self.bytes.available_permits()
+    }
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.permit
+            .as_ref()
+            .map_or(0, |permit| permit.num_permits())
+    }
+
+    /// `chunks` as one body holding the reservation until it is dropped.
+    pub fn hold(self, mut chunks: Vec<Bytes>) -> Bytes {
+        if self.permit.is_none() {
+            return chunks.concat().into();
+        }
+        match chunks.len() {
+            1 => Bytes::from_owner(Held {
+                data: chunks.pop().unwrap(),
+                _reservation: self,
+            }),
+            _ => Bytes::from_owner(Held {
+                data: chunks.concat(),
+                _reservation: self,
+            }),
+        }
+    }
+}
+
+/// A quarter of the memory the process may use: the cgroup limit of its
+/// container when there is one, the machine's memory otherwise.
+pub fn default_limit() -> usize {
+    let cgroup = [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ]
+    .into_iter()
+    .find_map(|path| {
+        std::fs::read_to_string(path)
+            .ok()?
+            .trim()
+            .parse::<usize>()
+            .ok()
+    });
+    let total = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|info| {
+            let line = info.lines().find(|line| line.starts_with("MemTotal:"))?;
+            let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        });
+    // cgroup v1 reports no limit as a huge number
+    match cgroup.into_iter().chain(total).min() {
+        Some(bytes) => bytes / 4,
+        None => FALLBACK_LIMIT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(limit: usize, wait: Duration) -> BodyBudget {
+        BodyBudget::new(&BodyBudgetConfig { limit, wait })
+    }
+
+    #[tokio::test]
+    async fn kept_bodies_hold_their_bytes_until_dropped() {
+        let budget = budget(100, Duration::ZERO);
+        let mut reservation = Reservation::default();
+        assert!(budget.grow(&mut reservation, 30, false).await);
+        assert!(budget.grow(&mut reservation, 20, false).await);
+        assert_eq!(reservation.bytes(), 50);
+        let body = reservation.hold(vec![Bytes::from(vec![1; 30]), Bytes::from(vec![2; 20])]);
+        assert_eq!(body.len(), 50);
+        assert_eq!(budget.reserved(), 50);
+        let clone = body.slice(10..20);
+        drop(body);
+        assert_eq!(budget.reserved(), 50);
+        drop(clone);
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn bodies_beyond_the_limit_are_refused() {
+        let budget = budget(100, Duration::ZERO);
+        let mut held = Reservation::default();
+        assert!(budget.grow(&mut held, 90, false).await);
+        assert!(budget.is_low());
+        let mut reservation = Reservation::default();
+        assert!(!budget.grow(&mut reservation, 20, false).await);
+        assert!(!budget.grow(&mut reservation, usize::MAX, true).await);
+        assert_eq!(reservation.bytes(), 0);
+        // an unreserved body is returned as it is
+        assert_eq!(reservation.hold(vec![Bytes::from_static(b"ab")]), "ab");
+        drop(held);
+        assert!(!budget.is_low());
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn waiting_bodies_get_room_once_it_is_released() {
+        let shared = Arc::new(budget(100, Duration::from_secs(5)));
+        let mut held = Reservation::default();
+        assert!(shared.grow(&mut held, 90, false).await);
+        let waiting = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                let mut reservation = Reservation::default();
+                shared.grow(&mut reservation, 20, true).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        assert!(waiting.await.unwrap());
+        // or give up after the wait
+        let full = budget(10, Duration::from_millis(10));
+        let mut held = Reservation::default();
+        assert!(full.grow(&mut held, 10, false).await);
+        let mut reservation = Reservation::default();
+        assert!(!full.grow(&mut reservation, 1, true).await);
+    }
+
+    #[test]
+    fn degraded_bodies_are_counted_by_kind() {
+        let budget = budget(100, Duration::ZERO);
+        budget.record(Degraded::Dropped);
+        budget.record(Degraded::Dropped);
+        budget.record(Degraded::Unbuffered);
+        let counts: Vec<u64> = Degraded::ALL.iter().map(|d| budget.degraded(*d)).collect();
+        assert_eq!(counts, [0, 2, 1]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Admin-triggered fault injection, to watch the quarantine react to a
+//! degrading upstream without waiting for a real one. Injections add to
+//! what the upstream really does: delays come on top of its latency,
+//! failures on top of its own, and slot lag is subtracted from the slots
+//! it reports. Every injection expires on its own.
+
+use crate::ServerConfig;
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest an injection may last, so a forgotten one cannot linger.
+const MAX_DURATION: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// Milliseconds added before every request is sent.
+    Latency,
+    /// Slots subtracted from every slot the upstream reports.
+    SlotLag,
+    /// Fraction of requests failed with a 503 instead of being sent.
+    ErrorRate,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "latency" => Some(Kind::Latency),
+            "slot_lag" => Some(Kind::SlotLag),
+            "error_rate" => Some(Kind::ErrorRate),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Latency => "latency",
+            Kind::SlotLag => "slot_lag",
+            Kind::ErrorRate => "error_rate",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Injection {
+    id: u64,
+    upstream: usize,
+    kind: Kind,
+    value: f64,
+    expires: Instant,
+}
+
+impl Injection {
+    fn describe(&self, name: &str) -> String {
+        let value = match self.kind {
+            Kind::Latency => format!("{}ms", self.value),
+            Kind::SlotLag => format!("{} slots", self.value),
+            Kind::ErrorRate => format!("{}", self.value),
+        };
+        format!("{} {} on {}", self.kind.as_str(), value, name)
+    }
+}
+
+/// What the injections on an upstream do to one request.
+#[derive(Default)]
+pub struct Effects {
+    pub delay: Duration,
+    pub fail: bool,
+}
+
+#[derive(Default)]
+pub struct Chaos {
+    injections: Mutex<Vec<Injection>>,
+    next_id: AtomicU64,
+}
+
+impl Chaos {
+    /// Injections in effect. Expired ones are removed, and logged, by the
+    /// task started with them.
+    fn active(&self) -> Vec<Injection> {
+        let injections = self.injections.lock().unwrap();
+        let now = Instant::now();
+        injections
+            .iter()
+            .filter(|injection| injection.expires > now)
+            .cloned()
+            .collect()
+    }
+
+    fn on(&self, upstream: usize) -> impl Iterator<Item = Injection> {
+        self.active()
+            .into_iter()
+            .filter(move |injection| injection.upstream == upstream)
+    }
+
+    /// Delay and failure for the next request to `upstream`: delays add
+    /// up, and each error rate gets its own roll.
+    pub fn effects(&self, upstream: usize) -> Effects {
+        let mut effects = Effects::default();
+        for injection in self.on(upstream) {
+            match injection.kind {
+                Kind::Latency => {
+                    effects.delay += Duration::from_millis(injection.value as u64);
+                }
+                Kind::ErrorRate => effects.fail |= crate::random::chance(injection.value),
+                Kind::SlotLag => {}
+            }
+        }
+        effects
+    }
+
+    /// `slot` as reported by `upstream`, minus the injected lag. Anything
+    /// but a slot number is passed through for the tracker to reject.
+    pub fn misreport(&self, upstream: usize, slot: &Value) -> Value {
+        let lag: f64 = self
+            .on(upstream)
+            .filter(|injection| injection.kind == Kind::SlotLag)
+            .map(|injection| injection.value)
+            .sum();
+        match slot.as_u64() {
+            Some(slot) if lag > 0.0 => slot.saturating_sub(lag as u64).into(),
+            _ => slot.clone(),
+        }
+    }
+
+    /// Active injections on `upstream`, for `/status`.
+    pub fn report(&self, upstream: usize) -> Vec<Value> {
+        let now = Instant::now();
+        self.on(upstream)
+            .map(|injection| {
+                json!({
+                    "inject": injection.kind.as_str(),
+                    "value": injection.value,
+                    "expires_in_secs": injection.expires.duration_since(now).as_secs(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Body of an injected failure, answered in place of the upstream.
+pub fn failure_body() -> Bytes {
+    crate::rpc::error_body(-32603, "chaos: injected failure", None).into()
+}
+
+fn list(config: &ServerConfig) -> Json<Value> {
+    let now = Instant::now();
+    let injections: Vec<Value> = config
+        .chaos
+        .active()
+        .iter()
+        .map(|injection| {
+            json!({
+                "host": config.servers[injection.upstream].name,
+                "inject": injection.kind.as_str(),
+                "value": injection.value,
+                "expires_in_secs": injection.expires.duration_since(now).as_secs(),
+            })
+        })
+        .collect();
+    Json(json!({ "injections": injections }))
+}
+
+/// The injection a `POST /admin/chaos` body asks for, and its duration.
+fn parse(config: &ServerConfig, body: &[u8]) -> Result<(Injection, Duration), String> {
+    let request: Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let name = request["host"].as_str().unwrap_or_default();
+    let upstream = config
+        .servers
+        .iter()
+        .position(|upstream| upstream.name == name)
+        .ok_or_else(|| format!("no upstream named '{}'", name))?;
+    let kind = Kind::parse(request["inject"].as_str().unwrap_or_default())
+        .ok_or("'inject' must be latency, slot_lag or error_rate")?;
+    let value = match kind {
+        Kind::Latency => request["value_ms"].as_f64().or(request["value"].as_f64()),
+        _ => request["value"].as_f64(),
+    };
+    let value = value
+        .filter(|value| *value >= 0.0 && value.is_finite())
+        .filter(|value| kind != Kind::ErrorRate || *value <= 1.0)
+        .ok_or(match kind {
+            Kind::Latency => "'value_ms' must be a non-negative number",
+            Kind::SlotLag => "'value' must be a non-negative number of slots",
+            Kind::ErrorRate => "'value' must be a fraction between 0 and 1",
+        })?;
+    let duration = request["duration_secs"]
+        .as_f64()
+        .filter(|secs| *secs > 0.0 && *secs <= MAX_DURATION.as_secs_f64())
+        .map(Duration::from_secs_f64)
+        .ok_or("'duration_secs' must be positive and at most 3600")?;
+    let injection = Injection {
+        id: config.chaos.next_id.fetch_add(1, Ordering::Relaxed),
+        upstream,
+        kind,
+        value,
+        expires: Instant::now() + duration,
+    };
+    Ok((injection, duration))
+}
+
+/// `GET /admin/chaos`: the injections in effect.
+pub async fn list_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    list(&config)
+}
+
+/// `POST /admin/chaos {"host", "inject", "value" or "value_ms",
+/// "duration_secs"}`: start an injection. Unlike the other admin endpoints
+/// it is not opened to localhost when no API keys are configured.
+pub async fn inject_handler(
+    State(config): State<Arc<ServerConfig>>,
+    body: Bytes,
+) -> Response<Body> {
+    if !config.api_keys().enabled() {
+        return crate::rpc::error_response(
+            StatusCode::FORBIDDEN,
+            crate::rpc::FORBIDDEN,
+            "chaos injection requires an admin API key",
+            None,
+        );
+    }
+    let (injection, duration) = match parse(&config, &body) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return crate::rpc::error_response(
+                StatusCode::BAD_REQUEST,
+                crate::rpc::INVALID_REQUEST,
+                &message,
+                None,
+            )
+        }
+    };
+    let description = injection.describe(&config.servers[injection.upstream].name);
+    println!("+ CHAOS injecting {} for {:?}", description, duration);
+    config.events.record(format!(
+        "chaos: injecting {} for {:?}",
+        description, duration
+    ));
+    let (id, expires) = (injection.id, injection.expires);
+    config.chaos.injections.lock().unwrap().push(injection);
+    let expiring = config.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(expires.into()).await;
+        let mut injections = expiring.chaos.injections.lock().unwrap();
+        // already gone when cleared
+        if let Some(position) = injections.iter().position(|i| i.id == id) {
+            injections.remove(position);
+            println!("+ CHAOS injection expired: {}", description);
+            expiring
+                .events
+                .record(format!("chaos: injection expired: {}", description));
+        }
+    });
+    list(&config).into_response()
+}
+
+/// `DELETE /admin/chaos`: end every injection.
+pub async fn clear_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    let cleared = std::mem::take(&mut *config.chaos.injections.lock().unwrap()).len();
+    println!("+ CHAOS cleared {} injections", cleared);
+    config
+        .events
+        .record(format!("chaos: cleared {} injections", cleared));
+    Json(json!({ "cleared": cleared }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{proxy, upstream};
+
+    const KEYS: &str = "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n";
+
+    #[tokio::test]
+    async fn localhost_cannot_inject_without_keys() {
+        let (config, url) =
+            proxy("[[upstreams]]\nname = \"a\"\nurl = \"http://127.0.0.1:1\"\n").await;
+        let client = reqwest::Client::new();
+        let injected = client
+            .post(format!("{}/admin/chaos", url))
+            .json(&json!({ "host": "a", "inject": "latency", "value_ms": 10 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(injected.status(), 403);
+        assert!(config.chaos.injections.lock().unwrap().is_empty());
+        // the rest of the admin API still answers localhost
+        let listed = client.get(format!("{}/admin/chaos", url)).send().await;
+        assert_eq!(listed.unwrap().status(), 200);
+    }
+
+    #[tokio::test]
+    async fn injections_degrade_the_upstream_until_they_expire() {
+        let a = upstream(Default::default()).await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n{}",
+            a, KEYS
+        ))
+        .await;
+        let client = reqwest::Client::new();
+        let inject = |body: Value| {
+            client
+                .post(format!("{}/admin/chaos", url))
+                .header("x-api-key", "secret-ops")
+                .json(&body)
+                .send()
+        };
+        let rejected = inject(json!({ "host": "a", "inject": "error_rate", "value": 2 }));
+        assert_eq!(rejected.await.unwrap().status(), 400);
+        let rejected = inject(json!({ "host": "b", "inject": "latency", "value_ms": 1 }));
+        assert_eq!(rejected.await.unwrap().status(), 400);
+
+        let injected = inject(json!({
+            "host": "a", "inject": "error_rate", "value": 1.0, "duration_secs": 0.3,
+        }))
+        .await
+        .unwrap();
+        assert_eq!(injected.status(), 200);
+        let listed: Value = injected.json().await.unwrap();
+        assert_eq!(
+            listed.to_string().matches("error_rate").count(),
+            1,
+            "{}",
+            listed
+        );
+        assert_eq!(config.chaos.report(0).len(), 1);
+        assert_eq!(config.chaos.misreport(0, &json!(1000)), 1000);
+
+        let call = || {
+            client
+                .post(&url)
+                .header("x-api-key", "secret-ops")
+                .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" }))
+                .send()
+        };
+        let failed = call().await.unwrap();
+        assert_ne!(failed.status(), 200);
+        let failed: Value = failed.json().await.unwrap();
+        assert!(failed.to_string().contains("chaos"), "{}", failed);
+        // it expires on its own
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(config.chaos.injections.lock().unwrap().is_empty());
+        assert_eq!(call().await.unwrap().status(), 200);
+
+        let injected = inject(json!({
+            "host": "a", "inject": "slot_lag", "value": 100, "duration_secs": 60,
+        }));
+        assert_eq!(injected.await.unwrap().status(), 200);
+        assert_eq!(config.chaos.misreport(0, &json!(1000)), 900);
+        let cleared: Value = client
+            .delete(format!("{}/admin/chaos", url))
+            .header("x-api-key", "secret-ops")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(cleared["cleared"], 1);
+        assert_eq!(config.chaos.misreport(0, &json!(1000)), 1000);
+    }
+}
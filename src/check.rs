@@ -0,0 +1,101 @@
+//! `check` subcommand: validate a configuration and probe its upstreams
+//! with `getSlot`, without starting the proxy. Exits with 0 when every
+//! upstream answers, 1 when some do not, and 2 when the configuration is
+//! invalid or no upstream answers.
+
+use crate::config::{Config, UpstreamConfig};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str =
+    "Usage: quarantier check [--no-probe] (--config <FILE> | <PORT> <URL1> <URL2> ...)
+  --no-probe  only validate the configuration";
+
+async fn probe(
+    client: &reqwest::Client,
+    upstream: &UpstreamConfig,
+) -> Result<(u64, Duration), String> {
+    let started = Instant::now();
+    let request = client.post(&upstream.url);
+    let response = crate::credentials::apply(&upstream.credentials, request)
+        .header("Content-Type", "application/json")
+        .body(r#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let body: Value = response.json().await.map_err(|e| e.to_string())?;
+    match body["result"].as_u64() {
+        Some(slot) => Ok((slot, started.elapsed())),
+        None => Err(format!("no slot in answer: {}", body)),
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (probing, args) = match args {
+        [flag, rest @ ..] if flag == "--no-probe" => (false, rest),
+        _ => (true, args),
+    };
+    let settings = match args {
+        [flag, path] if flag == "--config" => Config::load(path),
+        [port, urls @ ..] if !urls.is_empty() && port != "--config" => {
+            Config::from_args(port, urls)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    println!(
+        "Configuration is valid: port {}, {} upstreams",
+        settings.port,
+        settings.upstreams.len()
+    );
+    if !probing {
+        return Ok(());
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let probes = settings
+        .upstreams
+        .iter()
+        .map(|upstream| probe(&client, upstream));
+    let results = futures::future::join_all(probes).await;
+    let width = settings
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.name.len())
+        .max()
+        .unwrap_or_default();
+    let mut answering = 0;
+    for (upstream, result) in settings.upstreams.iter().zip(results) {
+        let outcome = match result {
+            Ok((slot, latency)) => {
+                answering += 1;
+                format!("slot {} in {:?}", slot, latency)
+            }
+            Err(err) => format!("FAILED: {}", err),
+        };
+        println!(
+            "  {:<width$}  {}  {}",
+            upstream.name,
+            upstream.url,
+            outcome,
+            width = width
+        );
+    }
+    std::process::exit(match answering {
+        0 => 2,
+        n if n < settings.upstreams.len() => 1,
+        _ => 0,
+    });
+}
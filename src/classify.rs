@@ -0,0 +1,277 @@
+use serde_json::Value;
+use std::error::Error as _;
+use std::time::Duration;
+
+/// Coarse category of a failed upstream attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    Dns,
+    ConnectTimeout,
+    Connect,
+    Tls,
+    ReadTimeout,
+    /// The attempt outlasted its method's `request_timeout_ms`, which a
+    /// heavy query explains as well as a slow host.
+    MethodTimeout,
+    /// A 4xx status other than 408 and 429: the request was refused, which
+    /// says nothing about the host.
+    Http4xx,
+    /// 408 or 429: the host was too slow or too busy to take the request.
+    HttpBusy,
+    Http5xx,
+    JsonParse,
+    EmptyBody,
+    RpcParse,
+    RpcInvalidRequest,
+    RpcMethodNotFound,
+    RpcInvalidParams,
+    RpcInternal,
+    RpcNodeUnhealthy,
+    RpcServer,
+    RpcOther,
+    Other,
+}
+
+impl ErrorClass {
+    pub const COUNT: usize = 20;
+    pub const ALL: [ErrorClass; Self::COUNT] = [
+        ErrorClass::Dns,
+        ErrorClass::ConnectTimeout,
+        ErrorClass::Connect,
+        ErrorClass::Tls,
+        ErrorClass::ReadTimeout,
+        ErrorClass::MethodTimeout,
+        ErrorClass::Http4xx,
+        ErrorClass::HttpBusy,
+        ErrorClass::Http5xx,
+        ErrorClass::JsonParse,
+        ErrorClass::EmptyBody,
+        ErrorClass::RpcParse,
+        ErrorClass::RpcInvalidRequest,
+        ErrorClass::RpcMethodNotFound,
+        ErrorClass::RpcInvalidParams,
+        ErrorClass::RpcInternal,
+        ErrorClass::RpcNodeUnhealthy,
+        ErrorClass::RpcServer,
+        ErrorClass::RpcOther,
+        ErrorClass::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Dns => "dns",
+            ErrorClass::ConnectTimeout => "connect_timeout",
+            ErrorClass::Connect => "connect",
+            ErrorClass::Tls => "tls",
+            ErrorClass::ReadTimeout => "read_timeout",
+            ErrorClass::MethodTimeout => "method_timeout",
+            ErrorClass::Http4xx => "http_4xx",
+            ErrorClass::HttpBusy => "http_busy",
+            ErrorClass::Http5xx => "http_5xx",
+            ErrorClass::JsonParse => "json_parse",
+            ErrorClass::EmptyBody => "empty_body",
+            ErrorClass::RpcParse => "rpc_parse",
+            ErrorClass::RpcInvalidRequest => "rpc_invalid_request",
+            ErrorClass::RpcMethodNotFound => "rpc_method_not_found",
+            ErrorClass::RpcInvalidParams => "rpc_invalid_params",
+            ErrorClass::RpcInternal => "rpc_internal",
+            ErrorClass::RpcNodeUnhealthy => "rpc_node_unhealthy",
+            ErrorClass::RpcServer => "rpc_server",
+            ErrorClass::RpcOther => "rpc_other",
+            ErrorClass::Other => "other",
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Whether this failure says something about the host itself rather than
+    /// about the request. Only these extend the failure streak.
+    pub fn is_host_failure(self) -> bool {
+        matches!(
+            self,
+            ErrorClass::Dns
+                | ErrorClass::ConnectTimeout
+                | ErrorClass::Connect
+                | ErrorClass::Tls
+                | ErrorClass::ReadTimeout
+                | ErrorClass::HttpBusy
+                | ErrorClass::Http5xx
+                | ErrorClass::JsonParse
+                | ErrorClass::EmptyBody
+                | ErrorClass::RpcNodeUnhealthy
+                | ErrorClass::Other
+        )
+    }
+
+    /// How far this failure extends the failure streak. A connect timeout
+    /// counts double: the host did not even accept a connection, which a
+    /// slow query cannot explain.
+    pub fn streak_weight(self) -> u64 {
+        match self {
+            ErrorClass::ConnectTimeout => 2,
+            class if class.is_host_failure() => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Classify a request that never produced an HTTP response.
+pub fn classify_transport(err: &reqwest::Error) -> ErrorClass {
+    // reqwest only exposes coarse kinds, the detail lives in the source chain
+    let mut chain = String::new();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        chain.push_str(&cause.to_string().to_lowercase());
+        chain.push(' ');
+        source = cause.source();
+    }
+
+    if err.is_connect() {
+        if err.is_timeout() {
+            ErrorClass::ConnectTimeout
+        } else if chain.contains("dns error") || chain.contains("failed to lookup address") {
+            ErrorClass::Dns
+        } else if chain.contains("tls") || chain.contains("ssl") || chain.contains("certificate") {
+            ErrorClass::Tls
+        } else {
+            ErrorClass::Connect
+        }
+    } else if err.is_timeout() {
+        ErrorClass::ReadTimeout
+    } else if err.is_decode() {
+        ErrorClass::JsonParse
+    } else {
+        ErrorClass::Other
+    }
+}
+
+/// Classify a request sent with `request_timeout`, its methods' own when
+/// `Some`: a timeout then is no host failure.
+pub fn classify_attempt(err: &reqwest::Error, request_timeout: Option<Duration>) -> ErrorClass {
+    match classify_transport(err) {
+        ErrorClass::ReadTimeout if request_timeout.is_some() => ErrorClass::MethodTimeout,
+        class => class,
+    }
+}
+
+/// Classify an HTTP response by its status, body and parsed body (`None`
+/// when the body was not valid JSON). Returns `None` for a successful
+/// answer. Some providers answer with an empty 200 while failing over.
+pub fn classify_response(status: u16, body: &[u8], json: Option<&Value>) -> Option<ErrorClass> {
+    match status {
+        408 | 429 => return Some(ErrorClass::HttpBusy),
+        400..=499 => return Some(ErrorClass::Http4xx),
+        500..=599 => return Some(ErrorClass::Http5xx),
+        _ => {}
+    }
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Some(ErrorClass::EmptyBody);
+    }
+    let body = match json {
+        Some(body) => body,
+        None => return Some(ErrorClass::JsonParse),
+    };
+    let code = match body.get("error") {
+        Some(error) => error.get("code").and_then(Value::as_i64),
+        None => return None,
+    };
+    Some(match code {
+        Some(-32700) => ErrorClass::RpcParse,
+        Some(-32600) => ErrorClass::RpcInvalidRequest,
+        Some(-32601) => ErrorClass::RpcMethodNotFound,
+        Some(-32602) => ErrorClass::RpcInvalidParams,
+        Some(-32603) => ErrorClass::RpcInternal,
+        Some(-32005) => ErrorClass::RpcNodeUnhealthy,
+        Some(-32099..=-32000) => ErrorClass::RpcServer,
+        _ => ErrorClass::RpcOther,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn classify(status: u16, body: &str) -> Option<ErrorClass> {
+        let json = serde_json::from_str::<Value>(body).ok();
+        classify_response(status, body.as_bytes(), json.as_ref())
+    }
+
+    #[test]
+    fn classes_are_listed_in_index_order() {
+        for (i, class) in ErrorClass::ALL.iter().enumerate() {
+            assert_eq!(class.index(), i, "{}", class.as_str());
+        }
+    }
+
+    #[test]
+    fn responses_are_classified_by_status_then_body() {
+        let answer = r#"{"jsonrpc":"2.0","result":1,"id":1}"#;
+        assert_eq!(classify(200, answer), None);
+        assert_eq!(classify(400, answer), Some(ErrorClass::Http4xx));
+        assert_eq!(classify(408, answer), Some(ErrorClass::HttpBusy));
+        assert_eq!(classify(429, answer), Some(ErrorClass::HttpBusy));
+        assert_eq!(classify(503, answer), Some(ErrorClass::Http5xx));
+        assert_eq!(classify(200, "<html>"), Some(ErrorClass::JsonParse));
+        assert_eq!(classify(200, ""), Some(ErrorClass::EmptyBody));
+        assert_eq!(classify(200, " \r\n"), Some(ErrorClass::EmptyBody));
+    }
+
+    #[test]
+    fn rpc_errors_are_classified_by_code() {
+        for (code, class) in [
+            (-32700, ErrorClass::RpcParse),
+            (-32600, ErrorClass::RpcInvalidRequest),
+            (-32601, ErrorClass::RpcMethodNotFound),
+            (-32602, ErrorClass::RpcInvalidParams),
+            (-32603, ErrorClass::RpcInternal),
+            (-32005, ErrorClass::RpcNodeUnhealthy),
+            (-32007, ErrorClass::RpcServer),
+            (-32099, ErrorClass::RpcServer),
+            (-32100, ErrorClass::RpcOther),
+            (7, ErrorClass::RpcOther),
+        ] {
+            let body = json!({ "jsonrpc": "2.0", "error": { "code": code }, "id": 1 });
+            assert_eq!(classify(200, &body.to_string()), Some(class), "{}", code);
+        }
+        assert_eq!(
+            classify(200, r#"{"error":{"message":"no code"}}"#),
+            Some(ErrorClass::RpcOther)
+        );
+    }
+
+    #[test]
+    fn only_host_failures_count_against_the_host() {
+        assert!(ErrorClass::ConnectTimeout.is_host_failure());
+        assert!(ErrorClass::Http5xx.is_host_failure());
+        assert!(ErrorClass::RpcNodeUnhealthy.is_host_failure());
+        assert!(ErrorClass::HttpBusy.is_host_failure());
+        // the request's fault
+        assert!(!ErrorClass::Http4xx.is_host_failure());
+        assert!(!ErrorClass::RpcInvalidParams.is_host_failure());
+        assert!(!ErrorClass::RpcMethodNotFound.is_host_failure());
+    }
+
+    #[tokio::test]
+    async fn transport_failures_are_classified() {
+        // nothing listens on a port just released
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let err = reqwest::get(&url).await.unwrap_err();
+        assert_eq!(classify_transport(&err), ErrorClass::Connect);
+
+        // accepts connections, through the backlog, but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", silent.local_addr().unwrap());
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let err = client.get(&url).send().await.unwrap_err();
+        assert_eq!(classify_transport(&err), ErrorClass::ReadTimeout);
+    }
+}
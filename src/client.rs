@@ -0,0 +1,29 @@
+use crate::config::ClientIpConfig;
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Address of the client behind a request. Forwarding headers are honored
+/// for every peer with `trust_forwarded_for`, otherwise only for peers in
+/// `trusted_proxies`, whose own entries are then skipped from the right of
+/// `x-forwarded-for`. `x-real-ip` is used when no `x-forwarded-for` is set.
+pub fn client_ip(peer: SocketAddr, headers: &HeaderMap, settings: &ClientIpConfig) -> IpAddr {
+    let peer = peer.ip();
+    let trusted = |ip: &IpAddr| settings.trusted_proxies.iter().any(|net| net.contains(ip));
+    if !settings.trust_forwarded_for && !trusted(&peer) {
+        return peer;
+    }
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let forwarded: Vec<IpAddr> = header("x-forwarded-for")
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.trim().parse().ok())
+        .collect();
+    let client = if settings.trust_forwarded_for {
+        forwarded.first().copied()
+    } else {
+        forwarded.iter().rev().find(|ip| !trusted(ip)).copied()
+    };
+    client
+        .or_else(|| header("x-real-ip").and_then(|value| value.trim().parse().ok()))
+        .unwrap_or(peer)
+}
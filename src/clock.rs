@@ -0,0 +1,129 @@
+//! Wall-clock helpers. Monotonic `Instant`s are used for all durations; these
+//! are only for timestamps shown to humans and external systems.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub fn unix_secs() -> u64 {
+    unix_ms() / 1000
+}
+
+/// Civil UTC date and time of a unix timestamp:
+/// (year, month, day, hour, minute, second).
+pub fn civil(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+/// `2024-05-01T12:00:00.123Z`
+pub fn rfc3339(ms: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil(ms / 1000);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        ms % 1000
+    )
+}
+
+/// Unix milliseconds of a UTC timestamp as written by `rfc3339`, with or
+/// without the fraction.
+pub fn parse_rfc3339(text: &str) -> Option<u64> {
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<u32>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let ms = match fraction {
+        "" => 0,
+        digits => format!("{:0<3}", digits.get(..3).unwrap_or(digits))
+            .parse::<u64>()
+            .ok()?,
+    };
+    let days = u64::try_from(days_from_civil(year as i64, month, day)).ok()?;
+    Some((days * 86_400 + hour * 3600 + minute * 60 + second) * 1000 + ms)
+}
+
+/// `01/May/2024:12:00:00 +0000`, as used by the Common Log Format.
+pub fn clf(secs: u64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Days since the unix epoch of a civil date.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Start of the UTC day, or month, containing `secs` as seen from a fixed
+/// UTC offset, and the start of the next one.
+pub fn calendar_window(secs: u64, offset_secs: i64, monthly: bool) -> (u64, u64) {
+    let local = (secs as i64 + offset_secs).max(0) as u64;
+    let (year, month, day, ..) = civil(local);
+    let (start, end) = if monthly {
+        let (next_year, next_month) = if month == 12 {
+            (year + 1, 1)
+        } else {
+            (year, month + 1)
+        };
+        (
+            days_from_civil(year, month, 1),
+            days_from_civil(next_year, next_month, 1),
+        )
+    } else {
+        let start = days_from_civil(year, month, day);
+        (start, start + 1)
+    };
+    let to_utc = |days: i64| (days * 86_400 - offset_secs).max(0) as u64;
+    (to_utc(start), to_utc(end))
+}
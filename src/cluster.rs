@@ -0,0 +1,373 @@
+//! Upstreams that serve another cluster, such as a devnet URL pasted into a
+//! mainnet pool, found by the `getGenesisHash` each reports at startup. The
+//! hash the pool is held to is `[cluster] genesis_hash`, or the one more
+//! than half the answers report once half the upstreams answered. An
+//! upstream reporting another is quarantined for good as "wrong cluster":
+//! it gets no traffic unless targeted, its slots are ignored so they cannot
+//! skew the tip, and it is no longer polled or probed. Upstreams that do
+//! not answer are asked again every `RETRY_INTERVAL` until they do, startup
+//! waiting only `startup_timeout_ms` for the first round. `verify = false`
+//! skips the check, for pools mixing clusters on purpose.
+
+use crate::config::ClusterConfig;
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Longest one `getGenesisHash` call may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Pause before asking the upstreams that did not answer again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The cluster an upstream serves.
+#[derive(Default)]
+pub struct Membership {
+    genesis_hash: Mutex<Option<String>>,
+    wrong: AtomicBool,
+}
+
+impl Membership {
+    pub fn genesis_hash(&self) -> Option<String> {
+        self.genesis_hash.lock().unwrap().clone()
+    }
+
+    /// Whether it reported a genesis hash other than the pool's.
+    pub fn is_wrong(&self) -> bool {
+        self.wrong.load(Ordering::Relaxed)
+    }
+}
+
+/// The genesis hash the upstreams are held to, once known.
+#[derive(Default)]
+pub struct Expected {
+    /// The hash, and whether it was "configured" or the "majority".
+    hash: Mutex<Option<(String, &'static str)>>,
+}
+
+impl Expected {
+    fn get(&self) -> Option<String> {
+        self.hash
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(hash, _)| hash.clone())
+    }
+
+    /// For `/status`, `null` when the check is disabled.
+    pub fn report(&self, settings: Option<&ClusterConfig>) -> Value {
+        if settings.is_none() {
+            return Value::Null;
+        }
+        match &*self.hash.lock().unwrap() {
+            Some((hash, source)) => json!({ "genesis_hash": hash, "source": source }),
+            None => json!({ "genesis_hash": null, "source": "undecided" }),
+        }
+    }
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+async fn genesis_hash(config: &ServerConfig, upstream: &Upstream) -> Option<String> {
+    if !upstream.take_token() {
+        return None;
+    }
+    crate::costs::charge(config, upstream.index, ["getGenesisHash"]);
+    let request = upstream
+        .post()
+        .header("Content-Type", "application/json")
+        .body(r#"{"jsonrpc":"2.0","id":1,"method":"getGenesisHash"}"#)
+        .send();
+    let answer = tokio::time::timeout(PROBE_TIMEOUT, async {
+        request.await?.json::<Value>().await
+    })
+    .await;
+    let answer = answer.ok()?.ok()?;
+    answer["result"].as_str().map(str::to_string)
+}
+
+/// The hash more than half of the answers report, once at least half the
+/// upstreams answered, so a host that is down does not hold the decision
+/// up. Once every upstream answered without such a majority, a warning
+/// naming who reports what.
+fn majority(config: &ServerConfig) -> Result<Option<String>, String> {
+    let mut reported: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for upstream in &config.servers {
+        if let Some(hash) = upstream.cluster.genesis_hash() {
+            reported.entry(hash).or_default().push(&upstream.name);
+        }
+    }
+    let total = config.servers.len();
+    let answered: usize = reported.values().map(Vec::len).sum();
+    let winner = reported
+        .iter()
+        .filter(|_| answered * 2 >= total)
+        .find(|(_, names)| names.len() * 2 > answered);
+    if let Some((hash, names)) = winner {
+        log(
+            config,
+            format!(
+                "Cluster genesis hash is {}, reported by {} of the {} upstreams that answered",
+                hash,
+                names.len(),
+                answered
+            ),
+        );
+        return Ok(Some(hash.clone()));
+    }
+    if answered < total {
+        return Ok(None);
+    }
+    let split: Vec<String> = reported
+        .iter()
+        .map(|(hash, names)| format!("{} from {}", hash, names.join(", ")))
+        .collect();
+    Err(format!(
+        "WARNING: upstreams disagree on the genesis hash ({}) without a majority; set [cluster] genesis_hash to pick the cluster, or verify = false if mixing clusters is intended",
+        split.join("; ")
+    ))
+}
+
+/// Ask every upstream its genesis hash, signalling `first_round` after the
+/// first round, and again those that did not answer until all have.
+pub async fn verify(
+    config: Arc<ServerConfig>,
+    settings: ClusterConfig,
+    first_round: oneshot::Sender<()>,
+) {
+    let mut first_round = Some(first_round);
+    if let Some(hash) = settings.genesis_hash {
+        *config.expected_cluster.hash.lock().unwrap() = Some((hash, "configured"));
+    }
+    loop {
+        let pending = config
+            .servers
+            .iter()
+            .filter(|upstream| upstream.cluster.genesis_hash().is_none());
+        let answers = futures::future::join_all(
+            pending
+                .map(|upstream| async { (upstream.index, genesis_hash(&config, upstream).await) }),
+        )
+        .await;
+        for (index, hash) in answers {
+            *config.servers[index].cluster.genesis_hash.lock().unwrap() = hash;
+        }
+        if config.expected_cluster.get().is_none() {
+            match majority(&config) {
+                Ok(Some(hash)) => {
+                    *config.expected_cluster.hash.lock().unwrap() = Some((hash, "majority"))
+                }
+                Ok(None) => {}
+                Err(warning) => {
+                    log(&config, warning);
+                    return;
+                }
+            }
+        }
+        if let Some(expected) = config.expected_cluster.get() {
+            for upstream in &config.servers {
+                let Some(hash) = upstream.cluster.genesis_hash() else {
+                    continue;
+                };
+                if hash == expected || upstream.cluster.wrong.swap(true, Ordering::Relaxed) {
+                    continue;
+                }
+                config.slots.exclude(upstream.index);
+                log(
+                    &config,
+                    format!(
+                        "WARNING: {} reports genesis hash {} but the cluster's is {}, it serves another cluster; quarantined as wrong cluster",
+                        upstream.name, hash, expected
+                    ),
+                );
+            }
+        }
+        if let Some(first_round) = first_round.take() {
+            let _ = first_round.send(());
+        }
+        let answered = config
+            .servers
+            .iter()
+            .all(|upstream| upstream.cluster.genesis_hash().is_some());
+        if answered {
+            return;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Behavior, Cluster};
+    use crate::slots::SlotSource;
+    use crate::testing::{call, upstream};
+    use std::time::Instant;
+
+    const MAINNET: &str = "5eykt4UsFv8P8NJdTREpY1vzqKqZKvdpKuc147dw2N9d";
+    const DEVNET: &str = "EtWTRABZaYq6iMfeYKouRu166VU2xqa1wcaWoxPkrZBG";
+
+    /// A proxy for upstreams `urls`, named after their position, with the
+    /// `[cluster]` table `cluster`.
+    async fn proxy(urls: &[String], cluster: &str) -> (Arc<ServerConfig>, String) {
+        let mut text = format!("[cluster]\n{}\n", cluster);
+        for (index, url) in urls.iter().enumerate() {
+            text += &format!("[[upstreams]]\nname = \"u{}\"\nurl = \"{}\"\n", index, url);
+        }
+        crate::testing::proxy(&text).await
+    }
+
+    async fn node(cluster: Cluster) -> String {
+        upstream(Behavior {
+            cluster,
+            ..Behavior::default()
+        })
+        .await
+    }
+
+    /// The first round of the check, or all of it when it ends there.
+    async fn verified(config: &Arc<ServerConfig>) {
+        let settings = config.settings.cluster.clone().unwrap();
+        let (first_round, done) = oneshot::channel();
+        tokio::spawn(verify(config.clone(), settings, first_round));
+        // a split without majority ends the check before the round is
+        // signalled
+        let _ = done.await;
+    }
+
+    fn events(config: &ServerConfig) -> Vec<String> {
+        config.events.recent().into_iter().map(|(_, e)| e).collect()
+    }
+
+    #[tokio::test]
+    async fn the_majority_puts_another_cluster_in_quarantine() {
+        let urls = [
+            node(Cluster::Mainnet).await,
+            node(Cluster::Devnet).await,
+            node(Cluster::Mainnet).await,
+        ];
+        let (config, url) = proxy(&urls, "").await;
+        verified(&config).await;
+        let devnet = &config.servers[1];
+        assert!(devnet.cluster.is_wrong());
+        assert_eq!(devnet.quarantine_reason(&[]), Some("wrong cluster"));
+        assert!(!config.servers[0].cluster.is_wrong());
+        assert!(events(&config).iter().any(|e| e.contains(&format!(
+            "u1 reports genesis hash {} but the cluster's is {}",
+            DEVNET, MAINNET
+        ))));
+
+        // its slots do not count toward the tip
+        config.slots.observe(1, 350_000_000, SlotSource::Poll);
+        assert!(config.slots.get(1).is_none());
+        // nor do its answers reach clients
+        for _ in 0..6 {
+            let answer: Value = call(
+                &url,
+                json!({"jsonrpc": "2.0", "id": 1, "method": "getGenesisHash"}),
+            )
+            .await
+            .json()
+            .await
+            .unwrap();
+            assert_eq!(answer["result"], MAINNET);
+        }
+
+        let status = crate::status::document(&config).await;
+        assert_eq!(
+            status["cluster"],
+            json!({ "genesis_hash": MAINNET, "source": "majority" })
+        );
+        assert_eq!(status["upstreams"][1]["genesis_hash"], DEVNET);
+        assert_eq!(status["upstreams"][1]["quarantine_reason"], "wrong cluster");
+    }
+
+    #[tokio::test]
+    async fn a_configured_hash_outweighs_the_majority() {
+        let urls = [
+            node(Cluster::Mainnet).await,
+            node(Cluster::Devnet).await,
+            node(Cluster::Mainnet).await,
+        ];
+        let (config, _) = proxy(&urls, &format!("genesis_hash = \"{}\"", DEVNET)).await;
+        verified(&config).await;
+        let wrong: Vec<bool> = config
+            .servers
+            .iter()
+            .map(|u| u.cluster.is_wrong())
+            .collect();
+        assert_eq!(wrong, [true, false, true]);
+        assert_eq!(
+            crate::status::document(&config).await["cluster"],
+            json!({ "genesis_hash": DEVNET, "source": "configured" })
+        );
+    }
+
+    #[tokio::test]
+    async fn upstreams_that_do_not_answer_are_left_alone() {
+        let urls = [
+            node(Cluster::Mainnet).await,
+            node(Cluster::Mainnet).await,
+            "http://127.0.0.1:1".to_string(),
+        ];
+        let (config, _) = proxy(&urls, "").await;
+        verified(&config).await;
+        // two of three answering is enough for a majority
+        assert_eq!(config.expected_cluster.get().as_deref(), Some(MAINNET));
+        let down = &config.servers[2];
+        assert_eq!(down.cluster.genesis_hash(), None);
+        assert!(!down.cluster.is_wrong());
+    }
+
+    #[tokio::test]
+    async fn a_split_without_majority_quarantines_no_one() {
+        let urls = [node(Cluster::Mainnet).await, node(Cluster::Devnet).await];
+        let (config, _) = proxy(&urls, "").await;
+        verified(&config).await;
+        assert!(config.servers.iter().all(|u| !u.cluster.is_wrong()));
+        assert_eq!(
+            crate::status::document(&config).await["cluster"]["source"],
+            "undecided"
+        );
+        let warning = format!(
+            "({} from u0; {} from u1) without a majority",
+            MAINNET, DEVNET
+        );
+        assert!(events(&config).iter().any(|e| e.contains(&warning)));
+    }
+
+    #[tokio::test]
+    async fn mixed_pools_may_skip_the_check() {
+        let urls = [node(Cluster::Mainnet).await, node(Cluster::Devnet).await];
+        let (config, _) = proxy(&urls, "verify = false").await;
+        assert!(config.settings.cluster.is_none());
+        assert_eq!(
+            crate::status::document(&config).await["cluster"],
+            Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn startup_waits_for_the_check_only_so_long() {
+        let blackhole = crate::testing::Blackhole::new().await;
+        let text = format!(
+            "[warmup]\nconnections = 0\n[discovery]\nenabled = false\n\
+             [cluster]\nstartup_timeout_ms = 200\n\
+             [[upstreams]]\nurl = \"{}\"\n",
+            blackhole.url()
+        );
+        let service = crate::ProxyService::new(crate::config::Config::parse(&text).unwrap());
+        let started = Instant::now();
+        service.start().await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(200), "{:?}", waited);
+        assert!(waited < PROBE_TIMEOUT, "{:?}", waited);
+    }
+}
@@ -0,0 +1,324 @@
+//! Coalescing of identical reads in flight at once. Dashboards and bots
+//! poll the same accounts from many processes, each one a full dispatch to
+//! every upstream. With `[coalesce]` enabled, a single call to one of its
+//! `methods` arriving while an identical one is being dispatched waits for
+//! that one's answer and gets it with its own `id`. Calls are identical
+//! when their canonical forms are: the `id` left out, object keys in order
+//! and numbers written the same way, so clients using different JSON
+//! serializers share their dispatches. The body sent upstream is never
+//! rewritten.
+
+use axum::body::Bytes;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The answer of the call a key is dispatched for, `None` until it is in.
+type Shared = watch::Receiver<Option<Bytes>>;
+
+#[derive(Default)]
+pub struct Coalescer {
+    in_flight: Arc<Mutex<HashMap<String, Shared>>>,
+    /// Calls answered with another call's answer.
+    coalesced: AtomicU64,
+}
+
+/// How a call takes part: dispatched for everyone, or waiting.
+pub enum Joined {
+    Lead(Lead),
+    Follow(Follow),
+}
+
+/// The call dispatched for its key. Dropping it without an answer lets
+/// those waiting dispatch their own calls.
+pub struct Lead {
+    in_flight: Arc<Mutex<HashMap<String, Shared>>>,
+    key: String,
+    answer: watch::Sender<Option<Bytes>>,
+}
+
+pub struct Follow(Shared);
+
+/// The canonical form of a JSON-RPC call or batch: compact JSON without
+/// the `id` of each call, object keys sorted and integral numbers written
+/// as integers, so `1e3`, `1000.0` and `1000` are the same number.
+pub fn canonical(request: &Value) -> String {
+    let mut out = String::new();
+    match request {
+        Value::Array(calls) => {
+            out.push('[');
+            for (i, call) in calls.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_call(&mut out, call);
+            }
+            out.push(']');
+        }
+        call => write_call(&mut out, call),
+    }
+    out
+}
+
+fn write_call(out: &mut String, call: &Value) {
+    match call {
+        Value::Object(fields) => {
+            let without_id: serde_json::Map<String, Value> = fields
+                .iter()
+                .filter(|(key, _)| *key != "id")
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            write(out, &Value::Object(without_id));
+        }
+        other => write(out, other),
+    }
+}
+
+fn write(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => {
+                let _ = write!(out, "{}", u);
+            }
+            (_, Some(i), _) => {
+                let _ = write!(out, "{}", i);
+            }
+            // integral floats within range are the integers they equal
+            (_, _, Some(f)) if f.fract() == 0.0 && f.abs() < 9.0e15 => {
+                let _ = write!(out, "{}", f as i64);
+            }
+            (_, _, Some(f)) => {
+                let _ = write!(out, "{:e}", f);
+            }
+            _ => out.push_str(&n.to_string()),
+        },
+        Value::String(s) => out.push_str(&Value::String(s.clone()).to_string()),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(fields) => {
+            // sorted here, whatever order the map keeps
+            let mut keys: Vec<&String> = fields.keys().collect();
+            keys.sort_unstable();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write(out, &fields[key]);
+            }
+            out.push('}');
+        }
+    }
+}
+
+impl Coalescer {
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Join the dispatch of `request`'s key, leading it if none is in
+    /// flight.
+    pub fn join(&self, request: &Value) -> Joined {
+        let key = canonical(request);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(shared) = in_flight.get(&key) {
+            return Joined::Follow(Follow(shared.clone()));
+        }
+        let (answer, shared) = watch::channel(None);
+        in_flight.insert(key.clone(), shared);
+        Joined::Lead(Lead {
+            in_flight: self.in_flight.clone(),
+            key,
+            answer,
+        })
+    }
+
+    /// Count a call answered with another's answer.
+    pub fn record(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Lead {
+    /// Hand `body`, the answer to the call, to those waiting.
+    pub fn answer(self, body: Bytes) {
+        self.answer.send_replace(Some(body));
+    }
+}
+
+impl Drop for Lead {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // a later call may lead the key by now
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|shared| shared.same_channel(&self.answer.subscribe()))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl Follow {
+    /// The answer of the call leading the key, or `None` when it ended
+    /// without one.
+    pub async fn answer(mut self) -> Option<Bytes> {
+        match self.0.wait_for(Option::is_some).await {
+            Ok(body) => body.clone(),
+            Err(_) => None,
+        }
+    }
+}
+
+/// `answer`, the JSON-RPC answer of another call, as the answer to a call
+/// with `id`, or `None` for an error, which the call had better get on its
+/// own.
+pub fn answer_as(answer: &[u8], id: &Value) -> Option<String> {
+    let mut answer: Value = serde_json::from_slice(answer).ok()?;
+    let fields = answer.as_object_mut()?;
+    if fields.contains_key("error") {
+        return None;
+    }
+    fields.insert("id".to_string(), id.clone());
+    Some(answer.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(body: &str) -> String {
+        canonical(&serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn identical_calls_share_a_key() {
+        let reference = key(
+            r#"{"jsonrpc":"2.0","id":1,"method":"getAccountInfo","params":["Vote111111111111111111111111111111111111111",{"encoding":"base64","commitment":"confirmed"}]}"#,
+        );
+        for body in [
+            // keys in another order, other whitespace
+            r#"{ "method": "getAccountInfo", "params": [ "Vote111111111111111111111111111111111111111", { "commitment": "confirmed", "encoding": "base64" } ], "jsonrpc": "2.0", "id": 1 }"#,
+            // another id, of another type
+            r#"{"id":"a7f3","jsonrpc":"2.0","method":"getAccountInfo","params":["Vote111111111111111111111111111111111111111",{"encoding":"base64","commitment":"confirmed"}]}"#,
+            "{\"jsonrpc\":\"2.0\",\n\t\"method\":\"getAccountInfo\",\"params\":[\"Vote111111111111111111111111111111111111111\",{\"encoding\":\"base64\",\"commitment\":\"confirmed\"}]}",
+        ] {
+            assert_eq!(key(body), reference, "{}", body);
+        }
+    }
+
+    #[test]
+    fn numbers_are_written_one_way() {
+        let reference =
+            key(r#"{"method":"getBlock","params":[1000,{"maxSupportedTransactionVersion":0}]}"#);
+        for body in [
+            r#"{"method":"getBlock","params":[1000.0,{"maxSupportedTransactionVersion":0}]}"#,
+            r#"{"method":"getBlock","params":[1e3,{"maxSupportedTransactionVersion":0.0}]}"#,
+            r#"{"method":"getBlock","params":[10E2,{"maxSupportedTransactionVersion":-0.0}]}"#,
+        ] {
+            assert_eq!(key(body), reference, "{}", body);
+        }
+        assert_eq!(key("[0.5]"), key("[5e-1]"));
+        assert_ne!(key("[0.5]"), key("[0.25]"));
+    }
+
+    #[test]
+    fn different_calls_have_different_keys() {
+        let base = key(
+            r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["11111111111111111111111111111111"]}"#,
+        );
+        for body in [
+            r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["Vote111111111111111111111111111111111111111"]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"getAccountInfo","params":["11111111111111111111111111111111"]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["11111111111111111111111111111111",{"commitment":"processed"}]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":[["11111111111111111111111111111111"]]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":"11111111111111111111111111111111"}"#,
+        ] {
+            assert_ne!(key(body), base, "{}", body);
+        }
+        // a string is not the number it spells
+        assert_ne!(key(r#"{"params":[1]}"#), key(r#"{"params":["1"]}"#));
+        // only the call's own id is left out
+        assert_ne!(
+            key(r#"{"params":[{"id":1}]}"#),
+            key(r#"{"params":[{"id":2}]}"#)
+        );
+    }
+
+    #[test]
+    fn batches_leave_out_every_id() {
+        assert_eq!(
+            key(r#"[{"id":1,"method":"getSlot"},{"id":2,"method":"getBalance"}]"#),
+            key(r#"[{"method":"getSlot","id":7},{"method":"getBalance","id":8}]"#)
+        );
+        assert_ne!(
+            key(r#"[{"method":"getSlot"},{"method":"getBalance"}]"#),
+            key(r#"[{"method":"getBalance"},{"method":"getSlot"}]"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn followers_get_the_leaders_answer() {
+        let coalescer = Coalescer::default();
+        let request = json!({ "id": 1, "method": "getBalance", "params": ["x"] });
+        let Joined::Lead(lead) = coalescer.join(&request) else {
+            panic!("the first call leads");
+        };
+        let repeat = json!({ "id": 2, "params": ["x"], "method": "getBalance" });
+        let Joined::Follow(follow) = coalescer.join(&repeat) else {
+            panic!("an identical call follows");
+        };
+        let other = json!({ "id": 3, "method": "getBalance", "params": ["y"] });
+        assert!(matches!(coalescer.join(&other), Joined::Lead(_)));
+        let waiting = tokio::spawn(follow.answer());
+        lead.answer(Bytes::from_static(
+            br#"{"jsonrpc":"2.0","result":5,"id":1}"#,
+        ));
+        let answer = waiting.await.unwrap().unwrap();
+        assert_eq!(
+            answer_as(&answer, &json!(2)).unwrap(),
+            r#"{"id":2,"jsonrpc":"2.0","result":5}"#
+        );
+        // answered, the key is free again
+        assert!(matches!(coalescer.join(&repeat), Joined::Lead(_)));
+    }
+
+    #[tokio::test]
+    async fn followers_of_a_failed_lead_dispatch_themselves() {
+        let coalescer = Coalescer::default();
+        let request = json!({ "id": 1, "method": "getBalance", "params": ["x"] });
+        let Joined::Lead(lead) = coalescer.join(&request) else {
+            panic!("the first call leads");
+        };
+        let Joined::Follow(follow) = coalescer.join(&request) else {
+            panic!("an identical call follows");
+        };
+        drop(lead);
+        assert_eq!(follow.answer().await, None);
+        assert!(matches!(coalescer.join(&request), Joined::Lead(_)));
+        // errors are not shared
+        assert_eq!(
+            answer_as(
+                br#"{"jsonrpc":"2.0","error":{"code":-32005},"id":1}"#,
+                &json!(2)
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Side-by-side answers of every upstream to one request, for looking into
+//! providers that disagree without curling each by hand. The request goes
+//! straight to the upstreams: nothing is cached, observed for the
+//! quarantine or slot tracking, or accounted as usage.
+
+use crate::divergence::{fingerprint, normalize};
+use crate::ServerConfig;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Characters kept of each answer.
+const MAX_BODY: usize = 64 * 1024;
+/// Longest `timeout_ms` may be.
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The highest context slot in an answer, batches included.
+fn context_slot(answer: &Value) -> Option<u64> {
+    match answer {
+        Value::Array(items) => items.iter().filter_map(context_slot).max(),
+        answer => answer["result"]["context"]["slot"].as_u64(),
+    }
+}
+
+/// What upstream `index` answered to `body` within `timeout`.
+async fn ask(config: &ServerConfig, index: usize, body: &Bytes, timeout: Duration) -> Value {
+    let upstream = &config.servers[index];
+    if !upstream.take_token() {
+        return json!({ "latency_ms": 0, "error": "at its outbound rate limit" });
+    }
+    let methods = crate::rpc::request_methods(body);
+    crate::costs::charge(config, index, methods.iter().map(String::as_str));
+    let started = Instant::now();
+    let request = upstream
+        .post()
+        .header("Content-Type", "application/json")
+        .body(body.clone())
+        .send();
+    let answered = tokio::time::timeout(timeout, async {
+        let response = request.await?;
+        let status = response.status().as_u16();
+        Ok::<_, reqwest::Error>((status, response.bytes().await?))
+    })
+    .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (status, bytes) = match answered {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(err)) => {
+            return json!({ "latency_ms": latency_ms, "error": err.without_url().to_string() })
+        }
+        Err(_) => return json!({ "latency_ms": latency_ms, "error": "timed out" }),
+    };
+    let answer = serde_json::from_slice::<Value>(&bytes).ok();
+    let truncated = bytes.len() > MAX_BODY;
+    let body = match &answer {
+        Some(answer) if !truncated => answer.clone(),
+        _ => Value::String(String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_BODY)]).into()),
+    };
+    json!({
+        "status": status,
+        "latency_ms": latency_ms,
+        "context_slot": answer.as_ref().and_then(context_slot),
+        "truncated": truncated,
+        "body": body,
+        "fingerprint": answer.map(|answer| fingerprint(&normalize(&answer))),
+    })
+}
+
+/// `POST /admin/compare?timeout_ms=N&include_quarantined=true` with a
+/// JSON-RPC body: send it to every healthy upstream at once and answer
+/// with each one's status, latency, context slot and body by name, and
+/// whether their normalized results are all equal. Quarantined, drained
+/// and last-resort upstreams are only asked with `include_quarantined`.
+pub async fn compare_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Response<Body> {
+    if serde_json::from_slice::<Value>(&body).is_err() {
+        return crate::rpc::error_response(
+            StatusCode::BAD_REQUEST,
+            crate::rpc::INVALID_REQUEST,
+            "the body must be a JSON-RPC request",
+            None,
+        );
+    }
+    let timeout = params
+        .get("timeout_ms")
+        .and_then(|ms| ms.parse().ok())
+        .map_or(config.settings.deadlines.default, Duration::from_millis)
+        .min(MAX_TIMEOUT);
+    let everyone = params
+        .get("include_quarantined")
+        .is_some_and(|v| v == "true");
+    let quarantine = config.quarantine.read().await.clone();
+    let asked: Vec<usize> = config
+        .servers
+        .iter()
+        .filter(|upstream| {
+            everyone
+                || !(upstream.is_quarantined(&quarantine)
+                    || upstream.is_drained()
+                    || upstream.is_last_resort())
+        })
+        .map(|upstream| upstream.index)
+        .collect();
+    let answers = futures::future::join_all(
+        asked
+            .iter()
+            .map(|&index| ask(&config, index, &body, timeout)),
+    )
+    .await;
+
+    let mut upstreams = Map::new();
+    let mut groups: Vec<(u64, Vec<&str>)> = Vec::new();
+    for (&index, mut answer) in asked.iter().zip(answers) {
+        let name = config.servers[index].name.as_str();
+        if let Some(fingerprint) = answer
+            .as_object_mut()
+            .and_then(|answer| answer.remove("fingerprint"))
+            .and_then(|fingerprint| fingerprint.as_u64())
+        {
+            match groups.iter_mut().find(|(f, _)| *f == fingerprint) {
+                Some((_, names)) => names.push(name),
+                None => groups.push((fingerprint, vec![name])),
+            }
+        }
+        upstreams.insert(name.to_string(), answer);
+    }
+    println!(
+        "+ Compared {} upstreams: {} distinct results",
+        asked.len(),
+        groups.len()
+    );
+    Json(json!({
+        "upstreams": upstreams,
+        // none when fewer than two upstreams gave a JSON answer
+        "equal": (groups.iter().map(|(_, names)| names.len()).sum::<usize>() > 1)
+            .then_some(groups.len() == 1),
+        "groups": groups.into_iter().map(|(_, names)| names).collect::<Vec<_>>(),
+        "timeout_ms": timeout.as_millis() as u64,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{proxy, serve};
+    use axum::{routing::post, Router};
+    use std::sync::atomic::Ordering;
+
+    /// An upstream answering every request with `result` at context slot 300.
+    async fn answering(result: Value) -> String {
+        let answer = json!({ "jsonrpc": "2.0", "id": 1, "result": {
+            "context": { "slot": 300 }, "value": result } });
+        serve(Router::new().route("/", post(move || async move { Json(answer) }))).await
+    }
+
+    /// A proxy with an admin key over upstreams a and b agreeing and c
+    /// answering otherwise.
+    async fn three_upstreams() -> (Arc<ServerConfig>, String) {
+        let mut text = String::from(
+            "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n",
+        );
+        for (name, result) in [("a", 5), ("b", 5), ("c", 6)] {
+            let url = answering(json!(result)).await;
+            text += &format!("[[upstreams]]\nname = \"{}\"\nurl = \"{}\"\n", name, url);
+        }
+        proxy(&text).await
+    }
+
+    async fn compare(url: &str, query: &str, key: &str, body: &str) -> (u16, Value) {
+        let response = reqwest::Client::new()
+            .post(format!("{}/admin/compare{}", url, query))
+            .header("x-api-key", key)
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    const GET_BALANCE: &str = r#"{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["x"]}"#;
+
+    #[test]
+    fn batches_report_their_highest_context_slot() {
+        let answer = |slot: u64| json!({ "result": { "context": { "slot": slot } } });
+        assert_eq!(context_slot(&answer(7)), Some(7));
+        assert_eq!(context_slot(&json!([answer(7), answer(9)])), Some(9));
+        assert_eq!(context_slot(&json!({ "result": 1 })), None);
+    }
+
+    #[tokio::test]
+    async fn answers_are_grouped_by_result() {
+        let (_, url) = three_upstreams().await;
+        let (status, body) = compare(&url, "", "secret-ops", GET_BALANCE).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["equal"], false);
+        assert_eq!(body["groups"], json!([["a", "b"], ["c"]]));
+        let a = &body["upstreams"]["a"];
+        assert_eq!(a["status"], 200);
+        assert_eq!(a["context_slot"], 300);
+        assert_eq!(a["truncated"], false);
+        assert_eq!(a["body"]["result"]["value"], 5);
+        assert!(a.get("fingerprint").is_none());
+    }
+
+    #[tokio::test]
+    async fn drained_upstreams_are_asked_only_when_included() {
+        let (config, url) = three_upstreams().await;
+        config.servers[2].drained.store(true, Ordering::Relaxed);
+        let (_, body) = compare(&url, "", "secret-ops", GET_BALANCE).await;
+        assert_eq!(body["equal"], true);
+        assert!(body["upstreams"].get("c").is_none());
+        let (_, body) = compare(&url, "?include_quarantined=true", "secret-ops", GET_BALANCE).await;
+        assert_eq!(body["groups"], json!([["a", "b"], ["c"]]));
+    }
+
+    #[tokio::test]
+    async fn only_admin_keys_compare_json_bodies() {
+        let (_, url) = three_upstreams().await;
+        let (status, _) = compare(&url, "", "secret-app", GET_BALANCE).await;
+        assert_eq!(status, 403);
+        let (status, body) = compare(&url, "", "secret-ops", "not json").await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["code"], crate::rpc::INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn the_timeout_is_capped() {
+        let (_, url) = three_upstreams().await;
+        let (_, body) = compare(&url, "?timeout_ms=600000", "secret-ops", GET_BALANCE).await;
+        assert_eq!(body["timeout_ms"], MAX_TIMEOUT.as_millis() as u64);
+    }
+}
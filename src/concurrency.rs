@@ -0,0 +1,208 @@
+//! Caps on the requests a single client has in flight at once.
+
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// In-flight requests per client identity. Idle clients have no entry.
+#[derive(Default)]
+pub struct InFlight {
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+/// One request in flight; dropping it, on any exit path, releases the slot.
+pub struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    client: String,
+}
+
+impl InFlight {
+    pub fn try_acquire(&self, client: &str, cap: usize) -> Option<InFlightGuard<'_>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(client.to_string()).or_default();
+        if *count >= cap {
+            return None;
+        }
+        *count += 1;
+        Some(InFlightGuard {
+            in_flight: self,
+            client: client.to_string(),
+        })
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Reject requests of clients that already have their cap in flight.
+pub async fn middleware(
+    State(config): State<Arc<ServerConfig>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let key = request.extensions().get::<Arc<crate::auth::ApiKey>>();
+    let cap = key
+        .and_then(|key| key.max_concurrent)
+        .or(config.settings.max_concurrent_per_client);
+    let Some(cap) = cap else {
+        return next.run(request).await;
+    };
+    let client = match (
+        request.extensions().get::<crate::auth::Identity>(),
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) {
+        (Some(identity), _) => identity.0.clone(),
+        (None, Some(ConnectInfo(peer))) => {
+            crate::client::client_ip(*peer, request.headers(), &config.settings.client_ip)
+                .to_string()
+        }
+        (None, None) => return next.run(request).await,
+    };
+    let Some(_guard) = config.in_flight.try_acquire(&client, cap) else {
+        config.usage.record_throttled(&client);
+        let mut response = crate::rpc::error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            crate::rpc::RATE_LIMITED,
+            "too many concurrent requests",
+            None,
+        );
+        response
+            .headers_mut()
+            .insert(axum::http::header::RETRY_AFTER, 1.into());
+        return response;
+    };
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    const CONFIG: &str =
+        "max_concurrent_per_client = 2\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n";
+
+    /// A proxy whose requests are handled by `handler`, capped per client.
+    async fn serve<H, T>(handler: H) -> (Arc<ServerConfig>, String)
+    where
+        H: axum::handler::Handler<T, ()>,
+        T: 'static,
+    {
+        let config = crate::testing::config(CONFIG);
+        let router = Router::new().route(
+            "/",
+            post(handler).layer(axum::middleware::from_fn_with_state(
+                config.clone(),
+                middleware,
+            )),
+        );
+        (config.clone(), crate::testing::serve(router).await)
+    }
+
+    fn in_flight(config: &ServerConfig) -> Option<usize> {
+        config
+            .in_flight
+            .counts
+            .lock()
+            .unwrap()
+            .get("127.0.0.1")
+            .copied()
+    }
+
+    /// Wait up to a second for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    #[test]
+    fn guards_release_their_slot() {
+        let in_flight = InFlight::default();
+        let first = in_flight.try_acquire("a", 2).unwrap();
+        let second = in_flight.try_acquire("a", 2).unwrap();
+        assert!(in_flight.try_acquire("a", 2).is_none());
+        // other clients have their own cap
+        assert!(in_flight.try_acquire("b", 2).is_some());
+        drop(first);
+        assert_eq!(in_flight.counts.lock().unwrap().get("a"), Some(&1));
+        drop(second);
+        assert!(in_flight.counts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_cap_are_rejected() {
+        let release = Arc::new(Notify::new());
+        let (config, url) = serve({
+            let release = release.clone();
+            move || async move { release.notified().await }
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let held: Vec<_> = (0..2)
+            .map(|_| tokio::spawn(client.post(&url).send()))
+            .collect();
+        assert!(eventually(|| in_flight(&config) == Some(2)).await);
+        let rejected = client.post(&url).send().await.unwrap();
+        assert_eq!(rejected.status(), 429);
+        assert_eq!(rejected.headers()["retry-after"], "1");
+        let body: serde_json::Value = rejected.json().await.unwrap();
+        assert_eq!(body["error"]["code"], crate::rpc::RATE_LIMITED);
+        release.notify_waiters();
+        for request in held {
+            assert_eq!(request.await.unwrap().unwrap().status(), 200);
+        }
+        assert_eq!(in_flight(&config), None);
+    }
+
+    #[tokio::test]
+    async fn a_client_disconnecting_releases_its_slot() {
+        let (config, url) = serve(std::future::pending::<()>).await;
+        let impatient = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let gone = impatient.post(&url).send().await;
+        assert!(gone.unwrap_err().is_timeout());
+        assert!(eventually(|| in_flight(&config).is_none()).await);
+        assert!(config.in_flight.counts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_releases_its_slot() {
+        async fn fail() {
+            panic!("handler failed");
+        }
+        let (config, url) = serve(fail).await;
+        let client = reqwest::Client::new();
+        for _ in 0..3 {
+            // the connection is dropped instead of answered
+            assert!(client.post(&url).send().await.is_err());
+        }
+        assert!(eventually(|| in_flight(&config).is_none()).await);
+        assert!(config.in_flight.counts.lock().unwrap().is_empty());
+    }
+}
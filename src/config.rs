@@ -0,0 +1,1231 @@
+use crate::methods::MethodPolicy;
+use crate::toml;
+use crate::versions::Version;
+use axum::http::{HeaderMap, HeaderName};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+mod access;
+mod outputs;
+mod routing;
+mod table;
+mod upstreams;
+
+pub use access::{
+    AclConfig, AuthConfig, ClientIpConfig, IpRateLimitConfig, JwtConfig, Quota, QuotaWindow,
+};
+pub use outputs::{
+    AccessLogConfig, AccessLogFormat, AlertsConfig, MirrorConfig, MirrorSink, StatsdConfig,
+    StatusExportConfig, TxJournalConfig,
+};
+pub use routing::{
+    CapabilityTable, CostTable, DeadlineTable, HedgePolicy, LoadSheddingConfig, Priority,
+    PriorityTable, ResponseLimits, RetryConfig, RoutingTable, Strategy,
+};
+use table::{unknown_keys, Table};
+pub use upstreams::{
+    CredentialConfig, CredentialPlacement, MaintenanceWindow, ProviderLimitConfig, ResetFormat,
+    UpstreamConfig, WEEKDAYS,
+};
+
+pub struct ConfigError {
+    message: String,
+    /// Dotted path of the offending key, to point at its line in the file.
+    key: Option<String>,
+}
+
+impl ConfigError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            key: None,
+        }
+    }
+
+    fn at(key: String, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            key: Some(key),
+        }
+    }
+
+    /// Prefix the message with the line of the offending key, or of the
+    /// closest enclosing table, and with `origin`, the file it came from.
+    fn locate(mut self, lines: &HashMap<String, usize>, origin: Option<&str>) -> Self {
+        if let Some(line) = self.line(lines) {
+            self.message = format!("line {}: {}", line, self.message);
+        }
+        if let Some(origin) = origin {
+            self.message = format!("{}: {}", origin, self.message);
+        }
+        self
+    }
+
+    fn line(&self, lines: &HashMap<String, usize>) -> Option<usize> {
+        let mut key = self.key.as_deref();
+        loop {
+            let path = key?;
+            match lines.get(path) {
+                Some(line) => return Some(*line),
+                None => key = path.rfind(['.', '[']).map(|end| &path[..end]),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ConfigError").field(&self.message).finish()
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config error: {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
+pub struct Config {
+    pub port: u16,
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Healthy upstreams required for `/readyz` to report ready.
+    pub min_healthy: usize,
+    /// Hosts with a fresh slot observation needed for the slot-lag
+    /// quarantine to change.
+    pub min_observations: usize,
+    pub all_quarantined: AllQuarantined,
+    /// `None` unless a `local_region` is set.
+    pub region: Option<RegionConfig>,
+    pub client_ip: ClientIpConfig,
+    pub acl: AclConfig,
+    /// Header carrying the correlation id in both directions.
+    pub request_id_header: HeaderName,
+    /// Attach the upstream identification headers to every response, not
+    /// only to admin requests asking for them.
+    pub debug_headers: bool,
+    /// Log the body of every response returned, for debugging; otherwise
+    /// only its status and size are.
+    pub log_bodies: bool,
+    /// Answer `getHealth` from the proxy's view of all upstreams instead of
+    /// passing it to the first one to answer.
+    pub aggregate_get_health: bool,
+    pub auth: AuthConfig,
+    /// Methods every client may call; keys can restrict them further.
+    pub methods: MethodPolicy,
+    /// Requests one client may have in flight, `None` for no cap.
+    pub max_concurrent_per_client: Option<usize>,
+    /// Dispatches that may keep waiting on slower upstreams after their
+    /// client was answered.
+    pub max_lingering_dispatches: usize,
+    pub priorities: PriorityTable,
+    /// `None` when `[load_shedding] enabled = false`.
+    pub load_shedding: Option<LoadSheddingConfig>,
+    /// Per client address limit of proxied requests, `None` when disabled.
+    pub ip_rate_limit: Option<IpRateLimitConfig>,
+    pub usage: UsageConfig,
+    pub costs: CostTable,
+    pub deadlines: DeadlineTable,
+    pub response_limits: ResponseLimits,
+    pub routing: RoutingTable,
+    pub capabilities: CapabilityTable,
+    /// Slots of ledger history every upstream is trusted to have; calls
+    /// about older data only go to archive upstreams.
+    pub retention_slots: u64,
+    /// Slots below the tracked tip injected as `minContextSlot` into read
+    /// calls, `None` unless `[min_context_slot]` is enabled.
+    pub min_context_margin: Option<u64>,
+    /// `None` when `[strict_freshness] enabled = false`.
+    pub strict_freshness: Option<StrictFreshnessConfig>,
+    /// Time the other upstreams get to better an answer held back, one
+    /// behind the minimum context slot, stale or empty; `None` unless
+    /// `[soft_deadline]` is enabled.
+    pub soft_deadline: Option<Duration>,
+    /// `None` when `[retry] enabled = false`.
+    pub retry: Option<RetryConfig>,
+    pub slots: SlotsConfig,
+    /// `None` when `[epoch_cache] enabled = false`.
+    pub epoch_cache: Option<EpochCacheConfig>,
+    /// `None` when `[local_get_slot] enabled = false`.
+    pub local_get_slot: Option<LocalGetSlotConfig>,
+    /// `None` unless `[coalesce] enabled = true`.
+    pub coalesce: Option<CoalesceConfig>,
+    /// `None` when `[send_dedup] enabled = false`.
+    pub send_dedup: Option<SendDedupConfig>,
+    /// `None` when `[discovery] enabled = false`.
+    pub discovery: Option<DiscoveryConfig>,
+    /// `None` when `[cluster] verify = false`.
+    pub cluster: Option<ClusterConfig>,
+    /// `None` when `[versions] enabled = false`.
+    pub versions: Option<VersionsConfig>,
+    /// `None` when `[ranking] enabled = false`.
+    pub ranking: Option<RankingConfig>,
+    /// `None` when `[unsupported_methods] enabled = false`.
+    pub unsupported_methods: Option<UnsupportedMethodsConfig>,
+    /// `None` when `[tx_tracking] enabled = false`.
+    pub tx_tracking: Option<TxTrackingConfig>,
+    /// `None` when `[blockhash] enabled = false`.
+    pub blockhash: Option<BlockhashConfig>,
+    pub warmup: WarmupConfig,
+    pub circuit: CircuitConfig,
+    pub failback: FailbackConfig,
+    /// `None` when `[error_rate] enabled = false`.
+    pub error_rate: Option<ErrorRateConfig>,
+    pub body_budget: BodyBudgetConfig,
+    /// Period of upstream hostname re-resolution, `None` when disabled.
+    pub dns_refresh: Option<Duration>,
+    /// How long before its maintenance window an upstream is drained.
+    pub maintenance_lead: Duration,
+    /// Period of the one-line summary log, `None` when disabled.
+    pub summary_interval: Option<Duration>,
+    /// DogStatsD export, `None` unless an agent address is configured.
+    pub statsd: Option<StatsdConfig>,
+    /// `None` unless `[status_export] path` is set.
+    pub status_export: Option<StatusExportConfig>,
+    /// Where state that survives restarts is kept, `None` to keep none.
+    pub state_file: Option<String>,
+    pub alerts: AlertsConfig,
+    /// Per-request access log, `None` unless a path is configured.
+    pub access_log: Option<AccessLogConfig>,
+    /// Journal of submitted transactions, `None` unless a path is
+    /// configured.
+    pub tx_journal: Option<TxJournalConfig>,
+    /// Copy of sampled traffic for analysis, `None` unless a sink is set.
+    pub mirror: Option<MirrorConfig>,
+}
+
+pub struct UsageConfig {
+    /// Client identities tracked, counting the "other" that the rest fold
+    /// into.
+    pub max_clients: usize,
+    /// Clients listed by default in `/admin/usage` and exported as metrics.
+    pub top: usize,
+}
+
+pub struct CircuitConfig {
+    /// Wait before the first probe of an upstream pulled for failures.
+    pub cooldown: Duration,
+    /// Cap of the cooldown, doubled after every failed probe.
+    pub max_cooldown: Duration,
+    /// Probes in a row that must succeed to restore the upstream.
+    pub probe_successes: u64,
+    /// Time failing every probe after which an upstream is declared dead,
+    /// `None` to keep probing it at the usual pace forever.
+    pub dead_after: Option<Duration>,
+    /// Time between the probes of a dead upstream.
+    pub dead_probe_interval: Duration,
+}
+
+/// When traffic returns to the regular upstreams after only last-resort
+/// ones were left.
+pub struct FailbackConfig {
+    pub mode: FailbackMode,
+    /// Time the regular upstreams must stay available before traffic shifts
+    /// back to them.
+    pub stabilize: Duration,
+    /// Cap of the stabilization period, doubled after every failback that
+    /// did not hold.
+    pub max_stabilize: Duration,
+    /// Time over which the share of traffic sent back grows to all of it.
+    pub ramp: Duration,
+    /// A fallback within this long of a failback doubles the next
+    /// stabilization period; one held for longer resets it.
+    pub damping_window: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FailbackMode {
+    /// Back as soon as a regular upstream is available.
+    Immediate,
+    /// Back once they are stable, gradually.
+    Auto,
+    /// Held on the last resorts until an admin confirms, then gradually.
+    Manual,
+}
+
+pub struct ErrorRateConfig {
+    /// Seconds of outcomes the rate is computed over.
+    pub window_secs: u64,
+    /// Fraction of attempts failing above which the circuit opens.
+    pub threshold: f64,
+    /// Attempts in the window below which the rate is not acted upon.
+    pub min_requests: u64,
+}
+
+pub struct BodyBudgetConfig {
+    /// Bytes of response bodies kept in memory at once.
+    pub limit: usize,
+    /// How long a body not streamed to a client waits for room.
+    pub wait: Duration,
+}
+
+#[derive(Clone)]
+pub struct WarmupConfig {
+    /// Connections opened to every upstream, 0 to skip the warmup.
+    pub connections: usize,
+    /// Idle time after which an upstream is warmed again, `None` to never
+    /// re-warm.
+    pub keep_warm_after: Option<Duration>,
+}
+
+#[derive(Clone)]
+pub struct EpochCacheConfig {
+    /// How often the epoch info is fetched again from the freshest upstream.
+    pub refresh_interval: Duration,
+    /// Epoch info older than this is passed through rather than served.
+    pub max_age: Duration,
+}
+
+/// What requests get while every upstream that could answer them is
+/// quarantined.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AllQuarantined {
+    /// Sent to the freshest upstreams regardless, with a warning header.
+    ServeBest,
+    /// Answered with a 503 right away.
+    Fail,
+}
+
+/// Which upstreams a race goes to, given the proxy's region.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RegionFanout {
+    /// Every healthy upstream, wherever it is.
+    All,
+    /// The healthy upstreams of the local region and one remote one for
+    /// slot comparison, or the remote ones while no local one is healthy.
+    Local,
+}
+
+impl RegionFanout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RegionFanout::All => "all",
+            RegionFanout::Local => "local",
+        }
+    }
+}
+
+pub struct RegionConfig {
+    /// Region of the proxy, matched against the upstreams' `region`.
+    pub local: String,
+    pub fanout: RegionFanout,
+}
+
+/// What a `sendTransaction` repeating a recently submitted signature gets.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateSends {
+    /// The signature, as the first submission was answered, without
+    /// contacting any upstream.
+    Answer,
+    /// Sent to one upstream instead of all of them.
+    ForwardOne,
+}
+
+#[derive(Clone)]
+pub struct SendDedupConfig {
+    /// How long after its first submission a signature counts as a repeat.
+    pub ttl: Duration,
+    /// Signatures remembered at most; the oldest are forgotten first.
+    pub max_entries: usize,
+    pub duplicates: DuplicateSends,
+}
+
+#[derive(Clone)]
+pub struct DiscoveryConfig {
+    /// How often the upstreams are probed again after startup.
+    pub interval: Duration,
+    /// Longest startup waits for the first probes.
+    pub startup_timeout: Duration,
+    /// Whether DAS support is probed with `getAsset`.
+    pub das: bool,
+    pub duplicates: DuplicatePolicy,
+    /// How often `getIdentity` is sampled again between the rounds.
+    pub identity_interval: Duration,
+}
+
+/// What becomes of an upstream found to be the same node as another.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Logged and counted once, still raced.
+    Warn,
+    /// Logged, counted once and sent no traffic.
+    Alias,
+}
+
+#[derive(Clone)]
+pub struct ClusterConfig {
+    /// The genesis hash every upstream must report; `None` to go by the
+    /// majority.
+    pub genesis_hash: Option<String>,
+    /// Longest startup waits for the upstreams' genesis hashes.
+    pub startup_timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct VersionsConfig {
+    /// How often the upstreams are asked `getVersion` again.
+    pub refresh: Duration,
+    /// How far apart the releases of healthy upstreams may be.
+    pub allowed_skew: VersionSkew,
+    pub min_version: Option<Version>,
+    pub below_min_version: BelowMinVersion,
+}
+
+/// The least significant part of the version healthy upstreams may differ
+/// in without a warning.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum VersionSkew {
+    /// Not at all.
+    None,
+    Patch,
+    Minor,
+    /// In any part, never warned of.
+    Major,
+}
+
+#[derive(Clone)]
+pub struct RankingConfig {
+    /// Hours each score is computed over, at most `MAX_RANKING_HOURS`.
+    pub windows: Vec<u64>,
+    pub weights: RankingWeights,
+    pub route_by: RouteBy,
+}
+
+/// Weights of the components in the composite score, relative to each
+/// other.
+#[derive(Clone, Copy)]
+pub struct RankingWeights {
+    pub success: f64,
+    pub latency: f64,
+    pub lag: f64,
+    pub quarantine: f64,
+}
+
+/// The order single and hedged requests try the upstreams in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RouteBy {
+    /// Fastest last answer first.
+    Latency,
+    /// Highest score over the first window first.
+    Score,
+}
+
+/// What becomes of an upstream running below the minimum version.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BelowMinVersion {
+    /// Logged, still raced.
+    Warn,
+    /// Logged and quarantined, asked only when every upstream is.
+    Quarantine,
+}
+
+#[derive(Clone)]
+pub struct UnsupportedMethodsConfig {
+    /// How long a method an upstream lacks is kept from it.
+    pub ttl: Duration,
+    /// How often upstreams are asked `getVersion`, to notice upgrades.
+    pub version_check_interval: Duration,
+}
+
+#[derive(Clone)]
+pub struct TxTrackingConfig {
+    /// How long submitted transactions are followed until finalized.
+    pub track_for: Duration,
+    /// How often the statuses of followed transactions are fetched.
+    pub poll_interval: Duration,
+    /// Transactions followed at most; further submissions are not tracked.
+    pub max_tracked: usize,
+}
+
+pub struct LocalGetSlotConfig {
+    /// Age the freshest slot observation may have for `getSlot` to be
+    /// answered from it.
+    pub max_age: Duration,
+    /// Slots subtracted from the tracked tip for the `processed`,
+    /// `confirmed` and `finalized` commitments.
+    pub offsets: [u64; 3],
+}
+
+pub struct CoalesceConfig {
+    /// Methods whose identical calls in flight at once share one dispatch.
+    pub methods: HashSet<String>,
+}
+
+/// Reads commonly polled by many clients at once, coalesced by default.
+const COALESCED_METHODS: [&str; 6] = [
+    "getAccountInfo",
+    "getBalance",
+    "getMultipleAccounts",
+    "getTokenAccountBalance",
+    "getBlock",
+    "getTransaction",
+];
+
+pub struct StrictFreshnessConfig {
+    /// Methods whose answers are always checked.
+    pub methods: HashSet<String>,
+    /// Header with which a client turns the check on, or off with `0` or
+    /// `false`, for its request.
+    pub header: HeaderName,
+    /// Slots below the tip answers at the `processed`, `confirmed` and
+    /// `finalized` commitments may be, before the tolerance.
+    pub offsets: [u64; 3],
+}
+
+impl StrictFreshnessConfig {
+    /// Whether the client asked for the check in `header`, `None` when it
+    /// did not say.
+    pub fn requested(&self, headers: &HeaderMap) -> Option<bool> {
+        let value = headers.get(&self.header)?.to_str().ok()?;
+        Some(!matches!(value.trim(), "0" | "false"))
+    }
+
+    /// Whether a request calling `methods` is checked, the client's
+    /// choice first.
+    pub fn applies(&self, requested: Option<bool>, methods: &[String]) -> bool {
+        requested.unwrap_or_else(|| methods.iter().any(|method| self.methods.contains(method)))
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockhashConfig {
+    /// Blocks an upstream's `lastValidBlockHeight` may trail the freshest
+    /// recent one by.
+    pub margin_blocks: u64,
+    /// Stale answers in a row after which an upstream is flagged.
+    pub strikes: u64,
+    /// Whether flagged upstreams are quarantined until they catch up.
+    pub quarantine: bool,
+    /// How often every upstream is asked for `getLatestBlockhash`, `None`
+    /// to only watch client traffic.
+    pub probe_interval: Option<Duration>,
+}
+
+pub struct SlotsConfig {
+    /// How often every upstream is asked for `getSlot`.
+    pub poll_interval: Duration,
+    /// Observations older than this no longer count towards lag decisions.
+    pub max_age: Duration,
+    /// Silence after which a WebSocket slot feed is dropped and the
+    /// upstream polled again.
+    pub ws_stale_after: Duration,
+    /// Longest wait between two WebSocket reconnection attempts.
+    pub ws_max_backoff: Duration,
+    /// How far behind the tip a host may be, see `tolerance`.
+    pub tolerance: Tolerance,
+}
+
+/// `[slots] tolerance`: a number of slots, or a time such as `"3s"`.
+#[derive(Clone, Copy)]
+pub enum Tolerance {
+    Slots(u64),
+    Time(Duration),
+}
+
+/// `"3s"`, `"1.5s"` or `"2500ms"`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.trim().strip_suffix("ms") {
+        Some(number) => (number, 1e-3),
+        None => (text.trim().strip_suffix('s')?, 1.0),
+    };
+    let secs = number.trim().parse::<f64>().ok()? * unit;
+    (secs.is_finite() && secs > 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Hours of upstream history kept for `[ranking]`, a week.
+pub const MAX_RANKING_HOURS: u64 = 7 * 24;
+impl Config {
+    /// Config equivalent to the positional `<PORT> <URL1> <URL2> ...` form.
+    pub fn from_args(port: &str, urls: &[String]) -> Result<Self> {
+        let port = port
+            .parse()
+            .map_err(|_| ConfigError::new(format!("invalid port '{}'", port)))?;
+        let seen = RefCell::new(HashSet::new());
+        let mut config = Self::from_value(&Value::Object(Map::new()), &seen)?;
+        config.port = port;
+        config.upstreams = upstreams::from_urls(urls)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new(format!("cannot read {}: {}", path, e)))?;
+        Self::from_text(&text, Some(path))
+    }
+
+    /// Config from the text of a config file, for configs kept in memory.
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::from_text(text, None)
+    }
+
+    /// Errors name `origin` and the line of the offending key.
+    fn from_text(text: &str, origin: Option<&str>) -> Result<Self> {
+        let (value, lines) = toml::parse_with_lines(text).map_err(|e| match origin {
+            Some(path) => ConfigError::new(format!("{}: {}", path, e)),
+            None => ConfigError::new(e.to_string()),
+        })?;
+        let seen = RefCell::new(HashSet::new());
+        let config = Self::from_value(&value, &seen)
+            .and_then(|config| {
+                let mut unknown = Vec::new();
+                unknown_keys(&value, "", &seen.borrow(), &mut unknown);
+                // the first one in the file
+                if let Some(err) = unknown.into_iter().min_by_key(|e| e.line(&lines)) {
+                    return Err(err);
+                }
+                if config.upstreams.is_empty() {
+                    return Err(ConfigError::new(
+                        "at least one [[upstreams]] entry is required",
+                    ));
+                }
+                config.validate()?;
+                Ok(config)
+            })
+            .map_err(|e| e.locate(&lines, origin))?;
+        Ok(config)
+    }
+
+    /// Checks across settings, once every value is read.
+    fn validate(&self) -> Result<()> {
+        let regular = self.upstreams.iter().filter(|u| u.last_resort.is_none());
+        let regular = regular.count();
+        if regular == 0 {
+            return Err(ConfigError::at(
+                "upstreams".to_string(),
+                "every upstream is a last resort, at least one must be regular".to_string(),
+            ));
+        }
+        if self.min_healthy > regular {
+            return Err(ConfigError::at(
+                "min_healthy".to_string(),
+                format!(
+                    "'min_healthy' is {} but only {} regular upstreams are configured",
+                    self.min_healthy, regular
+                ),
+            ));
+        }
+        // the deadline abandons an upstream request before it could time out
+        for (method, timeout) in &self.routing.timeouts {
+            let deadline = self.deadlines.deadline(std::slice::from_ref(method));
+            if *timeout > deadline {
+                return Err(ConfigError::at(
+                    format!("routing.{}.request_timeout_ms", method),
+                    format!(
+                        "'request_timeout_ms' of {} is {} but its deadline is {} ms",
+                        method,
+                        timeout.as_millis(),
+                        deadline.as_millis()
+                    ),
+                ));
+            }
+        }
+        if self.min_observations < 2 {
+            return Err(ConfigError::at(
+                "min_observations".to_string(),
+                "'min_observations' must be at least 2, a host is compared with the others"
+                    .to_string(),
+            ));
+        }
+        let genesis_hash = self.cluster.as_ref().and_then(|c| c.genesis_hash.as_ref());
+        if let Some(hash) = genesis_hash {
+            if crate::base58::decode(hash).map(|bytes| bytes.len()) != Some(32) {
+                return Err(ConfigError::at(
+                    "cluster.genesis_hash".to_string(),
+                    format!("'genesis_hash' {} is not a base58 32-byte hash", hash),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn from_value(value: &Value, seen: &RefCell<HashSet<String>>) -> Result<Self> {
+        let root = Table::root(value, seen)?;
+
+        let upstreams = upstreams::parse(&root)?;
+        let usage = root.table("usage")?;
+        let usage = UsageConfig {
+            max_clients: usage.integer("max_clients", 1000)? as usize,
+            top: usage.integer("top", 20)? as usize,
+        };
+        let costs = routing::costs(&root)?;
+        let deadlines = routing::deadlines(&root)?;
+        let response_limits = routing::response_limits(&root)?;
+        let routing_table = routing::routing(&root)?;
+        let capabilities = routing::capabilities(&root, &upstreams)?;
+
+        let retention_slots = root.table("history")?.integer("retention_slots", 400_000)?;
+        let min_context = root.table("min_context_slot")?;
+        let margin = min_context.integer("margin_slots", 20)?;
+        let min_context_margin = min_context.boolean("enabled", false)?.then_some(margin);
+
+        let strict_freshness = root.table("strict_freshness")?;
+        let strict_freshness_settings = StrictFreshnessConfig {
+            methods: strict_freshness.strings("methods")?.into_iter().collect(),
+            header: strict_freshness.header_name("header", "x-strict-freshness")?,
+            offsets: [
+                strict_freshness.integer("processed_offset", 0)?,
+                strict_freshness.integer("confirmed_offset", 0)?,
+                strict_freshness.integer("finalized_offset", 32)?,
+            ],
+        };
+        let strict_freshness = strict_freshness
+            .boolean("enabled", false)?
+            .then_some(strict_freshness_settings);
+
+        let soft_deadline = root.table("soft_deadline")?;
+        let after_first = Duration::from_millis(soft_deadline.integer("after_first_ms", 300)?);
+        let soft_deadline = soft_deadline
+            .boolean("enabled", false)?
+            .then_some(after_first);
+
+        let retry = routing::retry(&root)?;
+        let priorities = routing::priorities(&root)?;
+        let load_shedding = routing::load_shedding(&root)?;
+
+        let slots = root.table("slots")?;
+        let slots = SlotsConfig {
+            poll_interval: Duration::from_millis(slots.integer("poll_interval_ms", 1000)?.max(1)),
+            max_age: Duration::from_millis(slots.integer("max_age_ms", 10_000)?),
+            ws_stale_after: Duration::from_millis(slots.integer("ws_stale_ms", 5000)?.max(1)),
+            ws_max_backoff: Duration::from_millis(
+                slots.integer("ws_max_backoff_ms", 30_000)?.max(1),
+            ),
+            tolerance: match slots.get("tolerance") {
+                None => Tolerance::Slots(crate::tolerance::DEFAULT_SLOTS),
+                Some(value) => match (value.as_u64(), value.as_str().and_then(parse_duration)) {
+                    (Some(slots), _) => Tolerance::Slots(slots),
+                    (_, Some(time)) => Tolerance::Time(time),
+                    _ => return slots.invalid("tolerance", "slots, or a time such as \"3s\""),
+                },
+            },
+        };
+
+        let epoch_cache = root.table("epoch_cache")?;
+        let epoch_cache = EpochCacheConfig {
+            refresh_interval: Duration::from_secs(
+                epoch_cache.integer("refresh_interval_secs", 10)?.max(1),
+            ),
+            max_age: Duration::from_secs(epoch_cache.integer("max_age_secs", 30)?),
+        };
+        let epoch_cache = root
+            .table("epoch_cache")?
+            .boolean("enabled", true)?
+            .then_some(epoch_cache);
+
+        let send_dedup = root.table("send_dedup")?;
+        let duplicates = match send_dedup.string("duplicates", "answer")?.as_str() {
+            "answer" => DuplicateSends::Answer,
+            "forward_one" => DuplicateSends::ForwardOne,
+            _ => return send_dedup.invalid("duplicates", "answer or forward_one"),
+        };
+        let send_dedup = SendDedupConfig {
+            ttl: Duration::from_millis(send_dedup.integer("ttl_ms", 2000)?),
+            max_entries: send_dedup.integer("max_entries", 10_000)?.max(1) as usize,
+            duplicates,
+        };
+        let send_dedup = root
+            .table("send_dedup")?
+            .boolean("enabled", true)?
+            .then_some(send_dedup);
+
+        let probing = root.table("discovery")?;
+        let discovery = DiscoveryConfig {
+            interval: Duration::from_secs(probing.integer("interval_secs", 3600)?.max(1)),
+            startup_timeout: Duration::from_millis(probing.integer("startup_timeout_ms", 3000)?),
+            das: probing.boolean("das", false)?,
+            duplicates: match probing.string("duplicate_upstreams", "warn")?.as_str() {
+                "warn" => DuplicatePolicy::Warn,
+                "alias" => DuplicatePolicy::Alias,
+                _ => return probing.invalid("duplicate_upstreams", "warn or alias"),
+            },
+            identity_interval: Duration::from_secs(
+                probing.integer("identity_interval_secs", 300)?.max(1),
+            ),
+        };
+        let discovery = probing.boolean("enabled", true)?.then_some(discovery);
+
+        let membership = root.table("cluster")?;
+        let cluster = ClusterConfig {
+            genesis_hash: membership.optional_string("genesis_hash")?,
+            startup_timeout: Duration::from_millis(membership.integer("startup_timeout_ms", 3000)?),
+        };
+        let cluster = membership.boolean("verify", true)?.then_some(cluster);
+
+        let releases = root.table("versions")?;
+        let min_version = match releases.optional_string("min_version")? {
+            Some(text) => match Version::parse(&text) {
+                Some(version) => Some(version),
+                None => return releases.invalid("min_version", "a version such as \"1.18.22\""),
+            },
+            None => None,
+        };
+        let versions = VersionsConfig {
+            refresh: Duration::from_secs(releases.integer("refresh_secs", 600)?.max(1)),
+            allowed_skew: match releases.string("allowed_skew", "patch")?.as_str() {
+                "none" => VersionSkew::None,
+                "patch" => VersionSkew::Patch,
+                "minor" => VersionSkew::Minor,
+                "major" => VersionSkew::Major,
+                _ => return releases.invalid("allowed_skew", "none, patch, minor or major"),
+            },
+            min_version,
+            below_min_version: match releases.string("below_min_version", "warn")?.as_str() {
+                "warn" => BelowMinVersion::Warn,
+                "quarantine" => BelowMinVersion::Quarantine,
+                _ => return releases.invalid("below_min_version", "warn or quarantine"),
+            },
+        };
+        let versions = releases.boolean("enabled", true)?.then_some(versions);
+
+        let ranks = root.table("ranking")?;
+        let windows = match ranks.get("windows_hours") {
+            None => vec![1, 24, 168],
+            Some(Value::Array(items)) => {
+                let hours = items.iter().map(Value::as_u64);
+                let hours = hours.map(|h| h.filter(|h| (1..=MAX_RANKING_HOURS).contains(h)));
+                match hours.collect::<Option<Vec<u64>>>() {
+                    Some(hours) if !hours.is_empty() => hours,
+                    _ => return ranks.invalid("windows_hours", "hours from 1 to 168"),
+                }
+            }
+            Some(_) => return ranks.invalid("windows_hours", "an array of hours"),
+        };
+        let weights = [
+            ranks.float("success_weight", 0.4)?,
+            ranks.float("latency_weight", 0.3)?,
+            ranks.float("lag_weight", 0.2)?,
+            ranks.float("quarantine_weight", 0.1)?,
+        ];
+        if weights.iter().any(|w| *w < 0.0) || weights.iter().sum::<f64>() <= 0.0 {
+            return ranks.invalid("success_weight", "non-negative weights, not all 0");
+        }
+        let ranking = RankingConfig {
+            windows,
+            weights: RankingWeights {
+                success: weights[0],
+                latency: weights[1],
+                lag: weights[2],
+                quarantine: weights[3],
+            },
+            route_by: match ranks.string("route_by", "latency")?.as_str() {
+                "latency" => RouteBy::Latency,
+                "score" => RouteBy::Score,
+                _ => return ranks.invalid("route_by", "latency or score"),
+            },
+        };
+        let ranking = ranks.boolean("enabled", true)?.then_some(ranking);
+
+        let unsupported = root.table("unsupported_methods")?;
+        let unsupported_methods = UnsupportedMethodsConfig {
+            ttl: Duration::from_secs(unsupported.integer("ttl_secs", 6 * 3600)?.max(1)),
+            version_check_interval: Duration::from_secs(
+                unsupported.integer("version_check_secs", 300)?.max(1),
+            ),
+        };
+        let unsupported_methods = unsupported
+            .boolean("enabled", true)?
+            .then_some(unsupported_methods);
+
+        let tx_tracking = root.table("tx_tracking")?;
+        let tx_tracking = TxTrackingConfig {
+            track_for: Duration::from_secs(tx_tracking.integer("track_secs", 120)?),
+            poll_interval: Duration::from_millis(
+                tx_tracking.integer("poll_interval_ms", 2000)?.max(100),
+            ),
+            max_tracked: tx_tracking.integer("max_tracked", 10_000)? as usize,
+        };
+        let tx_tracking = root
+            .table("tx_tracking")?
+            .boolean("enabled", true)?
+            .then_some(tx_tracking);
+
+        let local_get_slot = root.table("local_get_slot")?;
+        let local_get_slot_settings = LocalGetSlotConfig {
+            max_age: Duration::from_millis(local_get_slot.integer("max_age_ms", 500)?),
+            offsets: [
+                local_get_slot.integer("processed_offset", 0)?,
+                local_get_slot.integer("confirmed_offset", 0)?,
+                local_get_slot.integer("finalized_offset", 0)?,
+            ],
+        };
+        let local_get_slot = local_get_slot
+            .boolean("enabled", false)?
+            .then_some(local_get_slot_settings);
+
+        let coalescing = root.table("coalesce")?;
+        let coalesce = CoalesceConfig {
+            methods: match coalescing.get("methods") {
+                Some(_) => coalescing.strings("methods")?.into_iter().collect(),
+                None => COALESCED_METHODS.map(str::to_string).into_iter().collect(),
+            },
+        };
+        let coalesce = coalescing.boolean("enabled", false)?.then_some(coalesce);
+
+        let blockhash = root.table("blockhash")?;
+        let probe_interval = match blockhash.integer("probe_interval_secs", 0)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let quarantine = blockhash.boolean("quarantine", false)?;
+        if quarantine && probe_interval.is_none() {
+            // quarantined upstreams get no client traffic to recover with
+            return Err(ConfigError::at(
+                "blockhash.quarantine".to_string(),
+                "'blockhash.quarantine' needs a 'probe_interval_secs' above 0",
+            ));
+        }
+        let blockhash_settings = BlockhashConfig {
+            margin_blocks: blockhash.integer("margin_blocks", 75)?,
+            strikes: blockhash.integer("strikes", 3)?.max(1),
+            quarantine,
+            probe_interval,
+        };
+        let blockhash = blockhash
+            .boolean("enabled", true)?
+            .then_some(blockhash_settings);
+
+        let warmup = root.table("warmup")?;
+        let warmup = WarmupConfig {
+            connections: warmup.integer("connections", 4)? as usize,
+            keep_warm_after: match warmup.integer("keep_warm_after_secs", 60)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        };
+
+        let circuit = root.table("circuit_breaker")?;
+        let cooldown = Duration::from_millis(circuit.integer("cooldown_ms", 5000)?);
+        let circuit = CircuitConfig {
+            cooldown,
+            max_cooldown: Duration::from_millis(circuit.integer("max_cooldown_ms", 60_000)?)
+                .max(cooldown),
+            probe_successes: circuit.integer("probe_successes", 3)?.max(1),
+            dead_after: match circuit.integer("dead_after_secs", 6 * 3600)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            dead_probe_interval: Duration::from_secs(
+                circuit.integer("dead_probe_interval_secs", 3600)?.max(1),
+            ),
+        };
+
+        let failback = root.table("failback")?;
+        let stabilize = Duration::from_secs(failback.integer("stabilize_secs", 30)?);
+        let failback = FailbackConfig {
+            mode: match failback.string("mode", "immediate")?.as_str() {
+                "immediate" => FailbackMode::Immediate,
+                "auto" => FailbackMode::Auto,
+                "manual" => FailbackMode::Manual,
+                _ => return failback.invalid("mode", "immediate, auto or manual"),
+            },
+            stabilize,
+            max_stabilize: Duration::from_secs(failback.integer("max_stabilize_secs", 600)?)
+                .max(stabilize),
+            ramp: Duration::from_secs(failback.integer("ramp_secs", 30)?),
+            damping_window: Duration::from_secs(failback.integer("damping_window_secs", 300)?),
+        };
+
+        let fanout = match root.string("region_fanout", "local")?.as_str() {
+            "all" => RegionFanout::All,
+            "local" => RegionFanout::Local,
+            _ => return root.invalid("region_fanout", "all or local"),
+        };
+        let region = match root.optional_string("local_region")? {
+            Some(local) if !upstreams.iter().any(|u| u.region.as_ref() == Some(&local)) => {
+                return Err(ConfigError::at(
+                    "local_region".to_string(),
+                    format!("no upstream has the local region '{}'", local),
+                ));
+            }
+            local => local.map(|local| RegionConfig { local, fanout }),
+        };
+
+        let error_rate = root.table("error_rate")?;
+        let window_secs = error_rate.integer("window_secs", 30)?;
+        if !(1..=crate::upstream::MAX_WINDOW_SECS).contains(&window_secs) {
+            return error_rate.invalid("window_secs", "between 1 and 300");
+        }
+        let error_rate_settings = ErrorRateConfig {
+            window_secs,
+            threshold: match error_rate.float("threshold", 0.2)? {
+                threshold if threshold > 0.0 && threshold <= 1.0 => threshold,
+                _ => return error_rate.invalid("threshold", "above 0 and at most 1"),
+            },
+            min_requests: error_rate.integer("min_requests", 20)?.max(1),
+        };
+        let error_rate = error_rate
+            .boolean("enabled", true)?
+            .then_some(error_rate_settings);
+
+        let body_budget = root.table("body_budget")?;
+        let body_budget = BodyBudgetConfig {
+            limit: match body_budget.optional_cap("max_mb")? {
+                Some(mb) => mb << 20,
+                None => crate::body_budget::default_limit(),
+            },
+            wait: Duration::from_millis(body_budget.integer("wait_ms", 50)?),
+        };
+
+        let statsd = outputs::statsd(&root)?;
+        let status_export = outputs::status_export(&root)?;
+        let access_log = outputs::access_log(&root)?;
+        let tx_journal = outputs::tx_journal(&root)?;
+        let mirror = outputs::mirror(&root)?;
+        let auth = access::auth(&root)?;
+        let ip_rate_limit = access::ip_rate_limit(&root)?;
+        let acl = access::acl(&root)?;
+
+        Ok(Self {
+            port: root.integer("port", 8080)? as u16,
+            upstreams,
+            min_healthy: root.integer("min_healthy", 1)? as usize,
+            min_observations: root.integer("min_observations", 2)? as usize,
+            all_quarantined: match root.string("all_quarantined", "serve_best")?.as_str() {
+                "serve_best" => AllQuarantined::ServeBest,
+                "fail" => AllQuarantined::Fail,
+                _ => return root.invalid("all_quarantined", "serve_best or fail"),
+            },
+            region,
+            client_ip: ClientIpConfig {
+                trust_forwarded_for: root.boolean("trust_forwarded_for", false)?,
+                trusted_proxies: root.networks("trusted_proxies")?,
+            },
+            request_id_header: root.header_name("request_id_header", "x-request-id")?,
+            debug_headers: root.boolean("debug_headers", false)?,
+            log_bodies: root.boolean("log_bodies", false)?,
+            aggregate_get_health: root.boolean("aggregate_get_health", true)?,
+            auth,
+            methods: MethodPolicy {
+                allow: root.table("methods")?.strings("allow")?,
+                deny: root.table("methods")?.strings("deny")?,
+            },
+            ip_rate_limit,
+            max_concurrent_per_client: root.optional_cap("max_concurrent_per_client")?,
+            max_lingering_dispatches: root.integer("max_lingering_dispatches", 1024)? as usize,
+            priorities,
+            load_shedding,
+            acl,
+            usage,
+            costs,
+            deadlines,
+            response_limits,
+            routing: routing_table,
+            capabilities,
+            retention_slots,
+            min_context_margin,
+            strict_freshness,
+            soft_deadline,
+            retry,
+            slots,
+            epoch_cache,
+            local_get_slot,
+            coalesce,
+            send_dedup,
+            discovery,
+            cluster,
+            versions,
+            ranking,
+            unsupported_methods,
+            tx_tracking,
+            blockhash,
+            warmup,
+            circuit,
+            failback,
+            error_rate,
+            body_budget,
+            dns_refresh: match root.table("dns")?.integer("refresh_interval_secs", 60)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            maintenance_lead: Duration::from_secs(
+                root.table("maintenance")?
+                    .integer("drain_before_secs", 120)?,
+            ),
+            summary_interval: match root.integer("summary_interval_secs", 60)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            statsd,
+            status_export,
+            state_file: root.optional_string("state_file")?,
+            alerts: AlertsConfig {
+                webhook_url: root.table("alerts")?.optional_string("webhook_url")?,
+            },
+            access_log,
+            tx_journal,
+            mirror,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) const UPSTREAM: &str = "[[upstreams]]\nurl = \"http://127.0.0.1:8899\"\n";
+
+    pub(super) fn error(text: &str) -> String {
+        Config::parse(text)
+            .err()
+            .expect("the config is invalid")
+            .to_string()
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_at_their_line_with_a_suggestion() {
+        let text = format!("port = 8080\nsumary_interval_secs = 5\n{}", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 2: unknown key 'sumary_interval_secs', did you mean 'summary_interval_secs'?"
+        );
+        let text = format!("{}\n[slots]\npoll_interval = 300\n", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 5: unknown key 'slots.poll_interval', did you mean 'slots.poll_interval_ms'?"
+        );
+        let text = format!("{}nmae = \"a\"\n", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 3: unknown key 'upstreams[0].nmae', did you mean 'upstreams[0].name'?"
+        );
+        let text = format!(
+            "{}frobnicate = true\n",
+            UPSTREAM.replace("[[upstreams]]", "[warmup]\n[[upstreams]]")
+        );
+        assert!(
+            error(&text).ends_with("unknown key 'upstreams[0].frobnicate'"),
+            "{}",
+            error(&text)
+        );
+    }
+
+    #[test]
+    fn top_level_keys_after_a_table_are_pointed_out() {
+        let text = format!("{}\n[slots]\nport = 8080\n", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 5: unknown key 'slots.port', did you mean the top-level 'port'? Top-level keys go before the first [table]"
+        );
+    }
+
+    #[test]
+    fn the_first_unknown_key_in_the_file_is_reported() {
+        let text = format!("{}\n[slots]\nzzz = 1\n\n[warmup]\naaa = 1\n", UPSTREAM);
+        assert!(
+            error(&text).contains("line 5: unknown key 'slots.zzz'"),
+            "{}",
+            error(&text)
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_their_key_and_line() {
+        let text = format!("port = \"eighty\"\n{}", UPSTREAM);
+        assert!(
+            error(&text).starts_with("config error: line 1: 'port' must be"),
+            "{}",
+            error(&text)
+        );
+        let text = format!("{}\n[slots]\npoll_interval_ms = -1\n", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 5: 'slots.poll_interval_ms' must be a non-negative integer"
+        );
+        let text = format!("all_quarantined = \"panic\"\n{}", UPSTREAM);
+        assert_eq!(
+            error(&text),
+            "config error: line 1: 'all_quarantined' must be serve_best or fail"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_and_missing_upstreams_are_reported() {
+        assert!(error("port = [1,\n").starts_with("config error: "));
+        assert_eq!(
+            error("port = 8080\n"),
+            "config error: at least one [[upstreams]] entry is required"
+        );
+    }
+
+    #[test]
+    fn files_are_named_in_their_errors() {
+        let path = std::env::temp_dir().join(format!("quarantier-{}.toml", crate::random::u64()));
+        std::fs::write(&path, format!("{}nmae = \"a\"\n", UPSTREAM)).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let err = Config::load(&path).err().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            err.starts_with(&format!("config error: {}: line 3: ", path)),
+            "{}",
+            err
+        );
+        let err = Config::load("/nonexistent/quarantier.toml")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.starts_with("config error: cannot read /nonexistent/quarantier.toml"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn deadline_headers_take_milliseconds_or_grpc_timeouts() {
+        let text = format!("[deadlines]\nheader = \"grpc-timeout\"\n{}", UPSTREAM);
+        let deadlines = Config::parse(&text).unwrap().deadlines;
+        let requested = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("grpc-timeout", value.parse().unwrap());
+            deadlines.requested(&headers)
+        };
+        assert_eq!(requested("250"), Some(Duration::from_millis(250)));
+        assert_eq!(requested(" 300m "), Some(Duration::from_millis(300)));
+        assert_eq!(requested("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(requested("1M"), Some(Duration::from_secs(60)));
+        assert_eq!(requested("1500u"), Some(Duration::from_micros(1500)));
+        for malformed in ["", "soon", "5x", "123456789m", "-5"] {
+            assert_eq!(requested(malformed), None, "{}", malformed);
+        }
+        assert_eq!(deadlines.requested(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn method_timeouts_fit_within_their_deadline() {
+        let text = format!(
+            "[deadlines]\ngetProgramAccounts = 30000\n[routing]\ngetSlot = {{ request_timeout_ms = 1000 }}\n\
+             getProgramAccounts = {{ request_timeout_ms = 25000 }}\n{}",
+            UPSTREAM
+        );
+        let routing = Config::parse(&text).unwrap().routing;
+        let timeout = |methods: &[&str]| {
+            let methods: Vec<String> = methods.iter().map(|m| m.to_string()).collect();
+            routing.timeout(&methods)
+        };
+        assert_eq!(timeout(&["getSlot"]), Some(Duration::from_secs(1)));
+        assert_eq!(timeout(&["getBalance"]), None);
+        // a batch gets its slowest call's, or the upstream's own
+        assert_eq!(
+            timeout(&["getSlot", "getProgramAccounts"]),
+            Some(Duration::from_secs(25))
+        );
+        assert_eq!(timeout(&["getSlot", "getBalance"]), None);
+        let message = error(&format!(
+            "[routing]\ngetBlock = {{ request_timeout_ms = 15000 }}\n{}",
+            UPSTREAM
+        ));
+        assert!(
+            message
+                .contains("'request_timeout_ms' of getBlock is 15000 but its deadline is 10000 ms"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Who may use the proxy: client addresses, ACLs, per-address rate limits,
+//! API keys with their quotas and JWT validation.
+
+use super::routing::Priority;
+use super::table::Table;
+use super::{ConfigError, Result};
+use crate::methods::MethodPolicy;
+use crate::ratelimit::Limit;
+use axum::http::HeaderName;
+use ipnet::IpNet;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct ClientIpConfig {
+    /// Identify clients by the first `x-forwarded-for` entry instead of the
+    /// peer address. Only safe behind a proxy that overwrites the header.
+    pub trust_forwarded_for: bool,
+    /// Peers whose forwarding headers are believed.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Clone, Default)]
+pub struct AclConfig {
+    /// Networks allowed to connect; empty allows everyone.
+    pub allow: Vec<IpNet>,
+    /// Networks always rejected, even when also allowed.
+    pub deny: Vec<IpNet>,
+}
+
+pub struct IpRateLimitConfig {
+    pub limit: Limit,
+    /// Addresses tracked before the least recently seen are forgotten.
+    pub max_clients: usize,
+}
+
+pub struct AuthConfig {
+    /// Header carrying the key; `authorization` expects `Bearer <key>`.
+    pub header: HeaderName,
+    /// Accepted keys. None configured means no authentication.
+    pub keys: Vec<ApiKeyConfig>,
+    /// Limit of keys that do not set their own, and of JWT identities;
+    /// `None` is unlimited.
+    pub rate_limit: Option<Limit>,
+    /// JWT validation, `None` unless a secret or JWKS URL is configured.
+    pub jwt: Option<JwtConfig>,
+    /// UTC offset at which quota windows turn over.
+    pub quota_utc_offset: i64,
+    /// Fraction of a quota after which a warning is sent.
+    pub quota_soft_limit: f64,
+}
+
+#[derive(Clone)]
+pub struct JwtConfig {
+    /// HS256 shared secret.
+    pub secret: Option<String>,
+    /// Source of RS256 keys.
+    pub jwks_url: Option<String>,
+    pub jwks_refresh: Duration,
+    /// Required `iss`, when set.
+    pub issuer: Option<String>,
+    /// Required in `aud`, when set.
+    pub audience: Option<String>,
+    /// Claim naming the client for rate limiting and usage accounting.
+    pub identity_claim: String,
+    /// Clock skew tolerated on `exp` and `nbf`.
+    pub leeway: Duration,
+}
+
+pub struct ApiKeyConfig {
+    /// Identity used in logs and usage accounting instead of the secret.
+    pub name: String,
+    pub key: String,
+    pub rate_limit: Option<Limit>,
+    /// Bypass rate limiting entirely.
+    pub unlimited: bool,
+    pub methods: MethodPolicy,
+    pub quota: Option<Quota>,
+    /// Overrides `max_concurrent_per_client` for this key.
+    pub max_concurrent: Option<usize>,
+    /// Allowed on the admin endpoints and to use the `x-quarantier-*`
+    /// debugging headers.
+    pub admin: bool,
+    /// Class of all the key's requests, whatever they call.
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum QuotaWindow {
+    Daily,
+    Monthly,
+}
+
+impl QuotaWindow {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuotaWindow::Daily => "daily",
+            QuotaWindow::Monthly => "monthly",
+        }
+    }
+}
+
+/// Requests allowed per calendar window.
+#[derive(Clone, Copy)]
+pub struct Quota {
+    pub limit: u64,
+    pub window: QuotaWindow,
+}
+
+/// `[auth]` and its `[[auth.keys]]`.
+pub(super) fn auth(root: &Table) -> Result<AuthConfig> {
+    let auth = root.table("auth")?;
+    let mut keys: Vec<ApiKeyConfig> = Vec::new();
+    for table in auth.tables("keys")? {
+        let key = ApiKeyConfig {
+            name: table.required_string("name")?,
+            key: table.required_string("key")?,
+            rate_limit: table.rate_limit()?,
+            unlimited: table.boolean("unlimited", false)?,
+            methods: MethodPolicy {
+                allow: table.strings("allow_methods")?,
+                deny: table.strings("deny_methods")?,
+            },
+            max_concurrent: table.optional_cap("max_concurrent")?,
+            admin: table.boolean("admin", false)?,
+            priority: match table.optional_string("priority")? {
+                None => None,
+                Some(name) => match Priority::parse(&name) {
+                    Some(class) => Some(class),
+                    None => return table.invalid("priority", "\"high\", \"normal\" or \"low\""),
+                },
+            },
+            quota: match table.get("quota") {
+                None if table.get("quota_window").is_some() => {
+                    return table.invalid("quota_window", "set together with 'quota'")
+                }
+                None => None,
+                Some(_) => Some(Quota {
+                    limit: table.integer("quota", 0)?,
+                    window: match table.string("quota_window", "monthly")?.as_str() {
+                        "daily" => QuotaWindow::Daily,
+                        "monthly" => QuotaWindow::Monthly,
+                        _ => return table.invalid("quota_window", "\"daily\" or \"monthly\""),
+                    },
+                }),
+            },
+        };
+        if keys.iter().any(|other| other.name == key.name) {
+            return Err(ConfigError::at(
+                table.key_path("name"),
+                format!("duplicate API key name '{}'", key.name),
+            ));
+        }
+        if keys.iter().any(|other| other.key == key.key) {
+            return Err(ConfigError::at(
+                table.key_path("key"),
+                format!(
+                    "API keys '{}' and '{}' share the same secret",
+                    keys.iter().find(|other| other.key == key.key).unwrap().name,
+                    key.name
+                ),
+            ));
+        }
+        keys.push(key);
+    }
+    Ok(AuthConfig {
+        header: auth.header_name("header", "x-api-key")?,
+        keys,
+        rate_limit: auth.rate_limit()?,
+        jwt: jwt_config(&auth.table("jwt")?)?,
+        quota_utc_offset: utc_offset(&auth.string("quota_timezone", "UTC")?)?,
+        quota_soft_limit: match auth.float("quota_soft_limit_pct", 80.0)? {
+            pct if (0.0..=100.0).contains(&pct) => pct / 100.0,
+            _ => return auth.invalid("quota_soft_limit_pct", "between 0 and 100"),
+        },
+    })
+}
+
+/// `[ip_rate_limit]`, `None` without an `rps`.
+pub(super) fn ip_rate_limit(root: &Table) -> Result<Option<IpRateLimitConfig>> {
+    let ip_rate_limit = root.table("ip_rate_limit")?;
+    ip_rate_limit.known(&["max_clients"]);
+    Ok(match ip_rate_limit.rate_limit()? {
+        None => None,
+        Some(limit) => Some(IpRateLimitConfig {
+            limit,
+            max_clients: ip_rate_limit.integer("max_clients", 100_000)?.max(1) as usize,
+        }),
+    })
+}
+
+/// `[acl]`: networks allowed and denied.
+pub(super) fn acl(root: &Table) -> Result<AclConfig> {
+    let acl = root.table("acl")?;
+    Ok(AclConfig {
+        allow: acl.networks("allow")?,
+        deny: acl.networks("deny")?,
+    })
+}
+
+/// Seconds east of UTC of `UTC` or an offset like `+05:30`.
+fn utc_offset(zone: &str) -> Result<i64> {
+    let invalid = || {
+        ConfigError::new(format!(
+            "invalid timezone '{}', expected UTC or +HH:MM",
+            zone
+        ))
+    };
+    let offset = match zone.strip_prefix("UTC").unwrap_or(zone) {
+        "" | "Z" => return Ok(0),
+        offset => offset,
+    };
+    let (sign, rest) = match offset.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i64 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+fn jwt_config(jwt: &Table) -> Result<Option<JwtConfig>> {
+    let secret = jwt.optional_string("secret")?;
+    let jwks_url = jwt.optional_string("jwks_url")?;
+    if secret.is_none() && jwks_url.is_none() {
+        jwt.known(&[
+            "jwks_refresh_secs",
+            "issuer",
+            "audience",
+            "identity_claim",
+            "leeway_secs",
+        ]);
+        return Ok(None);
+    }
+    Ok(Some(JwtConfig {
+        secret,
+        jwks_url,
+        jwks_refresh: Duration::from_secs(jwt.integer("jwks_refresh_secs", 300)?.max(1)),
+        issuer: jwt.optional_string("issuer")?,
+        audience: jwt.optional_string("audience")?,
+        identity_claim: jwt.string("identity_claim", "sub")?,
+        leeway: Duration::from_secs(jwt.integer("leeway_secs", 60)?),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{error, UPSTREAM};
+    use crate::config::Config;
+
+    fn config(sections: &str) -> Config {
+        Config::parse(&format!("{}\n{}", UPSTREAM, sections)).unwrap()
+    }
+
+    fn key(settings: &str) -> String {
+        format!(
+            "{}\n[[auth.keys]]\nname = \"bot\"\nkey = \"k1\"\n{}",
+            UPSTREAM, settings
+        )
+    }
+
+    #[test]
+    fn api_keys_need_unique_names_and_secrets() {
+        let text = format!("{}\n[[auth.keys]]\nname = \"bot\"\nkey = \"k2\"\n", key(""));
+        assert_eq!(
+            error(&text),
+            "config error: line 9: duplicate API key name 'bot'"
+        );
+        let text = format!("{}\n[[auth.keys]]\nname = \"app\"\nkey = \"k1\"\n", key(""));
+        assert_eq!(
+            error(&text),
+            "config error: line 10: API keys 'bot' and 'app' share the same secret"
+        );
+        let text = format!("{}\n[[auth.keys]]\nname = \"app\"\n", UPSTREAM);
+        assert!(error(&text).ends_with("'auth.keys[0].key' is required"));
+    }
+
+    #[test]
+    fn key_settings_are_checked() {
+        let config = Config::parse(&key(
+            "quota = 1000\nquota_window = \"daily\"\npriority = \"low\"\nmax_concurrent = 4\n",
+        ))
+        .unwrap();
+        let bot = &config.auth.keys[0];
+        let quota = bot.quota.unwrap();
+        assert_eq!(quota.limit, 1000);
+        assert!(quota.window == QuotaWindow::Daily);
+        assert_eq!(bot.priority, Some(Priority::Low));
+        assert_eq!(bot.max_concurrent, Some(4));
+        let monthly = Config::parse(&key("quota = 10\n")).unwrap();
+        assert!(monthly.auth.keys[0].quota.unwrap().window == QuotaWindow::Monthly);
+        for (settings, message) in [
+            (
+                "quota_window = \"daily\"\n",
+                "'auth.keys[0].quota_window' must be set together with 'quota'",
+            ),
+            (
+                "quota = 10\nquota_window = \"weekly\"\n",
+                "'auth.keys[0].quota_window' must be \"daily\" or \"monthly\"",
+            ),
+            (
+                "priority = \"urgent\"\n",
+                "'auth.keys[0].priority' must be \"high\", \"normal\" or \"low\"",
+            ),
+            (
+                "max_concurrent = 0\n",
+                "'auth.keys[0].max_concurrent' must be at least 1",
+            ),
+        ] {
+            let text = key(settings);
+            assert!(error(&text).ends_with(message), "{}", error(&text));
+        }
+    }
+
+    #[test]
+    fn quota_timezones_are_utc_offsets() {
+        assert_eq!(utc_offset("UTC").unwrap(), 0);
+        assert_eq!(utc_offset("Z").unwrap(), 0);
+        assert_eq!(utc_offset("+05:30").unwrap(), 5 * 3600 + 30 * 60);
+        assert_eq!(utc_offset("UTC-08:00").unwrap(), -8 * 3600);
+        assert_eq!(utc_offset("+9").unwrap(), 9 * 3600);
+        for zone in ["CET", "+15:00", "+05:60", "05:00", "+"] {
+            assert!(utc_offset(zone).is_err(), "{}", zone);
+        }
+        let config = config("[auth]\nquota_timezone = \"+02:00\"\nquota_soft_limit_pct = 90\n");
+        assert_eq!(config.auth.quota_utc_offset, 7200);
+        assert_eq!(config.auth.quota_soft_limit, 0.9);
+        let text = format!("{}\n[auth]\nquota_soft_limit_pct = 120\n", UPSTREAM);
+        assert!(error(&text).ends_with("'auth.quota_soft_limit_pct' must be between 0 and 100"));
+    }
+
+    #[test]
+    fn jwt_settings_wait_for_a_key_source() {
+        // set ahead of a secret, they are not unknown keys
+        let config =
+            config("[auth.jwt]\nissuer = \"https://issuer.example.com\"\nleeway_secs = 5\n");
+        assert!(config.auth.jwt.is_none());
+        let config = self::config("[auth.jwt]\nsecret = \"s\"\naudience = \"rpc\"\n");
+        let jwt = config.auth.jwt.unwrap();
+        assert_eq!(jwt.secret.as_deref(), Some("s"));
+        assert_eq!(jwt.audience.as_deref(), Some("rpc"));
+        assert_eq!(jwt.identity_claim, "sub");
+        assert_eq!(jwt.leeway, Duration::from_secs(60));
+        assert_eq!(jwt.jwks_refresh, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn addresses_are_limited_and_filtered_only_when_asked() {
+        let defaults = config("[ip_rate_limit]\nmax_clients = 10\n");
+        assert!(defaults.ip_rate_limit.is_none());
+        assert!(defaults.acl.allow.is_empty() && defaults.acl.deny.is_empty());
+        assert!(!defaults.client_ip.trust_forwarded_for);
+        let config = config(
+            "[ip_rate_limit]\nrps = 20\nmax_clients = 0\n\n[acl]\nallow = [\"10.0.0.0/8\"]\ndeny = [\"10.0.0.1\"]\n",
+        );
+        let limit = config.ip_rate_limit.unwrap();
+        assert_eq!((limit.limit.rps, limit.limit.burst), (20.0, 20));
+        assert_eq!(limit.max_clients, 1);
+        assert_eq!(config.acl.allow[0].to_string(), "10.0.0.0/8");
+        assert_eq!(config.acl.deny[0].to_string(), "10.0.0.1/32");
+    }
+}
@@ -0,0 +1,313 @@
+//! What the proxy reports besides its log: alerts, statsd metrics, status
+//! snapshots, the access log, the transaction journal and mirrored traffic.
+
+use super::table::Table;
+use super::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub struct AlertsConfig {
+    /// Receives a JSON POST for every alert, in addition to the log line.
+    pub webhook_url: Option<String>,
+}
+
+/// Periodic copy of the `/status` document in a file, for hosts that
+/// cannot be scraped.
+#[derive(Clone)]
+pub struct StatusExportConfig {
+    pub path: String,
+    pub interval: Duration,
+}
+
+#[derive(Clone)]
+pub struct StatsdConfig {
+    pub address: String,
+    pub flush_interval: Duration,
+    pub prefix: String,
+    /// Tags such as `env:prod` attached to every metric.
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+pub enum AccessLogFormat {
+    Combined,
+    Json,
+}
+
+pub struct AccessLogConfig {
+    /// File to append to, or `stdout`.
+    pub path: String,
+    pub format: AccessLogFormat,
+    /// Rotate once the file would grow past this many bytes; 0 disables it.
+    pub max_size: u64,
+    /// Rotated files kept next to the live one.
+    pub max_files: usize,
+    /// Fraction of requests logged.
+    pub sample_rate: f64,
+}
+
+#[derive(Clone)]
+pub struct TxJournalConfig {
+    pub path: String,
+    /// Rotate once the file would grow past this many bytes; 0 disables it.
+    pub max_size: u64,
+    /// Rotated files kept next to the live one, searched by lookups too.
+    pub max_files: usize,
+    /// Keep the transaction as the client sent it, not only its hash.
+    pub include_transaction: bool,
+}
+
+/// Where mirrored requests go.
+#[derive(Clone)]
+pub enum MirrorSink {
+    /// POSTed in newline-delimited batches.
+    Http(String),
+    /// Appended to a file or named pipe, one per line.
+    File(String),
+}
+
+#[derive(Clone)]
+pub struct MirrorConfig {
+    pub sink: MirrorSink,
+    /// Fraction of requests mirrored, unless their method has its own.
+    pub sample_rate: f64,
+    pub method_rates: HashMap<String, f64>,
+    /// Request bodies longer than this are cut, as a string.
+    pub max_body: usize,
+    /// Records waiting for the sink; beyond this they are dropped.
+    pub queue: usize,
+    /// Methods whose params are replaced before the body leaves.
+    pub redact_methods: Vec<String>,
+    /// Leave the client identity out of the records.
+    pub redact_clients: bool,
+}
+
+/// `[statsd]`, `None` without an `address`.
+pub(super) fn statsd(root: &Table) -> Result<Option<StatsdConfig>> {
+    let statsd = root.table("statsd")?;
+    statsd.known(&["flush_interval_ms", "prefix", "tags"]);
+    Ok(match statsd.optional_string("address")? {
+        None => None,
+        Some(address) => Some(StatsdConfig {
+            address,
+            flush_interval: Duration::from_millis(
+                statsd.integer("flush_interval_ms", 10_000)?.max(1),
+            ),
+            prefix: statsd.string("prefix", "quarantier")?,
+            tags: statsd.strings("tags")?,
+        }),
+    })
+}
+
+/// `[status_export]`, `None` without a `path`.
+pub(super) fn status_export(root: &Table) -> Result<Option<StatusExportConfig>> {
+    let status_export = root.table("status_export")?;
+    status_export.known(&["interval_secs"]);
+    Ok(match status_export.optional_string("path")? {
+        None => None,
+        Some(path) => Some(StatusExportConfig {
+            path,
+            interval: Duration::from_secs(status_export.integer("interval_secs", 10)?.max(1)),
+        }),
+    })
+}
+
+/// `[access_log]`, `None` without a `path`.
+pub(super) fn access_log(root: &Table) -> Result<Option<AccessLogConfig>> {
+    let access_log = root.table("access_log")?;
+    access_log.known(&["format", "max_size_mb", "max_files", "sample_rate"]);
+    Ok(match access_log.optional_string("path")? {
+        None => None,
+        Some(path) => Some(AccessLogConfig {
+            path,
+            format: match access_log.string("format", "combined")?.as_str() {
+                "combined" => AccessLogFormat::Combined,
+                "json" => AccessLogFormat::Json,
+                _ => return access_log.invalid("format", "\"combined\" or \"json\""),
+            },
+            max_size: access_log.integer("max_size_mb", 100)? * 1024 * 1024,
+            max_files: access_log.integer("max_files", 5)? as usize,
+            sample_rate: match access_log.float("sample_rate", 1.0)? {
+                rate if (0.0..=1.0).contains(&rate) => rate,
+                _ => return access_log.invalid("sample_rate", "between 0 and 1"),
+            },
+        }),
+    })
+}
+
+/// `[tx_journal]`, `None` without a `path`.
+pub(super) fn tx_journal(root: &Table) -> Result<Option<TxJournalConfig>> {
+    let tx_journal = root.table("tx_journal")?;
+    tx_journal.known(&["max_size_mb", "max_files", "include_transaction"]);
+    Ok(match tx_journal.optional_string("path")? {
+        None => None,
+        Some(path) => Some(TxJournalConfig {
+            path,
+            max_size: tx_journal.integer("max_size_mb", 100)? * 1024 * 1024,
+            max_files: tx_journal.integer("max_files", 10)? as usize,
+            include_transaction: tx_journal.boolean("include_transaction", false)?,
+        }),
+    })
+}
+
+/// `[mirror]`, `None` without a `url` or `path` to send records to.
+pub(super) fn mirror(root: &Table) -> Result<Option<MirrorConfig>> {
+    let mirror = root.table("mirror")?;
+    let sink = match (
+        mirror.optional_string("url")?,
+        mirror.optional_string("path")?,
+    ) {
+        (None, None) => None,
+        (Some(url), None) => match reqwest::Url::parse(&url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {
+                Some(MirrorSink::Http(url))
+            }
+            _ => return mirror.invalid("url", "an http(s) URL"),
+        },
+        (None, Some(path)) => Some(MirrorSink::File(path)),
+        (Some(_), Some(_)) => return mirror.invalid("path", "absent when url is set"),
+    };
+    let rate = |table: &Table, key: &str, value: Option<&Value>| {
+        let rate = match value {
+            Some(value) => table.expect_float(key, value)?,
+            None => table.float(key, 0.01)?,
+        };
+        match (0.0..=1.0).contains(&rate) {
+            true => Ok(rate),
+            false => table.invalid(key, "between 0 and 1"),
+        }
+    };
+    Ok(match sink {
+        None => {
+            mirror.known(&[
+                "sample_rate",
+                "methods",
+                "max_body_bytes",
+                "queue",
+                "redact_methods",
+                "redact_clients",
+            ]);
+            None
+        }
+        Some(sink) => {
+            let methods = mirror.table("methods")?;
+            let mut method_rates = HashMap::new();
+            for (method, value) in methods.entries() {
+                method_rates.insert(method.clone(), rate(&methods, method, Some(value))?);
+            }
+            Some(MirrorConfig {
+                sink,
+                sample_rate: rate(&mirror, "sample_rate", None)?,
+                method_rates,
+                max_body: mirror.integer("max_body_bytes", 4096)? as usize,
+                queue: mirror.integer("queue", 10_000)?.max(1) as usize,
+                redact_methods: mirror.strings("redact_methods")?,
+                redact_clients: mirror.boolean("redact_clients", false)?,
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{error, UPSTREAM};
+    use crate::config::Config;
+
+    fn config(sections: &str) -> Config {
+        Config::parse(&format!("{}\n{}", UPSTREAM, sections)).unwrap()
+    }
+
+    #[test]
+    fn outputs_are_off_without_a_destination() {
+        // their other settings are accepted, ready for a destination
+        let config = config(
+            "[statsd]\nprefix = \"rpc\"\n\n[status_export]\ninterval_secs = 5\n\n[access_log]\nformat = \"json\"\n\n[tx_journal]\nmax_files = 3\n\n[mirror]\nsample_rate = 0.5\nredact_clients = true\n",
+        );
+        assert!(config.statsd.is_none());
+        assert!(config.status_export.is_none());
+        assert!(config.access_log.is_none());
+        assert!(config.tx_journal.is_none());
+        assert!(config.mirror.is_none());
+        assert!(config.alerts.webhook_url.is_none());
+    }
+
+    #[test]
+    fn outputs_with_a_destination_get_their_defaults() {
+        let config = config(
+            "[statsd]\naddress = \"127.0.0.1:8125\"\ntags = [\"env:test\"]\n\n[status_export]\npath = \"/tmp/status.json\"\n\n[access_log]\npath = \"stdout\"\n\n[tx_journal]\npath = \"/tmp/tx.log\"\n",
+        );
+        let statsd = config.statsd.unwrap();
+        assert_eq!(statsd.prefix, "quarantier");
+        assert_eq!(statsd.tags, ["env:test"]);
+        assert_eq!(statsd.flush_interval, Duration::from_secs(10));
+        assert_eq!(
+            config.status_export.unwrap().interval,
+            Duration::from_secs(10)
+        );
+        let access_log = config.access_log.unwrap();
+        assert!(matches!(access_log.format, AccessLogFormat::Combined));
+        assert_eq!(access_log.max_size, 100 << 20);
+        assert_eq!((access_log.max_files, access_log.sample_rate), (5, 1.0));
+        let tx_journal = config.tx_journal.unwrap();
+        assert_eq!((tx_journal.max_size, tx_journal.max_files), (100 << 20, 10));
+        assert!(!tx_journal.include_transaction);
+    }
+
+    #[test]
+    fn access_log_settings_are_checked() {
+        for (settings, message) in [
+            (
+                "format = \"xml\"\n",
+                "'access_log.format' must be \"combined\" or \"json\"",
+            ),
+            (
+                "sample_rate = 1.5\n",
+                "'access_log.sample_rate' must be between 0 and 1",
+            ),
+        ] {
+            let text = format!(
+                "{}\n[access_log]\npath = \"stdout\"\n{}",
+                UPSTREAM, settings
+            );
+            assert!(error(&text).ends_with(message), "{}", error(&text));
+        }
+    }
+
+    #[test]
+    fn mirrors_go_to_a_url_or_a_file() {
+        let config = config(
+            "[mirror]\nurl = \"https://mirror.example.com/ingest\"\nsample_rate = 0.1\n\n[mirror.methods]\nsendTransaction = 1\ngetSlot = 0\n",
+        );
+        let mirror = config.mirror.unwrap();
+        assert!(
+            matches!(&mirror.sink, MirrorSink::Http(url) if url == "https://mirror.example.com/ingest")
+        );
+        assert_eq!(mirror.sample_rate, 0.1);
+        assert_eq!(mirror.method_rates["sendTransaction"], 1.0);
+        assert_eq!(mirror.method_rates["getSlot"], 0.0);
+        assert_eq!((mirror.max_body, mirror.queue), (4096, 10_000));
+        let config = self::config("[mirror]\npath = \"/tmp/mirror.pipe\"\n");
+        let mirror = config.mirror.unwrap();
+        assert!(matches!(&mirror.sink, MirrorSink::File(path) if path == "/tmp/mirror.pipe"));
+        assert_eq!(mirror.sample_rate, 0.01);
+        for (settings, message) in [
+            (
+                "url = \"ftp://mirror.example.com\"\n",
+                "'mirror.url' must be an http(s) URL",
+            ),
+            (
+                "url = \"https://mirror.example.com\"\npath = \"/tmp/mirror.pipe\"\n",
+                "'mirror.path' must be absent when url is set",
+            ),
+            (
+                "path = \"/tmp/mirror.pipe\"\n\n[mirror.methods]\ngetSlot = 2\n",
+                "'mirror.methods.getSlot' must be between 0 and 1",
+            ),
+        ] {
+            let text = format!("{}\n[mirror]\n{}", UPSTREAM, settings);
+            assert!(error(&text).ends_with(message), "{}", error(&text));
+        }
+    }
+}
@@ -0,0 +1,711 @@
+//! How requests are dispatched: what they cost and how long they may take,
+//! the answers relayed, how upstreams are raced, hedged and retried, and
+//! which requests are shed first under load.
+
+use super::table::Table;
+use super::upstreams::UpstreamConfig;
+use super::{ConfigError, Result};
+use axum::http::{HeaderMap, HeaderName};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upstream credits charged per call of a method.
+pub struct CostTable {
+    pub default: f64,
+    pub methods: HashMap<String, f64>,
+    /// Whether upstream requests abandoned before their answer, for the
+    /// client having been answered or gone, are charged.
+    pub count_aborted: bool,
+    /// Days `costs::CostLedger` keeps; 0 keeps them all.
+    pub retention_days: usize,
+}
+
+impl CostTable {
+    pub fn cost(&self, method: &str) -> f64 {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+}
+
+/// End-to-end time limits of client requests, from their arrival to the
+/// response headers.
+pub struct DeadlineTable {
+    pub default: Duration,
+    pub methods: HashMap<String, Duration>,
+    /// Header in which clients can ask for a shorter deadline.
+    pub header: HeaderName,
+}
+
+impl DeadlineTable {
+    /// Deadline of a request calling `methods`; a batch gets the longest.
+    pub fn deadline(&self, methods: &[String]) -> Duration {
+        methods
+            .iter()
+            .map(|method| self.methods.get(method).copied().unwrap_or(self.default))
+            .max()
+            .unwrap_or(self.default)
+    }
+
+    /// The deadline a client asked for in `header`, in milliseconds or as
+    /// a gRPC timeout such as `500m` or `2S`; `None` when absent or
+    /// malformed.
+    pub fn requested(&self, headers: &HeaderMap) -> Option<Duration> {
+        let value = headers.get(&self.header)?.to_str().ok()?.trim();
+        if let Ok(ms) = value.parse() {
+            return Some(Duration::from_millis(ms));
+        }
+        // gRPC allows at most 8 digits and a unit
+        let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+        let amount: u64 = amount.parse().ok().filter(|_| amount.len() <= 8)?;
+        Some(match unit {
+            "H" => Duration::from_secs(amount * 3600),
+            "M" => Duration::from_secs(amount * 60),
+            "S" => Duration::from_secs(amount),
+            "m" => Duration::from_millis(amount),
+            "u" => Duration::from_micros(amount),
+            "n" => Duration::from_nanos(amount),
+            _ => return None,
+        })
+    }
+}
+
+/// Largest upstream answer relayed per method, in bytes; `None` for no
+/// limit.
+pub struct ResponseLimits {
+    pub default: Option<u64>,
+    pub methods: HashMap<String, Option<u64>>,
+}
+
+impl ResponseLimits {
+    /// Limit of a request calling `methods`; a batch gets the largest.
+    pub fn limit(&self, methods: &[String]) -> Option<u64> {
+        let limits = methods
+            .iter()
+            .map(|method| self.methods.get(method).copied().unwrap_or(self.default));
+        limits
+            .reduce(|a, b| a.zip(b).map(|(a, b)| a.max(b)))
+            .unwrap_or(self.default)
+    }
+}
+
+/// Methods with side effects, not retried unless `[retry] exclude_methods`
+/// says otherwise.
+pub const WRITE_METHODS: [&str; 2] = ["sendTransaction", "requestAirdrop"];
+
+#[derive(Clone)]
+pub struct RetryConfig {
+    /// Methods never retried; a batch calling any of them is not either.
+    pub exclude_methods: Vec<String>,
+    /// Retries allowed per hundred requests that may be retried.
+    pub budget_percent: f64,
+}
+
+impl RetryConfig {
+    pub fn applies(&self, methods: &[String]) -> bool {
+        !methods
+            .iter()
+            .any(|method| self.exclude_methods.contains(method))
+    }
+}
+
+/// Order in which requests are shed under load, the low ones first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    pub(super) fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|class| class.as_str() == name)
+    }
+}
+
+/// Priority classes by method; unlisted methods are normal.
+#[derive(Clone)]
+pub struct PriorityTable {
+    pub high: Vec<String>,
+    pub low: Vec<String>,
+}
+
+impl PriorityTable {
+    /// The class of a request calling `methods`, the highest of its calls'
+    /// unless its API key has one.
+    pub fn class(&self, methods: &[String], key: Option<Priority>) -> Priority {
+        if let Some(class) = key {
+            return class;
+        }
+        methods
+            .iter()
+            .map(|method| match method {
+                method if self.high.contains(method) => Priority::High,
+                method if self.low.contains(method) => Priority::Low,
+                _ => Priority::Normal,
+            })
+            .max()
+            .unwrap_or(Priority::Normal)
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadSheddingConfig {
+    /// Proxied requests in flight at once beyond which normal and low
+    /// priority ones are shed; high priority ones always go through.
+    pub max_in_flight: usize,
+    /// How long a normal request waits for a slot before it is shed.
+    pub queue: Duration,
+    /// Share of `max_in_flight` low priority requests may fill; they never
+    /// wait.
+    pub low_share_percent: f64,
+}
+
+/// How the upstream answers to a request become the client's answer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Strategy {
+    /// The first acceptable answer wins.
+    Race,
+    /// Every healthy upstream is asked and their answers are combined.
+    Merge,
+}
+
+/// Methods whose answers the proxy knows how to merge.
+pub const MERGEABLE_METHODS: [&str; 1] = ["getRecentPrioritizationFees"];
+
+/// When a race adds upstreams instead of asking them all at once.
+#[derive(Clone, Copy, PartialEq)]
+pub struct HedgePolicy {
+    /// Time between the upstreams added.
+    pub delay: Duration,
+    /// Upstreams added after the first one, not counting those replacing
+    /// failed requests.
+    pub max_hedges: usize,
+}
+
+pub struct RoutingTable {
+    pub methods: HashMap<String, Strategy>,
+    /// How long a merge waits for more answers after the first usable one.
+    pub merge_wait: Duration,
+    /// `None` unless `hedge_ms` is set: every upstream is raced at once.
+    pub hedge: Option<HedgePolicy>,
+    /// Methods with a policy of their own, `None` for `hedge = false`.
+    pub hedges: HashMap<String, Option<HedgePolicy>>,
+    /// Upstreams a race goes to, the best ranked; `None` races them all.
+    pub fanout: Option<usize>,
+    /// Methods with a fan-out of their own, `None` for `fanout = 0`.
+    pub fanouts: HashMap<String, Option<usize>>,
+    /// Methods whose upstream requests time out apart from the upstream's
+    /// `request_timeout_ms`.
+    pub timeouts: HashMap<String, Duration>,
+}
+
+impl RoutingTable {
+    pub fn strategy(&self, method: &str) -> Strategy {
+        self.methods.get(method).copied().unwrap_or(Strategy::Race)
+    }
+
+    /// Hedging of a request calling `methods`. A batch waits the longest
+    /// delay and adds the fewest upstreams of its calls, and is not hedged
+    /// when one of them is not.
+    pub fn hedge(&self, methods: &[String]) -> Option<HedgePolicy> {
+        let mut policies = methods
+            .iter()
+            .map(|method| self.hedges.get(method).copied().unwrap_or(self.hedge));
+        let first = policies.next()??;
+        policies.try_fold(first, |policy, other| {
+            let other = other?;
+            Some(HedgePolicy {
+                delay: policy.delay.max(other.delay),
+                max_hedges: policy.max_hedges.min(other.max_hedges),
+            })
+        })
+    }
+
+    /// Timeout of each upstream request for `methods`, `None` for the
+    /// upstream's own. A batch gets its slowest call's, and the upstream's
+    /// when one of them has none.
+    pub fn timeout(&self, methods: &[String]) -> Option<Duration> {
+        let mut timeouts = methods
+            .iter()
+            .map(|method| self.timeouts.get(method).copied());
+        let first = timeouts.next()??;
+        timeouts.try_fold(first, |timeout, other| Some(timeout.max(other?)))
+    }
+
+    /// Fan-out of a request calling `methods`. A batch is raced as widely
+    /// as its widest call, and by every upstream when one of them is.
+    pub fn fanout(&self, methods: &[String]) -> Option<usize> {
+        let mut fanouts = methods
+            .iter()
+            .map(|method| self.fanouts.get(method).copied().unwrap_or(self.fanout));
+        let first = fanouts.next()??;
+        fanouts.try_fold(first, |fanout, other| Some(fanout.max(other?)))
+    }
+}
+
+/// Metaplex DAS methods, served by the providers indexing digital assets.
+pub const DAS_METHODS: [&str; 12] = [
+    "getAsset",
+    "getAssetBatch",
+    "getAssetProof",
+    "getAssetProofBatch",
+    "getAssetSignatures",
+    "getAssetsByAuthority",
+    "getAssetsByCreator",
+    "getAssetsByGroup",
+    "getAssetsByOwner",
+    "getNftEditions",
+    "getTokenAccounts",
+    "searchAssets",
+];
+
+/// Methods that only upstreams with some capability serve, standard nodes
+/// answering them with method-not-found.
+pub struct CapabilityTable {
+    /// A capability's bit in the masks below is its position here.
+    pub names: Vec<String>,
+    methods: HashMap<String, usize>,
+}
+
+impl CapabilityTable {
+    /// Mask of the capabilities a request calling `methods` needs.
+    pub fn required<'m>(&self, methods: impl IntoIterator<Item = &'m String>) -> u64 {
+        methods
+            .into_iter()
+            .filter_map(|method| self.methods.get(method))
+            .fold(0, |mask, bit| mask | 1 << bit)
+    }
+
+    /// Mask of the capabilities `names`, which must be known.
+    pub fn mask(&self, names: &[String]) -> u64 {
+        names
+            .iter()
+            .filter_map(|name| self.names.iter().position(|n| n == name))
+            .fold(0, |mask, bit| mask | 1 << bit)
+    }
+
+    /// Methods of the capability `name`, sorted.
+    pub fn methods_of(&self, name: &str) -> Vec<&str> {
+        let bit = self.names.iter().position(|n| n == name);
+        let mut methods: Vec<&str> = self
+            .methods
+            .iter()
+            .filter(|(_, b)| Some(**b) == bit)
+            .map(|(method, _)| method.as_str())
+            .collect();
+        methods.sort_unstable();
+        methods
+    }
+
+    /// Names of the capabilities in `mask`, for logs and errors.
+    pub fn describe(&self, mask: u64) -> Vec<&str> {
+        self.names
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| mask & 1 << bit != 0)
+            .map(|(_, name)| name.as_str())
+            .collect()
+    }
+}
+
+/// `[costs]`: the credits of each method listed, keyed by its name.
+pub(super) fn costs(root: &Table) -> Result<CostTable> {
+    let costs = root.table("costs")?;
+    let mut methods = HashMap::new();
+    for (method, cost) in costs.entries() {
+        if !["default", "count_aborted", "retention_days"].contains(&method.as_str()) {
+            let cost = costs.expect_float(method, cost)?;
+            if cost < 0.0 {
+                return costs.invalid(method, "a non-negative number");
+            }
+            methods.insert(method.clone(), cost);
+        }
+    }
+    Ok(CostTable {
+        default: match costs.float("default", 1.0)? {
+            cost if cost >= 0.0 => cost,
+            _ => return costs.invalid("default", "a non-negative number"),
+        },
+        methods,
+        count_aborted: costs.boolean("count_aborted", true)?,
+        retention_days: costs.integer("retention_days", 400)? as usize,
+    })
+}
+
+/// `[deadlines]`: the deadline of each method listed, in milliseconds.
+pub(super) fn deadlines(root: &Table) -> Result<DeadlineTable> {
+    let deadlines = root.table("deadlines")?;
+    let mut methods = HashMap::new();
+    for (method, _) in deadlines.entries() {
+        if method != "default_ms" && method != "header" {
+            let ms = deadlines.integer(method, 0)?.max(1);
+            methods.insert(method.clone(), Duration::from_millis(ms));
+        }
+    }
+    Ok(DeadlineTable {
+        default: Duration::from_millis(deadlines.integer("default_ms", 10_000)?.max(1)),
+        methods,
+        header: deadlines.header_name("header", "x-deadline-ms")?,
+    })
+}
+
+/// `[response_limits]`, in MiB; 0 is no limit.
+pub(super) fn response_limits(root: &Table) -> Result<ResponseLimits> {
+    let sizes = root.table("response_limits")?;
+    let mib = |mib: u64| (mib > 0).then_some(mib << 20);
+    let mut methods = HashMap::new();
+    for (method, _) in sizes.entries() {
+        if method != "default_mib" {
+            methods.insert(method.clone(), mib(sizes.integer(method, 0)?));
+        }
+    }
+    Ok(ResponseLimits {
+        default: mib(sizes.integer("default_mib", 1024)?),
+        methods,
+    })
+}
+
+/// `[routing]`: the default hedging and fan-out, then each method with
+/// a strategy or a table of its own settings.
+pub(super) fn routing(root: &Table) -> Result<RoutingTable> {
+    let routing = root.table("routing")?;
+    let max_hedges = routing.integer("max_hedges", 1)? as usize;
+    let default_hedge = match routing.get("hedge_ms") {
+        Some(_) => Some(HedgePolicy {
+            delay: Duration::from_millis(routing.integer("hedge_ms", 0)?),
+            max_hedges,
+        }),
+        None => None,
+    };
+    let fanout = match routing.integer("fanout", 0)? {
+        0 => None,
+        k => Some(k as usize),
+    };
+    let mut methods = HashMap::new();
+    let mut hedges = HashMap::new();
+    let mut fanouts = HashMap::new();
+    let mut timeouts = HashMap::new();
+    for (method, value) in routing.entries() {
+        if ["merge_wait_ms", "hedge_ms", "max_hedges", "fanout"].contains(&method.as_str()) {
+            continue;
+        }
+        // a table sets the method's hedging along with its strategy
+        let strategy = match value.is_object() {
+            true => {
+                let rule = routing.table(method)?;
+                let hedge = hedge_rule(&rule, default_hedge, max_hedges)?;
+                hedges.insert(method.clone(), hedge);
+                if rule.get("fanout").is_some() {
+                    let k = rule.integer("fanout", 0)? as usize;
+                    fanouts.insert(method.clone(), (k > 0).then_some(k));
+                }
+                if rule.get("request_timeout_ms").is_some() {
+                    let ms = rule.integer("request_timeout_ms", 0)?.max(1);
+                    timeouts.insert(method.clone(), Duration::from_millis(ms));
+                }
+                rule.string("strategy", "race")?
+            }
+            false => routing.string(method, "race")?,
+        };
+        let strategy = match strategy.as_str() {
+            "race" => Strategy::Race,
+            "merge" if MERGEABLE_METHODS.contains(&method.as_str()) => Strategy::Merge,
+            "merge" => {
+                return Err(ConfigError::at(
+                    format!("routing.{}", method),
+                    format!(
+                        "'merge' is only supported for {}",
+                        MERGEABLE_METHODS.join(", ")
+                    ),
+                ))
+            }
+            other => {
+                return Err(ConfigError::at(
+                    format!("routing.{}", method),
+                    format!("unknown strategy '{}', expected race or merge", other),
+                ))
+            }
+        };
+        methods.insert(method.clone(), strategy);
+    }
+    Ok(RoutingTable {
+        methods,
+        merge_wait: Duration::from_millis(routing.integer("merge_wait_ms", 250)?),
+        hedge: default_hedge,
+        hedges,
+        fanout,
+        fanouts,
+        timeouts,
+    })
+}
+
+/// `[capabilities]`, which every capability `upstreams` claim must be in.
+pub(super) fn capabilities(root: &Table, upstreams: &[UpstreamConfig]) -> Result<CapabilityTable> {
+    // methods listed under a capability add to its built-in ones
+    let capability_methods = root.table("capabilities")?;
+    let mut capabilities = CapabilityTable {
+        names: vec!["das".to_string()],
+        methods: DAS_METHODS.iter().map(|m| (m.to_string(), 0)).collect(),
+    };
+    for (name, _) in capability_methods.entries() {
+        let bit = match capabilities.names.iter().position(|n| n == name) {
+            Some(bit) => bit,
+            None if capabilities.names.len() < 64 => {
+                capabilities.names.push(name.clone());
+                capabilities.names.len() - 1
+            }
+            None => {
+                return Err(ConfigError::at(
+                    format!("capabilities.{}", name),
+                    "at most 64 capabilities are supported",
+                ))
+            }
+        };
+        for method in capability_methods.strings(name)? {
+            capabilities.methods.insert(method, bit);
+        }
+    }
+    for (i, upstream) in upstreams.iter().enumerate() {
+        if let Some(unknown) = upstream
+            .capabilities
+            .iter()
+            .find(|name| !capabilities.names.contains(name))
+        {
+            return Err(ConfigError::at(
+                    format!("upstreams[{}].capabilities", i),
+                    format!(
+                        "unknown capability '{}', expected one of {}; others need their methods listed in [capabilities]",
+                        unknown,
+                        capabilities.names.join(", ")
+                    ),
+                ));
+        }
+    }
+    Ok(capabilities)
+}
+
+/// `[retry]`, `None` when disabled.
+pub(super) fn retry(root: &Table) -> Result<Option<RetryConfig>> {
+    let retries = root.table("retry")?;
+    let retry = RetryConfig {
+        exclude_methods: match retries.get("exclude_methods") {
+            Some(_) => retries.strings("exclude_methods")?,
+            None => WRITE_METHODS.map(str::to_string).to_vec(),
+        },
+        budget_percent: retries.float("budget_percent", 10.0)?.max(0.0),
+    };
+    Ok(retries.boolean("enabled", true)?.then_some(retry))
+}
+
+/// `[priority]`: `sendTransaction` is high unless `high_methods` is set.
+pub(super) fn priorities(root: &Table) -> Result<PriorityTable> {
+    let priority = root.table("priority")?;
+    Ok(PriorityTable {
+        high: match priority.get("high_methods") {
+            Some(_) => priority.strings("high_methods")?,
+            None => vec!["sendTransaction".to_string()],
+        },
+        low: priority.strings("low_methods")?,
+    })
+}
+
+/// `[load_shedding]`, `None` unless enabled.
+pub(super) fn load_shedding(root: &Table) -> Result<Option<LoadSheddingConfig>> {
+    let shedding = root.table("load_shedding")?;
+    let load_shedding = LoadSheddingConfig {
+        max_in_flight: shedding.integer("max_in_flight", 1024)?.max(1) as usize,
+        queue: Duration::from_millis(shedding.integer("queue_ms", 50)?),
+        low_share_percent: shedding.float("low_share_percent", 50.0)?.clamp(0.0, 100.0),
+    };
+    Ok(shedding.boolean("enabled", false)?.then_some(load_shedding))
+}
+
+/// Hedging of a method from its `[routing]` table: `hedge = false`, or
+/// `hedge_ms` and `max_hedges` overriding the defaults.
+fn hedge_rule(
+    rule: &Table,
+    default: Option<HedgePolicy>,
+    max_hedges: usize,
+) -> Result<Option<HedgePolicy>> {
+    if !rule.boolean("hedge", true)? {
+        rule.known(&["hedge_ms", "max_hedges"]);
+        return Ok(None);
+    }
+    let delay = match rule.get("hedge_ms") {
+        Some(_) => Duration::from_millis(rule.integer("hedge_ms", 0)?),
+        None => match default {
+            Some(default) => default.delay,
+            None => {
+                rule.known(&["max_hedges"]);
+                return Ok(None);
+            }
+        },
+    };
+    Ok(Some(HedgePolicy {
+        delay,
+        max_hedges: rule.integer("max_hedges", max_hedges as u64)? as usize,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{error, UPSTREAM};
+    use crate::config::Config;
+
+    fn config(sections: &str) -> Config {
+        Config::parse(&format!("{}\n{}", UPSTREAM, sections)).unwrap()
+    }
+
+    fn methods(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn unlisted_methods_get_the_default_cost_and_deadline() {
+        let config = config(
+            "[costs]\ndefault = 2\ngetProgramAccounts = 10\n\n[deadlines]\ndefault_ms = 3000\ngetBlock = 8000\n",
+        );
+        assert_eq!(config.costs.cost("getProgramAccounts"), 10.0);
+        assert_eq!(config.costs.cost("getSlot"), 2.0);
+        let deadline = |names| config.deadlines.deadline(&methods(names));
+        assert_eq!(deadline(&["getSlot"]), Duration::from_secs(3));
+        // a batch gets the longest
+        assert_eq!(deadline(&["getSlot", "getBlock"]), Duration::from_secs(8));
+        assert_eq!(deadline(&[]), Duration::from_secs(3));
+        let text = format!("{}\n[costs]\ngetBlock = -1\n", UPSTREAM);
+        assert!(error(&text).ends_with("'costs.getBlock' must be a non-negative number"));
+    }
+
+    #[test]
+    fn clients_ask_for_deadlines_in_milliseconds_or_grpc_timeouts() {
+        let config = config("[deadlines]\nheader = \"grpc-timeout\"\n");
+        let requested = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("grpc-timeout", value.parse().unwrap());
+            config.deadlines.requested(&headers)
+        };
+        assert_eq!(requested("250"), Some(Duration::from_millis(250)));
+        assert_eq!(requested(" 2S "), Some(Duration::from_secs(2)));
+        assert_eq!(requested("1M"), Some(Duration::from_secs(60)));
+        assert_eq!(requested("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(requested("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(requested("750u"), Some(Duration::from_micros(750)));
+        assert_eq!(requested("10n"), Some(Duration::from_nanos(10)));
+        // gRPC allows eight digits at most
+        assert_eq!(requested("123456789S"), None);
+        assert_eq!(requested("5x"), None);
+        assert_eq!(requested("S"), None);
+        assert_eq!(config.deadlines.requested(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn response_limits_are_in_mib_and_a_batch_gets_the_largest() {
+        let config =
+            config("[response_limits]\ndefault_mib = 4\ngetBlock = 64\ngetProgramAccounts = 0\n");
+        let limit = |names| config.response_limits.limit(&methods(names));
+        assert_eq!(limit(&["getSlot"]), Some(4 << 20));
+        assert_eq!(limit(&["getSlot", "getBlock"]), Some(64 << 20));
+        // 0 is no limit, which no other limit beats
+        assert_eq!(limit(&["getBlock", "getProgramAccounts"]), None);
+    }
+
+    #[test]
+    fn routing_rules_set_hedging_fanout_and_timeouts_per_method() {
+        let config = config(
+            "[routing]\nhedge_ms = 100\nmax_hedges = 2\nfanout = 2\ngetBlock = { hedge = false, fanout = 0 }\ngetSlot = { hedge_ms = 300, max_hedges = 1, fanout = 3 }\ngetTransaction = { request_timeout_ms = 2000 }\n",
+        );
+        let routing = &config.routing;
+        let hedge = |names| routing.hedge(&methods(names));
+        let policy = |delay, max_hedges| {
+            Some(HedgePolicy {
+                delay: Duration::from_millis(delay),
+                max_hedges,
+            })
+        };
+        assert!(hedge(&["getBalance"]) == policy(100, 2));
+        assert!(hedge(&["getSlot"]) == policy(300, 1));
+        // a batch waits the longest and adds the fewest
+        assert!(hedge(&["getBalance", "getSlot"]) == policy(300, 1));
+        assert!(hedge(&["getBalance", "getBlock"]).is_none());
+        let fanout = |names| routing.fanout(&methods(names));
+        assert_eq!(fanout(&["getBalance"]), Some(2));
+        assert_eq!(fanout(&["getBalance", "getSlot"]), Some(3));
+        assert_eq!(fanout(&["getSlot", "getBlock"]), None);
+        let timeout = |names| routing.timeout(&methods(names));
+        assert_eq!(timeout(&["getTransaction"]), Some(Duration::from_secs(2)));
+        assert_eq!(timeout(&["getTransaction", "getSlot"]), None);
+        assert_eq!(routing.strategy("getBlock"), Strategy::Race);
+        // without a default delay a method's own settings are all it has
+        let config = self::config("[routing]\ngetSlot = { max_hedges = 3 }\n");
+        assert!(config.routing.hedge(&methods(&["getSlot"])).is_none());
+    }
+
+    #[test]
+    fn only_mergeable_methods_are_merged() {
+        let config = config("[routing]\ngetRecentPrioritizationFees = \"merge\"\n");
+        assert_eq!(
+            config.routing.strategy("getRecentPrioritizationFees"),
+            Strategy::Merge
+        );
+        let text = format!("{}\n[routing]\ngetSlot = \"merge\"\n", UPSTREAM);
+        assert!(error(&text).ends_with("'merge' is only supported for getRecentPrioritizationFees"));
+        let text = format!(
+            "{}\n[routing]\ngetSlot = {{ strategy = \"fastest\" }}\n",
+            UPSTREAM
+        );
+        assert!(error(&text).ends_with("unknown strategy 'fastest', expected race or merge"));
+    }
+
+    #[test]
+    fn capabilities_add_to_the_built_in_ones() {
+        let config = config(
+            "[capabilities]\ndas = [\"getAssetsByLabel\"]\ncompression = [\"getCompressedAccount\"]\n\n[[upstreams]]\nurl = \"http://127.0.0.1:8900\"\ncapabilities = [\"das\", \"compression\"]\n",
+        );
+        let capabilities = &config.capabilities;
+        assert_eq!(capabilities.names, ["das", "compression"]);
+        assert_eq!(capabilities.required(&methods(&["getAsset"])), 0b01);
+        assert_eq!(capabilities.required(&methods(&["getAssetsByLabel"])), 0b01);
+        let batch = methods(&["getCompressedAccount", "getAsset", "getSlot"]);
+        assert_eq!(capabilities.required(&batch), 0b11);
+        assert_eq!(capabilities.describe(0b10), ["compression"]);
+        assert_eq!(capabilities.mask(&methods(&["compression"])), 0b10);
+        assert!(capabilities.methods_of("das").contains(&"getAssetsByLabel"));
+        assert_eq!(config.upstreams[1].capabilities, ["das", "compression"]);
+        let text = format!("{}capabilities = [\"history\"]\n", UPSTREAM);
+        assert!(error(&text).ends_with(
+            "unknown capability 'history', expected one of das; others need their methods listed in [capabilities]"
+        ));
+    }
+
+    #[test]
+    fn writes_are_not_retried_and_transactions_go_first_by_default() {
+        let defaults = config("");
+        let retry = defaults.retry.unwrap();
+        assert_eq!(retry.exclude_methods, WRITE_METHODS);
+        assert!(!retry.applies(&methods(&["getSlot", "sendTransaction"])));
+        assert_eq!(retry.budget_percent, 10.0);
+        assert_eq!(defaults.priorities.high, ["sendTransaction"]);
+        assert!(defaults.load_shedding.is_none());
+        let config = config(
+            "[retry]\nexclude_methods = []\n\n[priority]\nhigh_methods = []\nlow_methods = [\"getProgramAccounts\"]\n\n[load_shedding]\nenabled = true\nlow_share_percent = 150\n",
+        );
+        assert!(config
+            .retry
+            .unwrap()
+            .applies(&methods(&["sendTransaction"])));
+        assert!(config.priorities.high.is_empty());
+        assert_eq!(config.priorities.low, ["getProgramAccounts"]);
+        assert_eq!(config.load_shedding.unwrap().low_share_percent, 100.0);
+        assert!(self::config("[retry]\nenabled = false\n").retry.is_none());
+    }
+}
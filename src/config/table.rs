@@ -0,0 +1,505 @@
+//! Typed reading of the parsed config file. Every key looked up is noted,
+//! present or not, so that the keys never looked up can be reported as
+//! unknown, along with the known key they were probably meant to be.
+
+use super::{ConfigError, Result};
+use crate::ratelimit::Limit;
+use axum::http::HeaderName;
+use ipnet::IpNet;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Collect an error for every key of `value` under `path` that was never
+/// looked up, suggesting the closest known key of the same table.
+pub(super) fn unknown_keys(
+    value: &Value,
+    path: &str,
+    seen: &HashSet<String>,
+    out: &mut Vec<ConfigError>,
+) {
+    let Value::Object(map) = value else {
+        return;
+    };
+    for (key, value) in map {
+        let key_path = match path {
+            "" => key.clone(),
+            _ => format!("{}.{}", path, key),
+        };
+        if !seen.contains(&key_path) {
+            let prefix = match path {
+                "" => String::new(),
+                _ => format!("{}.", path),
+            };
+            let closest = |prefix: &str| {
+                seen.iter()
+                    .filter_map(|known| known.strip_prefix(prefix))
+                    .filter(|known| !known.contains(['.', '[']))
+                    .map(|known| (edit_distance(key, known), known))
+                    // near misses, and keys missing a unit suffix like `_ms`
+                    .filter(|(distance, known)| {
+                        *distance <= 2.max(key.len() / 3)
+                            || (known.starts_with(key.as_str()) && key.len() * 2 >= known.len())
+                    })
+                    .min()
+                    .map(|(_, known)| known.to_string())
+            };
+            let message = match (closest(&prefix), closest("")) {
+                (Some(known), _) => format!(
+                    "unknown key '{}', did you mean '{}{}'?",
+                    key_path, prefix, known
+                ),
+                // a top-level key written after a table header lands in it
+                (None, Some(known)) if !path.is_empty() => format!(
+                    "unknown key '{}', did you mean the top-level '{}'? Top-level keys go before the first [table]",
+                    key_path, known
+                ),
+                _ => format!("unknown key '{}'", key_path),
+            };
+            out.push(ConfigError::at(key_path, message));
+            continue;
+        }
+        match value {
+            Value::Object(_) => unknown_keys(value, &key_path, seen, out),
+            Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    unknown_keys(item, &format!("{}[{}]", key_path, i), seen, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Levenshtein distance, for "did you mean" suggestions.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Typed accessors over a parsed TOML table, reporting errors with the
+/// dotted path of the offending key.
+pub(super) struct Table<'a> {
+    map: Option<&'a Map<String, Value>>,
+    path: String,
+    /// Paths of every key looked up, present or not, shared by all the
+    /// tables of a document so unknown keys can be told apart.
+    seen: &'a RefCell<HashSet<String>>,
+}
+
+impl<'a> Table<'a> {
+    pub(super) fn root(value: &'a Value, seen: &'a RefCell<HashSet<String>>) -> Result<Self> {
+        match value {
+            Value::Object(map) => Ok(Self {
+                map: Some(map),
+                path: String::new(),
+                seen,
+            }),
+            _ => Err(ConfigError::new("config root must be a table")),
+        }
+    }
+
+    pub(super) fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    pub(super) fn invalid<T>(&self, key: &str, expected: &str) -> Result<T> {
+        Err(ConfigError::at(
+            self.key_path(key),
+            format!("'{}' must be {}", self.key_path(key), expected),
+        ))
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<&'a Value> {
+        self.known(&[key]);
+        self.map.and_then(|map| map.get(key))
+    }
+
+    /// Accept `keys` without reading them, for settings that only apply
+    /// once the section is enabled.
+    pub(super) fn known(&self, keys: &[&str]) {
+        let mut seen = self.seen.borrow_mut();
+        seen.extend(keys.iter().map(|key| self.key_path(key)));
+    }
+
+    /// Every entry, for tables keyed by method name.
+    pub(super) fn entries(&self) -> impl Iterator<Item = (&'a String, &'a Value)> {
+        let entries = self.map.into_iter().flat_map(|map| map.iter());
+        let entries: Vec<_> = entries.collect();
+        for (key, _) in &entries {
+            self.known(&[key.as_str()]);
+        }
+        entries.into_iter()
+    }
+
+    /// A sub-table; a missing one behaves as an empty table.
+    pub(super) fn table(&self, key: &str) -> Result<Table<'a>> {
+        match self.get(key) {
+            None => Ok(Table {
+                map: None,
+                path: self.key_path(key),
+                seen: self.seen,
+            }),
+            Some(Value::Object(map)) => Ok(Table {
+                map: Some(map),
+                path: self.key_path(key),
+                seen: self.seen,
+            }),
+            Some(_) => self.invalid(key, "a table"),
+        }
+    }
+
+    /// An array of tables such as `[[upstreams]]`.
+    pub(super) fn tables(&self, key: &str) -> Result<Vec<Table<'a>>> {
+        let items = match self.get(key) {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(items)) => items,
+            Some(_) => return self.invalid(key, "an array of tables"),
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Value::Object(map) => Ok(Table {
+                    map: Some(map),
+                    path: format!("{}[{}]", self.key_path(key), i),
+                    seen: self.seen,
+                }),
+                _ => self.invalid(key, "an array of tables"),
+            })
+            .collect()
+    }
+
+    pub(super) fn string(&self, key: &str, default: &str) -> Result<String> {
+        Ok(self
+            .optional_string(key)?
+            .unwrap_or_else(|| default.to_string()))
+    }
+
+    pub(super) fn optional_string(&self, key: &str) -> Result<Option<String>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => self.invalid(key, "a string"),
+        }
+    }
+
+    pub(super) fn required_string(&self, key: &str) -> Result<String> {
+        match self.optional_string(key)? {
+            Some(s) => Ok(s),
+            None => Err(ConfigError::at(
+                self.key_path(key),
+                format!("'{}' is required", self.key_path(key)),
+            )),
+        }
+    }
+
+    /// `rps` with an optional `burst`, which defaults to one second's worth.
+    pub(super) fn rate_limit(&self) -> Result<Option<Limit>> {
+        self.known(&["burst"]);
+        let rps = match self.get("rps") {
+            None => return Ok(None),
+            Some(rps) => self.expect_float("rps", rps)?,
+        };
+        if rps <= 0.0 {
+            return self.invalid("rps", "a positive number");
+        }
+        let burst = self.integer("burst", rps.ceil() as u64)?;
+        if burst == 0 {
+            return self.invalid("burst", "at least 1");
+        }
+        Ok(Some(Limit {
+            rps,
+            burst: burst.min(u32::MAX as u64) as u32,
+        }))
+    }
+
+    /// A positive count, `None` when absent.
+    pub(super) fn optional_cap(&self, key: &str) -> Result<Option<usize>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(_) => match self.integer(key, 0)? {
+                0 => self.invalid(key, "at least 1"),
+                n => Ok(Some(n as usize)),
+            },
+        }
+    }
+
+    pub(super) fn header_name(&self, key: &str, default: &str) -> Result<HeaderName> {
+        let name = self.string(key, default)?;
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+            ConfigError::at(
+                self.key_path(key),
+                format!("invalid header name '{}'", name),
+            )
+        })
+    }
+
+    pub(super) fn strings(&self, key: &str) -> Result<Vec<String>> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s.clone()),
+                    _ => self.invalid(key, "an array of strings"),
+                })
+                .collect(),
+            Some(_) => self.invalid(key, "an array of strings"),
+        }
+    }
+
+    /// CIDRs such as `10.0.0.0/8`; a bare address is a single-host network.
+    pub(super) fn networks(&self, key: &str) -> Result<Vec<IpNet>> {
+        self.strings(key)?
+            .iter()
+            .map(|s| {
+                s.parse::<IpNet>()
+                    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        ConfigError::at(
+                            self.key_path(key),
+                            format!("'{}': invalid CIDR '{}'", self.key_path(key), s),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    pub(super) fn integer(&self, key: &str, default: u64) -> Result<u64> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(value) => match value.as_u64() {
+                Some(n) => Ok(n),
+                None => self.invalid(key, "a non-negative integer"),
+            },
+        }
+    }
+
+    pub(super) fn float(&self, key: &str, default: f64) -> Result<f64> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(value) => self.expect_float(key, value),
+        }
+    }
+
+    pub(super) fn expect_float(&self, key: &str, value: &Value) -> Result<f64> {
+        match value.as_f64() {
+            Some(n) => Ok(n),
+            None => self.invalid(key, "a number"),
+        }
+    }
+
+    pub(super) fn boolean(&self, key: &str, default: bool) -> Result<bool> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(_) => self.invalid(key, "true or false"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Run `read` on `value`, returning what it read and the keys it saw.
+    fn read<T>(value: Value, read: impl FnOnce(&Table) -> Result<T>) -> (Result<T>, Vec<String>) {
+        let seen = RefCell::new(HashSet::new());
+        let root = Table::root(&value, &seen).unwrap();
+        let result = read(&root);
+        let mut seen: Vec<String> = seen.into_inner().into_iter().collect();
+        seen.sort();
+        (result, seen)
+    }
+
+    fn message<T>(result: Result<T>) -> String {
+        result.err().expect("the value is invalid").message
+    }
+
+    #[test]
+    fn keys_looked_up_are_seen_present_or_not() {
+        let value = json!({
+            "slots": { "max_age_ms": 500 },
+            "upstreams": [{ "url": "a" }, { "url": "b" }],
+        });
+        let (result, seen) = read(value, |root| {
+            let slots = root.table("slots")?;
+            let ages = (
+                slots.integer("max_age_ms", 1)?,
+                slots.integer("ws_stale_ms", 2)?,
+            );
+            let urls: Vec<String> = root
+                .tables("upstreams")?
+                .iter()
+                .map(|upstream| upstream.required_string("url"))
+                .collect::<Result<_>>()?;
+            root.table("warmup")?.known(&["connections"]);
+            Ok((ages, urls))
+        });
+        assert_eq!(result.unwrap(), ((500, 2), vec!["a".into(), "b".into()]));
+        assert_eq!(
+            seen,
+            [
+                "slots",
+                "slots.max_age_ms",
+                "slots.ws_stale_ms",
+                "upstreams",
+                "upstreams[0].url",
+                "upstreams[1].url",
+                "warmup",
+                "warmup.connections",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_name_their_path() {
+        let value = json!({
+            "slots": { "max_age_ms": -1, "tolerance": "x", "enabled": 1 },
+            "upstreams": [{ "url": 5 }],
+            "methods": { "allow": ["getSlot", 1] },
+            "acl": 7,
+        });
+        let slots = |read: fn(&Table) -> Result<()>| {
+            message(self::read(value.clone(), |root| read(&root.table("slots")?)).0)
+        };
+        assert_eq!(
+            slots(|slots| slots.integer("max_age_ms", 0).map(drop)),
+            "'slots.max_age_ms' must be a non-negative integer"
+        );
+        assert_eq!(
+            slots(|slots| slots.float("tolerance", 0.0).map(drop)),
+            "'slots.tolerance' must be a number"
+        );
+        assert_eq!(
+            slots(|slots| slots.boolean("enabled", true).map(drop)),
+            "'slots.enabled' must be true or false"
+        );
+        assert_eq!(
+            slots(|slots| slots.required_string("mode").map(drop)),
+            "'slots.mode' is required"
+        );
+        let (result, _) = read(value.clone(), |root| {
+            root.tables("upstreams")?[0].optional_string("url")
+        });
+        assert_eq!(message(result), "'upstreams[0].url' must be a string");
+        let (result, _) = read(value.clone(), |root| {
+            root.table("methods")?.strings("allow")
+        });
+        assert_eq!(
+            message(result),
+            "'methods.allow' must be an array of strings"
+        );
+        let (result, _) = read(value.clone(), |root| root.table("acl").map(drop));
+        assert_eq!(message(result), "'acl' must be a table");
+        let (result, _) = read(value, |root| root.tables("slots").map(drop));
+        assert_eq!(message(result), "'slots' must be an array of tables");
+    }
+
+    #[test]
+    fn rate_limits_burst_one_seconds_worth_by_default() {
+        let parse = |value: Value| read(value, |root| root.rate_limit()).0;
+        let Ok(Some(limit)) = parse(json!({ "rps": 2.5 })) else {
+            panic!("a positive rps is a limit");
+        };
+        assert_eq!((limit.rps, limit.burst), (2.5, 3));
+        let Ok(Some(limit)) = parse(json!({ "rps": 10, "burst": 50 })) else {
+            panic!("a positive rps is a limit");
+        };
+        assert_eq!((limit.rps, limit.burst), (10.0, 50));
+        assert!(matches!(parse(json!({ "burst": 50 })), Ok(None)));
+        assert_eq!(
+            message(parse(json!({ "rps": 0 }))),
+            "'rps' must be a positive number"
+        );
+        assert_eq!(
+            message(parse(json!({ "rps": 1, "burst": 0 }))),
+            "'burst' must be at least 1"
+        );
+    }
+
+    #[test]
+    fn caps_headers_and_networks_are_checked() {
+        let (cap, _) = read(json!({ "max": 0 }), |root| root.optional_cap("max"));
+        assert_eq!(message(cap), "'max' must be at least 1");
+        let (cap, _) = read(json!({}), |root| root.optional_cap("max"));
+        assert_eq!(cap.unwrap(), None);
+        let (header, _) = read(json!({ "header": "X-Api-Key" }), |root| {
+            root.header_name("header", "x-default")
+        });
+        assert_eq!(header.unwrap(), "x-api-key");
+        let (header, _) = read(json!({ "header": "no spaces" }), |root| {
+            root.header_name("header", "x-default")
+        });
+        assert_eq!(message(header), "invalid header name 'no spaces'");
+        let (networks, _) = read(
+            json!({ "allow": ["10.0.0.0/8", "192.0.2.1", "::1"] }),
+            |root| root.networks("allow"),
+        );
+        let networks: Vec<String> = networks.unwrap().iter().map(IpNet::to_string).collect();
+        assert_eq!(networks, ["10.0.0.0/8", "192.0.2.1/32", "::1/128"]);
+        let (networks, _) = read(json!({ "allow": ["10.0.0.0/33"] }), |root| {
+            root.networks("allow")
+        });
+        assert_eq!(message(networks), "'allow': invalid CIDR '10.0.0.0/33'");
+    }
+
+    #[test]
+    fn unknown_keys_suggest_the_closest_known_one() {
+        let value = json!({
+            "sumary_interval_secs": 5,
+            "frobnicate": true,
+            "slots": { "poll_interval": 300, "port": 1 },
+            "upstreams": [{ "url": "a", "nmae": "x" }],
+        });
+        let seen: HashSet<String> = [
+            "summary_interval_secs",
+            "port",
+            "slots",
+            "slots.poll_interval_ms",
+            "upstreams",
+            "upstreams[0].url",
+            "upstreams[0].name",
+        ]
+        .map(str::to_string)
+        .into();
+        let mut errors = Vec::new();
+        unknown_keys(&value, "", &seen, &mut errors);
+        let mut messages: Vec<String> = errors.into_iter().map(|e| e.message).collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "unknown key 'frobnicate'",
+                "unknown key 'slots.poll_interval', did you mean 'slots.poll_interval_ms'?",
+                "unknown key 'slots.port', did you mean the top-level 'port'? Top-level keys go before the first [table]",
+                "unknown key 'sumary_interval_secs', did you mean 'summary_interval_secs'?",
+                "unknown key 'upstreams[0].nmae', did you mean 'upstreams[0].name'?",
+            ]
+        );
+    }
+
+    #[test]
+    fn suggestions_need_a_close_enough_key() {
+        assert_eq!(edit_distance("sumary", "summary"), 1);
+        assert_eq!(edit_distance("", "port"), 4);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,591 @@
+//! `[[upstreams]]`: the nodes requests are proxied to, their timeouts,
+//! provider rate limits, maintenance windows and credentials.
+
+use super::table::Table;
+use super::{ConfigError, Result};
+use crate::ratelimit::Limit;
+use axum::http::HeaderName;
+use std::time::Duration;
+
+/// Defaults of `connect_timeout_ms` and `request_timeout_ms`.
+const CONNECT_TIMEOUT_MS: u64 = 1000;
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+/// Rate of a last-resort upstream without `[upstreams.last_resort]`, well
+/// under what public endpoints allow a single address.
+const LAST_RESORT_LIMIT: Limit = Limit { rps: 2.0, burst: 4 };
+
+pub struct UpstreamConfig {
+    pub url: String,
+    /// Short identifier safe to show, unlike URLs that may embed keys.
+    pub name: String,
+    /// Whether the upstream keeps the full ledger history.
+    pub archive: bool,
+    /// Capabilities the upstream has, the methods of which only it serves.
+    pub capabilities: Vec<String>,
+    /// WebSocket endpoint to follow slots on instead of polling, see
+    /// `slot_feed`.
+    pub ws_url: Option<String>,
+    /// Where the upstream runs, see `RegionConfig`.
+    pub region: Option<String>,
+    /// Limit on opening a connection, so an unreachable host is found out
+    /// long before a slow query would be.
+    pub connect_timeout: Duration,
+    /// Limit on a whole attempt, connection included.
+    pub request_timeout: Duration,
+    pub rate_limit: ProviderLimitConfig,
+    /// The rate the provider's plan allows, `[upstreams.rate_limit] rps`,
+    /// that every request sent to it counts against.
+    pub outbound: Option<Limit>,
+    /// For `role = "last_resort"`, the rate it may be asked at. Such an
+    /// upstream, typically a public endpoint, is only used while no regular
+    /// one is available.
+    pub last_resort: Option<Limit>,
+    /// Recurring windows in which the upstream is drained, see
+    /// `maintenance`.
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// Keys sent with every request, see `credentials`.
+    pub credentials: Vec<CredentialConfig>,
+}
+
+/// Where an upstream credential is sent.
+#[derive(Clone, PartialEq, Eq)]
+pub enum CredentialPlacement {
+    Header(HeaderName),
+    /// A URL query parameter.
+    Query(String),
+}
+
+#[derive(Clone)]
+pub struct CredentialConfig {
+    pub placement: CredentialPlacement,
+    /// The value, read from `file` at load time when there is one.
+    pub value: String,
+    /// File the value is read from and re-read when it changes.
+    pub file: Option<String>,
+}
+
+/// A weekly or daily maintenance window, in UTC.
+#[derive(Clone, Copy)]
+pub struct MaintenanceWindow {
+    /// Day of the week, 0 for Monday; `None` for every day.
+    pub weekday: Option<u32>,
+    /// Start, in seconds after midnight.
+    pub start: u64,
+    pub duration: Duration,
+}
+
+/// Days `weekday` accepts, from Monday.
+pub const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// How an upstream's `x-ratelimit-reset`-style header gives the reset.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetFormat {
+    /// Seconds from now.
+    Seconds,
+    /// A Unix timestamp in seconds.
+    UnixSeconds,
+    /// A Unix timestamp in milliseconds.
+    UnixMillis,
+}
+
+/// The rate-limit headers a provider sends with its responses.
+#[derive(Clone)]
+pub struct ProviderLimitConfig {
+    pub remaining_header: String,
+    pub reset_header: String,
+    pub reset_format: ResetFormat,
+    /// Remaining requests under which the upstream is only used when no
+    /// other can answer, until the reset; 0 never avoids it.
+    pub min_remaining: u64,
+    /// How long the upstream is avoided when the provider gives no reset.
+    pub default_hold: Duration,
+}
+
+impl Default for ProviderLimitConfig {
+    fn default() -> Self {
+        Self {
+            remaining_header: "x-ratelimit-remaining".to_string(),
+            reset_header: "x-ratelimit-reset".to_string(),
+            reset_format: ResetFormat::Seconds,
+            min_remaining: 0,
+            default_hold: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The `[[upstreams]]` entries, with canonical URLs and unique names.
+pub(super) fn parse(root: &Table) -> Result<Vec<UpstreamConfig>> {
+    let connect_timeout = root.integer("connect_timeout_ms", CONNECT_TIMEOUT_MS)?;
+    let request_timeout = root.integer("request_timeout_ms", REQUEST_TIMEOUT_MS)?;
+    let mut upstreams = Vec::new();
+    for table in root.tables("upstreams")? {
+        let defaults = ProviderLimitConfig::default();
+        let limits = table.table("rate_limit")?;
+        let reset_format = match limits.string("reset_format", "seconds")?.as_str() {
+            "seconds" => ResetFormat::Seconds,
+            "unix" => ResetFormat::UnixSeconds,
+            "unix_ms" => ResetFormat::UnixMillis,
+            _ => return limits.invalid("reset_format", "seconds, unix or unix_ms"),
+        };
+        let last_resort = match table.string("role", "regular")?.as_str() {
+            "regular" => None,
+            "last_resort" => Some(
+                table
+                    .table("last_resort")?
+                    .rate_limit()?
+                    .unwrap_or(LAST_RESORT_LIMIT),
+            ),
+            _ => return table.invalid("role", "regular or last_resort"),
+        };
+        let mut maintenance = Vec::new();
+        for window in table.tables("maintenance")? {
+            maintenance.push(maintenance_window(&window)?);
+        }
+        let mut credentials = Vec::new();
+        for entry in table.tables("credentials")? {
+            credentials.push(credential(&entry)?);
+        }
+        upstreams.push(UpstreamConfig {
+            url: table.required_string("url")?,
+            name: table.string("name", "")?,
+            archive: table.boolean("archive", false)?,
+            capabilities: table.strings("capabilities")?,
+            ws_url: table.optional_string("ws_url")?,
+            region: table.optional_string("region")?,
+            connect_timeout: Duration::from_millis(
+                table.integer("connect_timeout_ms", connect_timeout)?.max(1),
+            ),
+            request_timeout: Duration::from_millis(
+                table.integer("request_timeout_ms", request_timeout)?.max(1),
+            ),
+            rate_limit: ProviderLimitConfig {
+                remaining_header: limits
+                    .string("remaining_header", &defaults.remaining_header)?
+                    .to_ascii_lowercase(),
+                reset_header: limits
+                    .string("reset_header", &defaults.reset_header)?
+                    .to_ascii_lowercase(),
+                reset_format,
+                min_remaining: limits.integer("min_remaining", 0)?,
+                default_hold: Duration::from_secs(limits.integer("default_hold_secs", 60)?),
+            },
+            outbound: limits.rate_limit()?,
+            last_resort,
+            maintenance,
+            credentials,
+        });
+    }
+    canonicalize(&mut upstreams)?;
+
+    for (i, upstream) in upstreams.iter().enumerate() {
+        let name = &upstream.name;
+        if !name.is_empty() && upstreams[..i].iter().any(|u| &u.name == name) {
+            return Err(ConfigError::at(
+                format!("upstreams[{}].name", i),
+                format!("duplicate upstream name '{}'", name),
+            ));
+        }
+    }
+    assign_names(&mut upstreams);
+    Ok(upstreams)
+}
+
+/// Upstreams of the positional `<PORT> <URL1> <URL2> ...` form, with every
+/// setting at its default.
+pub(super) fn from_urls(urls: &[String]) -> Result<Vec<UpstreamConfig>> {
+    let mut upstreams: Vec<UpstreamConfig> = urls
+        .iter()
+        .map(|url| UpstreamConfig {
+            url: url.clone(),
+            name: String::new(),
+            archive: false,
+            capabilities: Vec::new(),
+            ws_url: None,
+            region: None,
+            connect_timeout: Duration::from_millis(CONNECT_TIMEOUT_MS),
+            request_timeout: Duration::from_millis(REQUEST_TIMEOUT_MS),
+            rate_limit: ProviderLimitConfig::default(),
+            outbound: None,
+            last_resort: None,
+            maintenance: Vec::new(),
+            credentials: Vec::new(),
+        })
+        .collect();
+    canonicalize(&mut upstreams)?;
+    assign_names(&mut upstreams);
+    Ok(upstreams)
+}
+
+/// The form of an upstream URL used everywhere after loading, so two
+/// spellings of one endpoint cannot be told apart: the host lowercased,
+/// a default port dropped, the fragment removed and a bare `/` path left
+/// out. Other paths and the query string, which may carry an API key, are
+/// kept as written.
+pub fn canonical_url(url: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(url.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    url.set_fragment(None);
+    let canonical = url.to_string();
+    if url.path() == "/" && url.query().is_none() {
+        return canonical.strip_suffix('/').map(str::to_string);
+    }
+    Some(canonical)
+}
+
+/// Replace upstream URLs by their canonical form, rejecting invalid ones
+/// and two entries for the same endpoint.
+fn canonicalize(upstreams: &mut [UpstreamConfig]) -> Result<()> {
+    for i in 0..upstreams.len() {
+        let url = canonical_url(&upstreams[i].url).ok_or_else(|| {
+            ConfigError::at(
+                format!("upstreams[{}].url", i),
+                format!(
+                    "invalid upstream URL '{}', expected http(s)://host",
+                    upstreams[i].url
+                ),
+            )
+        })?;
+        if let Some(first) = upstreams[..i].iter().position(|u| u.url == url) {
+            return Err(ConfigError::at(
+                format!("upstreams[{}].url", i),
+                format!(
+                    "upstream URL '{}' is the same endpoint as upstreams[{}]",
+                    upstreams[i].url, first
+                ),
+            ));
+        }
+        upstreams[i].url = url;
+        if let Some(ws_url) = &upstreams[i].ws_url {
+            let valid = reqwest::Url::parse(ws_url.trim())
+                .is_ok_and(|url| matches!(url.scheme(), "ws" | "wss") && url.host_str().is_some());
+            if !valid {
+                return Err(ConfigError::at(
+                    format!("upstreams[{}].ws_url", i),
+                    format!("invalid WebSocket URL '{}', expected ws(s)://host", ws_url),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name upstreams without one after their host, with a numeric suffix when
+/// several share it.
+fn assign_names(upstreams: &mut [UpstreamConfig]) {
+    for i in 0..upstreams.len() {
+        if !upstreams[i].name.is_empty() {
+            continue;
+        }
+        let host = reqwest::Url::parse(&upstreams[i].url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| "upstream".to_string());
+        let mut name = host.clone();
+        let mut n = 1;
+        while upstreams.iter().any(|u| u.name == name) {
+            n += 1;
+            name = format!("{}-{}", host, n);
+        }
+        upstreams[i].name = name;
+    }
+}
+
+/// `weekday` ("daily" or a day such as "tue"), `start` ("HH:MM", UTC) and
+/// `duration_mins` of a maintenance window.
+fn maintenance_window(window: &Table) -> Result<MaintenanceWindow> {
+    let weekday = match window
+        .required_string("weekday")?
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "daily" => None,
+        day => match WEEKDAYS.iter().position(|name| day.starts_with(name)) {
+            Some(weekday) => Some(weekday as u32),
+            None => return window.invalid("weekday", "daily or a day such as tue"),
+        },
+    };
+    let start = window.required_string("start")?;
+    let start = match start
+        .split_once(':')
+        .map(|(h, m)| (h.parse::<u64>(), m.parse::<u64>()))
+    {
+        Some((Ok(hours), Ok(minutes))) if hours < 24 && minutes < 60 => hours * 3600 + minutes * 60,
+        _ => return window.invalid("start", "a UTC time as HH:MM"),
+    };
+    let minutes = window.integer("duration_mins", 0)?;
+    if minutes == 0 || minutes > 24 * 60 {
+        return window.invalid("duration_mins", "between 1 and 1440 minutes");
+    }
+    Ok(MaintenanceWindow {
+        weekday,
+        start,
+        duration: Duration::from_secs(minutes * 60),
+    })
+}
+
+/// A `[[upstreams.credentials]]` entry: a `header` or `query` parameter,
+/// with a literal `value` or one read from `value_from_file`.
+fn credential(entry: &Table) -> Result<CredentialConfig> {
+    let placement = match (
+        entry.optional_string("header")?,
+        entry.optional_string("query")?,
+    ) {
+        (Some(_), None) => CredentialPlacement::Header(entry.header_name("header", "")?),
+        (None, Some(query)) if !query.is_empty() => CredentialPlacement::Query(query),
+        _ => return entry.invalid("header", "set, or 'query' instead, but not both"),
+    };
+    let (value, file) = match (
+        entry.optional_string("value")?,
+        entry.optional_string("value_from_file")?,
+    ) {
+        (Some(value), None) => (value, None),
+        (None, Some(file)) => match crate::credentials::read(&file) {
+            Ok(value) => (value, Some(file)),
+            Err(e) => {
+                return Err(ConfigError::at(
+                    entry.key_path("value_from_file"),
+                    format!("cannot read credential file '{}': {}", file, e),
+                ))
+            }
+        },
+        _ => return entry.invalid("value", "set, or 'value_from_file' instead, but not both"),
+    };
+    // the value is kept out of the message, it is a secret
+    if !crate::credentials::valid(&placement, &value) {
+        let key = match file {
+            Some(_) => "value_from_file",
+            None => "value",
+        };
+        return entry.invalid(key, "a value allowed in a header or query parameter");
+    }
+    Ok(CredentialConfig {
+        placement,
+        value,
+        file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{error, UPSTREAM};
+    use crate::config::Config;
+
+    /// An upstream with `settings`, listed after a regular one.
+    fn upstream(settings: &str) -> UpstreamConfig {
+        let text = format!(
+            "{}\n[[upstreams]]\nurl = \"http://127.0.0.1:8900\"\n{}",
+            UPSTREAM, settings
+        );
+        let mut config = Config::parse(&text).unwrap();
+        config.upstreams.remove(1)
+    }
+
+    #[test]
+    fn upstream_urls_are_canonical() {
+        for (url, canonical) in [
+            ("http://127.0.0.1:8899", "http://127.0.0.1:8899"),
+            ("http://127.0.0.1:8899/", "http://127.0.0.1:8899"),
+            (" https://RPC.Example.com ", "https://rpc.example.com"),
+            ("https://rpc.example.com:443/", "https://rpc.example.com"),
+            ("http://rpc.example.com:80", "http://rpc.example.com"),
+            (
+                "https://rpc.example.com:8443",
+                "https://rpc.example.com:8443",
+            ),
+            (
+                "https://rpc.example.com/rpc/v1",
+                "https://rpc.example.com/rpc/v1",
+            ),
+            (
+                "https://rpc.example.com/rpc/v1/",
+                "https://rpc.example.com/rpc/v1/",
+            ),
+            (
+                "https://rpc.example.com/?api-key=K",
+                "https://rpc.example.com/?api-key=K",
+            ),
+            (
+                "https://rpc.example.com?api-key=K#frag",
+                "https://rpc.example.com/?api-key=K",
+            ),
+        ] {
+            assert_eq!(canonical_url(url).as_deref(), Some(canonical), "{}", url);
+        }
+        for url in [
+            "rpc.example.com",
+            "ftp://rpc.example.com",
+            "http://",
+            "file:///tmp/x",
+        ] {
+            assert_eq!(canonical_url(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn two_spellings_of_one_endpoint_are_rejected() {
+        let text = "[[upstreams]]\nurl = \"https://RPC.example.com:443/\"\n\n[[upstreams]]\nurl = \"https://rpc.example.com\"\n";
+        assert!(
+            error(text).ends_with(
+                "upstream URL 'https://rpc.example.com' is the same endpoint as upstreams[0]"
+            ),
+            "{}",
+            error(text)
+        );
+        let text = "[[upstreams]]\nurl = \"rpc.example.com\"\n";
+        assert!(error(text)
+            .ends_with("invalid upstream URL 'rpc.example.com', expected http(s)://host"));
+        let config =
+            Config::parse("[[upstreams]]\nurl = \"https://RPC.example.com/?k=1\"\n").unwrap();
+        assert_eq!(config.upstreams[0].url, "https://rpc.example.com/?k=1");
+    }
+
+    #[test]
+    fn timeouts_default_from_the_top_level() {
+        let config = Config::parse(&format!(
+            "connect_timeout_ms = 300\n\n{}\n[[upstreams]]\nurl = \"http://127.0.0.1:8900\"\nconnect_timeout_ms = 50\nrequest_timeout_ms = 0\n",
+            UPSTREAM
+        ))
+        .unwrap();
+        let (first, second) = (&config.upstreams[0], &config.upstreams[1]);
+        assert_eq!(first.connect_timeout, Duration::from_millis(300));
+        assert_eq!(
+            first.request_timeout,
+            Duration::from_millis(REQUEST_TIMEOUT_MS)
+        );
+        assert_eq!(second.connect_timeout, Duration::from_millis(50));
+        // a zero timeout would fail every attempt
+        assert_eq!(second.request_timeout, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn unnamed_upstreams_are_named_after_their_host() {
+        let config = Config::parse(
+            "[[upstreams]]\nurl = \"http://127.0.0.1:8899\"\n\n[[upstreams]]\nurl = \"http://127.0.0.1:8900\"\n\n[[upstreams]]\nurl = \"https://rpc.example.com\"\nname = \"127.0.0.1-3\"\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = config.upstreams.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, ["127.0.0.1", "127.0.0.1-2", "127.0.0.1-3"]);
+        let addresses = [
+            "http://127.0.0.1:8899".to_string(),
+            "http://127.0.0.1:8900".into(),
+        ];
+        let upstreams = from_urls(&addresses).unwrap();
+        assert_eq!(upstreams[1].name, "127.0.0.1-2");
+        assert_eq!(
+            upstreams[0].connect_timeout,
+            Duration::from_millis(CONNECT_TIMEOUT_MS)
+        );
+        let text = format!("{}name = \"a\"\n\n{}name = \"a\"\n", UPSTREAM, UPSTREAM)
+            .replacen("8899", "8900", 1);
+        assert_eq!(
+            error(&text),
+            "config error: line 7: duplicate upstream name 'a'"
+        );
+    }
+
+    #[test]
+    fn last_resort_upstreams_have_a_modest_default_rate() {
+        let regular = upstream("");
+        assert!(regular.last_resort.is_none());
+        let last_resort = upstream("role = \"last_resort\"\n");
+        let limit = last_resort.last_resort.unwrap();
+        assert_eq!(
+            (limit.rps, limit.burst),
+            (LAST_RESORT_LIMIT.rps, LAST_RESORT_LIMIT.burst)
+        );
+        let limited = upstream("role = \"last_resort\"\n\n[upstreams.last_resort]\nrps = 0.5\n");
+        assert_eq!(limited.last_resort.unwrap().rps, 0.5);
+        let text = format!("{}role = \"backup\"\n", UPSTREAM);
+        assert!(error(&text).ends_with("'upstreams[0].role' must be regular or last_resort"));
+    }
+
+    #[test]
+    fn provider_limit_headers_are_read_lowercased() {
+        let limits = upstream("\n[upstreams.rate_limit]\nremaining_header = \"X-Credits-Left\"\nreset_format = \"unix_ms\"\nrps = 25\n");
+        assert_eq!(limits.rate_limit.remaining_header, "x-credits-left");
+        assert_eq!(limits.rate_limit.reset_header, "x-ratelimit-reset");
+        assert!(limits.rate_limit.reset_format == ResetFormat::UnixMillis);
+        assert_eq!(limits.outbound.unwrap().rps, 25.0);
+        let text = format!(
+            "{}\n[upstreams.rate_limit]\nreset_format = \"iso\"\n",
+            UPSTREAM
+        );
+        assert!(error(&text)
+            .ends_with("'upstreams[0].rate_limit.reset_format' must be seconds, unix or unix_ms"));
+    }
+
+    #[test]
+    fn maintenance_windows_are_checked() {
+        let window =
+            |settings: &str| format!("{}\n[[upstreams.maintenance]]\n{}", UPSTREAM, settings);
+        let text = window("weekday = \"Tuesday\"\nstart = \"03:30\"\nduration_mins = 45\n");
+        let config = Config::parse(&text).unwrap();
+        let tuesday = config.upstreams[0].maintenance[0];
+        assert_eq!(tuesday.weekday, Some(1));
+        assert_eq!(tuesday.start, 3 * 3600 + 30 * 60);
+        assert_eq!(tuesday.duration, Duration::from_secs(45 * 60));
+        let text = window("weekday = \"daily\"\nstart = \"00:00\"\nduration_mins = 1440\n");
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.upstreams[0].maintenance[0].weekday, None);
+        for (settings, message) in [
+            (
+                "weekday = \"someday\"\nstart = \"03:30\"\nduration_mins = 45\n",
+                "'upstreams[0].maintenance[0].weekday' must be daily or a day such as tue",
+            ),
+            (
+                "weekday = \"mon\"\nstart = \"24:00\"\nduration_mins = 45\n",
+                "'upstreams[0].maintenance[0].start' must be a UTC time as HH:MM",
+            ),
+            (
+                "weekday = \"mon\"\nstart = \"03:30\"\n",
+                "'upstreams[0].maintenance[0].duration_mins' must be between 1 and 1440 minutes",
+            ),
+        ] {
+            let text = window(settings);
+            assert!(error(&text).ends_with(message), "{}", error(&text));
+        }
+    }
+
+    #[test]
+    fn credentials_go_in_a_header_or_the_query() {
+        let entry =
+            |settings: &str| format!("{}\n[[upstreams.credentials]]\n{}", UPSTREAM, settings);
+        let config = Config::parse(&entry("header = \"X-Token\"\nvalue = \"secret\"\n")).unwrap();
+        let header = &config.upstreams[0].credentials[0];
+        assert!(
+            header.placement == CredentialPlacement::Header(HeaderName::from_static("x-token"))
+        );
+        assert_eq!(
+            (header.value.as_str(), header.file.as_deref()),
+            ("secret", None)
+        );
+        let config = Config::parse(&entry("query = \"api-key\"\nvalue = \"secret\"\n")).unwrap();
+        let query = &config.upstreams[0].credentials[0].placement;
+        assert!(*query == CredentialPlacement::Query("api-key".into()));
+        for (settings, message) in [
+            (
+                "header = \"x-token\"\nquery = \"api-key\"\nvalue = \"secret\"\n",
+                "'upstreams[0].credentials[0].header' must be set, or 'query' instead, but not both",
+            ),
+            (
+                "header = \"x-token\"\n",
+                "'upstreams[0].credentials[0].value' must be set, or 'value_from_file' instead, but not both",
+            ),
+            (
+                "header = \"x-token\"\nvalue = \"line\\nbreak\"\n",
+                "'upstreams[0].credentials[0].value' must be a value allowed in a header or query parameter",
+            ),
+        ] {
+            let text = entry(settings);
+            assert!(error(&text).ends_with(message), "{}", error(&text));
+        }
+        // the secret stays out of the message
+        let text = entry("header = \"x-token\"\nvalue = \"line\\nbreak\"\n");
+        assert!(!error(&text).contains("break"));
+        let text = entry("header = \"x-token\"\nvalue_from_file = \"/nonexistent/token\"\n");
+        assert!(error(&text).contains("cannot read credential file '/nonexistent/token'"));
+    }
+}
@@ -0,0 +1,413 @@
+//! Credits spent on each provider, by method and UTC day, estimated with
+//! the `[costs]` table from every request sent upstream: race losers,
+//! hedges and probes, and the proxy's own background calls, as providers
+//! bill them all. Kept in the state file, so a month's totals survive
+//! restarts, for `GET /admin/costs` and `/metrics`.
+
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::Response,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Methods tracked per upstream and day, the rest counting as `other`.
+const MAX_METHODS: usize = 256;
+
+#[derive(Clone, Copy, Default)]
+pub struct Spent {
+    pub requests: u64,
+    pub credits: f64,
+}
+
+/// Spending by upstream name, then method.
+type Spending = HashMap<String, HashMap<String, Spent>>;
+
+#[derive(Default)]
+struct Ledger {
+    /// By UTC date, `YYYY-MM-DD`.
+    days: BTreeMap<String, Spending>,
+    /// Since the ledger was started, days past the retention included.
+    totals: Spending,
+}
+
+pub struct CostLedger {
+    ledger: Mutex<Ledger>,
+}
+
+fn today() -> String {
+    let (year, month, day, ..) = crate::clock::civil(crate::clock::unix_secs());
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn add(spending: &mut Spending, upstream: &str, method: &str, credits: f64) {
+    let methods = spending.entry(upstream.to_string()).or_default();
+    let method = if methods.contains_key(method) || methods.len() < MAX_METHODS {
+        method
+    } else {
+        "other"
+    };
+    let spent = methods.entry(method.to_string()).or_default();
+    spent.requests += 1;
+    spent.credits += credits;
+}
+
+fn restore(saved: &Value) -> Spending {
+    let upstreams = saved.as_object().into_iter().flatten();
+    upstreams
+        .map(|(upstream, methods)| {
+            let methods = methods.as_object().into_iter().flatten();
+            let methods = methods.filter_map(|(method, spent)| {
+                let spent = Spent {
+                    requests: spent["requests"].as_u64()?,
+                    credits: spent["credits"].as_f64()?,
+                };
+                Some((method.clone(), spent))
+            });
+            (upstream.clone(), methods.collect())
+        })
+        .collect()
+}
+
+fn save_methods(methods: &HashMap<String, Spent>) -> Value {
+    let methods = methods.iter().map(|(method, spent)| {
+        let spent = json!({ "requests": spent.requests, "credits": spent.credits });
+        (method.clone(), spent)
+    });
+    Value::Object(methods.collect())
+}
+
+fn save(spending: &Spending) -> Value {
+    let upstreams = spending
+        .iter()
+        .map(|(upstream, methods)| (upstream.clone(), save_methods(methods)));
+    Value::Object(upstreams.collect())
+}
+
+impl CostLedger {
+    /// Ledger resuming from the `costs` section of a saved state.
+    pub fn new(saved: &Value) -> Self {
+        let days = saved["days"].as_object().into_iter().flatten();
+        let ledger = Ledger {
+            days: days
+                .map(|(day, spending)| (day.clone(), restore(spending)))
+                .collect(),
+            totals: restore(&saved["totals"]),
+        };
+        Self {
+            ledger: Mutex::new(ledger),
+        }
+    }
+
+    fn record(&self, upstream: &str, method: &str, credits: f64, retention_days: usize) {
+        let day = today();
+        let mut ledger = self.ledger.lock().unwrap();
+        if !ledger.days.contains_key(&day) {
+            while retention_days > 0 && ledger.days.len() >= retention_days {
+                ledger.days.pop_first();
+            }
+        }
+        add(
+            ledger.days.entry(day).or_default(),
+            upstream,
+            method,
+            credits,
+        );
+        add(&mut ledger.totals, upstream, method, credits);
+    }
+
+    /// Totals per upstream and method, for `/metrics`.
+    pub fn totals(&self) -> Spending {
+        self.ledger.lock().unwrap().totals.clone()
+    }
+
+    /// State to persist across restarts.
+    pub fn snapshot(&self) -> Value {
+        let ledger = self.ledger.lock().unwrap();
+        let days = ledger
+            .days
+            .iter()
+            .map(|(day, spending)| (day.clone(), save(spending)));
+        json!({
+            "days": Value::Object(days.collect()),
+            "totals": save(&ledger.totals),
+        })
+    }
+
+    /// Days from `from` to `to`, both included, in order.
+    fn between(&self, from: &str, to: &str) -> Vec<(String, Spending)> {
+        if from > to {
+            return Vec::new();
+        }
+        let ledger = self.ledger.lock().unwrap();
+        ledger
+            .days
+            .range(from.to_string()..=to.to_string())
+            .map(|(day, spending)| (day.clone(), spending.clone()))
+            .collect()
+    }
+}
+
+/// Count a request to upstream `index` for each of `methods`, at their
+/// `[costs]` credits.
+pub fn charge<'a>(config: &ServerConfig, index: usize, methods: impl IntoIterator<Item = &'a str>) {
+    let costs = &config.settings.costs;
+    let upstream = &config.servers[index].name;
+    for method in methods {
+        let credits = costs.cost(method);
+        config
+            .costs
+            .record(upstream, method, credits, costs.retention_days);
+    }
+}
+
+/// `text` as a CSV field, quoted when it has to be.
+fn field(text: &str) -> String {
+    match text.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string(),
+    }
+}
+
+fn csv(days: &[(String, Spending)]) -> String {
+    let mut out = String::from("date,upstream,method,requests,credits\n");
+    for (day, spending) in days {
+        let mut rows: Vec<(&String, &String, &Spent)> = spending
+            .iter()
+            .flat_map(|(upstream, methods)| {
+                methods
+                    .iter()
+                    .map(move |(method, spent)| (upstream, method, spent))
+            })
+            .collect();
+        rows.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        for (upstream, method, spent) in rows {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{}",
+                day,
+                field(upstream),
+                field(method),
+                spent.requests,
+                spent.credits
+            );
+        }
+    }
+    out
+}
+
+/// `GET /admin/costs?from=YYYY-MM-DD&to=YYYY-MM-DD&format=csv`: credits
+/// spent per upstream and method on each day in the range, the current one
+/// by default, with their sum; or one CSV row per day, upstream and method.
+pub async fn costs_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response<Body> {
+    let today = today();
+    let from = params.get("from").unwrap_or(&today);
+    let to = params.get("to").unwrap_or(&today);
+    let days = config.costs.between(from, to);
+    if params.get("format").is_some_and(|format| format == "csv") {
+        return Response::builder()
+            .header("content-type", "text/csv")
+            .body(Body::from(csv(&days)))
+            .unwrap();
+    }
+    let mut sum = Spending::new();
+    for (_, spending) in &days {
+        for (upstream, methods) in spending {
+            let total = sum.entry(upstream.clone()).or_default();
+            for (method, spent) in methods {
+                let added = total.entry(method.clone()).or_default();
+                added.requests += spent.requests;
+                added.credits += spent.credits;
+            }
+        }
+    }
+    let upstreams: Map<String, Value> = sum
+        .iter()
+        .map(|(upstream, methods)| {
+            let credits: f64 = methods.values().map(|spent| spent.credits).sum();
+            let requests: u64 = methods.values().map(|spent| spent.requests).sum();
+            let report = json!({
+                "credits": credits,
+                "requests": requests,
+                "methods": save_methods(methods),
+            });
+            (upstream.clone(), report)
+        })
+        .collect();
+    let days: Map<String, Value> = days
+        .iter()
+        .map(|(day, spending)| (day.clone(), save(spending)))
+        .collect();
+    Json(json!({
+        "from": from,
+        "to": to,
+        "upstreams": upstreams,
+        "days": days,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{call, proxy, upstream};
+    use std::time::Duration;
+
+    async fn eventually(mut condition: impl FnMut() -> bool) -> bool {
+        for _ in 0..200 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    fn spent(config: &ServerConfig, upstream: &str, method: &str) -> u64 {
+        let totals = config.costs.totals();
+        totals
+            .get(upstream)
+            .and_then(|methods| methods.get(method))
+            .map_or(0, |spent| spent.requests)
+    }
+
+    #[tokio::test]
+    async fn every_request_sent_is_charged() {
+        let fast = upstream(Behavior::default()).await;
+        let slow = upstream(Behavior {
+            latency: Duration::from_millis(30),
+            ..Behavior::default()
+        })
+        .await;
+        let failing = upstream(Behavior {
+            fail_rate: 1.0,
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "[costs]\ngetBalance = 2.5\n[circuit_breaker]\ncooldown_ms = 300\n\
+             [[upstreams]]\nname = \"fast\"\nurl = \"{}\"\n\
+             [[upstreams]]\nname = \"slow\"\nurl = \"{}\"\n\
+             [[upstreams]]\nname = \"failing\"\nurl = \"{}\"\n",
+            fast, slow, failing
+        ))
+        .await;
+        let balance = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        for _ in 0..crate::upstream::FAILURE_STREAK_THRESHOLD {
+            assert_eq!(call(&url, balance.clone()).await.status(), 200);
+        }
+        // its circuit open, the failing upstream is left alone until probed
+        assert!(eventually(|| !config.servers[2].circuit.is_closed()).await);
+        assert_eq!(call(&url, balance.clone()).await.status(), 200);
+        assert_eq!(spent(&config, "failing", "getBalance"), 3);
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert_eq!(call(&url, balance.clone()).await.status(), 200);
+        assert_eq!(spent(&config, "failing", "getBalance"), 4);
+        // the slow losers are billed too
+        assert!(eventually(|| spent(&config, "slow", "getBalance") == 5).await);
+        assert_eq!(spent(&config, "fast", "getBalance"), 5);
+        let warmup = crate::config::WarmupConfig {
+            connections: 2,
+            keep_warm_after: None,
+        };
+        crate::warmup::warm_all(&config, &warmup).await;
+        assert_eq!(spent(&config, "fast", "getHealth"), 2);
+
+        let report: Value = reqwest::get(format!("{}/admin/costs", url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let fast = &report["upstreams"]["fast"];
+        assert_eq!(fast["requests"], 7);
+        assert_eq!(fast["credits"], 14.5);
+        assert_eq!(fast["methods"]["getBalance"]["credits"], 12.5);
+        assert_eq!(report["days"].as_object().unwrap().len(), 1);
+        let csv = reqwest::get(format!("{}/admin/costs?format=csv", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let today = today();
+        assert!(csv.starts_with("date,upstream,method,requests,credits\n"));
+        assert!(
+            csv.contains(&format!("\n{},failing,getBalance,4,10\n", today)),
+            "{}",
+            csv
+        );
+        assert!(
+            csv.contains(&format!("\n{},fast,getHealth,2,2\n", today)),
+            "{}",
+            csv
+        );
+        // nothing for days outside the range
+        let past = reqwest::get(format!(
+            "{}/admin/costs?from=2020-01-01&to=2020-01-31&format=csv",
+            url
+        ))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(past, "date,upstream,method,requests,credits\n");
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains(
+            "quarantier_upstream_credits_total{upstream=\"slow\",method=\"getBalance\"} 12.5\n"
+        ));
+
+        // a restart carries on from the saved ledger
+        let restarted = CostLedger::new(&config.costs.snapshot());
+        assert_eq!(restarted.snapshot(), config.costs.snapshot());
+        restarted.record("fast", "getBalance", 2.5, 400);
+        let totals = restarted.totals();
+        assert_eq!(totals["fast"]["getBalance"].requests, 6);
+        assert_eq!(totals["fast"]["getBalance"].credits, 15.0);
+    }
+
+    #[tokio::test]
+    async fn abandoned_requests_count_unless_told_otherwise() {
+        let fast = upstream(Behavior::default()).await;
+        let slow = upstream(Behavior {
+            latency: Duration::from_millis(300),
+            ..Behavior::default()
+        })
+        .await;
+        for (count_aborted, charged) in [(true, 1), (false, 0)] {
+            let (config, url) = proxy(&format!(
+                "max_lingering_dispatches = 0\n[costs]\ncount_aborted = {}\n\
+                 [[upstreams]]\nname = \"fast\"\nurl = \"{}\"\n\
+                 [[upstreams]]\nname = \"slow\"\nurl = \"{}\"\n",
+                count_aborted, fast, slow
+            ))
+            .await;
+            let response = call(
+                &url,
+                serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" }),
+            )
+            .await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(spent(&config, "fast", "getSlot"), 1);
+            // the loser is dropped rather than waited for, none may linger
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(spent(&config, "slow", "getSlot"), charged);
+        }
+    }
+}
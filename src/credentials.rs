@@ -0,0 +1,341 @@
+//! Keys sent to an upstream with every request, as a header or a query
+//! parameter, from `[[upstreams.credentials]]`. A value read from
+//! `value_from_file` is re-read whenever the file changes, so a secrets
+//! manager can rotate it without a restart or a new connection pool; requests
+//! already sent keep the value they went out with. The values never reach
+//! the logs.
+
+use crate::config::{CredentialConfig, CredentialPlacement};
+use crate::ServerConfig;
+use axum::http::HeaderValue;
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often credential files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The contents of a credential file, without the trailing newline editors
+/// and `echo` leave.
+pub fn read(path: &str) -> std::io::Result<String> {
+    let value = std::fs::read_to_string(path)?;
+    Ok(value.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Whether `value` can be sent where `placement` puts it.
+pub fn valid(placement: &CredentialPlacement, value: &str) -> bool {
+    match placement {
+        CredentialPlacement::Header(_) => HeaderValue::from_str(value).is_ok(),
+        CredentialPlacement::Query(_) => true,
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct Credential {
+    placement: CredentialPlacement,
+    value: RwLock<String>,
+    file: Option<String>,
+    /// Modification time of `file` when `value` was read.
+    modified: Mutex<Option<SystemTime>>,
+    /// Whether the last re-read failed, so a missing file is logged once.
+    failing: AtomicBool,
+}
+
+impl Credential {
+    /// What the log calls it, never its value.
+    fn describe(&self) -> String {
+        match &self.placement {
+            CredentialPlacement::Header(name) => format!("header {}", name),
+            CredentialPlacement::Query(name) => format!("query parameter {}", name),
+        }
+    }
+}
+
+/// The credentials of one upstream.
+pub struct Credentials {
+    credentials: Vec<Credential>,
+}
+
+impl Credentials {
+    pub fn new(settings: &[CredentialConfig]) -> Self {
+        let credentials = settings.iter().map(|credential| Credential {
+            placement: credential.placement.clone(),
+            value: RwLock::new(credential.value.clone()),
+            modified: Mutex::new(credential.file.as_deref().and_then(modified)),
+            file: credential.file.clone(),
+            failing: AtomicBool::new(false),
+        });
+        Self {
+            credentials: credentials.collect(),
+        }
+    }
+
+    /// Whether any is read from a file, which then has to be watched.
+    pub fn watched(&self) -> bool {
+        self.credentials.iter().any(|c| c.file.is_some())
+    }
+
+    /// Where each goes and a fingerprint of its current value, for the
+    /// effective config.
+    pub fn report(&self) -> Vec<Value> {
+        let credentials = self.credentials.iter().map(|credential| {
+            let (placement, name) = match &credential.placement {
+                CredentialPlacement::Header(name) => ("header", name.as_str()),
+                CredentialPlacement::Query(name) => ("query", name.as_str()),
+            };
+            json!({
+                placement: name,
+                "value": crate::effective::fingerprint(&credential.value.read().unwrap()),
+                "value_from_file": credential.file,
+            })
+        });
+        credentials.collect()
+    }
+
+    /// `request` with the current values added.
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        for credential in &self.credentials {
+            let value = credential.value.read().unwrap();
+            request = match &credential.placement {
+                CredentialPlacement::Header(name) => request.header(name.as_str(), value.as_str()),
+                CredentialPlacement::Query(name) => request.query(&[(name, value.as_str())]),
+            };
+        }
+        request
+    }
+}
+
+/// `request` with `settings` added, for callers without a running upstream.
+pub fn apply(settings: &[CredentialConfig], request: RequestBuilder) -> RequestBuilder {
+    Credentials::new(settings).apply(request)
+}
+
+/// Re-read the credential of `upstream` from its file if the file changed.
+fn refresh(config: &ServerConfig, upstream: &str, credential: &Credential) {
+    let Some(path) = &credential.file else {
+        return;
+    };
+    let now = modified(path);
+    if now.is_some() && now == *credential.modified.lock().unwrap() {
+        return;
+    }
+    let value = match read(path) {
+        Ok(value) if valid(&credential.placement, &value) => value,
+        result => {
+            if !credential.failing.swap(true, Ordering::Relaxed) {
+                let reason = match result {
+                    Err(e) => e.to_string(),
+                    Ok(_) => "the value cannot be sent".to_string(),
+                };
+                let msg = format!(
+                    "Cannot read the credential {} of {} from {}: {}, keeping the current one",
+                    credential.describe(),
+                    upstream,
+                    path,
+                    reason
+                );
+                println!("+ {}", msg);
+                config.events.record(msg);
+            }
+            return;
+        }
+    };
+    credential.failing.store(false, Ordering::Relaxed);
+    *credential.modified.lock().unwrap() = now;
+    let mut current = credential.value.write().unwrap();
+    if *current == value {
+        return;
+    }
+    *current = value;
+    drop(current);
+    let msg = format!(
+        "Rotated the credential {} of {} from {}",
+        credential.describe(),
+        upstream,
+        path
+    );
+    println!("+ {}", msg);
+    config.events.record(msg);
+}
+
+/// Check the credential files of every upstream forever, picking up new
+/// values for the requests to come.
+pub async fn watch(config: Arc<ServerConfig>) {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        for upstream in &config.servers {
+            for credential in &upstream.credentials.credentials {
+                refresh(&config, &upstream.name, credential);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::extract::ConnectInfo;
+    use axum::http::{HeaderMap, Uri};
+    use axum::{routing::post, Router};
+    use std::net::SocketAddr;
+
+    fn temp_path() -> String {
+        let name = format!("quarantier-key-{}", crate::random::u64());
+        std::env::temp_dir()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Rewrite the file at `path` with `value`, moving its modification
+    /// time on so the change is seen however coarse the clock.
+    fn rotate(path: &str, value: &str) {
+        std::fs::write(path, value).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+    }
+
+    /// What a node saw of each request: its `x-api-key` header, query and
+    /// the client's address.
+    type Seen = Arc<Mutex<Vec<(String, String, SocketAddr)>>>;
+
+    async fn node(seen: Seen) -> String {
+        crate::testing::serve(
+            Router::new().route(
+                "/",
+                post(
+                    move |ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                          uri: Uri,
+                          headers: HeaderMap| async move {
+                        let key = headers.get("x-api-key").map_or("", |v| v.to_str().unwrap());
+                        let query = uri.query().unwrap_or_default().to_string();
+                        seen.lock().unwrap().push((key.to_string(), query, peer));
+                        r#"{"jsonrpc":"2.0","id":1,"result":7}"#
+                    },
+                ),
+            ),
+        )
+        .await
+    }
+
+    #[test]
+    fn files_are_read_at_startup_without_their_newline() {
+        let path = temp_path();
+        std::fs::write(&path, "s3cret\r\n").unwrap();
+        assert_eq!(read(&path).unwrap(), "s3cret");
+        let text = |path: &str| {
+            format!(
+                "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n[[upstreams.credentials]]\nheader = \"x-api-key\"\nvalue_from_file = \"{}\"\n",
+                path
+            )
+        };
+        let config = Config::parse(&text(&path)).unwrap();
+        assert_eq!(config.upstreams[0].credentials[0].value, "s3cret");
+        let missing = Config::parse(&text("/nonexistent/key"))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            missing.contains("cannot read credential file '/nonexistent/key'"),
+            "{}",
+            missing
+        );
+        // a value that cannot go in a header is refused, and not shown
+        std::fs::write(&path, "s3cret\nagain").unwrap();
+        let invalid = Config::parse(&text(&path)).err().unwrap().to_string();
+        assert!(invalid.contains("value_from_file"), "{}", invalid);
+        assert!(!invalid.contains("s3cret"), "{}", invalid);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn rotated_values_apply_to_the_next_requests_on_the_same_connections() {
+        let seen = Seen::default();
+        let url = node(seen.clone()).await;
+        let (header, query) = (temp_path(), temp_path());
+        std::fs::write(&header, "first\n").unwrap();
+        std::fs::write(&query, "one").unwrap();
+        let (config, proxy) = crate::testing::proxy(&format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n\
+             [[upstreams.credentials]]\nheader = \"x-api-key\"\nvalue_from_file = \"{}\"\n\
+             [[upstreams.credentials]]\nquery = \"api-key\"\nvalue_from_file = \"{}\"\n",
+            url, header, query
+        ))
+        .await;
+        let get_slot = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" });
+        let refresh_all = || {
+            for credential in &config.servers[0].credentials.credentials {
+                refresh(&config, "a", credential);
+            }
+        };
+        assert_eq!(
+            crate::testing::call(&proxy, get_slot.clone())
+                .await
+                .status(),
+            200
+        );
+        // nothing changed, nothing happens
+        refresh_all();
+        assert!(config.events.recent().is_empty());
+        rotate(&header, "second\n");
+        rotate(&query, "two");
+        refresh_all();
+        assert_eq!(
+            crate::testing::call(&proxy, get_slot.clone())
+                .await
+                .status(),
+            200
+        );
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(
+            (seen[0].0.as_str(), seen[0].1.as_str()),
+            ("first", "api-key=one")
+        );
+        assert_eq!(
+            (seen[1].0.as_str(), seen[1].1.as_str()),
+            ("second", "api-key=two")
+        );
+        // the pooled connection was kept
+        assert_eq!(seen[0].2, seen[1].2);
+        let events: Vec<String> = config.events.recent().into_iter().map(|(_, e)| e).collect();
+        assert_eq!(
+            events,
+            [
+                format!(
+                    "Rotated the credential header x-api-key of a from {}",
+                    header
+                ),
+                format!(
+                    "Rotated the credential query parameter api-key of a from {}",
+                    query
+                ),
+            ]
+        );
+
+        // a file gone is reported once and its last value kept
+        std::fs::remove_file(&header).unwrap();
+        refresh_all();
+        refresh_all();
+        let events = config.events.recent();
+        assert_eq!(events.len(), 3);
+        assert!(
+            events[2].1.ends_with("keeping the current one"),
+            "{}",
+            events[2].1
+        );
+        assert!(!events[2].1.contains("second"));
+        let request = config.servers[0].post().build().unwrap();
+        assert_eq!(request.headers()["x-api-key"], "second");
+        let _ = std::fs::remove_file(&query);
+    }
+}
@@ -0,0 +1,394 @@
+//! Deduplication of `sendTransaction` by signature. Wallet retry loops
+//! submit the same signed transaction several times a second, and every
+//! submission would otherwise be broadcast to every upstream. The first
+//! signature of each submission is remembered for a short while; repeats
+//! are answered with it once the first was accepted, or sent to a single
+//! upstream.
+
+use crate::base58;
+use crate::config::SendDedupConfig;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::watch;
+
+pub type Signature = [u8; 64];
+
+/// The first signature of a serialized transaction, which starts with the
+/// signature count as a compact-u16 followed by 64-byte signatures.
+pub fn first_signature(transaction: &[u8]) -> Option<Signature> {
+    let mut count = 0usize;
+    let mut offset = 0;
+    for (i, byte) in transaction.iter().take(3).enumerate() {
+        count |= ((byte & 0x7f) as usize) << (7 * i);
+        offset = i + 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+    transaction.get(offset..offset + 64)?.try_into().ok()
+}
+
+/// The signature a single `sendTransaction` call submits, and the call's
+/// id, or `None` for anything else, batches included.
+pub fn submitted(body: &[u8]) -> Option<(Signature, Value)> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    let transaction = transaction(&request)?;
+    Some((first_signature(&transaction)?, request["id"].clone()))
+}
+
+/// The serialized transaction a single `sendTransaction` call submits.
+pub fn transaction(request: &Value) -> Option<Vec<u8>> {
+    if request.get("method")? != "sendTransaction" {
+        return None;
+    }
+    let transaction = request["params"][0].as_str()?;
+    // base58 is the default, as on the validator
+    match request["params"][1]["encoding"].as_str() {
+        None | Some("base58") => base58::decode(transaction),
+        Some("base64") => STANDARD.decode(transaction).ok(),
+        Some(_) => None,
+    }
+}
+
+/// Answer to a repeated submission: the signature, as a successful
+/// `sendTransaction` returns it.
+pub fn answer(signature: &Signature, id: &Value) -> String {
+    json!({ "jsonrpc": "2.0", "result": base58::encode(signature), "id": id }).to_string()
+}
+
+/// What became of the first submission of a signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Still being broadcast.
+    Pending,
+    /// An upstream accepted it.
+    Sent,
+    /// It failed, or its outcome was lost; it was forgotten.
+    Failed,
+}
+
+/// The outcome of the first submission, for a repeat to wait on.
+pub struct First(watch::Receiver<Outcome>);
+
+impl First {
+    /// Wait until the first submission is settled.
+    pub async fn settled(mut self) -> Outcome {
+        match self
+            .0
+            .wait_for(|outcome| *outcome != Outcome::Pending)
+            .await
+        {
+            Ok(outcome) => *outcome,
+            // evicted before it was settled
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
+
+struct Submitted {
+    at: Instant,
+    outcome: watch::Sender<Outcome>,
+}
+
+#[derive(Default)]
+struct Recent {
+    submitted: HashMap<Signature, Submitted>,
+    /// Signatures in submission order, to expire and evict the oldest; an
+    /// entry whose time no longer matches `submitted` was already removed.
+    order: VecDeque<(Signature, Instant)>,
+}
+
+impl Recent {
+    /// Drop the oldest submission, or return false when there is none.
+    fn evict_oldest(&mut self) -> bool {
+        let Some((oldest, at)) = self.order.pop_front() else {
+            return false;
+        };
+        if self.submitted.get(&oldest).is_some_and(|s| s.at == at) {
+            self.submitted.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[derive(Default)]
+pub struct SendDedup {
+    recent: Mutex<Recent>,
+}
+
+impl SendDedup {
+    /// The first submission `signature` repeats, if it was submitted within
+    /// the TTL; if not, it is remembered from now on, pending until
+    /// [`SendDedup::settle`]. Repeats do not extend the TTL, so a retry loop
+    /// gets a submission through every TTL.
+    pub fn repeated(&self, settings: &SendDedupConfig, signature: &Signature) -> Option<First> {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        while recent
+            .order
+            .front()
+            .is_some_and(|(_, at)| now.duration_since(*at) >= settings.ttl)
+        {
+            recent.evict_oldest();
+        }
+        if let Some(submitted) = recent.submitted.get(signature) {
+            return Some(First(submitted.outcome.subscribe()));
+        }
+        while recent.submitted.len() >= settings.max_entries && recent.evict_oldest() {}
+        let submitted = Submitted {
+            at: now,
+            outcome: watch::Sender::new(Outcome::Pending),
+        };
+        recent.submitted.insert(*signature, submitted);
+        recent.order.push_back((*signature, now));
+        None
+    }
+
+    /// Record how the submission of `signature` ended. A failed one is
+    /// forgotten, so the next attempt is broadcast rather than answered as
+    /// a success, and the repeats waiting on it are broadcast too.
+    pub fn settle(&self, signature: &Signature, sent: bool) {
+        let mut recent = self.recent.lock().unwrap();
+        match sent {
+            true => {
+                if let Some(submitted) = recent.submitted.get(signature) {
+                    submitted.outcome.send_replace(Outcome::Sent);
+                }
+            }
+            false => {
+                if let Some(submitted) = recent.submitted.remove(signature) {
+                    submitted.outcome.send_replace(Outcome::Failed);
+                }
+            }
+        }
+    }
+
+    /// Forget `signature` after its submission failed.
+    pub fn forget(&self, signature: &Signature) {
+        self.settle(signature, false);
+    }
+
+    /// A guard forgetting `signature` when dropped still pending, so the
+    /// repeats of a submission abandoned on any path are not kept waiting.
+    pub fn pending(&self, signature: Signature) -> Pending<'_> {
+        Pending {
+            dedup: self,
+            signature,
+        }
+    }
+}
+
+pub struct Pending<'a> {
+    dedup: &'a SendDedup,
+    signature: Signature,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let mut recent = self.dedup.recent.lock().unwrap();
+        let settled = recent
+            .submitted
+            .get(&self.signature)
+            .is_none_or(|submitted| *submitted.outcome.borrow() != Outcome::Pending);
+        if !settled {
+            if let Some(submitted) = recent.submitted.remove(&self.signature) {
+                submitted.outcome.send_replace(Outcome::Failed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateSends;
+    use crate::testing::{call, proxy, serve};
+    use axum::{routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn settings() -> SendDedupConfig {
+        SendDedupConfig {
+            ttl: Duration::from_secs(2),
+            max_entries: 2,
+            duplicates: DuplicateSends::Answer,
+        }
+    }
+
+    #[test]
+    fn first_signature_follows_the_count() {
+        let mut transaction = vec![1];
+        transaction.extend([7; 64]);
+        assert_eq!(first_signature(&transaction), Some([7; 64]));
+        assert_eq!(first_signature(&[0]), None);
+        assert_eq!(first_signature(&transaction[..40]), None);
+    }
+
+    #[tokio::test]
+    async fn a_repeat_waits_for_the_first() {
+        let dedup = SendDedup::default();
+        assert!(dedup.repeated(&settings(), &[1; 64]).is_none());
+        let first = dedup.repeated(&settings(), &[1; 64]).unwrap();
+        let waiting = tokio::spawn(first.settled());
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        dedup.settle(&[1; 64], true);
+        assert_eq!(waiting.await.unwrap(), Outcome::Sent);
+        // settled repeats are answered at once
+        let first = dedup.repeated(&settings(), &[1; 64]).unwrap();
+        assert_eq!(first.settled().await, Outcome::Sent);
+    }
+
+    #[tokio::test]
+    async fn a_failed_first_is_forgotten() {
+        let dedup = SendDedup::default();
+        assert!(dedup.repeated(&settings(), &[1; 64]).is_none());
+        let first = dedup.repeated(&settings(), &[1; 64]).unwrap();
+        dedup.forget(&[1; 64]);
+        assert_eq!(first.settled().await, Outcome::Failed);
+        assert!(dedup.repeated(&settings(), &[1; 64]).is_none());
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_first_is_forgotten() {
+        let dedup = SendDedup::default();
+        assert!(dedup.repeated(&settings(), &[1; 64]).is_none());
+        let first = dedup.repeated(&settings(), &[1; 64]).unwrap();
+        drop(dedup.pending([1; 64]));
+        assert_eq!(first.settled().await, Outcome::Failed);
+        // a settled one is left alone
+        assert!(dedup.repeated(&settings(), &[2; 64]).is_none());
+        dedup.settle(&[2; 64], true);
+        drop(dedup.pending([2; 64]));
+        assert!(dedup.repeated(&settings(), &[2; 64]).is_some());
+    }
+
+    #[test]
+    fn the_oldest_is_evicted_past_max_entries() {
+        let dedup = SendDedup::default();
+        for signature in [[1; 64], [2; 64], [3; 64]] {
+            assert!(dedup.repeated(&settings(), &signature).is_none());
+        }
+        assert!(dedup.repeated(&settings(), &[1; 64]).is_none());
+        assert!(dedup.repeated(&settings(), &[3; 64]).is_some());
+    }
+
+    /// An upstream taking `latency` over each submission and then accepting
+    /// or refusing it, with the count of submissions it got.
+    async fn sink(latency: Duration, accept: bool) -> (String, Arc<AtomicUsize>) {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counted = sent.clone();
+        let url = serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                let Some(transaction) = transaction(&request) else {
+                    return Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": 0 }));
+                };
+                counted.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(latency).await;
+                let signature = base58::encode(&first_signature(&transaction).unwrap());
+                Json(match accept {
+                    true => json!({ "jsonrpc": "2.0", "id": request["id"], "result": signature }),
+                    false => json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": -32002, "message": "Transaction simulation failed" },
+                    }),
+                })
+            }),
+        ))
+        .await;
+        (url, sent)
+    }
+
+    fn send(signature: u8, id: u64) -> Value {
+        let mut transaction = vec![1];
+        transaction.extend([signature; 64]);
+        transaction.extend([0; 32]);
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "sendTransaction",
+            "params": [STANDARD.encode(transaction), { "encoding": "base64" }],
+        })
+    }
+
+    async fn proxy_for(sinks: &[&str], dedup: &str) -> (Arc<crate::ServerConfig>, String) {
+        let mut text = String::new();
+        for url in sinks {
+            text += &format!("[[upstreams]]\nurl = \"{}\"\n\n", url);
+        }
+        proxy(&format!("{}[send_dedup]\n{}", text, dedup)).await
+    }
+
+    #[tokio::test]
+    async fn concurrent_repeats_are_answered_from_the_first() {
+        let (a, to_a) = sink(Duration::from_millis(200), true).await;
+        let (b, to_b) = sink(Duration::from_millis(200), true).await;
+        let (config, url) = proxy_for(&[&a, &b], "ttl_ms = 600\n").await;
+        // all in flight at once, the repeats waiting on the first
+        let first = tokio::spawn({
+            let url = url.clone();
+            async move { call(&url, send(1, 0)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let repeats = futures::future::join_all((1..5).map(|id| call(&url, send(1, id)))).await;
+        let answers = std::iter::once(first.await.unwrap()).chain(repeats);
+        for (id, answer) in answers.enumerate() {
+            assert_eq!(answer.status(), 200);
+            let answer: Value = answer.json().await.unwrap();
+            assert_eq!(answer["id"], id);
+            assert_eq!(answer["result"], base58::encode(&[1; 64]));
+        }
+        assert_eq!(to_a.load(Ordering::SeqCst), 1);
+        assert_eq!(to_b.load(Ordering::SeqCst), 1);
+        let (_, usage) = &config.usage.top(1)[0];
+        assert_eq!(usage.send_duplicates, 4);
+        // another transaction is no repeat
+        assert_eq!(call(&url, send(2, 5)).await.status(), 200);
+        assert_eq!(to_a.load(Ordering::SeqCst), 2);
+        // past the TTL the retry is broadcast again
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(call(&url, send(1, 6)).await.status(), 200);
+        assert_eq!(to_a.load(Ordering::SeqCst), 3);
+        assert_eq!(to_b.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn repeats_of_a_refused_first_are_broadcast() {
+        let (a, to_a) = sink(Duration::from_millis(200), false).await;
+        let (config, url) = proxy_for(&[&a], "").await;
+        let first = tokio::spawn({
+            let url = url.clone();
+            async move { call(&url, send(1, 0)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let repeats = futures::future::join_all((1..3).map(|id| call(&url, send(1, id)))).await;
+        for answer in std::iter::once(first.await.unwrap()).chain(repeats) {
+            let answer: Value = answer.json().await.unwrap();
+            assert_eq!(answer["error"]["code"], -32002, "{}", answer);
+        }
+        assert_eq!(to_a.load(Ordering::SeqCst), 3);
+        assert!(config.sends.recent.lock().unwrap().submitted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeats_can_go_to_one_upstream() {
+        let (a, to_a) = sink(Duration::from_millis(100), true).await;
+        let (b, to_b) = sink(Duration::from_millis(100), true).await;
+        let (_, url) = proxy_for(&[&a, &b], "duplicates = \"forward_one\"\n").await;
+        assert_eq!(call(&url, send(1, 1)).await.status(), 200);
+        let repeat: Value = call(&url, send(1, 2)).await.json().await.unwrap();
+        assert_eq!(repeat["result"], base58::encode(&[1; 64]));
+        let sent = to_a.load(Ordering::SeqCst) + to_b.load(Ordering::SeqCst);
+        assert_eq!(sent, 3);
+    }
+}
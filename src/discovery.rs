@@ -0,0 +1,394 @@
+//! What each upstream can serve, found by probing it at startup and every
+//! `[discovery] interval_secs` with a few cheap calls: `getVersion`, a
+//! `getBlock` older than `[history] retention_slots` for the history it
+//! keeps, a one-signature `getSignaturesForAddress` for its transaction
+//! index and, with `das = true`, a `getAsset`. Findings add to the config:
+//! a node found to keep old blocks counts as an archive, one answering
+//! `getAsset` has the `das` capability, and one without an index gets no
+//! history calls. A node lacking what it is declared to have is only
+//! reported. `getIdentity` tells upstreams configured twice, see
+//! `identity`.
+
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Longest one probe call may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Pause between the probe calls to one upstream, so a round is gentle on
+/// provider rate limits.
+const PROBE_GAP: Duration = Duration::from_millis(200);
+/// Slots below the retention window the history probe asks about.
+const HISTORY_MARGIN: u64 = 10_000;
+/// Slots tried downwards when the first is skipped.
+const HISTORY_TRIES: u64 = 3;
+/// Methods a node without a transaction index cannot answer.
+const TRANSACTION_HISTORY_METHODS: [&str; 4] = [
+    "getSignaturesForAddress",
+    "getConfirmedSignaturesForAddress2",
+    "getTransaction",
+    "getConfirmedTransaction",
+];
+/// A busy account whose newest signature is always there.
+const PROBE_ADDRESS: &str = "Vote111111111111111111111111111111111111111";
+
+/// Results of one probe round; `None` where the probe got no usable answer.
+#[derive(Clone, PartialEq)]
+struct Findings {
+    version: Option<String>,
+    /// Whether a block older than the retention window was served.
+    history: Option<bool>,
+    transaction_history: Option<bool>,
+    das: Option<bool>,
+}
+
+#[derive(Default)]
+pub struct Discovered {
+    findings: Mutex<Option<(Findings, Instant)>>,
+    archive: AtomicBool,
+    /// Mask of the capabilities found, see `config::CapabilityTable`.
+    capabilities: AtomicU64,
+}
+
+impl Discovered {
+    pub fn is_archive(&self) -> bool {
+        self.archive.load(Ordering::Relaxed)
+    }
+
+    pub fn capabilities(&self) -> u64 {
+        self.capabilities.load(Ordering::Relaxed)
+    }
+
+    /// Findings of the last round, for `/status`.
+    pub fn report(&self) -> Value {
+        match &*self.findings.lock().unwrap() {
+            Some((findings, at)) => json!({
+                "version": findings.version,
+                "history": findings.history,
+                "transaction_history": findings.transaction_history,
+                "das": findings.das,
+                "age_secs": at.elapsed().as_secs(),
+            }),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Send `method` to `upstream`, then pause for `PROBE_GAP`.
+pub async fn call(
+    config: &ServerConfig,
+    upstream: &Upstream,
+    method: &str,
+    params: Value,
+) -> Option<Value> {
+    if !upstream.take_token() {
+        return None;
+    }
+    crate::costs::charge(config, upstream.index, [method]);
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let request = upstream
+        .post()
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send();
+    let answer = tokio::time::timeout(PROBE_TIMEOUT, async {
+        request.await?.json::<Value>().await
+    })
+    .await;
+    tokio::time::sleep(PROBE_GAP).await;
+    answer.ok()?.ok()
+}
+
+/// Whether `upstream` serves a block from `slot` or, when it was skipped,
+/// from one of the slots below.
+async fn keeps_block(config: &ServerConfig, upstream: &Upstream, slot: u64) -> Option<bool> {
+    let options = json!({
+        "transactionDetails": "none",
+        "rewards": false,
+        "maxSupportedTransactionVersion": 0,
+    });
+    for slot in (slot.saturating_sub(HISTORY_TRIES - 1)..=slot).rev() {
+        let answer = call(config, upstream, "getBlock", json!([slot, options])).await?;
+        // -32007: skipped, or missing after a snapshot jump
+        match answer["error"]["code"].as_i64() {
+            Some(-32007) => continue,
+            Some(_) => return Some(false),
+            None => return Some(answer["result"].is_object()),
+        }
+    }
+    None
+}
+
+async fn probe(config: &ServerConfig, upstream: &Upstream, das: bool) -> Findings {
+    let version = call(config, upstream, "getVersion", json!([])).await;
+    let version = version.and_then(|v| v["result"]["solana-core"].as_str().map(str::to_string));
+    let slot = call(config, upstream, "getSlot", json!([])).await;
+    let history = match slot.and_then(|slot| slot["result"].as_u64()) {
+        Some(slot) => {
+            let old = slot.saturating_sub(config.settings.retention_slots + HISTORY_MARGIN);
+            keeps_block(config, upstream, old).await
+        }
+        None => None,
+    };
+    let index = call(
+        config,
+        upstream,
+        "getSignaturesForAddress",
+        json!([PROBE_ADDRESS, { "limit": 1 }]),
+    )
+    .await;
+    let transaction_history = index.and_then(|answer| answered(&answer));
+    let das = match das {
+        true => call(config, upstream, "getAsset", json!({ "id": PROBE_ADDRESS }))
+            .await
+            .and_then(|answer| answered(&answer)),
+        false => None,
+    };
+    crate::identity::sample(config, upstream).await;
+    Findings {
+        version,
+        history,
+        transaction_history,
+        das,
+    }
+}
+
+/// Whether a probe's answer shows the method is served: any result, or an
+/// error other than the method being unsupported, such as not found.
+fn answered(answer: &Value) -> Option<bool> {
+    if crate::unsupported::is_unsupported(answer) {
+        Some(false)
+    } else if answer.get("result").is_some() || answer.get("error").is_some() {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn yes_no(found: Option<bool>) -> &'static str {
+    match found {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Apply the findings of upstream `index` to its routing, reporting what
+/// changed since the last round.
+fn apply(config: &ServerConfig, index: usize, findings: Findings, hold: Duration) {
+    let upstream = &config.servers[index];
+    let state = &upstream.discovery;
+    let previous = state
+        .findings
+        .lock()
+        .unwrap()
+        .replace((findings.clone(), Instant::now()))
+        .map(|(previous, _)| previous);
+
+    state
+        .archive
+        .store(findings.history == Some(true), Ordering::Relaxed);
+    let das = config.settings.capabilities.mask(&["das".to_string()]);
+    let found = match findings.das {
+        Some(true) => das,
+        _ => 0,
+    };
+    state.capabilities.store(found, Ordering::Relaxed);
+    if let Some(available) = findings.transaction_history {
+        let methods = TRANSACTION_HISTORY_METHODS;
+        upstream.unsupported.discovered(&methods, !available, hold);
+    }
+
+    if previous.as_ref() == Some(&findings) {
+        return;
+    }
+    println!(
+        "+ Discovered {}: version {}, history {}, transaction history {}, DAS {}",
+        upstream.name,
+        findings.version.as_deref().unwrap_or("unknown"),
+        yes_no(findings.history),
+        yes_no(findings.transaction_history),
+        yes_no(findings.das)
+    );
+    let was = |finding: fn(&Findings) -> Option<bool>| previous.as_ref().and_then(finding);
+    if findings.transaction_history == Some(false) && was(|f| f.transaction_history) != Some(false)
+    {
+        log(
+            config,
+            format!(
+                "{} has no transaction history, is --enable-rpc-transaction-history set? Not sending it history calls",
+                upstream.name
+            ),
+        );
+    }
+    if upstream.archive && findings.history == Some(false) && was(|f| f.history) != Some(false) {
+        log(
+            config,
+            format!(
+                "{} is declared an archive but has no block older than the retention window",
+                upstream.name
+            ),
+        );
+    }
+    if upstream.capabilities & das != 0
+        && findings.das == Some(false)
+        && was(|f| f.das) != Some(false)
+    {
+        log(
+            config,
+            format!(
+                "{} is declared to have das but does not serve getAsset",
+                upstream.name
+            ),
+        );
+    }
+}
+
+/// Probe every regular upstream now, signalling `first_round` once done,
+/// and again every `interval`, forever.
+pub async fn follow(
+    config: Arc<ServerConfig>,
+    settings: crate::config::DiscoveryConfig,
+    first_round: oneshot::Sender<()>,
+) {
+    let mut first_round = Some(first_round);
+    let mut ticker = tokio::time::interval(settings.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // unsupported findings last until the round after next, so one failed
+    // round does not drop them
+    let hold = settings.interval * 2 + PROBE_TIMEOUT * 8;
+    loop {
+        ticker.tick().await;
+        // last-resort upstreams are not asked more than they must, dead ones
+        // only by their circuit's probes, and another cluster's not at all
+        let upstreams = config
+            .servers
+            .iter()
+            .filter(|u| !u.is_last_resort() && u.circuit.dead().is_none())
+            .filter(|u| !u.cluster.is_wrong());
+        let findings = futures::future::join_all(upstreams.map(|upstream| async {
+            (upstream.index, probe(&config, upstream, settings.das).await)
+        }))
+        .await;
+        for (index, findings) in findings {
+            apply(&config, index, findings, hold);
+        }
+        crate::identity::detect(&config);
+        if let Some(first_round) = first_round.take() {
+            let _ = first_round.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+
+    /// A node answering each call as `answer` says.
+    async fn node(answer: fn(&str, &Value) -> Value) -> String {
+        crate::testing::serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                let method = request["method"].as_str().unwrap_or_default();
+                let mut answer = answer(method, &request["params"]);
+                answer["jsonrpc"] = json!("2.0");
+                answer["id"] = request["id"].clone();
+                Json(answer)
+            }),
+        ))
+        .await
+    }
+
+    const TIP: u64 = 300_000_000;
+
+    /// The calls both nodes answer alike.
+    fn common(method: &str) -> Option<Value> {
+        match method {
+            "getVersion" => Some(json!({ "result": { "solana-core": "1.18.26" } })),
+            "getSlot" => Some(json!({ "result": TIP })),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_find_what_each_node_serves() {
+        let full = node(|method, params| {
+            common(method).unwrap_or_else(|| match method {
+                // the oldest slot asked was skipped
+                "getBlock" if params[0] == TIP - 410_000 => {
+                    json!({ "error": { "code": -32007, "message": "Slot was skipped" } })
+                }
+                "getBlock" => json!({ "result": { "blockHeight": 1 } }),
+                "getSignaturesForAddress" | "getAsset" => json!({ "result": [] }),
+                "getIdentity" => json!({ "result": { "identity": "Full111" } }),
+                _ => json!({ "error": { "code": -32601, "message": "Method not found" } }),
+            })
+        })
+        .await;
+        let pruned = node(|method, _| {
+            common(method).unwrap_or_else(|| match method {
+                "getBlock" => json!({ "error": { "code": -32001, "message": "Block cleaned up" } }),
+                "getSignaturesForAddress" => json!({
+                    "error": { "code": -32011, "message": "Transaction history is not available" },
+                }),
+                _ => json!({ "error": { "code": -32601, "message": "Method not found" } }),
+            })
+        })
+        .await;
+        let config = crate::testing::config(&format!(
+            "[discovery]\ndas = true\n\n[[upstreams]]\nname = \"full\"\nurl = \"{}\"\n[[upstreams]]\nname = \"pruned\"\nurl = \"{}\"\narchive = true\ncapabilities = [\"das\"]\n",
+            full, pruned
+        ));
+        let settings = config.settings.discovery.clone().unwrap();
+        let (first_round, probed) = oneshot::channel();
+        tokio::spawn(follow(config.clone(), settings, first_round));
+        tokio::time::timeout(Duration::from_secs(10), probed)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let das = config.settings.capabilities.mask(&["das".to_string()]);
+        let (full, pruned) = (&config.servers[0], &config.servers[1]);
+        assert!(full.discovery.is_archive());
+        assert_eq!(full.discovery.capabilities(), das);
+        assert!(!full.unsupported.lacks(&["getTransaction".to_string()]));
+        let report = full.discovery.report();
+        assert_eq!(report["version"], "1.18.26");
+        assert_eq!(report["history"], true);
+        assert_eq!(report["transaction_history"], true);
+        assert_eq!(report["das"], true);
+
+        assert!(!pruned.discovery.is_archive());
+        assert_eq!(pruned.discovery.capabilities(), 0);
+        assert!(pruned.unsupported.lacks(&["getTransaction".to_string()]));
+        let events: Vec<String> = config.events.recent().into_iter().map(|e| e.1).collect();
+        assert_eq!(
+            events,
+            [
+                "pruned has no transaction history, is --enable-rpc-transaction-history set? Not sending it history calls",
+                "pruned is declared an archive but has no block older than the retention window",
+                "pruned is declared to have das but does not serve getAsset",
+            ]
+        );
+    }
+
+    #[test]
+    fn probe_answers_tell_a_missing_method_from_missing_data() {
+        let error = |code: i64| json!({ "error": { "code": code, "message": "no" } });
+        assert_eq!(answered(&json!({ "result": null })), Some(true));
+        // not found means the method is there
+        assert_eq!(answered(&error(-32004)), Some(true));
+        assert_eq!(answered(&error(-32601)), Some(false));
+        assert_eq!(answered(&json!({})), None);
+    }
+}
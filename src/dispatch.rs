@@ -0,0 +1,1045 @@
+//! The per-request view of which upstreams may answer, which starts from the
+//! global quarantine and can be adjusted by admin debugging headers.
+
+use crate::config::{AllQuarantined, RegionFanout};
+use crate::{failback, history, ranking, rpc, unsupported, ServerConfig};
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
+};
+use serde_json::json;
+use std::time::Duration;
+
+/// Names an upstream whose answer is accepted even while it is quarantined.
+pub const FORCE_INCLUDE_HEADER: &str = "x-quarantier-force-include";
+/// Tells whether the force-included upstream served the response.
+pub const FORCED_SERVED_HEADER: &str = "x-quarantier-forced-served";
+/// Names the only upstream a request is sent to.
+pub const TARGET_HEADER: &str = "x-quarantier-target";
+/// Asks for the upstream identification headers on the response.
+pub const DEBUG_HEADER: &str = "x-quarantier-debug";
+/// Names the upstream a response came from, or how the proxy answered by
+/// itself: `local`, `cache`, `coalesced` or `local-error`.
+pub const UPSTREAM_HEADER: &str = "x-quarantier-upstream";
+/// Time the serving upstream took to answer.
+pub const LATENCY_HEADER: &str = "x-quarantier-upstream-latency-ms";
+/// How the serving upstream was chosen.
+pub const STRATEGY_HEADER: &str = "x-quarantier-strategy";
+/// Tells that the answer was chosen at the soft deadline.
+pub const SOFT_DEADLINE_HEADER: &str = "x-quarantier-soft-deadline";
+/// Warns that the response ignored the quarantine, every upstream being in
+/// it, or came from a last-resort upstream.
+pub const WARNING_HEADER: &str = "x-quarantier-warning";
+/// Upstream header value of errors generated by the proxy.
+pub const LOCAL_ERROR: &str = "local-error";
+/// Serving upstream of answers the proxy gives from its own state.
+pub const LOCAL_ANSWER: &str = "local";
+/// Serving upstream of answers from the epoch cache.
+pub const CACHED_ANSWER: &str = "cache";
+/// Serving upstream of answers shared from an identical call in flight.
+pub const COALESCED_ANSWER: &str = "coalesced";
+
+/// Where a response came from, for the identification headers.
+pub enum Origin<'a> {
+    /// The name and latency of the upstream that answered.
+    Upstream(&'a str, Duration),
+    /// The proxy answered by itself: `LOCAL_ANSWER`, `CACHED_ANSWER`,
+    /// `COALESCED_ANSWER` or `LOCAL_ERROR`.
+    Proxy(&'static str),
+}
+
+impl<'a> Origin<'a> {
+    /// The upstream that answered, or a local error when none did.
+    pub fn served(served: Option<(&'a str, Duration)>) -> Self {
+        match served {
+            Some((name, latency)) => Origin::Upstream(name, latency),
+            None => Origin::Proxy(LOCAL_ERROR),
+        }
+    }
+}
+
+/// Target time of a slot, the latency a slot of lag is worth when ranking.
+const SLOT_TIME: Duration = Duration::from_millis(400);
+
+/// Response extension naming the upstream a request was forced to, for the
+/// access log.
+#[derive(Clone)]
+pub struct ForcedTarget(pub String);
+
+#[derive(Clone, Copy, Default)]
+pub struct Dispatch {
+    /// Upstream exempt from the quarantine for this request.
+    pub force_include: Option<usize>,
+    /// The only upstream asked, with no failover.
+    pub target: Option<usize>,
+    /// Whether the response identifies its upstream.
+    pub debug: bool,
+    /// Number of upstream answers merged into the response, for requests
+    /// routed with the merge strategy.
+    pub merged: Option<usize>,
+    /// Only archive upstreams are asked, for calls about old ledger data.
+    pub archive_only: bool,
+    /// Mask of the capabilities an upstream needs to be asked, for methods
+    /// standard nodes do not serve.
+    pub capabilities: u64,
+    /// Every upstream that could answer is quarantined, and the freshest
+    /// are asked regardless.
+    pub all_quarantined: bool,
+    /// No regular upstream could answer, and a last-resort one is asked.
+    pub last_resort: bool,
+    /// Upstreams are added to the race one by one, see `hedge`.
+    pub hedged: bool,
+}
+
+impl Dispatch {
+    /// Read the debugging headers, which only admin keys may use; anyone
+    /// else has them ignored. An unknown target is answered with a 400
+    /// listing the valid names.
+    pub fn from_headers(
+        config: &ServerConfig,
+        headers: &HeaderMap,
+        admin: bool,
+        request_id: &str,
+    ) -> Result<Self, Box<Response<Body>>> {
+        let requested = headers.get(DEBUG_HEADER).is_some_and(|value| value == "1");
+        let mut dispatch = Self {
+            debug: config.settings.debug_headers || (admin && requested),
+            ..Self::default()
+        };
+        let debugging = [FORCE_INCLUDE_HEADER, TARGET_HEADER, DEBUG_HEADER];
+        if !admin {
+            for header in debugging.iter().filter(|h| headers.contains_key(**h)) {
+                println!(
+                    "[{}] + Ignoring {} from a non-admin client",
+                    request_id, header
+                );
+            }
+            return Ok(dispatch);
+        }
+        let value = |header: &str| {
+            let value = headers.get(header)?;
+            Some(value.to_str().unwrap_or_default().trim().to_string())
+        };
+
+        if let Some(name) = value(TARGET_HEADER) {
+            dispatch.target = config.servers.iter().position(|u| u.name == name);
+            if dispatch.target.is_none() {
+                let names: Vec<&str> = config.servers.iter().map(|u| u.name.as_str()).collect();
+                return Err(Box::new(rpc::error_response(
+                    StatusCode::BAD_REQUEST,
+                    rpc::INVALID_REQUEST,
+                    &format!("no upstream named '{}'", name),
+                    Some(json!({ "upstreams": names })),
+                )));
+            }
+            println!("[{}] + Targeting {} only", request_id, name);
+        }
+
+        let Some(name) = value(FORCE_INCLUDE_HEADER) else {
+            return Ok(dispatch);
+        };
+        dispatch.force_include = config.servers.iter().position(|u| u.name == name);
+        match dispatch.force_include {
+            Some(_) => println!("[{}] + Force-including {}", request_id, name),
+            None => println!(
+                "[{}] + Ignoring {}: no upstream named '{}'",
+                request_id, FORCE_INCLUDE_HEADER, name
+            ),
+        }
+        Ok(dispatch)
+    }
+
+    /// The upstreams to send `body`, the call of `methods`, to: `targets`,
+    /// after old ledger data routes the request to archives, methods the
+    /// proxy learned an upstream lacks skip it, and no regular upstream
+    /// being left falls back on last-resort ones, or on the freshest
+    /// quarantined ones. A request no upstream can serve is answered with
+    /// an error instead.
+    pub async fn route(
+        &mut self,
+        config: &ServerConfig,
+        body: &[u8],
+        methods: &[String],
+        request_id: &str,
+    ) -> Result<Vec<usize>, Box<Response<Body>>> {
+        if self.target.is_none() && history::needs_archive(config, body) {
+            println!(
+                "[{}] + Historical data asked for, routing to archives",
+                request_id
+            );
+            self.archive_only = true;
+        }
+        if self.target.is_none() {
+            self.capabilities = config.settings.capabilities.required(methods);
+        }
+        let mut targets = self.targets(config, &config.quarantine.read().await);
+        if self.target.is_none() {
+            targets = unsupported::skip(config, targets, methods, request_id);
+        }
+        // a public endpoint beats failing, but only while it is the only option,
+        // and recovered regular upstreams get traffic back as `[failback]` says
+        let regular = !targets.is_empty();
+        let held = self.target.is_none() && failback::holds(config, regular);
+        let falling_back = (!regular || held)
+            && match self.last_resort(config) {
+                Ok(Some(index)) => {
+                    match held {
+                        true => println!(
+                            "[{}] + Failing back, sending to last-resort {} meanwhile",
+                            request_id, config.servers[index].name
+                        ),
+                        false => println!(
+                            "[{}] + WARNING: no regular upstream is available, sending to last-resort {}",
+                            request_id, config.servers[index].name
+                        ),
+                    }
+                    self.last_resort = true;
+                    targets.clear();
+                    targets.push(index);
+                    true
+                }
+                Ok(None) => false,
+                Err(limited) => {
+                    println!(
+                        "[{}] + Last-resort upstreams over their rate limit: {:?}",
+                        request_id, limited
+                    );
+                    true
+                }
+            };
+        if config
+            .stats
+            .record_last_resort(falling_back && !regular, self.last_resort)
+        {
+            let message = match falling_back && !regular {
+                true => "no regular upstream is available, falling back to last-resort upstreams"
+                    .to_string(),
+                false => failback::recovered(config),
+            };
+            println!("[{}] + WARNING: {}", request_id, message);
+            config.events.record(message);
+        }
+        // a quarantine that pulls every upstream means its thresholds are wrong
+        let freshest = match targets.is_empty() {
+            true => self.freshest(config),
+            false => Vec::new(),
+        };
+        let policy = config.settings.all_quarantined;
+        // answers from a last resort leave the quarantine state as it was
+        if !self.last_resort && config.stats.record_all_quarantined(!freshest.is_empty()) {
+            let message = match (freshest.is_empty(), policy) {
+                (true, _) => "upstreams are out of quarantine again".to_string(),
+                (false, AllQuarantined::ServeBest) => {
+                    "every upstream is quarantined, serving the freshest regardless".to_string()
+                }
+                (false, AllQuarantined::Fail) => {
+                    "every upstream is quarantined, failing requests".to_string()
+                }
+            };
+            println!("[{}] + WARNING: {}", request_id, message);
+            config.events.record(message);
+        }
+        if !freshest.is_empty() {
+            if policy == AllQuarantined::Fail {
+                println!(
+                    "[{}] + Every upstream is quarantined, rejecting",
+                    request_id
+                );
+                let response = rpc::error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    rpc::UPSTREAM_ERROR,
+                    "every upstream is quarantined",
+                    None,
+                );
+                let method = rpc::method_label(methods);
+                return Err(Box::new(self.local_error(response, method)));
+            }
+            let names: Vec<&str> = freshest
+                .iter()
+                .map(|&index| config.servers[index].name.as_str())
+                .collect();
+            println!(
+                "[{}] + Every upstream is quarantined, asking the freshest: {:?}",
+                request_id, names
+            );
+            self.all_quarantined = true;
+            targets = freshest;
+        }
+        if targets.is_empty() && self.capabilities != 0 {
+            let capabilities = &config.settings.capabilities;
+            let needed = capabilities.describe(self.capabilities);
+            let mut needing: Vec<&str> = methods
+                .iter()
+                .filter(|method| capabilities.required([*method]) != 0)
+                .map(String::as_str)
+                .collect();
+            needing.sort_unstable();
+            needing.dedup();
+            let message = format!(
+                "no healthy upstream has {}, needed by {}",
+                needed.join(", "),
+                needing.join(", ")
+            );
+            println!("[{}] + Rejected: {}", request_id, message);
+            let response = rpc::error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                rpc::UPSTREAM_ERROR,
+                &message,
+                Some(json!({ "capabilities": needed })),
+            );
+            let method = rpc::method_label(methods);
+            return Err(Box::new(self.local_error(response, method)));
+        }
+        Ok(targets)
+    }
+
+    /// The routed `targets` actually asked: those of the local region, the
+    /// best few of a `[routing]` fan-out, or the one best upstream with
+    /// room under its plan for a repeated submission, `forward_one`.
+    /// Merged answers need every upstream, and when every one is
+    /// quarantined the freshest are all there is.
+    pub fn narrow(
+        &self,
+        config: &ServerConfig,
+        mut targets: Vec<usize>,
+        methods: &[String],
+        merging: bool,
+        forward_one: bool,
+        request_id: &str,
+    ) -> Vec<usize> {
+        if !merging && !self.all_quarantined && !self.last_resort {
+            let (local, fallback) = self.localize(config, targets);
+            targets = local;
+            if config.stats.record_region_fallback(fallback) {
+                let region = &config.settings.region.as_ref().unwrap().local;
+                let message = match fallback {
+                    true => format!(
+                        "no healthy upstream in region {}, reaching across regions",
+                        region
+                    ),
+                    false => format!("upstreams in region {} are healthy again", region),
+                };
+                println!("[{}] + WARNING: {}", request_id, message);
+                config.events.record(message);
+            }
+        }
+        // a fan-out races the best few, the rest are not asked
+        if !merging && !forward_one && self.target.is_none() && !self.all_quarantined {
+            if let Some(k) = config.settings.routing.fanout(methods) {
+                targets = Self::best(config, targets, k);
+            }
+        }
+        if forward_one {
+            if ranking::routes(config) {
+                ranking::order(config, &mut targets);
+            }
+            // the one asked has to have room under its plan's rate
+            if let Some(position) = targets
+                .iter()
+                .position(|&index| config.servers[index].has_room())
+            {
+                targets[..=position].rotate_right(1);
+            }
+            targets.truncate(1);
+        }
+        targets
+    }
+
+    /// Indices of the upstreams the request is sent to, decided before any
+    /// request is built: the target alone, or every upstream whose answer
+    /// could be accepted. Quarantined upstreams are left to the slot poller
+    /// until they catch up, drained ones until an admin undrains them,
+    /// aliases of another upstream under the `alias` policy for good, and
+    /// upstreams low on their provider plan are only asked when no other
+    /// upstream can be. Archive-only requests go
+    /// to every upstream when no archive is available; requests needing
+    /// capabilities go to no upstream when none has them.
+    pub fn targets(&self, config: &ServerConfig, quarantine: &[usize]) -> Vec<usize> {
+        if let Some(index) = self.target {
+            return vec![index];
+        }
+        let mut accepted: Vec<usize> = (0..config.servers.len())
+            .filter(|&index| !config.servers[index].is_drained())
+            .filter(|&index| !crate::identity::routed_out(config, index))
+            .filter(|&index| self.accepts(config, index, quarantine) && self.capable(config, index))
+            .collect();
+        if self.archive_only
+            && accepted
+                .iter()
+                .any(|&index| config.servers[index].is_archive())
+        {
+            accepted.retain(|&index| config.servers[index].is_archive());
+        }
+        let preferred: Vec<usize> = accepted
+            .iter()
+            .copied()
+            .filter(|&index| {
+                self.force_include == Some(index) || !config.servers[index].limits.is_low()
+            })
+            .collect();
+        if preferred.is_empty() {
+            accepted
+        } else {
+            preferred
+        }
+    }
+
+    /// `targets` narrowed to the local region with `region_fanout =
+    /// "local"`: its upstreams, local ones first, and the remote one that
+    /// answered fastest last time, for slot comparison. While none of the
+    /// targets is local they are all kept; the second value says so.
+    pub fn localize(&self, config: &ServerConfig, targets: Vec<usize>) -> (Vec<usize>, bool) {
+        let Some(region) = &config.settings.region else {
+            return (targets, false);
+        };
+        if region.fanout != RegionFanout::Local || self.target.is_some() {
+            return (targets, false);
+        }
+        let (mut local, remote): (Vec<usize>, Vec<usize>) = targets
+            .into_iter()
+            .partition(|&index| config.servers[index].region.as_ref() == Some(&region.local));
+        if local.is_empty() {
+            let fallback = !remote.is_empty();
+            return (remote, fallback);
+        }
+        let fastest = remote.into_iter().min_by_key(|&index| {
+            config.servers[index]
+                .stats
+                .last_latency()
+                .unwrap_or(Duration::MAX)
+        });
+        local.extend(fastest);
+        (local, false)
+    }
+
+    /// The `k` best ranked of `targets`, for a race with a `[routing]`
+    /// fan-out: by average latency, each slot behind the highest fresh one
+    /// counting as a slot time more, and those not heard from yet last.
+    /// Upstreams without a fresh slot estimate are ranked by latency alone.
+    pub fn best(config: &ServerConfig, mut targets: Vec<usize>, k: usize) -> Vec<usize> {
+        if targets.len() <= k {
+            return targets;
+        }
+        let fresh = config.slots.fresh();
+        let tip = fresh.iter().flatten().map(|o| o.slot).max();
+        targets.sort_by_key(|&index| {
+            let latency = config.servers[index].stats.average_latency();
+            let behind = match (tip, fresh[index]) {
+                (Some(tip), Some(observation)) => tip.saturating_sub(observation.slot),
+                _ => 0,
+            };
+            let score = latency.map(|latency| latency + SLOT_TIME * behind as u32);
+            (score.is_none(), score)
+        });
+        targets.truncate(k);
+        targets
+    }
+
+    /// The last-resort upstream to ask when no regular one can answer: the
+    /// first with the capabilities needed and room in its rate limit.
+    /// `Err` names the ones over their limit, to fall back on the
+    /// all-quarantined policy.
+    pub fn last_resort<'a>(&self, config: &'a ServerConfig) -> Result<Option<usize>, Vec<&'a str>> {
+        let mut limited = Vec::new();
+        for upstream in &config.servers {
+            let Some(bucket) = &upstream.last_resort else {
+                continue;
+            };
+            if upstream.is_drained() || !self.capable(config, upstream.index) {
+                continue;
+            }
+            match bucket.try_acquire() {
+                Ok(()) => return Ok(Some(upstream.index)),
+                Err(_) => limited.push(upstream.name.as_str()),
+            }
+        }
+        match limited.is_empty() {
+            true => Ok(None),
+            false => Err(limited),
+        }
+    }
+
+    /// An upstream pulled for failures to send the request to as well, as
+    /// its circuit breaker's probe, with the probe's number. Its answer is
+    /// only used to decide whether it recovered.
+    pub fn probe(&self, config: &ServerConfig, targets: &[usize]) -> Option<(usize, u64)> {
+        if self.target.is_some() {
+            return None;
+        }
+        (0..config.servers.len())
+            .filter(|index| !targets.contains(index))
+            .filter(|&index| !config.servers[index].circuit.is_closed())
+            .filter(|&index| !config.servers[index].is_drained())
+            .filter(|&index| !crate::identity::routed_out(config, index))
+            .filter(|&index| !config.servers[index].cluster.is_wrong())
+            .filter(|&index| config.servers[index].has_room())
+            .filter(|&index| self.capable(config, index))
+            .filter(|&index| !self.archive_only || config.servers[index].is_archive())
+            .find_map(|index| Some((index, crate::quarantine::admit_probe(config, index)?)))
+    }
+
+    fn capable(&self, config: &ServerConfig, index: usize) -> bool {
+        config.servers[index].capability_mask() & self.capabilities == self.capabilities
+    }
+
+    /// The upstreams to ask when every one that could answer is
+    /// quarantined: those within the quarantine tolerance of the highest
+    /// slot among them, or all of them when none has a fresh estimate.
+    /// Empty when no upstream has the capabilities needed.
+    pub fn freshest(&self, config: &ServerConfig) -> Vec<usize> {
+        let fresh = config.slots.fresh();
+        let capable: Vec<usize> = (0..config.servers.len())
+            .filter(|&index| !config.servers[index].is_last_resort())
+            .filter(|&index| !config.servers[index].is_drained())
+            .filter(|&index| !crate::identity::routed_out(config, index))
+            .filter(|&index| !config.servers[index].cluster.is_wrong())
+            .filter(|&index| self.capable(config, index))
+            .collect();
+        let Some(top) = capable
+            .iter()
+            .filter_map(|&i| fresh[i])
+            .map(|o| o.slot)
+            .max()
+        else {
+            return capable;
+        };
+        capable
+            .into_iter()
+            .filter(|&index| fresh[index].is_some_and(|o| o.slot + config.tolerance.slots() >= top))
+            .collect()
+    }
+
+    /// Name of the routing strategy, for the debugging headers.
+    pub fn strategy(&self) -> &'static str {
+        if self.target.is_some() {
+            "target"
+        } else if self.last_resort {
+            "last-resort"
+        } else if self.all_quarantined {
+            "all-quarantined"
+        } else if self.merged.is_some() {
+            "merge"
+        } else if self.hedged {
+            "hedge"
+        } else if self.capabilities != 0 {
+            "race+capability"
+        } else if self.archive_only {
+            "race+archive"
+        } else if self.force_include.is_some() {
+            "race+force-include"
+        } else {
+            "race"
+        }
+    }
+
+    /// Add the identification headers to `response`, which came from
+    /// `origin`. Targeted requests always name their upstream.
+    pub fn annotate(&self, response: &mut Response<Body>, origin: Origin<'_>) {
+        let headers = response.headers_mut();
+        if self.all_quarantined {
+            headers.insert(
+                WARNING_HEADER,
+                HeaderValue::from_static("every upstream is quarantined"),
+            );
+        }
+        if self.last_resort {
+            headers.insert(
+                WARNING_HEADER,
+                HeaderValue::from_static("served by a last-resort upstream"),
+            );
+        }
+        let upstream = match origin {
+            Origin::Upstream(name, _) => name,
+            Origin::Proxy(origin) => origin,
+        };
+        if self.debug || self.target.is_some() {
+            if let Ok(value) = HeaderValue::from_str(upstream) {
+                headers.insert(UPSTREAM_HEADER, value);
+            }
+        }
+        if !self.debug {
+            return;
+        }
+        if let Origin::Upstream(_, latency) = origin {
+            let millis = latency.as_millis().to_string();
+            headers.insert(LATENCY_HEADER, HeaderValue::from_str(&millis).unwrap());
+        }
+        headers.insert(STRATEGY_HEADER, HeaderValue::from_static(self.strategy()));
+        if let Some(merged) = self.merged {
+            headers.insert(crate::merge::MERGED_HEADER, HeaderValue::from(merged));
+        }
+    }
+
+    /// A response to `method` the proxy gives from `origin` without asking
+    /// any upstream, see `Origin::Proxy`.
+    pub fn local_answer(
+        &self,
+        method: String,
+        origin: &'static str,
+        body: impl Into<Body>,
+    ) -> Response<Body> {
+        let mut response = Response::builder()
+            .header("content-type", "application/json")
+            .extension(rpc::RequestMethod(method))
+            .extension(rpc::ServedBy(origin.to_string()))
+            .body(body.into())
+            .unwrap();
+        self.annotate(&mut response, Origin::Proxy(origin));
+        response
+    }
+
+    /// `response`, an error of the proxy's own to `method`, annotated as one.
+    pub fn local_error(&self, mut response: Response<Body>, method: String) -> Response<Body> {
+        response.extensions_mut().insert(rpc::RequestMethod(method));
+        self.annotate(&mut response, Origin::Proxy(LOCAL_ERROR));
+        response
+    }
+
+    /// Whether the answer of upstream `index` may be returned. Last-resort
+    /// upstreams only answer requests sent to them as such, or targeted.
+    pub fn accepts(&self, config: &ServerConfig, index: usize, quarantine: &[usize]) -> bool {
+        // no fallback makes another cluster's answers right
+        if config.servers[index].cluster.is_wrong() {
+            return self.target == Some(index);
+        }
+        if config.servers[index].is_last_resort() {
+            return self.target == Some(index) || self.last_resort;
+        }
+        self.target == Some(index)
+            || self.force_include == Some(index)
+            || self.all_quarantined
+            || !config.servers[index].is_quarantined(quarantine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{proxy, upstream};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    const KEYS: &str = "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n";
+
+    /// A proxy over a fast upstream a, quarantined for lagging, and a slower
+    /// upstream b.
+    async fn lagging_a(settings: &str) -> (Arc<ServerConfig>, String) {
+        let a = upstream(Behavior {
+            slot_lag: 1000,
+            latency: Duration::ZERO,
+            ..Behavior::default()
+        })
+        .await;
+        let b = upstream(Behavior {
+            latency: Duration::from_millis(100),
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "{}{}[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n[[upstreams]]\nname = \"b\"\nurl = \"{}\"\n",
+            settings, KEYS, a, b
+        ))
+        .await;
+        crate::slots::repoll(&config, 0).await;
+        crate::slots::repoll(&config, 1).await;
+        assert!(config.servers[0].is_quarantined(&config.quarantine.read().await));
+        (config, url)
+    }
+
+    async fn send(url: &str, key: &str, headers: &[(&str, &str)]) -> reqwest::Response {
+        let mut request = reqwest::Client::new()
+            .post(url)
+            .header("x-api-key", key)
+            .json(&json!({
+                "jsonrpc": "2.0", "id": 1, "method": "getBalance",
+                "params": ["11111111111111111111111111111111"],
+            }));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.send().await.unwrap()
+    }
+
+    fn header<'a>(response: &'a reqwest::Response, name: &str) -> Option<&'a str> {
+        response.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn admins_can_force_a_quarantined_upstream_in() {
+        let (_, url) = lagging_a("").await;
+        let forced = send(&url, "secret-ops", &[(FORCE_INCLUDE_HEADER, "a")]).await;
+        assert_eq!(forced.status(), 200);
+        assert_eq!(header(&forced, FORCED_SERVED_HEADER), Some("true"));
+        // anyone else has the header ignored
+        let ignored = send(&url, "secret-app", &[(FORCE_INCLUDE_HEADER, "a")]).await;
+        assert_eq!(ignored.status(), 200);
+        assert_eq!(header(&ignored, FORCED_SERVED_HEADER), None);
+    }
+
+    #[tokio::test]
+    async fn targeted_requests_ask_that_upstream_alone() {
+        let (config, url) = lagging_a("").await;
+        let before = config.servers[1].stats.successes();
+        let targeted = send(&url, "secret-ops", &[(TARGET_HEADER, "a")]).await;
+        assert_eq!(targeted.status(), 200);
+        assert_eq!(header(&targeted, UPSTREAM_HEADER), Some("a"));
+        assert_eq!(config.servers[1].stats.successes(), before);
+        let unknown = send(&url, "secret-ops", &[(TARGET_HEADER, "z")]).await;
+        assert_eq!(unknown.status(), 400);
+        let body: serde_json::Value = unknown.json().await.unwrap();
+        assert_eq!(body["error"]["data"]["upstreams"], json!(["a", "b"]));
+        let ignored = send(&url, "secret-app", &[(TARGET_HEADER, "z")]).await;
+        assert_eq!(ignored.status(), 200);
+        assert_eq!(header(&ignored, UPSTREAM_HEADER), None);
+    }
+
+    #[tokio::test]
+    async fn the_serving_upstream_is_named_when_asked() {
+        let (_, url) = lagging_a("").await;
+        let debugged = send(&url, "secret-ops", &[(DEBUG_HEADER, "1")]).await;
+        assert_eq!(header(&debugged, UPSTREAM_HEADER), Some("b"));
+        assert_eq!(header(&debugged, STRATEGY_HEADER), Some("race"));
+        assert!(header(&debugged, LATENCY_HEADER).is_some());
+        let plain = send(&url, "secret-ops", &[]).await;
+        assert_eq!(header(&plain, UPSTREAM_HEADER), None);
+        let ignored = send(&url, "secret-app", &[(DEBUG_HEADER, "1")]).await;
+        assert_eq!(header(&ignored, UPSTREAM_HEADER), None);
+        // or always, for everyone
+        let (_, url) = lagging_a("debug_headers = true\n").await;
+        let everyone = send(&url, "secret-app", &[]).await;
+        assert_eq!(header(&everyone, UPSTREAM_HEADER), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn excluded_upstreams_are_sent_nothing() {
+        let (config, url) = lagging_a("").await;
+        let (a, b) = (&config.servers[0].stats, &config.servers[1].stats);
+        let (polled_a, polled_b) = (a.successes(), b.successes());
+        assert_eq!(send(&url, "secret-app", &[]).await.status(), 200);
+        assert_eq!(b.successes(), polled_b + 1);
+        assert_eq!(a.successes(), polled_a);
+        let quarantine = config.quarantine.read().await;
+        assert_eq!(Dispatch::default().targets(&config, &quarantine), [1]);
+    }
+
+    #[tokio::test]
+    async fn with_every_upstream_quarantined_the_policy_decides() {
+        let (config, url) = lagging_a("").await;
+        config.quarantine.write().await.push(1);
+        let served = send(&url, "secret-ops", &[(DEBUG_HEADER, "1")]).await;
+        assert_eq!(served.status(), 200);
+        // only b is within the slot tolerance of the freshest
+        assert_eq!(header(&served, UPSTREAM_HEADER), Some("b"));
+        assert_eq!(header(&served, STRATEGY_HEADER), Some("all-quarantined"));
+        assert_eq!(
+            header(&served, WARNING_HEADER),
+            Some("every upstream is quarantined")
+        );
+        assert_eq!(config.stats.all_quarantined.load(Ordering::Relaxed), 1);
+        config.quarantine.write().await.retain(|&index| index != 1);
+        assert_eq!(send(&url, "secret-app", &[]).await.status(), 200);
+        assert_eq!(config.stats.all_quarantined.load(Ordering::Relaxed), 1);
+        let events: Vec<String> = config.events.recent().into_iter().map(|e| e.1).collect();
+        let announced = |message: &str| events.iter().filter(|e| *e == message).count();
+        assert_eq!(
+            announced("every upstream is quarantined, serving the freshest regardless"),
+            1
+        );
+        assert_eq!(announced("upstreams are out of quarantine again"), 1);
+
+        let (config, url) = lagging_a("all_quarantined = \"fail\"\n").await;
+        config.quarantine.write().await.push(1);
+        let before = config.servers[1].stats.successes();
+        let failed = send(&url, "secret-app", &[]).await;
+        assert_eq!(failed.status(), 503);
+        let body: serde_json::Value = failed.json().await.unwrap();
+        assert_eq!(body["error"]["code"], rpc::UPSTREAM_ERROR);
+        assert_eq!(body["error"]["message"], "every upstream is quarantined");
+        assert_eq!(config.servers[1].stats.successes(), before);
+        assert_eq!(config.stats.all_quarantined.load(Ordering::Relaxed), 1);
+    }
+
+    /// An upstream answering `getBalance` after `latency`, and the number
+    /// of requests it was sent.
+    async fn counted(latency: u64) -> (String, Arc<AtomicU64>) {
+        let hits = Arc::new(AtomicU64::new(0));
+        let url = crate::testing::serve(axum::Router::new().route(
+            "/",
+            axum::routing::post({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_millis(latency)).await;
+                    r#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":1},"value":1}}"#
+                }
+            }),
+        ))
+        .await;
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn races_stay_in_the_local_region_but_for_one_remote() {
+        let (near, near_hits) = counted(10).await;
+        let (fast, fast_hits) = counted(0).await;
+        let (slow, slow_hits) = counted(30).await;
+        let (config, url) = proxy(&format!(
+            "local_region = \"eu\"\n\n[[upstreams]]\nname = \"near\"\nurl = \"{}\"\nregion = \"eu\"\n[[upstreams]]\nname = \"fast\"\nurl = \"{}\"\nregion = \"us\"\n[[upstreams]]\nname = \"slow\"\nurl = \"{}\"\nregion = \"us\"\n",
+            near, fast, slow
+        ))
+        .await;
+        config.servers[1]
+            .stats
+            .record_latency(Duration::from_millis(5));
+        config.servers[2]
+            .stats
+            .record_latency(Duration::from_millis(50));
+        let hits = || [&near_hits, &fast_hits, &slow_hits].map(|hits| hits.load(Ordering::Relaxed));
+        let status = || async {
+            let status: serde_json::Value = reqwest::get(format!("{}/status", url))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            status["region"].clone()
+        };
+        assert_eq!(send(&url, "", &[]).await.status(), 200);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the fastest remote is raced, for slot comparison
+        assert_eq!(hits(), [1, 1, 0]);
+        assert_eq!(
+            status().await,
+            json!({ "local": "eu", "fanout": "local", "falling_back": false })
+        );
+
+        config.quarantine.write().await.push(0);
+        assert_eq!(send(&url, "", &[]).await.status(), 200);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hits(), [1, 2, 1]);
+        assert_eq!(config.stats.region_fallbacks.load(Ordering::Relaxed), 1);
+        assert_eq!(status().await["falling_back"], true);
+        config.quarantine.write().await.clear();
+        assert_eq!(send(&url, "", &[]).await.status(), 200);
+        assert_eq!(config.stats.region_fallbacks.load(Ordering::Relaxed), 1);
+        assert_eq!(status().await["falling_back"], false);
+        let events: Vec<String> = config
+            .events
+            .recent()
+            .into_iter()
+            .map(|e| e.1)
+            .filter(|e| e.contains("region"))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "no healthy upstream in region eu, reaching across regions",
+                "upstreams in region eu are healthy again",
+            ]
+        );
+
+        // or race everyone
+        let config = crate::testing::config(&format!(
+            "local_region = \"eu\"\nregion_fanout = \"all\"\n\n[[upstreams]]\nurl = \"{}\"\nregion = \"eu\"\n[[upstreams]]\nurl = \"{}\"\nregion = \"us\"\n",
+            near, fast
+        ));
+        assert_eq!(
+            Dispatch::default().localize(&config, vec![0, 1]),
+            (vec![0, 1], false)
+        );
+    }
+
+    #[tokio::test]
+    async fn last_resorts_only_answer_while_no_regular_upstream_can() {
+        let (regular, regular_hits) = counted(0).await;
+        let (public, public_hits) = counted(0).await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nname = \"regular\"\nurl = \"{}\"\n[[upstreams]]\nname = \"public\"\nurl = \"{}\"\nrole = \"last_resort\"\n[upstreams.last_resort]\nrps = 0.01\nburst = 2\n",
+            regular, public
+        ))
+        .await;
+        let hits = || [&regular_hits, &public_hits].map(|hits| hits.load(Ordering::Relaxed));
+        let sent = || config.stats.last_resort.load(Ordering::Relaxed);
+        assert_eq!(send(&url, "", &[]).await.status(), 200);
+        assert_eq!(hits(), [1, 0]);
+
+        // every request re-evaluates the quarantine
+        for expected in [[1, 1], [1, 2]] {
+            config.quarantine.write().await.push(0);
+            let response = send(&url, "", &[]).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                header(&response, WARNING_HEADER),
+                Some("served by a last-resort upstream")
+            );
+            assert_eq!(hits(), expected);
+        }
+        assert_eq!(sent(), 2);
+        // past its rate the public endpoint is spared, the quarantine ignored
+        config.quarantine.write().await.push(0);
+        let response = send(&url, "", &[]).await;
+        assert_eq!(
+            header(&response, WARNING_HEADER),
+            Some("every upstream is quarantined")
+        );
+        assert_eq!(hits(), [2, 2]);
+        assert_eq!(sent(), 2);
+
+        config.quarantine.write().await.clear();
+        let response = send(&url, "", &[]).await;
+        assert_eq!(header(&response, WARNING_HEADER), None);
+        assert_eq!(hits(), [3, 2]);
+        let events: Vec<String> = config
+            .events
+            .recent()
+            .into_iter()
+            .map(|e| e.1)
+            .filter(|e| e.contains("last-resort"))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "no regular upstream is available, falling back to last-resort upstreams",
+                "regular upstreams are available again, last-resort upstreams unused",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn capability_methods_only_go_to_capable_upstreams() {
+        let plain = upstream(Behavior::default()).await;
+        let das = upstream(Behavior {
+            latency: Duration::from_millis(100),
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "debug_headers = true\n\n[capabilities]\nenhanced = [\"getPriorityFeeEstimate\"]\n\n[[upstreams]]\nname = \"plain\"\nurl = \"{}\"\n\n[[upstreams]]\nname = \"das\"\nurl = \"{}\"\ncapabilities = [\"das\"]\n",
+            plain, das
+        ))
+        .await;
+        let call = |method: &str| {
+            crate::testing::call(
+                &url,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": ["x"] }),
+            )
+        };
+        let asset = call("getAsset").await;
+        assert_eq!(header(&asset, UPSTREAM_HEADER), Some("das"));
+        let (plain, das) = (&config.servers[0].stats, &config.servers[1].stats);
+        assert!(plain.last_latency().is_none());
+        // still measured like any other answer
+        assert!(das.last_latency().is_some());
+        // other methods are raced as usual, the fast one winning
+        let slot = call("getSlot").await;
+        assert_eq!(header(&slot, UPSTREAM_HEADER), Some("plain"));
+
+        let missing = call("getPriorityFeeEstimate").await;
+        assert_eq!(missing.status(), 503);
+        let body: serde_json::Value = missing.json().await.unwrap();
+        assert_eq!(body["error"]["code"], rpc::UPSTREAM_ERROR);
+        assert_eq!(body["error"]["data"]["capabilities"], json!(["enhanced"]));
+        assert_eq!(
+            body["error"]["message"],
+            "no healthy upstream has enhanced, needed by getPriorityFeeEstimate"
+        );
+    }
+
+    #[test]
+    fn the_best_are_the_fastest_counting_their_lag() {
+        let mut text = String::from(
+            "[routing]\nfanout = 2\ngetSlot = { fanout = 0 }\ngetBalance = { fanout = 3 }\n",
+        );
+        for port in 1..=4 {
+            text += &format!("[[upstreams]]\nurl = \"http://127.0.0.1:{}\"\n", port);
+        }
+        let config = crate::testing::config(&text);
+        for (index, ms) in [(0, 50), (1, 10), (2, 20)] {
+            config.servers[index]
+                .stats
+                .record_latency(Duration::from_millis(ms));
+        }
+        for (index, slot) in [(0, 1000), (1, 990), (2, 1000)] {
+            config
+                .slots
+                .observe(index, slot, crate::slots::SlotSource::Poll);
+        }
+        // 10 slots behind costs the fastest 4s, and unheard of comes last
+        assert_eq!(Dispatch::best(&config, vec![0, 1, 2, 3], 2), [2, 0]);
+        assert_eq!(Dispatch::best(&config, vec![0, 1, 2, 3], 3), [2, 0, 1]);
+        assert_eq!(Dispatch::best(&config, vec![3, 1], 2), [3, 1]);
+        let routing = &config.settings.routing;
+        let methods = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(routing.fanout(&methods(&["getAccountInfo"])), Some(2));
+        assert_eq!(routing.fanout(&methods(&["getSlot"])), None);
+        // a batch as wide as its widest call
+        assert_eq!(
+            routing.fanout(&methods(&["getAccountInfo", "getBalance"])),
+            Some(3)
+        );
+        assert_eq!(routing.fanout(&methods(&["getBalance", "getSlot"])), None);
+    }
+
+    #[tokio::test]
+    async fn a_fanout_races_the_best_few_and_counts_its_size() {
+        let mut text = String::from("[routing]\nfanout = 2\ngetBalance = { fanout = 1 }\n");
+        let mut counts = Vec::new();
+        for latency in [0, 10, 30, 60] {
+            let (url, hits) = counted(latency).await;
+            text += &format!("[[upstreams]]\nurl = \"{}\"\n", url);
+            counts.push(hits);
+        }
+        let (config, url) = proxy(&text).await;
+        for (index, ms) in [(0, 5), (1, 10), (2, 30), (3, 60)] {
+            config.servers[index]
+                .stats
+                .record_latency(Duration::from_millis(ms));
+        }
+        let hits = || {
+            counts
+                .iter()
+                .map(|hits| hits.load(Ordering::Relaxed))
+                .collect::<Vec<_>>()
+        };
+        let call = |method: &str| {
+            crate::testing::call(
+                &url,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": ["x"] }),
+            )
+        };
+        assert_eq!(call("getBalance").await.status(), 200);
+        assert_eq!(call("getAccountInfo").await.status(), 200);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hits(), [2, 1, 0, 0]);
+        // with the best quarantined the next best are raced
+        config.quarantine.write().await.push(0);
+        assert_eq!(call("getAccountInfo").await.status(), 200);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hits(), [2, 2, 1, 0]);
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("quarantier_fanout_size_total{size=\"1\"} 1\n"));
+        assert!(metrics.contains("quarantier_fanout_size_total{size=\"2\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn a_fanout_over_the_healthy_count_races_them_all() {
+        let mut text = String::from("[routing]\nfanout = 5\n");
+        let mut counts = Vec::new();
+        for latency in [0, 10, 30] {
+            let (url, hits) = counted(latency).await;
+            text += &format!("[[upstreams]]\nurl = \"{}\"\n", url);
+            counts.push(hits);
+        }
+        let (config, url) = proxy(&text).await;
+        assert_eq!(send(&url, "", &[]).await.status(), 200);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(counts.iter().all(|hits| hits.load(Ordering::Relaxed) == 1));
+        assert_eq!(config.stats.fanout_sizes.lock().unwrap()[&3], 1);
+    }
+}
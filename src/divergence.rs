@@ -0,0 +1,351 @@
+//! Passive comparison of the answers different upstreams give to the same
+//! request. Results are compared by hash; only retained examples are diffed.
+
+use crate::usage::OTHER;
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+const MAX_METHODS: usize = 256;
+const MAX_EXAMPLES: usize = 20;
+/// Characters kept of each side of an example.
+const EXAMPLE_LEN: usize = 1024;
+const MAX_DIFF_PATHS: usize = 20;
+
+/// Methods whose answer moves with every slot, so disagreement is expected.
+const VOLATILE_METHODS: &[&str] = &[
+    "getSlot",
+    "getBlockHeight",
+    "getEpochInfo",
+    "getLatestBlockhash",
+    "getRecentBlockhash",
+    "getRecentPerformanceSamples",
+    "getRecentPrioritizationFees",
+    "getMaxRetransmitSlot",
+    "getMaxShredInsertSlot",
+    "getTransactionCount",
+];
+
+/// The part of a response that should agree across upstreams: the result
+/// without its `context`, or the error. Batches normalize element-wise.
+pub fn normalize(response: &Value) -> Value {
+    match response {
+        Value::Array(items) => Value::Array(items.iter().map(normalize).collect()),
+        Value::Object(map) => match map.get("result") {
+            Some(Value::Object(result)) if result.contains_key("context") => {
+                result.get("value").cloned().unwrap_or(Value::Null)
+            }
+            Some(result) => result.clone(),
+            None => map.get("error").cloned().unwrap_or(Value::Null),
+        },
+        other => other.clone(),
+    }
+}
+
+/// serde_json keeps object keys sorted, so serialization is canonical.
+pub fn fingerprint(normalized: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    normalized.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Responses to a single request, folded as they arrive.
+#[derive(Default)]
+pub struct Comparison {
+    baseline: Option<(String, u64, Value)>,
+    mismatch: Option<(String, Value)>,
+    responses: usize,
+}
+
+impl Comparison {
+    pub fn add(&mut self, host: &str, response: &Value) {
+        let normalized = normalize(response);
+        let hash = fingerprint(&normalized);
+        self.responses += 1;
+        match &self.baseline {
+            None => self.baseline = Some((host.to_string(), hash, normalized)),
+            Some((_, baseline, _)) => {
+                if *baseline != hash && self.mismatch.is_none() {
+                    self.mismatch = Some((host.to_string(), normalized));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct MethodStats {
+    compared: u64,
+    mismatched: u64,
+}
+
+struct Example {
+    timestamp: u64,
+    method: String,
+    request_id: String,
+    hosts: [String; 2],
+    results: [String; 2],
+    diff: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct DivergenceTracker {
+    methods: Mutex<HashMap<String, MethodStats>>,
+    examples: Mutex<VecDeque<Example>>,
+}
+
+impl DivergenceTracker {
+    /// Record a finished comparison. Returns whether the upstreams disagreed.
+    pub fn record(&self, method: &str, request_id: &str, comparison: Comparison) -> bool {
+        if comparison.responses < 2 || VOLATILE_METHODS.contains(&method) {
+            return false;
+        }
+        let mismatched = comparison.mismatch.is_some();
+        {
+            let mut methods = self.methods.lock().unwrap();
+            let method = if methods.contains_key(method) || methods.len() < MAX_METHODS {
+                method
+            } else {
+                OTHER
+            };
+            let stats = methods.entry(method.to_string()).or_default();
+            stats.compared += 1;
+            stats.mismatched += mismatched as u64;
+        }
+
+        if let (Some((first_host, _, first)), Some((second_host, second))) =
+            (comparison.baseline, comparison.mismatch)
+        {
+            let mut diff = Vec::new();
+            diff_paths(&first, &second, "$", &mut diff);
+            let example = Example {
+                timestamp: crate::clock::unix_secs(),
+                method: method.to_string(),
+                request_id: request_id.to_string(),
+                hosts: [first_host, second_host],
+                results: [truncate(&first), truncate(&second)],
+                diff,
+            };
+            let mut examples = self.examples.lock().unwrap();
+            if examples.len() == MAX_EXAMPLES {
+                examples.pop_front();
+            }
+            examples.push_back(example);
+        }
+        mismatched
+    }
+
+    pub fn report(&self) -> Value {
+        let mut methods = Map::new();
+        for (method, stats) in self.methods.lock().unwrap().iter() {
+            methods.insert(
+                method.clone(),
+                json!({
+                    "compared": stats.compared,
+                    "mismatched": stats.mismatched,
+                    "mismatch_rate": stats.mismatched as f64 / stats.compared as f64,
+                }),
+            );
+        }
+        let examples: Vec<Value> = self
+            .examples
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|example| {
+                json!({
+                    "timestamp": example.timestamp,
+                    "method": example.method,
+                    "request_id": example.request_id,
+                    "hosts": example.hosts,
+                    "results": example.results,
+                    "diff": example.diff,
+                })
+            })
+            .collect();
+        json!({ "methods": methods, "examples": examples })
+    }
+}
+
+fn truncate(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(EXAMPLE_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// JSON paths at which two values differ, capped at `MAX_DIFF_PATHS`.
+fn diff_paths(a: &Value, b: &Value, path: &str, out: &mut Vec<String>) {
+    if out.len() >= MAX_DIFF_PATHS || a == b {
+        return;
+    }
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let path = format!("{}.{}", path, key);
+                match b.get(key) {
+                    Some(other) => diff_paths(value, other, &path, out),
+                    None => out.push(path),
+                }
+            }
+            for key in b.keys().filter(|key| !a.contains_key(*key)) {
+                out.push(format!("{}.{}", path, key));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                diff_paths(a, b, &format!("{}[{}]", path, i), out);
+            }
+        }
+        _ => out.push(path.to_string()),
+    }
+    out.truncate(MAX_DIFF_PATHS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, serve};
+    use axum::{http::StatusCode, routing::post, Json, Router};
+
+    fn answer(result: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "result": result })
+    }
+
+    fn compare(answers: &[(&str, Value)]) -> Comparison {
+        let mut comparison = Comparison::default();
+        for (host, answer) in answers {
+            comparison.add(host, answer);
+        }
+        comparison
+    }
+
+    #[test]
+    fn the_context_is_left_out_of_the_comparison() {
+        let at = |slot: u64| answer(json!({ "context": { "slot": slot }, "value": 5 }));
+        assert_eq!(normalize(&at(100)), json!(5));
+        assert_eq!(
+            fingerprint(&normalize(&at(100))),
+            fingerprint(&normalize(&at(101)))
+        );
+        let error = json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32602 } });
+        assert_eq!(normalize(&error), json!({ "code": -32602 }));
+        assert_eq!(
+            normalize(&json!([at(1), error])),
+            json!([5, { "code": -32602 }])
+        );
+    }
+
+    #[test]
+    fn disagreement_is_counted_per_method() {
+        let tracker = DivergenceTracker::default();
+        let same = compare(&[("a", answer(json!(1))), ("b", answer(json!(1)))]);
+        assert!(!tracker.record("getBalance", "r1", same));
+        let differ = compare(&[("a", answer(json!(1))), ("b", answer(json!(2)))]);
+        assert!(tracker.record("getBalance", "r2", differ));
+        let report = tracker.report();
+        let stats = &report["methods"]["getBalance"];
+        assert_eq!(stats["compared"], 2);
+        assert_eq!(stats["mismatched"], 1);
+        assert_eq!(stats["mismatch_rate"], 0.5);
+        let example = &report["examples"][0];
+        assert_eq!(example["request_id"], "r2");
+        assert_eq!(example["hosts"], json!(["a", "b"]));
+        assert_eq!(example["results"], json!(["1", "2"]));
+        assert_eq!(example["diff"], json!(["$"]));
+    }
+
+    #[test]
+    fn lone_answers_and_volatile_methods_are_not_compared() {
+        let tracker = DivergenceTracker::default();
+        let lone = compare(&[("a", answer(json!(1)))]);
+        assert!(!tracker.record("getBalance", "r1", lone));
+        let slots = compare(&[("a", answer(json!(100))), ("b", answer(json!(101)))]);
+        assert!(!tracker.record("getSlot", "r2", slots));
+        assert_eq!(tracker.report(), json!({ "methods": {}, "examples": [] }));
+    }
+
+    #[test]
+    fn examples_are_bounded_and_cut() {
+        let tracker = DivergenceTracker::default();
+        let long = "x".repeat(EXAMPLE_LEN * 2);
+        for n in 0..MAX_EXAMPLES + 5 {
+            let differ = compare(&[("a", answer(json!(long))), ("b", answer(json!(n)))]);
+            tracker.record("getAccountInfo", &n.to_string(), differ);
+        }
+        let report = tracker.report();
+        let examples = report["examples"].as_array().unwrap();
+        assert_eq!(examples.len(), MAX_EXAMPLES);
+        // newest first
+        assert_eq!(examples[0]["request_id"], (MAX_EXAMPLES + 4).to_string());
+        let kept = examples[0]["results"][0].as_str().unwrap();
+        assert_eq!(kept.len(), EXAMPLE_LEN + 3);
+        assert!(kept.ends_with("..."));
+    }
+
+    #[test]
+    fn diffs_name_the_paths_that_differ() {
+        let mut diff = Vec::new();
+        let a = json!({ "owner": "x", "lamports": 1, "data": [1, 2], "gone": true });
+        let b = json!({ "owner": "x", "lamports": 2, "data": [1, 3], "new": true });
+        diff_paths(&a, &b, "$", &mut diff);
+        assert_eq!(diff, ["$.data[1]", "$.gone", "$.lamports", "$.new"]);
+    }
+
+    /// An upstream answering every call with `balance`, or with a 500.
+    async fn answering(balance: Option<u64>) -> String {
+        serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                match balance {
+                    Some(balance) => {
+                        let result = json!({ "context": { "slot": 1 }, "value": balance });
+                        let body =
+                            json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+                        (StatusCode::OK, Json(body))
+                    }
+                    None => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({}))),
+                }
+            }),
+        ))
+        .await
+    }
+
+    async fn divergence(upstreams: [Option<u64>; 2]) -> Value {
+        let mut text = String::new();
+        for (name, balance) in ["a", "b"].into_iter().zip(upstreams) {
+            let url = answering(balance).await;
+            text += &format!("[[upstreams]]\nname = \"{}\"\nurl = \"{}\"\n", name, url);
+        }
+        let (config, url) = proxy(&text).await;
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        assert_eq!(call(&url, body).await.status(), 200);
+        // the slower upstream is compared after the client is answered
+        for _ in 0..100 {
+            if !config.divergence.methods.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let report = reqwest::get(format!("{}/admin/divergence", url)).await;
+        report.unwrap().json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn upstreams_serving_different_results_are_reported() {
+        let report = divergence([Some(1), Some(2)]).await;
+        assert_eq!(report["methods"]["getBalance"]["mismatched"], 1);
+        let hosts = report["examples"][0]["hosts"].as_array().unwrap();
+        assert!(hosts.contains(&json!("a")) && hosts.contains(&json!("b")));
+    }
+
+    #[tokio::test]
+    async fn a_failed_upstream_is_no_divergence() {
+        let report = divergence([Some(1), None]).await;
+        assert_eq!(report, json!({ "methods": {}, "examples": [] }));
+    }
+}
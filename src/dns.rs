@@ -0,0 +1,175 @@
+//! Periodic re-resolution of upstream hostnames. Pooled connections stay
+//! with the addresses the host had when they were opened, so a provider
+//! moving its endpoint would keep being reached at the old one until the
+//! connections idle out. When the set of addresses changes, the upstream
+//! gets a fresh pool; requests in flight finish on the old one, which is
+//! dropped with its last clone.
+
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use reqwest::Url;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The addresses `upstream`'s host resolves to, sorted, or `None` for an
+/// IP literal, which has nothing to re-resolve.
+async fn resolve(upstream: &Upstream) -> Option<io::Result<Vec<IpAddr>>> {
+    let url = Url::parse(&upstream.url).ok()?;
+    let host = url.domain()?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let resolved = tokio::net::lookup_host((host, port)).await;
+    Some(resolved.map(|addresses| {
+        let mut addresses: Vec<IpAddr> = addresses.map(|address| address.ip()).collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }))
+}
+
+/// Resolve every upstream's host again, replacing the pool of those whose
+/// addresses changed. A failed resolution keeps the pool as it is.
+async fn refresh_all(config: &ServerConfig) {
+    let resolved = futures::future::join_all(config.servers.iter().map(resolve)).await;
+    for (upstream, resolved) in config.servers.iter().zip(resolved) {
+        let addresses = match resolved {
+            None => continue,
+            Some(Ok(addresses)) if !addresses.is_empty() => addresses,
+            Some(Ok(_)) => {
+                unresolved(upstream, "no addresses");
+                continue;
+            }
+            Some(Err(err)) => {
+                unresolved(upstream, &err.to_string());
+                continue;
+            }
+        };
+        let previous =
+            std::mem::replace(&mut *upstream.addresses.lock().unwrap(), addresses.clone());
+        // the first resolution only learns the addresses the pool uses
+        if previous.is_empty() || previous == addresses {
+            continue;
+        }
+        upstream.rebuild_client(upstream.client_settings());
+        let message = format!(
+            "{} now resolves to {}, was {}; opened a new connection pool",
+            upstream.name,
+            list(&addresses),
+            list(&previous)
+        );
+        println!("+ {}", message);
+        config.events.record(message);
+    }
+}
+
+fn unresolved(upstream: &Upstream, reason: &str) {
+    let host = Url::parse(&upstream.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    println!(
+        "+ Cannot resolve {} for {} ({}), keeping its connection pool",
+        host, upstream.name, reason
+    );
+}
+
+fn list(addresses: &[IpAddr]) -> String {
+    let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+    addresses.join(", ")
+}
+
+/// Resolve the upstream hosts now and every `interval` from then on.
+pub async fn refresh(config: Arc<ServerConfig>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        refresh_all(&config).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, routing::post, Router};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// The client ports of the calls an upstream at `localhost` answered,
+    /// and its URL.
+    async fn by_name() -> (String, Arc<Mutex<Vec<u16>>>) {
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let url = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let peers = peers.clone();
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    peers.lock().unwrap().push(peer.port());
+                    r#"{"jsonrpc":"2.0","id":1,"result":"ok"}"#
+                }
+            }),
+        ))
+        .await;
+        (url.replace("127.0.0.1", "localhost"), peers)
+    }
+
+    async fn post_to(upstream: &Upstream) -> reqwest::StatusCode {
+        let client = upstream.client();
+        let response = client.post(&upstream.url).body("{}").send().await.unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn a_changed_address_set_gets_a_new_pool() {
+        let (url, peers) = by_name().await;
+        let config = crate::testing::config(&format!(
+            "[[upstreams]]\nname = \"named\"\nurl = \"{}\"\n[[upstreams]]\nname = \"literal\"\nurl = \"http://127.0.0.1:1\"\n[[upstreams]]\nname = \"lost\"\nurl = \"http://nowhere.invalid\"\n",
+            url
+        ));
+        let (named, literal, lost) = (&config.servers[0], &config.servers[1], &config.servers[2]);
+        refresh_all(&config).await;
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(named.addresses.lock().unwrap().contains(&localhost));
+        assert!(literal.addresses.lock().unwrap().is_empty());
+        assert!(lost.addresses.lock().unwrap().is_empty());
+        assert!(config.events.recent().is_empty());
+
+        post_to(named).await;
+        post_to(named).await;
+        let ports = |peers: &Mutex<Vec<u16>>| -> HashSet<u16> {
+            peers.lock().unwrap().iter().copied().collect()
+        };
+        assert_eq!(ports(&peers).len(), 1);
+
+        // as if the host had moved since the last resolution
+        let moved: IpAddr = "10.9.9.9".parse().unwrap();
+        *named.addresses.lock().unwrap() = vec![moved];
+        *lost.addresses.lock().unwrap() = vec![moved];
+        let in_flight = tokio::spawn({
+            let config = config.clone();
+            async move { post_to(&config.servers[0]).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        refresh_all(&config).await;
+        // requests in flight finish on the old pool
+        assert_eq!(in_flight.await.unwrap(), 200);
+        post_to(named).await;
+        assert_eq!(ports(&peers).len(), 2);
+        assert!(named.addresses.lock().unwrap().contains(&localhost));
+        let events = config.events.recent();
+        assert_eq!(events.len(), 1);
+        assert!(
+            events[0].1.starts_with("named now resolves to ")
+                && events[0]
+                    .1
+                    .ends_with(", was 10.9.9.9; opened a new connection pool"),
+            "{}",
+            events[0].1
+        );
+        // a failed resolution keeps what was known
+        assert_eq!(*lost.addresses.lock().unwrap(), [moved]);
+    }
+}
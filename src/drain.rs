@@ -0,0 +1,230 @@
+//! Draining upstreams ahead of planned maintenance. A drained upstream gets
+//! no new requests, while those in flight still finish and are answered;
+//! unlike the quarantine it is no failure, and the slot poller and the
+//! probes keep following it so it is current when undrained. Drain state
+//! lives with the upstreams, so config reloads keep it. Maintenance windows
+//! drain upstreams too, independently of this. Dead upstreams, see
+//! `quarantine::Phase::Dead`, are revived here as well.
+
+use crate::ServerConfig;
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+fn invalid(status: StatusCode, message: &str, data: Option<Value>) -> Response<Body> {
+    crate::rpc::error_response(status, crate::rpc::INVALID_REQUEST, message, data)
+}
+
+/// The upstream a `{"host"}` body names, and whether it asks to `force`.
+fn parse(config: &ServerConfig, body: &[u8]) -> Result<(usize, bool), String> {
+    let request: Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let name = request["host"].as_str().unwrap_or_default();
+    let index = config
+        .servers
+        .iter()
+        .position(|upstream| upstream.name == name)
+        .ok_or_else(|| format!("no upstream named '{}'", name))?;
+    Ok((index, request["force"].as_bool().unwrap_or(false)))
+}
+
+fn list(config: &ServerConfig) -> Json<Value> {
+    let drained: Vec<&str> = config
+        .servers
+        .iter()
+        .filter(|upstream| upstream.drained.load(Ordering::Relaxed))
+        .map(|upstream| upstream.name.as_str())
+        .collect();
+    Json(json!({ "drained": drained }))
+}
+
+/// `POST /admin/drain {"host", "force"}`: stop sending new requests to the
+/// upstream. Refused with a 409 when fewer than `min_healthy` healthy
+/// upstreams would be left, unless forced.
+pub async fn drain_handler(State(config): State<Arc<ServerConfig>>, body: Bytes) -> Response<Body> {
+    let (index, force) = match parse(&config, &body) {
+        Ok(parsed) => parsed,
+        Err(message) => return invalid(StatusCode::BAD_REQUEST, &message, None),
+    };
+    let upstream = &config.servers[index];
+    if upstream.drained.swap(true, Ordering::Relaxed) {
+        return list(&config).into_response();
+    }
+    let (healthy, _) = crate::quarantine::host_counts(&config).await;
+    let min_healthy = crate::quarantine::min_healthy(&config);
+    if healthy < min_healthy && !force {
+        upstream.drained.store(false, Ordering::Relaxed);
+        println!(
+            "+ Refused to drain {}: {} healthy upstreams would be left, {} required",
+            upstream.name, healthy, min_healthy
+        );
+        return invalid(
+            StatusCode::CONFLICT,
+            &format!(
+                "draining {} would leave {} healthy upstreams, below min_healthy {}; send \"force\": true to drain anyway",
+                upstream.name, healthy, min_healthy
+            ),
+            Some(json!({ "healthy": healthy, "min_healthy": min_healthy })),
+        );
+    }
+    let message = match force && healthy < min_healthy {
+        true => format!(
+            "{} drained (forced, {} healthy upstreams left)",
+            upstream.name, healthy
+        ),
+        false => format!("{} drained", upstream.name),
+    };
+    println!("+ {}", message);
+    config.events.record(message);
+    config
+        .generation
+        .record(format!("admin drain of {}", upstream.name));
+    config.readiness.update(&config).await;
+    list(&config).into_response()
+}
+
+/// `POST /admin/undrain {"host"}`: send new requests to the upstream again.
+pub async fn undrain_handler(
+    State(config): State<Arc<ServerConfig>>,
+    body: Bytes,
+) -> Response<Body> {
+    let index = match parse(&config, &body) {
+        Ok((index, _)) => index,
+        Err(message) => return invalid(StatusCode::BAD_REQUEST, &message, None),
+    };
+    let upstream = &config.servers[index];
+    if upstream.drained.swap(false, Ordering::Relaxed) {
+        let message = format!("{} undrained", upstream.name);
+        println!("+ {}", message);
+        config.events.record(message);
+        config
+            .generation
+            .record(format!("admin undrain of {}", upstream.name));
+        config.readiness.update(&config).await;
+    }
+    list(&config).into_response()
+}
+
+/// `GET /admin/drain`: the drained upstreams.
+pub async fn list_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    list(&config)
+}
+
+/// `POST /admin/revive {"host"}`: probe a dead upstream at the usual pace
+/// again, after its config was fixed. 409 when it is not dead.
+pub async fn revive_handler(
+    State(config): State<Arc<ServerConfig>>,
+    body: Bytes,
+) -> Response<Body> {
+    let index = match parse(&config, &body) {
+        Ok((index, _)) => index,
+        Err(message) => return invalid(StatusCode::BAD_REQUEST, &message, None),
+    };
+    let upstream = &config.servers[index];
+    if !crate::quarantine::revive(&config, index, "an operator") {
+        let message = format!("{} is not dead", upstream.name);
+        return invalid(StatusCode::CONFLICT, &message, None);
+    }
+    config.readiness.update(&config).await;
+    Json(json!({ "revived": upstream.name })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::slots::SlotSource;
+    use crate::testing::{proxy, upstream};
+
+    const ADMIN: &str = "secret-ops";
+
+    /// A proxy over upstreams a, b and c, all healthy, of which two must
+    /// stay so.
+    async fn three_upstreams() -> (Arc<ServerConfig>, String) {
+        let mut text = String::from(
+            "min_healthy = 2\n[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n",
+        );
+        for name in ["a", "b", "c"] {
+            let url = upstream(Behavior::default()).await;
+            text += &format!("[[upstreams]]\nname = \"{}\"\nurl = \"{}\"\n", name, url);
+        }
+        let (config, url) = proxy(&text).await;
+        for index in 0..3 {
+            config.slots.observe(index, 300, SlotSource::Poll);
+        }
+        (config, url)
+    }
+
+    async fn post(url: &str, path: &str, key: &str, body: Value) -> (u16, Value) {
+        let response = reqwest::Client::new()
+            .post(format!("{}{}", url, path))
+            .header("x-api-key", key)
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_admin_keys_drain() {
+        let (config, url) = three_upstreams().await;
+        let (status, _) = post(&url, "/admin/drain", "secret-app", json!({ "host": "a" })).await;
+        assert_eq!(status, 403);
+        // without a key the request is not authenticated at all
+        let listed = reqwest::get(format!("{}/admin/drain", url)).await.unwrap();
+        assert_eq!(listed.status(), 401);
+        assert!(!config.servers[0].is_drained());
+    }
+
+    #[tokio::test]
+    async fn drained_upstreams_get_no_new_requests() {
+        let (config, url) = three_upstreams().await;
+        let (status, body) = post(&url, "/admin/drain", ADMIN, json!({ "host": "a" })).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "drained": ["a"] }));
+        // draining twice changes nothing
+        let (_, body) = post(&url, "/admin/drain", ADMIN, json!({ "host": "a" })).await;
+        assert_eq!(body, json!({ "drained": ["a"] }));
+        let get_slot = json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" });
+        let (status, _) = post(&url, "/", "secret-app", get_slot).await;
+        assert_eq!(status, 200);
+        assert_eq!(config.servers[0].stats.successes(), 0);
+        let (_, body) = post(&url, "/admin/undrain", ADMIN, json!({ "host": "a" })).await;
+        assert_eq!(body, json!({ "drained": [] }));
+        assert!(!config.servers[0].is_drained());
+    }
+
+    #[tokio::test]
+    async fn draining_below_min_healthy_needs_force() {
+        let (config, url) = three_upstreams().await;
+        post(&url, "/admin/drain", ADMIN, json!({ "host": "a" })).await;
+        let (status, body) = post(&url, "/admin/drain", ADMIN, json!({ "host": "b" })).await;
+        assert_eq!(status, 409);
+        assert_eq!(body["error"]["code"], crate::rpc::INVALID_REQUEST);
+        assert_eq!(
+            body["error"]["data"],
+            json!({ "healthy": 1, "min_healthy": 2 })
+        );
+        assert!(!config.servers[1].is_drained());
+        let forced = json!({ "host": "b", "force": true });
+        let (status, body) = post(&url, "/admin/drain", ADMIN, forced).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "drained": ["a", "b"] }));
+    }
+
+    #[tokio::test]
+    async fn unknown_upstreams_are_rejected() {
+        let (_, url) = three_upstreams().await;
+        let (status, body) = post(&url, "/admin/drain", ADMIN, json!({ "host": "z" })).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["message"], "no upstream named 'z'");
+    }
+}
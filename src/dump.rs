@@ -0,0 +1,273 @@
+//! Human-readable snapshot of the internal state, written to stderr on
+//! SIGUSR1 for when the admin endpoints cannot be reached. A second signal
+//! within a few seconds also lists the requests in flight.
+
+use crate::ServerConfig;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// A dump longer than this is cut, so a huge in-flight list cannot flood
+/// the log.
+const MAX_DUMP_BYTES: usize = 64 * 1024;
+/// Second signal that also lists the requests in flight.
+const DETAIL_WINDOW: Duration = Duration::from_secs(5);
+
+struct Tracked {
+    client: String,
+    method: Option<String>,
+    started: Instant,
+}
+
+/// Client requests being handled, by request id.
+#[derive(Default)]
+pub struct InFlightRequests {
+    requests: Mutex<HashMap<u64, (String, Tracked)>>,
+    next: AtomicU64,
+}
+
+/// Registration of one request, removed when dropped.
+pub struct TrackedRequest<'a> {
+    registry: &'a InFlightRequests,
+    key: u64,
+}
+
+impl InFlightRequests {
+    pub fn track(&self, request_id: &str, client: String) -> TrackedRequest<'_> {
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        let tracked = Tracked {
+            client,
+            method: None,
+            started: Instant::now(),
+        };
+        let mut requests = self.requests.lock().unwrap();
+        requests.insert(key, (request_id.to_string(), tracked));
+        TrackedRequest {
+            registry: self,
+            key,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+impl TrackedRequest<'_> {
+    pub fn set_method(&self, method: &str) {
+        let mut requests = self.registry.requests.lock().unwrap();
+        if let Some((_, tracked)) = requests.get_mut(&self.key) {
+            tracked.method = Some(method.to_string());
+        }
+    }
+}
+
+impl Drop for TrackedRequest<'_> {
+    fn drop(&mut self) {
+        self.registry.requests.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Write a dump on every SIGUSR1.
+pub async fn dump_on_sigusr1(config: Arc<ServerConfig>) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            println!("+ State dumps disabled, cannot listen for SIGUSR1: {}", err);
+            return;
+        }
+    };
+    let mut last: Option<Instant> = None;
+    while signals.recv().await.is_some() {
+        let detailed = last.is_some_and(|last| last.elapsed() < DETAIL_WINDOW);
+        last = Some(Instant::now());
+        let mut dump = snapshot(&config, detailed).await;
+        if dump.len() > MAX_DUMP_BYTES {
+            let mut end = MAX_DUMP_BYTES;
+            while !dump.is_char_boundary(end) {
+                end -= 1;
+            }
+            dump.truncate(end);
+            dump.push_str("\n... truncated\n");
+        }
+        eprint!("{}", dump);
+    }
+}
+
+/// Every lock is only held to copy what it guards.
+async fn snapshot(config: &ServerConfig, detailed: bool) -> String {
+    let quarantine = config.quarantine.read().await.clone();
+    let settings = &config.settings;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "=== quarantier state dump at {} ===",
+        crate::clock::rfc3339(crate::clock::unix_ms())
+    );
+    let _ = writeln!(out, "{}", crate::version::describe());
+    let _ = writeln!(
+        out,
+        "config: port {}, {} upstreams, min_healthy {}, {} API keys, deadline {:?}, lingering cap {}",
+        settings.port,
+        config.servers.len(),
+        settings.min_healthy,
+        config.api_keys().len(),
+        settings.deadlines.default,
+        settings.max_lingering_dispatches
+    );
+
+    let _ = writeln!(out, "upstreams:");
+    let max_slot = crate::slots::tip(config);
+    for (index, upstream) in config.servers.iter().enumerate() {
+        let slot = match config.slots.get(index) {
+            Some(observation) => format!(
+                "slot {} (lag {}, {} {:?} ago{})",
+                observation.slot,
+                max_slot.map_or(0, |max| max.saturating_sub(observation.slot)),
+                observation.source.as_str(),
+                observation.at.elapsed(),
+                if config.slots.is_fresh(&observation) {
+                    ""
+                } else {
+                    ", stale"
+                }
+            ),
+            None => "no slot yet".to_string(),
+        };
+        let stats = &upstream.stats;
+        let _ = writeln!(
+            out,
+            "  {}: {}, last latency {}, {} successes, failure streak {}, idle {:?}, {}{}",
+            upstream.name,
+            slot,
+            stats
+                .last_latency()
+                .map_or("-".to_string(), |latency| format!("{:?}", latency)),
+            stats.successes(),
+            stats.failure_streak(),
+            stats.idle_for(),
+            if upstream.is_last_resort() {
+                "last resort"
+            } else if upstream.maintenance.excludes() {
+                "in maintenance"
+            } else if upstream.is_drained() {
+                "drained"
+            } else if quarantine.contains(&index) {
+                "quarantined (slot lag)"
+            } else if upstream.is_failing() {
+                "quarantined (failures)"
+            } else {
+                "active"
+            },
+            match config.chaos.report(index).len() {
+                0 => String::new(),
+                n => format!(", {} chaos injections", n),
+            }
+        );
+    }
+
+    let latency = config.stats.latency.snapshot();
+    let _ = writeln!(
+        out,
+        "requests: {} handled, {} errors, {} in flight, p50 {} ms, p99 {} ms",
+        config.stats.requests.load(Ordering::Relaxed),
+        config.stats.errors.load(Ordering::Relaxed),
+        config.requests.count(),
+        latency
+            .quantile(0.5)
+            .map_or("-".to_string(), |ms| format!("{:.0}", ms)),
+        latency
+            .quantile(0.99)
+            .map_or("-".to_string(), |ms| format!("{:.0}", ms)),
+    );
+    let tasks = &config.tasks;
+    let _ = writeln!(
+        out,
+        "dispatch tasks: {} spawned, {} completed, {} aborted, {} lingering",
+        tasks.spawned(),
+        tasks.completed(),
+        tasks.aborted(),
+        tasks.lingering()
+    );
+
+    let events = config.events.recent();
+    let _ = writeln!(out, "recent events ({}):", events.len());
+    for (at, event) in events {
+        let _ = writeln!(out, "  {} {}", crate::clock::rfc3339(at), event);
+    }
+
+    if detailed {
+        let mut requests: Vec<(String, String, Option<String>, Duration)> = {
+            let requests = config.requests.requests.lock().unwrap();
+            requests
+                .values()
+                .map(|(id, tracked)| {
+                    let method = tracked.method.clone();
+                    (
+                        id.clone(),
+                        tracked.client.clone(),
+                        method,
+                        tracked.started.elapsed(),
+                    )
+                })
+                .collect()
+        };
+        requests.sort_by_key(|request| std::cmp::Reverse(request.3));
+        let _ = writeln!(
+            out,
+            "in-flight requests ({}), oldest first:",
+            requests.len()
+        );
+        for (id, client, method, age) in requests {
+            let method = method.as_deref().unwrap_or("reading body");
+            let _ = writeln!(out, "  [{}] {} from {}, {:?}", id, method, client, age);
+        }
+    }
+    let _ = writeln!(out, "=== end of dump ===");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+
+    #[tokio::test]
+    async fn the_dump_lists_upstreams_and_in_flight_requests() {
+        let config = crate::testing::config(
+            "[[upstreams]]\nname = \"a\"\nurl = \"http://127.0.0.1:1\"\n\n[[upstreams]]\nname = \"b\"\nurl = \"http://127.0.0.1:2\"\n",
+        );
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config.quarantine.write().await.push(1);
+        let request = config.requests.track("abc123", "10.0.0.7:4000".into());
+        request.set_method("getBalance");
+
+        let dump = snapshot(&config, false).await;
+        assert!(
+            dump.starts_with("=== quarantier state dump at "),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("config: port "), "{}", dump);
+        assert!(dump.contains("\n  a: slot 1000 (lag 0, poll "), "{}", dump);
+        assert!(dump.contains("\n  b: no slot yet, "), "{}", dump);
+        assert!(dump.contains(", quarantined (slot lag)\n"), "{}", dump);
+        assert!(dump.contains(", 1 in flight, "), "{}", dump);
+        assert!(!dump.contains("in-flight requests"), "{}", dump);
+        assert!(dump.ends_with("=== end of dump ===\n"));
+
+        let dump = snapshot(&config, true).await;
+        assert!(
+            dump.contains(
+                "in-flight requests (1), oldest first:\n  [abc123] getBalance from 10.0.0.7:4000, "
+            ),
+            "{}",
+            dump
+        );
+        drop(request);
+        assert_eq!(config.requests.count(), 0);
+    }
+}
@@ -0,0 +1,646 @@
+//! The configuration in effect, for `GET /admin/config` and
+//! `--print-effective-config`: the loaded file with its defaults filled in,
+//! and what changed since at runtime on top, the API keys, ACL and upstream
+//! timeouts of reloads and the drains of the admin API. Secrets, API keys,
+//! credentials and the paths and queries of URLs that may embed provider
+//! keys, are replaced by a fingerprint, enough to tell two deployments
+//! apart without revealing them. A generation counter moves with every
+//! change so automation can notice drift.
+
+use crate::config::{
+    AccessLogFormat, AllQuarantined, BelowMinVersion, Config, DuplicatePolicy, DuplicateSends,
+    HedgePolicy, MirrorSink, Priority, ResetFormat, RouteBy, Strategy, Tolerance,
+};
+use crate::methods::MethodPolicy;
+use crate::ratelimit::Limit;
+use crate::ServerConfig;
+use axum::{extract::State, Json};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `sha256:` and the first bytes of the digest of `secret`, in hex.
+pub fn fingerprint(secret: &str) -> String {
+    let digest = openssl::sha::sha256(secret.as_bytes());
+    let hex: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+/// `url` with its path and query, where providers put keys, replaced by
+/// their fingerprint.
+pub fn redact_url(url: &str) -> String {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    let rest = url[host_start..]
+        .find(['/', '?'])
+        .map_or(url.len(), |i| host_start + i);
+    match &url[rest..] {
+        "" | "/" => url.to_string(),
+        secret => format!("{}/[redacted {}]", &url[..rest], fingerprint(secret)),
+    }
+}
+
+/// Counts the changes to the configuration in effect.
+pub struct Generation {
+    number: AtomicU64,
+    /// When and through what the last change came.
+    last: Mutex<(u64, String)>,
+}
+
+impl Generation {
+    pub fn new() -> Self {
+        Self {
+            number: AtomicU64::new(1),
+            last: Mutex::new((crate::clock::unix_ms(), "startup".to_string())),
+        }
+    }
+
+    /// Count a change made through `source`.
+    pub fn record(&self, source: impl Into<String>) {
+        let mut last = self.last.lock().unwrap();
+        self.number.fetch_add(1, Ordering::Relaxed);
+        *last = (crate::clock::unix_ms(), source.into());
+    }
+
+    fn report(&self) -> (u64, Value) {
+        let last = self.last.lock().unwrap();
+        let change = json!({ "at": crate::clock::rfc3339(last.0), "source": last.1 });
+        (self.number.load(Ordering::Relaxed), change)
+    }
+}
+
+fn ms(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+fn limit(limit: Option<Limit>) -> Value {
+    match limit {
+        Some(limit) => json!({ "rps": limit.rps, "burst": limit.burst }),
+        None => Value::Null,
+    }
+}
+
+fn methods(policy: &MethodPolicy) -> Value {
+    json!({ "allow": policy.allow, "deny": policy.deny })
+}
+
+fn hedge(policy: Option<HedgePolicy>) -> Value {
+    match policy {
+        Some(policy) => json!({ "hedge_ms": ms(policy.delay), "max_hedges": policy.max_hedges }),
+        None => json!(false),
+    }
+}
+
+fn upstreams(config: &ServerConfig) -> Vec<Value> {
+    let settings = &config.settings;
+    let upstreams = config.servers.iter().zip(&settings.upstreams);
+    upstreams
+        .map(|(upstream, configured)| {
+            // the client settings are those of the last reload
+            let client = upstream.client_settings();
+            let limits = &configured.rate_limit;
+            let windows = configured.maintenance.iter().map(|window| {
+                let weekday = window
+                    .weekday
+                    .map(|day| crate::config::WEEKDAYS[day as usize]);
+                json!({
+                    "weekday": weekday.unwrap_or("daily"),
+                    "start": format!("{:02}:{:02}", window.start / 3600, window.start % 3600 / 60),
+                    "duration_mins": window.duration.as_secs() / 60,
+                })
+            });
+            json!({
+                "name": upstream.name,
+                "url": redact_url(&upstream.url),
+                "ws_url": configured.ws_url.as_deref().map(redact_url),
+                "archive": configured.archive,
+                "capabilities": configured.capabilities,
+                "region": configured.region,
+                "role": match configured.last_resort {
+                    Some(_) => "last_resort",
+                    None => "regular",
+                },
+                "last_resort": limit(configured.last_resort),
+                "connect_timeout_ms": ms(client.connect_timeout),
+                "request_timeout_ms": ms(client.request_timeout),
+                "rate_limit": {
+                    "rps": configured.outbound.map(|limit| limit.rps),
+                    "burst": configured.outbound.map(|limit| limit.burst),
+                    "remaining_header": limits.remaining_header,
+                    "reset_header": limits.reset_header,
+                    "reset_format": match limits.reset_format {
+                        ResetFormat::Seconds => "seconds",
+                        ResetFormat::UnixSeconds => "unix",
+                        ResetFormat::UnixMillis => "unix_ms",
+                    },
+                    "min_remaining": limits.min_remaining,
+                    "default_hold_secs": limits.default_hold.as_secs(),
+                },
+                "maintenance": windows.collect::<Vec<_>>(),
+                "credentials": upstream.credentials.report(),
+                "drained": upstream.drained.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+fn auth(config: &ServerConfig) -> Value {
+    let settings = &config.settings.auth;
+    let keys = config.api_keys();
+    let keys: Vec<Value> = keys
+        .secrets()
+        .into_iter()
+        .map(|(secret, key)| {
+            json!({
+                "name": key.name,
+                "key": fingerprint(secret),
+                "rate_limit": limit(key.limit()),
+                "methods": methods(&key.methods),
+                "quota": key.quota.map(|quota| json!({
+                    "limit": quota.limit,
+                    "window": quota.window.as_str(),
+                })),
+                "max_concurrent": key.max_concurrent,
+                "admin": key.admin,
+                "priority": key.priority.map(Priority::as_str),
+            })
+        })
+        .collect();
+    let jwt = settings.jwt.as_ref().map(|jwt| {
+        json!({
+            "secret": jwt.secret.as_deref().map(fingerprint),
+            "jwks_url": jwt.jwks_url.as_deref().map(redact_url),
+            "jwks_refresh_secs": jwt.jwks_refresh.as_secs(),
+            "issuer": jwt.issuer,
+            "audience": jwt.audience,
+            "identity_claim": jwt.identity_claim,
+            "leeway_secs": jwt.leeway.as_secs(),
+        })
+    });
+    json!({
+        "header": config.api_keys().header().as_str(),
+        "keys": keys,
+        "rate_limit": limit(settings.rate_limit),
+        "jwt": jwt,
+        "quota_utc_offset_secs": settings.quota_utc_offset,
+        "quota_soft_limit_pct": settings.quota_soft_limit * 100.0,
+    })
+}
+
+fn routing(settings: &Config) -> Value {
+    let routing = &settings.routing;
+    let strategies: Map<String, Value> = routing
+        .methods
+        .iter()
+        .map(|(method, strategy)| {
+            let strategy = match strategy {
+                Strategy::Race => "race",
+                Strategy::Merge => "merge",
+            };
+            (method.clone(), json!(strategy))
+        })
+        .collect();
+    let hedges: Map<String, Value> = routing
+        .hedges
+        .iter()
+        .map(|(method, policy)| (method.clone(), hedge(*policy)))
+        .collect();
+    let fanouts: Map<String, Value> = routing
+        .fanouts
+        .iter()
+        .map(|(method, fanout)| (method.clone(), json!(fanout.unwrap_or(0))))
+        .collect();
+    json!({
+        "strategies": strategies,
+        "merge_wait_ms": ms(routing.merge_wait),
+        "hedge": hedge(routing.hedge),
+        "hedges": hedges,
+        "fanout": routing.fanout.unwrap_or(0),
+        "fanouts": fanouts,
+        "request_timeouts_ms": durations(&routing.timeouts),
+    })
+}
+
+fn durations(methods: &std::collections::HashMap<String, Duration>) -> Map<String, Value> {
+    let methods = methods.iter();
+    methods
+        .map(|(method, duration)| (method.clone(), json!(ms(*duration))))
+        .collect()
+}
+
+/// The configuration in effect, secrets redacted.
+pub fn document(config: &ServerConfig) -> Value {
+    let settings = &config.settings;
+    let (generation, last_change) = config.generation.report();
+    let acl = config.acl.lists();
+    let networks = |nets: &[ipnet::IpNet]| nets.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let capabilities: Map<String, Value> = settings
+        .capabilities
+        .names
+        .iter()
+        .map(|name| (name.clone(), json!(settings.capabilities.methods_of(name))))
+        .collect();
+    let mut document = json!({
+        "config_generation": generation,
+        "last_change": last_change,
+        "port": settings.port,
+        "upstreams": upstreams(config),
+        "min_healthy": settings.min_healthy,
+        "min_observations": settings.min_observations,
+        "all_quarantined": match settings.all_quarantined {
+            AllQuarantined::ServeBest => "serve_best",
+            AllQuarantined::Fail => "fail",
+        },
+        "local_region": settings.region.as_ref().map(|region| &region.local),
+        "region_fanout": settings.region.as_ref().map(|region| region.fanout.as_str()),
+        "trust_forwarded_for": settings.client_ip.trust_forwarded_for,
+        "trusted_proxies": networks(&settings.client_ip.trusted_proxies),
+        "acl": { "allow": networks(&acl.allow), "deny": networks(&acl.deny) },
+        "request_id_header": settings.request_id_header.as_str(),
+        "debug_headers": settings.debug_headers,
+        "log_bodies": settings.log_bodies,
+        "aggregate_get_health": settings.aggregate_get_health,
+        "auth": auth(config),
+        "methods": methods(&settings.methods),
+        "max_concurrent_per_client": settings.max_concurrent_per_client,
+        "max_lingering_dispatches": settings.max_lingering_dispatches,
+        "ip_rate_limit": settings.ip_rate_limit.as_ref().map(|ip| json!({
+            "rps": ip.limit.rps,
+            "burst": ip.limit.burst,
+            "max_clients": ip.max_clients,
+        })),
+        "usage": { "max_clients": settings.usage.max_clients, "top": settings.usage.top },
+        "costs": {
+            "default": settings.costs.default,
+            "methods": settings.costs.methods,
+            "count_aborted": settings.costs.count_aborted,
+            "retention_days": settings.costs.retention_days,
+        },
+        "deadlines": {
+            "default_ms": ms(settings.deadlines.default),
+            "methods": durations(&settings.deadlines.methods),
+            "header": settings.deadlines.header.as_str(),
+        },
+        "response_limits": {
+            "default_mib": settings.response_limits.default.map_or(0, |bytes| bytes >> 20),
+            "methods": settings
+                .response_limits
+                .methods
+                .iter()
+                .map(|(method, limit)| (method.clone(), json!(limit.map_or(0, |bytes| bytes >> 20))))
+                .collect::<Map<String, Value>>(),
+        },
+        "routing": routing(settings),
+        "capabilities": capabilities,
+    });
+    for part in [features(settings), outputs(settings)] {
+        if let (Value::Object(document), Value::Object(part)) = (&mut document, part) {
+            document.extend(part);
+        }
+    }
+    document
+}
+
+/// The optional behaviours and the upstream health checks.
+fn features(settings: &Config) -> Value {
+    let tolerance = match settings.slots.tolerance {
+        Tolerance::Slots(slots) => json!(slots),
+        Tolerance::Time(time) => json!(format!("{}ms", time.as_millis())),
+    };
+    let offsets = |offsets: &[u64; 3]| {
+        json!({
+            "processed_offset": offsets[0],
+            "confirmed_offset": offsets[1],
+            "finalized_offset": offsets[2],
+        })
+    };
+    let mut strict_methods: Vec<&String> = settings
+        .strict_freshness
+        .iter()
+        .flat_map(|strict| &strict.methods)
+        .collect();
+    strict_methods.sort_unstable();
+    json!({
+        "history": { "retention_slots": settings.retention_slots },
+        "min_context_slot": settings.min_context_margin.map(|margin| json!({ "margin_slots": margin })),
+        "strict_freshness": settings.strict_freshness.as_ref().map(|strict| {
+            let mut report = offsets(&strict.offsets);
+            report["methods"] = json!(strict_methods);
+            report["header"] = json!(strict.header.as_str());
+            report
+        }),
+        "soft_deadline": settings.soft_deadline.map(|after| json!({ "after_first_ms": ms(after) })),
+        "retry": settings.retry.as_ref().map(|retry| json!({
+            "exclude_methods": retry.exclude_methods,
+            "budget_percent": retry.budget_percent,
+        })),
+        "priority": {
+            "high_methods": settings.priorities.high,
+            "low_methods": settings.priorities.low,
+        },
+        "load_shedding": settings.load_shedding.as_ref().map(|shedding| json!({
+            "max_in_flight": shedding.max_in_flight,
+            "queue_ms": ms(shedding.queue),
+            "low_share_percent": shedding.low_share_percent,
+        })),
+        "slots": {
+            "poll_interval_ms": ms(settings.slots.poll_interval),
+            "max_age_ms": ms(settings.slots.max_age),
+            "ws_stale_ms": ms(settings.slots.ws_stale_after),
+            "ws_max_backoff_ms": ms(settings.slots.ws_max_backoff),
+            "tolerance": tolerance,
+        },
+        "epoch_cache": settings.epoch_cache.as_ref().map(|cache| json!({
+            "refresh_interval_secs": cache.refresh_interval.as_secs(),
+            "max_age_secs": cache.max_age.as_secs(),
+        })),
+        "local_get_slot": settings.local_get_slot.as_ref().map(|local| {
+            let mut report = offsets(&local.offsets);
+            report["max_age_ms"] = json!(ms(local.max_age));
+            report
+        }),
+        "coalesce": settings.coalesce.as_ref().map(|coalesce| {
+            let mut methods: Vec<&String> = coalesce.methods.iter().collect();
+            methods.sort();
+            json!({ "methods": methods })
+        }),
+        "send_dedup": settings.send_dedup.as_ref().map(|dedup| json!({
+            "ttl_ms": ms(dedup.ttl),
+            "max_entries": dedup.max_entries,
+            "duplicates": match dedup.duplicates {
+                DuplicateSends::Answer => "answer",
+                DuplicateSends::ForwardOne => "forward_one",
+            },
+        })),
+        "discovery": settings.discovery.as_ref().map(|discovery| json!({
+            "interval_secs": discovery.interval.as_secs(),
+            "startup_timeout_ms": ms(discovery.startup_timeout),
+            "das": discovery.das,
+            "duplicate_upstreams": match discovery.duplicates {
+                DuplicatePolicy::Warn => "warn",
+                DuplicatePolicy::Alias => "alias",
+            },
+            "identity_interval_secs": discovery.identity_interval.as_secs(),
+        })),
+        "cluster": settings.cluster.as_ref().map(|cluster| json!({
+            "genesis_hash": cluster.genesis_hash,
+            "startup_timeout_ms": ms(cluster.startup_timeout),
+        })),
+        "versions": settings.versions.as_ref().map(|versions| json!({
+            "refresh_secs": versions.refresh.as_secs(),
+            "allowed_skew": crate::versions::skew_name(versions.allowed_skew),
+            "min_version": versions.min_version.map(|version| version.to_string()),
+            "below_min_version": match versions.below_min_version {
+                BelowMinVersion::Warn => "warn",
+                BelowMinVersion::Quarantine => "quarantine",
+            },
+        })),
+        "ranking": settings.ranking.as_ref().map(|ranking| json!({
+            "windows_hours": ranking.windows,
+            "success_weight": ranking.weights.success,
+            "latency_weight": ranking.weights.latency,
+            "lag_weight": ranking.weights.lag,
+            "quarantine_weight": ranking.weights.quarantine,
+            "route_by": match ranking.route_by {
+                RouteBy::Latency => "latency",
+                RouteBy::Score => "score",
+            },
+        })),
+        "unsupported_methods": settings.unsupported_methods.as_ref().map(|unsupported| json!({
+            "ttl_secs": unsupported.ttl.as_secs(),
+            "version_check_secs": unsupported.version_check_interval.as_secs(),
+        })),
+        "tx_tracking": settings.tx_tracking.as_ref().map(|tracking| json!({
+            "track_secs": tracking.track_for.as_secs(),
+            "poll_interval_ms": ms(tracking.poll_interval),
+            "max_tracked": tracking.max_tracked,
+        })),
+        "blockhash": settings.blockhash.as_ref().map(|blockhash| json!({
+            "margin_blocks": blockhash.margin_blocks,
+            "strikes": blockhash.strikes,
+            "quarantine": blockhash.quarantine,
+            "probe_interval_secs": blockhash.probe_interval.map(|interval| interval.as_secs()),
+        })),
+        "warmup": {
+            "connections": settings.warmup.connections,
+            "keep_warm_after_secs": settings.warmup.keep_warm_after.map(|after| after.as_secs()),
+        },
+        "circuit_breaker": {
+            "cooldown_ms": ms(settings.circuit.cooldown),
+            "max_cooldown_ms": ms(settings.circuit.max_cooldown),
+            "probe_successes": settings.circuit.probe_successes,
+            "dead_after_secs": settings.circuit.dead_after.map(|after| after.as_secs()),
+            "dead_probe_interval_secs": settings.circuit.dead_probe_interval.as_secs(),
+        },
+        "failback": {
+            "mode": crate::failback::mode(settings.failback.mode),
+            "stabilize_secs": settings.failback.stabilize.as_secs(),
+            "max_stabilize_secs": settings.failback.max_stabilize.as_secs(),
+            "ramp_secs": settings.failback.ramp.as_secs(),
+            "damping_window_secs": settings.failback.damping_window.as_secs(),
+        },
+        "error_rate": settings.error_rate.as_ref().map(|rate| json!({
+            "window_secs": rate.window_secs,
+            "threshold": rate.threshold,
+            "min_requests": rate.min_requests,
+        })),
+        "body_budget": {
+            "max_bytes": settings.body_budget.limit,
+            "wait_ms": ms(settings.body_budget.wait),
+        },
+    })
+}
+
+/// Where the proxy's logs, metrics and state go.
+fn outputs(settings: &Config) -> Value {
+    json!({
+        "dns": { "refresh_interval_secs": settings.dns_refresh.map(|every| every.as_secs()) },
+        "maintenance": { "drain_before_secs": settings.maintenance_lead.as_secs() },
+        "summary_interval_secs": settings.summary_interval.map(|every| every.as_secs()),
+        "statsd": settings.statsd.as_ref().map(|statsd| json!({
+            "address": statsd.address,
+            "flush_interval_ms": ms(statsd.flush_interval),
+            "prefix": statsd.prefix,
+            "tags": statsd.tags,
+        })),
+        "status_export": settings.status_export.as_ref().map(|export| json!({
+            "path": export.path,
+            "interval_secs": export.interval.as_secs(),
+        })),
+        "state_file": settings.state_file,
+        "alerts": { "webhook_url": settings.alerts.webhook_url.as_deref().map(redact_url) },
+        "access_log": settings.access_log.as_ref().map(|log| json!({
+            "path": log.path,
+            "format": match log.format {
+                AccessLogFormat::Combined => "combined",
+                AccessLogFormat::Json => "json",
+            },
+            "max_size_bytes": log.max_size,
+            "max_files": log.max_files,
+            "sample_rate": log.sample_rate,
+        })),
+        "tx_journal": settings.tx_journal.as_ref().map(|journal| json!({
+            "path": journal.path,
+            "max_size_bytes": journal.max_size,
+            "max_files": journal.max_files,
+            "include_transaction": journal.include_transaction,
+        })),
+        "mirror": settings.mirror.as_ref().map(|mirror| json!({
+            "url": match &mirror.sink {
+                MirrorSink::Http(url) => Some(redact_url(url)),
+                MirrorSink::File(_) => None,
+            },
+            "path": match &mirror.sink {
+                MirrorSink::File(path) => Some(path),
+                MirrorSink::Http(_) => None,
+            },
+            "sample_rate": mirror.sample_rate,
+            "methods": mirror.method_rates,
+            "max_body_bytes": mirror.max_body,
+            "queue": mirror.queue,
+            "redact_methods": mirror.redact_methods,
+            "redact_clients": mirror.redact_clients,
+        })),
+    })
+}
+
+/// `GET /admin/config`: the configuration in effect, secrets redacted.
+pub async fn config_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    Json(document(&config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::proxy;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    const KEYS: &str =
+        "[auth]\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n\
+                        [auth.jwt]\nsecret = \"jwt-hunter2\"\n";
+
+    fn upstreams(timeout_ms: u64) -> String {
+        format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"http://127.0.0.1:1/v2/hunter2\"\nrequest_timeout_ms = {}\n\
+             [[upstreams.credentials]]\nheader = \"x-api-key\"\nvalue = \"cred-hunter2\"\n\
+             [[upstreams]]\nname = \"b\"\nurl = \"http://127.0.0.1:2\"\n",
+            timeout_ms
+        )
+    }
+
+    async fn admin_config(url: &str) -> Value {
+        reqwest::Client::new()
+            .get(format!("{}/admin/config", url))
+            .header("x-api-key", "secret-ops")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn secrets_become_fingerprints() {
+        assert_eq!(fingerprint("hunter2"), fingerprint("hunter2"));
+        assert_ne!(fingerprint("hunter2"), fingerprint("hunter3"));
+        assert!(fingerprint("hunter2").starts_with("sha256:"));
+        assert_eq!(fingerprint("hunter2").len(), "sha256:".len() + 12);
+        for plain in ["http://node:8899", "http://node:8899/", "node:8899"] {
+            assert_eq!(redact_url(plain), plain);
+        }
+        assert_eq!(
+            redact_url("https://node.io/v2/hunter2"),
+            format!("https://node.io/[redacted {}]", fingerprint("/v2/hunter2"))
+        );
+        assert_eq!(
+            redact_url("https://node.io?api-key=hunter2"),
+            format!(
+                "https://node.io/[redacted {}]",
+                fingerprint("?api-key=hunter2")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn the_document_shows_the_settings_and_no_secret() {
+        let text = format!("{}{}", KEYS, upstreams(5000));
+        let (_, url) = proxy(&text).await;
+        let document = admin_config(&url).await;
+        let serialized = document.to_string();
+        for secret in ["secret-ops", "hunter2"] {
+            assert!(!serialized.contains(secret), "{} in {}", secret, serialized);
+        }
+        assert_eq!(document["config_generation"], 1);
+        assert_eq!(document["last_change"]["source"], "startup");
+        let a = &document["upstreams"][0];
+        assert_eq!(a["name"], "a");
+        assert_eq!(a["request_timeout_ms"], 5000);
+        assert_eq!(a["credentials"][0]["header"], "x-api-key");
+        assert_eq!(a["credentials"][0]["value"], fingerprint("cred-hunter2"));
+        assert_eq!(
+            document["auth"]["keys"][0]["key"],
+            fingerprint("secret-ops")
+        );
+        assert_eq!(
+            document["auth"]["jwt"]["secret"],
+            fingerprint("jwt-hunter2")
+        );
+        // the same document as --print-effective-config
+        let service = crate::ProxyService::new(Config::parse(&text).unwrap());
+        let mut printed = service.effective_config();
+        let mut served = document;
+        printed["last_change"]["at"] = Value::Null;
+        served["last_change"]["at"] = Value::Null;
+        assert_eq!(printed, served);
+    }
+
+    #[tokio::test]
+    async fn reloads_and_admin_changes_move_the_generation() {
+        let path = std::env::temp_dir().join(format!("quarantier-{}.toml", crate::random::u64()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, format!("{}{}", KEYS, upstreams(5000))).unwrap();
+        let (config, url) = proxy(&std::fs::read_to_string(&path).unwrap()).await;
+        // caught from here on, the process is not hung up on
+        let _hangups = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(crate::reload::reload_on_sighup(
+            config.clone(),
+            path.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, format!("{}{}", KEYS, upstreams(2000))).unwrap();
+        let hangup = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(hangup.success());
+        let mut document = Value::Null;
+        for _ in 0..100 {
+            document = admin_config(&url).await;
+            if document["config_generation"] == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(document["config_generation"], 2);
+        assert_eq!(
+            document["last_change"]["source"],
+            format!("reload of {}", path)
+        );
+        assert_eq!(document["upstreams"][0]["request_timeout_ms"], 2000);
+
+        let drained = reqwest::Client::new()
+            .post(format!("{}/admin/drain", url))
+            .header("x-api-key", "secret-ops")
+            .json(&json!({ "host": "b", "force": true }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(drained.status(), 200);
+        let document = admin_config(&url).await;
+        assert_eq!(document["config_generation"], 3);
+        assert_eq!(document["last_change"]["source"], "admin drain of b");
+        assert_eq!(document["upstreams"][1]["drained"], true);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,324 @@
+//! Cheap inspection of upstream answers. Small bodies are parsed in full;
+//! large ones only have their `error` and `result.context` read, skipping
+//! the rest without allocating and stopping as soon as the context is seen,
+//! which Solana puts at the start of `result`. A large batch answer has
+//! each of its calls read that way, to the end of the array.
+
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// Bodies up to this size are parsed in full, so they can be compared
+/// across upstreams.
+pub const FULL_PARSE_LIMIT: usize = 256 * 1024;
+
+/// Sentinel error ending the scan once the context is read.
+const DONE: &str = "quarantier: context found";
+
+/// What an answer says, as a JSON value: the whole body when it is small,
+/// otherwise a skeleton with only `error` and `result.context`, or an array
+/// of them for a batch. `None` when the body is not JSON.
+pub struct Inspected {
+    pub json: Option<Value>,
+    /// Whether `json` is the whole body.
+    pub complete: bool,
+}
+
+pub fn inspect(body: &[u8]) -> Inspected {
+    if body.len() <= FULL_PARSE_LIMIT {
+        return Inspected {
+            json: serde_json::from_slice(body).ok(),
+            complete: true,
+        };
+    }
+    let mut skeleton = Skeleton::default();
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let scanned = match (&mut deserializer).deserialize_any(SkeletonVisitor(&mut skeleton)) {
+        Ok(()) => deserializer.end().is_ok(),
+        Err(err) => err.to_string().starts_with(DONE),
+    };
+    Inspected {
+        json: scanned.then(|| skeleton.into_value()),
+        complete: false,
+    }
+}
+
+#[derive(Default)]
+struct Skeleton {
+    fields: Map<String, Value>,
+    /// `result` is an object, possibly with a context.
+    result: Option<Map<String, Value>>,
+    /// The skeletons of the calls, when the answer is a batch.
+    batch: Option<Vec<Value>>,
+    /// Read on past the context, as a call of a batch is followed by others.
+    whole: bool,
+}
+
+impl Skeleton {
+    fn into_value(mut self) -> Value {
+        if let Some(batch) = self.batch {
+            return Value::Array(batch);
+        }
+        if let Some(result) = self.result {
+            self.fields.insert("result".into(), Value::Object(result));
+        }
+        Value::Object(self.fields)
+    }
+}
+
+struct SkeletonVisitor<'a>(&'a mut Skeleton);
+
+impl<'de> Visitor<'de> for SkeletonVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON-RPC response")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let batch = self.0.batch.insert(Vec::new());
+        while let Some(call) = seq.next_element_seed(CallSeed)? {
+            batch.push(call);
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "result" => map.next_value_seed(ResultSeed(self.0))?,
+                "error" | "id" | "jsonrpc" => {
+                    let value = map.next_value::<Value>()?;
+                    self.0.fields.insert(key, value);
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One call of a batch answer, read as a skeleton of its own.
+struct CallSeed;
+
+impl<'de> DeserializeSeed<'de> for CallSeed {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        let mut skeleton = Skeleton {
+            whole: true,
+            ..Default::default()
+        };
+        deserializer.deserialize_map(SkeletonVisitor(&mut skeleton))?;
+        Ok(skeleton.into_value())
+    }
+}
+
+struct ResultSeed<'a>(&'a mut Skeleton);
+
+impl<'de> DeserializeSeed<'de> for ResultSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ResultSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a result")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let result = self.0.result.insert(Map::new());
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "context" {
+                result.insert(key, map.next_value::<Value>()?);
+                if !self.0.whole {
+                    return Err(de::Error::custom(DONE));
+                }
+                continue;
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        self.0.fields.insert("result".into(), json!([]));
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        self.0.fields.insert("result".into(), json!(""));
+        Ok(())
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<(), E> {
+        self.0.fields.insert("result".into(), json!(value));
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<(), E> {
+        self.0.fields.insert("result".into(), json!(value));
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<(), E> {
+        self.0.fields.insert("result".into(), json!(value));
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<(), E> {
+        self.0.fields.insert("result".into(), json!(value));
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        self.0.fields.insert("result".into(), Value::Null);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `getProgramAccounts`-shaped answer over the full parse limit.
+    fn large(context_first: bool) -> String {
+        let account = json!({
+            "pubkey": "Vote111111111111111111111111111111111111111",
+            "account": { "data": ["A".repeat(1000), "base64"], "lamports": 1 },
+        });
+        let value = Value::Array(vec![account; 600]).to_string();
+        let context = r#""context":{"apiVersion":"2.0.15","slot":300000123}"#;
+        let result = match context_first {
+            true => format!(r#"{{{},"value":{}}}"#, context, value),
+            false => format!(r#"{{"value":{},{}}}"#, value, context),
+        };
+        let body = format!(r#"{{"jsonrpc":"2.0","result":{},"id":7}}"#, result);
+        assert!(body.len() > FULL_PARSE_LIMIT);
+        body
+    }
+
+    #[test]
+    fn small_bodies_are_parsed_in_full() {
+        let body = br#"{"jsonrpc":"2.0","result":{"context":{"slot":5},"value":1},"id":1}"#;
+        let inspected = inspect(body);
+        assert!(inspected.complete);
+        assert_eq!(inspected.json.unwrap()["result"]["value"], 1);
+        assert!(inspect(b"<html>").json.is_none());
+    }
+
+    #[test]
+    fn large_bodies_keep_their_context_only() {
+        for context_first in [true, false] {
+            let inspected = inspect(large(context_first).as_bytes());
+            assert!(!inspected.complete);
+            let json = inspected.json.unwrap();
+            assert_eq!(json["result"]["context"]["slot"], 300000123);
+            assert_eq!(json["result"].get("value"), None);
+            assert_eq!(json["jsonrpc"], "2.0");
+            // the scan ends at the context, before the id
+            assert_eq!(json.get("id"), None);
+        }
+    }
+
+    #[test]
+    fn the_scan_stops_at_the_context() {
+        // whatever follows the context is never read
+        let mut body = large(true);
+        body.truncate(FULL_PARSE_LIMIT + 1);
+        let json = inspect(body.as_bytes()).json.unwrap();
+        assert_eq!(json["result"]["context"]["slot"], 300000123);
+        // without a context first the rest must be valid
+        let mut body = large(false);
+        body.truncate(body.len() - 10);
+        assert!(inspect(body.as_bytes()).json.is_none());
+    }
+
+    #[test]
+    fn large_errors_and_plain_results_are_kept() {
+        let padding = "x".repeat(FULL_PARSE_LIMIT);
+        let error = format!(
+            r#"{{"jsonrpc":"2.0","error":{{"code":-32005,"message":"{}"}},"id":1}}"#,
+            padding
+        );
+        let json = inspect(error.as_bytes()).json.unwrap();
+        assert_eq!(json["error"]["code"], -32005);
+        let string = format!(r#"{{"jsonrpc":"2.0","result":"{}","id":1}}"#, padding);
+        assert_eq!(inspect(string.as_bytes()).json.unwrap()["result"], "");
+        let array = format!(
+            r#"{{"result":[{}1],"id":1}}"#,
+            "1,".repeat(FULL_PARSE_LIMIT)
+        );
+        assert_eq!(inspect(array.as_bytes()).json.unwrap()["result"], json!([]));
+        let trailing = format!(r#"{{"result":1,"id":1}} {}"#, padding);
+        assert!(inspect(trailing.as_bytes()).json.is_none());
+    }
+
+    #[test]
+    fn large_batches_keep_each_calls_context() {
+        let answer = |id: u64, context_first: bool| {
+            let mut answer: Value = serde_json::from_str(&large(context_first)).unwrap();
+            answer["id"] = json!(id);
+            answer
+        };
+        let error =
+            json!({ "jsonrpc": "2.0", "error": { "code": -32005, "message": "behind" }, "id": 3 });
+        let body = Value::Array(vec![answer(1, false), answer(2, true), error]).to_string();
+        let inspected = inspect(body.as_bytes());
+        assert!(!inspected.complete);
+        let json = inspected.json.unwrap();
+        let calls = json.as_array().unwrap();
+        assert_eq!(calls.len(), 3);
+        for (index, call) in calls[..2].iter().enumerate() {
+            assert_eq!(call["id"], index + 1);
+            assert_eq!(call["result"]["context"]["slot"], 300000123);
+            assert_eq!(call["result"].get("value"), None);
+        }
+        assert_eq!(calls[2]["error"]["code"], -32005);
+        assert_eq!(
+            crate::classify::classify_response(200, body.as_bytes(), Some(&json)),
+            None
+        );
+        // a batch is read to its end
+        assert!(inspect(&body.as_bytes()[..body.len() - 1]).json.is_none());
+    }
+
+    #[test]
+    fn a_20mb_body_is_not_parsed_in_full() {
+        let account = json!({
+            "pubkey": "Vote111111111111111111111111111111111111111",
+            "account": { "data": ["A".repeat(1000), "base64"], "lamports": 1 },
+        });
+        let value = Value::Array(vec![account; 20 * 1024]).to_string();
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","result":{{"context":{{"slot":300000123}},"value":{}}},"id":1}}"#,
+            value
+        );
+        assert!(body.len() > 20 * 1024 * 1024);
+        let started = std::time::Instant::now();
+        let full: Value = serde_json::from_slice(body.as_bytes()).unwrap();
+        let parsing = started.elapsed();
+        let started = std::time::Instant::now();
+        let json = inspect(body.as_bytes()).json.unwrap();
+        let inspecting = started.elapsed();
+        assert_eq!(json["result"]["context"], full["result"]["context"]);
+        println!(
+            "20 MB body: parsed in {:?}, inspected in {:?}",
+            parsing, inspecting
+        );
+        assert!(
+            inspecting * 10 < parsing,
+            "{:?} against {:?}",
+            inspecting,
+            parsing
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Local answers to `getEpochInfo` and `getLeaderSchedule`, which monitoring
+//! asks for constantly and which only change at epoch boundaries. A
+//! background task refreshes the epoch info from the freshest upstream and
+//! the leader schedule when the epoch rolls over; in between, served epoch
+//! info follows the slot tracker.
+
+use crate::config::EpochCacheConfig;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Methods the cache can answer.
+pub const METHODS: [&str; 2] = ["getEpochInfo", "getLeaderSchedule"];
+
+struct EpochInfo {
+    /// The `getEpochInfo` result as the upstream returned it.
+    raw: Value,
+    epoch: u64,
+    absolute_slot: u64,
+    slot_index: u64,
+    slots_in_epoch: u64,
+    fetched: Instant,
+}
+
+impl EpochInfo {
+    fn parse(raw: Value) -> Option<Self> {
+        let field = |key: &str| raw.get(key)?.as_u64();
+        Some(Self {
+            epoch: field("epoch")?,
+            absolute_slot: field("absoluteSlot")?,
+            slot_index: field("slotIndex")?,
+            slots_in_epoch: field("slotsInEpoch")?.max(1),
+            fetched: Instant::now(),
+            raw,
+        })
+    }
+
+    fn first_slot(&self) -> u64 {
+        self.absolute_slot - self.slot_index
+    }
+}
+
+struct LeaderSchedule {
+    epoch: u64,
+    /// Leader identities to the slot indices they lead.
+    result: serde_json::Map<String, Value>,
+    /// `result` serialized once, as full schedules are large.
+    serialized: String,
+}
+
+#[derive(Default)]
+pub struct EpochCache {
+    info: RwLock<Option<Arc<EpochInfo>>>,
+    schedule: RwLock<Option<Arc<LeaderSchedule>>>,
+    /// Wakes the refresh task early when the served slot crosses the end of
+    /// the cached epoch.
+    rollover: Notify,
+    hits: [AtomicU64; 2],
+    misses: [AtomicU64; 2],
+    refresh_failures: AtomicU64,
+}
+
+impl EpochCache {
+    fn count(&self, method: usize, hit: bool) {
+        let counters = if hit { &self.hits } else { &self.misses };
+        counters[method].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self, method: usize) -> u64 {
+        self.hits[method].load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self, method: usize) -> u64 {
+        self.misses[method].load(Ordering::Relaxed)
+    }
+
+    pub fn refresh_failures(&self) -> u64 {
+        self.refresh_failures.load(Ordering::Relaxed)
+    }
+
+    /// Epoch of the cached epoch info, for `/metrics`.
+    pub fn epoch(&self) -> Option<u64> {
+        Some(self.info.read().unwrap().as_ref()?.epoch)
+    }
+}
+
+/// Slot and slot index of the cached epoch info moved forward to the slot
+/// estimate, or `None` once that is past the cached epoch, which then needs
+/// a refresh first.
+fn advanced(config: &ServerConfig, info: &EpochInfo) -> Option<(u64, u64)> {
+    let slot = crate::slots::tip(config)
+        .unwrap_or_default()
+        .max(info.absolute_slot);
+    let slot_index = info.slot_index + (slot - info.absolute_slot);
+    if slot_index >= info.slots_in_epoch {
+        config.epochs.rollover.notify_one();
+        return None;
+    }
+    Some((slot, slot_index))
+}
+
+fn epoch_info(config: &ServerConfig, settings: &EpochCacheConfig, params: &Value) -> Option<Value> {
+    if !matches!(params, Value::Null) && params.as_array().is_none_or(|p| !p.is_empty()) {
+        return None;
+    }
+    let info = config.epochs.info.read().unwrap().clone()?;
+    if info.fetched.elapsed() > settings.max_age {
+        return None;
+    }
+    let (slot, slot_index) = advanced(config, &info)?;
+    let mut result = info.raw.clone();
+    result["absoluteSlot"] = slot.into();
+    result["slotIndex"] = slot_index.into();
+    Some(result)
+}
+
+/// The cached schedule, serialized, when `params` ask for the cached epoch
+/// and at most filter it by identity.
+fn leader_schedule(config: &ServerConfig, params: &Value) -> Option<String> {
+    let (slot, options) = match params {
+        Value::Null => (&Value::Null, &Value::Null),
+        Value::Array(params) => match params.as_slice() {
+            [] => (&Value::Null, &Value::Null),
+            [options @ Value::Object(_)] => (&Value::Null, options),
+            [slot] => (slot, &Value::Null),
+            [slot, options] => (slot, options),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let identity = match options {
+        Value::Null => None,
+        Value::Object(options) if options.keys().all(|key| key == "identity") => {
+            Some(options.get("identity")?.as_str()?)
+        }
+        _ => return None,
+    };
+    let info = config.epochs.info.read().unwrap().clone()?;
+    let schedule = config.epochs.schedule.read().unwrap().clone()?;
+    if schedule.epoch != info.epoch {
+        return None;
+    }
+    // a slot of another epoch asks for another schedule
+    let (current, _) = advanced(config, &info)?;
+    let slot = match slot {
+        Value::Null => current,
+        slot => slot.as_u64()?,
+    };
+    if !(info.first_slot()..info.first_slot() + info.slots_in_epoch).contains(&slot) {
+        return None;
+    }
+    match identity {
+        None => Some(schedule.serialized.clone()),
+        Some(identity) => {
+            let filtered: serde_json::Map<String, Value> = schedule
+                .result
+                .get(identity)
+                .map(|slots| (identity.to_string(), slots.clone()))
+                .into_iter()
+                .collect();
+            Some(Value::Object(filtered).to_string())
+        }
+    }
+}
+
+/// A local answer to `body` when it is a single call the cache can serve.
+pub fn answer(config: &ServerConfig, method: &str, body: &[u8]) -> Option<String> {
+    let settings = config.settings.epoch_cache.as_ref()?;
+    let index = METHODS.iter().position(|m| *m == method)?;
+    let request: Value = serde_json::from_slice(body).ok()?;
+    if !request.is_object() {
+        return None;
+    }
+    let params = request.get("params").unwrap_or(&Value::Null);
+    let result = match index {
+        0 => epoch_info(config, settings, params).map(|result| result.to_string()),
+        _ => leader_schedule(config, params),
+    };
+    config.epochs.count(index, result.is_some());
+    // the result is spliced in to avoid re-serializing a full schedule
+    let id = request.get("id").unwrap_or(&Value::Null);
+    Some(format!(
+        r#"{{"jsonrpc":"2.0","result":{},"id":{}}}"#,
+        result?, id
+    ))
+}
+
+/// Send `method` with `params` to upstream `index`, returning the result.
+async fn call(
+    config: &ServerConfig,
+    index: usize,
+    method: &str,
+    params: Value,
+) -> Result<Value, String> {
+    let upstream = &config.servers[index];
+    if !upstream.take_token() {
+        return Err(format!(
+            "{} on {}: at its outbound rate limit",
+            method, upstream.name
+        ));
+    }
+    crate::costs::charge(config, index, [method]);
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let response = upstream
+        .post()
+        .json(&body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("{} on {}: {}", method, upstream.name, e.without_url()))?;
+    let mut answer: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("{} on {}: {}", method, upstream.name, e.without_url()))?;
+    match answer.get_mut("result").map(Value::take) {
+        Some(result) if !result.is_null() => Ok(result),
+        _ => Err(format!(
+            "{} on {}: no result in {}",
+            method, upstream.name, answer["error"]
+        )),
+    }
+}
+
+/// The regular upstream with the highest fresh slot that is not
+/// quarantined.
+async fn freshest(config: &ServerConfig) -> Option<usize> {
+    let quarantine = config.quarantine.read().await;
+    let fresh = config.slots.fresh();
+    config
+        .servers
+        .iter()
+        .filter(|upstream| !upstream.is_last_resort() && !upstream.is_quarantined(&quarantine))
+        .max_by_key(|upstream| fresh[upstream.index].map(|o| o.slot))
+        .map(|upstream| upstream.index)
+}
+
+async fn refresh_once(config: &ServerConfig) -> Result<(), String> {
+    let index = freshest(config)
+        .await
+        .ok_or("every upstream is quarantined")?;
+    let raw = call(config, index, "getEpochInfo", json!([])).await?;
+    let info = EpochInfo::parse(raw).ok_or("getEpochInfo answer without epoch fields")?;
+    let cached = config.epochs.schedule.read().unwrap().clone();
+    let info = Arc::new(info);
+    if cached.is_none_or(|schedule| schedule.epoch != info.epoch) {
+        // an epoch not yet in the cache: serve neither half until both are
+        // current, so a new epoch is never paired with the old schedule
+        *config.epochs.info.write().unwrap() = None;
+        let slot = json!([info.first_slot()]);
+        let schedule = call(config, index, "getLeaderSchedule", slot).await?;
+        let Value::Object(result) = schedule else {
+            return Err("getLeaderSchedule answer is not an object".to_string());
+        };
+        let serialized = Value::Object(result.clone()).to_string();
+        println!(
+            "+ Cached the leader schedule of epoch {} from {} ({} leaders)",
+            info.epoch,
+            config.servers[index].name,
+            result.len()
+        );
+        config
+            .events
+            .record(format!("epoch cache: epoch {}", info.epoch));
+        *config.epochs.schedule.write().unwrap() = Some(Arc::new(LeaderSchedule {
+            epoch: info.epoch,
+            result,
+            serialized,
+        }));
+    }
+    *config.epochs.info.write().unwrap() = Some(info);
+    Ok(())
+}
+
+/// Refresh the cache every `refresh_interval`, and as soon as the served
+/// slot leaves the cached epoch. Failures are logged when they start and
+/// end, and counted.
+pub async fn refresh(config: Arc<ServerConfig>, settings: EpochCacheConfig) {
+    let mut ticker = tokio::time::interval(settings.refresh_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = config.epochs.rollover.notified() => {}
+        }
+        let result = refresh_once(&config).await;
+        // near a rollover, requests keep asking until an upstream is in
+        // the new epoch; one refresh a second is plenty
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        match result {
+            Ok(()) if failing => {
+                failing = false;
+                println!("+ Epoch cache refreshed again");
+            }
+            Ok(()) => {}
+            Err(err) => {
+                config
+                    .epochs
+                    .refresh_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                if !failing {
+                    failing = true;
+                    println!(
+                        "+ Epoch cache refresh failed, passing requests through: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::slots::SlotSource;
+
+    fn request(method: &str, params: Value) -> Vec<u8> {
+        json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params })
+            .to_string()
+            .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn answers_come_from_the_cache_within_the_epoch() {
+        let upstream = crate::testing::upstream(Behavior {
+            slots_per_epoch: 1000,
+            ..Behavior::default()
+        })
+        .await;
+        let config = crate::testing::config(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n\n[epoch_cache]\nenabled = true\n",
+            upstream
+        ));
+        let info = request("getEpochInfo", json!([]));
+        // nothing cached yet, the request passes through
+        assert_eq!(answer(&config, "getEpochInfo", &info), None);
+        assert_eq!(config.epochs.misses(0), 1);
+
+        refresh_once(&config).await.unwrap();
+        let cached = config.epochs.info.read().unwrap().clone().unwrap();
+        let answered: Value =
+            serde_json::from_str(&answer(&config, "getEpochInfo", &info).unwrap()).unwrap();
+        assert_eq!(answered["id"], 7);
+        assert_eq!(answered["result"]["epoch"], cached.epoch);
+        assert_eq!(config.epochs.hits(0), 1);
+        assert_eq!(config.epochs.epoch(), Some(cached.epoch));
+
+        // the served slot follows the tracker between refreshes
+        let first = cached.first_slot();
+        config.slots.observe(0, first + 990, SlotSource::Poll);
+        let answered: Value =
+            serde_json::from_str(&answer(&config, "getEpochInfo", &info).unwrap()).unwrap();
+        assert_eq!(answered["result"]["absoluteSlot"], first + 990);
+        assert_eq!(answered["result"]["slotIndex"], 990);
+
+        let schedule = |params| {
+            answer(
+                &config,
+                "getLeaderSchedule",
+                &request("getLeaderSchedule", params),
+            )
+        };
+        let full: Value = serde_json::from_str(&schedule(json!([])).unwrap()).unwrap();
+        assert_eq!(full["result"].as_object().unwrap().len(), 2);
+        let leader = "MockLeader1111111111111111111111111111111111";
+        let filtered: Value =
+            serde_json::from_str(&schedule(json!([null, { "identity": leader }])).unwrap())
+                .unwrap();
+        assert_eq!(filtered["result"].as_object().unwrap().len(), 1);
+        assert_eq!(filtered["result"][leader], full["result"][leader]);
+        // another epoch's schedule, or other options, pass through
+        assert_eq!(schedule(json!([first + 1000])), None);
+        assert_eq!(schedule(json!([null, { "commitment": "finalized" }])), None);
+
+        // past the end of the epoch neither is served until a refresh
+        config.slots.observe(0, first + 1000, SlotSource::Poll);
+        assert_eq!(answer(&config, "getEpochInfo", &info), None);
+        assert_eq!(schedule(json!([])), None);
+    }
+
+    #[tokio::test]
+    async fn refresh_failures_leave_requests_to_the_upstreams() {
+        let config = crate::testing::config(
+            "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n\n[epoch_cache]\nenabled = true\n",
+        );
+        assert!(refresh_once(&config).await.is_err());
+        let info = request("getEpochInfo", json!([]));
+        assert_eq!(answer(&config, "getEpochInfo", &info), None);
+        assert_eq!(config.epochs.misses(0), 1);
+        // with the cache disabled nothing is counted
+        let config = crate::testing::config(
+            "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n\n[epoch_cache]\nenabled = false\n",
+        );
+        assert_eq!(answer(&config, "getEpochInfo", &info), None);
+        assert_eq!(config.epochs.misses(0), 0);
+    }
+}
@@ -0,0 +1,28 @@
+//! Ring buffer of recent notable events (quarantine changes, reloads,
+//! alerts) kept for the SIGUSR1 state dump.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Events kept; older ones are dropped first.
+const CAPACITY: usize = 100;
+
+#[derive(Default)]
+pub struct EventLog {
+    events: Mutex<VecDeque<(u64, String)>>,
+}
+
+impl EventLog {
+    pub fn record(&self, event: impl Into<String>) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == CAPACITY {
+            events.pop_front();
+        }
+        events.push_back((crate::clock::unix_ms(), event.into()));
+    }
+
+    /// Copy of the events, oldest first, with their Unix milliseconds.
+    pub fn recent(&self) -> Vec<(u64, String)> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,352 @@
+//! When traffic returns to the regular upstreams after only last-resort ones
+//! were left, see `[failback]`. Going back the moment a regular upstream is
+//! available again flaps when it is marginal, so outside the `immediate`
+//! mode the regular upstreams must first stay available for a stabilization
+//! period, or be confirmed by an admin in the `manual` mode, and then get a
+//! share of the requests growing to all of them over the ramp. A fallback
+//! shortly after a failback doubles the next stabilization period. The
+//! phase is moved along by the requests routed, as the tier they need is
+//! only known then.
+
+use crate::config::{FailbackConfig, FailbackMode};
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+enum Phase {
+    /// Served by the regular upstreams.
+    Regular,
+    /// No regular upstream is available.
+    Fallback,
+    /// Regular upstreams available again since then, not yet sent traffic.
+    Stabilizing(Instant),
+    /// Regular upstreams available again, held until an admin confirms.
+    Confirming,
+    /// Sending the regular upstreams a growing share since then.
+    Ramping(Instant),
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Regular => "regular",
+            Phase::Fallback => "fallback",
+            Phase::Stabilizing(_) => "stabilizing",
+            Phase::Confirming => "awaiting_confirmation",
+            Phase::Ramping(_) => "ramping",
+        }
+    }
+}
+
+struct Progress {
+    phase: Phase,
+    /// Failbacks in a row that did not hold, each doubling the
+    /// stabilization period.
+    damping: u32,
+    /// When traffic last started shifting back.
+    failed_back: Option<Instant>,
+}
+
+pub struct Failback {
+    /// Whether there are last-resort upstreams to fall back on at all.
+    tiered: bool,
+    state: Mutex<Progress>,
+    pub fallbacks: AtomicU64,
+    pub failbacks: AtomicU64,
+}
+
+impl Failback {
+    pub fn new(tiered: bool) -> Self {
+        Self {
+            tiered,
+            state: Mutex::new(Progress {
+                phase: Phase::Regular,
+                damping: 0,
+                failed_back: None,
+            }),
+            fallbacks: AtomicU64::new(0),
+            failbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// The phase and stabilization period now, for `/status`.
+    pub fn report(&self, settings: &FailbackConfig) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "mode": mode(settings.mode),
+            "phase": state.phase.name(),
+            "stabilize_secs": stabilization(settings, state.damping).as_secs(),
+        })
+    }
+}
+
+pub fn mode(mode: FailbackMode) -> &'static str {
+    match mode {
+        FailbackMode::Immediate => "immediate",
+        FailbackMode::Auto => "auto",
+        FailbackMode::Manual => "manual",
+    }
+}
+
+fn stabilization(settings: &FailbackConfig, damping: u32) -> Duration {
+    let factor = 1u32.checked_shl(damping).unwrap_or(u32::MAX);
+    settings
+        .stabilize
+        .saturating_mul(factor)
+        .min(settings.max_stabilize)
+}
+
+fn event(config: &ServerConfig, msg: String) {
+    println!("+ {}", msg);
+    config.events.record(msg);
+}
+
+/// Start shifting traffic back, or finish right away without a ramp.
+fn ramp(config: &ServerConfig, state: &mut Progress, now: Instant) {
+    state.failed_back = Some(now);
+    let ramp = config.settings.failback.ramp;
+    if ramp.is_zero() {
+        finish(config, state);
+        return;
+    }
+    state.phase = Phase::Ramping(now);
+    event(
+        config,
+        format!(
+            "Failing back to regular upstreams, shifting traffic over {:?}",
+            ramp
+        ),
+    );
+}
+
+fn finish(config: &ServerConfig, state: &mut Progress) {
+    state.phase = Phase::Regular;
+    config.failback.failbacks.fetch_add(1, Ordering::Relaxed);
+    event(config, "Failed back to regular upstreams".to_string());
+}
+
+/// Move the phase along for a request that has `regular` upstreams
+/// available or not, returning whether it should still go to a last
+/// resort although it has.
+pub fn holds(config: &ServerConfig, regular: bool) -> bool {
+    let failback = &config.failback;
+    if !failback.tiered {
+        return false;
+    }
+    let settings = &config.settings.failback;
+    let mut state = failback.state.lock().unwrap();
+    let now = Instant::now();
+    if !regular {
+        let previous = std::mem::replace(&mut state.phase, Phase::Fallback);
+        if matches!(previous, Phase::Regular | Phase::Ramping(_)) {
+            failback.fallbacks.fetch_add(1, Ordering::Relaxed);
+            let shortly = state
+                .failed_back
+                .is_some_and(|at| now.duration_since(at) < settings.damping_window);
+            match shortly && settings.mode != FailbackMode::Immediate {
+                true => {
+                    state.damping = state.damping.saturating_add(1);
+                    let msg = format!(
+                        "Regular upstreams failed again {:?} after failing back, the next failback waits {:?}",
+                        now.duration_since(state.failed_back.unwrap_or(now)),
+                        stabilization(settings, state.damping)
+                    );
+                    event(config, msg);
+                }
+                false => state.damping = 0,
+            }
+        }
+        return false;
+    }
+    match state.phase {
+        Phase::Regular => false,
+        Phase::Fallback => {
+            match settings.mode {
+                FailbackMode::Immediate => {
+                    // logged as the recovery itself
+                    state.phase = Phase::Regular;
+                    failback.failbacks.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                FailbackMode::Auto => state.phase = Phase::Stabilizing(now),
+                FailbackMode::Manual => state.phase = Phase::Confirming,
+            }
+            true
+        }
+        Phase::Stabilizing(since) => {
+            if now.duration_since(since) < stabilization(settings, state.damping) {
+                return true;
+            }
+            ramp(config, &mut state, now);
+            matches!(state.phase, Phase::Ramping(_))
+        }
+        Phase::Confirming => true,
+        Phase::Ramping(since) => {
+            let share = now.duration_since(since).as_secs_f64() / settings.ramp.as_secs_f64();
+            if share >= 1.0 {
+                finish(config, &mut state);
+                return false;
+            }
+            !crate::random::chance(share)
+        }
+    }
+}
+
+/// What the log says when regular upstreams are available again.
+pub fn recovered(config: &ServerConfig) -> String {
+    let settings = &config.settings.failback;
+    match settings.mode {
+        FailbackMode::Immediate => {
+            "regular upstreams are available again, last-resort upstreams unused".to_string()
+        }
+        FailbackMode::Auto => {
+            let damping = config.failback.state.lock().unwrap().damping;
+            format!(
+                "regular upstreams are available again, failing back once they stay so for {:?}",
+                stabilization(settings, damping)
+            )
+        }
+        FailbackMode::Manual => "regular upstreams are available again, holding on last-resort \
+            upstreams until an admin confirms the failback"
+            .to_string(),
+    }
+}
+
+/// `POST /admin/failback`: shift traffic back to the regular upstreams now,
+/// without waiting for the rest of the stabilization period.
+pub async fn confirm_handler(State(config): State<Arc<ServerConfig>>) -> Response<Body> {
+    let mut state = config.failback.state.lock().unwrap();
+    if !matches!(state.phase, Phase::Confirming | Phase::Stabilizing(_)) {
+        let phase = state.phase.name();
+        return crate::rpc::error_response(
+            StatusCode::CONFLICT,
+            crate::rpc::INVALID_REQUEST,
+            "no failback is waiting, regular upstreams must be available again first",
+            Some(json!({ "phase": phase })),
+        );
+    }
+    event(&config, "Failback confirmed by an admin".to_string());
+    ramp(&config, &mut state, Instant::now());
+    let phase = state.phase.name();
+    Json(json!({ "phase": phase })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::config;
+
+    /// A regular upstream and a last-resort one, failing back as `failback`
+    /// says.
+    fn tiered(failback: &str) -> Arc<ServerConfig> {
+        config(&format!(
+            "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[failback]\n{}[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n[[upstreams]]\nurl = \"http://127.0.0.1:2\"\nrole = \"last_resort\"\n",
+            failback
+        ))
+    }
+
+    fn phase(config: &ServerConfig) -> &'static str {
+        config.failback.state.lock().unwrap().phase.name()
+    }
+
+    /// Pretend the current phase started `ago`.
+    fn backdate(config: &ServerConfig, ago: Duration) {
+        let since = Instant::now().checked_sub(ago).unwrap();
+        let mut state = config.failback.state.lock().unwrap();
+        state.phase = match state.phase {
+            Phase::Stabilizing(_) => Phase::Stabilizing(since),
+            Phase::Ramping(_) => Phase::Ramping(since),
+            phase => phase,
+        };
+    }
+
+    async fn confirm(config: &Arc<ServerConfig>) -> (u16, Value) {
+        let response = confirm_handler(State(config.clone())).await;
+        let status = response.status().as_u16();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn without_last_resorts_nothing_is_held() {
+        let config =
+            config("[failback]\nmode = \"manual\"\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n");
+        assert!(!holds(&config, false));
+        assert!(!holds(&config, true));
+        assert_eq!(config.failback.fallbacks.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn immediate_failbacks_happen_at_once() {
+        let config = tiered("");
+        assert!(!holds(&config, false));
+        assert_eq!(phase(&config), "fallback");
+        assert!(!holds(&config, true));
+        assert_eq!(phase(&config), "regular");
+        assert_eq!(config.failback.fallbacks.load(Ordering::Relaxed), 1);
+        assert_eq!(config.failback.failbacks.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn auto_failbacks_stabilize_then_ramp() {
+        let config = tiered("mode = \"auto\"\nstabilize_secs = 10\nramp_secs = 20\n");
+        holds(&config, false);
+        assert!(holds(&config, true));
+        assert_eq!(phase(&config), "stabilizing");
+        backdate(&config, Duration::from_secs(5));
+        assert!(holds(&config, true));
+        backdate(&config, Duration::from_secs(11));
+        assert!(holds(&config, true));
+        assert_eq!(phase(&config), "ramping");
+        backdate(&config, Duration::from_secs(21));
+        assert!(!holds(&config, true));
+        assert_eq!(phase(&config), "regular");
+        assert_eq!(config.failback.failbacks.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn falling_back_shortly_after_doubles_the_stabilization() {
+        let config = tiered(
+            "mode = \"auto\"\nstabilize_secs = 10\nmax_stabilize_secs = 15\nramp_secs = 0\n",
+        );
+        let stabilize = |config: &ServerConfig| {
+            config.failback.report(&config.settings.failback)["stabilize_secs"].clone()
+        };
+        holds(&config, false);
+        holds(&config, true);
+        backdate(&config, Duration::from_secs(11));
+        // without a ramp the failback is over at once
+        assert!(!holds(&config, true));
+        assert_eq!(phase(&config), "regular");
+        holds(&config, false);
+        assert_eq!(stabilize(&config), 15);
+        assert_eq!(config.failback.fallbacks.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn manual_failbacks_wait_for_an_admin() {
+        let config = tiered("mode = \"manual\"\nramp_secs = 20\n");
+        let (status, body) = confirm(&config).await;
+        assert_eq!(status, 409);
+        assert_eq!(body["error"]["data"]["phase"], "regular");
+        holds(&config, false);
+        assert!(holds(&config, true));
+        assert!(holds(&config, true));
+        assert_eq!(phase(&config), "awaiting_confirmation");
+        let (status, body) = confirm(&config).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["phase"], "ramping");
+    }
+}
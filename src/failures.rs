@@ -0,0 +1,196 @@
+//! What went wrong with each upstream asked, for the errors the proxy
+//! answers when none of them did. The report names upstreams and tells how
+//! they fail, so clients only get it in `error.data` with debug headers on;
+//! the log always has it. Upstream body snippets are cut short and have
+//! their URL and anything looking like a credential removed.
+
+use crate::upstream::Upstream;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Characters of an upstream's error body kept.
+const SNIPPET_LEN: usize = 200;
+/// Parameters whose values are never shown.
+const SECRETS: [&str; 5] = ["key=", "token=", "secret=", "password=", "auth="];
+
+struct Failure {
+    upstream: String,
+    /// `ErrorClass` name, `quarantined`, `rate_limited` or `timeout`.
+    class: String,
+    elapsed: Duration,
+    status: Option<u16>,
+    code: Option<i64>,
+    snippet: Option<String>,
+}
+
+#[derive(Default)]
+pub struct FailureReport {
+    failures: Vec<Failure>,
+}
+
+/// `body` cut to `SNIPPET_LEN` characters, with `url` replaced by `name`
+/// and the values of secret-looking parameters redacted.
+fn snippet(body: &[u8], url: &str, name: &str) -> String {
+    let text = String::from_utf8_lossy(body).replace(url, name);
+    let mut text: String = text.chars().take(SNIPPET_LEN).collect();
+    for secret in SECRETS {
+        let mut from = 0;
+        while let Some(found) = text[from..].to_ascii_lowercase().find(secret) {
+            let start = from + found + secret.len();
+            let end = text[start..]
+                .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+                .map_or(text.len(), |end| start + end);
+            text.replace_range(start..end, "[redacted]");
+            from = start + "[redacted]".len();
+        }
+    }
+    text
+}
+
+impl FailureReport {
+    /// A request that got no HTTP answer, or whose body could not be read.
+    pub fn transport(&mut self, upstream: &str, class: &str, elapsed: Duration) {
+        self.failures.push(Failure {
+            upstream: upstream.to_string(),
+            class: class.to_string(),
+            elapsed,
+            status: None,
+            code: None,
+            snippet: None,
+        });
+    }
+
+    /// An answer classified as an error, with its JSON-RPC error code.
+    pub fn answer(
+        &mut self,
+        upstream: &Upstream,
+        class: &str,
+        elapsed: Duration,
+        status: u16,
+        body: &[u8],
+        code: Option<i64>,
+    ) {
+        self.failures.push(Failure {
+            upstream: upstream.name.clone(),
+            class: class.to_string(),
+            elapsed,
+            status: Some(status),
+            code,
+            snippet: Some(snippet(body, &upstream.url, &upstream.name)),
+        });
+    }
+
+    /// Upstreams in `names` that were not heard from, as timed out.
+    pub fn pending<'a>(&mut self, names: impl IntoIterator<Item = &'a str>, elapsed: Duration) {
+        for name in names {
+            if !self.failures.iter().any(|f| f.upstream == name) {
+                self.transport(name, "timeout", elapsed);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The `error.data` of the proxy's error.
+    pub fn data(&self, strategy: &str) -> Value {
+        let attempts: Vec<Value> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                json!({
+                    "upstream": failure.upstream,
+                    "class": failure.class,
+                    "elapsed_ms": failure.elapsed.as_millis() as u64,
+                    "status": failure.status,
+                    "code": failure.code,
+                    "body": failure.snippet,
+                })
+            })
+            .collect();
+        json!({ "strategy": strategy, "attempts": attempts })
+    }
+
+    /// The failures on one line, for the log.
+    pub fn summary(&self) -> String {
+        let failures: Vec<String> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                let mut line = format!(
+                    "{} {} after {}ms",
+                    failure.upstream,
+                    failure.class,
+                    failure.elapsed.as_millis()
+                );
+                if let Some(code) = failure.code {
+                    line.push_str(&format!(" (code {})", code));
+                }
+                line
+            })
+            .collect();
+        failures.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, serve};
+    use axum::{http::StatusCode, routing::post, Router};
+
+    #[test]
+    fn snippets_are_short_and_redacted() {
+        let body = b"node https://rpc.example.com/?api-key=abc-123 failed, TOKEN=xyz; retry";
+        assert_eq!(
+            snippet(body, "https://rpc.example.com/", "main"),
+            "node main?api-key=[redacted] failed, TOKEN=[redacted]; retry"
+        );
+        let long = "x".repeat(SNIPPET_LEN * 2);
+        assert_eq!(snippet(long.as_bytes(), "", "main").len(), SNIPPET_LEN);
+    }
+
+    #[tokio::test]
+    async fn errors_name_each_failed_upstream_when_debugging() {
+        let failing = serve(Router::new().route(
+            "/",
+            post(|| async {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "overloaded, retry with secret=hunter2",
+                )
+            }),
+        ))
+        .await;
+        let hanging =
+            serve(Router::new().route("/", post(|| tokio::time::sleep(Duration::from_secs(5)))))
+                .await;
+        let upstreams = format!(
+            "[deadlines]\ndefault_ms = 300\n\n[[upstreams]]\nname = \"failing\"\nurl = \"{}\"\n[[upstreams]]\nname = \"closed\"\nurl = \"http://127.0.0.1:1\"\n[[upstreams]]\nname = \"hanging\"\nurl = \"{}\"\n",
+            failing, hanging
+        );
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        let (_, url) = proxy(&format!("debug_headers = true\n{}", upstreams)).await;
+        let body: Value = call(&url, request.clone()).await.json().await.unwrap();
+        let data = &body["error"]["data"];
+        assert_eq!(data["strategy"], "race");
+        assert_eq!(data["deadline_ms"], 300);
+        let mut attempts = data["attempts"].as_array().unwrap().clone();
+        attempts.sort_by_key(|attempt| attempt["upstream"].to_string());
+        let classes: Vec<&Value> = attempts.iter().map(|attempt| &attempt["class"]).collect();
+        assert_eq!(classes, ["connect", "http_5xx", "timeout"]);
+        assert_eq!(attempts[1]["upstream"], "failing");
+        assert_eq!(attempts[1]["status"], 503);
+        assert_eq!(
+            attempts[1]["body"],
+            "overloaded, retry with secret=[redacted]"
+        );
+        assert!(attempts[2]["elapsed_ms"].as_u64().unwrap() >= 300);
+
+        // the topology is not for everyone to see
+        let (_, url) = proxy(&upstreams).await;
+        let body: Value = call(&url, request).await.json().await.unwrap();
+        assert_eq!(body["error"]["data"], json!({ "deadline_ms": 300 }));
+    }
+}
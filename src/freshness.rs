@@ -0,0 +1,190 @@
+//! Strict freshness: an answer whose context slot trails the tracked tip by
+//! more than the slot tolerance is rejected like a failure, and another
+//! upstream's answer is waited for instead. When every answer was stale the
+//! client gets a distinct error carrying the lag. Answers without a context
+//! slot, batches included, pass through.
+
+use crate::config::StrictFreshnessConfig;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+
+/// JSON-RPC error of a request whose every answer was behind the tip.
+pub const STALE: i64 = -32017;
+
+/// Slots below the tip a single call's answer may be at for its
+/// commitment, before the tolerance; `None` for batches and bodies that
+/// are not JSON-RPC calls, which are not checked.
+pub fn allowance(settings: &StrictFreshnessConfig, body: &[u8]) -> Option<u64> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    request.get("method")?;
+    let params = request["params"].as_array().into_iter().flatten();
+    let commitment = params
+        .filter_map(|param| param["commitment"].as_str())
+        .next_back()
+        .unwrap_or("finalized");
+    // unknown commitments are checked as strictly as processed
+    let level = crate::local_slot::COMMITMENTS
+        .iter()
+        .position(|c| *c == commitment)
+        .unwrap_or(0);
+    Some(settings.offsets[level])
+}
+
+/// A context slot behind the tip.
+pub struct Stale {
+    pub slot: u64,
+    pub tip: u64,
+}
+
+impl Stale {
+    pub fn lag(&self) -> u64 {
+        self.tip - self.slot
+    }
+
+    /// `data` of the error answered in its place.
+    pub fn data(&self) -> Value {
+        json!({ "lag_slots": self.lag(), "slot": self.slot, "tip": self.tip })
+    }
+}
+
+/// The context slot of `answer` when it trails the tip by more than
+/// `allowance` and the tolerance.
+pub fn stale(config: &ServerConfig, allowance: u64, answer: &Value) -> Option<Stale> {
+    let slot = answer["result"]["context"]["slot"].as_u64()?;
+    let tip = crate::slots::tip(config)?;
+    (slot + allowance + config.tolerance.slots() < tip).then_some(Stale { slot, tip })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+    use axum::{routing::post, Router};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const STRICT: &str =
+        "[strict_freshness]\nenabled = true\nmethods = [\"getBalance\"]\nfinalized_offset = 32\n";
+
+    /// A node answering every call at context slot `slot`, after `latency`.
+    async fn node(slot: u64, latency: u64) -> String {
+        crate::testing::serve(Router::new().route(
+            "/",
+            post(move || async move {
+                tokio::time::sleep(Duration::from_millis(latency)).await;
+                json!({ "jsonrpc": "2.0", "id": 1, "result": { "context": { "slot": slot }, "value": 7 } })
+                    .to_string()
+            }),
+        ))
+        .await
+    }
+
+    /// A proxy for nodes answering at `slots`, the first the fastest, with
+    /// the tip at 1000.
+    async fn proxy(slots: &[u64]) -> (Arc<ServerConfig>, String) {
+        let mut text = format!("{}[slots]\ntolerance = 5\n", STRICT);
+        for (position, &slot) in slots.iter().enumerate() {
+            let url = node(slot, 50 * position as u64).await;
+            text += &format!("[[upstreams]]\nname = \"at-{}\"\nurl = \"{}\"\n", slot, url);
+        }
+        let (config, url) = crate::testing::proxy(&text).await;
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        (config, url)
+    }
+
+    async fn send(url: &str, method: &str, header: Option<&str>) -> Value {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": ["x", { "commitment": "processed" }] });
+        let mut request = reqwest::Client::new().post(url).json(&body);
+        if let Some(value) = header {
+            request = request.header("x-strict-freshness", value);
+        }
+        request.send().await.unwrap().json().await.unwrap()
+    }
+
+    #[test]
+    fn commitments_are_allowed_their_offsets() {
+        let config = crate::testing::config(&format!(
+            "{}[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n",
+            STRICT
+        ));
+        let settings = config.settings.strict_freshness.as_ref().unwrap();
+        let allowance = |body: Value| allowance(settings, body.to_string().as_bytes());
+        let call = |params: Value| json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": params });
+        assert_eq!(allowance(call(json!(["x"]))), Some(32));
+        assert_eq!(
+            allowance(call(json!(["x", { "commitment": "confirmed" }]))),
+            Some(0)
+        );
+        assert_eq!(
+            allowance(call(json!(["x", { "commitment": "recent" }]))),
+            Some(0)
+        );
+        // batches are not checked
+        assert_eq!(allowance(json!([call(json!(["x"]))])), None);
+    }
+
+    #[tokio::test]
+    async fn a_stale_winner_is_passed_over_for_a_fresh_answer() {
+        let (config, url) = proxy(&[900, 998]).await;
+        let answer = send(&url, "getBalance", None).await;
+        assert_eq!(answer["result"]["context"]["slot"], 998);
+        let stats = &config.servers[0].stats;
+        assert_eq!(stats.stale_rejections(), 1);
+        // counted as a failure in the error rate evidence
+        assert_eq!(stats.window(60), (1, 1));
+        assert_eq!(config.servers[1].stats.stale_rejections(), 0);
+    }
+
+    #[tokio::test]
+    async fn with_every_answer_stale_the_client_is_told_how_far_behind() {
+        let (config, url) = proxy(&[900, 950]).await;
+        let answer = send(&url, "getBalance", None).await;
+        assert_eq!(answer["error"]["code"], STALE);
+        let data = &answer["error"]["data"];
+        // the freshest of them
+        assert_eq!(data["slot"], 950);
+        assert_eq!(data["tip"], 1000);
+        assert_eq!(data["lag_slots"], 50);
+        assert_eq!(config.servers[1].stats.stale_rejections(), 1);
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            metrics.contains("quarantier_upstream_stale_rejections_total{upstream=\"at-900\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_choose_per_request() {
+        // other methods are not checked unless asked
+        let (_, url) = proxy(&[900]).await;
+        let answer = send(&url, "getAccountInfo", None).await;
+        assert_eq!(answer["result"]["context"]["slot"], 900);
+        let (_, url) = proxy(&[900]).await;
+        let answer = send(&url, "getAccountInfo", Some("1")).await;
+        assert_eq!(answer["error"]["code"], STALE);
+        // and listed ones can be let through
+        let (_, url) = proxy(&[900]).await;
+        let answer = send(&url, "getBalance", Some("false")).await;
+        assert_eq!(answer["result"]["context"]["slot"], 900);
+    }
+
+    #[tokio::test]
+    async fn answers_within_the_tolerance_or_without_a_slot_pass() {
+        let (_, url) = proxy(&[995]).await;
+        let answer = send(&url, "getBalance", None).await;
+        assert_eq!(answer["result"]["context"]["slot"], 995);
+        let plain = crate::testing::serve(Router::new().route(
+            "/",
+            post(|| async { r#"{"jsonrpc":"2.0","id":1,"result":7}"# }),
+        ))
+        .await;
+        let (config, url) =
+            crate::testing::proxy(&format!("{}[[upstreams]]\nurl = \"{}\"\n", STRICT, plain)).await;
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        assert_eq!(send(&url, "getBalance", None).await["result"], 7);
+    }
+}
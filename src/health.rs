@@ -0,0 +1,208 @@
+//! Liveness and readiness of the proxy itself, for load balancers and
+//! orchestrators.
+
+use crate::ServerConfig;
+use axum::{extract::State, http::StatusCode, Json};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Readiness verdict, recomputed by the background poller after every round
+/// so it flips without waiting for client traffic.
+#[derive(Default)]
+pub struct Readiness {
+    ready: AtomicBool,
+    healthy: AtomicUsize,
+}
+
+impl Readiness {
+    pub async fn update(&self, config: &ServerConfig) {
+        let (healthy, _) = crate::quarantine::host_counts(config).await;
+        let min_healthy = crate::quarantine::min_healthy(config);
+        let paused = config.pause.is_paused();
+        let ready = healthy >= min_healthy && !paused;
+        self.healthy.store(healthy, Ordering::Relaxed);
+        if self.ready.swap(ready, Ordering::Relaxed) != ready {
+            match paused {
+                true => println!("+ Readiness changed to not ready (traffic paused)"),
+                false => println!(
+                    "+ Readiness changed to {} ({} healthy upstreams, {} required)",
+                    if ready { "ready" } else { "not ready" },
+                    healthy,
+                    min_healthy
+                ),
+            }
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// Solana's `NodeUnhealthy` error code.
+const NODE_UNHEALTHY: i64 = -32005;
+
+/// The id of a single `getHealth` call, or `None` for any other body,
+/// batches included.
+pub fn get_health_id(body: &[u8]) -> Option<Value> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    (request.get("method")? == "getHealth").then(|| request["id"].clone())
+}
+
+/// Answer to `getHealth` in the shape of a validator's: `"ok"` while at
+/// least `min_healthy` upstreams are within the quarantine tolerance,
+/// otherwise `NodeUnhealthy` with the lag of the best upstreams that would
+/// make up `min_healthy`, when enough have a fresh slot estimate.
+pub async fn get_health_answer(config: &ServerConfig, id: &Value) -> String {
+    let (healthy, _) = crate::quarantine::host_counts(config).await;
+    let min_healthy = crate::quarantine::min_healthy(config);
+    if healthy >= min_healthy {
+        return json!({ "jsonrpc": "2.0", "result": "ok", "id": id }).to_string();
+    }
+    // one snapshot for the tip and the lags, or a slot observed in between
+    // could be past the tip
+    let fresh = config.slots.fresh();
+    let latest_slot = crate::slots::tip_of(config, &fresh).unwrap_or_default();
+    let mut lags: Vec<u64> = fresh
+        .iter()
+        .zip(&config.servers)
+        .filter(|(_, upstream)| !upstream.is_last_resort() && !upstream.is_drained())
+        .filter(|(_, upstream)| !upstream.identity.is_alias())
+        .filter(|(_, upstream)| !upstream.is_failing())
+        .filter_map(|(observation, _)| observation.as_ref())
+        .map(|observation| latest_slot.saturating_sub(observation.slot))
+        .collect();
+    lags.sort_unstable();
+    let behind = lags.get(min_healthy.max(1) - 1).copied();
+    let message = match behind {
+        Some(slots) => format!("Node is behind by {} slots", slots),
+        None => "Node is unhealthy".to_string(),
+    };
+    let error = json!({
+        "code": NODE_UNHEALTHY,
+        "message": message,
+        "data": { "numSlotsBehind": behind },
+    });
+    json!({ "jsonrpc": "2.0", "error": error, "id": id }).to_string()
+}
+
+/// `GET /healthz`: answering at all proves the process and listener work.
+pub async fn healthz_handler() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// `GET /readyz`: 200 while at least `min_healthy` upstreams are usable
+/// and traffic is not paused.
+pub async fn readyz_handler(State(config): State<Arc<ServerConfig>>) -> (StatusCode, Json<Value>) {
+    let ready = config.readiness.is_ready();
+    let healthy = config.readiness.healthy.load(Ordering::Relaxed);
+    let min_healthy = crate::quarantine::min_healthy(&config);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let reason = if let Some(by) = config.pause.paused_by() {
+        format!("traffic paused by {}", by)
+    } else if ready {
+        format!("{} of {} required upstreams healthy", healthy, min_healthy)
+    } else {
+        format!(
+            "only {} healthy upstreams, {} required",
+            healthy, min_healthy
+        )
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "healthy_upstreams": healthy,
+            "min_healthy": min_healthy,
+            "reason": reason,
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+    use crate::testing::{call, proxy, upstream};
+
+    /// Proxy state over three upstreams, of which `min_healthy` must be
+    /// within the tolerance.
+    fn config(min_healthy: usize) -> Arc<ServerConfig> {
+        let upstreams: String = ["a", "b", "c"]
+            .iter()
+            .map(|host| format!("[[upstreams]]\nurl = \"http://{}\"\n", host))
+            .collect();
+        crate::testing::config(&format!("min_healthy = {}\n{}", min_healthy, upstreams))
+    }
+
+    async fn answer(config: &ServerConfig) -> Value {
+        serde_json::from_str(&get_health_answer(config, &json!(4)).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn enough_healthy_upstreams_answer_ok() {
+        let config = config(2);
+        config.slots.observe(0, 300, SlotSource::Poll);
+        config.slots.observe(1, 295, SlotSource::Poll);
+        config.slots.observe(2, 100, SlotSource::Poll);
+        assert_eq!(
+            answer(&config).await,
+            json!({ "jsonrpc": "2.0", "result": "ok", "id": 4 })
+        );
+    }
+
+    #[tokio::test]
+    async fn otherwise_the_lag_of_the_best_upstreams_is_reported() {
+        let config = config(2);
+        config.slots.observe(0, 300, SlotSource::Poll);
+        config.slots.observe(1, 250, SlotSource::Poll);
+        config.slots.observe(2, 100, SlotSource::Poll);
+        let answer = answer(&config).await;
+        assert_eq!(answer["id"], 4);
+        assert_eq!(answer["error"]["code"], -32005);
+        assert_eq!(answer["error"]["message"], "Node is behind by 50 slots");
+        assert_eq!(answer["error"]["data"]["numSlotsBehind"], 50);
+    }
+
+    #[tokio::test]
+    async fn too_few_slot_estimates_leave_the_lag_unknown() {
+        let config = config(2);
+        config.slots.observe(0, 300, SlotSource::Poll);
+        let answer = answer(&config).await;
+        assert_eq!(answer["error"]["code"], -32005);
+        assert_eq!(answer["error"]["message"], "Node is unhealthy");
+        assert_eq!(answer["error"]["data"]["numSlotsBehind"], Value::Null);
+    }
+
+    #[test]
+    fn only_single_get_health_calls_are_answered() {
+        let call = br#"{"jsonrpc":"2.0","id":"h","method":"getHealth"}"#;
+        assert_eq!(get_health_id(call), Some(json!("h")));
+        let batch = br#"[{"jsonrpc":"2.0","id":1,"method":"getHealth"}]"#;
+        assert_eq!(get_health_id(batch), None);
+        assert_eq!(get_health_id(br#"{"id":1,"method":"getSlot"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn get_health_passes_through_unless_aggregated() {
+        let node = upstream(crate::mock::Behavior::default()).await;
+        let get_health = json!({ "jsonrpc": "2.0", "id": 1, "method": "getHealth" });
+        // without background polling the proxy knows no slot yet, so its
+        // own answer is unhealthy while the node's is ok
+        let (_, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", node)).await;
+        let answer: Value = call(&url, get_health.clone()).await.json().await.unwrap();
+        assert_eq!(answer["error"]["code"], -32005);
+        let (_, url) = proxy(&format!(
+            "aggregate_get_health = false\n[[upstreams]]\nurl = \"{}\"\n",
+            node
+        ))
+        .await;
+        let answer: Value = call(&url, get_health).await.json().await.unwrap();
+        assert_eq!(answer["result"], "ok");
+    }
+}
@@ -0,0 +1,213 @@
+//! Hedged races, for methods with a `[routing]` hedge delay. The upstream
+//! that answered fastest last, or the best scored with `[ranking] route_by
+//! = "score"`, is asked first and another one is added every
+//! `hedge_ms`, up to `max_hedges` of them, until an answer is accepted; the
+//! rest are not asked at all. A failed request is replaced by the next
+//! upstream right away, whatever the delay, so failing over is no slower
+//! than in a plain race.
+
+use crate::config::HedgePolicy;
+use crate::ServerConfig;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+#[derive(Clone, Copy)]
+struct Turns {
+    /// Attempts allowed to go regardless of their delay, those sent and
+    /// one more for every failure.
+    released: usize,
+    /// An answer was accepted; the attempts still waiting are not sent.
+    done: bool,
+}
+
+pub struct Hedge {
+    policy: HedgePolicy,
+    config: Arc<ServerConfig>,
+    method: String,
+    request_id: String,
+    turns: watch::Sender<Turns>,
+    /// Upstreams sent the request by their delay.
+    hedged: Mutex<Vec<usize>>,
+}
+
+/// Put `targets` in the order they are asked in: fastest last first, and
+/// those not heard from yet last.
+pub fn order(config: &ServerConfig, targets: &mut [usize]) {
+    targets.sort_by_key(|&index| {
+        let latency = config.servers[index].stats.last_latency();
+        (latency.is_none(), latency)
+    });
+}
+
+impl Hedge {
+    pub fn new(
+        policy: HedgePolicy,
+        config: Arc<ServerConfig>,
+        method: &str,
+        request_id: &str,
+    ) -> Arc<Self> {
+        let (turns, _) = watch::channel(Turns {
+            released: 1,
+            done: false,
+        });
+        Arc::new(Self {
+            policy,
+            config,
+            method: method.to_string(),
+            request_id: request_id.to_string(),
+            turns,
+            hedged: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Wait until attempt `position`, to upstream `index`, is to be sent:
+    /// after its delay, or when an earlier one failed. Resolves to `false`
+    /// when an answer was accepted first.
+    pub fn gate(self: &Arc<Self>, position: usize, index: usize) -> impl Future<Output = bool> {
+        let hedge = self.clone();
+        let mut turns = self.turns.subscribe();
+        let delay =
+            (position <= self.policy.max_hedges).then(|| self.policy.delay * position as u32);
+        async move {
+            let timer = async {
+                match delay {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                biased;
+                turn = turns.wait_for(|turn| turn.done || turn.released > position) => {
+                    return turn.is_ok_and(|turn| !turn.done);
+                }
+                _ = timer => {}
+            }
+            if turns.borrow().done {
+                return false;
+            }
+            // a failure lets the attempt after this one go
+            hedge
+                .turns
+                .send_modify(|turn| turn.released = turn.released.max(position + 1));
+            println!(
+                "[{}] + Hedging to {} after {:?}",
+                hedge.request_id,
+                hedge.config.servers[index].name,
+                hedge.policy.delay * position as u32
+            );
+            hedge.config.stats.record_hedge(&hedge.method);
+            hedge.hedged.lock().unwrap().push(index);
+            true
+        }
+    }
+
+    /// Let the next attempt go in place of one that failed.
+    pub fn failed(&self) {
+        self.turns.send_modify(|turns| turns.released += 1);
+    }
+
+    /// Upstream `index` answered: stop sending the request.
+    pub fn finish(&self, index: usize) {
+        self.turns.send_modify(|turns| turns.done = true);
+        if self.hedged.lock().unwrap().contains(&index) {
+            self.config.stats.record_hedge_won(&self.method);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::config;
+    use std::time::{Duration, Instant};
+
+    const THREE: &str = "[[upstreams]]\nname = \"a\"\nurl = \"http://a\"\n[[upstreams]]\nname = \"b\"\nurl = \"http://b\"\n[[upstreams]]\nname = \"c\"\nurl = \"http://c\"\n";
+
+    fn hedge(max_hedges: usize) -> Arc<Hedge> {
+        let policy = HedgePolicy {
+            delay: Duration::from_millis(50),
+            max_hedges,
+        };
+        Hedge::new(policy, config(THREE), "getSlot", "r1")
+    }
+
+    fn counts(counts: &Mutex<std::collections::HashMap<String, u64>>) -> Option<u64> {
+        counts.lock().unwrap().get("getSlot").copied()
+    }
+
+    #[test]
+    fn the_fastest_last_is_asked_first() {
+        let config = config(THREE);
+        config.servers[0]
+            .stats
+            .record_latency(Duration::from_millis(80));
+        config.servers[2]
+            .stats
+            .record_latency(Duration::from_millis(20));
+        let mut targets = vec![0, 1, 2];
+        order(&config, &mut targets);
+        assert_eq!(targets, [2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn upstreams_are_added_by_their_delay() {
+        let hedge = hedge(1);
+        let started = Instant::now();
+        assert!(hedge.gate(0, 0).await);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(hedge.gate(1, 1).await);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(counts(&hedge.config.stats.hedges_fired), Some(1));
+        hedge.finish(1);
+        assert_eq!(counts(&hedge.config.stats.hedges_won), Some(1));
+    }
+
+    #[tokio::test]
+    async fn a_failure_lets_the_next_one_go_at_once() {
+        let hedge = hedge(0);
+        let next = tokio::spawn(hedge.gate(1, 1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!next.is_finished());
+        hedge.failed();
+        assert!(next.await.unwrap());
+        // replacing a failure is not hedging
+        assert_eq!(counts(&hedge.config.stats.hedges_fired), None);
+    }
+
+    #[tokio::test]
+    async fn an_answer_stops_the_attempts_still_waiting() {
+        let hedge = hedge(2);
+        let waiting = tokio::spawn(hedge.gate(1, 1));
+        hedge.finish(0);
+        assert!(!waiting.await.unwrap());
+        assert!(!hedge.gate(2, 2).await);
+        assert_eq!(counts(&hedge.config.stats.hedges_won), None);
+    }
+
+    #[test]
+    fn batches_take_the_most_careful_policy() {
+        let config = config(&format!(
+            "[routing]\nhedge_ms = 20\nmax_hedges = 2\ngetSlot = {{ hedge_ms = 50, max_hedges = 1 }}\ngetProgramAccounts = {{ hedge = false }}\n{}",
+            THREE
+        ));
+        let routing = &config.settings.routing;
+        let methods = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let policy = |delay, max_hedges| {
+            Some(HedgePolicy {
+                delay: Duration::from_millis(delay),
+                max_hedges,
+            })
+        };
+        assert!(routing.hedge(&methods(&["getBalance"])) == policy(20, 2));
+        assert!(routing.hedge(&methods(&["getBalance", "getSlot"])) == policy(50, 1));
+        assert!(routing
+            .hedge(&methods(&["getSlot", "getProgramAccounts"]))
+            .is_none());
+    }
+}
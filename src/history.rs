@@ -0,0 +1,173 @@
+//! Routing of calls about old ledger data to archive upstreams. Nodes with
+//! limited history answer them with "not available" errors or nulls, often
+//! faster than an archive finds the data, and would win the race. Calls are
+//! dated from their parameters; those older than `[history]
+//! retention_slots`, and those that cannot be dated, only go to upstreams
+//! with `archive = true` or found to keep old blocks by `discovery`.
+
+use crate::ServerConfig;
+use serde_json::Value;
+
+/// Age of the data one call asks about.
+#[derive(PartialEq)]
+enum Age {
+    Recent,
+    /// Older than the retention window, or impossible to tell.
+    Historical,
+}
+
+/// Age of a call about `slot`, against the freshest slot estimate.
+fn slot_age(config: &ServerConfig, slot: &Value) -> Age {
+    let (Some(slot), Some(latest)) = (slot.as_u64(), crate::slots::tip(config)) else {
+        return Age::Historical;
+    };
+    if slot + config.settings.retention_slots >= latest {
+        Age::Recent
+    } else {
+        Age::Historical
+    }
+}
+
+fn call_age(config: &ServerConfig, call: &Value) -> Age {
+    let params = &call["params"];
+    match call["method"].as_str().unwrap_or_default() {
+        "getBlock"
+        | "getBlockTime"
+        | "getBlocks"
+        | "getBlocksWithLimit"
+        | "getConfirmedBlock"
+        | "getConfirmedBlocks"
+        | "getConfirmedBlocksWithLimit" => slot_age(config, &params[0]),
+        // a signature does not tell its slot, but one submitted through
+        // the proxy is recent
+        "getTransaction" | "getConfirmedTransaction" => {
+            let tracked = params[0]
+                .as_str()
+                .and_then(crate::base58::decode)
+                .and_then(|bytes| bytes.try_into().ok())
+                .is_some_and(|signature| config.transactions.is_tracked(&signature));
+            if tracked {
+                Age::Recent
+            } else {
+                Age::Historical
+            }
+        }
+        // without a cursor the newest signatures are asked for
+        "getSignaturesForAddress" | "getConfirmedSignaturesForAddress2" => {
+            if params[1]["before"].is_null() {
+                Age::Recent
+            } else {
+                Age::Historical
+            }
+        }
+        _ => Age::Recent,
+    }
+}
+
+/// Whether `body`, a call or a batch, asks about historical data. Always
+/// false when no upstream is an archive.
+pub fn needs_archive(config: &ServerConfig, body: &[u8]) -> bool {
+    if !config.servers.iter().any(|upstream| upstream.is_archive()) {
+        return false;
+    }
+    let Ok(request) = serde_json::from_slice::<Value>(body) else {
+        return false;
+    };
+    let calls = match &request {
+        Value::Array(calls) => calls.as_slice(),
+        call => std::slice::from_ref(call),
+    };
+    calls
+        .iter()
+        .any(|call| call_age(config, call) == Age::Historical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+    use crate::testing::{call, proxy, serve};
+    use axum::{routing::post, Json, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// An upstream answering every call with `result`, or with a "not
+    /// available" error when it has none, after `latency`, and the count
+    /// of calls it got.
+    async fn node(result: Option<Value>, latency: Duration) -> (String, Arc<AtomicUsize>) {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counted = asked.clone();
+        let url = serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(latency).await;
+                Json(match &result {
+                    Some(result) => {
+                        json!({ "jsonrpc": "2.0", "id": request["id"], "result": result })
+                    }
+                    None => json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": -32001, "message": "Block not available for slot 1000" },
+                    }),
+                })
+            }),
+        ))
+        .await;
+        (url, asked)
+    }
+
+    fn get_block(slot: u64) -> Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "getBlock", "params": [slot] })
+    }
+
+    #[tokio::test]
+    async fn old_data_is_only_asked_of_archives() {
+        let (pruned, to_pruned) = node(None, Duration::ZERO).await;
+        let block = json!({ "blockhash": "archived" });
+        let (archive, to_archive) = node(Some(block), Duration::from_millis(100)).await;
+        let (config, url) = proxy(&format!(
+            "[history]\nretention_slots = 1000\n\n[[upstreams]]\nurl = \"{}\"\n\n[[upstreams]]\nurl = \"{}\"\narchive = true\n",
+            pruned, archive
+        ))
+        .await;
+        config.slots.observe(0, 5000, SlotSource::Poll);
+        config.slots.observe(1, 5000, SlotSource::Poll);
+
+        let answer: Value = call(&url, get_block(1000)).await.json().await.unwrap();
+        assert_eq!(answer["result"]["blockhash"], "archived", "{}", answer);
+        assert_eq!(to_pruned.load(Ordering::SeqCst), 0);
+        assert_eq!(to_archive.load(Ordering::SeqCst), 1);
+        // a recent block is asked of both
+        call(&url, get_block(4500)).await;
+        assert_eq!(to_pruned.load(Ordering::SeqCst), 1);
+
+        let dated = |body: Value| needs_archive(&config, body.to_string().as_bytes());
+        assert!(!dated(get_block(4000)));
+        assert!(dated(json!([get_block(4000), get_block(10)])));
+        // undatable calls go to archives too
+        assert!(dated(json!({ "method": "getBlock", "params": ["latest"] })));
+        let signature = crate::base58::encode(&[7; 64]);
+        assert!(dated(
+            json!({ "method": "getTransaction", "params": [signature] })
+        ));
+        let address = "11111111111111111111111111111111";
+        assert!(!dated(
+            json!({ "method": "getSignaturesForAddress", "params": [address] })
+        ));
+        assert!(dated(json!({
+            "method": "getSignaturesForAddress",
+            "params": [address, { "before": signature }],
+        })));
+        assert!(!dated(json!({ "method": "getSlot" })));
+    }
+
+    #[test]
+    fn nothing_needs_an_archive_without_one() {
+        let config = crate::testing::config("[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n");
+        assert!(!needs_archive(&config, get_block(0).to_string().as_bytes()));
+    }
+}
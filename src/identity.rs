@@ -0,0 +1,394 @@
+//! Upstreams that are one node configured twice, such as a provider's CNAME
+//! next to its canonical hostname, found by the node identity `getIdentity`
+//! reports during discovery. Each round asks every upstream several times,
+//! and only an identity all the answers agree on counts, so a provider
+//! fronting many nodes behind one URL is never taken for a duplicate. An
+//! upstream reporting the identity of one configured before it is its
+//! alias: the slot-lag comparison and the health counts count the two as
+//! one, and with `duplicate_upstreams = "alias"` the alias gets no traffic.
+//! Upstreams resolving to the same addresses are only reported, many
+//! providers sharing a front end. Identities are sampled again between the
+//! rounds, as providers swap the nodes behind their URLs.
+
+use crate::config::DuplicatePolicy;
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `getIdentity` calls per upstream and discovery round.
+pub const SAMPLES: usize = 3;
+
+const NONE: usize = usize::MAX;
+
+pub struct Identity {
+    /// What the last round's answers agreed on.
+    node: Mutex<Option<String>>,
+    /// Whether the last round's answers disagreed.
+    varies: AtomicBool,
+    /// Index of the upstream this one is the same node as.
+    alias_of: AtomicUsize,
+    /// Index of an upstream resolving to the same addresses.
+    shares_addresses: AtomicUsize,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            node: Mutex::new(None),
+            varies: AtomicBool::new(false),
+            alias_of: AtomicUsize::new(NONE),
+            shares_addresses: AtomicUsize::new(NONE),
+        }
+    }
+}
+
+impl Identity {
+    /// Record the identities a round's calls got, `None` for no answer,
+    /// and return the node reported before when the answers changed it.
+    /// Samples with calls unanswered that do not disagree tell nothing and
+    /// are ignored.
+    pub fn record(&self, samples: &[Option<String>]) -> Option<Option<String>> {
+        let first = samples.first().cloned().flatten();
+        let agreed = first.filter(|first| samples.iter().all(|s| s.as_ref() == Some(first)));
+        let answered: Vec<&String> = samples.iter().flatten().collect();
+        let varies = answered.windows(2).any(|pair| pair[0] != pair[1]);
+        if agreed.is_none() && !varies {
+            return None;
+        }
+        self.varies.store(varies, Ordering::Relaxed);
+        let previous = std::mem::replace(&mut *self.node.lock().unwrap(), agreed.clone());
+        (previous.is_some() && previous != agreed).then_some(previous)
+    }
+
+    pub fn node(&self) -> Option<String> {
+        self.node.lock().unwrap().clone()
+    }
+
+    pub fn alias_of(&self) -> Option<usize> {
+        match self.alias_of.load(Ordering::Relaxed) {
+            NONE => None,
+            index => Some(index),
+        }
+    }
+
+    /// Whether another upstream speaks for the node, so this one adds no
+    /// vote of its own.
+    pub fn is_alias(&self) -> bool {
+        self.alias_of().is_some()
+    }
+}
+
+/// Whether upstream `index` gets no traffic, being an alias under the
+/// `alias` policy.
+pub fn routed_out(config: &ServerConfig, index: usize) -> bool {
+    let policy = config.settings.discovery.as_ref().map(|d| d.duplicates);
+    policy == Some(DuplicatePolicy::Alias) && config.servers[index].identity.is_alias()
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Ask `upstream` for its identity `SAMPLES` times and record what the
+/// answers agree on, logging a node that changed.
+pub async fn sample(config: &ServerConfig, upstream: &Upstream) {
+    let mut identities = Vec::new();
+    for _ in 0..SAMPLES {
+        let answer = crate::discovery::call(config, upstream, "getIdentity", json!([])).await;
+        identities.push(answer.and_then(|a| a["result"]["identity"].as_str().map(str::to_string)));
+    }
+    if let Some(previous) = upstream.identity.record(&identities) {
+        let now = match upstream.identity.node() {
+            Some(node) => format!("now reports node identity {}", node),
+            None => "now reports varying node identities".to_string(),
+        };
+        log(
+            config,
+            format!(
+                "{} {}, it reported {} before",
+                upstream.name,
+                now,
+                previous.unwrap_or_default()
+            ),
+        );
+    }
+}
+
+/// Sample the identities of the regular upstreams every `interval`, the
+/// discovery rounds having taken the first ones, and work out the aliases
+/// again.
+pub async fn follow(config: Arc<ServerConfig>, interval: Duration) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticker = tokio::time::interval_at(start, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let upstreams = config
+            .servers
+            .iter()
+            .filter(|u| !u.is_last_resort() && u.circuit.dead().is_none())
+            .filter(|u| !u.cluster.is_wrong());
+        futures::future::join_all(upstreams.map(|upstream| sample(&config, upstream))).await;
+        detect(&config);
+    }
+}
+
+/// Compare the identities and addresses of the regular upstreams after a
+/// discovery round, logging the duplicates found and those gone.
+pub fn detect(config: &ServerConfig) {
+    let policy = config.settings.discovery.as_ref().map(|d| d.duplicates);
+    let regular: Vec<usize> = (0..config.servers.len())
+        .filter(|&index| !config.servers[index].is_last_resort())
+        .collect();
+    let nodes: Vec<Option<String>> = config
+        .servers
+        .iter()
+        .map(|upstream| upstream.identity.node())
+        .collect();
+    let addresses: Vec<Vec<IpAddr>> = config
+        .servers
+        .iter()
+        .map(|upstream| upstream.addresses.lock().unwrap().clone())
+        .collect();
+    for (position, &index) in regular.iter().enumerate() {
+        let upstream = &config.servers[index];
+        let earlier = &regular[..position];
+        let canonical = nodes[index].as_ref().and_then(|node| {
+            earlier
+                .iter()
+                .copied()
+                .find(|&other| nodes[other].as_ref() == Some(node))
+        });
+        let previous = upstream
+            .identity
+            .alias_of
+            .swap(canonical.unwrap_or(NONE), Ordering::Relaxed);
+        match (canonical, previous) {
+            (Some(canonical), previous) if previous != canonical => {
+                let routing = match policy {
+                    Some(DuplicatePolicy::Alias) => {
+                        format!(", sending {} no traffic", upstream.name)
+                    }
+                    _ => String::new(),
+                };
+                log(
+                    config,
+                    format!(
+                        "WARNING: {} reports node identity {} like {}, the same node is configured twice; counting them as one{}",
+                        upstream.name,
+                        nodes[index].as_deref().unwrap_or_default(),
+                        config.servers[canonical].name,
+                        routing
+                    ),
+                );
+            }
+            (None, previous) if previous != NONE => log(
+                config,
+                format!(
+                    "{} no longer reports the node identity of {}, counting it on its own again",
+                    upstream.name, config.servers[previous].name
+                ),
+            ),
+            _ => {}
+        }
+        // a weaker hint, shared front ends are common
+        let sharing = match canonical {
+            Some(_) => None,
+            None if addresses[index].is_empty() => None,
+            None => earlier
+                .iter()
+                .copied()
+                .find(|&other| addresses[other] == addresses[index]),
+        };
+        let noted = upstream
+            .identity
+            .shares_addresses
+            .swap(sharing.unwrap_or(NONE), Ordering::Relaxed);
+        if let Some(other) = sharing.filter(|&other| other != noted) {
+            let addresses: Vec<String> = addresses[index].iter().map(IpAddr::to_string).collect();
+            log(
+                config,
+                format!(
+                    "{} resolves to the same addresses as {} ({}), possibly the same backend",
+                    upstream.name,
+                    config.servers[other].name,
+                    addresses.join(", ")
+                ),
+            );
+        }
+    }
+}
+
+/// What `/status` shows of upstream `index`.
+pub fn report(config: &ServerConfig, index: usize) -> Value {
+    let identity = &config.servers[index].identity;
+    let name = |index: Option<usize>| index.map(|index| config.servers[index].name.clone());
+    let shares = match identity.shares_addresses.load(Ordering::Relaxed) {
+        NONE => None,
+        index => Some(index),
+    };
+    json!({
+        "node": identity.node(),
+        "varies": identity.varies.load(Ordering::Relaxed),
+        "alias_of": name(identity.alias_of()),
+        "shares_addresses_with": name(shares),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+    use axum::{routing::post, Router};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    fn samples(answers: &[Option<&str>]) -> Vec<Option<String>> {
+        answers.iter().map(|a| a.map(str::to_string)).collect()
+    }
+
+    #[test]
+    fn only_agreeing_answers_make_a_node() {
+        let identity = Identity::default();
+        assert_eq!(
+            identity.record(&samples(&[Some("A"), Some("A"), Some("A")])),
+            None
+        );
+        assert_eq!(identity.node().as_deref(), Some("A"));
+        assert!(!identity.varies.load(Ordering::Relaxed));
+        let varying = Identity::default();
+        varying.record(&samples(&[Some("A"), Some("B"), Some("A")]));
+        assert_eq!(varying.node(), None);
+        assert!(varying.varies.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn a_changed_node_is_reported() {
+        let identity = Identity::default();
+        identity.record(&samples(&[Some("A"), Some("A"), Some("A")]));
+        let previous = identity.record(&samples(&[Some("B"), Some("B"), Some("B")]));
+        assert_eq!(previous, Some(Some("A".to_string())));
+        assert_eq!(identity.node().as_deref(), Some("B"));
+        assert_eq!(
+            identity.record(&samples(&[Some("B"), Some("B"), Some("B")])),
+            None
+        );
+        let previous = identity.record(&samples(&[Some("B"), Some("C"), Some("B")]));
+        assert_eq!(previous, Some(Some("B".to_string())));
+        assert_eq!(identity.node(), None);
+    }
+
+    #[test]
+    fn unanswered_samples_keep_the_node() {
+        let identity = Identity::default();
+        identity.record(&samples(&[Some("A"), Some("A"), Some("A")]));
+        assert_eq!(identity.record(&samples(&[None, None, None])), None);
+        assert_eq!(
+            identity.record(&samples(&[Some("A"), None, Some("A")])),
+            None
+        );
+        assert_eq!(identity.node().as_deref(), Some("A"));
+    }
+
+    fn events(config: &ServerConfig) -> Vec<String> {
+        config.events.recent().into_iter().map(|(_, e)| e).collect()
+    }
+
+    #[tokio::test]
+    async fn a_node_configured_twice_counts_once() {
+        let mut text = String::from("[discovery]\nduplicate_upstreams = \"alias\"\n");
+        for (name, port) in [("a", 1), ("a-cname", 2), ("b", 3)] {
+            text += &format!(
+                "[[upstreams]]\nname = \"{}\"\nurl = \"http://127.0.0.1:{}\"\n",
+                name, port
+            );
+        }
+        let config = crate::testing::config(&text);
+        for (index, node) in ["A", "A", "B"].into_iter().enumerate() {
+            config.servers[index]
+                .identity
+                .record(&samples(&[Some(node); SAMPLES]));
+            config.slots.observe(index, 1000, SlotSource::Poll);
+        }
+        detect(&config);
+        assert_eq!(config.servers[1].identity.alias_of(), Some(0));
+        assert!(!config.servers[0].identity.is_alias());
+        assert!(routed_out(&config, 1) && !routed_out(&config, 0));
+        assert_eq!(
+            events(&config),
+            ["WARNING: a-cname reports node identity A like a, the same node is configured twice; counting them as one, sending a-cname no traffic"]
+        );
+        assert_eq!(crate::quarantine::host_counts(&config).await, (2, 0));
+        assert_eq!(report(&config, 1)["alias_of"], "a");
+        // found again, it is not reported again
+        detect(&config);
+        assert_eq!(events(&config).len(), 1);
+
+        config.servers[1]
+            .identity
+            .record(&samples(&[Some("A"), Some("C"), Some("A")]));
+        detect(&config);
+        assert!(!config.servers[1].identity.is_alias());
+        assert_eq!(
+            events(&config)[1],
+            "a-cname no longer reports the node identity of a, counting it on its own again"
+        );
+        assert_eq!(crate::quarantine::host_counts(&config).await, (3, 0));
+    }
+
+    #[tokio::test]
+    async fn discovery_tells_an_alias_from_a_pool_of_nodes() {
+        let node = crate::testing::upstream(crate::mock::Behavior::default()).await;
+        let asked: Value = reqwest::Client::new()
+            .post(&node)
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": "getIdentity" }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let identity = asked["result"]["identity"].as_str().unwrap().to_string();
+        // a provider's front end, a different node at every other call, one
+        // of them the same as the mock's
+        let calls = Arc::new(AtomicU64::new(0));
+        let front = crate::testing::serve(Router::new().route(
+            "/",
+            post(move || async move {
+                let node = match calls.fetch_add(1, Ordering::Relaxed) % 2 {
+                    0 => identity,
+                    _ => "elsewhere".to_string(),
+                };
+                json!({ "jsonrpc": "2.0", "id": 1, "result": { "identity": node } }).to_string()
+            }),
+        ))
+        .await;
+        let (config, _) = crate::testing::proxy(&format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n[[upstreams]]\nname = \"a-cname\"\nurl = \"{}\"\n[[upstreams]]\nname = \"front\"\nurl = \"{}\"\n",
+            node,
+            node.replace("127.0.0.1", "localhost"),
+            front
+        ))
+        .await;
+        let settings = config.settings.discovery.clone().unwrap();
+        let (first_round, done) = tokio::sync::oneshot::channel();
+        let discovering = tokio::spawn(crate::discovery::follow(
+            config.clone(),
+            settings,
+            first_round,
+        ));
+        done.await.unwrap();
+        discovering.abort();
+        assert_eq!(report(&config, 1)["alias_of"], "a");
+        let front = report(&config, 2);
+        assert_eq!(front["alias_of"], Value::Null);
+        assert_eq!(front["varies"], true);
+        // warned of, still raced
+        assert!(!routed_out(&config, 1));
+    }
+}
@@ -0,0 +1,390 @@
+//! Opt-in journal of submitted transactions, the proof of what went where
+//! when a client disputes a submission. Every single `sendTransaction` call
+//! the proxy receives, repeats included, gets a JSON line once its dispatch
+//! is over: the client, the signature, a hash of the serialized
+//! transaction, the upstreams it was sent to and what each answered. A
+//! dedicated thread appends the lines to a file rotated by size like the
+//! access log, so disk latency never holds up a submission; when it falls
+//! behind, lines are dropped and counted. `GET /admin/tx-journal` searches
+//! the file and its rotations by signature.
+
+use crate::access_log::Sink;
+use crate::config::TxJournalConfig;
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+/// Lines queued for the writer; beyond this lines are dropped.
+const QUEUE_LEN: usize = 10_000;
+/// Bytes of a body that is not JSON kept in its upstream's entry.
+const MAX_RAW_BODY: usize = 256;
+
+pub struct Journal {
+    settings: TxJournalConfig,
+    lines: SyncSender<String>,
+    /// Taken by `open`.
+    receiver: Mutex<Option<Receiver<String>>>,
+    pub journaled: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+impl Journal {
+    pub fn new(settings: &TxJournalConfig) -> Self {
+        let (lines, receiver) = sync_channel(QUEUE_LEN);
+        Self {
+            settings: settings.clone(),
+            lines,
+            receiver: Mutex::new(Some(receiver)),
+            journaled: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Open the file and start the writer thread.
+    pub fn open(&self) -> io::Result<()> {
+        let Some(receiver) = self.receiver.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let settings = &self.settings;
+        let sink = Sink::file(&settings.path, settings.max_size, settings.max_files)?;
+        std::thread::Builder::new()
+            .name("tx-journal".into())
+            .spawn(move || sink.run(receiver, "Transaction journal"))?;
+        Ok(())
+    }
+
+    /// The submission `body` makes, to be journaled when dropped; `None`
+    /// unless it is a single `sendTransaction` call.
+    pub fn submission(
+        self: &Arc<Self>,
+        body: &[u8],
+        request_id: &str,
+        client: &str,
+    ) -> Option<Submission> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        let transaction = crate::dedup::transaction(&request)?;
+        let signature = crate::dedup::first_signature(&transaction)?;
+        let hash = openssl::sha::sha256(&transaction);
+        Some(Submission {
+            journal: self.clone(),
+            unix_ms: crate::clock::unix_ms(),
+            request_id: request_id.to_string(),
+            client: client.to_string(),
+            signature: crate::base58::encode(&signature),
+            transaction_sha256: hash.iter().map(|b| format!("{:02x}", b)).collect(),
+            transaction: self
+                .settings
+                .include_transaction
+                .then(|| request["params"][0].clone()),
+            repeat: None,
+            upstreams: Vec::new(),
+        })
+    }
+}
+
+/// A submission being dispatched, written to the journal when the dispatch
+/// drops it, however it ends.
+pub struct Submission {
+    journal: Arc<Journal>,
+    unix_ms: u64,
+    request_id: String,
+    client: String,
+    signature: String,
+    transaction_sha256: String,
+    transaction: Option<Value>,
+    /// How a repeat of a recent submission was handled, see `dedup`.
+    repeat: Option<&'static str>,
+    /// Upstreams asked and their answer, `None` until it comes.
+    upstreams: Vec<(String, Option<Value>)>,
+}
+
+impl Submission {
+    pub fn repeated(&mut self, handling: &'static str) {
+        self.repeat = Some(handling);
+    }
+
+    pub fn asked(&mut self, upstreams: &[String]) {
+        self.upstreams = upstreams.iter().map(|name| (name.clone(), None)).collect();
+    }
+
+    /// `upstream` was left out after all, by a hedge or its rate limit.
+    pub fn not_sent(&mut self, upstream: &str) {
+        self.upstreams.retain(|(name, _)| name != upstream);
+    }
+
+    pub fn answered(&mut self, upstream: &str, answer: Value) {
+        if let Some((_, entry)) = self.upstreams.iter_mut().find(|(name, _)| name == upstream) {
+            *entry = Some(answer);
+        }
+    }
+}
+
+impl Drop for Submission {
+    fn drop(&mut self) {
+        let upstreams: Vec<Value> = self
+            .upstreams
+            .iter()
+            .map(|(name, answer)| json!({ "upstream": name, "answer": answer }))
+            .collect();
+        let line = json!({
+            "ts": crate::clock::rfc3339(self.unix_ms),
+            "request_id": self.request_id,
+            "client": self.client,
+            "signature": self.signature,
+            "transaction_sha256": self.transaction_sha256,
+            "transaction": self.transaction,
+            "repeat": self.repeat,
+            "upstreams": upstreams,
+        });
+        let journal = &self.journal;
+        // a full queue means the writer cannot keep up; drop rather than block
+        match journal.lines.try_send(line.to_string()) {
+            Ok(()) => journal.journaled.fetch_add(1, Ordering::Relaxed),
+            Err(_) => journal.dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// What an upstream answered, with the result or error of a JSON-RPC body.
+pub fn answer(status: u16, body: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(json) if json.get("result").is_some() => {
+            json!({ "status": status, "result": json["result"] })
+        }
+        Ok(json) if json.get("error").is_some() => {
+            json!({ "status": status, "error": json["error"] })
+        }
+        _ => {
+            let raw = String::from_utf8_lossy(&body[..body.len().min(MAX_RAW_BODY)]);
+            json!({ "status": status, "body": raw })
+        }
+    }
+}
+
+/// The entries of `signature` in the file at `path` and its rotations,
+/// oldest first.
+fn search(settings: &TxJournalConfig, signature: &str) -> Vec<Value> {
+    let rotated = (1..=settings.max_files).rev();
+    let paths = rotated
+        .map(|n| format!("{}.{}", settings.path, n))
+        .chain([settings.path.clone()]);
+    let mut entries = Vec::new();
+    for path in paths {
+        let Ok(file) = std::fs::File::open(&path) else {
+            continue;
+        };
+        for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+            // most lines are not parsed at all
+            if !line.contains(signature) {
+                continue;
+            }
+            if let Ok(entry) = serde_json::from_str::<Value>(&line) {
+                if entry["signature"] == signature {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// `GET /admin/tx-journal?signature=...`: every journaled submission of the
+/// transaction, from the retained files.
+pub async fn lookup_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response<Body> {
+    let Some(journal) = config.journal.clone() else {
+        return crate::rpc::error_response(
+            StatusCode::NOT_FOUND,
+            crate::rpc::INVALID_REQUEST,
+            "the transaction journal is disabled, set [tx_journal] path",
+            None,
+        );
+    };
+    let Some(signature) = params.get("signature").cloned() else {
+        return crate::rpc::error_response(
+            StatusCode::BAD_REQUEST,
+            crate::rpc::INVALID_REQUEST,
+            "a signature is required",
+            None,
+        );
+    };
+    let entries = tokio::task::spawn_blocking({
+        let signature = signature.clone();
+        move || search(&journal.settings, &signature)
+    })
+    .await
+    .unwrap_or_default();
+    Json(json!({ "signature": signature, "entries": entries })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{proxy, upstream};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use std::time::Duration;
+
+    /// A `sendTransaction` call of a transaction signed by `signature`,
+    /// base64 encoded.
+    fn send(signature: u8) -> String {
+        let mut transaction = vec![1];
+        transaction.extend([signature; 64]);
+        transaction.extend(b"message");
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "sendTransaction",
+            "params": [STANDARD.encode(&transaction), { "encoding": "base64" }],
+        })
+        .to_string()
+    }
+
+    fn settings(path: &str) -> TxJournalConfig {
+        TxJournalConfig {
+            path: path.to_string(),
+            max_size: 0,
+            max_files: 2,
+            include_transaction: false,
+        }
+    }
+
+    fn temp_path() -> String {
+        let name = format!("quarantier-journal-{}.log", crate::random::u64());
+        std::env::temp_dir()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn only_single_submissions_are_journaled() {
+        let journal = Arc::new(Journal::new(&settings("unused")));
+        let get_slot = br#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#;
+        assert!(journal.submission(get_slot, "r1", "c").is_none());
+        let batch = format!("[{}]", send(7));
+        assert!(journal.submission(batch.as_bytes(), "r1", "c").is_none());
+        assert!(journal.submission(send(7).as_bytes(), "r1", "c").is_some());
+    }
+
+    #[test]
+    fn a_submission_is_written_when_dropped() {
+        let journal = Arc::new(Journal::new(&settings("unused")));
+        let lines = journal.receiver.lock().unwrap().take().unwrap();
+        let mut submission = journal.submission(send(7).as_bytes(), "r1", "c").unwrap();
+        submission.repeated("forwarded");
+        submission.asked(&["a".to_string(), "b".to_string(), "c".to_string()]);
+        submission.not_sent("c");
+        submission.answered(
+            "a",
+            answer(200, br#"{"jsonrpc":"2.0","id":1,"result":"sig"}"#),
+        );
+        drop(submission);
+        let line: Value = serde_json::from_str(&lines.recv().unwrap()).unwrap();
+        assert_eq!(line["signature"], crate::base58::encode(&[7; 64]));
+        assert_eq!(line["request_id"], "r1");
+        assert_eq!(line["repeat"], "forwarded");
+        assert_eq!(line["transaction"], Value::Null);
+        assert_eq!(
+            line["upstreams"],
+            json!([
+                { "upstream": "a", "answer": { "status": 200, "result": "sig" } },
+                { "upstream": "b", "answer": null },
+            ])
+        );
+        assert_eq!(journal.journaled.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn answers_keep_their_result_or_error() {
+        let error = br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32002,"message":"failed"}}"#;
+        assert_eq!(
+            answer(200, error),
+            json!({ "status": 200, "error": { "code": -32002, "message": "failed" } })
+        );
+        let page = "x".repeat(1000);
+        let raw = answer(502, page.as_bytes());
+        assert_eq!(raw["status"], 502);
+        assert_eq!(raw["body"].as_str().unwrap().len(), MAX_RAW_BODY);
+    }
+
+    #[test]
+    fn lookups_search_the_rotations_oldest_first() {
+        let path = temp_path();
+        let entry = |signature: &str, request_id: &str| {
+            json!({ "signature": signature, "request_id": request_id }).to_string() + "\n"
+        };
+        std::fs::write(format!("{}.2", path), entry("s1", "oldest")).unwrap();
+        std::fs::write(format!("{}.1", path), entry("s2", "other")).unwrap();
+        std::fs::write(&path, entry("s1", "latest") + "not json s1\n").unwrap();
+        let found = search(&settings(&path), "s1");
+        for file in [format!("{}.2", path), format!("{}.1", path), path] {
+            let _ = std::fs::remove_file(file);
+        }
+        let found: Vec<&Value> = found.iter().map(|entry| &entry["request_id"]).collect();
+        assert_eq!(found, ["oldest", "latest"]);
+    }
+
+    #[tokio::test]
+    async fn submissions_through_the_proxy_can_be_looked_up() {
+        let path = temp_path();
+        let url = upstream(Behavior::default()).await;
+        let (_, proxy) = proxy(&format!(
+            "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n[tx_journal]\npath = \"{}\"\n[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n",
+            path, url
+        ))
+        .await;
+        let client = reqwest::Client::new();
+        let sent = client
+            .post(&proxy)
+            .header("x-api-key", "secret-app")
+            .header("Content-Type", "application/json")
+            .body(send(9))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(sent.status(), 200);
+        let signature = crate::base58::encode(&[9; 64]);
+        let lookup = |key: &'static str| {
+            client
+                .get(format!(
+                    "{}/admin/tx-journal?signature={}",
+                    proxy, signature
+                ))
+                .header("x-api-key", key)
+                .send()
+        };
+        assert_eq!(lookup("secret-app").await.unwrap().status(), 403);
+        let mut entries = Value::Null;
+        for _ in 0..50 {
+            let found: Value = lookup("secret-ops").await.unwrap().json().await.unwrap();
+            entries = found["entries"].clone();
+            if entries
+                .as_array()
+                .is_some_and(|entries| !entries.is_empty())
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(entries[0]["signature"], signature);
+        assert_eq!(entries[0]["upstreams"][0]["upstream"], "a");
+        assert_eq!(entries[0]["upstreams"][0]["answer"]["result"], signature);
+    }
+}
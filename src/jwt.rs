@@ -0,0 +1,363 @@
+//! Validation of JWT bearer tokens, signed with a shared HS256 secret or
+//! with RS256 keys published at a JWKS URL.
+
+use crate::config::JwtConfig;
+use crate::ratelimit::KeyedLimiter;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Identities tracked by the rate limiter before the least recent are dropped.
+const MAX_IDENTITIES: usize = 100_000;
+/// Shortest time between JWKS fetches triggered by unknown key ids.
+const MIN_REFETCH: Duration = Duration::from_secs(10);
+
+pub struct JwtValidator {
+    settings: JwtConfig,
+    /// RS256 keys by `kid`.
+    jwks: RwLock<HashMap<String, PKey<Public>>>,
+    last_fetch: Mutex<Option<Instant>>,
+    refetch: Notify,
+    /// Buckets of token identities, when `[auth]` sets a default limit.
+    pub limiter: Option<KeyedLimiter<String>>,
+}
+
+impl JwtValidator {
+    pub fn new(settings: JwtConfig, limit: Option<crate::ratelimit::Limit>) -> Self {
+        Self {
+            settings,
+            jwks: RwLock::new(HashMap::new()),
+            last_fetch: Mutex::new(None),
+            refetch: Notify::new(),
+            limiter: limit.map(|limit| KeyedLimiter::new(limit, MAX_IDENTITIES)),
+        }
+    }
+
+    /// The identity claim of a valid token, or a machine-readable reason
+    /// for rejecting it.
+    pub fn validate(&self, token: &str) -> Result<String, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(encoded_header), Some(encoded_claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed");
+        };
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|_| "malformed");
+        let json =
+            |bytes: Vec<u8>| serde_json::from_slice::<Value>(&bytes).map_err(|_| "malformed");
+        let header = json(decode(encoded_header)?)?;
+        let claims = json(decode(encoded_claims)?)?;
+        let signature = decode(signature)?;
+        let signed = &token[..encoded_header.len() + 1 + encoded_claims.len()];
+
+        let valid = match header["alg"].as_str() {
+            Some("HS256") => {
+                let secret = self
+                    .settings
+                    .secret
+                    .as_ref()
+                    .ok_or("unsupported_algorithm")?;
+                verify_hs256(secret, signed, &signature)
+            }
+            Some("RS256") if self.settings.jwks_url.is_some() => {
+                let kid = header["kid"].as_str().unwrap_or_default();
+                let key = self.jwks.read().unwrap().get(kid).cloned();
+                let Some(key) = key else {
+                    self.refetch.notify_one();
+                    return Err("unknown_key");
+                };
+                verify_rs256(&key, signed, &signature)
+            }
+            _ => return Err("unsupported_algorithm"),
+        };
+        if !valid {
+            return Err("bad_signature");
+        }
+
+        let now = crate::clock::unix_secs() as i64;
+        let leeway = self.settings.leeway.as_secs() as i64;
+        match claims["exp"].as_i64() {
+            None => return Err("missing_exp"),
+            Some(exp) if now > exp + leeway => return Err("expired"),
+            _ => {}
+        }
+        if let Some(nbf) = claims["nbf"].as_i64() {
+            if now + leeway < nbf {
+                return Err("not_yet_valid");
+            }
+        }
+        if let Some(issuer) = &self.settings.issuer {
+            if claims["iss"].as_str() != Some(issuer) {
+                return Err("wrong_issuer");
+            }
+        }
+        if let Some(audience) = &self.settings.audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong_audience");
+            }
+        }
+        match &claims[&self.settings.identity_claim] {
+            Value::String(identity) if !identity.is_empty() => Ok(identity.clone()),
+            Value::Number(identity) => Ok(identity.to_string()),
+            _ => Err("missing_identity"),
+        }
+    }
+
+    async fn fetch_jwks(&self, client: &reqwest::Client, url: &str) -> Result<usize, String> {
+        *self.last_fetch.lock().unwrap() = Some(Instant::now());
+        let jwks: Value = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut keys = HashMap::new();
+        for key in jwks["keys"].as_array().into_iter().flatten() {
+            if key["kty"] != "RSA" || key["use"].as_str().is_some_and(|u| u != "sig") {
+                continue;
+            }
+            let component = |name: &str| {
+                let bytes = URL_SAFE_NO_PAD.decode(key[name].as_str()?).ok()?;
+                BigNum::from_slice(&bytes).ok()
+            };
+            let public = component("n")
+                .zip(component("e"))
+                .and_then(|(n, e)| Rsa::from_public_components(n, e).ok())
+                .and_then(|rsa| PKey::from_rsa(rsa).ok());
+            if let Some(public) = public {
+                let kid = key["kid"].as_str().unwrap_or_default().to_string();
+                keys.insert(kid, public);
+            }
+        }
+        let count = keys.len();
+        *self.jwks.write().unwrap() = keys;
+        Ok(count)
+    }
+}
+
+fn verify_hs256(secret: &str, signed: &str, signature: &[u8]) -> bool {
+    let Ok(key) = PKey::hmac(secret.as_bytes()) else {
+        return false;
+    };
+    let Ok(mut signer) = Signer::new(MessageDigest::sha256(), &key) else {
+        return false;
+    };
+    match signer.sign_oneshot_to_vec(signed.as_bytes()) {
+        Ok(expected) => {
+            expected.len() == signature.len() && openssl::memcmp::eq(&expected, signature)
+        }
+        Err(_) => false,
+    }
+}
+
+fn verify_rs256(key: &PKey<Public>, signed: &str, signature: &[u8]) -> bool {
+    Verifier::new(MessageDigest::sha256(), key)
+        .and_then(|mut verifier| verifier.verify_oneshot(signature, signed.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Keep the JWKS cache current: refreshed every `jwks_refresh`, and earlier
+/// when a token names a key id that is not cached yet.
+pub async fn refresh_jwks(validator: Arc<JwtValidator>) {
+    let Some(url) = validator.settings.jwks_url.clone() else {
+        return;
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("Failed to create HTTP client");
+    let refresh = validator.settings.jwks_refresh;
+    loop {
+        match validator.fetch_jwks(&client, &url).await {
+            Ok(count) => println!("+ Loaded {} signing keys from {}", count, url),
+            Err(err) => println!("+ Failed to fetch JWKS from {}: {}", url, err),
+        }
+        let _ = tokio::time::timeout(refresh, validator.refetch.notified()).await;
+        let last_fetch = *validator.last_fetch.lock().unwrap();
+        if let Some(wait) = last_fetch.and_then(|at| MIN_REFETCH.checked_sub(at.elapsed())) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use serde_json::json;
+
+    fn settings() -> JwtConfig {
+        JwtConfig {
+            secret: Some("shared".to_string()),
+            jwks_url: None,
+            jwks_refresh: Duration::from_secs(300),
+            issuer: None,
+            audience: None,
+            identity_claim: "sub".to_string(),
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    fn hs256(secret: &str, claims: Value) -> String {
+        let signed = format!(
+            "{}.{}",
+            encode(&json!({ "alg": "HS256", "typ": "JWT" })),
+            encode(&claims)
+        );
+        let key = PKey::hmac(secret.as_bytes()).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn rs256(key: &PKey<Private>, kid: &str, claims: Value) -> String {
+        let header = json!({ "alg": "RS256", "kid": kid });
+        let signed = format!("{}.{}", encode(&header), encode(&claims));
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn now() -> i64 {
+        crate::clock::unix_secs() as i64
+    }
+
+    #[test]
+    fn valid_tokens_name_their_identity() {
+        let validator = JwtValidator::new(settings(), None);
+        let token = hs256("shared", json!({ "sub": "bot-7", "exp": now() + 60 }));
+        assert_eq!(validator.validate(&token), Ok("bot-7".to_string()));
+        let token = hs256("shared", json!({ "sub": 42, "exp": now() + 60 }));
+        assert_eq!(validator.validate(&token), Ok("42".to_string()));
+        let token = hs256("shared", json!({ "sub": "", "exp": now() + 60 }));
+        assert_eq!(validator.validate(&token), Err("missing_identity"));
+    }
+
+    #[test]
+    fn tampered_and_malformed_tokens_are_rejected() {
+        let validator = JwtValidator::new(settings(), None);
+        let claims = json!({ "sub": "bot-7", "exp": now() + 60 });
+        let token = hs256("other", claims.clone());
+        assert_eq!(validator.validate(&token), Err("bad_signature"));
+        // claims swapped under a valid signature
+        let token = hs256("shared", claims);
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = encode(&json!({ "sub": "admin", "exp": now() + 60 }));
+        parts[1] = &forged;
+        assert_eq!(validator.validate(&parts.join(".")), Err("bad_signature"));
+        for token in ["", "a.b", "a.b.c.d", "!!.e30.", "e30.e30.***"] {
+            assert_eq!(validator.validate(token), Err("malformed"), "{}", token);
+        }
+        let unsigned = format!(
+            "{}.{}.",
+            encode(&json!({ "alg": "none" })),
+            encode(&json!({ "sub": "bot-7", "exp": now() + 60 }))
+        );
+        assert_eq!(validator.validate(&unsigned), Err("unsupported_algorithm"));
+    }
+
+    #[test]
+    fn time_claims_are_checked_with_leeway() {
+        let validator = JwtValidator::new(settings(), None);
+        let check = |claims| validator.validate(&hs256("shared", claims));
+        assert_eq!(check(json!({ "sub": "a" })), Err("missing_exp"));
+        assert_eq!(
+            check(json!({ "sub": "a", "exp": now() - 120 })),
+            Err("expired")
+        );
+        assert!(check(json!({ "sub": "a", "exp": now() - 30 })).is_ok());
+        assert_eq!(
+            check(json!({ "sub": "a", "exp": now() + 600, "nbf": now() + 120 })),
+            Err("not_yet_valid")
+        );
+        assert!(check(json!({ "sub": "a", "exp": now() + 600, "nbf": now() + 30 })).is_ok());
+    }
+
+    #[test]
+    fn issuer_and_audience_must_match_when_set() {
+        let validator = JwtValidator::new(
+            JwtConfig {
+                issuer: Some("https://auth.example.com/".to_string()),
+                audience: Some("rpc-proxy".to_string()),
+                ..settings()
+            },
+            None,
+        );
+        let check = |iss: &str, aud: Value| {
+            let claims = json!({ "sub": "a", "exp": now() + 60, "iss": iss, "aud": aud });
+            validator.validate(&hs256("shared", claims))
+        };
+        assert!(check("https://auth.example.com/", json!("rpc-proxy")).is_ok());
+        assert!(check("https://auth.example.com/", json!(["web", "rpc-proxy"])).is_ok());
+        assert_eq!(
+            check("https://evil.example.com/", json!("rpc-proxy")),
+            Err("wrong_issuer")
+        );
+        assert_eq!(
+            check("https://auth.example.com/", json!(["web"])),
+            Err("wrong_audience")
+        );
+    }
+
+    #[tokio::test]
+    async fn rs256_keys_come_from_the_jwks() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwks = json!({ "keys": [
+            {
+                "kty": "RSA",
+                "kid": "k1",
+                "use": "sig",
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            },
+            { "kty": "EC", "kid": "k2" },
+        ] });
+        let private = PKey::from_rsa(rsa).unwrap();
+        let router = axum::Router::new().route(
+            "/jwks.json",
+            axum::routing::get(move || async move { axum::Json(jwks) }),
+        );
+        let url = crate::testing::serve(router).await + "/jwks.json";
+        let validator = JwtValidator::new(
+            JwtConfig {
+                secret: None,
+                jwks_url: Some(url.clone()),
+                ..settings()
+            },
+            None,
+        );
+        let claims = json!({ "sub": "bot-7", "exp": now() + 60 });
+        let token = rs256(&private, "k1", claims.clone());
+        assert_eq!(validator.validate(&token), Err("unknown_key"));
+        let count = validator.fetch_jwks(&reqwest::Client::new(), &url).await;
+        assert_eq!(count, Ok(1));
+        assert_eq!(validator.validate(&token), Ok("bot-7".to_string()));
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let forged = rs256(&other, "k1", claims.clone());
+        assert_eq!(validator.validate(&forged), Err("bad_signature"));
+        // no secret configured, so no HS256
+        let token = hs256("shared", claims);
+        assert_eq!(validator.validate(&token), Err("unsupported_algorithm"));
+    }
+}
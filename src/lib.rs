@@ -0,0 +1,1684 @@
+//! The balancer as a library: [`ProxyService`] builds the proxy from a
+//! [`Config`], starts its background tasks and hands out the axum `Router`
+//! serving it, so it can be embedded in another service or driven in-process.
+
+mod access_log;
+mod acl;
+mod admin;
+mod alerts;
+mod auth;
+mod base58;
+mod batch;
+pub mod bench;
+mod blockhash;
+mod body_budget;
+mod chaos;
+pub mod check;
+mod classify;
+mod client;
+mod clock;
+mod cluster;
+mod coalesce;
+mod compare;
+mod concurrency;
+mod config;
+mod costs;
+mod credentials;
+mod dedup;
+mod discovery;
+mod dispatch;
+mod divergence;
+mod dns;
+mod drain;
+mod dump;
+mod effective;
+mod envelope;
+mod epoch;
+mod events;
+mod failback;
+mod failures;
+mod freshness;
+mod health;
+mod hedge;
+mod history;
+mod identity;
+mod journal;
+mod jwt;
+mod local_slot;
+mod maintenance;
+mod merge;
+mod methods;
+mod metrics;
+mod min_context;
+mod mirror;
+pub mod mock;
+mod pause;
+mod provider_limits;
+mod quarantine;
+mod quota;
+mod race;
+mod random;
+mod ranking;
+mod ratelimit;
+mod reload;
+pub mod replay;
+mod request_id;
+mod retry;
+mod rpc;
+mod shedding;
+mod slot_feed;
+mod slots;
+mod state;
+mod statsd;
+mod status;
+pub mod status_client;
+mod streaming;
+mod summary;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod tolerance;
+mod toml;
+mod tx;
+mod unsupported;
+mod upstream;
+mod usage;
+pub mod version;
+mod versions;
+mod warmup;
+mod ws;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, State},
+    http::{HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Router,
+};
+pub use config::{Config, ConfigError};
+use divergence::DivergenceTracker;
+use provider_limits::ProviderLimits;
+use slots::{Feed, SlotTracker};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use upstream::{ClientSettings, Upstream, UpstreamStats};
+use usage::UsageTracker;
+
+pub struct ServerConfig {
+    settings: Config,
+    servers: Vec<Upstream>,
+    /// Upstreams quarantined for slot lag, by index.
+    quarantine: tokio::sync::RwLock<Vec<usize>>,
+    usage: UsageTracker,
+    divergence: DivergenceTracker,
+    slots: SlotTracker,
+    stats: metrics::ProxyStats,
+    /// Per-request timings for the StatsD exporter, when enabled.
+    timings: Option<statsd::TimingBuffer>,
+    readiness: health::Readiness,
+    /// Replaced on config reload.
+    api_keys: std::sync::RwLock<Arc<auth::ApiKeys>>,
+    ip_limiter: Option<ratelimit::KeyedLimiter<IpAddr>>,
+    jwt: Option<Arc<jwt::JwtValidator>>,
+    acl: acl::AccessControl,
+    /// Changes to the configuration in effect, see `effective`.
+    generation: effective::Generation,
+    quotas: quota::QuotaTracker,
+    costs: costs::CostLedger,
+    tolerance: tolerance::SlotTolerance,
+    failback: failback::Failback,
+    /// The genesis hash the upstreams must report, see `cluster`.
+    expected_cluster: cluster::Expected,
+    /// The node versions last warned of, see `versions`.
+    version_spread: versions::Spread,
+    /// The `[retry]` budget and counts.
+    retries: retry::Retries,
+    /// The upstreams' `[ranking]` scores, for routing by them.
+    scores: ranking::Scores,
+    in_flight: concurrency::InFlight,
+    shedder: shedding::Shedder,
+    pause: pause::Pause,
+    tasks: tasks::DispatchTasks,
+    bodies: body_budget::BodyBudget,
+    events: events::EventLog,
+    requests: dump::InFlightRequests,
+    chaos: chaos::Chaos,
+    epochs: epoch::EpochCache,
+    sends: dedup::SendDedup,
+    coalescer: coalesce::Coalescer,
+    transactions: tx::TxTracker,
+    local_slots: local_slot::LocalSlots,
+    mirror: Option<Arc<mirror::Mirror>>,
+    /// `None` unless `[tx_journal] path` is set.
+    journal: Option<Arc<journal::Journal>>,
+}
+
+impl ServerConfig {
+    fn api_keys(&self) -> Arc<auth::ApiKeys> {
+        self.api_keys.read().unwrap().clone()
+    }
+
+    /// `saved` is the state persisted by a previous run.
+    fn new(settings: Config, saved: &serde_json::Value) -> Self {
+        let pause = pause::Pause::default();
+        let servers: Vec<Upstream> = settings
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(index, upstream)| Upstream {
+                index,
+                url: upstream.url.clone(),
+                name: upstream.name.clone(),
+                archive: upstream.archive,
+                capabilities: settings.capabilities.mask(&upstream.capabilities),
+                region: upstream.region.clone(),
+                outbound: upstream.outbound.map(ratelimit::TokenBucket::new),
+                outbound_skipped: AtomicU64::new(0),
+                last_resort: upstream.last_resort.map(ratelimit::TokenBucket::new),
+                client: RwLock::new(upstream::new_client(&ClientSettings::of(upstream))),
+                client_settings: RwLock::new(ClientSettings::of(upstream)),
+                addresses: Mutex::default(),
+                stats: UpstreamStats::default(),
+                limits: ProviderLimits::new(upstream.rate_limit.clone()),
+                blockhash: blockhash::BlockhashHealth::new(settings.blockhash.as_ref()),
+                circuit: quarantine::Circuit::new(),
+                drained: AtomicBool::new(false),
+                maintenance: maintenance::Maintenance::default(),
+                unsupported: unsupported::UnsupportedMethods::default(),
+                discovery: discovery::Discovered::default(),
+                identity: identity::Identity::default(),
+                cluster: cluster::Membership::default(),
+                version: versions::NodeVersion::default(),
+                credentials: credentials::Credentials::new(&upstream.credentials),
+                paused: pause.subscribe(),
+            })
+            .collect();
+        ranking::restore(&servers, &saved["ranking"]);
+
+        Self {
+            usage: UsageTracker::new(settings.usage.max_clients),
+            divergence: DivergenceTracker::default(),
+            slots: SlotTracker::new(
+                settings
+                    .upstreams
+                    .iter()
+                    .map(|upstream| match upstream.ws_url {
+                        Some(_) => Feed::Stale,
+                        None => Feed::Http,
+                    })
+                    .collect(),
+                settings.slots.max_age,
+            ),
+            stats: metrics::ProxyStats::default(),
+            readiness: health::Readiness::default(),
+            ip_limiter: settings
+                .ip_rate_limit
+                .as_ref()
+                .map(|ip| ratelimit::KeyedLimiter::new(ip.limit, ip.max_clients)),
+            in_flight: concurrency::InFlight::default(),
+            shedder: shedding::Shedder::default(),
+            pause,
+            tasks: tasks::DispatchTasks::new(settings.max_lingering_dispatches),
+            bodies: body_budget::BodyBudget::new(&settings.body_budget),
+            events: events::EventLog::default(),
+            failback: failback::Failback::new(
+                settings.upstreams.iter().any(|u| u.last_resort.is_some()),
+            ),
+            expected_cluster: cluster::Expected::default(),
+            version_spread: versions::Spread::default(),
+            retries: retry::Retries::default(),
+            scores: ranking::Scores::default(),
+            requests: dump::InFlightRequests::default(),
+            chaos: chaos::Chaos::default(),
+            epochs: epoch::EpochCache::default(),
+            sends: dedup::SendDedup::default(),
+            coalescer: coalesce::Coalescer::default(),
+            transactions: tx::TxTracker::default(),
+            local_slots: local_slot::LocalSlots::default(),
+            mirror: settings
+                .mirror
+                .as_ref()
+                .map(|mirror| Arc::new(mirror::Mirror::new(mirror))),
+            journal: settings
+                .tx_journal
+                .as_ref()
+                .map(|journal| Arc::new(journal::Journal::new(journal))),
+            acl: acl::AccessControl::new(settings.acl.clone()),
+            generation: effective::Generation::new(),
+            quotas: quota::QuotaTracker::new(
+                settings.auth.quota_utc_offset,
+                settings.auth.quota_soft_limit,
+                &saved["quotas"],
+            ),
+            costs: costs::CostLedger::new(&saved["costs"]),
+            tolerance: tolerance::SlotTolerance::new(settings.slots.tolerance),
+            jwt: settings
+                .auth
+                .jwt
+                .clone()
+                .map(|jwt| Arc::new(jwt::JwtValidator::new(jwt, settings.auth.rate_limit))),
+            api_keys: std::sync::RwLock::new(Arc::new(auth::ApiKeys::new(&settings.auth, None))),
+            timings: settings
+                .statsd
+                .as_ref()
+                .map(|_| statsd::TimingBuffer::default()),
+            settings,
+            servers,
+            quarantine: tokio::sync::RwLock::new(Vec::new()),
+        }
+    }
+}
+
+async fn load_balance_handler(
+    State(config): State<Arc<ServerConfig>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Extension(request_id::RequestId(request_id)): Extension<request_id::RequestId>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let started = Instant::now();
+    let unix_ms = clock::unix_ms();
+    let mut noted = Noted::default();
+    let mut response = proxy_request(
+        config.clone(),
+        peer,
+        request,
+        request_id.clone(),
+        started,
+        &mut noted,
+    )
+    .await
+    .into_response();
+    // the answer of a call others wait on is read in full and shared
+    if let Some(lead) = noted
+        .coalesced
+        .filter(|_| response.status() == StatusCode::OK)
+    {
+        let (parts, body) = response.into_parts();
+        response = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(body) => {
+                lead.answer(body.clone());
+                Response::from_parts(parts, Body::from(body))
+            }
+            Err(_) => rpc::error_response(
+                StatusCode::BAD_GATEWAY,
+                rpc::UPSTREAM_ERROR,
+                "the upstream answer broke off",
+                None,
+            ),
+        };
+    }
+    if let Some(priority) = noted.priority {
+        response
+            .extensions_mut()
+            .insert(shedding::RequestPriority(priority));
+    }
+    let elapsed = started.elapsed();
+    config.stats.record(response.status().as_u16(), elapsed);
+    let method = response.extensions().get::<rpc::RequestMethod>();
+    let method = method.map_or(rpc::UNKNOWN_METHOD, |m| m.0.as_str());
+    if let Some(timings) = &config.timings {
+        timings.record(method, elapsed.as_millis() as u64);
+    }
+    if let (Some(mirror), Some((client, body))) = (&config.mirror, noted.mirrored) {
+        if mirror.samples(method) {
+            mirror.record(mirror::Mirrored {
+                unix_ms,
+                request_id: request_id.clone(),
+                client,
+                method: method.to_string(),
+                body,
+                status: response.status().as_u16(),
+                upstream: response
+                    .extensions()
+                    .get::<rpc::ServedBy>()
+                    .map(|s| s.0.clone()),
+                duration: elapsed,
+            });
+        }
+    }
+    response
+}
+
+/// What `proxy_request` notes for the handler to act on once answered.
+#[derive(Default)]
+struct Noted {
+    /// The client and body as sent, for the mirror.
+    mirrored: Option<(String, Bytes)>,
+    /// The class the request was admitted in.
+    priority: Option<config::Priority>,
+    /// Set when identical calls wait for this one's answer.
+    coalesced: Option<coalesce::Lead>,
+}
+
+fn bad_gateway(dispatch: &dispatch::Dispatch, message: &str) -> Response<Body> {
+    let mut response =
+        rpc::error_response(StatusCode::BAD_GATEWAY, rpc::UPSTREAM_ERROR, message, None);
+    dispatch.annotate(
+        &mut response,
+        dispatch::Origin::Proxy(dispatch::LOCAL_ERROR),
+    );
+    response
+}
+
+/// The deadline of a request, `configured` unless the client asked for a
+/// shorter one, and where it comes from.
+fn effective_deadline(
+    configured: Duration,
+    requested: Option<Duration>,
+) -> (Duration, &'static str) {
+    match requested.filter(|&requested| requested < configured) {
+        Some(requested) => (requested, "header"),
+        None => (configured, "config"),
+    }
+}
+
+fn deadline_body(deadline: Duration) -> Bytes {
+    let data = serde_json::json!({ "deadline_ms": deadline.as_millis() as u64 });
+    rpc::error_body(
+        rpc::DEADLINE_EXCEEDED,
+        "request deadline exceeded",
+        Some(data),
+    )
+    .into()
+}
+
+async fn proxy_request(
+    config: Arc<ServerConfig>,
+    peer: SocketAddr,
+    request: Request<Body>,
+    request_id: String,
+    started: Instant,
+    noted: &mut Noted,
+) -> Result<Response<Body>, StatusCode> {
+    // refused before the body is even read while an admin paused traffic
+    if config.pause.is_paused() {
+        return Ok(config.pause.refusal());
+    }
+    // authenticated requests are accounted to their key, anonymous ones to
+    // their address
+    let client = match request.extensions().get::<auth::Identity>() {
+        Some(key) => key.0.clone(),
+        None => client::client_ip(peer, request.headers(), &config.settings.client_ip).to_string(),
+    };
+    // listed in SIGUSR1 dumps until answered
+    let tracked = config.requests.track(&request_id, client.clone());
+
+    let api_key = request.extensions().get::<Arc<auth::ApiKey>>().cloned();
+    let admin = api_key.as_ref().is_some_and(|key| key.admin);
+    let dispatch =
+        match dispatch::Dispatch::from_headers(&config, request.headers(), admin, &request_id) {
+            Ok(dispatch) => dispatch,
+            Err(response) => return Ok(*response),
+        };
+    let target = dispatch
+        .target
+        .map(|index| config.servers[index].name.clone());
+    let forced = dispatch
+        .force_include
+        .map(|index| config.servers[index].name.clone());
+
+    // the deadline runs from the arrival of the request, so slow uploads
+    // count against it; the method is not known yet. Clients may only
+    // shorten their deadline.
+    let requested = config.settings.deadlines.requested(request.headers());
+    let strict_requested = config
+        .settings
+        .strict_freshness
+        .as_ref()
+        .and_then(|settings| settings.requested(request.headers()));
+    let (upload_deadline, deadline_source) =
+        effective_deadline(config.settings.deadlines.default, requested);
+    let body = axum::body::to_bytes(request.into_body(), usize::MAX);
+    let Ok(body) = tokio::time::timeout_at((started + upload_deadline).into(), body).await else {
+        println!(
+            "[{}] + Deadline of {:?} ({}) exceeded reading request body",
+            request_id, upload_deadline, deadline_source
+        );
+        config.stats.record_timeout(rpc::UNKNOWN_METHOD);
+        let mut response = Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .header("content-type", "application/json")
+            .body(Body::from(deadline_body(upload_deadline)))
+            .unwrap();
+        dispatch.annotate(
+            &mut response,
+            dispatch::Origin::Proxy(dispatch::LOCAL_ERROR),
+        );
+        return Ok(response);
+    };
+    // Clone the request body for multiple uses
+    let mut body_bytes = body.map_err(|_| {
+        println!("[{}] Failed to read request body", request_id);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    // as the client sent it, for the mirror to pick or not once answered
+    if config.mirror.is_some() {
+        noted.mirrored = Some((client.clone(), body_bytes.clone()));
+    }
+
+    let mut denied_calls = Vec::new();
+    if !config.settings.methods.is_empty()
+        || api_key.as_ref().is_some_and(|k| !k.methods.is_empty())
+    {
+        let permits = |method: &str| {
+            config.settings.methods.permits(method)
+                && api_key
+                    .as_ref()
+                    .is_none_or(|key| key.methods.permits(method))
+        };
+        let count_denied = |denied: usize| {
+            if let Some(key) = &api_key {
+                key.denied.fetch_add(denied as u64, Ordering::Relaxed);
+            }
+            println!(
+                "[{}] + Rejected {} calls to methods not allowed",
+                request_id, denied
+            );
+        };
+        match methods::enforce(&body_bytes, permits) {
+            methods::Verdict::Allowed => {}
+            methods::Verdict::Denied { response, denied } => {
+                count_denied(denied);
+                let method = rpc::method_label(&rpc::request_methods(&body_bytes));
+                let response = Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Body::from(response.to_string()))
+                    .unwrap();
+                return Ok(dispatch.local_error(response, method));
+            }
+            methods::Verdict::Partial { body, errors } => {
+                count_denied(errors.len());
+                body_bytes = body.into();
+                denied_calls = errors;
+            }
+        }
+    }
+
+    // batch calls the proxy answers itself are not forwarded
+    let split = match dispatch.target {
+        None => batch::split(&config, &body_bytes),
+        Some(_) => None,
+    };
+    if let Some(split) = &split {
+        let (local, forwarded) = split.counts();
+        println!(
+            "[{}] + Answered {} batch calls from the proxy, forwarding {}",
+            request_id, local, forwarded
+        );
+        if let Some(mut answer) = split.answered() {
+            if !denied_calls.is_empty() {
+                answer = methods::merge(answer, denied_calls);
+            }
+            let method = "batch".to_string();
+            return Ok(dispatch.local_answer(method, dispatch::LOCAL_ANSWER, answer));
+        }
+        body_bytes = split.forwarded.clone().into();
+    }
+    let methods = rpc::request_methods(&body_bytes);
+    // some methods are answered from the proxy's own view, except for
+    // targeted requests, which ask for one node's answer
+    let local = match methods.as_slice() {
+        [method] if dispatch.target.is_none() => match method.as_str() {
+            "getHealth" if config.settings.aggregate_get_health => {
+                match health::get_health_id(&body_bytes) {
+                    Some(id) => Some(health::get_health_answer(&config, &id).await),
+                    None => None,
+                }
+                .map(|answer| (answer, dispatch::LOCAL_ANSWER))
+            }
+            "getSlot" => local_slot::answer(&config, &body_bytes)
+                .map(|answer| (answer, dispatch::LOCAL_ANSWER)),
+            method => epoch::answer(&config, method, &body_bytes)
+                .map(|answer| (answer, dispatch::CACHED_ANSWER)),
+        },
+        _ => None,
+    };
+    if let Some((answer, origin)) = local {
+        println!("[{}] + Answered {} from the proxy", request_id, methods[0]);
+        return Ok(dispatch.local_answer(methods[0].clone(), origin, answer));
+    }
+    // an identical read in flight is waited for rather than dispatched
+    // again; its answer is shared unless it is an error
+    let coalescing = match (&config.settings.coalesce, methods.as_slice()) {
+        (Some(settings), [method])
+            if settings.methods.contains(method)
+                && dispatch.target.is_none()
+                && dispatch.force_include.is_none() =>
+        {
+            serde_json::from_slice::<serde_json::Value>(&body_bytes).ok()
+        }
+        _ => None,
+    };
+    if let Some(call) = coalescing {
+        match config.coalescer.join(&call) {
+            coalesce::Joined::Lead(lead) => noted.coalesced = Some(lead),
+            coalesce::Joined::Follow(follow) => {
+                let (deadline, _) =
+                    effective_deadline(config.settings.deadlines.deadline(&methods), requested);
+                let shared = tokio::time::timeout_at((started + deadline).into(), follow.answer());
+                let answer = match shared.await {
+                    Ok(Some(answer)) => coalesce::answer_as(&answer, &call["id"]),
+                    _ => None,
+                };
+                match answer {
+                    Some(answer) => {
+                        println!(
+                            "[{}] + Answered {} with an identical call's answer",
+                            request_id, methods[0]
+                        );
+                        config.coalescer.record();
+                        let method = methods[0].clone();
+                        let origin = dispatch::COALESCED_ANSWER;
+                        return Ok(dispatch.local_answer(method, origin, answer));
+                    }
+                    None => println!(
+                        "[{}] + No answer to share from an identical call, dispatching {}",
+                        request_id, methods[0]
+                    ),
+                }
+            }
+        }
+    }
+    // under load the low priority requests are shed first and the high
+    // priority ones never; the slot is held until the answer is returned
+    let class = config
+        .settings
+        .priorities
+        .class(&methods, api_key.as_ref().and_then(|key| key.priority));
+    noted.priority = Some(class);
+    let Some(_slot) = config
+        .shedder
+        .admit(config.settings.load_shedding.as_ref(), class)
+        .await
+    else {
+        println!(
+            "[{}] + Shed {} priority {} under load",
+            request_id,
+            class.as_str(),
+            rpc::method_label(&methods)
+        );
+        let response = shedding::shed_response(class);
+        return Ok(dispatch.local_error(response, rpc::method_label(&methods)));
+    };
+    // journaled once the dispatch is over, whichever way it ends
+    let mut journaled = config
+        .journal
+        .as_ref()
+        .and_then(|journal| journal.submission(&body_bytes, &request_id, &client));
+    // a signature submitted moments ago is not broadcast again
+    let mut forward_one = false;
+    let submission = match dispatch.target {
+        None => dedup::submitted(&body_bytes),
+        Some(_) => None,
+    };
+    let repeated = match (&submission, &config.settings.send_dedup) {
+        (Some((signature, _)), Some(settings)) => config
+            .sends
+            .repeated(settings, signature)
+            .map(|first| (first, settings.duplicates)),
+        _ => None,
+    };
+    let signature = match (submission, repeated) {
+        (Some((signature, id)), Some((first, config::DuplicateSends::Answer))) => {
+            config.usage.record_send_duplicate(&client);
+            // answered like the first once it is settled, which takes no
+            // longer than this request may
+            let (deadline, _) =
+                effective_deadline(config.settings.deadlines.deadline(&methods), requested);
+            let settled = tokio::time::timeout_at((started + deadline).into(), first.settled());
+            match settled.await {
+                Ok(dedup::Outcome::Sent) => {
+                    println!(
+                        "[{}] + Repeated sendTransaction, answered without upstreams",
+                        request_id
+                    );
+                    if let Some(journaled) = journaled.as_mut() {
+                        journaled.repeated("answered");
+                    }
+                    let answer = dedup::answer(&signature, &id);
+                    let method = methods[0].clone();
+                    return Ok(dispatch.local_answer(method, dispatch::LOCAL_ANSWER, answer));
+                }
+                Ok(_) => {
+                    println!(
+                        "[{}] + Repeated sendTransaction, the first failed, broadcasting it",
+                        request_id
+                    );
+                    Some(signature)
+                }
+                Err(_) => {
+                    println!(
+                        "[{}] + Repeated sendTransaction, the first is still in flight at the deadline",
+                        request_id
+                    );
+                    if let Some(journaled) = journaled.as_mut() {
+                        journaled.repeated("in_flight");
+                    }
+                    let response = rpc::error_response(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        rpc::DEADLINE_EXCEEDED,
+                        "the first submission of this transaction is still in flight",
+                        None,
+                    );
+                    return Ok(dispatch.local_error(response, methods[0].clone()));
+                }
+            }
+        }
+        (Some(_), Some(_)) => {
+            config.usage.record_send_duplicate(&client);
+            println!(
+                "[{}] + Repeated sendTransaction, sending it to one upstream",
+                request_id
+            );
+            if let Some(journaled) = journaled.as_mut() {
+                journaled.repeated("forwarded");
+            }
+            forward_one = true;
+            None
+        }
+        (submission, _) => submission.map(|(signature, _)| signature),
+    };
+    if let (Some(signature), Some(settings)) = (signature, &config.settings.tx_tracking) {
+        config.transactions.track(settings, signature);
+    }
+    // answers to requests asking for a minimum slot are read in full, so
+    // that an upstream that has not reached it loses the race
+    let mut buffered = false;
+    let tip = slots::tip(&config);
+    if let (Some(margin), Some(tip)) = (config.settings.min_context_margin, tip) {
+        if let Some(body) = min_context::inject(&body_bytes, tip.saturating_sub(margin)) {
+            // buffering needs room for the answers, without it the
+            // request goes as it is
+            if config.bodies.is_low() {
+                config.bodies.record(body_budget::Degraded::Unbuffered);
+            } else {
+                body_bytes = body;
+                buffered = true;
+            }
+        }
+    }
+    // strict answers are read in full too, so a stale one can be passed over
+    let strict = config
+        .settings
+        .strict_freshness
+        .as_ref()
+        .and_then(|settings| {
+            if !settings.applies(strict_requested, &methods) {
+                return None;
+            }
+            let allowance = freshness::allowance(settings, &body_bytes)?;
+            if !buffered && config.bodies.is_low() {
+                config.bodies.record(body_budget::Degraded::Unbuffered);
+                return None;
+            }
+            buffered = true;
+            Some(allowance)
+        });
+    let mut dispatch = dispatch;
+    let targets = match dispatch
+        .route(&config, &body_bytes, &methods, &request_id)
+        .await
+    {
+        Ok(targets) => targets,
+        Err(response) => return Ok(*response),
+    };
+    let merging = match methods.as_slice() {
+        [method] if dispatch.target.is_none() => {
+            config.settings.routing.strategy(method) == config::Strategy::Merge
+        }
+        _ => false,
+    };
+    let mut targets = dispatch.narrow(
+        &config,
+        targets,
+        &methods,
+        merging,
+        forward_one,
+        &request_id,
+    );
+    // low priority requests are not hedged, nor retried
+    let low = class == config::Priority::Low;
+    let hedging = match dispatch.target.is_none() && targets.len() > 1 && !merging && !low {
+        true => config.settings.routing.hedge(&methods),
+        false => None,
+    };
+    dispatch.hedged = hedging.is_some();
+    // a hedged race asks the fastest, or best scored, first, and may ask
+    // no one else
+    let fanout = match hedging {
+        Some(policy) => {
+            match ranking::routes(&config) {
+                true => ranking::order(&config, &mut targets),
+                false => hedge::order(&config, &mut targets),
+            }
+            targets.len().min(policy.max_hedges + 1)
+        }
+        None => targets.len(),
+    } as f64;
+    if !merging {
+        config.stats.record_fanout(fanout as usize);
+    }
+    for method in &methods {
+        let cost = config.settings.costs.cost(method) * fanout;
+        config.usage.record(&client, method, cost);
+    }
+    let method = rpc::method_label(&methods);
+    tracked.set_method(&method);
+    let request_method = method.clone();
+    let (deadline, deadline_source) =
+        effective_deadline(config.settings.deadlines.deadline(&methods), requested);
+    let expiry = tokio::time::Instant::from(started + deadline);
+
+    if let Some(id) = merging.then(|| merge::call_id(&body_bytes)).flatten() {
+        let merged = merge::fan_out(
+            &config,
+            targets,
+            body_bytes,
+            &id,
+            &request_id,
+            (expiry, deadline),
+        )
+        .await;
+        dispatch.merged = Some(merged.contributors);
+        let mut response = Response::builder()
+            .status(merged.status)
+            .header("content-type", "application/json")
+            .extension(rpc::RequestMethod(request_method))
+            .body(Body::from(merged.body))
+            .unwrap();
+        dispatch.annotate(
+            &mut response,
+            dispatch::Origin::served(
+                merged
+                    .served
+                    .as_ref()
+                    .map(|(names, latency)| (names.as_str(), *latency)),
+            ),
+        );
+        if let Some((names, _)) = merged.served {
+            response.extensions_mut().insert(rpc::ServedBy(names));
+        }
+        return Ok(response);
+    }
+
+    // hosts pulled for failures get one request at a time, to tell whether
+    // they are back
+    let probe = match forward_one || dispatch.all_quarantined {
+        true => None,
+        false => dispatch.probe(&config, &targets),
+    };
+    if let Some((index, _)) = probe {
+        println!(
+            "[{}] + Probing {}, its circuit is half-open",
+            request_id, config.servers[index].name
+        );
+        targets.push(index);
+    }
+
+    let request_timeout = config.settings.routing.timeout(&methods);
+    let max_response = config.settings.response_limits.limit(&methods);
+    let hedge =
+        hedging.map(|policy| hedge::Hedge::new(policy, config.clone(), &method, &request_id));
+    // a read holds back the 5xx that would win, for its one retry to do
+    // better
+    let may_retry = match &config.settings.retry {
+        Some(settings) if settings.applies(&methods) && !low => {
+            config.retries.deposit(settings.budget_percent);
+            true
+        }
+        _ => false,
+    };
+    let early = Arc::new(AtomicBool::new(false));
+    let race = race::Race {
+        config: config.clone(),
+        dispatch,
+        request_id: request_id.clone(),
+        body: body_bytes,
+        methods,
+        method,
+        client,
+        targets,
+        probe,
+        hedge,
+        signature,
+        journaled,
+        buffered,
+        strict,
+        may_retry,
+        request_timeout,
+        max_response,
+        deadline: (deadline, deadline_source),
+        expiry,
+        early: early.clone(),
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(race.run(tx));
+
+    let Ok((status, body, served_by)) = rx.await else {
+        println!("[{}] Dispatch ended without an answer", request_id);
+        return Ok(bad_gateway(&dispatch, "no upstream answered"));
+    };
+    let body = match body {
+        streaming::AnswerBody::Stream(chunks) if denied_calls.is_empty() && split.is_none() => {
+            println!(
+                "[{}] RETURNING Response status: {:?}, body streamed",
+                request_id, status
+            );
+            streaming::body(chunks)
+        }
+        body => {
+            let mut body = match body {
+                streaming::AnswerBody::Full(body) => body,
+                // local and denied batch entries are merged into the
+                // complete answer
+                streaming::AnswerBody::Stream(chunks) => match streaming::collect(chunks).await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        println!("[{}] Upstream failed mid-response: {}", request_id, err);
+                        return Ok(bad_gateway(&dispatch, "upstream failed mid-response"));
+                    }
+                },
+            };
+            if let Some(split) = split {
+                body = batch::merge(body, split);
+            }
+            if !denied_calls.is_empty() {
+                body = methods::merge(body, denied_calls);
+            }
+            if config.settings.log_bodies {
+                println!(
+                    "[{}] RETURNING Response status: {:?}, body: {:?}",
+                    request_id,
+                    status,
+                    // borrows the bytes unless they are not valid UTF-8
+                    String::from_utf8_lossy(&body)
+                );
+            } else {
+                println!(
+                    "[{}] RETURNING Response status: {:?}, {} bytes",
+                    request_id,
+                    status,
+                    body.len()
+                );
+            }
+            Body::from(body)
+        }
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .extension(rpc::RequestMethod(request_method))
+        .body(body)
+        .unwrap();
+    if dispatch.debug && early.load(Ordering::Relaxed) {
+        response.headers_mut().insert(
+            dispatch::SOFT_DEADLINE_HEADER,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if let Some(forced) = forced {
+        let served = served_by.as_ref().is_some_and(|(name, _)| *name == forced);
+        response.headers_mut().insert(
+            dispatch::FORCED_SERVED_HEADER,
+            HeaderValue::from_static(if served { "true" } else { "false" }),
+        );
+    }
+    dispatch.annotate(
+        &mut response,
+        dispatch::Origin::served(
+            served_by
+                .as_ref()
+                .map(|(name, latency)| (name.as_str(), *latency)),
+        ),
+    );
+    if let Some(target) = target {
+        response
+            .extensions_mut()
+            .insert(dispatch::ForcedTarget(target));
+    }
+    if let Some((upstream, _)) = served_by {
+        response.extensions_mut().insert(rpc::ServedBy(upstream));
+    }
+    Ok(response)
+}
+
+/// A configured proxy. [`ProxyService::start`] launches the background tasks
+/// (warmup, slot polling, exporters); [`ProxyService::router`] serves the
+/// endpoints.
+pub struct ProxyService {
+    config: Arc<ServerConfig>,
+    /// Reloaded on SIGHUP, for services loaded from a file.
+    config_path: Option<String>,
+}
+
+impl ProxyService {
+    /// Proxy for `settings`, restoring the state saved in its `state_file`.
+    pub fn new(settings: Config) -> Self {
+        let saved = match &settings.state_file {
+            Some(path) => state::load(path),
+            None => serde_json::json!({}),
+        };
+        Self {
+            config: Arc::new(ServerConfig::new(settings, &saved)),
+            config_path: None,
+        }
+    }
+
+    /// Proxy for the config file at `path`, which is reloaded on SIGHUP.
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let mut service = Self::new(Config::load(path)?);
+        service.config_path = Some(path.to_string());
+        Ok(service)
+    }
+
+    /// The configuration in effect, secrets redacted, as `GET /admin/config`
+    /// serves it.
+    pub fn effective_config(&self) -> serde_json::Value {
+        effective::document(&self.config)
+    }
+
+    /// Port the config asks to listen on.
+    pub fn port(&self) -> u16 {
+        self.config.settings.port
+    }
+
+    /// Warm the upstream connections, then spawn the background tasks.
+    /// Without it the proxy still answers, but nothing polls the upstreams.
+    pub async fn start(&self) {
+        let config = &self.config;
+        let warmup = config.settings.warmup.clone();
+        if warmup.connections > 0 {
+            warmup::warm_all(config, &warmup).await;
+            tokio::spawn(warmup::keep_warm(config.clone(), warmup));
+        }
+        if let Some(settings) = config.settings.cluster.clone() {
+            // a host on another cluster must be known before its slots are
+            // taken for the tip
+            let timeout = settings.startup_timeout;
+            let (first_round, verified) = tokio::sync::oneshot::channel();
+            tokio::spawn(cluster::verify(config.clone(), settings, first_round));
+            if tokio::time::timeout(timeout, verified).await.is_err() {
+                println!(
+                    "+ Genesis hash check is taking over {:?}, starting without it",
+                    timeout
+                );
+            }
+        }
+        if let Some(settings) = config.settings.discovery.clone() {
+            // routing should know the upstreams before serving, but a slow
+            // one must not hold startup for long
+            let timeout = settings.startup_timeout;
+            let (first_round, probed) = tokio::sync::oneshot::channel();
+            tokio::spawn(identity::follow(config.clone(), settings.identity_interval));
+            tokio::spawn(discovery::follow(config.clone(), settings, first_round));
+            if tokio::time::timeout(timeout, probed).await.is_err() {
+                println!(
+                    "+ Upstream discovery is taking over {:?}, starting without it",
+                    timeout
+                );
+            }
+        }
+        tokio::spawn(slots::poll_slots(
+            config.clone(),
+            config.settings.slots.poll_interval,
+        ));
+        for (index, upstream) in config.settings.upstreams.iter().enumerate() {
+            if let Some(ws_url) = upstream.ws_url.clone() {
+                tokio::spawn(slot_feed::follow(config.clone(), index, ws_url));
+            }
+        }
+        if let Some(epoch_cache) = config.settings.epoch_cache.clone() {
+            tokio::spawn(epoch::refresh(config.clone(), epoch_cache));
+        }
+        if let Some(settings) = config.settings.blockhash.clone() {
+            if let Some(interval) = settings.probe_interval {
+                tokio::spawn(blockhash::probe(config.clone(), settings, interval));
+            }
+        }
+        if let Some(interval) = config.settings.dns_refresh {
+            tokio::spawn(dns::refresh(config.clone(), interval));
+        }
+        if config
+            .settings
+            .upstreams
+            .iter()
+            .any(|upstream| !upstream.maintenance.is_empty())
+        {
+            tokio::spawn(maintenance::follow(config.clone()));
+        }
+        if let Some(settings) = &config.settings.unsupported_methods {
+            tokio::spawn(unsupported::check_versions(
+                config.clone(),
+                settings.version_check_interval,
+            ));
+        }
+        if let Some(settings) = config.settings.versions.clone() {
+            tokio::spawn(versions::follow(config.clone(), settings));
+        }
+        if let Some(settings) = config.settings.ranking.clone() {
+            tokio::spawn(ranking::follow(config.clone(), settings));
+        }
+        if let Some(tx_tracking) = config.settings.tx_tracking.clone() {
+            tokio::spawn(tx::follow(config.clone(), tx_tracking));
+        }
+        if let Some(interval) = config.settings.summary_interval {
+            tokio::spawn(summary::log_summaries(config.clone(), interval));
+        }
+        if let Some(statsd) = config.settings.statsd.clone() {
+            tokio::spawn(statsd::run(config.clone(), statsd));
+        }
+        if let Some(path) = config.settings.state_file.clone() {
+            tokio::spawn(state::persist(config.clone(), path));
+        }
+        tokio::spawn(tolerance::follow(config.clone()));
+        if config.servers.iter().any(|u| u.credentials.watched()) {
+            tokio::spawn(credentials::watch(config.clone()));
+        }
+        if let Some(export) = config.settings.status_export.clone() {
+            tokio::spawn(status::export(config.clone(), export));
+        }
+        if let Some(jwt) = config.jwt.clone() {
+            tokio::spawn(jwt::refresh_jwks(jwt));
+        }
+        if let Some(path) = self.config_path.clone() {
+            tokio::spawn(reload::reload_on_sighup(config.clone(), path));
+        }
+        if let Some(mirror) = config.mirror.clone() {
+            tokio::spawn(mirror::run(mirror));
+        }
+        tokio::spawn(dump::dump_on_sigusr1(config.clone()));
+    }
+
+    /// Rewrite each mirrored record, a JSON object, before it is sent, to
+    /// remove what `redact_methods` and `redact_clients` cannot. Returns
+    /// false when no mirror is configured or a hook is already set.
+    pub fn redact_mirrored(
+        &self,
+        redact: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> bool {
+        match &self.config.mirror {
+            Some(mirror) => mirror.set_redactor(Box::new(redact)),
+            None => false,
+        }
+    }
+
+    /// The proxy and its admin endpoints, opening the access log if one is
+    /// configured. Handlers read the client address from `ConnectInfo`, so
+    /// serve it with `into_make_service_with_connect_info::<SocketAddr>()`,
+    /// or add a `MockConnectInfo` layer when calling it directly.
+    pub fn router(&self) -> Result<Router, Box<dyn std::error::Error>> {
+        let server_config = self.config.clone();
+        let access_log = match &server_config.settings.access_log {
+            Some(settings) => Some(Arc::new(
+                access_log::AccessLog::open(
+                    settings,
+                    server_config.settings.client_ip.clone(),
+                    server_config.settings.request_id_header.clone(),
+                )
+                .map_err(|e| format!("cannot open access log {}: {}", settings.path, e))?,
+            )),
+            None => None,
+        };
+        if let (Some(journal), Some(settings)) =
+            (&server_config.journal, &server_config.settings.tx_journal)
+        {
+            journal
+                .open()
+                .map_err(|e| format!("cannot open transaction journal {}: {}", settings.path, e))?;
+        }
+
+        // every admin endpoint needs an admin API key, checked once here
+        let admin = Router::new()
+            .route(
+                "/usage",
+                get(admin::usage_handler).delete(admin::reset_usage_handler),
+            )
+            .route("/divergence", get(admin::divergence_handler))
+            .route(
+                "/chaos",
+                get(chaos::list_handler)
+                    .post(chaos::inject_handler)
+                    .delete(chaos::clear_handler),
+            )
+            .route(
+                "/drain",
+                get(drain::list_handler).post(drain::drain_handler),
+            )
+            .route("/undrain", post(drain::undrain_handler))
+            .route("/revive", post(drain::revive_handler))
+            .route("/pause", post(pause::pause_handler))
+            .route("/resume", post(pause::resume_handler))
+            .route("/compare", post(compare::compare_handler))
+            .route("/unsupported", get(unsupported::list_handler))
+            .route("/costs", get(costs::costs_handler))
+            .route("/ranking", get(ranking::ranking_handler))
+            .route("/config", get(effective::config_handler))
+            .route("/tx-journal", get(journal::lookup_handler))
+            .route("/failback", post(failback::confirm_handler))
+            .route_layer(axum::middleware::from_fn_with_state(
+                server_config.clone(),
+                auth::admin_middleware,
+            ));
+
+        let mut app = Router::new()
+            .route(
+                "/",
+                post(load_balance_handler)
+                    .layer(axum::middleware::from_fn_with_state(
+                        server_config.clone(),
+                        concurrency::middleware,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        server_config.clone(),
+                        auth::middleware,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        server_config.clone(),
+                        ratelimit::ip_middleware,
+                    )),
+            )
+            .route(
+                "/tx/:signature",
+                get(tx::tx_handler)
+                    .layer(axum::middleware::from_fn_with_state(
+                        server_config.clone(),
+                        auth::middleware,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        server_config.clone(),
+                        ratelimit::ip_middleware,
+                    )),
+            )
+            .route("/status", get(status::status_handler))
+            .route("/version", get(version::version_handler))
+            .route("/slots", get(slots::slots_handler))
+            .nest("/admin", admin)
+            .layer(axum::middleware::from_fn_with_state(
+                server_config.clone(),
+                acl::middleware,
+            ))
+            // load balancer probes and scrapers come from addresses the
+            // clients' ACL has no reason to list
+            .route("/healthz", get(health::healthz_handler))
+            .route("/readyz", get(health::readyz_handler))
+            .route("/metrics", get(metrics::metrics_handler))
+            .with_state(server_config);
+        if let Some(access_log) = access_log {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                access_log,
+                access_log::middleware,
+            ));
+        }
+        Ok(app.layer(axum::middleware::from_fn_with_state(
+            self.config.clone(),
+            request_id::middleware,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::ErrorClass;
+    use crate::mock::Behavior;
+    use crate::testing::{call, proxy, serve, upstream};
+    use axum::Json;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Tests `scenario` on a current-thread runtime and on a multi-thread
+    /// one, since dispatch tasks and cancellation behave differently there.
+    macro_rules! on_both_runtimes {
+        ($scenario:ident) => {
+            mod $scenario {
+                #[tokio::test(flavor = "current_thread")]
+                async fn current_thread() {
+                    super::$scenario().await
+                }
+
+                #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+                async fn multi_thread() {
+                    super::$scenario().await
+                }
+            }
+        };
+    }
+
+    fn get_balance(id: u64) -> serde_json::Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "getBalance",
+            "params": ["11111111111111111111111111111111"],
+        })
+    }
+
+    /// Wait up to a second for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    /// Sets its flag when the upstream handler holding it is dropped
+    /// before answering.
+    struct Abandoned(Arc<AtomicBool>);
+
+    impl Drop for Abandoned {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    on_both_runtimes!(a_client_disconnecting_abandons_its_dispatch);
+    async fn a_client_disconnecting_abandons_its_dispatch() {
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let answered = Arc::new(AtomicUsize::new(0));
+        // the first request hangs for half a second, the others are answered
+        let slow = serve(Router::new().route(
+            "/",
+            post({
+                let abandoned = abandoned.clone();
+                let answered = answered.clone();
+                move |Json(request): Json<serde_json::Value>| async move {
+                    if answered.fetch_add(1, Ordering::SeqCst) == 0 {
+                        let guard = Abandoned(abandoned);
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        std::mem::forget(guard);
+                    }
+                    Json(json!({"jsonrpc": "2.0", "id": request["id"], "result": {"value": 1}}))
+                }
+            }),
+        ))
+        .await;
+        let (_, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", slow)).await;
+        let impatient = reqwest::Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let gone = impatient.post(&url).json(&get_balance(1)).send().await;
+        assert!(gone.unwrap_err().is_timeout());
+        // the upstream request is dropped well before it would be answered
+        assert!(eventually(|| abandoned.load(Ordering::SeqCst)).await);
+        let answered = call(&url, get_balance(2)).await;
+        assert_eq!(answered.status(), 200);
+        let body: serde_json::Value = answered.json().await.unwrap();
+        assert_eq!(body["id"], 2);
+        assert_eq!(body["result"]["value"], 1);
+    }
+
+    on_both_runtimes!(a_body_cut_short_fails_the_attempt);
+    async fn a_body_cut_short_fails_the_attempt() {
+        // promises a longer body than it sends, then hangs up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let truncating = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let head = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 100\r\n\r\n";
+                let _ = stream
+                    .write_all(format!("{}{{\"jsonrpc\"", head).as_bytes())
+                    .await;
+            }
+        });
+        let (config, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", truncating)).await;
+        // the answer is streamed, so its head may be out before the body
+        // breaks off: either way the client sees it break off too
+        let answer = reqwest::Client::new()
+            .post(&url)
+            .json(&get_balance(1))
+            .send()
+            .await;
+        let broken = match answer {
+            Ok(response) => {
+                assert_eq!(response.status(), 200);
+                response.bytes().await.is_err()
+            }
+            Err(_) => true,
+        };
+        assert!(broken);
+        let stats = &config.servers[0].stats;
+        assert!(eventually(|| stats.errors(ErrorClass::Other) == 1).await);
+        assert_eq!(stats.successes(), 0);
+        assert_eq!(stats.failure_streak(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_reach_the_client_byte_for_byte() {
+        const ANSWER: &str =
+            "{ \"jsonrpc\":\"2.0\",\n  \"id\": 1, \"result\": \"h\u{e9}llo \u{2713}\" }";
+        let verbatim = serve(Router::new().route(
+            "/",
+            post(|| async { ([("content-type", "application/json")], ANSWER) }),
+        ))
+        .await;
+        let (_, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", verbatim)).await;
+        let response = call(&url, get_balance(1)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap(), ANSWER.as_bytes());
+    }
+
+    on_both_runtimes!(an_empty_answer_never_wins);
+    async fn an_empty_answer_never_wins() {
+        let hollow = upstream(Behavior {
+            empty_rate: 1.0,
+            ..Behavior::default()
+        })
+        .await;
+        let slow = upstream(Behavior {
+            latency: Duration::from_millis(100),
+            ..Behavior::default()
+        })
+        .await;
+        let upstreams = format!("[[upstreams]]\nurl = \"{}\"\nname = \"hollow\"\n", hollow);
+        let (config, url) = proxy(&upstreams).await;
+        let response = call(&url, get_balance(1)).await;
+        assert_eq!(response.status(), 502);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], rpc::UPSTREAM_ERROR);
+        assert_eq!(
+            body["error"]["message"],
+            "upstream hollow answered with an empty body"
+        );
+        let stats = &config.servers[0].stats;
+        assert_eq!(stats.errors(ErrorClass::EmptyBody), 1);
+        assert_eq!(stats.failure_streak(), 1);
+        // answering first, it still loses to a slower upstream with a body
+        let (config, url) = proxy(&format!(
+            "{}\n[[upstreams]]\nurl = \"{}\"\n",
+            upstreams, slow
+        ))
+        .await;
+        let response = call(&url, get_balance(2)).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["result"]["value"], 1_000_000_000);
+        assert!(eventually(|| config.servers[0].stats.errors(ErrorClass::EmptyBody) == 1).await);
+        assert!(eventually(|| config.servers[1].stats.successes() == 1).await);
+    }
+
+    on_both_runtimes!(a_refused_request_does_not_count_against_the_upstream);
+    async fn a_refused_request_does_not_count_against_the_upstream() {
+        let refusing = serve(Router::new().route(
+            "/",
+            post(|Json(request): Json<serde_json::Value>| async move {
+                let error = json!({"code": -32600, "message": "invalid request"});
+                let body = json!({"jsonrpc": "2.0", "id": request["id"], "error": error});
+                (StatusCode::BAD_REQUEST, Json(body))
+            }),
+        ))
+        .await;
+        let (config, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", refusing)).await;
+        for id in 1..=5 {
+            let response = call(&url, get_balance(id)).await;
+            assert_eq!(response.status(), 400);
+        }
+        let stats = &config.servers[0].stats;
+        assert!(eventually(|| stats.errors(ErrorClass::Http4xx) == 5).await);
+        assert_eq!(stats.failure_streak(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_carry_a_request_id() {
+        let (_, url) = proxy(
+            "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n",
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let rejected = client
+            .post(&url)
+            .json(&get_balance(1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rejected.status(), 401);
+        let id = rejected.headers()["x-request-id"].to_str().unwrap();
+        assert_eq!(id.len(), 32);
+        let rejected = client
+            .post(&url)
+            .header("x-request-id", "client-chosen")
+            .json(&get_balance(2))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rejected.status(), 401);
+        assert_eq!(rejected.headers()["x-request-id"], "client-chosen");
+    }
+
+    on_both_runtimes!(the_deadline_answers_504_and_cancels_the_upstreams);
+    async fn the_deadline_answers_504_and_cancels_the_upstreams() {
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let hanging = serve(Router::new().route(
+            "/",
+            post({
+                let abandoned = abandoned.clone();
+                move || async move {
+                    let guard = Abandoned(abandoned);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    std::mem::forget(guard);
+                    Json(json!({"jsonrpc": "2.0", "id": 1, "result": {"value": 1}}))
+                }
+            }),
+        ))
+        .await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n\n[deadlines]\ndefault_ms = 300\ngetBalance = 200\n",
+            hanging
+        ))
+        .await;
+        let started = Instant::now();
+        let response = call(&url, get_balance(1)).await;
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(response.status(), 504);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], rpc::DEADLINE_EXCEEDED);
+        assert_eq!(body["error"]["data"]["deadline_ms"], 200);
+        assert!(eventually(|| abandoned.load(Ordering::SeqCst)).await);
+        assert_eq!(config.stats.timeouts.lock().unwrap()["getBalance"], 1);
+
+        // a client still uploading its body is bounded by the default
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nhost: proxy\r\ncontent-type: application/json\r\ncontent-length: 100\r\n\r\n{")
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let mut answer = vec![0; 1024];
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut answer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        let answer = String::from_utf8_lossy(&answer[..read]);
+        assert!(answer.starts_with("HTTP/1.1 504"), "{}", answer);
+    }
+
+    on_both_runtimes!(clients_can_only_shorten_their_deadline);
+    async fn clients_can_only_shorten_their_deadline() {
+        let hanging =
+            serve(Router::new().route("/", post(|| tokio::time::sleep(Duration::from_secs(5)))))
+                .await;
+        let (_, url) = proxy(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n\n[deadlines]\ndefault_ms = 600\n",
+            hanging
+        ))
+        .await;
+        let with_deadline = |value: &'static str| {
+            let url = url.clone();
+            async move {
+                let started = Instant::now();
+                let response = reqwest::Client::new()
+                    .post(&url)
+                    .header("x-deadline-ms", value)
+                    .json(&get_balance(1))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), 504);
+                let body: serde_json::Value = response.json().await.unwrap();
+                (
+                    body["error"]["data"]["deadline_ms"].clone(),
+                    started.elapsed(),
+                )
+            }
+        };
+        let (deadline, elapsed) = with_deadline("150").await;
+        assert_eq!(deadline, 150);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+        assert_eq!(with_deadline("100m").await.0, 100);
+        // longer than configured, or malformed, the configured one holds
+        let (deadline, elapsed) = with_deadline("60000").await;
+        assert_eq!(deadline, 600);
+        assert!(elapsed >= Duration::from_millis(600), "{:?}", elapsed);
+        assert_eq!(with_deadline("whenever").await.0, 600);
+    }
+
+    on_both_runtimes!(the_soft_deadline_bounds_the_wait_for_stragglers);
+    async fn the_soft_deadline_bounds_the_wait_for_stragglers() {
+        let hollow = upstream(Behavior {
+            empty_rate: 1.0,
+            ..Behavior::default()
+        })
+        .await;
+        let straggler = upstream(Behavior {
+            latency: Duration::from_millis(600),
+            ..Behavior::default()
+        })
+        .await;
+        let upstreams = format!(
+            "debug_headers = true\n\n[[upstreams]]\nurl = \"{}\"\nname = \"hollow\"\n\n[[upstreams]]\nurl = \"{}\"\n",
+            hollow, straggler
+        );
+        let (config, url) = proxy(&format!(
+            "{}\n[soft_deadline]\nenabled = true\nafter_first_ms = 100\n",
+            upstreams
+        ))
+        .await;
+        let started = Instant::now();
+        let response = call(&url, get_balance(1)).await;
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(response.status(), 502);
+        assert_eq!(response.headers()[dispatch::SOFT_DEADLINE_HEADER], "true");
+        assert_eq!(
+            config.stats.soft_deadline_answers.load(Ordering::Relaxed),
+            1
+        );
+        // the straggler is still read, for its slot
+        assert!(eventually(|| config.servers[1].stats.successes() == 1).await);
+        // without a soft deadline the straggler's answer is waited for
+        let (config, url) = proxy(&upstreams).await;
+        let response = call(&url, get_balance(2)).await;
+        assert_eq!(response.status(), 200);
+        assert!(response
+            .headers()
+            .get(dispatch::SOFT_DEADLINE_HEADER)
+            .is_none());
+        assert_eq!(
+            config.stats.soft_deadline_answers.load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn a_blackholed_host_is_found_out_at_the_connect_timeout() {
+        let blackhole = crate::testing::Blackhole::new().await;
+        let live = upstream(Behavior::default()).await;
+        let (config, url) = proxy(&format!(
+            "connect_timeout_ms = 200\nrequest_timeout_ms = 5000\n\n[[upstreams]]\nurl = \"{}\"\n\n[[upstreams]]\nurl = \"{}\"\n",
+            blackhole.url(),
+            live
+        ))
+        .await;
+        let started = Instant::now();
+        assert_eq!(call(&url, get_balance(1)).await.status(), 200);
+        let dead = &config.servers[0].stats;
+        // a connect timeout counts double in the streak
+        assert!(eventually(|| dead.failure_streak() == 2).await);
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(dead.errors(ErrorClass::ConnectTimeout), 1);
+    }
+
+    #[tokio::test]
+    async fn upstreams_go_by_their_names_not_their_urls() {
+        let live = upstream(Behavior::default()).await;
+        let (config, url) = proxy(&format!(
+            "debug_headers = true\n\n[[upstreams]]\nname = \"helius-1\"\nurl = \"{}/?api-key=hunter2\"\n\n[[upstreams]]\nurl = \"http://127.0.0.1:1/?api-key=hunter2\"\n",
+            live
+        ))
+        .await;
+        // named after its host when not given a name
+        assert_eq!(config.servers[1].name, "127.0.0.1");
+        let response = call(&url, get_balance(1)).await;
+        assert_eq!(response.headers()[dispatch::UPSTREAM_HEADER], "helius-1");
+        // nor in the errors of one that cannot be reached
+        let (_, unreachable) = proxy(
+            "debug_headers = true\n\n[[upstreams]]\nurl = \"http://127.0.0.1:1/?api-key=hunter2\"\n",
+        )
+        .await;
+        let refused = call(&unreachable, get_balance(2))
+            .await
+            .text()
+            .await
+            .unwrap();
+        assert!(refused.contains("127.0.0.1"), "{}", refused);
+        assert!(!refused.contains("hunter2"), "{}", refused);
+
+        let get = |path: &'static str| {
+            let url = url.clone();
+            async move {
+                reqwest::get(format!("{}{}", url, path))
+                    .await
+                    .unwrap()
+                    .text()
+                    .await
+                    .unwrap()
+            }
+        };
+        let metrics = get("/metrics").await;
+        assert!(metrics.contains("quarantier_upstream_successes_total{upstream=\"helius-1\"} 1\n"));
+        assert!(metrics.contains("{upstream=\"127.0.0.1\",class=\"connect\"} 1\n"));
+        assert!(!metrics.contains("hunter2"));
+        let status = get("/status").await;
+        assert!(status.contains("\"name\":\"helius-1\""));
+        assert!(!status.contains("hunter2"));
+        // the admin API takes names
+        let drain = |host: String| {
+            reqwest::Client::new()
+                .post(format!("{}/admin/drain", url))
+                .json(&json!({ "host": host, "force": true }))
+                .send()
+        };
+        let unknown = drain(format!("{}/?api-key=hunter2", live)).await.unwrap();
+        assert_eq!(unknown.status(), 400);
+        assert_eq!(drain("helius-1".to_string()).await.unwrap().status(), 200);
+        assert!(config.servers[0].is_drained());
+    }
+}
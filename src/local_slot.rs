@@ -0,0 +1,129 @@
+//! Local answers to `getSlot`, the heartbeat of countless services, from
+//! the slot tracker's tip while its freshest observation is recent. The
+//! tip follows the poller, the WebSocket feeds and the context slots of
+//! responses; each commitment is answered a configured offset below it.
+
+use crate::config::LocalGetSlotConfig;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Commitments in the order of `LocalGetSlotConfig::offsets`.
+pub const COMMITMENTS: [&str; 3] = ["processed", "confirmed", "finalized"];
+
+#[derive(Default)]
+pub struct LocalSlots {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl LocalSlots {
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// The slot to answer for `params`, or `None` to leave the call to the
+/// upstreams: options other than the commitment and `minContextSlot`, a
+/// stale tip, or a tip below the minimum asked for.
+fn slot(config: &ServerConfig, settings: &LocalGetSlotConfig, params: &Value) -> Option<u64> {
+    let options = match params {
+        Value::Null => params,
+        Value::Array(params) if params.is_empty() => &Value::Null,
+        Value::Array(params) if params.len() == 1 && params[0].is_object() => &params[0],
+        _ => return None,
+    };
+    let known = |key: &String| key == "commitment" || key == "minContextSlot";
+    if options.as_object().is_some_and(|o| !o.keys().all(known)) {
+        return None;
+    }
+    // finalized is the default, as on the validator
+    let commitment = options["commitment"].as_str().unwrap_or("finalized");
+    let level = COMMITMENTS.iter().position(|c| *c == commitment)?;
+    let tip = crate::slots::recent_tip(config, settings.max_age)?;
+    let slot = tip.checked_sub(settings.offsets[level])?;
+    // the upstreams answer it with the proper error
+    if options["minContextSlot"]
+        .as_u64()
+        .is_some_and(|min| min > slot)
+    {
+        return None;
+    }
+    Some(slot)
+}
+
+/// A local answer to `body` when it is a single `getSlot` call the tip can
+/// serve.
+pub fn answer(config: &ServerConfig, body: &[u8]) -> Option<String> {
+    let settings = config.settings.local_get_slot.as_ref()?;
+    let request: Value = serde_json::from_slice(body).ok()?;
+    if !request.is_object() {
+        return None;
+    }
+    let result = slot(config, settings, &request["params"]);
+    config.local_slots.count(result.is_some());
+    Some(json!({ "jsonrpc": "2.0", "result": result?, "id": request["id"] }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::UPSTREAM_HEADER;
+    use crate::slots::SlotSource;
+    use crate::testing::{call, proxy};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn get_slot_is_answered_from_a_recent_tip() {
+        let (config, url) = proxy(
+            "debug_headers = true\n\n[local_get_slot]\nenabled = true\nmax_age_ms = 300\nconfirmed_offset = 2\nfinalized_offset = 32\n\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n",
+        )
+        .await;
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        let get_slot = |params: Value| {
+            call(
+                &url,
+                json!({ "jsonrpc": "2.0", "id": 3, "method": "getSlot", "params": params }),
+            )
+        };
+        for (params, slot) in [
+            (json!([]), 968),
+            (json!([{ "commitment": "confirmed" }]), 998),
+            (
+                json!([{ "commitment": "processed", "minContextSlot": 1000 }]),
+                1000,
+            ),
+        ] {
+            let response = get_slot(params).await;
+            assert_eq!(response.headers()[UPSTREAM_HEADER], "local");
+            let answer: Value = response.json().await.unwrap();
+            assert_eq!(answer, json!({ "jsonrpc": "2.0", "result": slot, "id": 3 }));
+        }
+        assert_eq!(config.local_slots.hits(), 3);
+
+        // calls the tip cannot answer go to the upstreams
+        for params in [
+            json!([{ "commitment": "processed", "minContextSlot": 1001 }]),
+            json!([{ "commitment": "recent" }]),
+            json!([{ "commitment": "processed", "dataSlice": {} }]),
+        ] {
+            let response = get_slot(params).await;
+            assert_ne!(response.headers()[UPSTREAM_HEADER], "local");
+        }
+        // and every call, once the tip is older than max_age_ms
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let response = get_slot(json!([])).await;
+        assert_ne!(response.headers()[UPSTREAM_HEADER], "local");
+        assert_eq!(config.local_slots.hits(), 3);
+        assert_eq!(config.local_slots.misses(), 4);
+    }
+}
@@ -0,0 +1,283 @@
+//! Recurring maintenance windows, declared per upstream in UTC. Shortly
+//! before a window starts the upstream is drained, and while it lasts the
+//! failures of a restarting node neither open its circuit nor put it in the
+//! slot-lag quarantine, so planned restarts stay out of the logs and
+//! alerts. Once the window is over the upstream stays out until it is
+//! healthy again. A manual drain holds regardless, see `drain`.
+
+use crate::config::MaintenanceWindow;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often windows are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// In service.
+    Idle,
+    /// Drained, from the lead before a window until its end.
+    Window,
+    /// Drained after a window until it answers healthily again.
+    Returning,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Idle => "idle",
+            Phase::Window => "window",
+            Phase::Returning => "returning",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    phase: AtomicU8,
+}
+
+impl Maintenance {
+    pub fn phase(&self) -> Phase {
+        match self.phase.load(Ordering::Relaxed) {
+            1 => Phase::Window,
+            2 => Phase::Returning,
+            _ => Phase::Idle,
+        }
+    }
+
+    fn set(&self, phase: Phase) {
+        self.phase.store(phase as u8, Ordering::Relaxed);
+    }
+
+    /// Whether the upstream gets no new requests.
+    pub fn excludes(&self) -> bool {
+        self.phase() != Phase::Idle
+    }
+
+    /// Whether its failures are expected and not held against it.
+    pub fn is_in_window(&self) -> bool {
+        self.phase() == Phase::Window
+    }
+}
+
+/// The occurrence of `window` in progress at `now`, or else the next one,
+/// as Unix seconds from its start to its end.
+pub fn occurrence(window: &MaintenanceWindow, now: u64) -> (u64, u64) {
+    let today = now / 86_400;
+    let mut days = today.saturating_sub(1)..=today + 7;
+    days.find_map(|day| {
+        // 1970-01-01 was a Thursday
+        let weekday = ((day + 3) % 7) as u32;
+        if window.weekday.is_some_and(|w| w != weekday) {
+            return None;
+        }
+        let start = day * 86_400 + window.start;
+        let end = start + window.duration.as_secs();
+        (end > now).then_some((start, end))
+    })
+    .expect("windows recur at least weekly")
+}
+
+/// Whether the upstream answers as a host in service would: no failures,
+/// its circuit closed and a fresh slot within tolerance of the tip.
+fn is_healthy(config: &ServerConfig, index: usize, quarantine: &[usize]) -> bool {
+    let upstream = &config.servers[index];
+    if upstream.is_failing() || quarantine.contains(&index) {
+        return false;
+    }
+    let latest = crate::slots::tip(config).unwrap_or_default();
+    config.slots.fresh()[index].is_some_and(|o| o.slot + config.tolerance.slots() >= latest)
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Move the upstreams with maintenance windows in and out of them.
+fn check(config: &ServerConfig, quarantine: &[usize], now: u64) -> bool {
+    let lead = config.settings.maintenance_lead.as_secs();
+    let mut changed = false;
+    for (index, upstream) in config.servers.iter().enumerate() {
+        let windows = &config.settings.upstreams[index].maintenance;
+        let due = windows
+            .iter()
+            .map(|window| occurrence(window, now))
+            .filter(|&(start, _)| start.saturating_sub(lead) <= now)
+            .max_by_key(|&(_, end)| end);
+        let state = &upstream.maintenance;
+        match (state.phase(), due) {
+            (Phase::Idle | Phase::Returning, Some((start, end))) => {
+                state.set(Phase::Window);
+                log(
+                    config,
+                    format!(
+                        "{} drained for maintenance from {} to {}",
+                        upstream.name,
+                        crate::clock::rfc3339(start * 1000),
+                        crate::clock::rfc3339(end * 1000)
+                    ),
+                );
+            }
+            (Phase::Window, None) => {
+                state.set(Phase::Returning);
+                // failures in the window are not held against it afterwards
+                upstream.stats.clear_window();
+                log(
+                    config,
+                    format!(
+                        "{} maintenance window over, back once healthy",
+                        upstream.name
+                    ),
+                );
+            }
+            (Phase::Returning, None) if is_healthy(config, index, quarantine) => {
+                state.set(Phase::Idle);
+                log(config, format!("{} back from maintenance", upstream.name));
+            }
+            _ => continue,
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Check the maintenance windows every second, forever.
+pub async fn follow(config: Arc<ServerConfig>) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let quarantine = config.quarantine.read().await.clone();
+        if check(&config, &quarantine, crate::clock::unix_secs()) {
+            config.readiness.update(&config).await;
+        }
+    }
+}
+
+/// Phase and windows of upstream `index`, with the current or next
+/// occurrence of each, for `/status`.
+pub fn report(config: &ServerConfig, index: usize) -> Value {
+    let now = crate::clock::unix_secs();
+    let windows: Vec<Value> = config.settings.upstreams[index]
+        .maintenance
+        .iter()
+        .map(|window| {
+            let (start, end) = occurrence(window, now);
+            json!({
+                "weekday": match window.weekday {
+                    Some(weekday) => crate::config::WEEKDAYS[weekday as usize],
+                    None => "daily",
+                },
+                "start": format!("{:02}:{:02}", window.start / 3600, window.start % 3600 / 60),
+                "duration_mins": window.duration.as_secs() / 60,
+                "next_start": crate::clock::rfc3339(start * 1000),
+                "next_end": crate::clock::rfc3339(end * 1000),
+                "active": start <= now,
+            })
+        })
+        .collect();
+    json!({
+        "phase": config.servers[index].maintenance.phase().as_str(),
+        "windows": windows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::ErrorClass;
+    use crate::slots::SlotSource;
+
+    /// Tuesday 2026-10-13, 03:00 UTC.
+    const TUESDAY_3AM: u64 = 1_791_860_400;
+
+    fn window(weekday: Option<u32>, start: u64, minutes: u64) -> MaintenanceWindow {
+        MaintenanceWindow {
+            weekday,
+            start,
+            duration: Duration::from_secs(minutes * 60),
+        }
+    }
+
+    #[test]
+    fn occurrences_recur_on_their_day() {
+        let tuesdays = window(Some(1), 3 * 3600, 30);
+        let this_week = (TUESDAY_3AM, TUESDAY_3AM + 1800);
+        assert_eq!(occurrence(&tuesdays, TUESDAY_3AM - 3600), this_week);
+        assert_eq!(occurrence(&tuesdays, TUESDAY_3AM + 600), this_week);
+        let next_week = (TUESDAY_3AM + 7 * 86_400, TUESDAY_3AM + 7 * 86_400 + 1800);
+        assert_eq!(occurrence(&tuesdays, TUESDAY_3AM + 1800), next_week);
+        // a window past midnight is still in progress the next day
+        let nightly = window(None, 23 * 3600 + 1800, 60);
+        let monday_night = TUESDAY_3AM - 3 * 3600 - 1800;
+        assert_eq!(
+            occurrence(&nightly, TUESDAY_3AM - 3 * 3600 + 900),
+            (monday_night, monday_night + 3600)
+        );
+    }
+
+    #[tokio::test]
+    async fn windows_drain_spare_and_return_the_upstream() {
+        let config = crate::testing::config(
+            "[maintenance]\ndrain_before_secs = 120\n\n[[upstreams]]\nname = \"own\"\nurl = \"http://127.0.0.1:1\"\n[[upstreams.maintenance]]\nweekday = \"tue\"\nstart = \"03:00\"\nduration_mins = 30\n\n[[upstreams]]\nurl = \"http://127.0.0.1:2\"\n",
+        );
+        let upstream = &config.servers[0];
+        let phase = || upstream.maintenance.phase().as_str();
+        assert!(!check(&config, &[], TUESDAY_3AM - 121));
+        assert_eq!(phase(), "idle");
+        // drained a little ahead of the window
+        assert!(check(&config, &[], TUESDAY_3AM - 120));
+        assert_eq!(phase(), "window");
+        assert!(upstream.is_drained());
+        assert!(!config.servers[1].is_drained());
+        // a restarting node's failures are expected
+        for _ in 0..5 {
+            upstream.stats.record_error(ErrorClass::Http5xx);
+        }
+        crate::quarantine::reevaluate(&config, "test").await;
+        assert!(upstream.circuit.is_closed());
+        // an admin drain outlasts the window
+        upstream.drained.store(true, Ordering::Relaxed);
+
+        assert!(check(&config, &[], TUESDAY_3AM + 1800));
+        assert_eq!(phase(), "returning");
+        assert!(!check(&config, &[], TUESDAY_3AM + 1801));
+        upstream.stats.record_success();
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config.slots.observe(1, 1000, SlotSource::Poll);
+        // nor does a quarantine let it back in
+        assert!(!check(&config, &[0], TUESDAY_3AM + 1802));
+        assert!(check(&config, &[], TUESDAY_3AM + 1803));
+        assert_eq!(phase(), "idle");
+        assert!(upstream.is_drained());
+        upstream.drained.store(false, Ordering::Relaxed);
+        assert!(!upstream.is_drained());
+
+        let events: Vec<String> = config
+            .events
+            .recent()
+            .into_iter()
+            .map(|e| e.1)
+            .filter(|e| e.starts_with("own "))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "own drained for maintenance from 2026-10-13T03:00:00.000Z to 2026-10-13T03:30:00.000Z",
+                "own maintenance window over, back once healthy",
+                "own back from maintenance",
+            ]
+        );
+        let reported = report(&config, 0);
+        assert_eq!(reported["phase"], "idle");
+        assert_eq!(reported["windows"][0]["weekday"], "tue");
+        assert_eq!(reported["windows"][0]["start"], "03:00");
+        assert_eq!(reported["windows"][0]["duration_mins"], 30);
+        assert_eq!(report(&config, 1)["windows"], json!([]));
+    }
+}
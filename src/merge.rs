@@ -0,0 +1,412 @@
+//! The `merge` routing strategy for `getRecentPrioritizationFees`. Each node
+//! only knows the fees of the blocks it has seen, so the race winner's
+//! answer can miss the slots where fees spiked. Every healthy upstream is
+//! asked instead, and their answers are combined slot by slot, keeping the
+//! highest fee seen for each.
+
+use crate::classify::{classify_attempt, classify_response};
+use crate::streaming::Read;
+use crate::ServerConfig;
+use axum::body::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Slots a node keeps recent fees for; older slots in a merged answer are
+/// left out, as no single node would return them.
+const WINDOW: u64 = 150;
+
+/// Tells how many upstream answers were merged into the response.
+pub const MERGED_HEADER: &str = "x-quarantier-merged-upstreams";
+
+pub struct Merged {
+    pub status: u16,
+    pub body: Bytes,
+    /// Names of the contributing upstreams, and the time until the answer
+    /// was ready; `None` when the proxy generated the response.
+    pub served: Option<(String, Duration)>,
+    /// Upstream answers merged into `body`, 0 when it is a single answer
+    /// passed on unmerged.
+    pub contributors: usize,
+}
+
+/// The id of a single call, or `None` for a batch or anything unparsable.
+pub fn call_id(body: &[u8]) -> Option<Value> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    request.is_object().then(|| request["id"].clone())
+}
+
+/// Slot and fee pairs of a `getRecentPrioritizationFees` answer, or `None`
+/// when the answer is not one.
+fn fees(body: &[u8]) -> Option<Vec<(u64, u64)>> {
+    let answer: Value = serde_json::from_slice(body).ok()?;
+    answer
+        .get("result")?
+        .as_array()?
+        .iter()
+        .map(|entry| {
+            Some((
+                entry.get("slot")?.as_u64()?,
+                entry.get("prioritizationFee")?.as_u64()?,
+            ))
+        })
+        .collect()
+}
+
+/// Union of `answers` by slot with the highest fee of each, in slot order,
+/// within the window ending at the newest slot.
+fn combine(answers: &[Vec<(u64, u64)>]) -> Vec<(u64, u64)> {
+    let mut by_slot = BTreeMap::new();
+    for &(slot, fee) in answers.iter().flatten() {
+        let highest = by_slot.entry(slot).or_insert(fee);
+        *highest = (*highest).max(fee);
+    }
+    let newest = by_slot.keys().next_back().copied().unwrap_or_default();
+    by_slot
+        .into_iter()
+        .filter(|(slot, _)| slot + WINDOW > newest)
+        .collect()
+}
+
+/// Send `body` to upstream `index`, returning the answer when it is a
+/// success. Failures are counted like those of raced requests.
+async fn ask(
+    config: &ServerConfig,
+    index: usize,
+    body: Bytes,
+    request_id: &str,
+) -> (usize, Option<(u16, Bytes)>) {
+    let upstream = &config.servers[index];
+    if !upstream.take_token() {
+        println!(
+            "[{}] + Not sending to {}, it is at its outbound rate limit",
+            request_id, upstream.name
+        );
+        return (index, None);
+    }
+    let methods = crate::rpc::request_methods(&body);
+    let request_timeout = config.settings.routing.timeout(&methods);
+    let limit = config.settings.response_limits.limit(&methods);
+    let charge = || crate::costs::charge(config, index, methods.iter().map(String::as_str));
+    upstream.stats.record_request();
+    let chaos = config.chaos.effects(index);
+    tokio::time::sleep(chaos.delay).await;
+    let sent = Instant::now();
+    let response = if chaos.fail {
+        println!(
+            "[{}] + CHAOS: injecting a failure on {}",
+            request_id, upstream.name
+        );
+        Ok((503, crate::chaos::failure_body()))
+    } else {
+        // charged when answered without `count_aborted`, as in races
+        let count_aborted = config.settings.costs.count_aborted;
+        if count_aborted {
+            charge();
+        }
+        let mut request = upstream
+            .post()
+            .body(body)
+            .header("Content-Type", "application/json")
+            .header(config.settings.request_id_header.as_str(), request_id);
+        if let Some(timeout) = request_timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await;
+        if !count_aborted {
+            charge();
+        }
+        match response {
+            Ok(response) => {
+                upstream.stats.record_latency(sent.elapsed());
+                upstream
+                    .limits
+                    .observe(&upstream.name, response.headers(), &config.events);
+                let status = response.status().as_u16();
+                let read = match crate::streaming::oversize(&response, limit) {
+                    true => Ok(Read::Oversize),
+                    false => {
+                        crate::streaming::read_body(
+                            response,
+                            Vec::new(),
+                            None,
+                            &config.bodies,
+                            limit,
+                        )
+                        .await
+                    }
+                };
+                match read {
+                    Ok(Read::Kept(body)) => Ok((status, body)),
+                    Ok(Read::Unkept) => {
+                        println!(
+                            "[{}] + Body from {} is over the memory budget, not merged",
+                            request_id, upstream.name
+                        );
+                        return (index, None);
+                    }
+                    Ok(Read::Oversize) => {
+                        config
+                            .stats
+                            .record_oversize(&crate::rpc::method_label(&methods));
+                        println!(
+                            "[{}] + Body from {} is over the response size limit, not merged",
+                            request_id, upstream.name
+                        );
+                        return (index, None);
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    };
+    match response {
+        Ok((status, body)) => {
+            let json = crate::envelope::inspect(&body).json;
+            match classify_response(status, &body, json.as_ref()) {
+                Some(class) => {
+                    println!(
+                        "[{}] + Response from {} classified as {}",
+                        request_id,
+                        upstream.name,
+                        class.as_str()
+                    );
+                    upstream.stats.record_error(class);
+                    (index, None)
+                }
+                None => {
+                    upstream.stats.record_success();
+                    (index, Some((status, body)))
+                }
+            }
+        }
+        Err(err) => {
+            let class = classify_attempt(&err, request_timeout);
+            upstream.stats.record_error(class);
+            println!(
+                "[{}] Request to {} failed ({}): {:?}",
+                request_id,
+                upstream.name,
+                class.as_str(),
+                err
+            );
+            (index, None)
+        }
+    }
+}
+
+/// Ask every upstream in `targets` and merge their answers. After the
+/// first usable answer the others get `merge_wait` more, within the
+/// request deadline; when no answer can be merged, the first successful
+/// one is returned as it is.
+pub async fn fan_out(
+    config: &ServerConfig,
+    targets: Vec<usize>,
+    body: Bytes,
+    id: &Value,
+    request_id: &str,
+    deadline: (tokio::time::Instant, Duration),
+) -> Merged {
+    let started = Instant::now();
+    let (expiry, deadline) = deadline;
+    let mut pending: FuturesUnordered<_> = targets
+        .into_iter()
+        .map(|index| ask(config, index, body.clone(), request_id))
+        .collect();
+    let mut until = expiry;
+    let mut contributors = Vec::new();
+    let mut answers = Vec::new();
+    let mut first = None;
+    let mut expired = false;
+    loop {
+        let (index, answer) = match tokio::time::timeout_at(until, pending.next()).await {
+            Ok(Some(next)) => next,
+            Ok(None) => break,
+            Err(_) => {
+                expired = until == expiry;
+                break;
+            }
+        };
+        let Some((status, body)) = answer else {
+            continue;
+        };
+        let name = &config.servers[index].name;
+        match fees(&body) {
+            Some(fees) => {
+                if answers.is_empty() {
+                    until = expiry
+                        .min(tokio::time::Instant::now() + config.settings.routing.merge_wait);
+                }
+                contributors.push(name.clone());
+                answers.push(fees);
+            }
+            None => println!(
+                "[{}] + Answer from {} has no prioritization fees, not merging it",
+                request_id, name
+            ),
+        }
+        first.get_or_insert((status, body, name.clone()));
+    }
+    if !pending.is_empty() {
+        println!(
+            "[{}] + Merging without {} upstreams that did not answer in time",
+            request_id,
+            pending.len()
+        );
+    }
+    drop(pending);
+    crate::quarantine::reevaluate(config, request_id).await;
+
+    if !answers.is_empty() {
+        let result: Vec<Value> = combine(&answers)
+            .into_iter()
+            .map(|(slot, fee)| json!({ "slot": slot, "prioritizationFee": fee }))
+            .collect();
+        println!(
+            "[{}] + Merged {} slots from {}",
+            request_id,
+            result.len(),
+            contributors.join(", ")
+        );
+        let body = json!({ "jsonrpc": "2.0", "result": result, "id": id });
+        return Merged {
+            status: 200,
+            body: body.to_string().into(),
+            served: Some((contributors.join(","), started.elapsed())),
+            contributors: contributors.len(),
+        };
+    }
+    if let Some((status, body, name)) = first {
+        return Merged {
+            status,
+            body,
+            served: Some((name, started.elapsed())),
+            contributors: 0,
+        };
+    }
+    let (status, body) = if expired {
+        println!(
+            "[{}] + Deadline of {:?} exceeded, abandoning upstream requests",
+            request_id, deadline
+        );
+        config.stats.record_timeout("getRecentPrioritizationFees");
+        (504, crate::deadline_body(deadline))
+    } else {
+        let body = crate::rpc::error_body(crate::rpc::UPSTREAM_ERROR, "no upstream answered", None);
+        (502, body.into())
+    };
+    Merged {
+        status,
+        body,
+        served: None,
+        contributors: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, serve};
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use axum::{routing::post, Json, Router};
+
+    /// An upstream answering every call with `result`, or with a 503 when
+    /// it is `None`.
+    async fn upstream(result: Option<Value>) -> String {
+        serve(Router::new().route(
+            "/",
+            post(move |Json(call): Json<Value>| async move {
+                match result {
+                    Some(result) => {
+                        Json(json!({ "jsonrpc": "2.0", "result": result, "id": call["id"] }))
+                            .into_response()
+                    }
+                    None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+                }
+            }),
+        ))
+        .await
+    }
+
+    fn entries(fees: &[(u64, u64)]) -> Value {
+        fees.iter()
+            .map(|(slot, fee)| json!({ "slot": slot, "prioritizationFee": fee }))
+            .collect()
+    }
+
+    #[test]
+    fn the_highest_fee_of_each_slot_is_kept() {
+        let a = vec![(100, 5), (101, 0), (102, 7)];
+        let b = vec![(101, 3), (102, 2), (103, 1)];
+        assert_eq!(combine(&[a, b]), [(100, 5), (101, 3), (102, 7), (103, 1)]);
+    }
+
+    #[test]
+    fn slots_before_the_window_are_left_out() {
+        let old = vec![(10, 99), (249, 1)];
+        let new = vec![(250, 2), (398, 3)];
+        assert_eq!(combine(&[old, new]), [(249, 1), (250, 2), (398, 3)]);
+        assert_eq!(combine(&[]), []);
+    }
+
+    #[test]
+    fn only_fee_lists_are_merged() {
+        let answer = json!({ "result": entries(&[(1, 2)]) }).to_string();
+        assert_eq!(fees(answer.as_bytes()), Some(vec![(1, 2)]));
+        let malformed = json!({ "result": [{ "slot": 1 }] }).to_string();
+        assert_eq!(fees(malformed.as_bytes()), None);
+        assert_eq!(fees(br#"{"result":5}"#), None);
+        assert_eq!(fees(b"<html>"), None);
+        assert_eq!(call_id(br#"{"id":"f","method":"x"}"#), Some(json!("f")));
+        assert_eq!(call_id(br#"[{"id":1,"method":"x"}]"#), None);
+    }
+
+    #[tokio::test]
+    async fn every_upstream_answer_goes_into_the_merge() {
+        let mut upstreams = String::new();
+        for result in [
+            Some(entries(&[(100, 5), (101, 0)])),
+            Some(entries(&[(101, 3), (102, 1)])),
+            Some(json!("not fees")),
+            None,
+        ] {
+            let url = upstream(result).await;
+            upstreams += &format!("[[upstreams]]\nurl = \"{}\"\n", url);
+        }
+        let (config, url) = proxy(&format!(
+            "debug_headers = true\n{}[routing]\ngetRecentPrioritizationFees = \"merge\"\nmerge_wait_ms = 1000\n",
+            upstreams
+        ))
+        .await;
+        let request = json!({ "jsonrpc": "2.0", "id": 9, "method": "getRecentPrioritizationFees" });
+        let response = call(&url, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[MERGED_HEADER], "2");
+        let answer: Value = response.json().await.unwrap();
+        assert_eq!(answer["id"], 9);
+        assert_eq!(answer["result"], entries(&[(100, 5), (101, 3), (102, 1)]));
+        // the failing upstream is counted like in a race
+        assert_eq!(config.servers[3].stats.successes(), 0);
+        assert_eq!(config.servers[2].stats.successes(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_single_usable_answer_is_passed_on_unmerged() {
+        let not_fees = upstream(Some(json!("not fees"))).await;
+        let failing = upstream(None).await;
+        let (_, url) = proxy(&format!(
+            "debug_headers = true\n[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n[routing]\ngetRecentPrioritizationFees = \"merge\"\n",
+            not_fees, failing
+        ))
+        .await;
+        let request = json!({ "jsonrpc": "2.0", "id": 9, "method": "getRecentPrioritizationFees" });
+        let response = call(&url, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[MERGED_HEADER], "0");
+        let answer: Value = response.json().await.unwrap();
+        assert_eq!(answer["result"], "not fees");
+    }
+}
@@ -0,0 +1,267 @@
+//! Method allow and deny lists, enforced per batch element.
+
+use axum::body::Bytes;
+use serde_json::{json, Value};
+
+/// JSON-RPC code of calls to methods that are not allowed.
+const METHOD_NOT_ALLOWED: i64 = -32601;
+
+#[derive(Clone, Default)]
+pub struct MethodPolicy {
+    /// Methods allowed; empty allows all.
+    pub allow: Vec<String>,
+    /// Methods always rejected, even when also allowed.
+    pub deny: Vec<String>,
+}
+
+impl MethodPolicy {
+    pub fn permits(&self, method: &str) -> bool {
+        let listed = |methods: &[String]| methods.iter().any(|m| m == method);
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+pub enum Verdict {
+    /// Forward the body unchanged.
+    Allowed,
+    /// Nothing may be forwarded; answer with this body.
+    Denied { response: Value, denied: usize },
+    /// Forward `body`, the allowed part of a batch, and add `errors` to the
+    /// upstream's answer.
+    Partial { body: Vec<u8>, errors: Vec<Value> },
+}
+
+fn not_allowed(request: &Value) -> Value {
+    let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+    json!({
+        "jsonrpc": "2.0",
+        "id": request.get("id").cloned().unwrap_or(Value::Null),
+        "error": {
+            "code": METHOD_NOT_ALLOWED,
+            "message": format!("method '{}' is not allowed", method),
+        },
+    })
+}
+
+/// Apply `permits` to every call in a request body. Bodies that are not
+/// JSON-RPC are left for the upstreams to reject.
+pub fn enforce(body: &[u8], permits: impl Fn(&str) -> bool) -> Verdict {
+    let allowed = |request: &Value| match request.get("method").and_then(Value::as_str) {
+        Some(method) => permits(method),
+        None => true,
+    };
+    match serde_json::from_slice::<Value>(body) {
+        Ok(request @ Value::Object(_)) if !allowed(&request) => Verdict::Denied {
+            response: not_allowed(&request),
+            denied: 1,
+        },
+        Ok(Value::Array(batch)) if !batch.iter().all(allowed) => {
+            let (permitted, rejected): (Vec<Value>, Vec<Value>) =
+                batch.into_iter().partition(|request| allowed(request));
+            let errors: Vec<Value> = rejected.iter().map(not_allowed).collect();
+            if permitted.is_empty() {
+                Verdict::Denied {
+                    denied: errors.len(),
+                    response: Value::Array(errors),
+                }
+            } else {
+                Verdict::Partial {
+                    body: Value::Array(permitted).to_string().into_bytes(),
+                    errors,
+                }
+            }
+        }
+        _ => Verdict::Allowed,
+    }
+}
+
+/// Add the answers to rejected batch elements to an upstream's batch answer.
+pub fn merge(body: Bytes, errors: Vec<Value>) -> Bytes {
+    match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(mut responses)) => {
+            responses.extend(errors);
+            Value::Array(responses).to_string().into()
+        }
+        _ => body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{proxy, serve};
+    use axum::{routing::post, Json, Router};
+
+    fn policy(allow: &[&str], deny: &[&str]) -> MethodPolicy {
+        MethodPolicy {
+            allow: allow.iter().map(|m| m.to_string()).collect(),
+            deny: deny.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    fn call_of(id: u64, method: &str) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method })
+    }
+
+    fn enforce_json(body: Value, policy: &MethodPolicy) -> Verdict {
+        enforce(body.to_string().as_bytes(), |method| policy.permits(method))
+    }
+
+    #[test]
+    fn denied_methods_win_over_allowed_ones() {
+        assert!(MethodPolicy::default().permits("getSlot"));
+        assert!(MethodPolicy::default().is_empty());
+        let policy = policy(&["getSlot", "sendTransaction"], &["sendTransaction"]);
+        assert!(policy.permits("getSlot"));
+        assert!(!policy.permits("sendTransaction"));
+        assert!(!policy.permits("getBalance"));
+    }
+
+    #[test]
+    fn allowed_calls_are_forwarded_unchanged() {
+        let policy = policy(&[], &["requestAirdrop"]);
+        let single = enforce_json(call_of(1, "getSlot"), &policy);
+        assert!(matches!(single, Verdict::Allowed));
+        let batch = json!([call_of(1, "getSlot"), call_of(2, "getBalance")]);
+        assert!(matches!(enforce_json(batch, &policy), Verdict::Allowed));
+        // not JSON-RPC: left for the upstreams to reject
+        let garbage = enforce(b"{not json", |_| false);
+        assert!(matches!(garbage, Verdict::Allowed));
+    }
+
+    #[test]
+    fn denied_calls_get_method_not_found() {
+        let policy = policy(&[], &["requestAirdrop"]);
+        let Verdict::Denied { response, denied } =
+            enforce_json(call_of(7, "requestAirdrop"), &policy)
+        else {
+            panic!("not denied");
+        };
+        assert_eq!(denied, 1);
+        assert_eq!(
+            response,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 7,
+                "error": {
+                    "code": -32601,
+                    "message": "method 'requestAirdrop' is not allowed",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn a_batch_of_denied_calls_is_answered_whole() {
+        let policy = policy(&["getSlot"], &[]);
+        let batch = json!([call_of(1, "getBalance"), call_of(2, "requestAirdrop")]);
+        let Verdict::Denied { response, denied } = enforce_json(batch, &policy) else {
+            panic!("not denied");
+        };
+        assert_eq!(denied, 2);
+        let errors = response.as_array().unwrap();
+        assert_eq!(errors[0]["id"], 1);
+        assert_eq!(errors[1]["id"], 2);
+        assert!(errors.iter().all(|e| e["error"]["code"] == -32601));
+    }
+
+    #[test]
+    fn a_partly_denied_batch_forwards_the_rest() {
+        let policy = policy(&[], &["requestAirdrop"]);
+        let batch = json!([
+            call_of(1, "getSlot"),
+            call_of(2, "requestAirdrop"),
+            call_of(3, "getBalance"),
+        ]);
+        let Verdict::Partial { body, errors } = enforce_json(batch, &policy) else {
+            panic!("not partial");
+        };
+        let forwarded: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            forwarded,
+            json!([call_of(1, "getSlot"), call_of(3, "getBalance")])
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["id"], 2);
+        let answer = json!([{ "id": 1, "result": 5 }, { "id": 3, "result": 6 }]);
+        let merged: Value =
+            serde_json::from_slice(&merge(answer.to_string().into(), errors)).unwrap();
+        assert_eq!(merged.as_array().unwrap().len(), 3);
+        assert_eq!(merged[2]["error"]["code"], -32601);
+        // an upstream error instead of a batch answer is returned as is
+        let failure = Bytes::from_static(br#"{"error":{"code":-32005}}"#);
+        assert_eq!(merge(failure.clone(), vec![json!({})]), failure);
+    }
+
+    /// An upstream answering every call of a batch with its method name.
+    async fn echo_upstream() -> String {
+        let echo = |Json(batch): Json<Value>| async move {
+            let answers: Vec<Value> = batch
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|call| json!({ "jsonrpc": "2.0", "id": call["id"], "result": call["method"] }))
+                .collect();
+            Json(Value::Array(answers))
+        };
+        serve(Router::new().route("/", post(echo))).await
+    }
+
+    #[tokio::test]
+    async fn key_policies_apply_on_top_of_the_global_one() {
+        let upstream = echo_upstream().await;
+        let (config, url) = proxy(&format!(
+            r#"
+            [[upstreams]]
+            url = "{}"
+
+            [methods]
+            deny = ["requestAirdrop"]
+
+            [[auth.keys]]
+            name = "reader"
+            key = "secret"
+            allow_methods = ["getSlot", "requestAirdrop"]
+            "#,
+            upstream
+        ))
+        .await;
+        let url = &url;
+        let send = |body: Value| async move {
+            let request = reqwest::Client::new()
+                .post(url)
+                .header("x-api-key", "secret");
+            let response = request.json(&body).send().await.unwrap();
+            response.json::<Value>().await.unwrap()
+        };
+        let answer = send(json!([
+            call_of(1, "getSlot"),
+            call_of(2, "requestAirdrop"),
+            call_of(3, "getBalance"),
+        ]))
+        .await;
+        let answers = answer.as_array().unwrap();
+        assert_eq!(answers.len(), 3);
+        // the upstream's answers first, then the rejected calls
+        assert_eq!(
+            answers[0],
+            json!({ "jsonrpc": "2.0", "id": 1, "result": "getSlot" })
+        );
+        // denied globally, though the key allows it
+        assert_eq!(answers[1]["id"], 2);
+        assert_eq!(answers[1]["error"]["code"], -32601);
+        // allowed globally, but not to the key
+        assert_eq!(answers[2]["id"], 3);
+        assert_eq!(answers[2]["error"]["code"], -32601);
+        let key = config.api_keys().keys()[0].denied.clone();
+        assert_eq!(key.load(std::sync::atomic::Ordering::Relaxed), 2);
+        // a single denied call is answered without the upstream
+        let single = send(call_of(4, "getBalance")).await;
+        assert_eq!(single["id"], 4);
+        assert_eq!(single["error"]["code"], -32601);
+    }
+}
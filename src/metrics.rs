@@ -0,0 +1,1255 @@
+use crate::body_budget;
+use crate::classify::ErrorClass;
+use crate::config::Priority;
+use crate::quarantine::Phase;
+use crate::ServerConfig;
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+pub const LATENCY_BUCKETS_MS: [u64; 12] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// Fixed-bucket latency histogram, cheap to update from any task.
+#[derive(Default)]
+pub struct Histogram {
+    /// One counter per bucket plus the overflow bucket; not cumulative.
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+/// Index of the bucket `elapsed` falls in, the overflow bucket last.
+pub fn latency_bucket(elapsed: Duration) -> usize {
+    let ms = elapsed.as_millis() as u64;
+    LATENCY_BUCKETS_MS
+        .iter()
+        .position(|bound| ms <= *bound)
+        .unwrap_or(LATENCY_BUCKETS_MS.len())
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        self.buckets[latency_bucket(elapsed)].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<u64>,
+    pub sum_ms: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Observations made between `earlier` and this snapshot.
+    pub fn since(&self, earlier: &HistogramSnapshot) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(i, n)| n - earlier.buckets.get(i).copied().unwrap_or_default())
+                .collect(),
+            sum_ms: self.sum_ms - earlier.sum_ms,
+        }
+    }
+
+    /// Estimated quantile in milliseconds, interpolated within its bucket.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = q * count as f64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            if *n > 0 && (seen + n) as f64 >= rank {
+                let lower = if i == 0 { 0 } else { LATENCY_BUCKETS_MS[i - 1] };
+                let upper = match LATENCY_BUCKETS_MS.get(i) {
+                    Some(upper) => *upper,
+                    // nothing better to report for the overflow bucket
+                    None => return Some(lower as f64),
+                };
+                let within = (rank - seen as f64) / *n as f64;
+                return Some(lower as f64 + within * (upper - lower) as f64);
+            }
+            seen += n;
+        }
+        None
+    }
+}
+
+/// Methods tracked separately in the per-method counts; the rest share one.
+const MAX_METHODS: usize = 256;
+
+/// Count one for `method` in `counts`.
+fn count_method(counts: &Mutex<HashMap<String, u64>>, method: &str) {
+    let mut counts = counts.lock().unwrap();
+    let method = if counts.contains_key(method) || counts.len() < MAX_METHODS {
+        method
+    } else {
+        "other"
+    };
+    *counts.entry(method.to_string()).or_default() += 1;
+}
+
+/// Client-facing request counters, shared by `/metrics` and the summary log.
+#[derive(Default)]
+pub struct ProxyStats {
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub latency: Histogram,
+    /// Requests answered with 504 at their deadline, by method.
+    pub timeouts: Mutex<HashMap<String, u64>>,
+    /// Upstream answers abandoned over the response size limit, by method.
+    pub oversize: Mutex<HashMap<String, u64>>,
+    /// Upstreams added to races by their hedge delay, by method.
+    pub hedges_fired: Mutex<HashMap<String, u64>>,
+    /// Races won by such an upstream, by method.
+    pub hedges_won: Mutex<HashMap<String, u64>>,
+    /// Races by the number of upstreams they may ask.
+    pub fanout_sizes: Mutex<BTreeMap<usize, u64>>,
+    /// Requests that found every upstream able to answer them quarantined.
+    pub all_quarantined: AtomicU64,
+    /// Whether the last request did, to log the change once.
+    all_quarantined_now: AtomicBool,
+    /// Races sent across regions for want of a healthy local upstream.
+    pub region_fallbacks: AtomicU64,
+    region_fallback_now: AtomicBool,
+    /// Requests sent to a last-resort upstream.
+    pub last_resort: AtomicU64,
+    /// Whether the last request found no regular upstream, with a last
+    /// resort configured.
+    last_resort_now: AtomicBool,
+    /// Batch calls answered by the proxy, see `batch`.
+    pub batch_local: AtomicU64,
+    /// Batch calls forwarded to the upstreams.
+    pub batch_forwarded: AtomicU64,
+    /// Slot-lag quarantine decisions put off for want of `min_observations`.
+    pub quarantine_deferrals: AtomicU64,
+    quarantine_deferred_now: AtomicBool,
+    /// Writes of `[status_export] path` that failed.
+    pub status_export_failures: AtomicU64,
+    /// Requests answered at the soft deadline with a held answer.
+    pub soft_deadline_answers: AtomicU64,
+}
+
+impl ProxyStats {
+    pub fn record(&self, status: u16, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status >= 400 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(elapsed);
+    }
+
+    pub fn record_timeout(&self, method: &str) {
+        count_method(&self.timeouts, method);
+    }
+
+    pub fn record_oversize(&self, method: &str) {
+        count_method(&self.oversize, method);
+    }
+
+    /// Record an upstream added to a race of `method` by its hedge delay.
+    pub fn record_hedge(&self, method: &str) {
+        count_method(&self.hedges_fired, method);
+    }
+
+    /// Record a race that may ask `size` upstreams.
+    pub fn record_fanout(&self, size: usize) {
+        *self.fanout_sizes.lock().unwrap().entry(size).or_default() += 1;
+    }
+
+    /// Record a race of `method` won by a hedged upstream.
+    pub fn record_hedge_won(&self, method: &str) {
+        count_method(&self.hedges_won, method);
+    }
+
+    /// Record whether a request found every upstream quarantined, returning
+    /// whether that changed since the previous request.
+    pub fn record_all_quarantined(&self, all: bool) -> bool {
+        if all {
+            self.all_quarantined.fetch_add(1, Ordering::Relaxed);
+        }
+        self.all_quarantined_now.swap(all, Ordering::Relaxed) != all
+    }
+
+    /// Record whether a quarantine decision was put off, returning whether
+    /// that changed since the previous one.
+    pub fn record_quarantine_deferral(&self, deferred: bool) -> bool {
+        if deferred {
+            self.quarantine_deferrals.fetch_add(1, Ordering::Relaxed);
+        }
+        self.quarantine_deferred_now
+            .swap(deferred, Ordering::Relaxed)
+            != deferred
+    }
+
+    /// Record whether a race had to leave the local region, returning
+    /// whether that changed since the previous one.
+    pub fn record_region_fallback(&self, fallback: bool) -> bool {
+        if fallback {
+            self.region_fallbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        self.region_fallback_now.swap(fallback, Ordering::Relaxed) != fallback
+    }
+
+    pub fn is_region_fallback(&self) -> bool {
+        self.region_fallback_now.load(Ordering::Relaxed)
+    }
+
+    /// Record whether a request had only last-resort upstreams left and
+    /// whether it was `sent` to one, returning whether the former changed
+    /// since the previous request.
+    pub fn record_last_resort(&self, falling_back: bool, sent: bool) -> bool {
+        if sent {
+            self.last_resort.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_resort_now.swap(falling_back, Ordering::Relaxed) != falling_back
+    }
+}
+
+/// Escape a value for use inside a Prometheus label.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub async fn metrics_handler(State(config): State<Arc<ServerConfig>>) -> impl IntoResponse {
+    let quarantine = config.quarantine.read().await;
+    let mut out = String::new();
+
+    family(
+        &mut out,
+        "quarantier_build_info",
+        "gauge",
+        "Version and commit of the running build, always 1.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_build_info{{version=\"{}\",commit=\"{}\"}} 1",
+        crate::version::VERSION,
+        crate::version::COMMIT
+    );
+    family(
+        &mut out,
+        "quarantier_requests_total",
+        "counter",
+        "Client requests handled.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_requests_total {}",
+        config.stats.requests.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "quarantier_request_errors_total",
+        "counter",
+        "Client requests answered with an HTTP error status.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_request_errors_total {}",
+        config.stats.errors.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "quarantier_request_timeouts_total",
+        "counter",
+        "Client requests answered with 504 at their deadline, by method.",
+    );
+    for (method, n) in config.stats.timeouts.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "quarantier_request_timeouts_total{{method=\"{}\"}} {}",
+            label(method),
+            n
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_oversize_responses_total",
+        "counter",
+        "Upstream answers abandoned over the response size limit, by method.",
+    );
+    for (method, n) in config.stats.oversize.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "quarantier_oversize_responses_total{{method=\"{}\"}} {}",
+            label(method),
+            n
+        );
+    }
+    for (name, help, counts) in [
+        (
+            "quarantier_hedges_fired_total",
+            "Upstreams added to races after their hedge delay, by method.",
+            &config.stats.hedges_fired,
+        ),
+        (
+            "quarantier_hedges_won_total",
+            "Races won by an upstream added after its hedge delay, by method.",
+            &config.stats.hedges_won,
+        ),
+    ] {
+        family(&mut out, name, "counter", help);
+        for (method, n) in counts.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{method=\"{}\"}} {}", name, label(method), n);
+        }
+    }
+    if config.settings.retry.is_some() {
+        family(
+            &mut out,
+            "quarantier_retries_total",
+            "counter",
+            "Reads sent again after every upstream asked answered 5xx, by the upstream that answered last and its status.",
+        );
+        for ((upstream, status), n) in config.retries.sent.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "quarantier_retries_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                label(upstream),
+                status,
+                n
+            );
+        }
+        for (name, help, count) in [
+            (
+                "quarantier_retries_throttled_total",
+                "Reads not sent again for being over the [retry] budget.",
+                &config.retries.throttled,
+            ),
+            (
+                "quarantier_retries_recovered_total",
+                "Reads answered by their retry.",
+                &config.retries.recovered,
+            ),
+        ] {
+            family(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, count.load(Ordering::Relaxed));
+        }
+    }
+    family(
+        &mut out,
+        "quarantier_priority_requests_total",
+        "counter",
+        "Proxied requests by priority class, shed ones included.",
+    );
+    for class in Priority::ALL {
+        let _ = writeln!(
+            out,
+            "quarantier_priority_requests_total{{class=\"{}\"}} {}",
+            class.as_str(),
+            config.shedder.requests(class)
+        );
+    }
+    if config.settings.load_shedding.is_some() {
+        family(
+            &mut out,
+            "quarantier_shed_requests_total",
+            "counter",
+            "Requests shed under load, by priority class.",
+        );
+        for class in Priority::ALL {
+            let _ = writeln!(
+                out,
+                "quarantier_shed_requests_total{{class=\"{}\"}} {}",
+                class.as_str(),
+                config.shedder.shed(class)
+            );
+        }
+        family(
+            &mut out,
+            "quarantier_in_flight_requests",
+            "gauge",
+            "Proxied requests holding a [load_shedding] slot.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_in_flight_requests {}",
+            config.shedder.in_flight()
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_fanout_size_total",
+        "counter",
+        "Races by the number of upstreams they may ask, hedges included.",
+    );
+    for (size, n) in config.stats.fanout_sizes.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "quarantier_fanout_size_total{{size=\"{}\"}} {}",
+            size, n
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_all_quarantined_requests_total",
+        "counter",
+        "Client requests that found every upstream able to answer them quarantined.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_all_quarantined_requests_total{{policy=\"{}\"}} {}",
+        match config.settings.all_quarantined {
+            crate::config::AllQuarantined::ServeBest => "serve_best",
+            crate::config::AllQuarantined::Fail => "fail",
+        },
+        config.stats.all_quarantined.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "quarantier_quarantine_deferrals_total",
+        "counter",
+        "Slot-lag quarantine decisions put off for having fresh slots from fewer than min_observations hosts.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_quarantine_deferrals_total {}",
+        config.stats.quarantine_deferrals.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "quarantier_last_resort_requests_total",
+        "counter",
+        "Client requests sent to a last-resort upstream, no regular one being available or failed back to yet.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_last_resort_requests_total {}",
+        config.stats.last_resort.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "quarantier_tier_transitions_total",
+        "counter",
+        "Moves of traffic between regular and last-resort upstreams, by direction.",
+    );
+    for (direction, transitions) in [
+        ("fallback", &config.failback.fallbacks),
+        ("failback", &config.failback.failbacks),
+    ] {
+        let _ = writeln!(
+            out,
+            "quarantier_tier_transitions_total{{direction=\"{}\"}} {}",
+            direction,
+            transitions.load(Ordering::Relaxed)
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_batch_calls_total",
+        "counter",
+        "Calls in client batches, by whether the proxy answered them or forwarded them.",
+    );
+    for (served, calls) in [
+        ("local", &config.stats.batch_local),
+        ("forwarded", &config.stats.batch_forwarded),
+    ] {
+        let _ = writeln!(
+            out,
+            "quarantier_batch_calls_total{{served=\"{}\"}} {}",
+            served,
+            calls.load(Ordering::Relaxed)
+        );
+    }
+    if config.settings.status_export.is_some() {
+        family(
+            &mut out,
+            "quarantier_status_export_failures_total",
+            "counter",
+            "Writes of the /status document to [status_export] path that failed.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_status_export_failures_total {}",
+            config.stats.status_export_failures.load(Ordering::Relaxed)
+        );
+    }
+    if config.settings.soft_deadline.is_some() {
+        family(
+            &mut out,
+            "quarantier_soft_deadline_answers_total",
+            "counter",
+            "Requests answered at the soft deadline with the best answer held, without waiting for the slower upstreams.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_soft_deadline_answers_total {}",
+            config.stats.soft_deadline_answers.load(Ordering::Relaxed)
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_request_duration_seconds",
+        "histogram",
+        "Time to answer client requests.",
+    );
+    let latency = config.stats.latency.snapshot();
+    let mut cumulative = 0;
+    for (i, n) in latency.buckets.iter().enumerate() {
+        cumulative += n;
+        let bound = match LATENCY_BUCKETS_MS.get(i) {
+            Some(ms) => (*ms as f64 / 1000.0).to_string(),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "quarantier_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "quarantier_request_duration_seconds_sum {}",
+        latency.sum_ms as f64 / 1000.0
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_request_duration_seconds_count {}",
+        cumulative
+    );
+
+    if config.settings.local_get_slot.is_some() {
+        family(
+            &mut out,
+            "quarantier_local_get_slot_requests_total",
+            "counter",
+            "getSlot calls, by whether the proxy answered them from the tracked tip.",
+        );
+        let local = &config.local_slots;
+        for (result, n) in [("hit", local.hits()), ("miss", local.misses())] {
+            let _ = writeln!(
+                out,
+                "quarantier_local_get_slot_requests_total{{result=\"{}\"}} {}",
+                result, n
+            );
+        }
+    }
+
+    if config.settings.coalesce.is_some() {
+        family(
+            &mut out,
+            "quarantier_coalesced_requests_total",
+            "counter",
+            "Reads answered with the answer of an identical call in flight.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_coalesced_requests_total {}",
+            config.coalescer.coalesced()
+        );
+    }
+
+    if config.settings.epoch_cache.is_some() {
+        let epochs = &config.epochs;
+        family(
+            &mut out,
+            "quarantier_epoch_cache_requests_total",
+            "counter",
+            "Cacheable requests, by method and whether the cache answered them.",
+        );
+        for (i, method) in crate::epoch::METHODS.iter().enumerate() {
+            for (result, n) in [("hit", epochs.hits(i)), ("miss", epochs.misses(i))] {
+                let _ = writeln!(
+                    out,
+                    "quarantier_epoch_cache_requests_total{{method=\"{}\",result=\"{}\"}} {}",
+                    method, result, n
+                );
+            }
+        }
+        family(
+            &mut out,
+            "quarantier_epoch_cache_refresh_failures_total",
+            "counter",
+            "Failed refreshes of the cached epoch info or leader schedule.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_epoch_cache_refresh_failures_total {}",
+            epochs.refresh_failures()
+        );
+        if let Some(epoch) = epochs.epoch() {
+            family(
+                &mut out,
+                "quarantier_epoch_cache_epoch",
+                "gauge",
+                "Epoch of the cached epoch info.",
+            );
+            let _ = writeln!(out, "quarantier_epoch_cache_epoch {}", epoch);
+        }
+    }
+    if config.settings.tx_tracking.is_some() {
+        family(
+            &mut out,
+            "quarantier_tracked_transactions",
+            "gauge",
+            "Transactions submitted through the proxy whose status is followed.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_tracked_transactions {}",
+            config.transactions.count()
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_successes_total",
+        "counter",
+        "Upstream attempts that produced a usable answer.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_successes_total{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.stats.successes()
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_errors_total",
+        "counter",
+        "Failed upstream attempts by failure class.",
+    );
+    for upstream in &config.servers {
+        for class in ErrorClass::ALL {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_errors_total{{upstream=\"{}\",class=\"{}\"}} {}",
+                label(&upstream.name),
+                class.as_str(),
+                upstream.stats.errors(class)
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_failure_streak",
+        "gauge",
+        "Consecutive host failures of an upstream.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_failure_streak{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.stats.failure_streak()
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_quarantined",
+        "gauge",
+        "Whether responses from an upstream are currently ignored.",
+    );
+    for upstream in &config.servers {
+        let quarantined = upstream.is_quarantined(&quarantine);
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_quarantined{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            quarantined as u8
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_version_info",
+        "gauge",
+        "The node version an upstream last reported, see /status for whether it is stale.",
+    );
+    for upstream in &config.servers {
+        let Some(core) = upstream.version.core() else {
+            continue;
+        };
+        let feature_set = upstream.version.feature_set();
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_version_info{{upstream=\"{}\",solana_core=\"{}\",feature_set=\"{}\"}} 1",
+            label(&upstream.name),
+            label(&core),
+            feature_set.map(|f| f.to_string()).unwrap_or_default()
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_drained",
+        "gauge",
+        "Whether an upstream is drained by an admin, getting no new requests.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_drained{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.drained.load(Ordering::Relaxed) as u8
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_maintenance",
+        "gauge",
+        "Whether an upstream is out for a scheduled maintenance window, or not back from one yet.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_maintenance{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.maintenance.excludes() as u8
+        );
+    }
+
+    let max_slot = crate::slots::tip(&config);
+    family(
+        &mut out,
+        "quarantier_upstream_slot",
+        "gauge",
+        "Latest slot observed on an upstream.",
+    );
+    for (index, upstream) in config.servers.iter().enumerate() {
+        if let Some(observation) = config.slots.get(index) {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_slot{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                observation.slot
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_slot_lag",
+        "gauge",
+        "Slots an upstream trails the freshest upstream by.",
+    );
+    for (index, upstream) in config.servers.iter().enumerate() {
+        if let (Some(observation), Some(max_slot)) = (config.slots.get(index), max_slot) {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_slot_lag{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                max_slot.saturating_sub(observation.slot)
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_slot_age_seconds",
+        "gauge",
+        "Time since the slot of an upstream was last observed.",
+    );
+    for (index, upstream) in config.servers.iter().enumerate() {
+        if let Some(observation) = config.slots.get(index) {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_slot_age_seconds{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                observation.at.elapsed().as_secs_f64()
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_unparsable_slots_total",
+        "counter",
+        "Slots reported by an upstream that were not a non-negative integer.",
+    );
+    for (index, upstream) in config.servers.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_unparsable_slots_total{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            config.slots.unparsable(index)
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_plan_remaining",
+        "gauge",
+        "Requests left on the upstream's provider plan, from its rate-limit headers.",
+    );
+    for upstream in &config.servers {
+        if let Some(remaining) = upstream.limits.remaining() {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_plan_remaining{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                remaining
+            );
+        }
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_plan_low",
+        "gauge",
+        "Whether the upstream is avoided until its provider plan resets.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_plan_low{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.limits.is_low() as u8
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_blockhash_behind_blocks",
+        "gauge",
+        "Blocks the upstream's last lastValidBlockHeight trailed the freshest recent one by.",
+    );
+    for upstream in &config.servers {
+        if let Some(behind) = upstream.blockhash.behind() {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_blockhash_behind_blocks{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                behind
+            );
+        }
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_blockhash_stale",
+        "gauge",
+        "Whether the upstream is flagged for serving stale blockhashes.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_blockhash_stale{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.blockhash.is_stale() as u8
+        );
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_circuit_state",
+        "gauge",
+        "Phase of the upstream's circuit breaker, 1 for the current one.",
+    );
+    for upstream in &config.servers {
+        let phase = upstream.circuit.phase();
+        for state in Phase::ALL {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_circuit_state{{upstream=\"{}\",state=\"{}\"}} {}",
+                label(&upstream.name),
+                state.as_str(),
+                (state == phase) as u8
+            );
+        }
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_circuit_transitions_total",
+        "counter",
+        "Transitions of the upstream's circuit breaker, by the phase entered.",
+    );
+    for upstream in &config.servers {
+        for state in Phase::ALL {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_circuit_transitions_total{{upstream=\"{}\",state=\"{}\"}} {}",
+                label(&upstream.name),
+                state.as_str(),
+                upstream.circuit.entered(state)
+            );
+        }
+    }
+    family(
+        &mut out,
+        "quarantier_upstream_dead",
+        "gauge",
+        "Whether the upstream failed every probe for dead_after_secs, probes of it included.",
+    );
+    for upstream in &config.servers {
+        let _ = writeln!(
+            out,
+            "quarantier_upstream_dead{{upstream=\"{}\"}} {}",
+            label(&upstream.name),
+            upstream.circuit.dead().is_some() as u8
+        );
+    }
+
+    if config.servers.iter().any(|u| u.outbound.is_some()) {
+        family(
+            &mut out,
+            "quarantier_upstream_outbound_tokens",
+            "gauge",
+            "Requests the upstream may be sent right away within its [upstreams.rate_limit] rps.",
+        );
+        for upstream in &config.servers {
+            if let Some(bucket) = &upstream.outbound {
+                let _ = writeln!(
+                    out,
+                    "quarantier_upstream_outbound_tokens{{upstream=\"{}\"}} {}",
+                    label(&upstream.name),
+                    bucket.available()
+                );
+            }
+        }
+        family(
+            &mut out,
+            "quarantier_upstream_outbound_skipped_total",
+            "counter",
+            "Requests not sent to the upstream for being at its outbound rate limit.",
+        );
+        for upstream in &config.servers {
+            if upstream.outbound.is_some() {
+                let _ = writeln!(
+                    out,
+                    "quarantier_upstream_outbound_skipped_total{{upstream=\"{}\"}} {}",
+                    label(&upstream.name),
+                    upstream.outbound_skipped.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    if config.settings.strict_freshness.is_some() {
+        family(
+            &mut out,
+            "quarantier_upstream_stale_rejections_total",
+            "counter",
+            "Answers rejected by strict freshness for trailing the tip by more than the tolerance.",
+        );
+        for upstream in &config.servers {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_stale_rejections_total{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                upstream.stats.stale_rejections()
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_upstream_credits_total",
+        "counter",
+        "Credits estimated from [costs] for every request sent to the upstream, background ones included.",
+    );
+    let spent = config.costs.totals();
+    for upstream in &config.servers {
+        let Some(methods) = spent.get(&upstream.name) else {
+            continue;
+        };
+        let mut methods: Vec<_> = methods.iter().collect();
+        methods.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (method, spent) in methods {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_credits_total{{upstream=\"{}\",method=\"{}\"}} {}",
+                label(&upstream.name),
+                label(method),
+                spent.credits
+            );
+        }
+    }
+
+    if config.settings.unsupported_methods.is_some() {
+        family(
+            &mut out,
+            "quarantier_upstream_unsupported_methods",
+            "gauge",
+            "Methods the upstream answered it does not serve, kept from it for now.",
+        );
+        for upstream in &config.servers {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_unsupported_methods{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                upstream.unsupported.count()
+            );
+        }
+        family(
+            &mut out,
+            "quarantier_upstream_unsupported_skipped_total",
+            "counter",
+            "Requests not sent to the upstream for calling a method it does not serve.",
+        );
+        for upstream in &config.servers {
+            let _ = writeln!(
+                out,
+                "quarantier_upstream_unsupported_skipped_total{{upstream=\"{}\"}} {}",
+                label(&upstream.name),
+                upstream.unsupported.skipped()
+            );
+        }
+    }
+
+    if let Some(region) = &config.settings.region {
+        family(
+            &mut out,
+            "quarantier_region_fallback_requests_total",
+            "counter",
+            "Races sent to other regions while no local upstream was healthy.",
+        );
+        let _ = writeln!(
+            out,
+            "quarantier_region_fallback_requests_total{{region=\"{}\"}} {}",
+            region.local,
+            config.stats.region_fallbacks.load(Ordering::Relaxed)
+        );
+    }
+
+    if let Some(mirror) = &config.mirror {
+        family(
+            &mut out,
+            "quarantier_mirror_sent_total",
+            "counter",
+            "Sampled requests delivered to the mirror sink.",
+        );
+        let _ = writeln!(out, "quarantier_mirror_sent_total {}", mirror.sent());
+        family(
+            &mut out,
+            "quarantier_mirror_dropped_total",
+            "counter",
+            "Sampled requests dropped, with the mirror queue full or the sink failing.",
+        );
+        let (queue_full, sink_error) = mirror.dropped();
+        for (reason, dropped) in [("queue_full", queue_full), ("sink_error", sink_error)] {
+            let _ = writeln!(
+                out,
+                "quarantier_mirror_dropped_total{{reason=\"{}\"}} {}",
+                reason, dropped
+            );
+        }
+    }
+
+    if let Some(journal) = &config.journal {
+        family(
+            &mut out,
+            "quarantier_tx_journal_entries_total",
+            "counter",
+            "Submitted transactions queued for the transaction journal.",
+        );
+        let journaled = journal.journaled.load(Ordering::Relaxed);
+        let _ = writeln!(out, "quarantier_tx_journal_entries_total {}", journaled);
+        family(
+            &mut out,
+            "quarantier_tx_journal_dropped_total",
+            "counter",
+            "Submitted transactions left out of the journal, its writer falling behind.",
+        );
+        let dropped = journal.dropped.load(Ordering::Relaxed);
+        let _ = writeln!(out, "quarantier_tx_journal_dropped_total {}", dropped);
+    }
+
+    let clients = config.usage.top(config.settings.usage.top);
+    family(
+        &mut out,
+        "quarantier_client_requests_total",
+        "counter",
+        "Client requests by method, for the heaviest clients.",
+    );
+    for (client, usage) in &clients {
+        for (method, counts) in &usage.methods {
+            let _ = writeln!(
+                out,
+                "quarantier_client_requests_total{{client=\"{}\",method=\"{}\"}} {}",
+                label(client),
+                label(method),
+                counts.requests
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_client_cost_total",
+        "counter",
+        "Upstream credits spent on behalf of a client, by method.",
+    );
+    for (client, usage) in &clients {
+        for (method, counts) in &usage.methods {
+            let _ = writeln!(
+                out,
+                "quarantier_client_cost_total{{client=\"{}\",method=\"{}\"}} {}",
+                label(client),
+                label(method),
+                counts.cost
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "quarantier_client_send_duplicates_total",
+        "counter",
+        "sendTransaction calls repeating a recently submitted signature.",
+    );
+    for (client, usage) in &clients {
+        let _ = writeln!(
+            out,
+            "quarantier_client_send_duplicates_total{{client=\"{}\"}} {}",
+            label(client),
+            usage.send_duplicates
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_client_oversize_responses_total",
+        "counter",
+        "Upstream answers to a client abandoned over the response size limit.",
+    );
+    for (client, usage) in &clients {
+        let _ = writeln!(
+            out,
+            "quarantier_client_oversize_responses_total{{client=\"{}\"}} {}",
+            label(client),
+            usage.oversize
+        );
+    }
+
+    for (name, help, value) in [
+        (
+            "quarantier_dispatch_tasks_spawned_total",
+            "Dispatch tasks started, one per proxied request.",
+            config.tasks.spawned(),
+        ),
+        (
+            "quarantier_dispatch_tasks_completed_total",
+            "Dispatch tasks that collected every upstream answer.",
+            config.tasks.completed(),
+        ),
+        (
+            "quarantier_dispatch_tasks_aborted_total",
+            "Dispatch tasks that dropped outstanding upstream requests.",
+            config.tasks.aborted(),
+        ),
+    ] {
+        family(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    family(
+        &mut out,
+        "quarantier_dispatch_tasks_lingering",
+        "gauge",
+        "Dispatch tasks still waiting on upstreams after answering their client.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_dispatch_tasks_lingering {}",
+        config.tasks.lingering()
+    );
+
+    let bodies = &config.bodies;
+    for (name, help, value) in [
+        (
+            "quarantier_body_budget_bytes",
+            "Bytes of response bodies that may be kept in memory at once.",
+            bodies.limit(),
+        ),
+        (
+            "quarantier_body_budget_reserved_bytes",
+            "Bytes of response bodies kept in memory now.",
+            bodies.reserved(),
+        ),
+    ] {
+        family(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    family(
+        &mut out,
+        "quarantier_body_budget_degraded_total",
+        "counter",
+        "Bodies not kept for want of budget, by what became of them.",
+    );
+    for degraded in body_budget::Degraded::ALL {
+        let _ = writeln!(
+            out,
+            "quarantier_body_budget_degraded_total{{action=\"{}\"}} {}",
+            degraded.as_str(),
+            bodies.degraded(degraded)
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_acl_rejected_total",
+        "counter",
+        "Requests rejected by the client address allow and deny lists.",
+    );
+    let _ = writeln!(
+        out,
+        "quarantier_acl_rejected_total {}",
+        config.acl.rejected.load(Ordering::Relaxed)
+    );
+
+    family(
+        &mut out,
+        "quarantier_api_key_throttled_total",
+        "counter",
+        "Requests rejected by the rate limit of their API key.",
+    );
+    for key in config.api_keys().keys() {
+        let _ = writeln!(
+            out,
+            "quarantier_api_key_throttled_total{{key=\"{}\"}} {}",
+            label(&key.name),
+            key.throttled.load(Ordering::Relaxed)
+        );
+    }
+
+    family(
+        &mut out,
+        "quarantier_api_key_method_denied_total",
+        "counter",
+        "Calls rejected by the method restrictions of their API key or the global policy.",
+    );
+    for key in config.api_keys().keys() {
+        let _ = writeln!(
+            out,
+            "quarantier_api_key_method_denied_total{{key=\"{}\"}} {}",
+            label(&key.name),
+            key.denied.load(Ordering::Relaxed)
+        );
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
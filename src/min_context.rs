@@ -0,0 +1,204 @@
+//! Injection of `minContextSlot` into read calls, so an upstream behind the
+//! proxy's tracked tip fails fast instead of answering with stale data.
+//! Such a failure loses the race like a slow answer would; answers to
+//! rewritten requests are therefore read in full before one is chosen.
+
+use axum::body::Bytes;
+use serde_json::{json, Value};
+
+/// JSON-RPC error of a node that has not reached the `minContextSlot`.
+pub const NOT_REACHED: i64 = -32016;
+
+/// Methods taking `minContextSlot`, with the position of their config
+/// object in the params.
+const METHODS: [(&str, usize); 18] = [
+    ("getAccountInfo", 1),
+    ("getBalance", 1),
+    ("getBlockHeight", 0),
+    ("getEpochInfo", 0),
+    ("getFeeForMessage", 1),
+    ("getInflationReward", 1),
+    ("getLatestBlockhash", 0),
+    ("getMultipleAccounts", 1),
+    ("getProgramAccounts", 1),
+    ("getSignaturesForAddress", 1),
+    ("getSlot", 0),
+    ("getSlotLeader", 0),
+    ("getStakeActivation", 1),
+    ("getTokenAccountsByDelegate", 2),
+    ("getTokenAccountsByOwner", 2),
+    ("getTransactionCount", 0),
+    ("isBlockhashValid", 1),
+    ("simulateTransaction", 1),
+];
+
+/// Add `minContextSlot` to `call` when its method takes one and the client
+/// did not set it. Returns whether the call changed.
+fn inject_call(call: &mut Value, slot: u64) -> bool {
+    let Some(method) = call["method"].as_str() else {
+        return false;
+    };
+    let Some(&(_, position)) = METHODS.iter().find(|(name, _)| *name == method) else {
+        return false;
+    };
+    let Some(call) = call.as_object_mut() else {
+        return false;
+    };
+    let params = call.entry("params").or_insert_with(|| json!([]));
+    let Some(params) = params.as_array_mut() else {
+        return false;
+    };
+    // only the config object may be missing; earlier params are the
+    // client's to get right
+    if params.len() == position {
+        params.push(json!({ "minContextSlot": slot }));
+        return true;
+    }
+    match params.get_mut(position) {
+        Some(config @ Value::Null) => {
+            *config = json!({ "minContextSlot": slot });
+            true
+        }
+        Some(Value::Object(config)) if !config.contains_key("minContextSlot") => {
+            config.insert("minContextSlot".to_string(), slot.into());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// `body` with `minContextSlot` set to `slot` in every call that takes
+/// one, or `None` when no call changed.
+pub fn inject(body: &[u8], slot: u64) -> Option<Bytes> {
+    let mut request: Value = serde_json::from_slice(body).ok()?;
+    let changed = match &mut request {
+        Value::Array(calls) => calls
+            .iter_mut()
+            .fold(false, |changed, call| inject_call(call, slot) | changed),
+        call => inject_call(call, slot),
+    };
+    changed.then(|| request.to_string().into())
+}
+
+/// Whether an answer, or any entry of a batch answer, says the node has
+/// not reached the slot asked for.
+pub fn not_reached(answer: Option<&Value>) -> bool {
+    let reached = |answer: &Value| answer["error"]["code"].as_i64() != Some(NOT_REACHED);
+    match answer {
+        Some(Value::Array(answers)) => !answers.iter().all(reached),
+        Some(answer) => !reached(answer),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::slots::SlotSource;
+    use crate::testing::{call, proxy, upstream};
+    use std::time::Duration;
+
+    fn injected(request: Value) -> Option<Value> {
+        let body = inject(request.to_string().as_bytes(), 300)?;
+        Some(serde_json::from_slice(&body).unwrap())
+    }
+
+    fn call_of(method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })
+    }
+
+    #[test]
+    fn the_config_object_gets_the_slot() {
+        let request = injected(call_of("getBalance", json!(["a"]))).unwrap();
+        assert_eq!(request["params"], json!(["a", { "minContextSlot": 300 }]));
+        let request = injected(call_of("getBalance", json!(["a", null]))).unwrap();
+        assert_eq!(request["params"], json!(["a", { "minContextSlot": 300 }]));
+        let config = json!(["a", { "commitment": "confirmed" }]);
+        let request = injected(call_of("getBalance", config)).unwrap();
+        assert_eq!(
+            request["params"][1],
+            json!({ "commitment": "confirmed", "minContextSlot": 300 })
+        );
+        let request = injected(json!({ "id": 1, "method": "getSlot" })).unwrap();
+        assert_eq!(request["params"], json!([{ "minContextSlot": 300 }]));
+    }
+
+    #[test]
+    fn calls_are_otherwise_left_alone() {
+        // the client's own slot, another method, and params it got wrong
+        let set = json!(["a", { "minContextSlot": 5 }]);
+        assert!(injected(call_of("getBalance", set)).is_none());
+        assert!(injected(call_of("sendTransaction", json!(["tx"]))).is_none());
+        assert!(injected(call_of("getTokenAccountsByOwner", json!(["a"]))).is_none());
+        assert!(injected(call_of("getBalance", json!({ "pubkey": "a" }))).is_none());
+        assert!(inject(b"not json", 300).is_none());
+    }
+
+    #[test]
+    fn batches_are_injected_call_by_call() {
+        let batch = json!([
+            call_of("sendTransaction", json!(["tx"])),
+            call_of("getBalance", json!(["a"])),
+        ]);
+        let batch = injected(batch).unwrap();
+        assert_eq!(batch[0]["params"], json!(["tx"]));
+        assert_eq!(batch[1]["params"][1]["minContextSlot"], 300);
+    }
+
+    #[test]
+    fn any_entry_not_reached_counts() {
+        let error = json!({ "error": { "code": NOT_REACHED } });
+        assert!(not_reached(Some(&error)));
+        assert!(not_reached(Some(&json!([{ "result": 1 }, error]))));
+        assert!(!not_reached(Some(&json!({ "result": 1 }))));
+        assert!(!not_reached(Some(&json!({ "error": { "code": -32005 } }))));
+        assert!(!not_reached(None));
+    }
+
+    fn get_balance() -> Value {
+        call_of("getBalance", json!(["11111111111111111111111111111111"]))
+    }
+
+    #[tokio::test]
+    async fn upstreams_behind_the_tip_lose_the_race() {
+        let behind = upstream(Behavior {
+            slot_lag: 100,
+            ..Behavior::default()
+        })
+        .await;
+        let fresh = upstream(Behavior {
+            latency: Duration::from_millis(100),
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n[min_context_slot]\nenabled = true\nmargin_slots = 0\n",
+            behind, fresh
+        ))
+        .await;
+        // the mocks start at slot 300000000
+        config.slots.observe(1, 300_000_000, SlotSource::Poll);
+        let answer: Value = call(&url, get_balance()).await.json().await.unwrap();
+        assert_eq!(answer["result"]["value"], 1_000_000_000);
+        let slot = answer["result"]["context"]["slot"].as_u64().unwrap();
+        assert!(slot >= 300_000_000, "{}", slot);
+    }
+
+    #[tokio::test]
+    async fn with_every_upstream_behind_the_error_is_returned() {
+        let behind = upstream(Behavior {
+            slot_lag: 100,
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n[min_context_slot]\nenabled = true\nmargin_slots = 0\n",
+            behind
+        ))
+        .await;
+        config.slots.observe(0, 300_000_000, SlotSource::Poll);
+        let answer: Value = call(&url, get_balance()).await.json().await.unwrap();
+        assert_eq!(answer["error"]["code"], NOT_REACHED);
+    }
+}
@@ -0,0 +1,352 @@
+//! Copy of sampled traffic for offline analysis. Handlers only pick the
+//! requests to mirror and queue them; a background task turns them into
+//! JSON lines, the shape `replay` reads, and hands them to the sink, an
+//! HTTP endpoint fed in batches or a file or named pipe. With the queue
+//! full, and when the sink fails, records are dropped and counted, so the
+//! sink can never slow clients down.
+
+use crate::config::{MirrorConfig, MirrorSink};
+use axum::body::Bytes;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Records sent to the sink at once at most.
+const BATCH: usize = 100;
+/// Longest a record waits for its batch to fill.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const SINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stand-in for redacted params.
+const REDACTED: &str = "[redacted]";
+
+/// Rewrites a record before it leaves the proxy, see
+/// `ProxyService::redact_mirrored`.
+pub type Redactor = Box<dyn Fn(&mut Value) + Send + Sync>;
+
+/// What the handler knew about a mirrored request.
+pub struct Mirrored {
+    pub unix_ms: u64,
+    pub request_id: String,
+    pub client: String,
+    pub method: String,
+    pub body: Bytes,
+    pub status: u16,
+    pub upstream: Option<String>,
+    pub duration: Duration,
+}
+
+pub struct Mirror {
+    settings: MirrorConfig,
+    records: Sender<Mirrored>,
+    /// Taken by `run`.
+    receiver: std::sync::Mutex<Option<Receiver<Mirrored>>>,
+    redactor: OnceLock<Redactor>,
+    sent: AtomicU64,
+    queue_full: AtomicU64,
+    sink_failed: AtomicU64,
+}
+
+impl Mirror {
+    pub fn new(settings: &MirrorConfig) -> Self {
+        let (records, receiver) = mpsc::channel(settings.queue);
+        Self {
+            settings: settings.clone(),
+            records,
+            receiver: std::sync::Mutex::new(Some(receiver)),
+            redactor: OnceLock::new(),
+            sent: AtomicU64::default(),
+            queue_full: AtomicU64::default(),
+            sink_failed: AtomicU64::default(),
+        }
+    }
+
+    /// Install the hook rewriting records, once.
+    pub fn set_redactor(&self, redactor: Redactor) -> bool {
+        self.redactor.set(redactor).is_ok()
+    }
+
+    /// Whether a request for `method` is picked, by its method's rate or
+    /// the default one.
+    pub fn samples(&self, method: &str) -> bool {
+        let rate = self.settings.method_rates.get(method);
+        crate::random::chance(*rate.unwrap_or(&self.settings.sample_rate))
+    }
+
+    /// Queue `record` for the sink, dropping it when the queue is full.
+    pub fn record(&self, record: Mirrored) {
+        if self.records.try_send(record).is_err() {
+            self.queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Records dropped with the queue full, and for the sink failing.
+    pub fn dropped(&self) -> (u64, u64) {
+        (
+            self.queue_full.load(Ordering::Relaxed),
+            self.sink_failed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The JSON line of `record`, its body cut and redacted as configured.
+    fn line(&self, record: Mirrored) -> String {
+        let settings = &self.settings;
+        let mut body = match serde_json::from_slice::<Value>(&record.body) {
+            Ok(body) if record.body.len() <= settings.max_body => body,
+            _ => {
+                let cut = &record.body[..record.body.len().min(settings.max_body)];
+                Value::String(String::from_utf8_lossy(cut).into_owned())
+            }
+        };
+        let calls = match &mut body {
+            Value::Array(calls) => calls.iter_mut().collect(),
+            call => vec![call],
+        };
+        for call in calls {
+            let method = call["method"].as_str().unwrap_or_default();
+            if settings.redact_methods.iter().any(|m| m == method) && call.get("params").is_some() {
+                call["params"] = REDACTED.into();
+            }
+        }
+        let mut line = json!({
+            "ts": crate::clock::rfc3339(record.unix_ms),
+            "request_id": record.request_id,
+            "client": (!settings.redact_clients).then_some(record.client),
+            "method": record.method,
+            "status": record.status,
+            "upstream": record.upstream,
+            "duration_ms": (record.duration.as_secs_f64() * 1e6).round() / 1000.0,
+            "truncated": record.body.len() > settings.max_body,
+            "body": body,
+        });
+        if let Some(redactor) = self.redactor.get() {
+            redactor(&mut line);
+        }
+        line.to_string()
+    }
+}
+
+/// Deliver one batch of lines, appending to `file` for a file sink.
+async fn deliver(
+    settings: &MirrorConfig,
+    client: &reqwest::Client,
+    file: &mut Option<tokio::fs::File>,
+    lines: &str,
+) -> Result<(), String> {
+    match &settings.sink {
+        MirrorSink::Http(url) => client
+            .post(url)
+            .header("content-type", "application/x-ndjson")
+            .timeout(SINK_TIMEOUT)
+            .body(lines.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(drop)
+            .map_err(|err| err.to_string()),
+        MirrorSink::File(path) => {
+            if file.is_none() {
+                // opening a named pipe waits for its reader
+                let opened = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await;
+                *file = Some(opened.map_err(|err| err.to_string())?);
+            }
+            let written = file.as_mut().unwrap().write_all(lines.as_bytes()).await;
+            // a pipe whose reader left is opened again for the next one
+            written.map_err(|err| {
+                *file = None;
+                err.to_string()
+            })
+        }
+    }
+}
+
+/// Turn queued records into lines and send them to the sink in batches,
+/// forever.
+pub async fn run(mirror: Arc<Mirror>) {
+    let Some(mut receiver) = mirror.receiver.lock().unwrap().take() else {
+        return;
+    };
+    let client = reqwest::Client::new();
+    let mut file = None;
+    let mut failing = false;
+    let mut batch = Vec::with_capacity(BATCH);
+    loop {
+        if receiver.recv_many(&mut batch, BATCH).await == 0 {
+            return;
+        }
+        let flush = tokio::time::sleep(FLUSH_INTERVAL);
+        tokio::pin!(flush);
+        while batch.len() < BATCH {
+            let room = BATCH - batch.len();
+            tokio::select! {
+                _ = &mut flush => break,
+                received = receiver.recv_many(&mut batch, room) => {
+                    if received == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        let count = batch.len() as u64;
+        let mut lines = String::new();
+        for record in batch.drain(..) {
+            lines.push_str(&mirror.line(record));
+            lines.push('\n');
+        }
+        match deliver(&mirror.settings, &client, &mut file, &lines).await {
+            Ok(()) => {
+                mirror.sent.fetch_add(count, Ordering::Relaxed);
+                if std::mem::take(&mut failing) {
+                    println!("+ Mirror sink is accepting records again");
+                }
+            }
+            Err(err) => {
+                mirror.sink_failed.fetch_add(count, Ordering::Relaxed);
+                // logged once per outage, not per batch
+                if !std::mem::replace(&mut failing, true) {
+                    println!("+ Mirror sink failed, dropping records: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::post, Router};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn settings(sink: MirrorSink) -> MirrorConfig {
+        MirrorConfig {
+            sink,
+            sample_rate: 0.0,
+            method_rates: HashMap::from([("sendTransaction".to_string(), 1.0)]),
+            max_body: 200,
+            queue: 10,
+            redact_methods: vec!["sendTransaction".to_string()],
+            redact_clients: true,
+        }
+    }
+
+    fn mirrored(body: &str) -> Mirrored {
+        Mirrored {
+            unix_ms: 0,
+            request_id: "r1".to_string(),
+            client: "10.0.0.1".to_string(),
+            method: "getSlot".to_string(),
+            body: Bytes::from(body.to_string()),
+            status: 200,
+            upstream: Some("a".to_string()),
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    fn line(mirror: &Mirror, body: &str) -> Value {
+        serde_json::from_str(&mirror.line(mirrored(body))).unwrap()
+    }
+
+    #[test]
+    fn methods_have_their_own_rates() {
+        let mirror = Mirror::new(&settings(MirrorSink::File(String::new())));
+        assert!(mirror.samples("sendTransaction"));
+        assert!(!mirror.samples("getSlot"));
+    }
+
+    #[test]
+    fn records_leave_without_what_is_redacted() {
+        let mirror = Mirror::new(&settings(MirrorSink::File(String::new())));
+        let batch =
+            r#"[{"method":"sendTransaction","params":["tx"]},{"method":"getSlot","params":[]}]"#;
+        let line = line(&mirror, batch);
+        assert_eq!(line["body"][0]["params"], REDACTED);
+        assert_eq!(line["body"][1]["params"], json!([]));
+        assert_eq!(line["client"], Value::Null);
+        assert_eq!(line["duration_ms"], 1.5);
+        assert_eq!(line["truncated"], false);
+        mirror.set_redactor(Box::new(|line| line["upstream"] = "hidden".into()));
+        assert_eq!(self::line(&mirror, "{}")["upstream"], "hidden");
+    }
+
+    #[test]
+    fn long_bodies_are_cut_as_strings() {
+        let mirror = Mirror::new(&settings(MirrorSink::File(String::new())));
+        let long = format!(r#"{{"method":"getSlot","params":["{}"]}}"#, "x".repeat(300));
+        let line = line(&mirror, &long);
+        assert_eq!(line["truncated"], true);
+        assert_eq!(line["body"], long[..200]);
+    }
+
+    #[test]
+    fn a_full_queue_drops_records() {
+        let mut settings = settings(MirrorSink::File(String::new()));
+        settings.queue = 1;
+        let mirror = Mirror::new(&settings);
+        mirror.record(mirrored("{}"));
+        mirror.record(mirrored("{}"));
+        assert_eq!(mirror.dropped(), (1, 0));
+    }
+
+    /// An HTTP sink keeping what it is sent, or failing every batch.
+    async fn sink(fails: bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let url = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let received = received.clone();
+                move |body: String| async move {
+                    received.lock().unwrap().push(body);
+                    match fails {
+                        true => StatusCode::SERVICE_UNAVAILABLE,
+                        false => StatusCode::OK,
+                    }
+                }
+            }),
+        ))
+        .await;
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn records_reach_the_sink_in_batches() {
+        let (url, received) = sink(false).await;
+        let mirror = Arc::new(Mirror::new(&settings(MirrorSink::Http(url))));
+        for _ in 0..3 {
+            mirror.record(mirrored(r#"{"method":"getSlot"}"#));
+        }
+        tokio::spawn(run(mirror.clone()));
+        tokio::time::sleep(FLUSH_INTERVAL + Duration::from_millis(300)).await;
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let lines: Vec<Value> = received[0]
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["request_id"], "r1");
+        assert_eq!(mirror.sent(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_drops_its_batches() {
+        let (url, _) = sink(true).await;
+        let mirror = Arc::new(Mirror::new(&settings(MirrorSink::Http(url))));
+        mirror.record(mirrored("{}"));
+        tokio::spawn(run(mirror.clone()));
+        tokio::time::sleep(FLUSH_INTERVAL + Duration::from_millis(300)).await;
+        assert_eq!(mirror.sent(), 0);
+        assert_eq!(mirror.dropped(), (0, 1));
+    }
+}
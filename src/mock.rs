@@ -0,0 +1,681 @@
+//! Mock Solana upstream, for demos, the bench and in-process tests: a
+//! minimal JSON-RPC endpoint whose slot advances like a real cluster's, with
+//! injected lag, latency and failures that a scenario can change over time.
+
+use crate::versions::Version;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str = "Usage: quarantier mock-upstream [options]
+  --port <PORT>          port to listen on, on 127.0.0.1 (default 9000)
+  --ws-port <PORT>       also answer slotSubscribe over WebSocket on this port
+  --slot-lag <N>         slots behind the simulated cluster (default 0)
+  --latency-ms <MS>      delay before every answer (default 5)
+  --fail-rate <P>        fraction of requests answered with a 503 (default 0)
+  --empty-rate <P>       fraction of requests answered with an empty 200,
+                         as some providers do while failing over (default 0)
+  --slots-per-epoch <N>  epoch length (default 432000, as on mainnet)
+  --blockhash-lag <N>    blocks the latest blockhash trails the slot by (default 0)
+  --plan-requests <N>    requests allowed per minute, announced in
+                         x-ratelimit-remaining/-reset, then 429 (default none)
+  --cluster <NAME>       mainnet, devnet or testnet, for the genesis hash and
+                         the slot (default mainnet)
+  --node-version <V>     solana-core version getVersion reports (default 2.0.15)
+  --program-accounts <N> accounts getProgramAccounts streams, without a
+                         content-length, like an unfiltered call (default 0)
+  --scenario <FILE>      timeline of behavior changes, see the README";
+
+/// Slots behind the cluster at which `getHealth` reports the node behind,
+/// as Solana validators do.
+const HEALTH_SLOT_DISTANCE: u64 = 150;
+
+/// The cluster a mock serves, telling by its genesis hash and slot.
+#[derive(Clone, Copy)]
+pub enum Cluster {
+    Mainnet,
+    Devnet,
+    Testnet,
+}
+
+impl Cluster {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Cluster::Mainnet),
+            "devnet" => Some(Cluster::Devnet),
+            "testnet" => Some(Cluster::Testnet),
+            _ => None,
+        }
+    }
+
+    fn genesis_hash(self) -> &'static str {
+        match self {
+            Cluster::Mainnet => "5eykt4UsFv8P8NJdTREpY1vzqKqZKvdpKuc147dw2N9d",
+            Cluster::Devnet => "EtWTRABZaYq6iMfeYKouRu166VU2xqa1wcaWoxPkrZBG",
+            Cluster::Testnet => "4uhcVJyU9pJkvQyS88uRDiswHXSCkY3zQawwpjk2NsNY",
+        }
+    }
+
+    fn first_slot(self) -> u64 {
+        match self {
+            Cluster::Mainnet => 300_000_000,
+            Cluster::Devnet => 350_000_000,
+            Cluster::Testnet => 320_000_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Behavior {
+    pub slot_lag: u64,
+    pub latency: Duration,
+    /// Fraction of requests failing, from 0 to 1.
+    pub fail_rate: f64,
+    /// Fraction of requests answered with an empty body, from 0 to 1.
+    pub empty_rate: f64,
+    pub slots_per_epoch: u64,
+    /// Blocks `getLatestBlockhash` is behind, like a node stuck on an old
+    /// bank while its slot advances.
+    pub blockhash_lag: u64,
+    /// Requests allowed per minute, like a provider plan.
+    pub plan_requests: Option<u64>,
+    pub cluster: Cluster,
+    pub node_version: Version,
+    /// Accounts `getProgramAccounts` streams, 0 for the plain answer.
+    pub program_accounts: u64,
+}
+
+impl Default for Behavior {
+    fn default() -> Self {
+        Self {
+            slot_lag: 0,
+            latency: Duration::from_millis(5),
+            fail_rate: 0.0,
+            empty_rate: 0.0,
+            slots_per_epoch: 432_000,
+            blockhash_lag: 0,
+            plan_requests: None,
+            cluster: Cluster::Mainnet,
+            node_version: Version {
+                major: 2,
+                minor: 0,
+                patch: 15,
+            },
+            program_accounts: 0,
+        }
+    }
+}
+
+/// Behavior taking effect `at` after the mock started.
+pub struct Phase {
+    pub at: Duration,
+    pub behavior: Behavior,
+}
+
+struct Mock {
+    behavior: RwLock<Behavior>,
+    started: Instant,
+    /// Slots that transactions sent to the mock landed in, by signature.
+    landed: Mutex<HashMap<String, u64>>,
+    /// Minute of the plan window and requests counted in it.
+    plan_window: Mutex<(u64, u64)>,
+    /// What `getIdentity` answers, random per mock.
+    identity: String,
+}
+
+impl Mock {
+    fn slot(&self, behavior: &Behavior) -> u64 {
+        let cluster_slot =
+            behavior.cluster.first_slot() + self.started.elapsed().as_millis() as u64 / 400;
+        cluster_slot.saturating_sub(behavior.slot_lag)
+    }
+}
+
+/// Status of a transaction that landed `age` slots ago, confirmed after a
+/// slot and finalized after 32 as on a healthy cluster.
+fn signature_status(slot: u64, age: u64) -> Value {
+    let (confirmation_status, confirmations) = match age {
+        0 => ("processed", json!(0)),
+        1..=31 => ("confirmed", json!(age)),
+        _ => ("finalized", Value::Null),
+    };
+    json!({
+        "slot": slot,
+        "confirmations": confirmations,
+        "err": null,
+        "status": { "Ok": null },
+        "confirmationStatus": confirmation_status,
+    })
+}
+
+/// Parse a scenario file: `[[phase]]` entries with `at_secs` and any of
+/// `slot_lag`, `latency_ms` and `fail_rate`, each phase keeping the values
+/// it does not set from the one before, starting from `initial`.
+pub fn parse_scenario(text: &str, initial: Behavior) -> Result<Vec<Phase>, String> {
+    let value = crate::toml::parse(text).map_err(|e| e.to_string())?;
+    let mut behavior = initial;
+    let mut phases: Vec<Phase> = Vec::new();
+    for (i, phase) in value["phase"].as_array().into_iter().flatten().enumerate() {
+        let field = |key: &str| match &phase[key] {
+            Value::Null => Ok(None),
+            value => value
+                .as_f64()
+                .filter(|n| *n >= 0.0)
+                .map(Some)
+                .ok_or_else(|| format!("phase {}: {} must be a non-negative number", i + 1, key)),
+        };
+        let at =
+            field("at_secs")?.ok_or_else(|| format!("phase {}: at_secs is required", i + 1))?;
+        if let Some(lag) = field("slot_lag")? {
+            behavior.slot_lag = lag as u64;
+        }
+        if let Some(ms) = field("latency_ms")? {
+            behavior.latency = Duration::from_millis(ms as u64);
+        }
+        if let Some(rate) = field("fail_rate")? {
+            behavior.fail_rate = rate;
+        }
+        let at = Duration::from_secs_f64(at);
+        if phases.last().is_some_and(|last| last.at > at) {
+            return Err(format!("phase {}: phases must be in time order", i + 1));
+        }
+        phases.push(Phase { at, behavior });
+    }
+    Ok(phases)
+}
+
+/// Two mock leaders taking turns every 4 slots, the pair swapped in odd
+/// epochs so consecutive schedules differ.
+fn leader_schedule(epoch: u64, slots_per_epoch: u64) -> Value {
+    let leaders = [
+        "MockLeader1111111111111111111111111111111111",
+        "MockLeader2222222222222222222222222222222222",
+    ];
+    let mut slots: [Vec<u64>; 2] = Default::default();
+    for index in 0..slots_per_epoch {
+        slots[((index / 4 + epoch) % 2) as usize].push(index);
+    }
+    let [first, second] = slots;
+    json!({ leaders[0]: first, leaders[1]: second })
+}
+
+/// A `getProgramAccounts` answer of `count` accounts, streamed in chunks
+/// of a thousand so its length is not known up front.
+fn program_accounts(id: Value, count: u64) -> Response {
+    const CHUNK: u64 = 1_000;
+    let head = format!(r#"{{"jsonrpc":"2.0","id":{},"result":["#, id);
+    let accounts = (0..count.div_ceil(CHUNK)).map(move |chunk| {
+        let mut text = String::new();
+        for index in chunk * CHUNK..((chunk + 1) * CHUNK).min(count) {
+            let account = json!({
+                "pubkey": crate::base58::encode(&index.to_le_bytes().repeat(4)),
+                "account": {
+                    "data": ["AAAAAAAAAAA=", "base64"],
+                    "executable": false,
+                    "lamports": 1_000_000u64,
+                    "owner": "11111111111111111111111111111111",
+                    "rentEpoch": 0,
+                    "space": 8,
+                },
+            });
+            if index > 0 {
+                text.push(',');
+            }
+            text.push_str(&account.to_string());
+        }
+        text
+    });
+    let chunks = std::iter::once(head)
+        .chain(accounts)
+        .chain(std::iter::once("]}".to_string()))
+        .map(|text| Ok::<_, std::io::Error>(Bytes::from(text)));
+    let body = axum::body::Body::from_stream(futures::stream::iter(chunks));
+    ([("content-type", "application/json")], body).into_response()
+}
+
+async fn handler(State(mock): State<Arc<Mock>>, body: Bytes) -> Response {
+    let behavior = *mock.behavior.read().unwrap();
+    tokio::time::sleep(behavior.latency).await;
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let mut plan_headers = None;
+    if let Some(limit) = behavior.plan_requests {
+        let elapsed = mock.started.elapsed().as_secs();
+        let mut window = mock.plan_window.lock().unwrap();
+        if window.0 != elapsed / 60 {
+            *window = (elapsed / 60, 0);
+        }
+        window.1 += 1;
+        let remaining = limit.saturating_sub(window.1).to_string();
+        let reset = (60 - elapsed % 60).to_string();
+        let headers = [
+            ("x-ratelimit-remaining", remaining),
+            ("x-ratelimit-reset", reset),
+        ];
+        if window.1 > limit {
+            let error = json!({ "code": 429, "message": "mock plan exhausted" });
+            let body = json!({ "jsonrpc": "2.0", "id": request["id"], "error": error });
+            return (StatusCode::TOO_MANY_REQUESTS, headers, Json(body)).into_response();
+        }
+        plan_headers = Some(headers);
+    }
+    if crate::random::chance(behavior.fail_rate) {
+        let error = json!({ "code": -32603, "message": "mock failure" });
+        let body = json!({ "jsonrpc": "2.0", "id": request["id"], "error": error });
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    }
+    if crate::random::chance(behavior.empty_rate) {
+        return ([("content-type", "application/json")], "").into_response();
+    }
+    if request["method"] == "getProgramAccounts" && behavior.program_accounts > 0 {
+        return program_accounts(request["id"].clone(), behavior.program_accounts);
+    }
+    let slot = mock.slot(&behavior);
+    let context = json!({ "slot": slot, "apiVersion": "mock" });
+    let min_context_slot = request["params"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|param| param["minContextSlot"].as_u64());
+    if min_context_slot.is_some_and(|min| min > slot) {
+        let error = json!({
+            "code": -32016,
+            "message": "Minimum context slot has not been reached",
+            "data": { "contextSlot": slot },
+        });
+        return Json(json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }))
+            .into_response();
+    }
+    let result = match request["method"].as_str().unwrap_or_default() {
+        "getSlot" => json!(slot),
+        "getHealth" if behavior.slot_lag >= HEALTH_SLOT_DISTANCE => {
+            let error = json!({
+                "code": -32005,
+                "message": format!("Node is behind by {} slots", behavior.slot_lag),
+                "data": { "numSlotsBehind": behavior.slot_lag },
+            });
+            return Json(json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }))
+                .into_response();
+        }
+        "getHealth" => json!("ok"),
+        "getIdentity" => json!({ "identity": mock.identity }),
+        "getGenesisHash" => json!(behavior.cluster.genesis_hash()),
+        "getVersion" => {
+            let version = behavior.node_version;
+            json!({
+                "solana-core": version.to_string(),
+                // each release has its own feature set
+                "feature-set": (version.major << 20 | version.minor << 10 | version.patch) as u32,
+            })
+        }
+        "getEpochInfo" => json!({
+            "absoluteSlot": slot,
+            "blockHeight": slot - 1_000,
+            "epoch": slot / behavior.slots_per_epoch,
+            "slotIndex": slot % behavior.slots_per_epoch,
+            "slotsInEpoch": behavior.slots_per_epoch,
+            "transactionCount": slot * 3,
+        }),
+        "getLeaderSchedule" => {
+            let epoch = request["params"][0].as_u64().unwrap_or(slot) / behavior.slots_per_epoch;
+            if epoch > slot / behavior.slots_per_epoch + 1 {
+                Value::Null
+            } else {
+                leader_schedule(epoch, behavior.slots_per_epoch)
+            }
+        }
+        "getRecentPrioritizationFees" => (slot.saturating_sub(149)..=slot)
+            .map(|slot| json!({ "slot": slot, "prioritizationFee": slot * 7_919 % 10_000 }))
+            .collect(),
+        "sendTransaction" => match crate::dedup::submitted(&body) {
+            Some((signature, _)) => {
+                let signature = crate::base58::encode(&signature);
+                let mut landed = mock.landed.lock().unwrap();
+                // a mock is not run long enough to need anything smarter
+                if landed.len() >= 100_000 {
+                    landed.clear();
+                }
+                landed.entry(signature.clone()).or_insert(slot + 1);
+                json!(signature)
+            }
+            None => {
+                let error = json!({ "code": -32602, "message": "invalid transaction" });
+                return Json(json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }))
+                    .into_response();
+            }
+        },
+        "getSignatureStatuses" => {
+            let landed = mock.landed.lock().unwrap();
+            let statuses: Vec<Value> = request["params"][0]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(
+                    |signature| match landed.get(signature.as_str().unwrap_or_default()) {
+                        Some(&at) if at <= slot => signature_status(at, slot - at),
+                        _ => Value::Null,
+                    },
+                )
+                .collect();
+            json!({ "context": context, "value": statuses })
+        }
+        "getBalance" => json!({ "context": context, "value": 1_000_000_000u64 }),
+        "getLatestBlockhash" => json!({
+            "context": context,
+            "value": {
+                "blockhash": "EkSnNWid2cvwEVnVx9aBqawnmiCNiDgp3gUdkDPTKN1N",
+                "lastValidBlockHeight": (slot + 150).saturating_sub(behavior.blockhash_lag),
+            },
+        }),
+        "getAccountInfo" => json!({
+            "context": context,
+            "value": {
+                "data": ["AAAAAAAAAAA=", "base64"],
+                "executable": false,
+                "lamports": 1_000_000_000u64,
+                "owner": "11111111111111111111111111111111",
+                "rentEpoch": 0,
+            },
+        }),
+        _ => json!({ "context": context, "value": null }),
+    };
+    let body = Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }));
+    match plan_headers {
+        Some(headers) => (headers, body).into_response(),
+        None => body.into_response(),
+    }
+}
+
+/// Apply the phases of a scenario as their time comes.
+async fn play(mock: Arc<Mock>, phases: Vec<Phase>, name: String) {
+    for phase in phases {
+        tokio::time::sleep_until((mock.started + phase.at).into()).await;
+        let behavior = phase.behavior;
+        println!(
+            "[{}] + At {:?}: slot lag {}, latency {:?}, fail rate {}",
+            name, phase.at, behavior.slot_lag, behavior.latency, behavior.fail_rate
+        );
+        *mock.behavior.write().unwrap() = behavior;
+    }
+}
+
+/// Notify the slot to a WebSocket client once it sent `slotSubscribe`, as
+/// a validator's PubSub port does, until it disconnects.
+async fn notify_slots(stream: tokio::net::TcpStream, mock: Arc<Mock>) {
+    let Ok(mut socket) = crate::ws::accept(stream).await else {
+        return;
+    };
+    let Ok(Some(request)) = socket.receive().await else {
+        return;
+    };
+    let request: Value = serde_json::from_str(&request).unwrap_or_default();
+    if request["method"] != "slotSubscribe" {
+        let error = json!({ "code": -32601, "message": "Method not found" });
+        let answer = json!({ "jsonrpc": "2.0", "id": request["id"], "error": error });
+        let _ = socket.send(&answer.to_string()).await;
+        return;
+    }
+    let answer = json!({ "jsonrpc": "2.0", "id": request["id"], "result": 0 });
+    if socket.send(&answer.to_string()).await.is_err() {
+        return;
+    }
+    let mut notified = None;
+    loop {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let slot = mock.slot(&mock.behavior.read().unwrap());
+        if notified == Some(slot) {
+            continue;
+        }
+        notified = Some(slot);
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "slotNotification",
+            "params": {
+                "result": { "parent": slot.saturating_sub(1), "root": slot.saturating_sub(32), "slot": slot },
+                "subscription": 0,
+            },
+        });
+        if socket.send(&notification.to_string()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Serve a mock upstream on `listener`, and its slot subscriptions on
+/// `ws_listener`, until the task is dropped.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    ws_listener: Option<tokio::net::TcpListener>,
+    behavior: Behavior,
+    phases: Vec<Phase>,
+) -> std::io::Result<()> {
+    let mock = Arc::new(Mock {
+        behavior: RwLock::new(behavior),
+        started: Instant::now(),
+        landed: Mutex::default(),
+        plan_window: Mutex::default(),
+        identity: crate::base58::encode(&crate::random::u64().to_le_bytes().repeat(4)),
+    });
+    let name = format!("mock {}", listener.local_addr()?.port());
+    tokio::spawn(play(mock.clone(), phases, name));
+    if let Some(ws_listener) = ws_listener {
+        let mock = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = ws_listener.accept().await {
+                tokio::spawn(notify_slots(stream, mock.clone()));
+            }
+        });
+    }
+    let app = Router::new().route("/", post(handler)).with_state(mock);
+    axum::serve(listener, app).await
+}
+
+/// Start a mock upstream on an ephemeral local port, returning its URL.
+pub async fn spawn(behavior: Behavior, phases: Vec<Phase>) -> std::io::Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(serve(listener, None, behavior, phases));
+    Ok(url)
+}
+
+/// `mock-upstream` subcommand.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut port = 9000;
+    let mut ws_port: Option<u16> = None;
+    let mut behavior = Behavior::default();
+    let mut scenario = None;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            eprintln!("{} needs a value\n\n{}", flag, USAGE);
+            std::process::exit(1);
+        };
+        let parsed = match flag.as_str() {
+            "--port" => value.parse().map(|p| port = p).is_ok(),
+            "--ws-port" => value.parse().map(|p| ws_port = Some(p)).is_ok(),
+            "--slot-lag" => value.parse().map(|n| behavior.slot_lag = n).is_ok(),
+            "--blockhash-lag" => value.parse().map(|n| behavior.blockhash_lag = n).is_ok(),
+            "--plan-requests" => value
+                .parse()
+                .map(|n| behavior.plan_requests = Some(n))
+                .is_ok(),
+            "--slots-per-epoch" => value
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(|n| behavior.slots_per_epoch = n)
+                .is_some(),
+            "--latency-ms" => value
+                .parse()
+                .map(|ms| behavior.latency = Duration::from_millis(ms))
+                .is_ok(),
+            "--fail-rate" => value
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .map(|rate| behavior.fail_rate = rate)
+                .is_some(),
+            "--empty-rate" => value
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .map(|rate| behavior.empty_rate = rate)
+                .is_some(),
+            "--program-accounts" => value.parse().map(|n| behavior.program_accounts = n).is_ok(),
+            "--node-version" => Version::parse(value)
+                .map(|version| behavior.node_version = version)
+                .is_some(),
+            "--cluster" => Cluster::parse(value)
+                .map(|cluster| behavior.cluster = cluster)
+                .is_some(),
+            "--scenario" => {
+                scenario = Some(value.clone());
+                true
+            }
+            _ => {
+                eprintln!("unknown option {}\n\n{}", flag, USAGE);
+                std::process::exit(1);
+            }
+        };
+        if !parsed {
+            eprintln!("invalid value '{}' for {}\n\n{}", value, flag, USAGE);
+            std::process::exit(1);
+        }
+    }
+    let phases = match &scenario {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read {}: {}", path, e))?;
+            parse_scenario(&text, behavior).map_err(|e| format!("{}: {}", path, e))?
+        }
+        None => Vec::new(),
+    };
+    let address = format!("127.0.0.1:{}", port);
+    let listener = tokio::net::TcpListener::bind(&address).await?;
+    println!("Mock upstream listening on http://{}", address);
+    let ws_listener = match ws_port {
+        Some(ws_port) => {
+            let address = format!("127.0.0.1:{}", ws_port);
+            let listener = tokio::net::TcpListener::bind(&address).await?;
+            println!("Mock slot subscriptions on ws://{}", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    serve(listener, ws_listener, behavior, phases).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn call(url: &str, method: &str, params: Value) -> (u16, Value) {
+        let request = json!({ "jsonrpc": "2.0", "id": 3, "method": method, "params": params });
+        let response = crate::testing::call(url, request).await;
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    #[test]
+    fn phases_inherit_what_they_do_not_set() {
+        let scenario = "
+            [[phase]]
+            at_secs = 10
+            slot_lag = 50
+
+            [[phase]]
+            at_secs = 20.5
+            fail_rate = 0.5
+        ";
+        let phases = parse_scenario(scenario, Behavior::default()).unwrap();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[1].at, Duration::from_millis(20_500));
+        assert_eq!(phases[1].behavior.slot_lag, 50);
+        assert_eq!(phases[1].behavior.fail_rate, 0.5);
+        assert_eq!(phases[0].behavior.fail_rate, 0.0);
+        assert_eq!(phases[0].behavior.latency, Behavior::default().latency);
+    }
+
+    #[test]
+    fn invalid_scenarios_are_rejected() {
+        for (scenario, error) in [
+            ("[[phase]]\nslot_lag = 1", "phase 1: at_secs is required"),
+            (
+                "[[phase]]\nat_secs = 1\nlatency_ms = -5",
+                "phase 1: latency_ms must be a non-negative number",
+            ),
+            (
+                "[[phase]]\nat_secs = 5\n[[phase]]\nat_secs = 2",
+                "phase 2: phases must be in time order",
+            ),
+        ] {
+            let err = parse_scenario(scenario, Behavior::default()).err();
+            assert_eq!(err.as_deref(), Some(error), "{}", scenario);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_mock_answers_like_a_node() {
+        let url = crate::testing::upstream(Behavior {
+            slot_lag: HEALTH_SLOT_DISTANCE,
+            ..Behavior::default()
+        })
+        .await;
+        let (status, slot) = call(&url, "getSlot", json!([])).await;
+        assert_eq!(status, 200);
+        assert_eq!(slot["id"], 3);
+        let slot = slot["result"].as_u64().unwrap();
+        let (_, balance) = call(
+            &url,
+            "getBalance",
+            json!(["11111111111111111111111111111111"]),
+        )
+        .await;
+        assert!(balance["result"]["context"]["slot"].as_u64().unwrap() >= slot);
+        // far enough behind to be unhealthy
+        let (_, health) = call(&url, "getHealth", json!([])).await;
+        assert_eq!(health["error"]["code"], -32005);
+        let (_, ahead) = call(
+            &url,
+            "getBalance",
+            json!(["x", { "minContextSlot": slot + 1000 }]),
+        )
+        .await;
+        assert_eq!(ahead["error"]["code"], -32016);
+    }
+
+    #[tokio::test]
+    async fn failing_mocks_answer_503() {
+        let url = crate::testing::upstream(Behavior {
+            fail_rate: 1.0,
+            ..Behavior::default()
+        })
+        .await;
+        let (status, body) = call(&url, "getSlot", json!([])).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["error"]["code"], -32603);
+    }
+
+    #[tokio::test]
+    async fn scenarios_change_the_behavior_over_time() {
+        let phases = vec![Phase {
+            at: Duration::from_millis(100),
+            behavior: Behavior {
+                fail_rate: 1.0,
+                ..Behavior::default()
+            },
+        }];
+        let url = spawn(Behavior::default(), phases).await.unwrap();
+        assert_eq!(call(&url, "getSlot", json!([])).await.0, 200);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(call(&url, "getSlot", json!([])).await.0, 503);
+    }
+}
@@ -0,0 +1,335 @@
+//! Pausing all traffic without stopping the process, for emergencies such
+//! as rotating upstream credentials. While paused every JSON-RPC request is
+//! answered with a 503 at once, nothing is sent to any upstream, not even
+//! probes or slot polls, the WebSocket slot feeds are closed and `/readyz`
+//! reports not ready so load balancers drain the replica. Everything else
+//! the proxy knows is kept, and the quarantine is not re-evaluated on the
+//! silence. An admin pauses with `POST /admin/pause`, for `duration_secs`
+//! or until `POST /admin/resume`.
+
+use crate::auth::Identity;
+use crate::ServerConfig;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Longest pause that resumes by itself.
+const MAX_DURATION: Duration = Duration::from_secs(24 * 3600);
+
+/// `Retry-After` of requests refused during a pause without an end.
+const RETRY_AFTER_SECS: u64 = 10;
+
+struct Paused {
+    id: u64,
+    /// Name of the admin key that paused.
+    by: String,
+    since_ms: u64,
+    resumes_at_ms: Option<u64>,
+}
+
+pub struct Pause {
+    /// Whether traffic is paused; each upstream holds a receiver.
+    paused: watch::Sender<bool>,
+    current: Mutex<Option<Paused>>,
+    next_id: AtomicU64,
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self {
+            paused: watch::Sender::new(false),
+            current: Mutex::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Pause {
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Returns once traffic is paused.
+    pub async fn paused(&self) {
+        let _ = self.subscribe().wait_for(|paused| *paused).await;
+    }
+
+    /// Returns once traffic is not paused.
+    pub async fn resumed(&self) {
+        let _ = self.subscribe().wait_for(|paused| !*paused).await;
+    }
+
+    /// Who paused, for `/readyz`.
+    pub fn paused_by(&self) -> Option<String> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().map(|paused| paused.by.clone())
+    }
+
+    /// For `/status`.
+    pub fn report(&self) -> Value {
+        match &*self.current.lock().unwrap() {
+            Some(paused) => json!({
+                "paused": true,
+                "by": paused.by,
+                "since": crate::clock::rfc3339(paused.since_ms),
+                "resumes_at": paused.resumes_at_ms.map(crate::clock::rfc3339),
+            }),
+            None => json!({ "paused": false }),
+        }
+    }
+
+    /// The answer to a JSON-RPC request during the pause, telling the
+    /// client to come back when it is due to end.
+    pub fn refusal(&self) -> Response<Body> {
+        let resumes_at_ms = self
+            .current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|paused| paused.resumes_at_ms);
+        let retry_after = match resumes_at_ms {
+            Some(at) => at.saturating_sub(crate::clock::unix_ms()).div_ceil(1000),
+            None => RETRY_AFTER_SECS,
+        };
+        let mut response = crate::rpc::error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            crate::rpc::PAUSED,
+            "proxy paused by an operator",
+            None,
+        );
+        response
+            .headers_mut()
+            .insert(axum::http::header::RETRY_AFTER, retry_after.max(1).into());
+        response
+    }
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// End pause `id`, unless another replaced it or it already ended.
+async fn resume(config: &ServerConfig, id: Option<u64>, message: String) {
+    {
+        let mut current = config.pause.current.lock().unwrap();
+        match &*current {
+            Some(paused) if id.is_none_or(|id| id == paused.id) => {}
+            _ => return,
+        }
+        *current = None;
+        config.pause.paused.send_replace(false);
+    }
+    log(config, message);
+    config.readiness.update(config).await;
+}
+
+/// The `duration_secs` of a `POST /admin/pause` body, which may be empty.
+fn parse(body: &[u8]) -> Result<Option<Duration>, String> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let request: Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    match &request["duration_secs"] {
+        Value::Null => Ok(None),
+        secs => secs
+            .as_f64()
+            .filter(|secs| *secs > 0.0 && *secs <= MAX_DURATION.as_secs_f64())
+            .map(|secs| Some(Duration::from_secs_f64(secs)))
+            .ok_or_else(|| "'duration_secs' must be positive and at most 86400".to_string()),
+    }
+}
+
+/// `POST /admin/pause {"duration_secs"}`: stop all traffic, until resumed
+/// or for `duration_secs`. Pausing again replaces the pause in effect.
+pub async fn pause_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Extension(Identity(by)): Extension<Identity>,
+    body: Bytes,
+) -> Response<Body> {
+    let duration = match parse(&body) {
+        Ok(duration) => duration,
+        Err(message) => {
+            return crate::rpc::error_response(
+                StatusCode::BAD_REQUEST,
+                crate::rpc::INVALID_REQUEST,
+                &message,
+                None,
+            )
+        }
+    };
+    let id = config.pause.next_id.fetch_add(1, Ordering::Relaxed);
+    let since_ms = crate::clock::unix_ms();
+    {
+        let mut current = config.pause.current.lock().unwrap();
+        *current = Some(Paused {
+            id,
+            by: by.clone(),
+            since_ms,
+            resumes_at_ms: duration.map(|duration| since_ms + duration.as_millis() as u64),
+        });
+        config.pause.paused.send_replace(true);
+    }
+    let message = match duration {
+        Some(duration) => format!(
+            "Traffic paused by {} for {:?}: no requests are proxied and nothing is sent upstream",
+            by, duration
+        ),
+        None => format!(
+            "Traffic paused by {} until resumed: no requests are proxied and nothing is sent upstream",
+            by
+        ),
+    };
+    log(&config, message);
+    config.readiness.update(&config).await;
+    if let Some(duration) = duration {
+        let expiring = config.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            let message = format!("Traffic resumed: the pause by {} ran out", by);
+            resume(&expiring, Some(id), message).await;
+        });
+    }
+    Json(config.pause.report()).into_response()
+}
+
+/// `POST /admin/resume`: end the pause in effect.
+pub async fn resume_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Extension(Identity(by)): Extension<Identity>,
+) -> Json<Value> {
+    resume(&config, None, format!("Traffic resumed by {}", by)).await;
+    Json(config.pause.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{proxy, upstream};
+
+    /// A proxy with admin key "ops" over one healthy upstream.
+    async fn proxied() -> (Arc<ServerConfig>, String) {
+        let url = upstream(Behavior::default()).await;
+        proxy(&format!(
+            "[auth]\nheader = \"x-api-key\"\n[[auth.keys]]\nname = \"ops\"\nkey = \"secret-ops\"\nadmin = true\n[[auth.keys]]\nname = \"app\"\nkey = \"secret-app\"\n[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n",
+            url
+        ))
+        .await
+    }
+
+    async fn post(url: &str, path: &str, key: &str, body: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}{}", url, path))
+            .header("x-api-key", key)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn get_slot(url: &str) -> reqwest::Response {
+        post(
+            url,
+            "/",
+            "secret-app",
+            r#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#,
+        )
+        .await
+    }
+
+    #[test]
+    fn durations_are_optional_and_bounded() {
+        assert_eq!(parse(b""), Ok(None));
+        assert_eq!(parse(b"{}"), Ok(None));
+        assert_eq!(
+            parse(br#"{"duration_secs":1.5}"#),
+            Ok(Some(Duration::from_millis(1500)))
+        );
+        let out_of_range = Err("'duration_secs' must be positive and at most 86400".to_string());
+        assert_eq!(parse(br#"{"duration_secs":0}"#), out_of_range);
+        assert_eq!(parse(br#"{"duration_secs":86401}"#), out_of_range);
+        assert_eq!(parse(br#"{"duration_secs":"60"}"#), out_of_range);
+        assert!(parse(b"{").unwrap_err().starts_with("invalid JSON: "));
+    }
+
+    #[tokio::test]
+    async fn paused_requests_are_refused_until_resumed() {
+        let (config, url) = proxied().await;
+        assert_eq!(
+            post(&url, "/admin/pause", "secret-app", "").await.status(),
+            403
+        );
+        assert_eq!(get_slot(&url).await.status(), 200);
+
+        let paused = post(&url, "/admin/pause", "secret-ops", "").await;
+        assert_eq!(paused.status(), 200);
+        let report: Value = paused.json().await.unwrap();
+        assert_eq!(report["by"], "ops");
+        assert_eq!(report["resumes_at"], Value::Null);
+        let refused = get_slot(&url).await;
+        assert_eq!(refused.status(), 503);
+        assert_eq!(refused.headers()["retry-after"], "10");
+        let body: Value = refused.json().await.unwrap();
+        assert_eq!(body["error"]["code"], crate::rpc::PAUSED);
+        assert_eq!(config.servers[0].stats.successes(), 1);
+        let ready = reqwest::get(format!("{}/readyz", url)).await.unwrap();
+        assert_eq!(ready.status(), 503);
+        let ready: Value = ready.json().await.unwrap();
+        assert_eq!(ready["reason"], "traffic paused by ops");
+
+        let resumed = post(&url, "/admin/resume", "secret-ops", "").await;
+        let report: Value = resumed.json().await.unwrap();
+        assert_eq!(report, json!({ "paused": false }));
+        assert_eq!(get_slot(&url).await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn timed_pauses_resume_by_themselves() {
+        let (config, url) = proxied().await;
+        post(
+            &url,
+            "/admin/pause",
+            "secret-ops",
+            r#"{"duration_secs":0.2}"#,
+        )
+        .await;
+        let refused = get_slot(&url).await;
+        assert_eq!(refused.headers()["retry-after"], "1");
+        tokio::time::timeout(Duration::from_secs(2), config.pause.resumed())
+            .await
+            .unwrap();
+        assert_eq!(get_slot(&url).await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn a_new_pause_outlasts_the_one_it_replaced() {
+        let (config, url) = proxied().await;
+        post(
+            &url,
+            "/admin/pause",
+            "secret-ops",
+            r#"{"duration_secs":0.1}"#,
+        )
+        .await;
+        post(&url, "/admin/pause", "secret-ops", "").await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(config.pause.is_paused());
+        assert_eq!(get_slot(&url).await.status(), 503);
+    }
+}
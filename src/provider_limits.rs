@@ -0,0 +1,169 @@
+//! Provider rate limits as reported in upstream response headers. Paid
+//! plans announce the requests left before they start answering 429; once
+//! an upstream is under its configured minimum it is only used when no
+//! other upstream can answer, until the provider's reset time.
+
+use crate::clock::unix_ms;
+use crate::config::{ProviderLimitConfig, ResetFormat};
+use crate::events::EventLog;
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Stored as `remaining` before any response carried the header.
+const UNKNOWN: u64 = u64::MAX;
+
+pub struct ProviderLimits {
+    settings: ProviderLimitConfig,
+    remaining: AtomicU64,
+    /// When the provider resets the remaining count, in Unix milliseconds.
+    reset_at_ms: AtomicU64,
+    /// Whether the upstream was last seen under its minimum, to log the
+    /// change once.
+    low: AtomicBool,
+}
+
+impl ProviderLimits {
+    pub fn new(settings: ProviderLimitConfig) -> Self {
+        Self {
+            settings,
+            remaining: AtomicU64::new(UNKNOWN),
+            reset_at_ms: AtomicU64::new(0),
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Read the rate-limit headers of a response from the upstream named
+    /// `name`, logging and recording in `events` when it goes under its
+    /// minimum or back over it.
+    pub fn observe(&self, name: &str, headers: &HeaderMap, events: &EventLog) {
+        let number = |header: &str| {
+            let value: f64 = headers.get(header)?.to_str().ok()?.trim().parse().ok()?;
+            (value >= 0.0 && value.is_finite()).then_some(value)
+        };
+        let Some(remaining) = number(&self.settings.remaining_header) else {
+            return;
+        };
+        let now = unix_ms();
+        let reset_at = match (
+            number(&self.settings.reset_header),
+            self.settings.reset_format,
+        ) {
+            (Some(secs), ResetFormat::Seconds) => now + (secs * 1000.0) as u64,
+            (Some(secs), ResetFormat::UnixSeconds) => (secs * 1000.0) as u64,
+            (Some(ms), ResetFormat::UnixMillis) => ms as u64,
+            (None, _) => now + self.settings.default_hold.as_millis() as u64,
+        };
+        let remaining = remaining as u64;
+        self.remaining.store(remaining, Ordering::Relaxed);
+        self.reset_at_ms.store(reset_at, Ordering::Relaxed);
+        let low = remaining < self.settings.min_remaining;
+        if self.low.swap(low, Ordering::Relaxed) == low {
+            return;
+        }
+        let message = if low {
+            format!(
+                "{} has {} requests left on its plan, avoiding it for {}s",
+                name,
+                remaining,
+                reset_at.saturating_sub(now) / 1000
+            )
+        } else {
+            format!("{} has {} requests left on its plan again", name, remaining)
+        };
+        println!("+ {}", message);
+        events.record(message);
+    }
+
+    /// Requests the provider last said were left, `None` until a response
+    /// carried the header.
+    pub fn remaining(&self) -> Option<u64> {
+        Some(self.remaining.load(Ordering::Relaxed)).filter(|r| *r != UNKNOWN)
+    }
+
+    /// Seconds until the provider's reset, 0 once it has passed.
+    pub fn reset_in_secs(&self) -> u64 {
+        self.reset_at_ms
+            .load(Ordering::Relaxed)
+            .saturating_sub(unix_ms())
+            / 1000
+    }
+
+    /// Whether the upstream should only be used as a last resort: under
+    /// its minimum, with the reset still to come.
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed) && self.reset_at_ms.load(Ordering::Relaxed) > unix_ms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{call, proxy, serve, upstream};
+    use axum::http::HeaderMap;
+    use axum::{routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn an_upstream_low_on_its_plan_is_avoided_until_the_reset() {
+        // reports its own headers, the plan resetting 400ms after each answer
+        let asked = Arc::new(AtomicU64::new(0));
+        let counted = asked.clone();
+        let plan = serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let mut headers = HeaderMap::new();
+                headers.insert("x-left", "3".parse().unwrap());
+                let reset = crate::clock::unix_ms() + 400;
+                headers.insert("x-reset-at", reset.to_string().parse().unwrap());
+                let answer = json!({ "jsonrpc": "2.0", "id": request["id"], "result": 1 });
+                (headers, Json(answer))
+            }),
+        ))
+        .await;
+        let spare = upstream(Default::default()).await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nname = \"plan\"\nurl = \"{}\"\n[upstreams.rate_limit]\nremaining_header = \"x-left\"\nreset_header = \"x-reset-at\"\nreset_format = \"unix_ms\"\nmin_remaining = 5\n\n[[upstreams]]\nname = \"spare\"\nurl = \"{}\"\n",
+            plan, spare
+        ))
+        .await;
+        let get_slot = || {
+            call(
+                &url,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" }),
+            )
+        };
+        assert_eq!(get_slot().await.status(), 200);
+        let limits = &config.servers[0].limits;
+        // the race may be won by the spare before the plan's headers are in
+        for _ in 0..100 {
+            if limits.is_low() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(limits.is_low());
+        assert_eq!(limits.remaining(), Some(3));
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("quarantier_upstream_plan_remaining{upstream=\"plan\"} 3\n"));
+        assert!(metrics.contains("quarantier_upstream_plan_low{upstream=\"plan\"} 1\n"));
+
+        let before = asked.load(Ordering::SeqCst);
+        for _ in 0..3 {
+            assert_eq!(get_slot().await.status(), 200);
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), before);
+        // after the reset it is asked again
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!limits.is_low());
+        assert_eq!(get_slot().await.status(), 200);
+        assert_eq!(asked.load(Ordering::SeqCst), before + 1);
+    }
+}
@@ -0,0 +1,684 @@
+use crate::config::CircuitConfig;
+use crate::ServerConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where an upstream's circuit breaker stands.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Serving traffic.
+    Closed,
+    /// Pulled for failures, sent nothing until the cooldown ends.
+    Open,
+    /// After the cooldown: one probe at a time, until enough succeed.
+    HalfOpen,
+    /// Failed every probe for `dead_after`, likely gone for good: probed
+    /// every `dead_probe_interval` only, until an operator revives it.
+    Dead,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Closed, Phase::Open, Phase::HalfOpen, Phase::Dead];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Closed => "closed",
+            Phase::Open => "open",
+            Phase::HalfOpen => "half_open",
+            Phase::Dead => "dead",
+        }
+    }
+}
+
+struct State {
+    phase: Phase,
+    /// End of the cooldown, while open.
+    until: Instant,
+    /// The current cooldown, doubled by every failed probe.
+    cooldown: Duration,
+    /// Probes in a row that succeeded, while half-open.
+    successes: u64,
+    /// Number and start of the probe in flight. A probe that never reports
+    /// back, cancelled with its request, is given up on after the
+    /// upstream's request timeout.
+    probe: Option<(u64, Instant)>,
+    probes: u64,
+    /// When the circuit last opened from closed.
+    failing_since: Instant,
+    /// When the upstream was declared dead, until a probe or an operator
+    /// brings it back.
+    dead_since: Option<Instant>,
+}
+
+/// Circuit breaker of one upstream: a run of host failures opens it, as
+/// does an error rate over the threshold, and after a cooldown probes tell
+/// whether the host is back. Transitions happen here, in `reevaluate`, `admit_probe`
+/// and `probed`, which log them and record events.
+pub struct Circuit {
+    state: Mutex<State>,
+    /// Transitions into each phase.
+    entered: [AtomicU64; 4],
+}
+
+impl Circuit {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                phase: Phase::Closed,
+                until: Instant::now(),
+                cooldown: Duration::ZERO,
+                successes: 0,
+                probe: None,
+                probes: 0,
+                failing_since: Instant::now(),
+                dead_since: None,
+            }),
+            entered: Default::default(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.state.lock().unwrap().phase
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.phase() == Phase::Closed
+    }
+
+    /// How long ago the upstream was declared dead, and when it is probed
+    /// next, while it is.
+    pub fn dead(&self) -> Option<(Duration, Duration)> {
+        let state = self.state.lock().unwrap();
+        let since = state.dead_since?;
+        Some((
+            since.elapsed(),
+            state.until.saturating_duration_since(Instant::now()),
+        ))
+    }
+
+    pub fn entered(&self, phase: Phase) -> u64 {
+        self.entered[phase as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Move `state` of upstream `index` to `phase`, logging why.
+fn enter(config: &ServerConfig, index: usize, state: &mut State, phase: Phase, why: String) {
+    state.phase = phase;
+    state.successes = 0;
+    state.probe = None;
+    config.servers[index].circuit.entered[phase as usize].fetch_add(1, Ordering::Relaxed);
+    let message = format!(
+        "{} circuit {}: {}",
+        config.servers[index].name,
+        phase.as_str().replace('_', "-"),
+        why
+    );
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// The error rate of upstream `index` over the window, when it exceeds the
+/// threshold on enough attempts.
+fn error_rate_exceeded(config: &ServerConfig, index: usize) -> Option<String> {
+    let settings = config.settings.error_rate.as_ref()?;
+    let (attempts, failed) = config.servers[index].stats.window(settings.window_secs);
+    let rate = failed as f64 / attempts.max(1) as f64;
+    (attempts >= settings.min_requests && rate > settings.threshold).then(|| {
+        format!(
+            "{:.0}% of {} attempts failed in {}s",
+            rate * 100.0,
+            attempts,
+            settings.window_secs
+        )
+    })
+}
+
+/// Open the circuit of a closed upstream whose failure streak or error
+/// rate reached the threshold, and let open ones whose cooldown ended be
+/// probed.
+fn reevaluate_circuits(config: &ServerConfig) {
+    let settings = &config.settings.circuit;
+    for (index, upstream) in config.servers.iter().enumerate() {
+        // a node restarting for planned maintenance is expected to fail
+        if upstream.is_last_resort() || upstream.maintenance.is_in_window() {
+            continue;
+        }
+        let mut state = upstream.circuit.state.lock().unwrap();
+        let failing = match upstream.stats.is_failing() {
+            true => Some(format!(
+                "{} failures in a row",
+                upstream.stats.failure_streak()
+            )),
+            false => error_rate_exceeded(config, index),
+        };
+        match (state.phase, failing) {
+            (Phase::Closed, Some(failing)) => {
+                state.cooldown = settings.cooldown;
+                state.until = Instant::now() + state.cooldown;
+                state.failing_since = Instant::now();
+                let why = format!("{}, probing in {:?}", failing, state.cooldown);
+                enter(config, index, &mut state, Phase::Open, why);
+            }
+            (Phase::Open | Phase::Dead, _) if state.until <= Instant::now() => {
+                let why = "cooldown over, probing".to_string();
+                enter(config, index, &mut state, Phase::HalfOpen, why);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The number of a probe to send to upstream `index`, when its circuit is
+/// half-open, or open past the cooldown, and no other probe is in flight.
+pub fn admit_probe(config: &ServerConfig, index: usize) -> Option<u64> {
+    let upstream = &config.servers[index];
+    let mut state = upstream.circuit.state.lock().unwrap();
+    if matches!(state.phase, Phase::Open | Phase::Dead) && state.until <= Instant::now() {
+        let why = "cooldown over, probing".to_string();
+        enter(config, index, &mut state, Phase::HalfOpen, why);
+    }
+    if state.phase != Phase::HalfOpen {
+        return None;
+    }
+    let timeout = upstream.client_settings().request_timeout;
+    if state
+        .probe
+        .is_some_and(|(_, started)| started.elapsed() < timeout)
+    {
+        return None;
+    }
+    state.probes += 1;
+    state.probe = Some((state.probes, Instant::now()));
+    Some(state.probes)
+}
+
+/// Report how probe number `probe` of upstream `index` went. Enough
+/// successes in a row close the circuit; a failure opens it again for
+/// twice the last cooldown, up to the cap.
+pub fn probed(config: &ServerConfig, index: usize, probe: u64, succeeded: bool) {
+    let settings: &CircuitConfig = &config.settings.circuit;
+    let mut state = config.servers[index].circuit.state.lock().unwrap();
+    if state.phase != Phase::HalfOpen || state.probe.map(|(number, _)| number) != Some(probe) {
+        return;
+    }
+    state.probe = None;
+    if !succeeded {
+        let failing = state.failing_since.elapsed();
+        if settings.dead_after.is_some_and(|after| failing >= after) {
+            state.until = Instant::now() + settings.dead_probe_interval;
+            let why = format!(
+                "probes failing for {:?}, probing again in {:?}",
+                Duration::from_secs(failing.as_secs()),
+                settings.dead_probe_interval
+            );
+            if state.dead_since.is_none() {
+                state.dead_since = Some(Instant::now());
+                let name = &config.servers[index].name;
+                crate::alerts::notify(
+                    config,
+                    &format!(
+                        "{} has failed every probe for {}s and is considered dead; fix or remove it in the config, or revive it with POST /admin/revive",
+                        name,
+                        failing.as_secs()
+                    ),
+                    serde_json::json!({
+                        "event": "upstream_dead",
+                        "upstream": name,
+                        "failing_secs": failing.as_secs(),
+                    }),
+                );
+            }
+            return enter(config, index, &mut state, Phase::Dead, why);
+        }
+        state.cooldown = (state.cooldown * 2).min(settings.max_cooldown);
+        state.until = Instant::now() + state.cooldown;
+        let why = format!("probe failed, probing again in {:?}", state.cooldown);
+        return enter(config, index, &mut state, Phase::Open, why);
+    }
+    state.successes += 1;
+    if state.successes >= settings.probe_successes {
+        // the failures that opened the circuit are not held against it again
+        config.servers[index].stats.clear_window();
+        let why = format!("{} probes succeeded, restored", state.successes);
+        if state.dead_since.take().is_some() {
+            let name = &config.servers[index].name;
+            crate::alerts::notify(
+                config,
+                &format!("{} answers again and is no longer dead", name),
+                serde_json::json!({ "event": "upstream_revived", "upstream": name }),
+            );
+        }
+        enter(config, index, &mut state, Phase::Closed, why);
+    }
+}
+
+/// Bring dead upstream `index` back to probing at the usual pace, its
+/// failures counted afresh. Returns whether it was dead.
+pub fn revive(config: &ServerConfig, index: usize, origin: &str) -> bool {
+    let mut state = config.servers[index].circuit.state.lock().unwrap();
+    if state.dead_since.take().is_none() {
+        return false;
+    }
+    state.cooldown = config.settings.circuit.cooldown;
+    state.until = Instant::now();
+    state.failing_since = Instant::now();
+    let why = format!("revived by {}, probing", origin);
+    enter(config, index, &mut state, Phase::HalfOpen, why);
+    true
+}
+
+/// Dead upstreams do not hold back readiness: `min_healthy` is lowered to
+/// the regular upstreams left, down to one.
+pub fn min_healthy(config: &ServerConfig) -> usize {
+    let min_healthy = config.settings.min_healthy;
+    let alive = config
+        .servers
+        .iter()
+        .filter(|upstream| !upstream.is_last_resort() && upstream.circuit.dead().is_none())
+        .filter(|upstream| !upstream.identity.is_alias() && !upstream.cluster.is_wrong())
+        .count();
+    min_healthy.min(alive.max(1))
+}
+
+/// Recompute the slot-lag quarantine from the slot tracker, and the circuit
+/// breakers from the failure streaks. Both the poller and finished requests
+/// call this, so there is a single source of truth.
+/// `origin` prefixes the log lines (a request id or "poller").
+pub async fn reevaluate(config: &ServerConfig, origin: &str) {
+    reevaluate_circuits(config);
+    let fresh = config.slots.fresh();
+    // a node configured twice is one observation, and last-resort hosts
+    // are not judged by their lag so do not make up the count
+    let observed = fresh
+        .iter()
+        .zip(&config.servers)
+        .filter(|(observation, _)| observation.is_some())
+        .filter(|(_, upstream)| !upstream.is_last_resort() && !upstream.identity.is_alias())
+        .count();
+    // a lone upstream has nothing to be compared with, nothing is put off
+    let comparable = config
+        .servers
+        .iter()
+        .filter(|u| !u.is_last_resort() && !u.identity.is_alias() && !u.cluster.is_wrong())
+        .count()
+        >= 2;
+    let min_observations = config.settings.min_observations;
+    let deferred = observed < min_observations;
+    if comparable && config.stats.record_quarantine_deferral(deferred) {
+        let message = match deferred {
+            true => format!(
+                "slot-lag quarantine decisions deferred, fresh slots from {} hosts, {} needed",
+                observed, min_observations
+            ),
+            false => format!(
+                "slot-lag quarantine decisions resumed, fresh slots from {} hosts",
+                observed
+            ),
+        };
+        println!("[{}] + {}", origin, message);
+        config.events.record(message);
+    }
+    if deferred {
+        // too few to tell who is behind, keep the previous verdict
+        return;
+    }
+    let latest_slot = crate::slots::tip_of(config, &fresh).unwrap_or_default();
+    let tolerance = config.tolerance.slots();
+    let slowest_hosts: Vec<usize> = fresh
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| !config.servers[index].is_last_resort())
+        .filter(|&(index, _)| !config.servers[index].maintenance.is_in_window())
+        .filter_map(|(index, observation)| {
+            let observation = observation.as_ref()?;
+            (observation.slot + tolerance < latest_slot).then_some(index)
+        })
+        .collect();
+
+    let mut quarantine = config.quarantine.write().await;
+    if *quarantine != slowest_hosts {
+        let names: Vec<&str> = slowest_hosts
+            .iter()
+            .map(|&index| config.servers[index].name.as_str())
+            .collect();
+        println!("[{}] + Slot {} is the latest slot", origin, latest_slot);
+        println!("[{}] + Removing slowest hosts: {:?}", origin, names);
+        config.events.record(format!(
+            "slot-lag quarantine {:?} at slot {} ({})",
+            names, latest_slot, origin
+        ));
+        *quarantine = slowest_hosts;
+    }
+}
+
+/// Healthy upstreams (not quarantined, with a fresh slot within tolerance
+/// of the tip) and quarantined upstreams. Hosts without a fresh slot
+/// estimate count as neither, and so do last-resort, drained and dead
+/// upstreams, and aliases of another one.
+pub async fn host_counts(config: &ServerConfig) -> (usize, usize) {
+    let quarantine = config.quarantine.read().await;
+    let fresh = config.slots.fresh();
+    let latest_slot = crate::slots::tip_of(config, &fresh).unwrap_or_default();
+    let mut healthy = 0;
+    let mut quarantined = 0;
+    for (upstream, observation) in config.servers.iter().zip(&fresh) {
+        let counted = !upstream.is_last_resort() && !upstream.identity.is_alias();
+        if !counted || upstream.is_drained() || upstream.circuit.dead().is_some() {
+            continue;
+        } else if upstream.is_quarantined(&quarantine) {
+            quarantined += 1;
+        } else if let Some(observation) = observation {
+            if observation.slot + config.tolerance.slots() >= latest_slot {
+                healthy += 1;
+            }
+        }
+    }
+    (healthy, quarantined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::ErrorClass;
+    use crate::slots::SlotSource;
+    use crate::testing::config;
+
+    const TWO: &str = "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n\n[[upstreams]]\nurl = \"http://127.0.0.1:2\"\n";
+
+    fn error_rate(threshold: &str) -> std::sync::Arc<ServerConfig> {
+        config(&format!(
+            "{}\n[error_rate]\nwindow_secs = 30\nthreshold = {}\nmin_requests = 10\n",
+            TWO, threshold
+        ))
+    }
+
+    #[test]
+    fn an_error_rate_over_the_threshold_opens_the_circuit() {
+        let config = error_rate("0.5");
+        let stats = &config.servers[0].stats;
+        // failures between answers, so never a streak
+        for _ in 0..5 {
+            stats.record_error(ErrorClass::Http5xx);
+            stats.record_success();
+        }
+        // exactly at the threshold is not over it
+        reevaluate_circuits(&config);
+        assert!(config.servers[0].circuit.is_closed());
+        stats.record_error(ErrorClass::Http5xx);
+        reevaluate_circuits(&config);
+        assert!(config.servers[0].circuit.phase() == Phase::Open);
+        assert_eq!(config.servers[0].circuit.entered(Phase::Open), 1);
+        assert!(config.servers[1].circuit.is_closed());
+    }
+
+    #[test]
+    fn too_few_attempts_are_not_judged() {
+        let config = error_rate("0.1");
+        let stats = &config.servers[0].stats;
+        stats.record_error(ErrorClass::Http5xx);
+        for _ in 0..8 {
+            stats.record_success();
+        }
+        reevaluate_circuits(&config);
+        assert!(config.servers[0].circuit.is_closed());
+        stats.record_error(ErrorClass::Http5xx);
+        reevaluate_circuits(&config);
+        assert!(!config.servers[0].circuit.is_closed());
+    }
+
+    #[test]
+    fn the_error_rate_can_be_turned_off() {
+        let config = config(&format!("{}\n[error_rate]\nenabled = false\n", TWO));
+        for _ in 0..50 {
+            config.servers[0].stats.record_error(ErrorClass::Http5xx);
+            config.servers[0].stats.record_success();
+        }
+        reevaluate_circuits(&config);
+        assert!(config.servers[0].circuit.is_closed());
+    }
+
+    const CIRCUIT: &str =
+        "[circuit_breaker]\ncooldown_ms = 100\nmax_cooldown_ms = 250\nprobe_successes = 2\n";
+
+    /// Fail upstream 0 of `config` until its circuit opens.
+    fn trip(config: &ServerConfig) {
+        for _ in 0..crate::upstream::FAILURE_STREAK_THRESHOLD {
+            config.servers[0].stats.record_error(ErrorClass::Http5xx);
+        }
+        reevaluate_circuits(config);
+        assert!(config.servers[0].circuit.phase() == Phase::Open);
+    }
+
+    #[test]
+    fn probes_close_the_circuit_or_back_it_off() {
+        let config = config(&format!("{}{}", CIRCUIT, TWO));
+        let circuit = &config.servers[0].circuit;
+        trip(&config);
+        assert_eq!(admit_probe(&config, 0), None);
+        std::thread::sleep(Duration::from_millis(120));
+        let probe = admit_probe(&config, 0).unwrap();
+        assert!(circuit.phase() == Phase::HalfOpen);
+        // one probe in flight at a time
+        assert_eq!(admit_probe(&config, 0), None);
+        // a failed probe doubles the cooldown
+        probed(&config, 0, probe, false);
+        assert!(circuit.phase() == Phase::Open);
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(admit_probe(&config, 0), None);
+        std::thread::sleep(Duration::from_millis(100));
+        let probe = admit_probe(&config, 0).unwrap();
+        // up to the cap
+        probed(&config, 0, probe, false);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(admit_probe(&config, 0), None);
+        std::thread::sleep(Duration::from_millis(70));
+        let probe = admit_probe(&config, 0).unwrap();
+        // a late report of an earlier probe changes nothing
+        probed(&config, 0, probe - 1, false);
+        probed(&config, 0, probe, true);
+        assert!(circuit.phase() == Phase::HalfOpen);
+        let probe = admit_probe(&config, 0).unwrap();
+        probed(&config, 0, probe, true);
+        assert!(circuit.is_closed());
+        assert_eq!(circuit.entered(Phase::Open), 3);
+        assert_eq!(circuit.entered(Phase::HalfOpen), 3);
+        assert_eq!(circuit.entered(Phase::Closed), 1);
+        let events = config.events.recent();
+        let last = &events.last().unwrap().1;
+        assert!(
+            last.ends_with(" circuit closed: 2 probes succeeded, restored"),
+            "{}",
+            last
+        );
+        assert!(config.servers[1].circuit.is_closed());
+    }
+
+    #[test]
+    fn concurrent_requests_admit_a_single_probe() {
+        let config = config(&format!("{}{}", CIRCUIT, TWO));
+        trip(&config);
+        std::thread::sleep(Duration::from_millis(120));
+        let admitted: Vec<Option<u64>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| admit_probe(&config, 0)))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(admitted.iter().flatten().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_half_open_upstream_gets_one_request_at_a_time() {
+        use axum::{routing::post, Router};
+        let hits = std::sync::Arc::new(AtomicU64::new(0));
+        let probed = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    r#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":1},"value":1}}"#
+                }
+            }),
+        ))
+        .await;
+        let healthy = crate::testing::upstream(crate::mock::Behavior::default()).await;
+        let (config, url) = crate::testing::proxy(&format!(
+            "{}[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n",
+            CIRCUIT, probed, healthy
+        ))
+        .await;
+        trip(&config);
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let body = serde_json::json!({
+            "jsonrpc": "2.0", "id": 1, "method": "getBalance",
+            "params": ["11111111111111111111111111111111"],
+        });
+        let requests = (0..8).map(|_| crate::testing::call(&url, body.clone()));
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response.status(), 200);
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert!(config.servers[0].circuit.phase() == Phase::HalfOpen);
+    }
+
+    /// Pretend upstream 0 of `config` has been failing for two hours.
+    fn failing_for_hours(config: &ServerConfig) {
+        let mut state = config.servers[0].circuit.state.lock().unwrap();
+        state.failing_since = Instant::now() - Duration::from_secs(7200);
+    }
+
+    #[tokio::test]
+    async fn upstreams_failing_their_probes_for_hours_are_declared_dead() {
+        use axum::{routing::post, Json, Router};
+        let alerts = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let webhook = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let alerts = alerts.clone();
+                move |Json(alert): Json<serde_json::Value>| async move {
+                    alerts.lock().unwrap().push(alert["event"].clone());
+                }
+            }),
+        ))
+        .await;
+        let config = config(&format!(
+            "min_healthy = 2\n\n[alerts]\nwebhook_url = \"{}\"\n\n[circuit_breaker]\ncooldown_ms = 0\nprobe_successes = 1\ndead_after_secs = 3600\ndead_probe_interval_secs = 600\n{}",
+            webhook, TWO
+        ));
+        let circuit = &config.servers[0].circuit;
+        trip(&config);
+        failing_for_hours(&config);
+        let probe = admit_probe(&config, 0).unwrap();
+        probed(&config, 0, probe, false);
+        assert!(circuit.phase() == Phase::Dead);
+        let (_, next_probe) = circuit.dead().unwrap();
+        assert!(next_probe > Duration::from_secs(590));
+        // probed at the slow pace only, and no longer counted on
+        assert_eq!(admit_probe(&config, 0), None);
+        assert_eq!(min_healthy(&config), 1);
+        let axum::Json(status) =
+            crate::status::status_handler(axum::extract::State(config.clone())).await;
+        assert_eq!(status["dead"][0]["name"], config.servers[0].name);
+        assert_eq!(status["dead"].as_array().unwrap().len(), 1);
+
+        // a probe that gets through brings it back
+        circuit.state.lock().unwrap().until = Instant::now();
+        let probe = admit_probe(&config, 0).unwrap();
+        probed(&config, 0, probe, true);
+        assert!(circuit.is_closed());
+        assert!(circuit.dead().is_none());
+        assert_eq!(min_healthy(&config), 2);
+
+        // as does an operator
+        config.servers[0].stats.record_success();
+        trip(&config);
+        failing_for_hours(&config);
+        let probe = admit_probe(&config, 0).unwrap();
+        probed(&config, 0, probe, false);
+        assert!(revive(&config, 0, "test"));
+        assert!(circuit.phase() == Phase::HalfOpen);
+        assert!(circuit.dead().is_none());
+        assert!(!revive(&config, 0, "test"));
+
+        for _ in 0..200 {
+            if alerts.lock().unwrap().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut alerts = alerts.lock().unwrap().clone();
+        alerts.sort_by_key(|event| event.to_string());
+        assert_eq!(
+            alerts,
+            ["upstream_dead", "upstream_dead", "upstream_revived"]
+        );
+    }
+
+    fn hosts(count: usize, extra: &str) -> String {
+        let mut text = String::from(extra);
+        for port in 1..=count {
+            text.push_str(&format!(
+                "\n[[upstreams]]\nurl = \"http://127.0.0.1:{}\"\n",
+                port
+            ));
+        }
+        text
+    }
+
+    async fn quarantined(config: &ServerConfig) -> Vec<usize> {
+        reevaluate(config, "test").await;
+        config.quarantine.read().await.clone()
+    }
+
+    fn deferrals(config: &ServerConfig) -> u64 {
+        config.stats.quarantine_deferrals.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn two_responders_are_not_enough_when_three_are_required() {
+        let config = config(&hosts(3, "min_observations = 3\n"));
+        // the leader may be the outlier, nobody is put off on its word
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config.slots.observe(1, 100, SlotSource::Poll);
+        assert!(quarantined(&config).await.is_empty());
+        assert_eq!(deferrals(&config), 1);
+        config.slots.observe(2, 1000, SlotSource::Poll);
+        assert_eq!(quarantined(&config).await, [1]);
+        assert_eq!(deferrals(&config), 1);
+    }
+
+    #[tokio::test]
+    async fn a_single_responder_keeps_the_previous_verdict() {
+        let config = config(&hosts(2, "[slots]\nmax_age_ms = 100\n"));
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config.slots.observe(1, 100, SlotSource::Poll);
+        // two responders are the default minimum
+        assert_eq!(quarantined(&config).await, [1]);
+        assert_eq!(deferrals(&config), 0);
+        // the leader goes quiet, only the host behind is still fresh
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        config.slots.observe(1, 100, SlotSource::Poll);
+        assert_eq!(quarantined(&config).await, [1]);
+        assert_eq!(deferrals(&config), 1);
+    }
+
+    #[tokio::test]
+    async fn last_resort_hosts_neither_count_nor_get_quarantined() {
+        let hosts = format!(
+            "{}\n[[upstreams]]\nurl = \"http://127.0.0.1:9\"\nrole = \"last_resort\"\n",
+            hosts(2, "")
+        );
+        let config = config(&hosts);
+        config.slots.observe(0, 1000, SlotSource::Poll);
+        config.slots.observe(2, 100, SlotSource::Poll);
+        assert!(quarantined(&config).await.is_empty());
+        assert_eq!(deferrals(&config), 1);
+        config.slots.observe(1, 1000, SlotSource::Poll);
+        // far behind, the last resort is still not quarantined
+        assert!(quarantined(&config).await.is_empty());
+        assert_eq!(host_counts(&config).await, (2, 0));
+    }
+}
@@ -0,0 +1,250 @@
+//! Request budgets per API key over calendar days or months.
+
+use crate::config::{Quota, QuotaWindow};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy)]
+struct Period {
+    /// Unix time at which the counted window started.
+    start: u64,
+    used: u64,
+    /// Whether the soft-limit warning went out for this window.
+    warned: bool,
+}
+
+pub enum Outcome {
+    Allowed,
+    /// The request crossed the soft limit and is allowed.
+    SoftLimit {
+        used: u64,
+    },
+    /// The quota is used up until `resets_at`.
+    Exhausted {
+        resets_at: u64,
+    },
+}
+
+pub struct QuotaTracker {
+    /// UTC offset at which windows turn over.
+    offset_secs: i64,
+    /// Fraction of the quota that triggers a warning.
+    soft_limit: f64,
+    periods: Mutex<HashMap<String, Period>>,
+}
+
+impl QuotaTracker {
+    /// Tracker resuming from the `quotas` section of a saved state.
+    pub fn new(offset_secs: i64, soft_limit: f64, saved: &Value) -> Self {
+        let periods = saved
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, period)| {
+                let period = Period {
+                    start: period["window_start"].as_u64()?,
+                    used: period["used"].as_u64()?,
+                    warned: period["warned"].as_bool().unwrap_or(false),
+                };
+                Some((key.clone(), period))
+            })
+            .collect();
+        Self {
+            offset_secs,
+            soft_limit,
+            periods: Mutex::new(periods),
+        }
+    }
+
+    fn window(&self, quota: &Quota, now: u64) -> (u64, u64) {
+        let monthly = quota.window == QuotaWindow::Monthly;
+        crate::clock::calendar_window(now, self.offset_secs, monthly)
+    }
+
+    /// Count a request of `key` against its quota.
+    pub fn consume(&self, key: &str, quota: &Quota) -> Outcome {
+        self.consume_at(key, quota, crate::clock::unix_secs())
+    }
+
+    /// `consume` at the Unix time `now`.
+    fn consume_at(&self, key: &str, quota: &Quota, now: u64) -> Outcome {
+        let (start, end) = self.window(quota, now);
+        let mut periods = self.periods.lock().unwrap();
+        let period = periods.entry(key.to_string()).or_insert(Period {
+            start,
+            used: 0,
+            warned: false,
+        });
+        if period.start != start {
+            *period = Period {
+                start,
+                used: 0,
+                warned: false,
+            };
+        }
+        if period.used >= quota.limit {
+            return Outcome::Exhausted { resets_at: end };
+        }
+        period.used += 1;
+        if !period.warned && period.used as f64 >= quota.limit as f64 * self.soft_limit {
+            period.warned = true;
+            return Outcome::SoftLimit { used: period.used };
+        }
+        Outcome::Allowed
+    }
+
+    /// Consumption of the current window of every key with a quota.
+    pub fn report<'a>(&self, keys: impl Iterator<Item = (&'a str, &'a Quota)>) -> Vec<Value> {
+        let now = crate::clock::unix_secs();
+        let periods = self.periods.lock().unwrap();
+        keys.map(|(key, quota)| {
+            let (start, end) = self.window(quota, now);
+            let used = periods
+                .get(key)
+                .filter(|period| period.start == start)
+                .map_or(0, |period| period.used);
+            json!({
+                "key": key,
+                "window": quota.window.as_str(),
+                "limit": quota.limit,
+                "used": used,
+                "remaining": quota.limit.saturating_sub(used),
+                "window_start": start,
+                "resets_at": end,
+            })
+        })
+        .collect()
+    }
+
+    /// State to persist across restarts.
+    pub fn snapshot(&self) -> Value {
+        let periods = self.periods.lock().unwrap();
+        let mut out = Map::new();
+        for (key, period) in periods.iter() {
+            out.insert(
+                key.clone(),
+                json!({
+                    "window_start": period.start,
+                    "used": period.used,
+                    "warned": period.warned,
+                }),
+            );
+        }
+        Value::Object(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-15 12:00:00 UTC.
+    const NOON: u64 = 1_710_504_000;
+    const DAY: u64 = 86_400;
+
+    fn daily(limit: u64) -> Quota {
+        Quota {
+            limit,
+            window: QuotaWindow::Daily,
+        }
+    }
+
+    #[test]
+    fn a_quota_is_exhausted_until_its_window_turns_over() {
+        let tracker = QuotaTracker::new(0, 1.0, &json!({}));
+        let quota = daily(3);
+        for _ in 0..2 {
+            assert!(matches!(
+                tracker.consume_at("team-a", &quota, NOON),
+                Outcome::Allowed
+            ));
+        }
+        // the last request allowed reaches the soft limit of 100%
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, NOON),
+            Outcome::SoftLimit { used: 3 }
+        ));
+        let midnight = NOON + DAY / 2;
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, NOON + 60),
+            Outcome::Exhausted { resets_at } if resets_at == midnight
+        ));
+        // other keys have their own count
+        assert!(matches!(
+            tracker.consume_at("team-b", &quota, NOON),
+            Outcome::Allowed
+        ));
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, midnight),
+            Outcome::Allowed
+        ));
+    }
+
+    #[test]
+    fn the_soft_limit_warns_once_per_window() {
+        let tracker = QuotaTracker::new(0, 0.5, &json!({}));
+        let quota = daily(4);
+        let outcomes: Vec<bool> = (0..4)
+            .map(|_| {
+                let outcome = tracker.consume_at("team-a", &quota, NOON);
+                matches!(outcome, Outcome::SoftLimit { .. })
+            })
+            .collect();
+        assert_eq!(outcomes, [false, true, false, false]);
+        let tomorrow = NOON + DAY;
+        tracker.consume_at("team-a", &quota, tomorrow);
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, tomorrow),
+            Outcome::SoftLimit { used: 2 }
+        ));
+    }
+
+    #[test]
+    fn windows_turn_over_at_the_configured_offset() {
+        // in UTC+14 the day turns over at 10:00 UTC
+        let tracker = QuotaTracker::new(14 * 3600, 1.0, &json!({}));
+        let quota = daily(1);
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, NOON - 3 * 3600),
+            Outcome::SoftLimit { used: 1 }
+        ));
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, NOON - 3 * 3600),
+            Outcome::Exhausted { resets_at } if resets_at == NOON - 2 * 3600
+        ));
+        assert!(matches!(
+            tracker.consume_at("team-a", &quota, NOON),
+            Outcome::SoftLimit { used: 1 }
+        ));
+        let monthly = Quota {
+            limit: 1,
+            window: QuotaWindow::Monthly,
+        };
+        let utc = QuotaTracker::new(0, 1.0, &json!({}));
+        utc.consume_at("team-a", &monthly, NOON);
+        // 2024-04-01 00:00:00 UTC
+        let april = 1_711_929_600;
+        assert!(matches!(
+            utc.consume_at("team-a", &monthly, NOON + DAY),
+            Outcome::Exhausted { resets_at } if resets_at == april
+        ));
+    }
+
+    #[test]
+    fn consumption_survives_a_restart() {
+        let tracker = QuotaTracker::new(0, 0.5, &json!({}));
+        let quota = daily(2);
+        tracker.consume_at("team-a", &quota, NOON);
+        let restored = QuotaTracker::new(0, 0.5, &tracker.snapshot());
+        // the warning went out before the restart
+        assert!(matches!(
+            restored.consume_at("team-a", &quota, NOON),
+            Outcome::Allowed
+        ));
+        assert!(matches!(
+            restored.consume_at("team-a", &quota, NOON),
+            Outcome::Exhausted { .. }
+        ));
+    }
+}
@@ -0,0 +1,1026 @@
+//! The race of a request's upstream attempts, run by a dispatch task of its
+//! own. The first acceptable answer is handed to the waiting handler, and
+//! the other attempts are still read for their slots, failures and
+//! divergence. Answers that are not good enough are held back in case no
+//! upstream does better, and a 5xx may be retried once on another upstream.
+
+use crate::classify::{classify_attempt, classify_response, ErrorClass};
+use crate::dispatch::Dispatch;
+use crate::divergence::Comparison;
+use crate::failures::FailureReport;
+use crate::streaming::{self, AnswerBody};
+use crate::{
+    blockhash, chaos, costs, dedup, envelope, freshness, hedge, journal, min_context, quarantine,
+    retry, rpc, slots, unsupported, ServerConfig,
+};
+use axum::body::Bytes;
+use futures::future::{select_all, BoxFuture};
+use futures::FutureExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+
+/// Status, body and the name and time to first byte of the upstream that
+/// served it, handed from the dispatch task to the waiting handler.
+pub type Answer = (u16, AnswerBody, Option<(String, Duration)>);
+
+/// An attempt in flight, resolving to its next step.
+type Pending = BoxFuture<'static, (usize, Attempt)>;
+
+/// Progress of one upstream request, as seen by the dispatch loop.
+enum Attempt {
+    /// The headers arrived, or the request failed.
+    Sent(Result<reqwest::Response, reqwest::Error>),
+    /// The body started, with the chunks read so far.
+    Started(u16, reqwest::Response, Vec<Bytes>),
+    /// The body was read in full, streamed to the client or not.
+    Read(u16, Result<Bytes, reqwest::Error>),
+    /// The body did not fit in the `body_budget`.
+    Unkept,
+    /// Abandoned over the `[response_limits]` limit.
+    Oversize,
+    /// A hedged request that was answered before its turn to be sent.
+    Unsent,
+    /// Not sent, the upstream being at its plan's rate.
+    Limited,
+}
+
+/// The race ended early: the client left, the deadline passed or too many
+/// dispatches were lingering already.
+struct Abandoned;
+
+/// Answers kept in case no upstream does better: one that has not reached
+/// the minimum context slot, the freshest rejected as stale, the last
+/// upstream to answer over the response size limit, with the limit, and
+/// the last to answer with an empty body.
+#[derive(Default)]
+struct Held {
+    not_reached: Option<Answer>,
+    /// A 5xx answer to retry, and who gave it.
+    retryable: Option<(usize, Answer)>,
+    stale: Option<freshness::Stale>,
+    oversize: Option<(String, u64)>,
+    empty: Option<String>,
+}
+
+impl Held {
+    fn is_some(&self) -> bool {
+        self.not_reached.is_some()
+            || self.stale.is_some()
+            || self.oversize.is_some()
+            || self.empty.is_some()
+    }
+
+    /// Whether the answer is the proxy's own error for having none, or a
+    /// 5xx held back for a retry.
+    fn is_failure(&self) -> bool {
+        self.not_reached.is_none() && self.stale.is_none()
+    }
+
+    /// The best of the held answers, in that order, or the error of no
+    /// upstream answering.
+    fn answer(
+        &mut self,
+        dispatch: &Dispatch,
+        request_id: &str,
+        failures: &FailureReport,
+    ) -> Answer {
+        if let Some(answer) = self.not_reached.take() {
+            return answer;
+        }
+        if let Some((_, answer)) = self.retryable.take() {
+            return answer;
+        }
+        let (code, message, data) = match (&self.stale, &self.empty) {
+            (Some(stale), _) => (
+                freshness::STALE,
+                "every answer was behind the tip".to_string(),
+                Some(stale.data()),
+            ),
+            (None, _) if self.oversize.is_some() => {
+                let (name, limit) = self.oversize.as_ref().unwrap();
+                (
+                    rpc::RESPONSE_TOO_LARGE,
+                    format!(
+                        "the answer of upstream {} exceeds the response size limit of {} bytes, narrow the request with filters or a dataSlice",
+                        name, limit
+                    ),
+                    Some(serde_json::json!({ "max_response_bytes": limit })),
+                )
+            }
+            (None, Some(name)) => (
+                rpc::UPSTREAM_ERROR,
+                format!("upstream {} answered with an empty body", name),
+                None,
+            ),
+            (None, None) => (
+                rpc::UPSTREAM_ERROR,
+                "no upstream answered".to_string(),
+                None,
+            ),
+        };
+        let body = failure_body(dispatch, request_id, failures, (code, &message), data);
+        (502, AnswerBody::Full(body), None)
+    }
+}
+
+/// The error the proxy answers when no upstream did, with `data` and,
+/// when the request asked for debugging, the failures of each upstream.
+/// The failures are logged either way.
+fn failure_body(
+    dispatch: &Dispatch,
+    request_id: &str,
+    failures: &FailureReport,
+    (code, message): (i64, &str),
+    data: Option<serde_json::Value>,
+) -> Bytes {
+    if !failures.is_empty() {
+        println!(
+            "[{}] + WARNING: {}: {}",
+            request_id,
+            message,
+            failures.summary()
+        );
+    }
+    let data = match (dispatch.debug, data) {
+        (true, data) => {
+            let mut details = failures.data(dispatch.strategy());
+            if let Some(serde_json::Value::Object(data)) = data {
+                details.as_object_mut().unwrap().extend(data);
+            }
+            Some(details)
+        }
+        (false, data) => data,
+    };
+    rpc::error_body(code, message, data).into()
+}
+
+fn client_gone(request_id: &str) -> Abandoned {
+    println!(
+        "[{}] + Client disconnected, abandoning upstream requests",
+        request_id
+    );
+    Abandoned
+}
+
+/// Upstream `index` sent the request, once `gate`, if any, lets it go and
+/// its plan has room.
+fn attempt(
+    config: &Arc<ServerConfig>,
+    index: usize,
+    body: Bytes,
+    request_id: &str,
+    request_timeout: Option<Duration>,
+    methods: &[String],
+    gate: Option<BoxFuture<'static, bool>>,
+) -> Pending {
+    let upstream = &config.servers[index];
+    let mut request = upstream
+        .post()
+        .body(body)
+        .header("Content-Type", "application/json")
+        .header(config.settings.request_id_header.as_str(), request_id);
+    if let Some(timeout) = request_timeout {
+        request = request.timeout(timeout);
+    }
+    let request = request.send();
+    let chaos = config.chaos.effects(index);
+    if chaos.fail {
+        println!(
+            "[{}] + CHAOS: injecting a failure on {}",
+            request_id, upstream.name
+        );
+    }
+    let config = config.clone();
+    let methods = methods.to_vec();
+    async move {
+        if let Some(gate) = gate {
+            if !gate.await {
+                return (index, Attempt::Unsent);
+            }
+        }
+        if !config.servers[index].take_token() {
+            return (index, Attempt::Limited);
+        }
+        config.servers[index].stats.record_request();
+        tokio::time::sleep(chaos.delay).await;
+        if chaos.fail {
+            return (index, Attempt::Read(503, Ok(chaos::failure_body())));
+        }
+        let methods = methods.iter().map(String::as_str);
+        // a request dropped before its answer costs nothing unless
+        // `count_aborted`
+        if config.settings.costs.count_aborted {
+            costs::charge(&config, index, methods);
+            return (index, Attempt::Sent(request.await));
+        }
+        let response = request.await;
+        costs::charge(&config, index, methods);
+        (index, Attempt::Sent(response))
+    }
+    .boxed()
+}
+
+/// A request ready to race, as `proxy_request` routed it.
+pub struct Race {
+    pub config: Arc<ServerConfig>,
+    pub dispatch: Dispatch,
+    pub request_id: String,
+    pub body: Bytes,
+    pub methods: Vec<String>,
+    /// The label of `methods`, for metrics and logs.
+    pub method: String,
+    /// The key or address the request is accounted to.
+    pub client: String,
+    pub targets: Vec<usize>,
+    /// The upstream among `targets` probed for its circuit breaker, with
+    /// the probe's number.
+    pub probe: Option<(usize, u64)>,
+    pub hedge: Option<Arc<hedge::Hedge>>,
+    /// The transaction submitted, settled once answered.
+    pub signature: Option<dedup::Signature>,
+    pub journaled: Option<journal::Submission>,
+    /// Whether bodies are read in full before one is accepted.
+    pub buffered: bool,
+    /// The slots a `[strict_freshness]` answer may be behind the tip.
+    pub strict: Option<u64>,
+    /// Whether the 5xx that would win is held back for a retry.
+    pub may_retry: bool,
+    pub request_timeout: Option<Duration>,
+    pub max_response: Option<u64>,
+    /// The deadline and where it comes from.
+    pub deadline: (Duration, &'static str),
+    pub expiry: tokio::time::Instant,
+    /// Set when the answer was chosen at the soft deadline.
+    pub early: Arc<AtomicBool>,
+}
+
+/// What the dispatch loop learned so far.
+struct Progress {
+    /// Taken once the client is answered.
+    sender: Option<oneshot::Sender<Answer>>,
+    /// Held while the race goes on after the client was answered.
+    lingering: Option<OwnedSemaphorePermit>,
+    winner: Option<usize>,
+    held: Held,
+    /// Once an answer is held, the others get `[soft_deadline]` to do
+    /// better.
+    soft_expiry: Option<tokio::time::Instant>,
+    started: Instant,
+    /// When the retry was sent.
+    retried: Option<Instant>,
+    may_retry: bool,
+    comparison: Comparison,
+    failures: FailureReport,
+    journaled: Option<journal::Submission>,
+    /// Names of the upstreams asked.
+    asked: Vec<String>,
+}
+
+impl Race {
+    /// Ask the targets and hand the first acceptable answer to `tx`, or
+    /// the best one held once every upstream was heard from.
+    pub async fn run(mut self, tx: oneshot::Sender<Answer>) {
+        let mut journaled = self.journaled.take();
+        let config = &self.config;
+        let task = config.tasks.start();
+        // a submission abandoned on any path is forgotten
+        let _pending = self
+            .signature
+            .map(|signature| config.sends.pending(signature));
+        let asked: Vec<String> = self
+            .targets
+            .iter()
+            .map(|&index| config.servers[index].name.clone())
+            .collect();
+        if let Some(journaled) = journaled.as_mut() {
+            journaled.asked(&asked);
+        }
+        let attempts = self
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(position, &index)| {
+                // the probe was added last and goes at once
+                let gate = self
+                    .hedge
+                    .as_ref()
+                    .filter(|_| self.probe.is_none_or(|(probe, _)| probe != index))
+                    .map(|hedge| hedge.gate(position, index).boxed());
+                let body = self.body.clone();
+                attempt(
+                    config,
+                    index,
+                    body,
+                    &self.request_id,
+                    self.request_timeout,
+                    &self.methods,
+                    gate,
+                )
+            })
+            .collect();
+        let mut progress = Progress {
+            sender: Some(tx),
+            lingering: None,
+            winner: None,
+            held: Held::default(),
+            soft_expiry: None,
+            started: Instant::now(),
+            retried: None,
+            may_retry: self.may_retry,
+            comparison: Comparison::default(),
+            failures: FailureReport::default(),
+            journaled,
+            asked,
+        };
+        if self.race(&mut progress, attempts).await.is_err() {
+            return;
+        }
+        if progress.retried.is_some() && progress.winner.is_some() {
+            config.retries.recovered.fetch_add(1, Ordering::Relaxed);
+        }
+        let method = &self.method;
+        if config
+            .divergence
+            .record(method, &self.request_id, progress.comparison)
+        {
+            println!("[{}] + Upstreams disagree on {}", self.request_id, method);
+        }
+        quarantine::reevaluate(config, &self.request_id).await;
+        if let Some(sender) = progress.sender.take() {
+            if let Some(signature) = &self.signature {
+                config.sends.settle(signature, !progress.held.is_failure());
+            }
+            let held = &mut progress.held;
+            // nobody is left to tell when this fails
+            let _ = sender.send(held.answer(&self.dispatch, &self.request_id, &progress.failures));
+        }
+        task.complete();
+    }
+
+    /// Follow `attempts` until each is over. Until the client is answered,
+    /// its disconnection or the deadline cancels the outstanding ones.
+    async fn race(
+        &self,
+        progress: &mut Progress,
+        mut attempts: Vec<Pending>,
+    ) -> Result<(), Abandoned> {
+        let config = &self.config;
+        loop {
+            if attempts.is_empty() {
+                match self.retry(progress).await {
+                    Some(retry) => attempts.push(retry),
+                    None => break,
+                }
+            }
+            if progress.sender.is_none() && progress.lingering.is_none() {
+                progress.lingering = config.tasks.linger();
+                if progress.lingering.is_none() {
+                    println!(
+                        "[{}] + Too many dispatches still running, dropping {} upstream requests",
+                        self.request_id,
+                        attempts.len()
+                    );
+                    return Err(Abandoned);
+                }
+            }
+            let mut next = select_all(attempts);
+            let ((index, attempt), _, mut rest) = match &mut progress.sender {
+                Some(tx) => tokio::select! {
+                    next = &mut next => next,
+                    _ = tx.closed() => return Err(client_gone(&self.request_id)),
+                    _ = tokio::time::sleep_until(self.expiry) => {
+                        self.expire(progress);
+                        return Err(Abandoned);
+                    }
+                    // the held answer is returned, the stragglers are
+                    // still read for their slots
+                    _ = tokio::time::sleep_until(progress.soft_expiry.unwrap_or(self.expiry)), if progress.soft_expiry.is_some() => {
+                        attempts = next.into_inner();
+                        self.answer_early(progress, attempts.len())?;
+                        continue;
+                    }
+                },
+                None => next.await,
+            };
+            // a body that cannot be read fails the attempt like a request
+            // that cannot be sent
+            match attempt {
+                Attempt::Sent(Ok(response)) => rest.push(self.sent(progress, index, response)),
+                Attempt::Started(status, response, read) => rest.push(
+                    self.started(progress, index, status, response, read)
+                        .await?,
+                ),
+                Attempt::Sent(Err(err)) | Attempt::Read(_, Err(err)) => {
+                    self.failed(progress, index, err)?
+                }
+                Attempt::Read(status, Ok(body)) => self.read(progress, index, status, body).await?,
+                Attempt::Unsent => {
+                    if let Some(journaled) = progress.journaled.as_mut() {
+                        journaled.not_sent(&config.servers[index].name);
+                    }
+                }
+                Attempt::Limited => self.limited(progress, index),
+                Attempt::Oversize => self.oversize(progress, index),
+                Attempt::Unkept => {
+                    self.probed(index, true);
+                    println!(
+                        "[{}] + Body from {} is over the memory budget, not inspected",
+                        self.request_id, config.servers[index].name
+                    );
+                }
+            }
+            attempts = rest;
+        }
+        Ok(())
+    }
+
+    /// Hand `answer` to the client.
+    fn answer(&self, progress: &mut Progress, answer: Answer) -> Result<(), Abandoned> {
+        match progress.sender.take().unwrap().send(answer) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(client_gone(&self.request_id)),
+        }
+    }
+
+    /// Answer the client with the deadline error.
+    fn expire(&self, progress: &mut Progress) {
+        let config = &self.config;
+        let (deadline, source) = self.deadline;
+        println!(
+            "[{}] + Deadline of {:?} ({}) exceeded, abandoning upstream requests",
+            self.request_id, deadline, source
+        );
+        config.stats.record_timeout(&self.method);
+        if let Some(signature) = &self.signature {
+            config.sends.forget(signature);
+        }
+        let asked = progress.asked.iter().map(String::as_str);
+        progress.failures.pending(asked, progress.started.elapsed());
+        let data = serde_json::json!({ "deadline_ms": deadline.as_millis() as u64 });
+        let body = failure_body(
+            &self.dispatch,
+            &self.request_id,
+            &progress.failures,
+            (rpc::DEADLINE_EXCEEDED, "request deadline exceeded"),
+            Some(data),
+        );
+        let body = AnswerBody::Full(body);
+        let _ = progress.sender.take().unwrap().send((504, body, None));
+    }
+
+    /// Answer the client with the held answer at the soft deadline, while
+    /// `waiting` upstreams are still to answer.
+    fn answer_early(&self, progress: &mut Progress, waiting: usize) -> Result<(), Abandoned> {
+        let config = &self.config;
+        println!(
+            "[{}] + Soft deadline reached, answering without waiting for {} upstreams",
+            self.request_id, waiting
+        );
+        config
+            .stats
+            .soft_deadline_answers
+            .fetch_add(1, Ordering::Relaxed);
+        self.early.store(true, Ordering::Relaxed);
+        if let Some(signature) = &self.signature {
+            config.sends.settle(signature, !progress.held.is_failure());
+        }
+        let answer = progress
+            .held
+            .answer(&self.dispatch, &self.request_id, &progress.failures);
+        self.answer(progress, answer)
+    }
+
+    /// Once every attempt is over, the retry of the 5xx held back, on
+    /// another upstream, while the retry budget allows.
+    async fn retry(&self, progress: &mut Progress) -> Option<Pending> {
+        let config = &self.config;
+        let failed = progress
+            .held
+            .retryable
+            .as_ref()
+            .filter(|_| progress.may_retry && progress.sender.is_some());
+        let &(failed, (status, _, _)) = failed?;
+        progress.may_retry = false;
+        let name = &config.servers[failed].name;
+        if !config.retries.withdraw(name, status) {
+            println!(
+                "[{}] + {} answered {}, not retrying over the retry budget",
+                self.request_id, name, status
+            );
+            return None;
+        }
+        let index = {
+            let quarantine = config.quarantine.read().await;
+            retry::target(config, &self.dispatch, &quarantine, &progress.asked, failed)
+        };
+        println!(
+            "[{}] + {} answered {}, retrying on {}",
+            self.request_id, name, status, config.servers[index].name
+        );
+        if let Some(journaled) = progress.journaled.as_mut() {
+            journaled.asked(&[config.servers[index].name.clone()]);
+        }
+        let body = self.body.clone();
+        let retry = attempt(
+            config,
+            index,
+            body,
+            &self.request_id,
+            self.request_timeout,
+            &self.methods,
+            None,
+        );
+        progress.retried = Some(Instant::now());
+        Some(retry)
+    }
+
+    /// Report the outcome of the circuit breaker's probe, when upstream
+    /// `index` is it.
+    fn probed(&self, index: usize, succeeded: bool) {
+        if let Some((probe_index, probe)) = self.probe.filter(|(i, _)| *i == index) {
+            quarantine::probed(&self.config, probe_index, probe, succeeded);
+        }
+    }
+
+    /// Let the hedge send the next attempt, if the race is hedged.
+    fn hedge_failed(&self) {
+        if let Some(hedge) = &self.hedge {
+            hedge.failed();
+        }
+    }
+
+    /// The headers of upstream `index` arrived: its body is read up to the
+    /// first bytes, which enter it in the race.
+    fn sent(&self, progress: &mut Progress, index: usize, response: reqwest::Response) -> Pending {
+        let config = &self.config;
+        let upstream = &config.servers[index];
+        // the retry is the only request left once sent
+        upstream
+            .stats
+            .record_latency(progress.retried.unwrap_or(progress.started).elapsed());
+        upstream
+            .limits
+            .observe(&upstream.name, response.headers(), &config.events);
+        let status = response.status().as_u16();
+        if let Some(journaled) = progress.journaled.as_mut() {
+            journaled.answered(&upstream.name, serde_json::json!({ "status": status }));
+        }
+        let max_response = self.max_response;
+        async move {
+            // dropped unread, its connection is closed
+            if streaming::oversize(&response, max_response) {
+                return (index, Attempt::Oversize);
+            }
+            let mut response = response;
+            let attempt = match streaming::first_bytes(&mut response).await {
+                Ok((read, false)) => Attempt::Started(status, response, read),
+                Ok((read, true)) => Attempt::Read(status, Ok(read.concat().into())),
+                Err(err) => Attempt::Read(status, Err(err)),
+            };
+            (index, attempt)
+        }
+        .boxed()
+    }
+
+    /// The body of upstream `index` started: the first acceptable body to
+    /// start wins, and it is streamed to the client while it is read.
+    async fn started(
+        &self,
+        progress: &mut Progress,
+        index: usize,
+        status: u16,
+        response: reqwest::Response,
+        read: Vec<Bytes>,
+    ) -> Result<Pending, Abandoned> {
+        let config = &self.config;
+        let upstream = &config.servers[index];
+        let mut client = None;
+        // read in full to be held back instead
+        let retryable = status >= 500 && (progress.may_retry || progress.retried.is_some());
+        if progress.sender.is_some() && !self.buffered && !retryable {
+            let quarantine = config.quarantine.read().await;
+            if self.dispatch.accepts(config, index, &quarantine) {
+                let (chunks, body) = streaming::channel();
+                let served = Some((upstream.name.clone(), progress.started.elapsed()));
+                self.answer(progress, (status, AnswerBody::Stream(body), served))?;
+                progress.winner = Some(index);
+                client = Some(chunks);
+                if let Some(hedge) = &self.hedge {
+                    hedge.finish(index);
+                }
+            } else {
+                println!(
+                    "[{}] + Host {} is in quarantine, ignoring",
+                    self.request_id, upstream.name
+                );
+                let elapsed = progress.started.elapsed();
+                progress
+                    .failures
+                    .transport(&upstream.name, "quarantined", elapsed);
+                self.hedge_failed();
+            }
+        }
+        let config = config.clone();
+        let max_response = self.max_response;
+        Ok(async move {
+            let bodies = &config.bodies;
+            let body = streaming::read_body(response, read, client, bodies, max_response);
+            let attempt = match body.await {
+                Ok(streaming::Read::Kept(body)) => Attempt::Read(status, Ok(body)),
+                Ok(streaming::Read::Unkept) => Attempt::Unkept,
+                Ok(streaming::Read::Oversize) => Attempt::Oversize,
+                Err(err) => Attempt::Read(status, Err(err)),
+            };
+            (index, attempt)
+        }
+        .boxed())
+    }
+
+    /// Upstream `index` was not sent the request, being at its outbound
+    /// rate limit.
+    fn limited(&self, progress: &mut Progress, index: usize) {
+        let name = &self.config.servers[index].name;
+        println!(
+            "[{}] + Not sending to {}, it is at its outbound rate limit",
+            self.request_id, name
+        );
+        let elapsed = progress.started.elapsed();
+        progress.failures.transport(name, "rate_limited", elapsed);
+        self.hedge_failed();
+        if let Some(journaled) = progress.journaled.as_mut() {
+            journaled.not_sent(name);
+        }
+    }
+
+    /// The answer of upstream `index` went past the response size limit.
+    fn oversize(&self, progress: &mut Progress, index: usize) {
+        let config = &self.config;
+        let host = &config.servers[index].name;
+        self.probed(index, true);
+        let limit = self.max_response.unwrap_or_default();
+        config.stats.record_oversize(&self.method);
+        config.usage.record_oversize(&self.client);
+        let elapsed = progress.started.elapsed();
+        progress.failures.transport(host, "oversize", elapsed);
+        match progress.winner == Some(index) {
+            true => println!(
+                "[{}] + Answer from {} exceeded the {} byte response limit while streamed, cutting it",
+                self.request_id, host, limit
+            ),
+            false => {
+                println!(
+                    "[{}] + Answer from {} exceeds the {} byte response limit, abandoning it",
+                    self.request_id, host, limit
+                );
+                progress.held.oversize = Some((host.clone(), limit));
+                self.hedge_failed();
+            }
+        }
+    }
+
+    /// The request to upstream `index` failed, or its body broke off. A
+    /// targeted request reports its upstream's failure instead of failing
+    /// over.
+    fn failed(
+        &self,
+        progress: &mut Progress,
+        index: usize,
+        err: reqwest::Error,
+    ) -> Result<(), Abandoned> {
+        let upstream = &self.config.servers[index];
+        // the URL may carry the provider's API key
+        let err = err.without_url();
+        let class = classify_attempt(&err, self.request_timeout);
+        if let Some(journaled) = progress.journaled.as_mut() {
+            let answer = serde_json::json!({ "error": class.as_str() });
+            journaled.answered(&upstream.name, answer);
+        }
+        upstream.stats.record_error(class);
+        self.probed(index, false);
+        let elapsed = progress.started.elapsed();
+        progress
+            .failures
+            .transport(&upstream.name, class.as_str(), elapsed);
+        self.hedge_failed();
+        println!(
+            "[{}] Request to {} failed ({}): {:?}",
+            self.request_id,
+            upstream.name,
+            class.as_str(),
+            err
+        );
+        if self.dispatch.target != Some(index) || progress.sender.is_none() {
+            return Ok(());
+        }
+        let message = format!("upstream {} failed: {}", upstream.name, class.as_str());
+        let body = failure_body(
+            &self.dispatch,
+            &self.request_id,
+            &progress.failures,
+            (rpc::UPSTREAM_ERROR, &message),
+            None,
+        );
+        let served = Some((upstream.name.clone(), elapsed));
+        self.answer(progress, (502, AnswerBody::Full(body), served))
+    }
+
+    /// Upstream `index` answered in full: the answer is classified,
+    /// compared with the others and its slot recorded, and it wins unless
+    /// a better one may still come.
+    async fn read(
+        &self,
+        progress: &mut Progress,
+        index: usize,
+        status: u16,
+        body: Bytes,
+    ) -> Result<(), Abandoned> {
+        let config = &self.config;
+        let upstream = &config.servers[index];
+        let host = &upstream.name;
+        if let Some(journaled) = progress.journaled.as_mut() {
+            journaled.answered(host, journal::answer(status, &body));
+        }
+        let envelope::Inspected { json, complete } = envelope::inspect(&body);
+        // a failed submission may be retried in full
+        if let Some(signature) = self
+            .signature
+            .as_ref()
+            .filter(|_| progress.winner == Some(index))
+        {
+            let failed =
+                status != 200 || json.as_ref().is_none_or(|json| json.get("error").is_some());
+            config.sends.settle(signature, !failed);
+        }
+        let mut stale = None;
+        let class = classify_response(status, &body, json.as_ref());
+        // an empty answer never wins, it is only reported when nothing
+        // better comes
+        let empty = class == Some(ErrorClass::EmptyBody);
+        if empty && progress.sender.is_some() {
+            progress.held.empty = Some(host.clone());
+            self.hedge_failed();
+        }
+        match class {
+            Some(class) => {
+                println!(
+                    "[{}] + Response from {} classified as {}",
+                    self.request_id,
+                    host,
+                    class.as_str()
+                );
+                upstream.stats.record_error(class);
+                self.probed(index, !class.is_host_failure());
+                let code = json.as_ref().and_then(|j| j["error"]["code"].as_i64());
+                let elapsed = progress.started.elapsed();
+                progress
+                    .failures
+                    .answer(upstream, class.as_str(), elapsed, status, &body, code);
+                // batches answer call by call, an array
+                if let Some(json) = json.as_ref().filter(|json| json.is_object()) {
+                    if unsupported::is_unsupported(json) {
+                        unsupported::observe(config, index, &self.method);
+                    }
+                }
+            }
+            None => {
+                stale = self
+                    .strict
+                    .zip(json.as_ref())
+                    .and_then(|(allowance, json)| freshness::stale(config, allowance, json));
+                match stale {
+                    Some(_) => upstream.stats.record_stale(),
+                    None => upstream.stats.record_success(),
+                }
+                self.probed(index, true);
+                if let (Some(settings), "getLatestBlockhash") =
+                    (&config.settings.blockhash, self.method.as_str())
+                {
+                    if let Some(last_valid) =
+                        json.as_ref().and_then(blockhash::last_valid_block_height)
+                    {
+                        blockhash::observe(config, settings, index, last_valid);
+                    }
+                }
+                // large answers are not compared
+                if let Some(json) = json.as_ref().filter(|_| complete) {
+                    progress.comparison.add(host, json);
+                }
+            }
+        }
+        let served = Some((host.clone(), progress.started.elapsed()));
+        let answer = (status, AnswerBody::Full(body.clone()), served);
+        let retryable = status >= 500 && (progress.may_retry || progress.retried.is_some());
+        if retryable && progress.sender.is_some() {
+            match progress.may_retry {
+                true => println!(
+                    "[{}] + Holding back the {} from {} to retry unless another upstream answers",
+                    self.request_id, status, host
+                ),
+                false => println!(
+                    "[{}] + Retry on {} answered {} too",
+                    self.request_id, host, status
+                ),
+            }
+            progress.held.retryable = Some((index, answer));
+            self.hedge_failed();
+        } else if self.buffered && progress.sender.is_some() && !empty {
+            self.judge(progress, index, &body, json.as_ref(), stale, answer)
+                .await?;
+        }
+        if let (Some(soft), true) = (config.settings.soft_deadline, progress.held.is_some()) {
+            progress
+                .soft_expiry
+                .get_or_insert_with(|| tokio::time::Instant::now() + soft);
+        }
+        slots::observe_answer(config, index, json.as_ref(), &self.request_id);
+        println!(
+            "[{}] + Response from {} received in {:?}",
+            self.request_id,
+            host,
+            progress.started.elapsed()
+        );
+        if progress.winner == Some(index) && config.settings.log_bodies {
+            println!(
+                "[{}] STREAMED Response status: {:?}, body: {:?}",
+                self.request_id,
+                status,
+                // borrows the bytes unless they are not valid UTF-8
+                String::from_utf8_lossy(&body)
+            );
+        } else if progress.winner == Some(index) {
+            println!(
+                "[{}] STREAMED Response status: {:?}, {} bytes read",
+                self.request_id,
+                status,
+                body.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Decide on `answer`, read in full from upstream `index` before the
+    /// race is won: a stale one, or one short of the minimum context slot,
+    /// is held in case no upstream does better, and any other wins if its
+    /// upstream may answer.
+    async fn judge(
+        &self,
+        progress: &mut Progress,
+        index: usize,
+        body: &Bytes,
+        json: Option<&serde_json::Value>,
+        stale: Option<freshness::Stale>,
+        answer: Answer,
+    ) -> Result<(), Abandoned> {
+        let config = &self.config;
+        let upstream = &config.servers[index];
+        let host = &upstream.name;
+        if let Some(stale) = stale {
+            println!(
+                "[{}] + {} answered at slot {}, {} slots behind the tip, rejecting it",
+                self.request_id,
+                host,
+                stale.slot,
+                stale.lag()
+            );
+            let elapsed = progress.started.elapsed();
+            progress
+                .failures
+                .answer(upstream, "stale", elapsed, answer.0, body, None);
+            let held = &mut progress.held;
+            if held.stale.as_ref().is_none_or(|s| s.slot < stale.slot) {
+                held.stale = Some(stale);
+            }
+            self.hedge_failed();
+        } else if min_context::not_reached(json) {
+            println!(
+                "[{}] + {} has not reached the minimum context slot, ignoring",
+                self.request_id, host
+            );
+            // returned only if no upstream does better
+            progress.held.not_reached = Some(answer);
+            self.hedge_failed();
+        } else if self
+            .dispatch
+            .accepts(config, index, &config.quarantine.read().await)
+        {
+            self.answer(progress, answer)?;
+            progress.winner = Some(index);
+            if let Some(hedge) = &self.hedge {
+                hedge.finish(index);
+            }
+        } else {
+            self.hedge_failed();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn answer(status: u16, body: &'static str) -> Answer {
+        let served = Some(("a".to_string(), Duration::from_millis(5)));
+        (
+            status,
+            AnswerBody::Full(Bytes::from_static(body.as_bytes())),
+            served,
+        )
+    }
+
+    /// The status and body of the held answer, and who served it.
+    fn best(held: &mut Held) -> (u16, Value, Option<String>) {
+        let (status, body, served) =
+            held.answer(&Dispatch::default(), "test", &FailureReport::default());
+        let AnswerBody::Full(body) = body else {
+            panic!("held answers are read in full");
+        };
+        let body = serde_json::from_slice(&body).unwrap();
+        (status, body, served.map(|(name, _)| name))
+    }
+
+    #[test]
+    fn held_answers_are_returned_best_first() {
+        let mut held = Held {
+            not_reached: Some(answer(200, r#"{"result":1}"#)),
+            retryable: Some((0, answer(503, r#"{"error":{"code":-32005}}"#))),
+            stale: Some(freshness::Stale { slot: 90, tip: 100 }),
+            oversize: Some(("b".to_string(), 1024)),
+            empty: Some("c".to_string()),
+        };
+        assert!(held.is_some());
+        assert!(!held.is_failure());
+        let (status, body, served) = best(&mut held);
+        assert_eq!((status, body["result"].clone()), (200, 1.into()));
+        assert_eq!(served.as_deref(), Some("a"));
+        // the retry's 5xx next, then the proxy's own errors
+        assert_eq!(best(&mut held).0, 503);
+        let (status, body, served) = best(&mut held);
+        assert_eq!((status, served), (502, None));
+        assert_eq!(body["error"]["code"], freshness::STALE);
+        assert_eq!(body["error"]["data"]["lag_slots"], 10);
+        held.stale = None;
+        assert!(held.is_failure());
+        let (_, body, _) = best(&mut held);
+        assert_eq!(body["error"]["code"], rpc::RESPONSE_TOO_LARGE);
+        assert_eq!(body["error"]["data"]["max_response_bytes"], 1024);
+        held.oversize = None;
+        let (_, body, _) = best(&mut held);
+        assert_eq!(
+            body["error"]["message"],
+            "upstream c answered with an empty body"
+        );
+        held.empty = None;
+        assert!(!held.is_some());
+        let (_, body, _) = best(&mut held);
+        assert_eq!(body["error"]["message"], "no upstream answered");
+        assert_eq!(body["id"], Value::Null);
+    }
+
+    /// A node answering every call after `latency`.
+    async fn slow(latency: u64) -> String {
+        crate::testing::serve(axum::Router::new().route(
+            "/",
+            axum::routing::post(move || async move {
+                tokio::time::sleep(Duration::from_millis(latency)).await;
+                r#"{"jsonrpc":"2.0","id":1,"result":7}"#
+            }),
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn methods_time_out_on_their_own_budget() {
+        let node = slow(200).await;
+        let (config, url) = crate::testing::proxy(&format!(
+            "[routing]\ngetSlot = {{ request_timeout_ms = 50 }}\ngetBlock = {{ request_timeout_ms = 1000 }}\n\
+             [[upstreams]]\nurl = \"{}\"\nrequest_timeout_ms = 100\n",
+            node
+        ))
+        .await;
+        let call = |method: &str| {
+            crate::testing::call(
+                &url,
+                serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method }),
+            )
+        };
+        let stats = &config.servers[0].stats;
+        // a quick method over its budget is no fault of the host
+        let started = std::time::Instant::now();
+        assert_eq!(call("getSlot").await.status(), 502);
+        assert!(started.elapsed() < Duration::from_millis(150));
+        assert_eq!(stats.errors(ErrorClass::MethodTimeout), 1);
+        assert_eq!(stats.failure_streak(), 0);
+        assert_eq!(stats.window(60), (1, 0));
+        // a heavy one may take longer than the upstream's own timeout
+        let answer: Value = call("getBlock").await.json().await.unwrap();
+        assert_eq!(answer["result"], 7);
+        // while the others still time out at that
+        assert_eq!(call("getBalance").await.status(), 502);
+        assert_eq!(stats.errors(ErrorClass::ReadTimeout), 1);
+        assert_eq!(stats.failure_streak(), 1);
+    }
+}
@@ -0,0 +1,31 @@
+//! Cheap non-cryptographic randomness for sampling and jitter.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// xorshift64*, seeded per thread.
+pub fn u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Uniform in `[0, 1)`.
+pub fn f64() -> f64 {
+    (u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// True with probability `rate`.
+pub fn chance(rate: f64) -> bool {
+    rate >= 1.0 || f64() < rate
+}
@@ -0,0 +1,506 @@
+//! A quality score per upstream, to rank providers by evidence. Every
+//! answer, host failure and latency is counted by the hour, and every
+//! `SAMPLE_INTERVAL` the slot lag behind the tip and whether the upstream
+//! is quarantined are sampled, for a week, kept in the state file across
+//! restarts. Over each `[ranking] windows_hours` these give four
+//! components from 0 to 1: the success rate, the p95 latency as
+//! `1 / (1 + p95 / 500 ms)`, the average slot lag as `1 / (1 + lag / 10
+//! slots)` and the share of the time not quarantined. The score is their
+//! weighted mean times 100, over the components with data, so an upstream
+//! sent no traffic is scored on its lag and quarantine time alone. With
+//! `route_by = "score"` single and hedged requests try the upstreams by
+//! their score over the first window instead of by their last latency.
+
+use crate::config::{RankingConfig, RankingWeights, RouteBy, MAX_RANKING_HOURS};
+use crate::metrics::{HistogramSnapshot, LATENCY_BUCKETS_MS};
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Pause between samples of the slot lag and quarantine.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// p95 latency at which the latency component is one half.
+const LATENCY_HALF_MS: f64 = 500.0;
+/// Average slot lag at which the lag component is one half.
+const LAG_HALF_SLOTS: f64 = 10.0;
+
+#[derive(Clone, Default)]
+struct Hour {
+    /// Hours since the Unix epoch.
+    hour: u64,
+    answered: u64,
+    failed: u64,
+    /// Latencies by `LATENCY_BUCKETS_MS` bucket, the overflow last.
+    latency: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    lag_slots: u64,
+    lag_samples: u64,
+    sampled_secs: u64,
+    quarantined_secs: u64,
+}
+
+/// The hourly history of one upstream, in a ring of `MAX_RANKING_HOURS`.
+pub struct Quality {
+    hours: Mutex<Vec<Hour>>,
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Self {
+            hours: Mutex::new(vec![Hour::default(); MAX_RANKING_HOURS as usize]),
+        }
+    }
+}
+
+fn current_hour() -> u64 {
+    crate::clock::unix_secs() / 3600
+}
+
+/// What an upstream's history over a window amounts to.
+#[derive(Default)]
+pub struct Components {
+    pub requests: u64,
+    pub success_rate: Option<f64>,
+    pub p95_latency_ms: Option<f64>,
+    pub average_lag_slots: Option<f64>,
+    /// Share of the sampled time the upstream was quarantined.
+    pub quarantined: Option<f64>,
+}
+
+impl Components {
+    /// The composite score from 0 to 100, `None` without any data.
+    pub fn score(&self, weights: &RankingWeights) -> Option<f64> {
+        let latency = self
+            .p95_latency_ms
+            .map(|ms| 1.0 / (1.0 + ms / LATENCY_HALF_MS));
+        let lag = self
+            .average_lag_slots
+            .map(|lag| 1.0 / (1.0 + lag / LAG_HALF_SLOTS));
+        let parts = [
+            (weights.success, self.success_rate),
+            (weights.latency, latency),
+            (weights.lag, lag),
+            (
+                weights.quarantine,
+                self.quarantined.map(|share| 1.0 - share),
+            ),
+        ];
+        let (sum, weight) = parts
+            .iter()
+            .filter_map(|(weight, part)| part.map(|part| (weight * part, *weight)))
+            .fold((0.0, 0.0), |(sum, total), (part, weight)| {
+                (sum + part, total + weight)
+            });
+        (weight > 0.0).then(|| 100.0 * sum / weight)
+    }
+
+    fn report(&self, weights: &RankingWeights) -> Value {
+        let round = |value: Option<f64>| value.map(|value| (value * 1000.0).round() / 1000.0);
+        json!({
+            "score": round(self.score(weights)),
+            "requests": self.requests,
+            "success_rate": round(self.success_rate),
+            "p95_latency_ms": round(self.p95_latency_ms),
+            "average_lag_slots": round(self.average_lag_slots),
+            "quarantined": round(self.quarantined),
+        })
+    }
+}
+
+impl Quality {
+    fn update(&self, change: impl FnOnce(&mut Hour)) {
+        let hour = current_hour();
+        let mut hours = self.hours.lock().unwrap();
+        let bucket = &mut hours[(hour % MAX_RANKING_HOURS) as usize];
+        if bucket.hour != hour {
+            *bucket = Hour {
+                hour,
+                ..Hour::default()
+            };
+        }
+        change(bucket);
+    }
+
+    /// An answer, or with `failed` a host failure.
+    pub fn record_outcome(&self, failed: bool) {
+        self.update(|hour| match failed {
+            true => hour.failed += 1,
+            false => hour.answered += 1,
+        });
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let bucket = crate::metrics::latency_bucket(latency);
+        self.update(|hour| hour.latency[bucket] += 1);
+    }
+
+    fn sample(&self, lag: Option<u64>, quarantined: bool) {
+        let secs = SAMPLE_INTERVAL.as_secs();
+        self.update(|hour| {
+            if let Some(lag) = lag {
+                hour.lag_slots += lag;
+                hour.lag_samples += 1;
+            }
+            hour.sampled_secs += secs;
+            if quarantined {
+                hour.quarantined_secs += secs;
+            }
+        });
+    }
+
+    /// The components over the last `hours`, the current one included.
+    pub fn window(&self, hours: u64) -> Components {
+        let now = current_hour();
+        let mut sum = Hour::default();
+        for hour in self.hours.lock().unwrap().iter() {
+            if hour.hour > now || hour.hour + hours <= now {
+                continue;
+            }
+            sum.answered += hour.answered;
+            sum.failed += hour.failed;
+            for (total, n) in sum.latency.iter_mut().zip(hour.latency) {
+                *total += n;
+            }
+            sum.lag_slots += hour.lag_slots;
+            sum.lag_samples += hour.lag_samples;
+            sum.sampled_secs += hour.sampled_secs;
+            sum.quarantined_secs += hour.quarantined_secs;
+        }
+        let ratio = |part: u64, whole: u64| (whole > 0).then(|| part as f64 / whole as f64);
+        let latency = HistogramSnapshot {
+            buckets: sum.latency.to_vec(),
+            sum_ms: 0,
+        };
+        Components {
+            requests: sum.answered + sum.failed,
+            success_rate: ratio(sum.answered, sum.answered + sum.failed),
+            p95_latency_ms: latency.quantile(0.95),
+            average_lag_slots: ratio(sum.lag_slots, sum.lag_samples),
+            quarantined: ratio(sum.quarantined_secs, sum.sampled_secs),
+        }
+    }
+
+    /// The hours with anything recorded, to persist across restarts.
+    fn snapshot(&self) -> Value {
+        let oldest = current_hour().saturating_sub(MAX_RANKING_HOURS);
+        let hours = self.hours.lock().unwrap();
+        let mut kept: Vec<&Hour> = hours.iter().filter(|hour| hour.hour > oldest).collect();
+        kept.sort_unstable_by_key(|hour| hour.hour);
+        let kept = kept.into_iter().map(|hour| {
+            json!({
+                "hour": hour.hour,
+                "answered": hour.answered,
+                "failed": hour.failed,
+                "latency": hour.latency,
+                "lag_slots": hour.lag_slots,
+                "lag_samples": hour.lag_samples,
+                "sampled_secs": hour.sampled_secs,
+                "quarantined_secs": hour.quarantined_secs,
+            })
+        });
+        Value::Array(kept.collect())
+    }
+
+    fn restore(&self, saved: &Value) {
+        let mut hours = self.hours.lock().unwrap();
+        for saved in saved.as_array().into_iter().flatten() {
+            let count = |key: &str| saved[key].as_u64().unwrap_or_default();
+            let Some(hour) = saved["hour"].as_u64() else {
+                continue;
+            };
+            // a week or more before the hour already in its slot, so stale
+            let slot = (hour % MAX_RANKING_HOURS) as usize;
+            if hours[slot].hour > hour {
+                continue;
+            }
+            let mut latency = [0; LATENCY_BUCKETS_MS.len() + 1];
+            let buckets = saved["latency"].as_array().into_iter().flatten();
+            for (total, n) in latency.iter_mut().zip(buckets) {
+                *total = n.as_u64().unwrap_or_default();
+            }
+            hours[slot] = Hour {
+                hour,
+                answered: count("answered"),
+                failed: count("failed"),
+                latency,
+                lag_slots: count("lag_slots"),
+                lag_samples: count("lag_samples"),
+                sampled_secs: count("sampled_secs"),
+                quarantined_secs: count("quarantined_secs"),
+            };
+        }
+    }
+}
+
+/// The scores over the first window, by upstream index, for routing.
+#[derive(Default)]
+pub struct Scores {
+    first_window: RwLock<Vec<Option<f64>>>,
+}
+
+/// Histories by upstream name, for the state file.
+pub fn snapshot(config: &ServerConfig) -> Value {
+    let upstreams = config
+        .servers
+        .iter()
+        .map(|upstream| (upstream.name.clone(), upstream.stats.quality.snapshot()));
+    Value::Object(upstreams.collect())
+}
+
+/// Resume the upstreams' histories from the `ranking` section of a saved
+/// state, by name, so reordering the upstreams keeps them.
+pub fn restore(servers: &[Upstream], saved: &Value) {
+    for upstream in servers {
+        upstream.stats.quality.restore(&saved[&upstream.name]);
+    }
+}
+
+/// Whether single and hedged requests try the upstreams by score.
+pub fn routes(config: &ServerConfig) -> bool {
+    let route_by = config.settings.ranking.as_ref().map(|r| r.route_by);
+    route_by == Some(RouteBy::Score)
+}
+
+/// Put `targets` in the order of their score over the first window, those
+/// not scored yet last.
+pub fn order(config: &ServerConfig, targets: &mut [usize]) {
+    let scores = config.scores.first_window.read().unwrap();
+    let score = |index: usize| scores.get(index).copied().flatten();
+    targets.sort_by(|&a, &b| score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal));
+}
+
+/// Sample the upstreams' slot lag and quarantine every `SAMPLE_INTERVAL`
+/// and score them over the first window again, forever.
+pub async fn follow(config: Arc<ServerConfig>, settings: RankingConfig) {
+    let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let quarantine = config.quarantine.read().await.clone();
+        let fresh = config.slots.fresh();
+        let tip = fresh.iter().flatten().map(|o| o.slot).max();
+        for upstream in &config.servers {
+            let lag = tip
+                .zip(fresh[upstream.index])
+                .map(|(tip, observation)| tip.saturating_sub(observation.slot));
+            let quarantined = upstream.is_quarantined(&quarantine);
+            upstream.stats.quality.sample(lag, quarantined);
+        }
+        let scores = config
+            .servers
+            .iter()
+            .map(|upstream| {
+                let components = upstream.stats.quality.window(settings.windows[0]);
+                components.score(&settings.weights)
+            })
+            .collect();
+        *config.scores.first_window.write().unwrap() = scores;
+    }
+}
+
+/// `1h`, or `7d` for whole days past the first.
+fn window_name(hours: u64) -> String {
+    match hours > 24 && hours.is_multiple_of(24) {
+        true => format!("{}d", hours / 24),
+        false => format!("{}h", hours),
+    }
+}
+
+/// `GET /admin/ranking`: per window, the upstreams from the best score to
+/// the worst, with their components.
+pub async fn ranking_handler(State(config): State<Arc<ServerConfig>>) -> Response<Body> {
+    let Some(settings) = &config.settings.ranking else {
+        return crate::rpc::error_response(
+            StatusCode::NOT_FOUND,
+            crate::rpc::INVALID_REQUEST,
+            "the upstream ranking is disabled, set [ranking] enabled = true",
+            None,
+        );
+    };
+    let weights = &settings.weights;
+    let windows: Map<String, Value> = settings
+        .windows
+        .iter()
+        .map(|&hours| {
+            let mut ranked: Vec<(&str, Components)> = config
+                .servers
+                .iter()
+                .map(|upstream| (upstream.name.as_str(), upstream.stats.quality.window(hours)))
+                .collect();
+            ranked.sort_by(|(_, a), (_, b)| {
+                b.score(weights)
+                    .partial_cmp(&a.score(weights))
+                    .unwrap_or(Ordering::Equal)
+            });
+            let ranked = ranked.iter().map(|(name, components)| {
+                let mut report = components.report(weights);
+                report["upstream"] = json!(name);
+                report
+            });
+            (window_name(hours), Value::Array(ranked.collect()))
+        })
+        .collect();
+    Json(json!({
+        "weights": {
+            "success": weights.success,
+            "latency": weights.latency,
+            "lag": weights.lag,
+            "quarantine": weights.quarantine,
+        },
+        "route_by": match settings.route_by {
+            RouteBy::Latency => "latency",
+            RouteBy::Score => "score",
+        },
+        "windows": windows,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVEN: RankingWeights = RankingWeights {
+        success: 1.0,
+        latency: 1.0,
+        lag: 1.0,
+        quarantine: 1.0,
+    };
+
+    fn close(a: Option<f64>, b: f64) -> bool {
+        a.is_some_and(|a| (a - b).abs() < 1e-9)
+    }
+
+    /// A history made of `hours`, each `(hours ago, field, count)`.
+    fn history(hours: &[(u64, &str, u64)]) -> Quality {
+        let now = current_hour();
+        let mut saved: Vec<Map<String, Value>> = Vec::new();
+        for &(ago, field, count) in hours {
+            let hour = json!(now - ago);
+            let at = match saved.iter().position(|saved| saved["hour"] == hour) {
+                Some(at) => at,
+                None => {
+                    let mut entry = Map::new();
+                    entry.insert("hour".into(), hour);
+                    saved.push(entry);
+                    saved.len() - 1
+                }
+            };
+            let value = match field {
+                // every latency in the 250-500 ms bucket
+                "latency" => json!([0, 0, 0, 0, 0, 0, count]),
+                _ => json!(count),
+            };
+            saved[at].insert(field.into(), value);
+        }
+        let quality = Quality::default();
+        quality.restore(&json!(saved));
+        quality
+    }
+
+    #[test]
+    fn the_score_is_the_weighted_mean_of_the_components() {
+        let components = Components {
+            requests: 100,
+            success_rate: Some(1.0),
+            p95_latency_ms: Some(LATENCY_HALF_MS),
+            average_lag_slots: Some(LAG_HALF_SLOTS),
+            quarantined: Some(0.0),
+        };
+        // (1 + 1/2 + 1/2 + 1) / 4
+        assert!(close(components.score(&EVEN), 75.0));
+        let success_only = RankingWeights {
+            latency: 0.0,
+            lag: 0.0,
+            quarantine: 0.0,
+            ..EVEN
+        };
+        assert!(close(components.score(&success_only), 100.0));
+        let doubled_latency = RankingWeights {
+            latency: 2.0,
+            ..EVEN
+        };
+        // (1 + 2 * 1/2 + 1/2 + 1) / 5
+        assert!(close(components.score(&doubled_latency), 70.0));
+    }
+
+    #[test]
+    fn only_components_with_data_are_scored() {
+        // no traffic: lag and quarantine time only
+        let idle = Components {
+            average_lag_slots: Some(0.0),
+            quarantined: Some(0.5),
+            ..Components::default()
+        };
+        assert!(close(idle.score(&EVEN), 75.0));
+        assert_eq!(Components::default().score(&EVEN), None);
+    }
+
+    #[test]
+    fn each_window_counts_its_own_hours() {
+        let quality = history(&[
+            (0, "answered", 90),
+            (0, "failed", 10),
+            (0, "latency", 100),
+            (5, "failed", 100),
+            (48, "sampled_secs", 3600),
+            (48, "quarantined_secs", 3600),
+            (48, "lag_slots", 200),
+            (48, "lag_samples", 10),
+        ]);
+        let hour = quality.window(1);
+        assert_eq!(hour.requests, 100);
+        assert!(close(hour.success_rate, 0.9));
+        // interpolated in the 250-500 ms bucket
+        assert!(close(hour.p95_latency_ms, 487.5));
+        assert_eq!(hour.quarantined, None);
+        assert_eq!(hour.average_lag_slots, None);
+        let day = quality.window(24);
+        assert_eq!(day.requests, 200);
+        assert!(close(day.success_rate, 0.45));
+        assert_eq!(day.quarantined, None);
+        let week = quality.window(MAX_RANKING_HOURS);
+        assert_eq!(week.requests, 200);
+        assert!(close(week.quarantined, 1.0));
+        assert!(close(week.average_lag_slots, 20.0));
+        // a day quarantined and lagging drags the week below the day
+        assert!(week.score(&EVEN).unwrap() < day.score(&EVEN).unwrap());
+    }
+
+    #[test]
+    fn hours_older_than_the_window_are_left_out() {
+        let quality = history(&[(3, "answered", 10), (MAX_RANKING_HOURS + 3, "failed", 10)]);
+        // the older hour falls in the newer one's slot, the newer is kept
+        assert_eq!(quality.window(MAX_RANKING_HOURS).requests, 10);
+        let quality = history(&[(MAX_RANKING_HOURS, "failed", 10), (1, "answered", 1)]);
+        assert_eq!(quality.window(MAX_RANKING_HOURS).requests, 1);
+    }
+
+    #[test]
+    fn histories_survive_a_restart() {
+        let quality = history(&[(0, "answered", 7), (30, "failed", 3), (2, "latency", 4)]);
+        let restored = Quality::default();
+        restored.restore(&quality.snapshot());
+        let (before, after) = (quality.window(48), restored.window(48));
+        assert_eq!(after.requests, 10);
+        assert_eq!(after.success_rate, before.success_rate);
+        assert_eq!(after.p95_latency_ms, before.p95_latency_ms);
+    }
+
+    #[test]
+    fn windows_are_named_in_days_past_the_first() {
+        assert_eq!(window_name(1), "1h");
+        assert_eq!(window_name(24), "24h");
+        assert_eq!(window_name(36), "36h");
+        assert_eq!(window_name(168), "7d");
+    }
+}
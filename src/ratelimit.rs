@@ -0,0 +1,288 @@
+//! Lock-free token buckets, implemented as GCRA: a single atomic holds the
+//! theoretical arrival time of the next request.
+
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Sustained rate and burst of a bucket.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Limit {
+    pub rps: f64,
+    pub burst: u32,
+}
+
+pub struct TokenBucket {
+    limit: Limit,
+    /// Nanoseconds between tokens.
+    interval: u64,
+    /// Nanoseconds since `epoch()` at which the bucket is full again.
+    tat: AtomicU64,
+}
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn now() -> u64 {
+    epoch().elapsed().as_nanos() as u64
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            interval: (1e9 / limit.rps.max(1e-9)) as u64,
+            tat: AtomicU64::new(now()),
+        }
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Tokens left, each good for one request right away.
+    pub fn available(&self) -> u32 {
+        let now = now();
+        let tolerance = self.interval * self.limit.burst.max(1) as u64;
+        let used = self.tat.load(Ordering::Relaxed).saturating_sub(now);
+        (tolerance.saturating_sub(used) / self.interval.max(1)) as u32
+    }
+
+    /// Take a token, or say how long until one is available.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_acquire_at(now())
+    }
+
+    /// `try_acquire` at `now`, in nanoseconds since `epoch()`.
+    fn try_acquire_at(&self, now: u64) -> Result<(), Duration> {
+        let tolerance = self.interval * self.limit.burst.max(1) as u64;
+        let mut tat = self.tat.load(Ordering::Relaxed);
+        loop {
+            let next = tat.max(now) + self.interval;
+            if next - now > tolerance {
+                return Err(Duration::from_nanos(next - now - tolerance));
+            }
+            match self
+                .tat
+                .compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => tat = current,
+            }
+        }
+    }
+}
+
+const SHARDS: usize = 16;
+
+struct Shard<K> {
+    /// Bucket and last-use stamp per client.
+    buckets: HashMap<K, (TokenBucket, u64)>,
+    /// Clients by last-use stamp, oldest first.
+    order: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K> Default for Shard<K> {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+/// One bucket per client, in a bounded table that evicts the least recently
+/// seen clients so sprayed addresses or identities cannot exhaust memory.
+pub struct KeyedLimiter<K> {
+    limit: Limit,
+    max_per_shard: usize,
+    shards: Vec<Mutex<Shard<K>>>,
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    pub fn new(limit: Limit, max_clients: usize) -> Self {
+        Self {
+            limit,
+            max_per_shard: max_clients.div_ceil(SHARDS).max(1),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn try_acquire(&self, client: &K) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap();
+        let shard = &mut *shard;
+        shard.clock += 1;
+        let stamp = shard.clock;
+        if let Some((_, last)) = shard.buckets.get(client) {
+            shard.order.remove(last);
+        } else if shard.buckets.len() >= self.max_per_shard {
+            if let Some((_, oldest)) = shard.order.pop_first() {
+                shard.buckets.remove(&oldest);
+            }
+        }
+        shard.order.insert(stamp, client.clone());
+        let (bucket, last) = shard
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| (TokenBucket::new(self.limit), stamp));
+        *last = stamp;
+        bucket.try_acquire()
+    }
+}
+
+/// Throttle proxied requests per client address before anything else runs.
+pub async fn ip_middleware(
+    State(config): State<Arc<ServerConfig>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (Some(limiter), Some(ConnectInfo(peer))) = (
+        &config.ip_limiter,
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) else {
+        return next.run(request).await;
+    };
+    let ip = crate::client::client_ip(*peer, request.headers(), &config.settings.client_ip);
+    if let Err(wait) = limiter.try_acquire(&ip) {
+        config.usage.record_throttled(&ip.to_string());
+        return crate::rpc::rate_limited(wait);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    const MS: u64 = 1_000_000;
+
+    fn bucket(rps: f64, burst: u32) -> (TokenBucket, u64) {
+        let bucket = TokenBucket::new(Limit { rps, burst });
+        let start = bucket.tat.load(Ordering::Relaxed);
+        (bucket, start)
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst() {
+        let (bucket, start) = bucket(10.0, 3);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire_at(start), Ok(()));
+        }
+        // the fourth waits for the first token to come back
+        assert_eq!(
+            bucket.try_acquire_at(start),
+            Err(Duration::from_millis(100))
+        );
+        assert_eq!(
+            bucket.try_acquire_at(start + 40 * MS),
+            Err(Duration::from_millis(60))
+        );
+    }
+
+    #[test]
+    fn tokens_come_back_one_interval_apart() {
+        let (bucket, start) = bucket(10.0, 2);
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert!(bucket.try_acquire_at(start + 99 * MS).is_err());
+        assert!(bucket.try_acquire_at(start + 100 * MS).is_ok());
+        assert!(bucket.try_acquire_at(start + 100 * MS).is_err());
+        // idle time refills up to the burst, not beyond
+        let later = start + 10_000 * MS;
+        assert!(bucket.try_acquire_at(later).is_ok());
+        assert!(bucket.try_acquire_at(later).is_ok());
+        assert_eq!(
+            bucket.try_acquire_at(later),
+            Err(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn a_zero_burst_still_allows_one_request() {
+        let (bucket, start) = bucket(2.0, 0);
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert_eq!(
+            bucket.try_acquire_at(start),
+            Err(Duration::from_millis(500))
+        );
+    }
+
+    fn clients(limiter: &KeyedLimiter<IpAddr>) -> usize {
+        let shards = limiter.shards.iter();
+        shards
+            .map(|shard| shard.lock().unwrap().buckets.len())
+            .sum()
+    }
+
+    #[test]
+    fn the_least_recently_seen_address_is_evicted() {
+        // a single request each, never refilled during the test
+        let limit = Limit {
+            rps: 0.001,
+            burst: 1,
+        };
+        let limiter = KeyedLimiter::new(limit, SHARDS * 4);
+        // five addresses sharing a shard, which holds four
+        let shard = |ip: &IpAddr| {
+            let mut hasher = DefaultHasher::new();
+            ip.hash(&mut hasher);
+            hasher.finish() as usize % SHARDS
+        };
+        let ips: Vec<IpAddr> = (0..=u16::MAX)
+            .map(|i| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]))
+            .collect();
+        let same: Vec<IpAddr> = ips
+            .iter()
+            .filter(|ip| shard(ip) == shard(&ips[0]))
+            .take(5)
+            .copied()
+            .collect();
+        for ip in &same[..4] {
+            assert!(limiter.try_acquire(ip).is_ok());
+        }
+        // seen again, so the second is now the least recently seen
+        assert!(limiter.try_acquire(&same[0]).is_err());
+        assert!(limiter.try_acquire(&same[4]).is_ok());
+        assert_eq!(clients(&limiter), 4);
+        // the first kept its empty bucket, the second was forgotten
+        assert!(limiter.try_acquire(&same[0]).is_err());
+        assert!(limiter.try_acquire(&same[1]).is_ok());
+        assert_eq!(clients(&limiter), 4);
+    }
+
+    #[test]
+    fn the_table_never_grows_past_its_cap() {
+        let limit = Limit { rps: 1.0, burst: 1 };
+        let limiter = KeyedLimiter::new(limit, 64);
+        for i in 0..64 {
+            let _ = limiter.try_acquire(&IpAddr::from([10, 0, 0, i]));
+        }
+        assert!(clients(&limiter) <= 64);
+        for i in 0..2000u32 {
+            let _ = limiter.try_acquire(&IpAddr::from(i.to_be_bytes()));
+            assert!(clients(&limiter) <= 64);
+        }
+        // every shard filled up to its share
+        assert_eq!(clients(&limiter), 64);
+    }
+}
@@ -0,0 +1,147 @@
+//! Hot reload of the config file on SIGHUP. Only some settings can change
+//! at runtime: API keys, the ACL and the timeouts of the upstreams, matched
+//! by name or, when renamed, by URL; the rest keep their startup values
+//! until a restart.
+
+use crate::config::Config;
+use crate::upstream::ClientSettings;
+use crate::ServerConfig;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Reload `path` on every SIGHUP. A config that fails to load is reported
+/// and the running one stays in effect.
+pub async fn reload_on_sighup(config: Arc<ServerConfig>, path: String) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            println!(
+                "+ Config reload disabled, cannot listen for SIGHUP: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match Config::load(&path) {
+            Ok(settings) => {
+                apply(&config, &settings);
+                config.generation.record(format!("reload of {}", path));
+            }
+            Err(err) => {
+                println!(
+                    "+ Config reload failed, keeping the running config: {}",
+                    err
+                );
+                config
+                    .events
+                    .record(format!("config reload failed: {}", err));
+            }
+        }
+    }
+}
+
+fn apply(config: &ServerConfig, settings: &Config) {
+    let keys = crate::auth::ApiKeys::new(&settings.auth, Some(&config.api_keys()));
+    println!(
+        "+ Config reloaded: {} API keys, {} allowed and {} denied networks",
+        keys.len(),
+        settings.acl.allow.len(),
+        settings.acl.deny.len()
+    );
+    config
+        .events
+        .record(format!("config reloaded, {} API keys", keys.len()));
+    *config.api_keys.write().unwrap() = Arc::new(keys);
+    config.acl.replace(settings.acl.clone());
+    for upstream in &config.servers {
+        // a renamed upstream is still known by its URL
+        let reloaded = settings
+            .upstreams
+            .iter()
+            .find(|u| u.name == upstream.name)
+            .or_else(|| settings.upstreams.iter().find(|u| u.url == upstream.url));
+        let Some(reloaded) = reloaded else {
+            continue;
+        };
+        if reloaded.name != upstream.name {
+            let message = format!(
+                "upstream {} is named {} in the reloaded config, it keeps its state and the new name applies after a restart",
+                upstream.name, reloaded.name
+            );
+            println!("+ Config reload: {}", message);
+            config.events.record(message);
+        }
+        // a dead upstream still named in the config gets another chance
+        crate::quarantine::revive(config, upstream.index, "a config reload");
+        // the others keep their pools and open connections
+        let running = upstream.client_settings();
+        let changes = running.changes(&ClientSettings::of(reloaded));
+        if changes.is_empty() {
+            continue;
+        }
+        upstream.rebuild_client(ClientSettings::of(reloaded));
+        let message = format!(
+            "rebuilt the client of {}: {}",
+            upstream.name,
+            changes.join(", ")
+        );
+        println!("+ Config reload {}", message);
+        config.events.record(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const RUNNING: &str = "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\nname = \"a\"\n\n[[upstreams]]\nurl = \"http://127.0.0.1:2\"\nname = \"b\"\n";
+
+    fn rebuilt(config: &ServerConfig) -> Vec<String> {
+        let events = config.events.recent();
+        let events = events.into_iter().map(|(_, event)| event);
+        events
+            .filter(|event| event.starts_with("rebuilt"))
+            .collect()
+    }
+
+    #[test]
+    fn only_changed_clients_are_rebuilt() {
+        let config = crate::testing::config(RUNNING);
+        let reloaded = RUNNING.replace("name = \"b\"", "name = \"b\"\nrequest_timeout_ms = 30000");
+        apply(&config, &Config::parse(&reloaded).unwrap());
+        assert_eq!(
+            rebuilt(&config),
+            ["rebuilt the client of b: request timeout 5s -> 30s"]
+        );
+        let settings = |index: usize| config.servers[index].client_settings();
+        assert_eq!(settings(0).request_timeout, Duration::from_secs(5));
+        assert_eq!(settings(1).request_timeout, Duration::from_secs(30));
+        // reloading the same file again rebuilds nothing
+        apply(&config, &Config::parse(&reloaded).unwrap());
+        assert_eq!(rebuilt(&config).len(), 1);
+    }
+
+    #[test]
+    fn renamed_upstreams_are_matched_by_url() {
+        let config = crate::testing::config(RUNNING);
+        let reloaded = RUNNING
+            .replace(
+                "name = \"a\"",
+                "name = \"renamed\"\nconnect_timeout_ms = 100",
+            )
+            .replace("http://127.0.0.1:2", "http://127.0.0.1:3");
+        apply(&config, &Config::parse(&reloaded).unwrap());
+        assert_eq!(
+            rebuilt(&config),
+            ["rebuilt the client of a: connect timeout 1s -> 100ms"]
+        );
+        assert_eq!(config.servers[0].name, "a");
+        // b is neither named nor found at its URL, it keeps its client
+        assert_eq!(
+            config.servers[1].client_settings().connect_timeout,
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! `replay` subcommand: re-send recorded requests to a proxy or a single
+//! upstream, with their original spacing or at a fixed rate, and compare
+//! the latencies and statuses with the recorded ones. Used to try config
+//! changes and new providers against real traffic shapes.
+//!
+//! The file holds one JSON object per line with the request in `body` (a
+//! JSON-RPC object or batch, or a string holding one). `ts` (RFC 3339 or
+//! Unix milliseconds), `status` and `duration_ms`, as in the JSON access
+//! log, are used when present.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str = "Usage: quarantier replay --file <FILE> --target <URL> [options]
+  --file <FILE>          recorded requests, one JSON object per line
+  --target <URL>         proxy or upstream to send them to
+  --rate <N>             requests per second (default: the recorded spacing)
+  --speed <X>            divide the recorded spacing by X (default 1)
+  --header <NAME:VALUE>  add a header to every request, e.g. an API key
+  --include-writes       also replay sendTransaction and requestAirdrop
+  --json <FILE>          also write the results as JSON, - for stdout";
+
+/// Methods with side effects, skipped unless `--include-writes` is given.
+const WRITE_METHODS: [&str; 2] = ["sendTransaction", "requestAirdrop"];
+
+struct Options {
+    file: String,
+    target: String,
+    rate: Option<f64>,
+    speed: f64,
+    headers: Vec<(String, String)>,
+    include_writes: bool,
+    json: Option<String>,
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut file = None;
+    let mut target = None;
+    let mut options = Options {
+        file: String::new(),
+        target: String::new(),
+        rate: None,
+        speed: 1.0,
+        headers: Vec::new(),
+        include_writes: false,
+        json: None,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--include-writes" {
+            options.include_writes = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let positive = || {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|n| *n > 0.0 && n.is_finite())
+                .ok_or_else(|| format!("{} expects a positive number, got '{}'", flag, value))
+        };
+        match flag.as_str() {
+            "--file" => file = Some(value.clone()),
+            "--target" => target = Some(value.clone()),
+            "--rate" => options.rate = Some(positive()?),
+            "--speed" => options.speed = positive()?,
+            "--json" => options.json = Some(value.clone()),
+            "--header" => {
+                let (name, header) = value
+                    .split_once(':')
+                    .ok_or_else(|| format!("--header expects NAME:VALUE, got '{}'", value))?;
+                options
+                    .headers
+                    .push((name.trim().to_string(), header.trim().to_string()));
+            }
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    options.file = file.ok_or("--file is required")?;
+    options.target = target.ok_or("--target is required")?;
+    Ok(options)
+}
+
+/// A recorded request and what it got originally.
+struct Sample {
+    body: String,
+    method: String,
+    at_ms: Option<u64>,
+    status: Option<u16>,
+    duration_ms: Option<f64>,
+}
+
+/// Samples to replay, and how many write requests were skipped.
+fn load(text: &str, include_writes: bool) -> Result<(Vec<Sample>, usize), String> {
+    let mut samples = Vec::new();
+    let mut skipped = 0;
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let body = match &record["body"] {
+            Value::String(body) => body.clone(),
+            Value::Null => return Err(format!("line {}: no request body", i + 1)),
+            body => body.to_string(),
+        };
+        let methods = crate::rpc::request_methods(body.as_bytes());
+        if !include_writes && methods.iter().any(|m| WRITE_METHODS.contains(&m.as_str())) {
+            skipped += 1;
+            continue;
+        }
+        let at_ms = match &record["ts"] {
+            Value::String(ts) => Some(
+                crate::clock::parse_rfc3339(ts)
+                    .ok_or_else(|| format!("line {}: invalid ts '{}'", i + 1, ts))?,
+            ),
+            ts => ts.as_u64(),
+        };
+        samples.push(Sample {
+            body,
+            method: crate::rpc::method_label(&methods),
+            at_ms,
+            status: record["status"].as_u64().map(|status| status as u16),
+            duration_ms: record["duration_ms"].as_f64(),
+        });
+    }
+    Ok((samples, skipped))
+}
+
+/// When each sample is sent, relative to the start of the replay.
+fn schedule(samples: &[Sample], options: &Options) -> Result<Vec<Duration>, String> {
+    if let Some(rate) = options.rate {
+        return Ok((0..samples.len())
+            .map(|i| Duration::from_secs_f64(i as f64 / rate))
+            .collect());
+    }
+    let times: Vec<u64> = samples
+        .iter()
+        .map(|sample| sample.at_ms)
+        .collect::<Option<_>>()
+        .ok_or("some samples have no ts, pass --rate to replay them")?;
+    let first = times.iter().copied().min().unwrap_or_default();
+    Ok(times
+        .iter()
+        .map(|at| Duration::from_secs_f64((at - first) as f64 / 1000.0 / options.speed))
+        .collect())
+}
+
+/// Status of the replayed request, 0 when no response arrived.
+async fn send(client: &reqwest::Client, options: &Options, body: String) -> (u16, Duration) {
+    let started = Instant::now();
+    let mut request = client
+        .post(&options.target)
+        .header("Content-Type", "application/json")
+        .body(body);
+    for (name, value) in &options.headers {
+        request = request.header(name, value);
+    }
+    let status = match request.send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            match response.bytes().await {
+                Ok(_) => status,
+                Err(_) => 0,
+            }
+        }
+        Err(_) => 0,
+    };
+    (status, started.elapsed())
+}
+
+#[derive(Default)]
+struct MethodResults {
+    latencies_ms: Vec<f64>,
+    statuses: BTreeMap<u16, u64>,
+    recorded_ms: Vec<f64>,
+    recorded_statuses: BTreeMap<u16, u64>,
+    /// Requests whose status differs from the recorded one.
+    mismatches: u64,
+}
+
+fn percentile(values: &mut [f64], q: f64) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let index = ((values.len() as f64 * q).ceil() as usize).saturating_sub(1);
+    values.get(index).map(|ms| (ms * 1000.0).round() / 1000.0)
+}
+
+fn statuses(counts: &BTreeMap<u16, u64>) -> Value {
+    counts
+        .iter()
+        .map(|(status, n)| {
+            let status = match status {
+                0 => "error".to_string(),
+                status => status.to_string(),
+            };
+            (status, json!(n))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn report(
+    options: &Options,
+    mut methods: BTreeMap<String, MethodResults>,
+    skipped: usize,
+    elapsed: Duration,
+) -> Value {
+    let requests: usize = methods.values().map(|m| m.latencies_ms.len()).sum();
+    let per_method: serde_json::Map<String, Value> = methods
+        .iter_mut()
+        .map(|(method, results)| {
+            let entry = json!({
+                "requests": results.latencies_ms.len(),
+                "latency_ms": {
+                    "p50": percentile(&mut results.latencies_ms, 0.5),
+                    "p99": percentile(&mut results.latencies_ms, 0.99),
+                },
+                "statuses": statuses(&results.statuses),
+                "recorded": {
+                    "latency_ms": {
+                        "p50": percentile(&mut results.recorded_ms, 0.5),
+                        "p99": percentile(&mut results.recorded_ms, 0.99),
+                    },
+                    "statuses": statuses(&results.recorded_statuses),
+                },
+                "status_mismatches": results.mismatches,
+            });
+            (method.clone(), entry)
+        })
+        .collect();
+    json!({
+        "file": options.file,
+        "target": options.target,
+        "rate": options.rate,
+        "duration_secs": elapsed.as_secs_f64(),
+        "requests": requests,
+        "skipped_writes": skipped,
+        "methods": per_method,
+    })
+}
+
+fn print_report(report: &Value) {
+    println!(
+        "{} requests replayed in {:.1}s against {}, {} write requests skipped",
+        report["requests"],
+        report["duration_secs"].as_f64().unwrap_or_default(),
+        report["target"].as_str().unwrap_or_default(),
+        report["skipped_writes"]
+    );
+    let ms = |value: &Value| {
+        value
+            .as_f64()
+            .map_or("-".to_string(), |ms| format!("{:.1}", ms))
+    };
+    let statuses = |value: &Value| {
+        let counts: Vec<String> = value
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(status, n)| format!("{}x{}", status, n))
+            .collect();
+        if counts.is_empty() {
+            "-".to_string()
+        } else {
+            counts.join(",")
+        }
+    };
+    let mut rows = vec![[
+        "METHOD",
+        "N",
+        "P50",
+        "P99",
+        "REC P50",
+        "REC P99",
+        "STATUSES",
+        "REC STATUSES",
+        "MISMATCHED",
+    ]
+    .map(String::from)];
+    for (method, results) in report["methods"].as_object().into_iter().flatten() {
+        let recorded = &results["recorded"];
+        rows.push([
+            method.clone(),
+            results["requests"].to_string(),
+            ms(&results["latency_ms"]["p50"]),
+            ms(&results["latency_ms"]["p99"]),
+            ms(&recorded["latency_ms"]["p50"]),
+            ms(&recorded["latency_ms"]["p99"]),
+            statuses(&results["statuses"]),
+            statuses(&recorded["statuses"]),
+            results["status_mismatches"].to_string(),
+        ]);
+    }
+    let mut widths = [0; 9];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let options = match parse(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+    let text = std::fs::read_to_string(&options.file)
+        .map_err(|e| format!("cannot read {}: {}", options.file, e))?;
+    let (samples, skipped) =
+        load(&text, options.include_writes).map_err(|e| format!("{}: {}", options.file, e))?;
+    let schedule = schedule(&samples, &options)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let options = std::sync::Arc::new(options);
+    let started = tokio::time::Instant::now();
+    let mut sent = Vec::with_capacity(samples.len());
+    for (sample, offset) in samples.iter().zip(schedule) {
+        tokio::time::sleep_until(started + offset).await;
+        let (client, options, body) = (client.clone(), options.clone(), sample.body.clone());
+        sent.push(tokio::spawn(
+            async move { send(&client, &options, body).await },
+        ));
+    }
+
+    let mut methods: BTreeMap<String, MethodResults> = BTreeMap::new();
+    for (sample, task) in samples.iter().zip(sent) {
+        let (status, latency) = task.await?;
+        let results = methods.entry(sample.method.clone()).or_default();
+        results.latencies_ms.push(latency.as_secs_f64() * 1000.0);
+        *results.statuses.entry(status).or_default() += 1;
+        if let Some(recorded) = sample.status {
+            *results.recorded_statuses.entry(recorded).or_default() += 1;
+            if recorded != status {
+                results.mismatches += 1;
+            }
+        }
+        results.recorded_ms.extend(sample.duration_ms);
+    }
+    let report = report(&options, methods, skipped, started.elapsed());
+
+    match options.json.as_deref() {
+        Some("-") => println!("{}", report),
+        Some(path) => {
+            print_report(&report);
+            std::fs::write(path, format!("{:#}\n", report))?;
+        }
+        None => print_report(&report),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: &str = r#"{"ts":"2024-05-01T12:00:00.000Z","status":200,"duration_ms":12.5,"body":{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["11111111111111111111111111111111"]}}
+{"ts":"2024-05-01T12:00:00.400Z","body":"{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"sendTransaction\",\"params\":[\"AQ==\"]}"}
+
+{"ts":1714564801000,"status":500,"body":{"jsonrpc":"2.0","id":3,"method":"getSlot"}}
+"#;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn writes_are_skipped_and_the_spacing_kept() {
+        let options = parse(&args("--file samples --target http://x --speed 2")).unwrap();
+        let (samples, skipped) = load(SAMPLES, false).unwrap();
+        assert_eq!(skipped, 1);
+        let methods: Vec<_> = samples.iter().map(|s| s.method.as_str()).collect();
+        assert_eq!(methods, ["getBalance", "getSlot"]);
+        assert_eq!(
+            schedule(&samples, &options).unwrap(),
+            [Duration::ZERO, Duration::from_millis(500)]
+        );
+        let (samples, skipped) = load(SAMPLES, true).unwrap();
+        assert_eq!((samples.len(), skipped), (3, 0));
+
+        let options = parse(&args("--file samples --target http://x --rate 4")).unwrap();
+        let (samples, _) = load(r#"{"body":{"method":"getSlot"}}"#, false).unwrap();
+        assert_eq!(schedule(&samples, &options).unwrap(), [Duration::ZERO]);
+        let options = parse(&args("--file samples --target http://x")).unwrap();
+        assert!(schedule(&samples, &options).is_err());
+        assert_eq!(
+            load("{\"ts\":1}", false).err().as_deref(),
+            Some("line 1: no request body")
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_are_compared_with_the_recording() {
+        let target = crate::testing::upstream(Default::default()).await;
+        let dir = std::env::temp_dir();
+        let id = crate::random::u64();
+        let file = dir.join(format!("quarantier-replay-{}.jsonl", id));
+        let json = dir.join(format!("quarantier-replay-{}.json", id));
+        std::fs::write(&file, SAMPLES).unwrap();
+        let args = args(&format!(
+            "--file {} --target {} --rate 50 --json {}",
+            file.display(),
+            target,
+            json.display()
+        ));
+        run(&args).await.unwrap();
+        let report: Value = serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_file(&json);
+
+        assert_eq!(report["requests"], 2, "{}", report);
+        assert_eq!(report["skipped_writes"], 1);
+        let balance = &report["methods"]["getBalance"];
+        assert_eq!(balance["statuses"], json!({ "200": 1 }));
+        assert_eq!(balance["recorded"]["latency_ms"]["p50"], 12.5);
+        assert_eq!(balance["status_mismatches"], 0);
+        let slot = &report["methods"]["getSlot"];
+        assert_eq!(slot["recorded"]["statuses"], json!({ "500": 1 }));
+        assert_eq!(slot["status_mismatches"], 1);
+        assert_eq!(report["methods"].get("sendTransaction"), None);
+    }
+}
@@ -0,0 +1,110 @@
+//! Request ids, taken from the client or generated, that follow a request
+//! through the logs and to the upstreams, and come back in the answer.
+
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Longest incoming request id that is honored rather than replaced.
+const MAX_LEN: usize = 128;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh 32 hex digit id, unique within the process and unpredictable
+/// across restarts.
+pub fn generate() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let random = hasher.finish();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    format!("{:016x}{:016x}", nanos, random)
+}
+
+/// The client's request id when it sent a sane one, otherwise a new one.
+pub fn from_headers(headers: &HeaderMap, header: &str) -> String {
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(generate)
+}
+
+/// Request extension holding the id of the request.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+/// Outermost layer: give every request an id, in its header and its
+/// extensions, and return it with every answer, including the rejections of
+/// the layers within.
+pub async fn middleware(
+    State(config): State<Arc<ServerConfig>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let header = &config.settings.request_id_header;
+    let id = from_headers(request.headers(), header.as_str());
+    let value = HeaderValue::from_str(&id).ok();
+    if let Some(value) = &value {
+        request.headers_mut().insert(header.clone(), value.clone());
+    }
+    request.extensions_mut().insert(RequestId(id));
+    let mut response = next.run(request).await;
+    if let Some(value) = value {
+        response.headers_mut().insert(header.clone(), value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn generated_ids_are_unique_hex() {
+        let (a, b) = (generate(), generate());
+        assert_eq!(a.len(), 32);
+        assert!(a.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn sane_incoming_ids_are_kept() {
+        assert_eq!(from_headers(&headers("abc-123"), "x-request-id"), "abc-123");
+        let longest = "a".repeat(MAX_LEN);
+        assert_eq!(from_headers(&headers(&longest), "x-request-id"), longest);
+    }
+
+    #[test]
+    fn missing_or_odd_ids_are_replaced() {
+        let generated = |headers: &HeaderMap| {
+            let id = from_headers(headers, "x-request-id");
+            id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+        };
+        assert!(generated(&HeaderMap::new()));
+        assert!(generated(&headers("")));
+        assert!(generated(&headers("has space")));
+        assert!(generated(&headers(&"a".repeat(MAX_LEN + 1))));
+        // an id in another header is not taken
+        assert_eq!(from_headers(&headers("abc"), "x-correlation-id").len(), 32);
+    }
+}
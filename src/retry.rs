@@ -0,0 +1,158 @@
+//! One more try for reads that got nothing but 5xx answers. A 5xx that
+//! would win the race is held back while the other upstreams asked may
+//! still do better; once none is left the request is sent again, to the
+//! best ranked upstream able to answer it that was not asked yet, or to
+//! the same one, within what is left of the request deadline. The client
+//! only sees the retry's answer, or the 5xx held back when the retry gets
+//! none. Methods in `[retry] exclude_methods`, the writes by default, are
+//! never retried. Every request that may be retried earns
+//! `budget_percent` hundredths of a retry and every retry spends one, so
+//! an outage of every upstream adds that share of traffic rather than
+//! doubling it.
+
+use crate::dispatch::Dispatch;
+use crate::ServerConfig;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Retries saved up at most, and those allowed right after startup.
+const RESERVE: f64 = 10.0;
+
+pub struct Retries {
+    /// In hundredths of a retry, so whole percents add up exactly.
+    balance: Mutex<f64>,
+    /// Retries sent, by the upstream that answered 5xx and its status.
+    pub sent: Mutex<BTreeMap<(String, u16), u64>>,
+    /// Retries not sent for want of budget.
+    pub throttled: AtomicU64,
+    /// Retries whose answer was returned.
+    pub recovered: AtomicU64,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            balance: Mutex::new(RESERVE * 100.0),
+            sent: Mutex::default(),
+            throttled: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Retries {
+    /// Credit a request that may be retried with its share of a retry.
+    pub fn deposit(&self, percent: f64) {
+        let mut balance = self.balance.lock().unwrap();
+        *balance = (*balance + percent).min(RESERVE * 100.0);
+    }
+
+    /// Spend a retry for a request upstream `failed` answered with
+    /// `status`, unless the budget is spent.
+    pub fn withdraw(&self, failed: &str, status: u16) -> bool {
+        let mut balance = self.balance.lock().unwrap();
+        if *balance < 100.0 {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        *balance -= 100.0;
+        let mut sent = self.sent.lock().unwrap();
+        *sent.entry((failed.to_string(), status)).or_default() += 1;
+        true
+    }
+}
+
+/// Where the retry of a request upstream `failed` answered with a 5xx
+/// goes: the best ranked upstream able to answer it that was not `asked`
+/// and has room under its plan's rate, or `failed` again.
+pub fn target(
+    config: &ServerConfig,
+    dispatch: &Dispatch,
+    quarantine: &[usize],
+    asked: &[String],
+    failed: usize,
+) -> usize {
+    let others: Vec<usize> = dispatch
+        .targets(config, quarantine)
+        .into_iter()
+        .filter(|&index| !asked.contains(&config.servers[index].name))
+        .filter(|&index| config.servers[index].has_room())
+        .collect();
+    let best = Dispatch::best(config, others, 1);
+    best.first().copied().unwrap_or(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{call, proxy, upstream};
+    use serde_json::json;
+
+    #[test]
+    fn every_retry_is_paid_for() {
+        let retries = Retries::default();
+        // the reserve covers the first few
+        for _ in 0..RESERVE as usize {
+            assert!(retries.withdraw("a", 502));
+        }
+        assert!(!retries.withdraw("a", 520));
+        assert_eq!(retries.throttled.load(Ordering::Relaxed), 1);
+        // ten requests at 10% earn one retry
+        for _ in 0..9 {
+            retries.deposit(10.0);
+        }
+        assert!(!retries.withdraw("b", 502));
+        retries.deposit(10.0);
+        assert!(retries.withdraw("b", 502));
+        let sent = retries.sent.lock().unwrap().clone();
+        let sent: Vec<_> = sent.into_iter().collect();
+        assert_eq!(
+            sent,
+            [(("a".to_string(), 502), 10), (("b".to_string(), 502), 1)]
+        );
+    }
+
+    #[test]
+    fn the_reserve_is_capped() {
+        let retries = Retries::default();
+        for _ in 0..1000 {
+            retries.deposit(100.0);
+        }
+        for _ in 0..RESERVE as usize {
+            assert!(retries.withdraw("a", 503));
+        }
+        assert!(!retries.withdraw("a", 503));
+    }
+
+    #[tokio::test]
+    async fn a_5xx_is_retried_on_an_upstream_not_asked_yet() {
+        let failing = upstream(Behavior {
+            fail_rate: 1.0,
+            ..Behavior::default()
+        })
+        .await;
+        let healthy = upstream(Behavior::default()).await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nurl = \"{}\"\nname = \"failing\"\n\n[[upstreams]]\nurl = \"{}\"\nname = \"healthy\"\n\n[routing]\nfanout = 1\n",
+            failing, healthy
+        ))
+        .await;
+        let balance = json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        let response = call(&url, balance).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["result"]["value"], 1_000_000_000);
+        let sent = config.retries.sent.lock().unwrap().clone();
+        assert_eq!(sent.get(&("failing".to_string(), 503)), Some(&1));
+        assert_eq!(config.retries.recovered.load(Ordering::Relaxed), 1);
+        // writes get the 5xx as it is
+        let (config, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", failing)).await;
+        let send =
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "sendTransaction", "params": ["x"] });
+        assert_eq!(call(&url, send).await.status(), 503);
+        assert!(config.retries.sent.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Helpers for looking inside JSON-RPC request bodies.
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Response, StatusCode},
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Method name recorded for bodies that are not valid JSON-RPC.
+pub const UNKNOWN_METHOD: &str = "unknown";
+
+/// JSON-RPC error code of malformed requests.
+pub const INVALID_REQUEST: i64 = -32600;
+/// JSON-RPC error code of failures reaching an upstream.
+pub const UPSTREAM_ERROR: i64 = -32002;
+/// JSON-RPC error code of requests rejected for lack of credentials.
+pub const UNAUTHORIZED: i64 = -32001;
+/// JSON-RPC error code of requests from disallowed addresses, or with a key
+/// lacking the rights they need.
+pub const FORBIDDEN: i64 = -32003;
+/// JSON-RPC error code of requests not answered within their deadline.
+pub const DEADLINE_EXCEEDED: i64 = -32004;
+/// JSON-RPC error code of throttled requests.
+pub const RATE_LIMITED: i64 = -32029;
+/// JSON-RPC error code of requests beyond their key's quota.
+pub const QUOTA_EXCEEDED: i64 = -32030;
+/// JSON-RPC error code of answers over the response size limit.
+pub const RESPONSE_TOO_LARGE: i64 = -32031;
+/// JSON-RPC error code of requests shed under load.
+pub const OVERLOADED: i64 = -32032;
+/// JSON-RPC error code of requests refused while an admin paused traffic.
+pub const PAUSED: i64 = -32033;
+
+/// Response extension naming the JSON-RPC method that was served ("batch"
+/// for batches), for layers that only see the response.
+#[derive(Clone)]
+pub struct RequestMethod(pub String);
+
+/// Response extension naming the upstream whose answer was returned.
+#[derive(Clone)]
+pub struct ServedBy(pub String);
+
+/// Methods called by a request body, one entry per batch element.
+pub fn request_methods(body: &[u8]) -> Vec<String> {
+    let method = |request: &Value| {
+        request
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or(UNKNOWN_METHOD)
+            .to_string()
+    };
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(batch)) if !batch.is_empty() => batch.iter().map(method).collect(),
+        Ok(request @ Value::Object(_)) => vec![method(&request)],
+        _ => vec![UNKNOWN_METHOD.to_string()],
+    }
+}
+
+/// How a request is labeled in logs and metrics: its method, or "batch".
+pub fn method_label(methods: &[String]) -> String {
+    match methods {
+        [method] => method.clone(),
+        _ => "batch".to_string(),
+    }
+}
+
+/// A JSON-RPC error answered by the proxy itself, without an upstream.
+pub fn error_response(
+    status: StatusCode,
+    code: i64,
+    message: &str,
+    data: Option<Value>,
+) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(error_body(code, message, data)))
+        .unwrap()
+}
+
+pub fn error_body(code: i64, message: &str, data: Option<Value>) -> String {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({ "jsonrpc": "2.0", "id": null, "error": error }).to_string()
+}
+
+/// 429 telling the client when a token will be available again.
+pub fn rate_limited(wait: Duration) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        RATE_LIMITED,
+        "rate limit exceeded",
+        None,
+    );
+    let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+    response
+}
+
+/// 429 for a used up quota, which renews at `resets_at` (unix seconds).
+pub fn quota_exceeded(resets_at: u64) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        QUOTA_EXCEEDED,
+        "request quota exceeded",
+        Some(json!({ "resets_at": resets_at })),
+    );
+    let wait = resets_at.saturating_sub(crate::clock::unix_secs()).max(1);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(wait));
+    response
+}
@@ -0,0 +1,151 @@
+//! Tokio runtime settings taken from the command line, ahead of any other
+//! argument: `--runtime current|multi`, `--worker-threads N` and
+//! `--max-blocking-threads N`.
+
+use tokio::runtime::{Builder, Runtime};
+
+pub const USAGE: &str = "Runtime options, before the other arguments:
+  --runtime current|multi     scheduler flavor (default multi)
+  --worker-threads <N>        workers of the multi-thread runtime (default: one per core)
+  --max-blocking-threads <N>  threads for blocking work (default 512)";
+
+#[derive(Default)]
+pub struct RuntimeOptions {
+    current_thread: bool,
+    worker_threads: Option<usize>,
+    max_blocking_threads: Option<usize>,
+}
+
+impl RuntimeOptions {
+    /// Remove the runtime options from the front of `args`.
+    pub fn take_from(args: &mut Vec<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        while let Some(flag) = args.first().filter(|flag| flag.starts_with("--")) {
+            let number = |value: &str| {
+                value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("{} expects a positive number, got '{}'", flag, value))
+            };
+            let value = args.get(1).map(String::as_str).unwrap_or_default();
+            match flag.as_str() {
+                "--runtime" => {
+                    options.current_thread = match value {
+                        "current" => true,
+                        "multi" => false,
+                        _ => {
+                            return Err(format!(
+                                "--runtime expects current or multi, got '{}'",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "--worker-threads" => options.worker_threads = Some(number(value)?),
+                "--max-blocking-threads" => options.max_blocking_threads = Some(number(value)?),
+                _ => break,
+            }
+            args.drain(..2);
+        }
+        if options.current_thread && options.worker_threads.is_some() {
+            return Err("--worker-threads only applies to --runtime multi".into());
+        }
+        Ok(options)
+    }
+
+    pub fn build(&self) -> std::io::Result<Runtime> {
+        let mut builder = if self.current_thread {
+            Builder::new_current_thread()
+        } else {
+            Builder::new_multi_thread()
+        };
+        if let Some(workers) = self.worker_threads {
+            builder.worker_threads(workers);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        builder.enable_all().build()
+    }
+
+    /// One line describing the effective settings, for the startup log.
+    pub fn describe(&self) -> String {
+        let blocking = self.max_blocking_threads.unwrap_or(512);
+        if self.current_thread {
+            format!("current-thread, max {} blocking threads", blocking)
+        } else {
+            let workers = self
+                .worker_threads
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            format!(
+                "multi-thread with {} workers, max {} blocking threads",
+                workers, blocking
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn runtime_options_are_taken_from_the_front() {
+        let mut line =
+            args("--runtime multi --worker-threads 4 --max-blocking-threads 64 --config q.toml");
+        let options = RuntimeOptions::take_from(&mut line).unwrap();
+        assert_eq!(line, args("--config q.toml"));
+        assert!(!options.current_thread);
+        assert_eq!(options.worker_threads, Some(4));
+        assert_eq!(options.max_blocking_threads, Some(64));
+        assert_eq!(
+            options.describe(),
+            "multi-thread with 4 workers, max 64 blocking threads"
+        );
+        // later ones belong to the command
+        let mut line = args("check --runtime current");
+        let options = RuntimeOptions::take_from(&mut line).unwrap();
+        assert_eq!(line, args("check --runtime current"));
+        assert!(!options.current_thread);
+    }
+
+    #[test]
+    fn invalid_runtime_options_are_rejected() {
+        for (line, error) in [
+            (
+                "--runtime single",
+                "--runtime expects current or multi, got 'single'",
+            ),
+            (
+                "--worker-threads 0",
+                "--worker-threads expects a positive number, got '0'",
+            ),
+            (
+                "--max-blocking-threads",
+                "--max-blocking-threads expects a positive number, got ''",
+            ),
+            (
+                "--runtime current --worker-threads 2",
+                "--worker-threads only applies to --runtime multi",
+            ),
+        ] {
+            let err = RuntimeOptions::take_from(&mut args(line)).err();
+            assert_eq!(err.as_deref(), Some(error), "{}", line);
+        }
+    }
+
+    #[test]
+    fn current_thread_runtimes_run() {
+        let mut line = args("--runtime current --max-blocking-threads 2");
+        let options = RuntimeOptions::take_from(&mut line).unwrap();
+        assert_eq!(options.describe(), "current-thread, max 2 blocking threads");
+        let runtime = options.build().unwrap();
+        let answer = runtime.block_on(async { tokio::task::spawn_blocking(|| 42).await.unwrap() });
+        assert_eq!(answer, 42);
+    }
+}
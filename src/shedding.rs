@@ -0,0 +1,250 @@
+//! Load shedding by priority class. Every proxied request is classed by
+//! the methods it calls, `[priority] high_methods` and `low_methods`, or by
+//! its API key's `priority`. With `[load_shedding]` enabled at most
+//! `max_in_flight` requests are forwarded at once: a normal request beyond
+//! that waits up to `queue_ms` for a slot and is then shed, a low one is
+//! shed at once past `low_share_percent` of the cap, and a high one, a
+//! transaction submission by default, always goes through, though it
+//! takes a slot like any other. Low requests are not hedged or retried.
+
+use crate::config::{LoadSheddingConfig, Priority};
+use axum::{body::Body, http::StatusCode, response::Response};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Response extension with the class of a proxied request, for the access
+/// log.
+#[derive(Clone, Copy)]
+pub struct RequestPriority(pub Priority);
+
+#[derive(Default)]
+pub struct Shedder {
+    in_flight: Mutex<usize>,
+    /// Woken for each slot released, to hand it to a queued request.
+    freed: Notify,
+    /// Requests classed, by class in `Priority::ALL` order.
+    requests: [AtomicU64; 3],
+    /// Requests shed, by class.
+    shed: [AtomicU64; 3],
+}
+
+/// A slot taken; dropping it, on any exit path, releases it.
+pub struct Slot<'a> {
+    shedder: Option<&'a Shedder>,
+}
+
+fn position(class: Priority) -> usize {
+    Priority::ALL.iter().position(|&c| c == class).unwrap()
+}
+
+impl Shedder {
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    pub fn requests(&self, class: Priority) -> u64 {
+        self.requests[position(class)].load(Ordering::Relaxed)
+    }
+
+    pub fn shed(&self, class: Priority) -> u64 {
+        self.shed[position(class)].load(Ordering::Relaxed)
+    }
+
+    fn try_acquire(&self, cap: usize) -> Option<Slot<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if *in_flight >= cap {
+            return None;
+        }
+        *in_flight += 1;
+        Some(Slot {
+            shedder: Some(self),
+        })
+    }
+
+    /// A slot for a request of `class`, waiting for one if it is normal,
+    /// or `None` when it is shed. Without `settings` every request goes
+    /// through and is only counted.
+    pub async fn admit(
+        &self,
+        settings: Option<&LoadSheddingConfig>,
+        class: Priority,
+    ) -> Option<Slot<'_>> {
+        self.requests[position(class)].fetch_add(1, Ordering::Relaxed);
+        let Some(settings) = settings else {
+            return Some(Slot { shedder: None });
+        };
+        let slot = match class {
+            Priority::High => self.try_acquire(usize::MAX),
+            Priority::Low => {
+                let share = settings.max_in_flight as f64 * settings.low_share_percent / 100.0;
+                self.try_acquire(share as usize)
+            }
+            Priority::Normal => {
+                let deadline = Instant::now() + settings.queue;
+                loop {
+                    // registered before trying, so a slot released in
+                    // between is not missed
+                    let freed = self.freed.notified();
+                    tokio::pin!(freed);
+                    freed.as_mut().enable();
+                    if let Some(slot) = self.try_acquire(settings.max_in_flight) {
+                        break Some(slot);
+                    }
+                    if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                        break None;
+                    }
+                }
+            }
+        };
+        if slot.is_none() {
+            self.shed[position(class)].fetch_add(1, Ordering::Relaxed);
+        }
+        slot
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(shedder) = self.shedder {
+            *shedder.in_flight.lock().unwrap() -= 1;
+            shedder.freed.notify_one();
+        }
+    }
+}
+
+/// The answer to a shed request.
+pub fn shed_response(class: Priority) -> Response<Body> {
+    let mut response = crate::rpc::error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        crate::rpc::OVERLOADED,
+        &format!("proxy overloaded, {} priority request shed", class.as_str()),
+        None,
+    );
+    response
+        .headers_mut()
+        .insert(axum::http::header::RETRY_AFTER, 1.into());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PriorityTable;
+    use std::time::Duration;
+
+    fn settings(max_in_flight: usize, queue_ms: u64) -> LoadSheddingConfig {
+        LoadSheddingConfig {
+            max_in_flight,
+            queue: Duration::from_millis(queue_ms),
+            low_share_percent: 50.0,
+        }
+    }
+
+    #[test]
+    fn requests_are_classed_by_their_highest_call() {
+        let table = PriorityTable {
+            high: vec!["sendTransaction".into()],
+            low: vec!["getRecentPerformanceSamples".into()],
+        };
+        let class = |methods: &[&str], key| {
+            let methods: Vec<String> = methods.iter().map(|m| m.to_string()).collect();
+            table.class(&methods, key)
+        };
+        assert_eq!(class(&["sendTransaction"], None), Priority::High);
+        assert_eq!(class(&["getBalance"], None), Priority::Normal);
+        assert_eq!(class(&["getRecentPerformanceSamples"], None), Priority::Low);
+        assert_eq!(
+            class(&["getRecentPerformanceSamples", "sendTransaction"], None),
+            Priority::High
+        );
+        assert_eq!(class(&[], None), Priority::Normal);
+        // an API key's class wins over its methods'
+        assert_eq!(
+            class(&["sendTransaction"], Some(Priority::Low)),
+            Priority::Low
+        );
+    }
+
+    #[tokio::test]
+    async fn high_priority_requests_are_never_shed() {
+        let shedder = Shedder::default();
+        let settings = settings(2, 10);
+        let mut slots = Vec::new();
+        for _ in 0..2 {
+            slots.push(
+                shedder
+                    .admit(Some(&settings), Priority::Normal)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(shedder
+            .admit(Some(&settings), Priority::Normal)
+            .await
+            .is_none());
+        assert!(shedder
+            .admit(Some(&settings), Priority::Low)
+            .await
+            .is_none());
+        for _ in 0..5 {
+            slots.push(
+                shedder
+                    .admit(Some(&settings), Priority::High)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(shedder.in_flight(), 7);
+        assert_eq!(shedder.shed(Priority::High), 0);
+        assert_eq!(shedder.shed(Priority::Normal), 1);
+        assert_eq!(shedder.shed(Priority::Low), 1);
+        assert_eq!(shedder.requests(Priority::High), 5);
+        drop(slots);
+        assert_eq!(shedder.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn low_priority_requests_get_their_share_only() {
+        let shedder = Shedder::default();
+        let settings = settings(4, 10);
+        let first = shedder.admit(Some(&settings), Priority::Low).await;
+        let second = shedder.admit(Some(&settings), Priority::Low).await;
+        assert!(first.is_some() && second.is_some());
+        // half of the cap is taken, the rest is for normal requests
+        assert!(shedder
+            .admit(Some(&settings), Priority::Low)
+            .await
+            .is_none());
+        assert!(shedder
+            .admit(Some(&settings), Priority::Normal)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn normal_requests_wait_for_a_slot_in_the_queue() {
+        let shedder = Shedder::default();
+        let settings = settings(1, 1000);
+        let slot = shedder.admit(Some(&settings), Priority::Normal).await;
+        let started = Instant::now();
+        let (queued, ()) = tokio::join!(shedder.admit(Some(&settings), Priority::Normal), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(slot);
+        });
+        assert!(queued.is_some());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(shedder.shed(Priority::Normal), 0);
+    }
+
+    #[tokio::test]
+    async fn without_load_shedding_requests_are_only_counted() {
+        let shedder = Shedder::default();
+        let slots: Vec<_> =
+            futures::future::join_all((0..10).map(|_| shedder.admit(None, Priority::Low))).await;
+        assert!(slots.iter().all(Option::is_some));
+        assert_eq!(shedder.requests(Priority::Low), 10);
+        assert_eq!(shedder.in_flight(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Slot tracking over WebSocket `slotSubscribe`, for upstreams with a
+//! `ws_url`. Notifications arrive as the slot advances, where polling
+//! `getSlot` costs a request per upstream and interval and is always a
+//! poll behind. While a feed is down, or silent for `[slots] ws_stale_ms`,
+//! the poller takes the upstream back until a reconnection succeeds, and
+//! the length of the gap is logged once the feed is live again. The
+//! upstream is polled at once when its feed drops, so the gap does not
+//! leave its estimate behind until the next round.
+
+use crate::slots::{Feed, SlotSource};
+use crate::ws;
+use crate::ServerConfig;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first reconnection, doubled after every failed one.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Follow upstream `index` by `feed` from now on, logging the change.
+/// `dropped` is when a live feed last went stale, to log how long the
+/// gap lasted once it is back.
+fn switch(
+    config: &ServerConfig,
+    index: usize,
+    feed: Feed,
+    reason: &str,
+    dropped: &mut Option<Instant>,
+) {
+    if config.slots.set_feed(index, feed) == feed {
+        return;
+    }
+    let name = &config.servers[index].name;
+    let message = match (feed, dropped.take()) {
+        (Feed::Ws, Some(dropped)) => format!(
+            "{} slot feed is live over WebSocket again after a {:?} gap",
+            name,
+            dropped.elapsed()
+        ),
+        (Feed::Ws, None) => format!("{} slot feed is live over WebSocket", name),
+        _ => {
+            *dropped = Some(Instant::now());
+            format!("{} slot feed is stale ({}), polling getSlot", name, reason)
+        }
+    };
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Subscribe on one connection and record the slots notified until it
+/// fails or goes silent, returning why it ended.
+async fn subscribe(
+    config: &ServerConfig,
+    index: usize,
+    url: &str,
+    dropped: &mut Option<Instant>,
+) -> String {
+    let stale_after = config.settings.slots.ws_stale_after;
+    let mut socket = match tokio::time::timeout(CONNECT_TIMEOUT, ws::connect(url)).await {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(err)) => return format!("cannot connect: {}", err),
+        Err(_) => return "connection timed out".to_string(),
+    };
+    let request = r#"{"jsonrpc":"2.0","id":1,"method":"slotSubscribe"}"#;
+    if let Err(err) = socket.send(request).await {
+        return err.to_string();
+    }
+    loop {
+        let received = tokio::select! {
+            received = tokio::time::timeout(stale_after, socket.receive()) => received,
+            _ = config.pause.paused() => return "traffic paused".to_string(),
+        };
+        let message = match received {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) => return "closed by the upstream".to_string(),
+            Ok(Err(err)) => return err.to_string(),
+            Err(_) => return format!("no slot in {}ms", stale_after.as_millis()),
+        };
+        let Ok(message) = serde_json::from_str::<Value>(&message) else {
+            continue;
+        };
+        // nodes without PubSub, and plans without it, refuse the subscription
+        if let Some(error) = message.get("error") {
+            return format!("slotSubscribe failed: {}", error["message"]);
+        }
+        if message["method"] != "slotNotification" {
+            continue;
+        }
+        let slot = config
+            .chaos
+            .misreport(index, &message["params"]["result"]["slot"]);
+        if config
+            .slots
+            .observe_value(index, &slot, SlotSource::Ws)
+            .is_some()
+        {
+            switch(config, index, Feed::Ws, "", dropped);
+        }
+    }
+}
+
+/// Keep a slot subscription open to upstream `index` at `url` forever,
+/// reconnecting with exponential backoff up to `[slots] ws_max_backoff_ms`.
+pub async fn follow(config: Arc<ServerConfig>, index: usize, url: String) {
+    let mut backoff = FIRST_BACKOFF;
+    let mut first = true;
+    let mut dropped = None;
+    loop {
+        // no connection is opened while traffic is paused
+        config.pause.resumed().await;
+        let reason = subscribe(&config, index, &url, &mut dropped).await;
+        let was_live = config.slots.feed(index) == Feed::Ws;
+        if was_live {
+            backoff = FIRST_BACKOFF;
+        } else if first {
+            println!(
+                "+ {} slot feed is not available over WebSocket yet ({}), polling getSlot",
+                config.servers[index].name, reason
+            );
+        }
+        first = false;
+        switch(&config, index, Feed::Stale, &reason, &mut dropped);
+        if was_live && !config.pause.is_paused() {
+            crate::slots::repoll(&config, index).await;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(config.settings.slots.ws_max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    /// Wait up to two seconds for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    /// A PubSub endpoint notifying `slots[n]` on its `n`th connection and
+    /// then going silent, and its URL.
+    async fn pubsub(slots: Vec<u64>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for slot in slots {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut socket = ws::accept(stream).await.unwrap();
+                    socket.receive().await.unwrap();
+                    let subscribed = json!({ "jsonrpc": "2.0", "result": 0, "id": 1 });
+                    socket.send(&subscribed.to_string()).await.unwrap();
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "slotNotification",
+                        "params": { "result": { "parent": slot - 1, "root": slot - 32, "slot": slot }, "subscription": 0 },
+                    });
+                    socket.send(&notification.to_string()).await.unwrap();
+                    // silent from now on, without closing
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    drop(socket);
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn a_silent_feed_falls_back_to_polling_until_it_reconnects() {
+        let upstream = crate::testing::upstream(Default::default()).await;
+        let polled = u64::MAX / 2;
+        let ws_url = pubsub(vec![100, polled + 100]).await;
+        let config = crate::testing::config(&format!(
+            "[slots]\nws_stale_ms = 300\n\n[[upstreams]]\nurl = \"{}\"\nws_url = \"{}\"\n",
+            upstream, ws_url
+        ));
+        let started = Instant::now();
+        tokio::spawn(follow(config.clone(), 0, ws_url));
+        let slots = &config.slots;
+        assert!(eventually(|| slots.feed(0) == Feed::Ws).await);
+        let notified = slots.get(0).unwrap();
+        assert_eq!((notified.slot, notified.source.as_str()), (100, "ws"));
+
+        // silent past ws_stale_ms: polled at once, and until it is back
+        assert!(eventually(|| slots.feed(0) == Feed::Stale).await);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(eventually(|| slots.get(0).is_some_and(|o| o.source.as_str() == "poll")).await);
+        // reconnected after the first backoff
+        assert!(eventually(|| slots.feed(0) == Feed::Ws).await);
+        assert!(started.elapsed() >= FIRST_BACKOFF);
+        assert_eq!(slots.get(0).unwrap().slot, polled + 100);
+        let events = config.events.recent();
+        let gap = events
+            .iter()
+            .any(|(_, e)| e.contains("live over WebSocket again after a"));
+        assert!(gap, "{:?}", events);
+    }
+
+    #[tokio::test]
+    async fn an_upstream_refusing_the_subscription_is_polled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut socket = ws::accept(stream).await.unwrap();
+                socket.receive().await.unwrap();
+                let refused = json!({
+                    "jsonrpc": "2.0",
+                    "error": { "code": -32601, "message": "Method not found" },
+                    "id": 1,
+                });
+                let _ = socket.send(&refused.to_string()).await;
+            }
+        });
+        let upstream = crate::testing::upstream(Default::default()).await;
+        let config = crate::testing::config(&format!(
+            "[[upstreams]]\nurl = \"{}\"\nws_url = \"{}\"\n",
+            upstream, ws_url
+        ));
+        tokio::spawn(follow(config.clone(), 0, ws_url));
+        tokio::spawn(crate::slots::poll_slots(
+            config.clone(),
+            Duration::from_millis(50),
+        ));
+        let slots = &config.slots;
+        assert!(eventually(|| slots.get(0).is_some_and(|o| o.source.as_str() == "poll")).await);
+        assert_eq!(slots.feed(0), Feed::Stale);
+        let axum::Json(report) =
+            crate::slots::slots_handler(axum::extract::State(config.clone())).await;
+        assert_eq!(report["upstreams"][0]["feed"], "stale", "{}", report);
+    }
+}
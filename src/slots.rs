@@ -0,0 +1,468 @@
+//! Per-upstream slot estimates, fed by the background poller, by WebSocket
+//! slot feeds and by the context slots of proxied responses.
+
+use crate::classify::{classify_response, classify_transport, ErrorClass};
+use crate::effective::redact_url;
+use crate::ServerConfig;
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotSource {
+    Poll,
+    Response,
+    Ws,
+}
+
+impl SlotSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SlotSource::Poll => "poll",
+            SlotSource::Response => "response",
+            SlotSource::Ws => "ws",
+        }
+    }
+}
+
+/// How the slot of an upstream is followed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feed {
+    /// Polled over HTTP, the upstream has no WebSocket endpoint.
+    Http,
+    /// Notified over a live WebSocket, the poller skips the upstream.
+    Ws,
+    /// Polled over HTTP while its WebSocket is down or silent.
+    Stale,
+}
+
+impl Feed {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Feed::Http => "http",
+            Feed::Ws => "ws",
+            Feed::Stale => "stale",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SlotObservation {
+    pub slot: u64,
+    pub at: Instant,
+    pub unix_ms: u64,
+    pub source: SlotSource,
+}
+
+pub struct SlotTracker {
+    max_age: Duration,
+    hosts: Vec<Mutex<Option<SlotObservation>>>,
+    /// Slots per host that were not a non-negative integer.
+    unparsable: Vec<AtomicU64>,
+    feeds: Vec<Mutex<Feed>>,
+    /// Hosts whose slots are ignored, serving another cluster.
+    excluded: Vec<AtomicBool>,
+}
+
+impl SlotTracker {
+    /// One host per entry of `feeds`, how each starts being followed.
+    pub fn new(feeds: Vec<Feed>, max_age: Duration) -> Self {
+        Self {
+            max_age,
+            hosts: feeds.iter().map(|_| Mutex::new(None)).collect(),
+            unparsable: feeds.iter().map(|_| AtomicU64::new(0)).collect(),
+            excluded: feeds.iter().map(|_| AtomicBool::new(false)).collect(),
+            feeds: feeds.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    pub fn feed(&self, index: usize) -> Feed {
+        *self.feeds[index].lock().unwrap()
+    }
+
+    /// Switch how upstream `index` is followed, returning the previous way.
+    pub fn set_feed(&self, index: usize, feed: Feed) -> Feed {
+        std::mem::replace(&mut *self.feeds[index].lock().unwrap(), feed)
+    }
+
+    /// Record `slot` as reported by upstream `index`. Anything but a
+    /// non-negative integer is counted and skipped rather than trusted.
+    pub fn observe_value(&self, index: usize, slot: &Value, source: SlotSource) -> Option<u64> {
+        match slot.as_u64() {
+            Some(slot) => {
+                self.observe(index, slot, source);
+                Some(slot)
+            }
+            None => {
+                self.unparsable[index].fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn unparsable(&self, index: usize) -> u64 {
+        self.unparsable[index].load(Ordering::Relaxed)
+    }
+
+    /// Forget the slot of upstream `index` and ignore those it reports
+    /// from now on.
+    pub fn exclude(&self, index: usize) {
+        self.excluded[index].store(true, Ordering::Relaxed);
+        *self.hosts[index].lock().unwrap() = None;
+    }
+
+    pub fn observe(&self, index: usize, slot: u64, source: SlotSource) {
+        if self.excluded[index].load(Ordering::Relaxed) {
+            return;
+        }
+        let now = Instant::now();
+        let mut current = self.hosts[index].lock().unwrap();
+        // a fresh estimate never moves backwards, observations sampled at
+        // slightly different times would otherwise make it jitter
+        let slot = match *current {
+            Some(previous) if now.duration_since(previous.at) < self.max_age => {
+                slot.max(previous.slot)
+            }
+            _ => slot,
+        };
+        *current = Some(SlotObservation {
+            slot,
+            at: now,
+            unix_ms: crate::clock::unix_ms(),
+            source,
+        });
+    }
+
+    pub fn get(&self, index: usize) -> Option<SlotObservation> {
+        *self.hosts[index].lock().unwrap()
+    }
+
+    pub fn is_fresh(&self, observation: &SlotObservation) -> bool {
+        observation.at.elapsed() < self.max_age
+    }
+
+    /// Fresh estimates only, indexed like the upstreams.
+    pub fn fresh(&self) -> Vec<Option<SlotObservation>> {
+        (0..self.hosts.len())
+            .map(|index| self.get(index).filter(|o| self.is_fresh(o)))
+            .collect()
+    }
+
+    /// Largest lag among upstreams with a fresh estimate.
+    pub fn max_lag(&self) -> Option<u64> {
+        let fresh = self.fresh();
+        let slots = fresh.iter().flatten().map(|o| o.slot);
+        Some(slots.clone().max()? - slots.min()?)
+    }
+}
+
+/// The tip: the highest slot of `fresh`, indexed like the upstreams, among
+/// the regular ones, a node configured twice counting once. Last-resort
+/// upstreams are not judged by their lag and do not move it either.
+pub fn tip_of(config: &ServerConfig, fresh: &[Option<SlotObservation>]) -> Option<u64> {
+    fresh
+        .iter()
+        .zip(&config.servers)
+        .filter(|(_, upstream)| !upstream.is_last_resort() && !upstream.identity.is_alias())
+        .filter_map(|(observation, _)| observation.as_ref())
+        .map(|observation| observation.slot)
+        .max()
+}
+
+/// The tip of the current fresh estimates.
+pub fn tip(config: &ServerConfig) -> Option<u64> {
+    tip_of(config, &config.slots.fresh())
+}
+
+/// The tip of the estimates observed within `within`.
+pub fn recent_tip(config: &ServerConfig, within: Duration) -> Option<u64> {
+    let recent: Vec<Option<SlotObservation>> = (0..config.servers.len())
+        .map(|index| config.slots.get(index))
+        .map(|observation| observation.filter(|o| o.at.elapsed() < within))
+        .collect();
+    tip_of(config, &recent)
+}
+
+/// Record the `result.context.slot` of a proxied answer from upstream
+/// `index`, logging why there is none.
+pub fn observe_answer(
+    config: &ServerConfig,
+    index: usize,
+    answer: Option<&Value>,
+    request_id: &str,
+) {
+    let Some(answer) = answer else {
+        println!("[{}] Failed to parse response as JSON", request_id);
+        return;
+    };
+    let Some(result) = answer.get("result") else {
+        println!("[{}] Result field not found", request_id);
+        return;
+    };
+    if !result.is_object() {
+        println!("[{}] Result is not an object", request_id);
+        return;
+    }
+    let Some(context) = result.get("context") else {
+        println!("[{}] Context not found in result", request_id);
+        return;
+    };
+    if !context.is_object() {
+        println!("[{}] Context is not an object", request_id);
+        return;
+    }
+    let Some(slot) = context.get("slot") else {
+        return;
+    };
+    let host = &config.servers[index].name;
+    let slot = &config.chaos.misreport(index, slot);
+    match config
+        .slots
+        .observe_value(index, slot, SlotSource::Response)
+    {
+        Some(slot) => println!("[{}] + Slot on {} is {}", request_id, host, slot),
+        None => println!(
+            "[{}] + Unparsable slot {} from {}, skipping",
+            request_id, slot, host
+        ),
+    }
+}
+
+/// Ask upstream `index` for its slot, recording the outcome. Returns
+/// whether the host answered, with a slot or a JSON-RPC error about the
+/// request.
+async fn poll(config: &ServerConfig, index: usize) -> bool {
+    let upstream = &config.servers[index];
+    let chaos = config.chaos.effects(index);
+    tokio::time::sleep(chaos.delay).await;
+    if chaos.fail {
+        upstream.stats.record_error(ErrorClass::Http5xx);
+        return false;
+    }
+    let response = upstream
+        .post()
+        .header("Content-Type", "application/json")
+        .body(r#"{"jsonrpc":"2.0","id":1,"method":"getSlot"}"#)
+        .send()
+        .await;
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            upstream.stats.record_error(classify_transport(&err));
+            return false;
+        }
+    };
+    upstream
+        .limits
+        .observe(&upstream.name, response.headers(), &config.events);
+    let status = response.status().as_u16();
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(err) => {
+            upstream.stats.record_error(classify_transport(&err));
+            return false;
+        }
+    };
+    let json = serde_json::from_slice::<Value>(&body).ok();
+    match classify_response(status, &body, json.as_ref()) {
+        Some(class) => {
+            upstream.stats.record_error(class);
+            !class.is_host_failure()
+        }
+        None => {
+            upstream.stats.record_success();
+            if let Some(json) = &json {
+                let slot = config.chaos.misreport(index, &json["result"]);
+                config.slots.observe_value(index, &slot, SlotSource::Poll);
+            }
+            true
+        }
+    }
+}
+
+/// Poll upstream `index` at once, when its WebSocket feed dropped, so its
+/// estimate does not wait for the next round, and re-evaluate the
+/// quarantine with it.
+pub async fn repoll(config: &ServerConfig, index: usize) {
+    let upstream = &config.servers[index];
+    // the same upstreams as in a round, a pulled one being left to its probes
+    if upstream.is_last_resort() || upstream.cluster.is_wrong() || !upstream.circuit.is_closed() {
+        return;
+    }
+    if !upstream.take_token() {
+        return;
+    }
+    crate::costs::charge(config, index, ["getSlot"]);
+    poll(config, index).await;
+    crate::quarantine::reevaluate(config, "slot feed").await;
+}
+
+/// Poll `getSlot` on every upstream without a live WebSocket feed forever,
+/// so estimates stay current without client traffic, re-evaluating the
+/// quarantine after each round. Upstreams pulled for failures are polled
+/// as probes, one at a time.
+pub async fn poll_slots(config: Arc<ServerConfig>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        // nothing is polled while traffic is paused, nor is the quarantine
+        // re-evaluated on estimates that stopped moving
+        if config.pause.is_paused() {
+            config.readiness.update(&config).await;
+            continue;
+        }
+        let polls = config.servers.iter().enumerate().map(|(index, upstream)| {
+            let config = config.clone();
+            async move {
+                // last resorts are spared the polling, their slots come from
+                // the answers they give, and another cluster's slots are
+                // ignored
+                if upstream.is_last_resort() || upstream.cluster.is_wrong() {
+                    return;
+                }
+                // a host pulled for failures is only polled as the probe of
+                // its circuit breaker
+                let probe = match upstream.circuit.is_closed() {
+                    true => None,
+                    false => match crate::quarantine::admit_probe(&config, index) {
+                        Some(probe) => Some(probe),
+                        None => return,
+                    },
+                };
+                if probe.is_none() && config.slots.feed(index) == Feed::Ws {
+                    return;
+                }
+                // a round skipped at the plan's rate leaves the estimate to
+                // the next one
+                if !upstream.take_token() {
+                    return;
+                }
+                crate::costs::charge(&config, index, ["getSlot"]);
+                let answered = poll(&config, index).await;
+                if let Some(probe) = probe {
+                    crate::quarantine::probed(&config, index, probe, answered);
+                }
+            }
+        });
+        futures::future::join_all(polls).await;
+        crate::quarantine::reevaluate(&config, "poller").await;
+        config.readiness.update(&config).await;
+    }
+}
+
+/// `GET /slots`: current slot estimate and lag of every upstream, with its
+/// URL redacted like in `/admin/config`.
+pub async fn slots_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    let max_slot = tip(&config);
+    let upstreams: Vec<Value> = config
+        .servers
+        .iter()
+        .enumerate()
+        .map(|(index, upstream)| match config.slots.get(index) {
+            Some(observation) => json!({
+                "name": upstream.name,
+                "url": redact_url(&upstream.url),
+                "slot": observation.slot,
+                "lag": max_slot.map(|max| max.saturating_sub(observation.slot)),
+                "observed_at_ms": observation.unix_ms,
+                "age_ms": observation.at.elapsed().as_millis() as u64,
+                "source": observation.source.as_str(),
+                "feed": config.slots.feed(index).as_str(),
+                "stale": !config.slots.is_fresh(&observation),
+                "unparsable": config.slots.unparsable(index),
+            }),
+            None => json!({
+                "name": upstream.name,
+                "url": redact_url(&upstream.url),
+                "slot": null,
+                "lag": null,
+                "observed_at_ms": null,
+                "age_ms": null,
+                "source": null,
+                "feed": config.slots.feed(index).as_str(),
+                "stale": true,
+                "unparsable": config.slots.unparsable(index),
+            }),
+        })
+        .collect();
+    Json(json!({ "max_slot": max_slot, "upstreams": upstreams }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(hosts: usize) -> SlotTracker {
+        SlotTracker::new(vec![Feed::Http; hosts], Duration::from_secs(10))
+    }
+
+    #[test]
+    fn only_non_negative_integers_are_slots() {
+        let slots = tracker(1);
+        for bad in [
+            json!("300"),
+            json!(-1),
+            json!(1.5),
+            json!(null),
+            json!([300]),
+        ] {
+            assert_eq!(slots.observe_value(0, &bad, SlotSource::Response), None);
+        }
+        assert_eq!(slots.unparsable(0), 5);
+        assert!(slots.get(0).is_none());
+        let slot = slots.observe_value(0, &json!(300), SlotSource::Poll);
+        assert_eq!(slot, Some(300));
+        assert_eq!(slots.get(0).unwrap().slot, 300);
+        assert_eq!(slots.unparsable(0), 5);
+    }
+
+    #[test]
+    fn fresh_estimates_never_move_backwards() {
+        let slots = tracker(2);
+        slots.observe(0, 300, SlotSource::Ws);
+        slots.observe(0, 298, SlotSource::Response);
+        let observation = slots.get(0).unwrap();
+        assert_eq!(observation.slot, 300);
+        assert_eq!(observation.source, SlotSource::Response);
+        slots.observe(1, 290, SlotSource::Poll);
+        assert_eq!(slots.max_lag(), Some(10));
+        // a stale one is replaced outright
+        let slots = SlotTracker::new(vec![Feed::Http], Duration::ZERO);
+        slots.observe(0, 300, SlotSource::Poll);
+        slots.observe(0, 298, SlotSource::Poll);
+        assert_eq!(slots.get(0).unwrap().slot, 298);
+        assert_eq!(slots.fresh()[0].map(|o| o.slot), None);
+    }
+
+    #[tokio::test]
+    async fn the_slots_endpoint_reports_every_upstream_lag() {
+        let live = crate::testing::upstream(crate::mock::Behavior::default()).await;
+        let text = format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n[[upstreams]]\nname = \"b\"\nurl = \"http://127.0.0.1:1/secret\"\n[[upstreams]]\nname = \"c\"\nurl = \"http://127.0.0.1:1\"\n",
+            live
+        );
+        let (config, url) = crate::testing::proxy(&text).await;
+        assert!(poll(&config, 0).await);
+        let tip = config.slots.get(0).unwrap().slot;
+        config.slots.observe(1, tip - 7, SlotSource::Response);
+        let slots: Value = reqwest::get(format!("{}/slots", url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(slots["max_slot"], tip);
+        let [a, b, c] = [0, 1, 2].map(|i| &slots["upstreams"][i]);
+        assert_eq!(a["lag"], 0);
+        assert_eq!(a["source"], "poll");
+        assert_eq!(b["lag"], 7);
+        assert_eq!(b["source"], "response");
+        assert!(!b["url"].as_str().unwrap().contains("secret"));
+        // never observed
+        assert_eq!(c["slot"], Value::Null);
+        assert_eq!(c["stale"], true);
+    }
+}
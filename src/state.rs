@@ -0,0 +1,59 @@
+//! State that survives restarts, kept in a JSON file written periodically.
+
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Previously saved state, or an empty object when there is none yet.
+pub fn load(path: &str) -> Value {
+    match std::fs::read_to_string(path) {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(state) => state,
+            Err(err) => {
+                println!("+ Ignoring unreadable state file {}: {}", path, err);
+                json!({})
+            }
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => json!({}),
+        Err(err) => {
+            println!("+ Cannot read state file {}: {}", path, err);
+            json!({})
+        }
+    }
+}
+
+fn snapshot(config: &ServerConfig) -> Value {
+    json!({
+        "quotas": config.quotas.snapshot(),
+        "costs": config.costs.snapshot(),
+        "ranking": crate::ranking::snapshot(config),
+    })
+}
+
+/// Write the state, through a temporary file so a crash never leaves a
+/// truncated one behind.
+fn save(path: &str, state: &Value) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, state.to_string())?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Save the state whenever it changed, every few seconds.
+pub async fn persist(config: Arc<ServerConfig>, path: String) {
+    let mut last = Value::Null;
+    let mut ticker = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let state = snapshot(&config);
+        if state == last {
+            continue;
+        }
+        match save(&path, &state) {
+            Ok(()) => last = state,
+            Err(err) => println!("+ Failed to save state to {}: {}", path, err),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! DogStatsD export of the metrics also served at `/metrics`. Packets go out
+//! over UDP with `try_send`, so an absent agent never slows anything down.
+
+use crate::classify::ErrorClass;
+use crate::config::StatsdConfig;
+use crate::ServerConfig;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// Timings buffered between flushes; beyond this samples are dropped.
+const MAX_TIMINGS: usize = 10_000;
+/// Keeps datagrams under a typical MTU.
+const MAX_PACKET: usize = 1400;
+
+/// Per-request timings waiting for the next flush.
+#[derive(Default)]
+pub struct TimingBuffer {
+    samples: Mutex<Vec<(String, u64)>>,
+}
+
+impl TimingBuffer {
+    pub fn record(&self, method: &str, ms: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() < MAX_TIMINGS {
+            samples.push((method.to_string(), ms));
+        }
+    }
+
+    fn take(&self) -> Vec<(String, u64)> {
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+}
+
+/// Restrict a tag value to characters DogStatsD accepts.
+fn tag(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | ':' | '.' | '/' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+struct Packets {
+    global_tags: String,
+    prefix: String,
+    lines: Vec<String>,
+}
+
+impl Packets {
+    fn push(&mut self, name: &str, value: impl std::fmt::Display, kind: &str, tags: &[String]) {
+        let mut all_tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+        if !self.global_tags.is_empty() {
+            all_tags.push(&self.global_tags);
+        }
+        let mut line = format!("{}.{}:{}|{}", self.prefix, name, value, kind);
+        if !all_tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&all_tags.join(","));
+        }
+        self.lines.push(line);
+    }
+
+    fn datagrams(self) -> Vec<String> {
+        let mut datagrams = Vec::new();
+        let mut current = String::new();
+        for line in self.lines {
+            if !current.is_empty() && current.len() + 1 + line.len() > MAX_PACKET {
+                datagrams.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&line);
+        }
+        if !current.is_empty() {
+            datagrams.push(current);
+        }
+        datagrams
+    }
+}
+
+/// Flush counters (as deltas), gauges and buffered timings every interval.
+pub async fn run(config: Arc<ServerConfig>, settings: StatsdConfig) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!(
+                "+ StatsD exporter disabled, cannot bind UDP socket: {}",
+                err
+            );
+            return;
+        }
+    };
+    let mut connected = false;
+    let mut previous: HashMap<String, u64> = HashMap::new();
+    let mut delta = |key: String, value: u64| {
+        let last = previous.insert(key, value).unwrap_or_default();
+        value.saturating_sub(last)
+    };
+
+    let mut ticker = tokio::time::interval(settings.flush_interval);
+    loop {
+        ticker.tick().await;
+        if !connected {
+            // resolved lazily so a late agent is picked up
+            connected = socket.connect(&settings.address).await.is_ok();
+            if !connected {
+                continue;
+            }
+        }
+
+        let mut packets = Packets {
+            global_tags: settings
+                .tags
+                .iter()
+                .map(|t| tag(t))
+                .chain([format!("version:{}", crate::version::VERSION)])
+                .collect::<Vec<_>>()
+                .join(","),
+            prefix: settings.prefix.clone(),
+            lines: Vec::new(),
+        };
+
+        let requests = config.stats.requests.load(Ordering::Relaxed);
+        packets.push("requests", delta("requests".into(), requests), "c", &[]);
+        let errors = config.stats.errors.load(Ordering::Relaxed);
+        packets.push("request_errors", delta("errors".into(), errors), "c", &[]);
+
+        let quarantine = config.quarantine.read().await.clone();
+        let max_slot = crate::slots::tip(&config);
+        for (index, upstream) in config.servers.iter().enumerate() {
+            let upstream_tag = format!("upstream:{}", tag(&upstream.name));
+            let tags = [upstream_tag.clone()];
+            let successes = upstream.stats.successes();
+            packets.push(
+                "upstream.successes",
+                delta(format!("{}|ok", upstream.name), successes),
+                "c",
+                &tags,
+            );
+            for class in ErrorClass::ALL {
+                let count = delta(
+                    format!("{}|{}", upstream.name, class.as_str()),
+                    upstream.stats.errors(class),
+                );
+                if count > 0 {
+                    let class_tags = [upstream_tag.clone(), format!("class:{}", class.as_str())];
+                    packets.push("upstream.errors", count, "c", &class_tags);
+                }
+            }
+            packets.push(
+                "upstream.failure_streak",
+                upstream.stats.failure_streak(),
+                "g",
+                &tags,
+            );
+            packets.push(
+                "upstream.quarantined",
+                upstream.is_quarantined(&quarantine) as u8,
+                "g",
+                &tags,
+            );
+            if let (Some(observation), Some(max_slot)) = (config.slots.get(index), max_slot) {
+                packets.push("upstream.slot", observation.slot, "g", &tags);
+                packets.push(
+                    "upstream.slot_lag",
+                    max_slot.saturating_sub(observation.slot),
+                    "g",
+                    &tags,
+                );
+            }
+        }
+
+        if let Some(timings) = &config.timings {
+            for (method, ms) in timings.take() {
+                packets.push(
+                    "request.duration",
+                    ms,
+                    "ms",
+                    &[format!("method:{}", tag(&method))],
+                );
+            }
+        }
+
+        for datagram in packets.datagrams() {
+            // UDP: losing a packet is fine, blocking is not
+            let _ = socket.try_send(datagram.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn lines_carry_their_tags_and_fit_in_datagrams() {
+        assert_eq!(tag("node a/1,b|c"), "node_a/1_b_c");
+        let mut packets = Packets {
+            global_tags: "env:prod".to_string(),
+            prefix: "q".to_string(),
+            lines: Vec::new(),
+        };
+        packets.push("requests", 3, "c", &[]);
+        packets.push("upstream.slot", 300, "g", &["upstream:a".to_string()]);
+        assert_eq!(
+            packets.lines,
+            [
+                "q.requests:3|c|#env:prod",
+                "q.upstream.slot:300|g|#upstream:a,env:prod"
+            ]
+        );
+        for n in 0..200 {
+            packets.push("request.duration", n, "ms", &[]);
+        }
+        let datagrams = packets.datagrams();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_PACKET));
+        let lines: usize = datagrams.iter().map(|d| d.lines().count()).sum();
+        assert_eq!(lines, 202);
+    }
+
+    async fn receive(agent: &UdpSocket) -> String {
+        let mut buffer = [0; 65536];
+        let len = agent.recv(&mut buffer).await.unwrap();
+        String::from_utf8(buffer[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn counters_go_out_as_deltas() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let text = format!(
+            "[[upstreams]]\nname = \"a\"\nurl = \"http://127.0.0.1:1\"\n[statsd]\naddress = \"{}\"\nflush_interval_ms = 50\nprefix = \"q\"\n",
+            agent.local_addr().unwrap()
+        );
+        let config = crate::testing::config(&text);
+        let settings = config.settings.statsd.clone().unwrap();
+        for _ in 0..3 {
+            config.stats.record(200, Duration::from_millis(5));
+        }
+        config.timings.as_ref().unwrap().record("getSlot", 5);
+        tokio::spawn(run(config.clone(), settings));
+        let first = receive(&agent).await;
+        assert!(first.contains("q.requests:3|c|#version:"), "{}", first);
+        assert!(
+            first.contains("q.request.duration:5|ms|#method:getSlot"),
+            "{}",
+            first
+        );
+        assert!(
+            first.contains("q.upstream.quarantined:0|g|#upstream:a"),
+            "{}",
+            first
+        );
+        config.stats.record(502, Duration::from_millis(5));
+        let second = receive(&agent).await;
+        assert!(second.contains("q.requests:1|c"), "{}", second);
+        assert!(second.contains("q.request_errors:1|c"), "{}", second);
+        assert!(!second.contains("request.duration"), "{}", second);
+    }
+}
@@ -0,0 +1,227 @@
+//! The `/status` document: every upstream's state for operators, served
+//! over HTTP and, with `[status_export]`, written to a file every few
+//! seconds for hosts that cannot be reached by monitoring.
+
+use crate::classify::ErrorClass;
+use crate::config::StatusExportConfig;
+use crate::effective::redact_url;
+use crate::ServerConfig;
+use axum::{extract::State, Json};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+pub async fn status_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    Json(document(&config).await)
+}
+
+/// The document, with the time it was put together so a copy read later
+/// shows its age. Upstream URLs are redacted here, so neither `/status` nor
+/// the exported file hands out the provider keys they may carry.
+pub async fn document(config: &ServerConfig) -> Value {
+    let quarantine = config.quarantine.read().await;
+    let max_slot = crate::slots::tip(config);
+    let upstreams: Vec<Value> = config
+        .servers
+        .iter()
+        .enumerate()
+        .map(|(index, upstream)| {
+            let slot = config.slots.get(index).map(|observation| observation.slot);
+            let mut errors = Map::new();
+            for class in ErrorClass::ALL {
+                errors.insert(
+                    class.as_str().to_string(),
+                    upstream.stats.errors(class).into(),
+                );
+            }
+            json!({
+                "name": upstream.name,
+                "url": redact_url(&upstream.url),
+                "addresses": *upstream.addresses.lock().unwrap(),
+                "region": upstream.region,
+                "role": match upstream.is_last_resort() {
+                    true => "last_resort",
+                    false => "regular",
+                },
+                "archive": upstream.is_archive(),
+                "capabilities": config.settings.capabilities.describe(upstream.capability_mask()),
+                "discovered": upstream.discovery.report(),
+                "identity": crate::identity::report(config, upstream.index),
+                "genesis_hash": upstream.cluster.genesis_hash(),
+                "version": upstream.version.report(),
+                "slot": slot,
+                "lag": slot.zip(max_slot).map(|(slot, max)| max.saturating_sub(slot)),
+                "quarantined": upstream.is_quarantined(&quarantine),
+                "quarantine_reason": upstream.quarantine_reason(&quarantine),
+                "circuit": upstream.circuit.phase().as_str(),
+                "drained": upstream.drained.load(Ordering::Relaxed),
+                "maintenance": crate::maintenance::report(config, index),
+                "error_rate": config.settings.error_rate.as_ref().map(|settings| {
+                    let (attempts, failed) = upstream.stats.window(settings.window_secs);
+                    json!({
+                        "window_secs": settings.window_secs,
+                        "attempts": attempts,
+                        "failures": failed,
+                        "rate": (attempts > 0).then(|| failed as f64 / attempts as f64),
+                    })
+                }),
+                "successes": upstream.stats.successes(),
+                "failure_streak": upstream.stats.failure_streak(),
+                "stale_rejections": upstream.stats.stale_rejections(),
+                "errors": errors,
+                "chaos": config.chaos.report(index),
+                "blockhash": upstream.blockhash.report(),
+                "plan": {
+                    "remaining": upstream.limits.remaining(),
+                    "reset_in_secs": upstream.limits.reset_in_secs(),
+                    "low": upstream.limits.is_low(),
+                },
+            })
+        })
+        .collect();
+    let region = config.settings.region.as_ref().map(|region| {
+        json!({
+            "local": region.local,
+            "fanout": region.fanout.as_str(),
+            "falling_back": config.stats.is_region_fallback(),
+        })
+    });
+    // listed apart, as they need fixing in the config rather than waiting
+    let dead: Vec<Value> = config
+        .servers
+        .iter()
+        .filter_map(|upstream| {
+            let (since, next_probe) = upstream.circuit.dead()?;
+            Some(json!({
+                "name": upstream.name,
+                "dead_for_secs": since.as_secs(),
+                "next_probe_in_secs": next_probe.as_secs(),
+            }))
+        })
+        .collect();
+    json!({
+        "generated_at": crate::clock::rfc3339(crate::clock::unix_ms()),
+        "pause": config.pause.report(),
+        "upstreams": upstreams,
+        "dead": dead,
+        "region": region,
+        "failback": config.failback.report(&config.settings.failback),
+        "cluster": config.expected_cluster.report(config.settings.cluster.as_ref()),
+        "slot_tolerance": config.tolerance.report(),
+    })
+}
+
+/// Write the document to `settings.path` every `settings.interval`, through
+/// a temporary file so readers never see a partial one. It is the same
+/// redacted document `/status` serves. Failures are logged and counted,
+/// and the next round tries again.
+pub async fn export(config: Arc<ServerConfig>, settings: StatusExportConfig) {
+    let temporary = format!("{}.tmp", settings.path);
+    let mut ticker = tokio::time::interval(settings.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let document = document(&config).await.to_string();
+        let written = match tokio::fs::write(&temporary, document).await {
+            Ok(()) => tokio::fs::rename(&temporary, &settings.path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            config
+                .stats
+                .status_export_failures
+                .fetch_add(1, Ordering::Relaxed);
+            println!(
+                "+ Failed to export the status to {}: {}",
+                settings.path, err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, upstream};
+    use std::time::Duration;
+
+    fn temp_path() -> String {
+        let name = format!("quarantier-status-{}.json", crate::random::u64());
+        std::env::temp_dir()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn the_exported_file_is_the_status_document() {
+        let node = upstream(crate::mock::Behavior::default()).await;
+        let path = temp_path();
+        let (config, url) = proxy(&format!(
+            "[status_export]\npath = \"{}\"\ninterval_secs = 1\n[[upstreams]]\nname = \"a\"\nurl = \"{}\"\n",
+            path, node
+        ))
+        .await;
+        let balance = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        assert_eq!(call(&url, balance).await.status(), 200);
+        let settings = config.settings.status_export.clone().unwrap();
+        let exporting = tokio::spawn(export(config.clone(), settings));
+        let mut exported = None;
+        for _ in 0..100 {
+            if let Ok(text) = std::fs::read_to_string(&path) {
+                exported = Some(serde_json::from_str::<Value>(&text).unwrap());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        exporting.abort();
+        let mut exported = exported.expect("the status was never exported");
+        let mut served: Value = reqwest::get(format!("{}/status", url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        // the same document but for when each was put together
+        assert!(exported["generated_at"].as_str().unwrap().ends_with('Z'));
+        exported["generated_at"] = Value::Null;
+        served["generated_at"] = Value::Null;
+        assert_eq!(exported, served);
+        assert_eq!(exported["upstreams"][0]["name"], "a");
+        assert_eq!(exported["upstreams"][0]["lag"], 0);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn failed_exports_are_counted_and_requests_go_on() {
+        let node = upstream(crate::mock::Behavior::default()).await;
+        let path = format!("{}/status.json", temp_path());
+        let (config, url) = proxy(&format!(
+            "[status_export]\npath = \"{}\"\ninterval_secs = 1\n[[upstreams]]\nurl = \"{}\"\n",
+            path, node
+        ))
+        .await;
+        let settings = config.settings.status_export.clone().unwrap();
+        let exporting = tokio::spawn(export(config.clone(), settings));
+        let failures = &config.stats.status_export_failures;
+        for _ in 0..100 {
+            if failures.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(failures.load(Ordering::Relaxed), 1);
+        let balance = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] });
+        assert_eq!(call(&url, balance).await.status(), 200);
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("quarantier_status_export_failures_total 1\n"));
+        exporting.abort();
+    }
+}
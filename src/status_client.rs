@@ -0,0 +1,196 @@
+//! `status` subcommand: a terminal view of a running proxy, built from its
+//! `/status` and `/slots` endpoints. The exit code reflects health so
+//! scripts can act on it: 0 when every upstream is healthy, 1 when some
+//! are not, 2 when none is or the proxy cannot be reached.
+
+use serde_json::Value;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: quarantier status [--admin-url <URL>]
+  --admin-url <URL>  proxy to query (default http://127.0.0.1:8080)";
+
+async fn fetch(client: &reqwest::Client, url: String) -> Result<Value, String> {
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("{}: {}", url, e))?;
+    response.json().await.map_err(|e| format!("{}: {}", url, e))
+}
+
+/// Text of a table cell; missing values show as `-`.
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let admin_url = match args {
+        [] => "http://127.0.0.1:8080".to_string(),
+        [flag, url] if flag == "--admin-url" => url.trim_end_matches('/').to_string(),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let (status, slots) = tokio::join!(
+        fetch(&client, format!("{}/status", admin_url)),
+        fetch(&client, format!("{}/slots", admin_url)),
+    );
+    let (status, slots) = match (status, slots) {
+        (Ok(status), Ok(slots)) => (status, slots),
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("cannot reach the proxy: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let (text, code) = render(&status, &slots);
+    print!("{}", text);
+    std::process::exit(code);
+}
+
+/// The table of the proxy's upstreams from its `/status` and `/slots`
+/// answers, and the exit code for their health.
+fn render(status: &Value, slots: &Value) -> (String, i32) {
+    let max_slot = cell(&slots["max_slot"]);
+    let empty = Vec::new();
+    let slots = slots["upstreams"].as_array().unwrap_or(&empty);
+    let mut rows = vec![[
+        "NAME",
+        "URL",
+        "STATE",
+        "SLOT",
+        "LAG",
+        "SUCCESSES",
+        "FAILING",
+    ]
+    .map(String::from)];
+    let mut healthy = 0;
+    let upstreams = status["upstreams"].as_array().unwrap_or(&empty);
+    for upstream in upstreams {
+        let slot = slots
+            .iter()
+            .find(|slot| slot["url"] == upstream["url"])
+            .unwrap_or(&Value::Null);
+        let state = if upstream["quarantined"] == true {
+            "quarantined"
+        } else if slot["stale"] != false {
+            "stale"
+        } else {
+            healthy += 1;
+            "ok"
+        };
+        let chaos = upstream["chaos"].as_array().is_some_and(|c| !c.is_empty());
+        let mut state = state.to_string();
+        if upstream["blockhash"]["stale"] == true {
+            state.push_str("+stale-blockhash");
+        }
+        if upstream["plan"]["low"] == true {
+            state.push_str("+plan-low");
+        }
+        rows.push([
+            cell(&upstream["name"]),
+            cell(&upstream["url"]),
+            if chaos {
+                format!("{}+chaos", state)
+            } else {
+                state
+            },
+            cell(&slot["slot"]),
+            cell(&slot["lag"]),
+            cell(&upstream["successes"]),
+            cell(&upstream["failure_streak"]),
+        ]);
+    }
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut text = String::new();
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        text += line.join("  ").trim_end();
+        text.push('\n');
+    }
+    text += &format!(
+        "\n{}/{} upstreams healthy, highest slot {}\n",
+        healthy,
+        upstreams.len(),
+        max_slot
+    );
+
+    let code = match healthy {
+        0 => 2,
+        n if n < upstreams.len() => 1,
+        _ => 0,
+    };
+    (text, code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use crate::testing::{proxy, upstream};
+
+    #[tokio::test]
+    async fn the_table_and_exit_code_follow_the_proxy() {
+        let good = upstream(Behavior::default()).await;
+        let behind = upstream(Behavior {
+            slot_lag: 500,
+            ..Behavior::default()
+        })
+        .await;
+        let (config, url) = proxy(&format!(
+            "[[upstreams]]\nname = \"good\"\nurl = \"{}\"\n\n[[upstreams]]\nname = \"behind\"\nurl = \"{}\"\n",
+            good, behind
+        ))
+        .await;
+        let client = reqwest::Client::new();
+        let read = || async {
+            let status = fetch(&client, format!("{}/status", url)).await.unwrap();
+            let slots = fetch(&client, format!("{}/slots", url)).await.unwrap();
+            render(&status, &slots)
+        };
+        // nothing polled yet, nothing known to be healthy
+        let (text, code) = read().await;
+        assert_eq!(code, 2, "{}", text);
+        assert!(text.starts_with("NAME "), "{}", text);
+
+        crate::slots::repoll(&config, 0).await;
+        crate::slots::repoll(&config, 1).await;
+        let (text, code) = read().await;
+        assert_eq!(code, 1, "{}", text);
+        let row = |name: &str| {
+            text.lines()
+                .find(|line| line.starts_with(name))
+                .unwrap()
+                .split_whitespace()
+                .nth(2)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(row("good"), "ok");
+        assert_eq!(row("behind"), "quarantined");
+        assert!(
+            text.contains("\n1/2 upstreams healthy, highest slot "),
+            "{}",
+            text
+        );
+    }
+}
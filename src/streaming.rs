@@ -0,0 +1,287 @@
+//! Streaming of the winning upstream body: its chunks are forwarded to the
+//! client as they arrive, while the whole body is still collected for slot
+//! tracking and divergence comparison, within the `body_budget`. A body
+//! growing past its `[response_limits]` limit is abandoned.
+
+use crate::body_budget::{BodyBudget, Degraded, Reservation};
+use axum::body::{Body, Bytes};
+use std::io;
+use tokio::sync::mpsc;
+
+/// Chunks buffered between the upstream and a slow client.
+const CHUNK_BUFFER: usize = 16;
+
+pub type Chunk = Result<Bytes, io::Error>;
+
+/// Body of the answer handed to the handler.
+pub enum AnswerBody {
+    /// Generated by the proxy, or already read in full.
+    Full(Bytes),
+    /// The winner's body, still arriving.
+    Stream(mpsc::Receiver<Chunk>),
+}
+
+pub fn channel() -> (mpsc::Sender<Chunk>, mpsc::Receiver<Chunk>) {
+    mpsc::channel(CHUNK_BUFFER)
+}
+
+/// What reading a body came to.
+pub enum Read {
+    Kept(Bytes),
+    /// Read, but over the `budget` and not kept.
+    Unkept,
+    /// Abandoned past the response size limit.
+    Oversize,
+}
+
+/// Whether a response announcing its length is over `limit`.
+pub fn oversize(response: &reqwest::Response, limit: Option<u64>) -> bool {
+    response
+        .content_length()
+        .zip(limit)
+        .is_some_and(|(len, limit)| len > limit)
+}
+
+/// The error a streamed body is cut with past `limit`.
+pub fn oversize_error(limit: u64) -> io::Error {
+    io::Error::other(format!("response exceeds the {} byte limit", limit))
+}
+
+fn degraded(client: &Option<mpsc::Sender<Chunk>>) -> Degraded {
+    match client {
+        Some(_) => Degraded::StreamedThrough,
+        None => Degraded::Dropped,
+    }
+}
+
+/// Read `response` up to its first chunk with anything but whitespace, so
+/// an empty body does not win the race. Returns the chunks read, and
+/// whether the body ended with them.
+pub async fn first_bytes(
+    response: &mut reqwest::Response,
+) -> Result<(Vec<Bytes>, bool), reqwest::Error> {
+    let mut chunks = Vec::new();
+    loop {
+        let Some(chunk) = response.chunk().await? else {
+            return Ok((chunks, true));
+        };
+        let started = !chunk.iter().all(u8::is_ascii_whitespace);
+        chunks.push(chunk);
+        if started {
+            return Ok((chunks, false));
+        }
+    }
+}
+
+/// Read the whole body of `response` after the `read` chunks already taken
+/// from it, forwarding every chunk to `client` when given. A client gone
+/// mid-body stops receiving chunks but the body is still read; an upstream
+/// failing mid-body aborts the client's stream. `Unkept` when the body did
+/// not fit in `budget`: the client's chunks are still forwarded, without
+/// one the rest is not read. Past `limit` bytes the client's stream is
+/// aborted and the response dropped, which closes its connection rather
+/// than reading the rest.
+pub async fn read_body(
+    mut response: reqwest::Response,
+    read: Vec<Bytes>,
+    mut client: Option<mpsc::Sender<Chunk>>,
+    budget: &BodyBudget,
+    limit: Option<u64>,
+) -> Result<Read, reqwest::Error> {
+    // a streamed body cannot wait for room, its client would
+    let waits = client.is_none();
+    let mut reservation = Reservation::default();
+    let mut kept = match response.content_length() {
+        Some(len) => budget.grow(&mut reservation, len as usize, waits).await,
+        None => true,
+    };
+    if !kept {
+        budget.record(degraded(&client));
+    }
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    let mut relayed = 0;
+    let mut read = read.into_iter();
+    loop {
+        if !kept && client.is_none() {
+            return Ok(Read::Unkept);
+        }
+        let next = match read.next() {
+            Some(chunk) => Ok(Some(chunk)),
+            None => response.chunk().await,
+        };
+        match next {
+            Ok(Some(chunk)) => {
+                relayed += chunk.len() as u64;
+                if let Some(limit) = limit.filter(|&limit| relayed > limit) {
+                    if let Some(sender) = &client {
+                        let _ = sender.send(Err(oversize_error(limit))).await;
+                    }
+                    return Ok(Read::Oversize);
+                }
+                if let Some(sender) = &client {
+                    if sender.send(Ok(chunk.clone())).await.is_err() {
+                        client = None;
+                    }
+                }
+                if !kept {
+                    continue;
+                }
+                size += chunk.len();
+                let more = size.saturating_sub(reservation.bytes());
+                if more == 0 || budget.grow(&mut reservation, more, waits).await {
+                    chunks.push(chunk);
+                    continue;
+                }
+                kept = false;
+                chunks.clear();
+                reservation = Reservation::default();
+                budget.record(degraded(&client));
+            }
+            Ok(None) => break,
+            Err(err) => {
+                // the URL may carry the provider's API key
+                let err = err.without_url();
+                if let Some(sender) = &client {
+                    let _ = sender.send(Err(io::Error::other(err.to_string()))).await;
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(match kept {
+        true => Read::Kept(reservation.hold(chunks)),
+        false => Read::Unkept,
+    })
+}
+
+/// Response body fed by the chunks of a streamed answer.
+pub fn body(chunks: mpsc::Receiver<Chunk>) -> Body {
+    let stream = futures::stream::unfold(chunks, |mut chunks| async move {
+        let chunk = chunks.recv().await?;
+        Some((chunk, chunks))
+    });
+    Body::from_stream(stream)
+}
+
+/// Wait for the rest of a streamed answer, for the rare responses that must
+/// be rewritten as a whole.
+pub async fn collect(mut chunks: mpsc::Receiver<Chunk>) -> Result<Bytes, io::Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = chunks.recv().await {
+        body.extend_from_slice(&chunk?);
+    }
+    Ok(body.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BodyBudgetConfig;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    /// An upstream answering with `chunks`, sent one by one, then breaking
+    /// off mid-body when `cut` is set.
+    async fn upstream(chunks: &'static [&'static str], cut: bool) -> reqwest::Response {
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || async move {
+                let chunks = chunks
+                    .iter()
+                    .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                    .chain(cut.then(|| Err(io::Error::other("cut"))));
+                // spaced out so the head is sent before anything fails
+                Body::from_stream(futures::stream::iter(chunks).then(|chunk| async {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    chunk
+                }))
+            }),
+        );
+        let url = crate::testing::serve(router).await;
+        reqwest::get(&url).await.unwrap()
+    }
+
+    fn budget(limit: usize) -> BodyBudget {
+        BodyBudget::new(&BodyBudgetConfig {
+            limit,
+            wait: Duration::ZERO,
+        })
+    }
+
+    #[tokio::test]
+    async fn the_body_is_kept_while_it_streams() {
+        let response = upstream(&["{\"jsonrpc\":", "\"2.0\",", "\"result\":1}"], false).await;
+        let (sender, receiver) = channel();
+        let budget = budget(1 << 20);
+        let reading = read_body(response, Vec::new(), Some(sender), &budget, None);
+        let (kept, streamed) = tokio::join!(reading, collect(receiver));
+        let Read::Kept(kept) = kept.unwrap() else {
+            panic!("the body fits in the budget");
+        };
+        assert_eq!(
+            kept,
+            Bytes::from_static(b"{\"jsonrpc\":\"2.0\",\"result\":1}")
+        );
+        assert_eq!(streamed.unwrap(), kept);
+        assert_eq!(budget.reserved(), kept.len());
+        drop(kept);
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_client_gone_mid_body_leaves_it_read() {
+        let response = upstream(&["0123456789", "0123456789"], false).await;
+        let (sender, receiver) = channel();
+        drop(receiver);
+        let kept = read_body(response, Vec::new(), Some(sender), &budget(1 << 20), None).await;
+        assert!(matches!(kept.unwrap(), Read::Kept(body) if body.len() == 20));
+    }
+
+    #[tokio::test]
+    async fn an_upstream_failing_mid_body_aborts_the_stream() {
+        let response = upstream(&["0123456789"], true).await;
+        let (sender, receiver) = channel();
+        let budget = budget(1 << 20);
+        let reading = read_body(response, Vec::new(), Some(sender), &budget, None);
+        let (kept, streamed) = tokio::join!(reading, collect(receiver));
+        assert!(kept.is_err());
+        assert!(streamed.is_err());
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_budget_still_reach_the_client() {
+        let response = upstream(&["0123456789", "0123456789"], false).await;
+        let (sender, receiver) = channel();
+        let budget = budget(8);
+        let reading = read_body(response, Vec::new(), Some(sender), &budget, None);
+        let (kept, streamed) = tokio::join!(reading, collect(receiver));
+        assert!(matches!(kept.unwrap(), Read::Unkept));
+        assert_eq!(streamed.unwrap().len(), 20);
+        assert_eq!(budget.degraded(Degraded::StreamedThrough), 1);
+        assert_eq!(budget.reserved(), 0);
+        // nobody to stream to, the rest is not even read
+        let response = upstream(&["0123456789", "0123456789"], false).await;
+        let kept = read_body(response, Vec::new(), None, &budget, None).await;
+        assert!(matches!(kept.unwrap(), Read::Unkept));
+        assert_eq!(budget.degraded(Degraded::Dropped), 1);
+    }
+
+    #[tokio::test]
+    async fn bodies_past_the_limit_are_abandoned() {
+        let response = upstream(&["0123456789", "0123456789"], false).await;
+        let (sender, receiver) = channel();
+        let budget = budget(1 << 20);
+        let reading = read_body(response, Vec::new(), Some(sender), &budget, Some(15));
+        let (kept, streamed) = tokio::join!(reading, collect(receiver));
+        assert!(matches!(kept.unwrap(), Read::Oversize));
+        assert!(streamed.is_err());
+        assert_eq!(budget.reserved(), 0);
+        // the chunks already taken count towards the limit
+        let response = upstream(&["0123456789"], false).await;
+        let read = vec![Bytes::from_static(b"0123456789")];
+        let kept = read_body(response, read, None, &budget, Some(15)).await;
+        assert!(matches!(kept.unwrap(), Read::Oversize));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::metrics::HistogramSnapshot;
+use crate::ServerConfig;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Print a one-line operational summary every `interval`, built from the
+/// same counters as `/metrics`. Idle periods with no state change are skipped.
+pub async fn log_summaries(config: Arc<ServerConfig>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let mut summary = Summary::new(&config);
+    loop {
+        ticker.tick().await;
+        if let Some(line) = summary.next(&config).await {
+            println!("{}", line);
+        }
+    }
+}
+
+/// The counters as of the last summary, to report what happened since.
+struct Summary {
+    last_tick: Instant,
+    last_requests: u64,
+    last_errors: u64,
+    last_latency: HistogramSnapshot,
+    /// Healthy and quarantined hosts and the largest slot lag.
+    last_state: Option<(usize, usize, Option<u64>)>,
+}
+
+impl Summary {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            last_tick: Instant::now(),
+            last_requests: config.stats.requests.load(Ordering::Relaxed),
+            last_errors: config.stats.errors.load(Ordering::Relaxed),
+            last_latency: config.stats.latency.snapshot(),
+            last_state: None,
+        }
+    }
+
+    /// The line for the period since the last call, `None` when it was idle
+    /// and nothing changed.
+    async fn next(&mut self, config: &ServerConfig) -> Option<String> {
+        let requests_total = config.stats.requests.load(Ordering::Relaxed);
+        let errors_total = config.stats.errors.load(Ordering::Relaxed);
+        let latency_total = config.stats.latency.snapshot();
+        let requests = requests_total - self.last_requests;
+        let errors = errors_total - self.last_errors;
+        let latency = latency_total.since(&self.last_latency);
+        let elapsed = self.last_tick.elapsed().as_secs_f64();
+        self.last_tick = Instant::now();
+        self.last_requests = requests_total;
+        self.last_errors = errors_total;
+        self.last_latency = latency_total;
+
+        let (healthy, quarantined) = crate::quarantine::host_counts(config).await;
+        let max_lag = config.slots.max_lag();
+        let state = Some((healthy, quarantined, max_lag));
+        if requests == 0 && state == self.last_state {
+            return None;
+        }
+        self.last_state = state;
+
+        let ms = |q: f64| match latency.quantile(q) {
+            Some(ms) => format!("{:.0}ms", ms),
+            None => "-".to_string(),
+        };
+        let error_rate = if requests == 0 {
+            0.0
+        } else {
+            100.0 * errors as f64 / requests as f64
+        };
+        Some(format!(
+            "quarantier-summary rps={:.1} requests={} error_rate={:.2}% p50={} p99={} healthy={} quarantined={} max_slot_lag={}",
+            requests as f64 / elapsed,
+            requests,
+            error_rate,
+            ms(0.5),
+            ms(0.99),
+            healthy,
+            quarantined,
+            max_lag.map_or("-".to_string(), |lag| lag.to_string()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotSource;
+
+    #[tokio::test]
+    async fn summaries_cover_the_period_since_the_last() {
+        let config = crate::testing::config("[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n");
+        config.slots.observe(0, 300, SlotSource::Poll);
+        // traffic before the first summary is not part of it
+        config.stats.record(200, Duration::from_millis(5));
+        let mut summary = Summary::new(&config);
+        for status in [200, 200, 200, 502] {
+            config.stats.record(status, Duration::from_millis(5));
+        }
+        let line = summary.next(&config).await.unwrap();
+        assert!(line.starts_with("quarantier-summary rps="), "{}", line);
+        assert!(line.contains(" requests=4 error_rate=25.00% "), "{}", line);
+        assert!(line.contains(" healthy=1 quarantined=0 "), "{}", line);
+        // an idle period with nothing changed stays quiet
+        assert_eq!(summary.next(&config).await, None);
+        config.stats.record(200, Duration::from_millis(5));
+        let line = summary.next(&config).await.unwrap();
+        assert!(line.contains(" requests=1 error_rate=0.00% "), "{}", line);
+    }
+}
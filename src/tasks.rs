@@ -0,0 +1,144 @@
+//! Accounting and bounds for the dispatch tasks spawned per request. A task
+//! keeps collecting the remaining upstream answers after the client is
+//! served, for slot and divergence tracking; only a bounded number may do
+//! so at once, the others drop their outstanding requests.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct DispatchTasks {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    aborted: AtomicU64,
+    lingering: Arc<Semaphore>,
+    max_lingering: usize,
+}
+
+/// One spawned task. Dropping it counts the task as aborted unless it was
+/// marked complete, so panics and early returns are accounted for too.
+pub struct TaskGuard<'a> {
+    tasks: &'a DispatchTasks,
+    completed: bool,
+}
+
+impl DispatchTasks {
+    pub fn new(max_lingering: usize) -> Self {
+        Self {
+            spawned: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            aborted: AtomicU64::new(0),
+            lingering: Arc::new(Semaphore::new(max_lingering)),
+            max_lingering,
+        }
+    }
+
+    pub fn start(&self) -> TaskGuard<'_> {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        TaskGuard {
+            tasks: self,
+            completed: false,
+        }
+    }
+
+    /// Permission to keep waiting on upstreams once the client has its
+    /// answer, `None` when too many tasks already do.
+    pub fn linger(&self) -> Option<OwnedSemaphorePermit> {
+        self.lingering.clone().try_acquire_owned().ok()
+    }
+
+    pub fn spawned(&self) -> u64 {
+        self.spawned.load(Ordering::Relaxed)
+    }
+
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn aborted(&self) -> u64 {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Tasks currently collecting answers past the client's.
+    pub fn lingering(&self) -> usize {
+        self.max_lingering - self.lingering.available_permits()
+    }
+}
+
+impl TaskGuard<'_> {
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        let counter = if self.completed {
+            &self.tasks.completed
+        } else {
+            &self.tasks.aborted
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{call, proxy, serve};
+    use axum::{routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn tasks_not_completed_count_as_aborted() {
+        let tasks = DispatchTasks::new(1);
+        tasks.start().complete();
+        drop(tasks.start());
+        assert_eq!(
+            (tasks.spawned(), tasks.completed(), tasks.aborted()),
+            (2, 1, 1)
+        );
+        let lingering = tasks.linger();
+        assert!(lingering.is_some());
+        assert_eq!(tasks.lingering(), 1);
+        assert!(tasks.linger().is_none());
+        drop(lingering);
+        assert_eq!(tasks.lingering(), 0);
+    }
+
+    /// An upstream answering after `delay`, counting the requests it
+    /// answered in full.
+    async fn answering_after(delay: Duration, answered: Arc<AtomicU64>) -> String {
+        serve(Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                tokio::time::sleep(delay).await;
+                answered.fetch_add(1, Ordering::SeqCst);
+                Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": 1 }))
+            }),
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn beyond_the_bound_slower_answers_are_dropped() {
+        for (max_lingering, slow_answers) in [(1, 1), (0, 0)] {
+            let slow_answered = Arc::new(AtomicU64::new(0));
+            let fast = answering_after(Duration::ZERO, Default::default()).await;
+            let slow = answering_after(Duration::from_millis(200), slow_answered.clone()).await;
+            let (config, url) = proxy(&format!(
+                "max_lingering_dispatches = {}\n[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n",
+                max_lingering, fast, slow
+            ))
+            .await;
+            let get_balance = json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance" });
+            assert_eq!(call(&url, get_balance).await.status(), 200);
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            assert_eq!(slow_answered.load(Ordering::SeqCst), slow_answers);
+            let tasks = &config.tasks;
+            assert_eq!(tasks.spawned(), 1);
+            assert_eq!(tasks.lingering(), 0);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Helpers shared by the unit tests.
+
+use crate::config::Config;
+use crate::ServerConfig;
+use axum::Router;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// The proxy state for the config file `text`, with nothing saved.
+pub fn config(text: &str) -> Arc<ServerConfig> {
+    let settings = Config::parse(text).unwrap();
+    Arc::new(ServerConfig::new(settings, &serde_json::json!({})))
+}
+
+/// Serve `router` on a port of its own, returning its base URL.
+pub async fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, service).await });
+    url
+}
+
+/// A proxy for the config file `text` serving on a port of its own,
+/// without its background tasks, and its base URL.
+pub async fn proxy(text: &str) -> (Arc<ServerConfig>, String) {
+    let service = crate::ProxyService::new(Config::parse(text).unwrap());
+    let url = serve(service.router().unwrap()).await;
+    (service.config, url)
+}
+
+/// A mock upstream behaving like `behavior` for good, and its URL.
+pub async fn upstream(behavior: crate::mock::Behavior) -> String {
+    crate::mock::spawn(behavior, Vec::new()).await.unwrap()
+}
+
+/// Post the JSON-RPC `body` to `url`.
+pub async fn call(url: &str, body: serde_json::Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(url)
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+/// A host accepting no more connections: its backlog is full, so it drops
+/// their SYNs the way a blackholed host does.
+pub struct Blackhole {
+    listener: TcpListener,
+    _queued: Vec<TcpStream>,
+}
+
+impl Blackhole {
+    pub async fn new() -> Self {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        // never accepted, they fill the backlog
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+        {
+            queued.push(stream);
+        }
+        Self {
+            listener,
+            _queued: queued,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.listener.local_addr().unwrap())
+    }
+}
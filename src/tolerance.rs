@@ -0,0 +1,254 @@
+//! How far behind the tip an upstream may be before the slot-lag quarantine
+//! pulls it. `[slots] tolerance` is a number of slots, or a time such as
+//! `"3s"` turned into slots with the slot time measured from the tip's
+//! advance over the last minute, so the tolerance follows the cluster
+//! through slowdowns instead of assuming 400ms slots.
+
+use crate::config::Tolerance;
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Slots a host may trail the tip by, without `[slots] tolerance`.
+pub const DEFAULT_SLOTS: u64 = 7;
+/// Assumed until the slot time is measured.
+const NOMINAL_SLOT_TIME: Duration = Duration::from_millis(400);
+/// Span of tip samples the slot time is measured over.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Pause between samples of the tip, and between recomputations.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Slots the tip must advance across the window for a measurement.
+const MIN_ADVANCE: u64 = 10;
+/// Bounds of a measured slot time, past which it is taken as a stalled or
+/// jumping tip rather than the cluster's pace.
+const SLOT_TIME_BOUNDS: (Duration, Duration) =
+    (Duration::from_millis(200), Duration::from_millis(2000));
+/// Bounds of a tolerance derived from a time.
+const SLOT_BOUNDS: (u64, u64) = (2, 150);
+
+pub struct SlotTolerance {
+    configured: Tolerance,
+    /// Effective tolerance, in slots.
+    slots: AtomicU64,
+    /// Measured slot time in microseconds, 0 until measured.
+    slot_time_us: AtomicU64,
+    /// The tip sampled every `SAMPLE_INTERVAL`, over `WINDOW`.
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl SlotTolerance {
+    pub fn new(configured: Tolerance) -> Self {
+        let tolerance = Self {
+            configured,
+            slots: AtomicU64::new(DEFAULT_SLOTS),
+            slot_time_us: AtomicU64::new(0),
+            samples: Mutex::default(),
+        };
+        tolerance.recompute();
+        tolerance
+    }
+
+    /// Slots a host may trail the tip by.
+    pub fn slots(&self) -> u64 {
+        self.slots.load(Ordering::Relaxed)
+    }
+
+    fn slot_time(&self) -> Option<Duration> {
+        match self.slot_time_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// Record the tip, and measure the slot time over the samples kept.
+    fn sample(&self, tip: u64) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        // a tip going back means the estimates were reset, start over
+        if samples.back().is_some_and(|&(_, slot)| slot > tip) {
+            samples.clear();
+        }
+        samples.push_back((now, tip));
+        while samples.front().is_some_and(|&(at, _)| now - at > WINDOW) {
+            samples.pop_front();
+        }
+        let &(since, first) = samples.front().unwrap();
+        let advance = tip - first;
+        if advance >= MIN_ADVANCE {
+            let slot_time =
+                ((now - since) / advance as u32).clamp(SLOT_TIME_BOUNDS.0, SLOT_TIME_BOUNDS.1);
+            self.slot_time_us
+                .store(slot_time.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn recompute(&self) {
+        let slots = match self.configured {
+            Tolerance::Slots(slots) => slots,
+            Tolerance::Time(time) => {
+                let slot_time = self.slot_time().unwrap_or(NOMINAL_SLOT_TIME);
+                let slots = time.as_micros().div_ceil(slot_time.as_micros()) as u64;
+                slots.clamp(SLOT_BOUNDS.0, SLOT_BOUNDS.1)
+            }
+        };
+        self.slots.store(slots, Ordering::Relaxed);
+    }
+
+    /// The tolerance in effect and what it comes from, for `/status`.
+    pub fn report(&self) -> Value {
+        let configured = match self.configured {
+            Tolerance::Slots(slots) => json!(slots),
+            Tolerance::Time(time) => json!(format!("{}ms", time.as_millis())),
+        };
+        json!({
+            "configured": configured,
+            "slots": self.slots(),
+            "slot_time_ms": self.slot_time().map(|time| time.as_secs_f64() * 1000.0),
+        })
+    }
+}
+
+/// Sample the tip every few seconds forever, keeping the measured slot time
+/// and the tolerance derived from it current.
+pub async fn follow(config: Arc<ServerConfig>) {
+    let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let Some(tip) = crate::slots::tip(&config) else {
+            continue;
+        };
+        let tolerance = &config.tolerance;
+        let before = tolerance.slots();
+        tolerance.sample(tip);
+        tolerance.recompute();
+        if tolerance.slots() != before {
+            println!(
+                "+ Slot tolerance now {} slots, at a measured {:?} per slot",
+                tolerance.slots(),
+                tolerance.slot_time().unwrap_or(NOMINAL_SLOT_TIME)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::slots::SlotSource;
+
+    fn configured(tolerance: &str) -> Result<Tolerance, String> {
+        let text = format!(
+            "[slots]\ntolerance = {}\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n",
+            tolerance
+        );
+        Config::parse(&text)
+            .map(|config| config.slots.tolerance)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Tip samples as if taken `ago` before now.
+    fn sampled(tolerance: &SlotTolerance, samples: &[(Duration, u64)]) {
+        let now = Instant::now();
+        let mut kept = tolerance.samples.lock().unwrap();
+        kept.extend(samples.iter().map(|&(ago, slot)| (now - ago, slot)));
+    }
+
+    #[test]
+    fn tolerances_are_slots_or_times() {
+        assert!(matches!(configured("9"), Ok(Tolerance::Slots(9))));
+        let time = |text| match configured(text) {
+            Ok(Tolerance::Time(time)) => time,
+            _ => panic!("{} is not a time", text),
+        };
+        assert_eq!(time("\"3s\""), Duration::from_secs(3));
+        assert_eq!(time("\"1.5s\""), Duration::from_millis(1500));
+        assert_eq!(time("\"2500ms\""), Duration::from_millis(2500));
+        for invalid in ["\"3 parsecs\"", "\"0s\"", "\"-1s\"", "true"] {
+            assert!(configured(invalid).is_err(), "{}", invalid);
+        }
+        assert!(matches!(
+            Config::parse("[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n")
+                .unwrap()
+                .slots
+                .tolerance,
+            Tolerance::Slots(DEFAULT_SLOTS)
+        ));
+    }
+
+    #[test]
+    fn times_follow_the_measured_slot_time() {
+        let tolerance = SlotTolerance::new(Tolerance::Time(Duration::from_secs(3)));
+        // 400ms slots until measured
+        assert_eq!(tolerance.slots(), 8);
+        assert_eq!(tolerance.report()["slot_time_ms"], Value::Null);
+        // too little advance to measure anything
+        sampled(&tolerance, &[(Duration::from_secs(10), 1000)]);
+        tolerance.sample(1005);
+        tolerance.recompute();
+        assert_eq!(tolerance.slot_time(), None);
+        // a slowed cluster, 800ms a slot
+        tolerance.samples.lock().unwrap().clear();
+        sampled(&tolerance, &[(Duration::from_secs(20), 1000)]);
+        tolerance.sample(1025);
+        tolerance.recompute();
+        let slot_time = tolerance.slot_time().unwrap();
+        assert!(slot_time.abs_diff(Duration::from_millis(800)) < Duration::from_millis(5));
+        assert_eq!(tolerance.slots(), 4);
+        let report = tolerance.report();
+        assert_eq!(report["configured"], "3000ms");
+        assert_eq!(report["slots"], 4);
+        // samples older than the window are forgotten
+        tolerance.samples.lock().unwrap().clear();
+        sampled(
+            &tolerance,
+            &[
+                (Duration::from_secs(120), 0),
+                (Duration::from_secs(4), 1000),
+            ],
+        );
+        tolerance.sample(1010);
+        assert_eq!(tolerance.samples.lock().unwrap().len(), 2);
+        tolerance.recompute();
+        assert_eq!(tolerance.slots(), 8);
+    }
+
+    #[test]
+    fn measurements_and_tolerances_are_kept_in_bounds() {
+        // a tip jumping ahead is not a 20ms slot
+        let tolerance = SlotTolerance::new(Tolerance::Time(Duration::from_secs(3)));
+        sampled(&tolerance, &[(Duration::from_secs(20), 0)]);
+        tolerance.sample(1000);
+        tolerance.recompute();
+        assert_eq!(tolerance.slot_time(), Some(SLOT_TIME_BOUNDS.0));
+        assert_eq!(tolerance.slots(), 15);
+        let long = SlotTolerance::new(Tolerance::Time(Duration::from_secs(300)));
+        assert_eq!(long.slots(), SLOT_BOUNDS.1);
+        let short = SlotTolerance::new(Tolerance::Time(Duration::from_millis(100)));
+        assert_eq!(short.slots(), SLOT_BOUNDS.0);
+        // plain slots are taken as they are
+        let slots = SlotTolerance::new(Tolerance::Slots(300));
+        sampled(&slots, &[(Duration::from_secs(20), 1000)]);
+        slots.sample(1025);
+        slots.recompute();
+        assert_eq!(slots.slots(), 300);
+        assert_eq!(slots.report()["configured"], 300);
+    }
+
+    #[tokio::test]
+    async fn the_quarantine_uses_the_effective_tolerance() {
+        let two = "[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n[[upstreams]]\nurl = \"http://127.0.0.1:2\"\n";
+        for (tolerance, quarantined) in [("", false), ("[slots]\ntolerance = \"1s\"\n", true)] {
+            let config = crate::testing::config(&format!("{}{}", tolerance, two));
+            config.slots.observe(0, 1000, SlotSource::Poll);
+            config.slots.observe(1, 995, SlotSource::Poll);
+            crate::quarantine::reevaluate(&config, "test").await;
+            let quarantine = config.quarantine.read().await;
+            assert_eq!(config.servers[1].is_quarantined(&quarantine), quarantined);
+        }
+    }
+}
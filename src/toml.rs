@@ -0,0 +1,559 @@
+//! Reader for the subset of TOML used by the config file: tables, arrays of
+//! tables, dotted keys, strings, integers, floats, booleans, arrays and inline
+//! tables. Documents are returned as `serde_json::Value` trees.
+
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Value, ParseError> {
+    parse_with_lines(input).map(|(value, _)| value)
+}
+
+/// Parse `input`, also returning the line defining each table and key, by
+/// dotted path such as `upstreams[1].url`. Keys inside inline tables are
+/// not listed; their table's line stands for them.
+pub fn parse_with_lines(input: &str) -> Result<(Value, HashMap<String, usize>), ParseError> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+        lines: HashMap::new(),
+    };
+    let value = parser.document()?;
+    Ok((value, parser.lines))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    lines: HashMap<String, usize>,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    /// Consume `c`, or fail with `message` without consuming a newline, so
+    /// the error names the line at fault.
+    fn expect(&mut self, c: char, message: &str) -> Result<(), ParseError> {
+        if self.peek() != Some(c) {
+            return self.error(message);
+        }
+        self.bump();
+        Ok(())
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines and comments (used inside arrays).
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_inline_whitespace();
+        self.skip_comment();
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some('\r') => {
+                self.bump();
+                self.end_of_line()
+            }
+            Some(c) => self.error(format!("unexpected character '{}'", c)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Value, ParseError> {
+        let mut root = Value::Object(Map::new());
+        let mut current: Vec<PathSegment> = Vec::new();
+
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    self.bump();
+                    let array = self.peek() == Some('[');
+                    if array {
+                        self.bump();
+                    }
+                    self.skip_inline_whitespace();
+                    let keys = self.key_path()?;
+                    self.skip_inline_whitespace();
+                    for _ in 0..(1 + array as usize) {
+                        self.expect(']', "expected ']' to close table header")?;
+                    }
+                    current = self.open_table(&mut root, &keys, array)?;
+                    self.lines.entry(dotted(&current, &[])).or_insert(self.line);
+                    self.end_of_line()?;
+                }
+                Some(_) => {
+                    let line = self.line;
+                    let keys = self.key_path()?;
+                    self.skip_inline_whitespace();
+                    self.expect('=', "expected '=' after key")?;
+                    self.skip_inline_whitespace();
+                    let value = self.value()?;
+                    // checked before the line ends, so errors name this one
+                    let table = resolve(&mut root, &current);
+                    self.insert(table, &keys, value)?;
+                    self.lines.insert(dotted(&current, &keys), line);
+                    self.end_of_line()?;
+                }
+            }
+        }
+        Ok(root)
+    }
+
+    fn open_table(
+        &self,
+        root: &mut Value,
+        keys: &[String],
+        array: bool,
+    ) -> Result<Vec<PathSegment>, ParseError> {
+        let mut path = Vec::new();
+        let mut node = root;
+        for (i, key) in keys.iter().enumerate() {
+            let last = i + 1 == keys.len();
+            let map = node.as_object_mut().expect("tables are objects");
+            if last && array {
+                let entry = map
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                let items = match entry.as_array_mut() {
+                    Some(items) => items,
+                    None => return self.error(format!("'{}' is not an array of tables", key)),
+                };
+                items.push(Value::Object(Map::new()));
+                path.push(PathSegment::Key(key.clone()));
+                path.push(PathSegment::Index(items.len() - 1));
+                return Ok(path);
+            }
+            let entry = map
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            path.push(PathSegment::Key(key.clone()));
+            node = match entry {
+                Value::Object(_) => entry,
+                // `[a.b]` after `[[a]]` refers to the latest element of `a`
+                Value::Array(items) if !last => {
+                    path.push(PathSegment::Index(items.len().saturating_sub(1)));
+                    match items.last_mut() {
+                        Some(item @ Value::Object(_)) => item,
+                        _ => return self.error(format!("'{}' is not a table", key)),
+                    }
+                }
+                _ => return self.error(format!("'{}' is already defined as a value", key)),
+            };
+        }
+        Ok(path)
+    }
+
+    fn insert(&self, table: &mut Value, keys: &[String], value: Value) -> Result<(), ParseError> {
+        let mut node = table;
+        for key in &keys[..keys.len() - 1] {
+            let map = node.as_object_mut().expect("tables are objects");
+            node = map
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !node.is_object() {
+                return self.error(format!("'{}' is already defined as a value", key));
+            }
+        }
+        let key = &keys[keys.len() - 1];
+        let map = node.as_object_mut().expect("tables are objects");
+        if map.contains_key(key) {
+            return self.error(format!("duplicate key '{}'", key));
+        }
+        map.insert(key.clone(), value);
+        Ok(())
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut keys = vec![self.key()?];
+        loop {
+            self.skip_inline_whitespace();
+            if self.peek() != Some('.') {
+                return Ok(keys);
+            }
+            self.bump();
+            self.skip_inline_whitespace();
+            keys.push(self.key()?);
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    return self.error("expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some('t') | Some('f') => self.boolean(),
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => self.number(),
+            Some(c) => self.error(format!("unexpected character '{}' in value", c)),
+            None => self.error("expected a value"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            // a newline ends the line at fault, it is not consumed
+            let Some(c) = self.peek().filter(|c| *c != '\n') else {
+                return self.error("unterminated string");
+            };
+            self.bump();
+            match c {
+                '"' => return Ok(out),
+                '\\' => match self.bump() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => out.push(c),
+                            None => return self.error("invalid unicode escape"),
+                        }
+                    }
+                    _ => return self.error("invalid escape sequence"),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            let Some(c) = self.peek().filter(|c| *c != '\n') else {
+                return self.error("unterminated string");
+            };
+            self.bump();
+            match c {
+                '\'' => return Ok(out),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn boolean(&mut self) -> Result<Value, ParseError> {
+        for (word, value) in [("true", true), ("false", false)] {
+            let end = self.pos + word.len();
+            if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(word.chars())
+            {
+                self.pos = end;
+                return Ok(Value::Bool(value));
+            }
+        }
+        self.error("expected a value")
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_') {
+                if c != '_' {
+                    text.push(c);
+                }
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if let Ok(int) = text.parse::<i64>() {
+            return Ok(Value::Number(int.into()));
+        }
+        match text.parse::<f64>().ok().and_then(Number::from_f64) {
+            Some(float) => Ok(Value::Number(float)),
+            None => self.error(format!("invalid number '{}'", text)),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, ParseError> {
+        self.bump();
+        let mut table = Value::Object(Map::new());
+        self.skip_inline_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(table);
+        }
+        loop {
+            self.skip_inline_whitespace();
+            let keys = self.key_path()?;
+            self.skip_inline_whitespace();
+            self.expect('=', "expected '=' after key")?;
+            self.skip_inline_whitespace();
+            let value = self.value()?;
+            self.insert(&mut table, &keys, value)?;
+            self.skip_inline_whitespace();
+            if self.peek() == Some('}') {
+                self.bump();
+                return Ok(table);
+            }
+            self.expect(',', "expected ',' or '}' in inline table")?;
+        }
+    }
+}
+
+#[derive(Clone)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// `a.b[0].c` form of a table path followed by keys.
+fn dotted(path: &[PathSegment], keys: &[String]) -> String {
+    let mut out = String::new();
+    let keys = keys.iter().map(|key| PathSegment::Key(key.clone()));
+    for segment in path.iter().cloned().chain(keys) {
+        match segment {
+            PathSegment::Key(key) if out.is_empty() => out.push_str(&key),
+            PathSegment::Key(key) => {
+                out.push('.');
+                out.push_str(&key);
+            }
+            PathSegment::Index(index) => out.push_str(&format!("[{}]", index)),
+        }
+    }
+    out
+}
+
+fn resolve<'a>(root: &'a mut Value, path: &[PathSegment]) -> &'a mut Value {
+    path.iter().fold(root, |node, segment| match segment {
+        PathSegment::Key(key) => &mut node[key.as_str()],
+        PathSegment::Index(index) => &mut node[*index],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn error(input: &str) -> String {
+        parse(input).expect_err("the input is invalid").to_string()
+    }
+
+    #[test]
+    fn tables_and_arrays_of_tables_nest() {
+        let value = parse(
+            "port = 8080\n\n[slots]\nmax_age_ms = 500\n\n[[upstreams]]\nurl = \"a\"\n\n[upstreams.rate_limit]\nrps = 5\n\n[[upstreams]]\nurl = \"b\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "port": 8080,
+                "slots": { "max_age_ms": 500 },
+                "upstreams": [
+                    { "url": "a", "rate_limit": { "rps": 5 } },
+                    { "url": "b" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn dotted_keys_and_inline_tables_make_tables() {
+        let value = parse(
+            "[routing]\ngetBlock.hedge = false\n\"getSlot\" = { strategy = \"race\", fanout = 2 }\nempty = {}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            value["routing"],
+            json!({
+                "getBlock": { "hedge": false },
+                "getSlot": { "strategy": "race", "fanout": 2 },
+                "empty": {},
+            })
+        );
+    }
+
+    #[test]
+    fn values_of_every_supported_type_are_read() {
+        let value = parse(concat!(
+            "basic = \"tab\\there \\\"quoted\\\" \\\\ \\u00e9\"\n",
+            "literal = 'C:\\path'\n",
+            "negative = -5\n",
+            "grouped = 1_000_000\n",
+            "float = 2.5\n",
+            "exponent = 1e3\n",
+            "yes = true\n",
+            "no = false\n",
+            "list = [\n  1, # one\n  2,\n]\n",
+            "nested = [[1], [\"a\", 'b']]\n",
+        ))
+        .unwrap();
+        assert_eq!(value["basic"], "tab\there \"quoted\" \\ \u{e9}");
+        assert_eq!(value["literal"], "C:\\path");
+        assert_eq!(value["negative"], -5);
+        assert_eq!(value["grouped"], 1_000_000);
+        assert_eq!(value["float"], 2.5);
+        assert_eq!(value["exponent"], 1000.0);
+        assert_eq!(value["yes"], true);
+        assert_eq!(value["no"], false);
+        assert_eq!(value["list"], json!([1, 2]));
+        assert_eq!(value["nested"], json!([[1], ["a", "b"]]));
+    }
+
+    #[test]
+    fn comments_blank_lines_and_crlf_are_skipped() {
+        let value =
+            parse("# settings\r\n\r\nport = 8080 # the port\r\n\n[slots] # polling\n").unwrap();
+        assert_eq!(value, json!({ "port": 8080, "slots": {} }));
+    }
+
+    #[test]
+    fn every_key_and_table_has_its_line() {
+        let (_, lines) = parse_with_lines(
+            "port = 8080\n\n[[upstreams]]\nurl = \"a\"\n\n[[upstreams]]\nurl = \"b\"\nrate_limit.rps = 5\n\n[upstreams.maintenance]\nweekday = \"daily\"\nwindow = { start = \"01:00\" }\n",
+        )
+        .unwrap();
+        for (path, line) in [
+            ("port", 1),
+            ("upstreams[0]", 3),
+            ("upstreams[0].url", 4),
+            ("upstreams[1]", 6),
+            ("upstreams[1].url", 7),
+            ("upstreams[1].rate_limit.rps", 8),
+            ("upstreams[1].maintenance", 10),
+            ("upstreams[1].maintenance.weekday", 11),
+            ("upstreams[1].maintenance.window", 12),
+        ] {
+            assert_eq!(lines.get(path), Some(&line), "{}", path);
+        }
+        // keys of inline tables go by their table's line
+        assert_eq!(lines.get("upstreams[1].maintenance.window.start"), None);
+    }
+
+    #[test]
+    fn errors_name_their_line() {
+        for (input, message) in [
+            ("a = 1\nb 2\n", "line 2: expected '=' after key"),
+            ("a = 1\na = 2\n", "line 2: duplicate key 'a'"),
+            ("a = 1\n[a]\n", "line 2: 'a' is already defined as a value"),
+            ("[a]\n[[a]]\n", "line 2: 'a' is not an array of tables"),
+            ("[a\n", "line 1: expected ']' to close table header"),
+            ("a = 1 2\n", "line 1: unexpected character '2'"),
+            ("\na = \"open\n", "line 2: unterminated string"),
+            ("a = 'open\n", "line 1: unterminated string"),
+            ("a = \"\\q\"\n", "line 1: invalid escape sequence"),
+            ("a = \"\\uzzzz\"\n", "line 1: invalid unicode escape"),
+            ("a = 0x10\n", "line 1: invalid number '0x10'"),
+            ("a = yes\n", "line 1: unexpected character 'y' in value"),
+            ("a = [1\n\n", "line 3: expected ',' or ']' in array"),
+            (
+                "a = { b = 1\n",
+                "line 1: expected ',' or '}' in inline table",
+            ),
+            ("a =\n", "line 1: unexpected character '\n' in value"),
+            ("a = ", "line 1: expected a value"),
+            ("= 1\n", "line 1: expected a key"),
+        ] {
+            assert_eq!(error(input), message, "{:?}", input);
+        }
+    }
+}
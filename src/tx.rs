@@ -0,0 +1,412 @@
+//! `GET /tx/{signature}`: whether a transaction landed, as seen across the
+//! healthy upstreams rather than by a single node. Transactions submitted
+//! through the proxy are followed for a while after their broadcast, so
+//! the endpoint can usually answer from memory.
+
+use crate::base58;
+use crate::config::TxTrackingConfig;
+use crate::dedup::Signature;
+use crate::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use futures::future::join_all;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Signatures `getSignatureStatuses` accepts in one call.
+const MAX_SIGNATURES_PER_CALL: usize = 256;
+
+const LEVELS: [&str; 3] = ["processed", "confirmed", "finalized"];
+
+/// The most advanced status any upstream reported for a transaction.
+#[derive(Clone)]
+struct Status {
+    /// Index into `LEVELS`.
+    level: usize,
+    slot: u64,
+    err: Value,
+    /// Upstreams that know the transaction.
+    seen_by: Vec<String>,
+}
+
+impl Status {
+    /// Fold in the status `upstream` reported, `null` when it does not
+    /// know the transaction.
+    fn merge(status: &mut Option<Status>, upstream: &str, reported: &Value) {
+        if !reported.is_object() {
+            return;
+        }
+        // nodes without confirmationStatus report null confirmations once
+        // the transaction is rooted
+        let level = match reported["confirmationStatus"].as_str() {
+            Some(name) => LEVELS.iter().position(|level| *level == name).unwrap_or(0),
+            None if reported["confirmations"].is_null() => 2,
+            None => 0,
+        };
+        let slot = reported["slot"].as_u64().unwrap_or_default();
+        let status = status.get_or_insert_with(|| Status {
+            level,
+            slot,
+            err: reported["err"].clone(),
+            seen_by: Vec::new(),
+        });
+        if (level, slot) > (status.level, status.slot) {
+            status.level = level;
+            status.slot = slot;
+            status.err = reported["err"].clone();
+        }
+        status.seen_by.push(upstream.to_string());
+    }
+
+    fn finalized(&self) -> bool {
+        self.level == LEVELS.len() - 1
+    }
+}
+
+struct Tracked {
+    submitted: Instant,
+    status: Option<Status>,
+    /// When the statuses were last fetched.
+    checked: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct TxTracker {
+    tracked: Mutex<HashMap<Signature, Tracked>>,
+}
+
+impl TxTracker {
+    /// Follow `signature`, just broadcast, unless the tracker is full.
+    pub fn track(&self, settings: &TxTrackingConfig, signature: Signature) {
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.len() < settings.max_tracked {
+            tracked.entry(signature).or_insert(Tracked {
+                submitted: Instant::now(),
+                status: None,
+                checked: None,
+            });
+        }
+    }
+
+    /// Whether `signature` was submitted through the proxy recently.
+    pub fn is_tracked(&self, signature: &Signature) -> bool {
+        self.tracked.lock().unwrap().contains_key(signature)
+    }
+
+    pub fn count(&self) -> usize {
+        self.tracked.lock().unwrap().len()
+    }
+
+    /// The remembered status of `signature`, when it is tracked and the
+    /// memory is current: finalized, or checked within `fresh`.
+    fn remembered(&self, signature: &Signature, fresh: Duration) -> Option<Option<Status>> {
+        let tracked = self.tracked.lock().unwrap();
+        let entry = tracked.get(signature)?;
+        let current = entry.status.as_ref().is_some_and(Status::finalized)
+            || entry
+                .checked
+                .is_some_and(|checked| checked.elapsed() < fresh);
+        current.then(|| entry.status.clone())
+    }
+
+    fn update(&self, signature: &Signature, status: Option<Status>) {
+        if let Some(entry) = self.tracked.lock().unwrap().get_mut(signature) {
+            entry.status = status;
+            entry.checked = Some(Instant::now());
+        }
+    }
+}
+
+/// Ask every upstream that is not quarantined for the statuses of
+/// `signatures`, and merge their answers. Fails when no upstream answers.
+async fn fetch(
+    config: &ServerConfig,
+    signatures: &[Signature],
+    search_history: bool,
+) -> Result<(Vec<Option<Status>>, usize), String> {
+    let healthy: Vec<usize> = {
+        let quarantine = config.quarantine.read().await;
+        config
+            .servers
+            .iter()
+            .filter(|upstream| !upstream.is_last_resort() && !upstream.is_quarantined(&quarantine))
+            .map(|upstream| upstream.index)
+            .collect()
+    };
+    let encoded: Vec<String> = signatures.iter().map(|s| base58::encode(s)).collect();
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "getSignatureStatuses",
+        "params": [encoded, { "searchTransactionHistory": search_history }],
+    });
+    let sent = healthy
+        .iter()
+        .filter(|&&index| config.servers[index].take_token());
+    let answers = join_all(sent.map(|&index| {
+        crate::costs::charge(config, index, ["getSignatureStatuses"]);
+        let upstream = &config.servers[index];
+        let request = upstream.post().json(&body).send();
+        async move {
+            let response = request.await.and_then(|r| r.error_for_status());
+            let answer: Value = response.ok()?.json().await.ok()?;
+            let statuses = answer["result"]["value"].as_array()?.clone();
+            Some((upstream.name.as_str(), statuses))
+        }
+    }))
+    .await;
+    let mut merged = vec![None; signatures.len()];
+    let mut answered = 0;
+    for (upstream, statuses) in answers.into_iter().flatten() {
+        answered += 1;
+        for (status, reported) in merged.iter_mut().zip(&statuses) {
+            Status::merge(status, upstream, reported);
+        }
+    }
+    if answered == 0 {
+        return Err(format!(
+            "none of {} upstreams answered getSignatureStatuses",
+            healthy.len()
+        ));
+    }
+    Ok((merged, answered))
+}
+
+/// Fetch the statuses of the tracked transactions every `poll_interval`,
+/// until they are finalized. They are remembered for `track_for`.
+pub async fn follow(config: Arc<ServerConfig>, settings: TxTrackingConfig) {
+    let mut ticker = tokio::time::interval(settings.poll_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let pending: Vec<Signature> = {
+            let mut tracked = config.transactions.tracked.lock().unwrap();
+            tracked.retain(|_, entry| entry.submitted.elapsed() < settings.track_for);
+            tracked
+                .iter()
+                .filter(|(_, entry)| !entry.status.as_ref().is_some_and(Status::finalized))
+                .map(|(signature, _)| *signature)
+                .collect()
+        };
+        for chunk in pending.chunks(MAX_SIGNATURES_PER_CALL) {
+            // a failed poll is retried at the next tick
+            if let Ok((statuses, _)) = fetch(&config, chunk, false).await {
+                for (signature, status) in chunk.iter().zip(statuses) {
+                    config.transactions.update(signature, status);
+                }
+            }
+        }
+    }
+}
+
+/// The answer about `signature`; `answered` counts the upstreams that
+/// answered, `None` when it comes from the tracker's memory.
+fn describe(signature: &str, status: Option<Status>, answered: Option<usize>) -> Response<Body> {
+    let mut body = json!({
+        "signature": signature,
+        "found": status.is_some(),
+        "source": if answered.is_some() { "upstreams" } else { "tracked" },
+    });
+    if let Some(answered) = answered {
+        body["upstreams_answered"] = answered.into();
+    }
+    let Some(status) = status else {
+        return (StatusCode::NOT_FOUND, Json(body)).into_response();
+    };
+    body["status"] = LEVELS[status.level].into();
+    body["slot"] = status.slot.into();
+    body["err"] = status.err;
+    body["seen_by"] = status.seen_by.into();
+    Json(body).into_response()
+}
+
+/// `GET /tx/{signature}?search_history=true`: the most advanced status
+/// of the transaction any healthy upstream reports, and which upstreams
+/// know it. History search finds transactions older than the nodes'
+/// recent status cache, at the upstreams' expense.
+pub async fn tx_handler(
+    State(config): State<Arc<ServerConfig>>,
+    Path(encoded): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response<Body> {
+    let Some(signature) = base58::decode(&encoded).and_then(|bytes| bytes.try_into().ok()) else {
+        return crate::rpc::error_response(
+            StatusCode::BAD_REQUEST,
+            crate::rpc::INVALID_REQUEST,
+            "not a base58 transaction signature",
+            None,
+        );
+    };
+    let search_history = params.get("search_history").is_some_and(|v| v == "true");
+    if let Some(settings) = config
+        .settings
+        .tx_tracking
+        .as_ref()
+        .filter(|_| !search_history)
+    {
+        if let Some(status) = config
+            .transactions
+            .remembered(&signature, settings.poll_interval * 2)
+        {
+            return describe(&encoded, status, None);
+        }
+    }
+    match fetch(&config, &[signature], search_history).await {
+        Ok((mut statuses, asked)) => {
+            let status = statuses.pop().flatten();
+            config.transactions.update(&signature, status.clone());
+            describe(&encoded, status, Some(asked))
+        }
+        Err(message) => crate::rpc::error_response(
+            StatusCode::BAD_GATEWAY,
+            crate::rpc::UPSTREAM_ERROR,
+            &message,
+            None,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{proxy, serve};
+    use axum::{routing::post, Router};
+
+    fn settings(max_tracked: usize) -> TxTrackingConfig {
+        TxTrackingConfig {
+            track_for: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(2),
+            max_tracked,
+        }
+    }
+
+    fn merged(reports: &[(&str, Value)]) -> Option<Status> {
+        let mut status = None;
+        for (upstream, reported) in reports {
+            Status::merge(&mut status, upstream, reported);
+        }
+        status
+    }
+
+    #[test]
+    fn the_most_advanced_status_wins() {
+        let status = merged(&[
+            (
+                "a",
+                json!({ "slot": 10, "confirmationStatus": "processed", "err": null }),
+            ),
+            ("b", Value::Null),
+            (
+                "c",
+                json!({ "slot": 12, "confirmationStatus": "confirmed", "err": null }),
+            ),
+            (
+                "d",
+                json!({ "slot": 11, "confirmationStatus": "processed", "err": null }),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(LEVELS[status.level], "confirmed");
+        assert_eq!(status.slot, 12);
+        assert_eq!(status.seen_by, ["a", "c", "d"]);
+        assert!(merged(&[("a", Value::Null)]).is_none());
+    }
+
+    #[test]
+    fn null_confirmations_without_a_status_mean_rooted() {
+        let rooted = merged(&[("a", json!({ "slot": 5, "confirmations": null }))]).unwrap();
+        assert!(rooted.finalized());
+        let counted = merged(&[("a", json!({ "slot": 5, "confirmations": 3 }))]).unwrap();
+        assert_eq!(LEVELS[counted.level], "processed");
+    }
+
+    #[test]
+    fn statuses_are_remembered_while_current() {
+        let tracker = TxTracker::default();
+        let (first, second) = ([1; 64], [2; 64]);
+        tracker.track(&settings(1), first);
+        tracker.track(&settings(1), second);
+        assert_eq!(tracker.count(), 1);
+        // never checked, or checked too long ago
+        assert!(tracker.remembered(&first, Duration::from_secs(1)).is_none());
+        tracker.update(&first, None);
+        assert!(tracker.remembered(&first, Duration::ZERO).is_none());
+        assert!(matches!(
+            tracker.remembered(&first, Duration::from_secs(1)),
+            Some(None)
+        ));
+        // finalized statuses do not change any more
+        let finalized = merged(&[("a", json!({ "slot": 5, "confirmationStatus": "finalized" }))]);
+        tracker.update(&first, finalized);
+        assert!(tracker
+            .remembered(&first, Duration::ZERO)
+            .unwrap()
+            .is_some());
+        tracker.update(&second, None);
+        assert!(tracker
+            .remembered(&second, Duration::from_secs(1))
+            .is_none());
+    }
+
+    /// An upstream reporting `reported` for every signature it is asked.
+    async fn upstream(reported: Value) -> String {
+        serve(Router::new().route(
+            "/",
+            post(move |Json(call): Json<Value>| async move {
+                let asked = call["params"][0].as_array().unwrap().len();
+                let value = vec![reported; asked];
+                Json(json!({ "jsonrpc": "2.0", "id": call["id"], "result": { "value": value } }))
+            }),
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn the_endpoint_asks_every_upstream() {
+        let mut upstreams = String::new();
+        for (name, reported) in [
+            (
+                "a",
+                json!({ "slot": 7, "confirmationStatus": "confirmed", "err": null }),
+            ),
+            ("b", Value::Null),
+        ] {
+            let url = upstream(reported).await;
+            upstreams += &format!("[[upstreams]]\nname = \"{}\"\nurl = \"{}\"\n", name, url);
+        }
+        let (_, url) = proxy(&upstreams).await;
+        let signature = base58::encode(&[7; 64]);
+        let response = reqwest::get(format!("{}/tx/{}", url, signature))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let answer: Value = response.json().await.unwrap();
+        assert_eq!(answer["status"], "confirmed");
+        assert_eq!(answer["slot"], 7);
+        assert_eq!(answer["seen_by"], json!(["a"]));
+        assert_eq!(answer["source"], "upstreams");
+        assert_eq!(answer["upstreams_answered"], 2);
+        let invalid = reqwest::get(format!("{}/tx/0OIl", url)).await.unwrap();
+        assert_eq!(invalid.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn unknown_transactions_are_not_found() {
+        let url = upstream(Value::Null).await;
+        let (_, url) = proxy(&format!("[[upstreams]]\nurl = \"{}\"\n", url)).await;
+        let signature = base58::encode(&[7; 64]);
+        let response = reqwest::get(format!("{}/tx/{}", url, signature))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        let answer: Value = response.json().await.unwrap();
+        assert_eq!(answer["found"], false);
+    }
+}
@@ -0,0 +1,337 @@
+//! Methods an upstream answered it does not serve, such as the transaction
+//! history or DAS calls of a plain node. Racing such a host wastes its quota
+//! and its fast error may beat the real answers, so once it says so the
+//! method is kept from it for `[unsupported_methods] ttl_secs`, as long as
+//! another upstream is left to ask. An upgrade may add the method, so the
+//! host's methods are forgotten when its `getVersion` changes.
+
+use crate::ServerConfig;
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Error codes meaning the node lacks the method, whatever the params:
+/// method not found, and transaction history disabled.
+const UNSUPPORTED_CODES: [i64; 2] = [-32601, -32011];
+
+#[derive(Default)]
+pub struct UnsupportedMethods {
+    /// Until when each method is kept from the upstream.
+    methods: Mutex<HashMap<String, Instant>>,
+    /// Its last `getVersion` result.
+    version: Mutex<Option<Value>>,
+    /// Requests not sent to it for a method it lacks.
+    skipped: AtomicU64,
+}
+
+impl UnsupportedMethods {
+    /// Whether any of `methods` is known to be unsupported.
+    pub fn lacks(&self, methods: &[String]) -> bool {
+        let now = Instant::now();
+        let known = self.methods.lock().unwrap();
+        methods
+            .iter()
+            .any(|method| known.get(method).is_some_and(|&until| until > now))
+    }
+
+    /// Keep `method` from the upstream for `ttl`. Returns whether it was
+    /// not known already.
+    fn record(&self, method: &str, ttl: Duration) -> bool {
+        let now = Instant::now();
+        let mut known = self.methods.lock().unwrap();
+        known.retain(|_, until| *until > now);
+        known.insert(method.to_string(), now + ttl).is_none()
+    }
+
+    /// Keep `methods` from the upstream for `hold` when discovery found it
+    /// `lacking` them, or send them again when it found them served.
+    pub fn discovered(&self, methods: &[&str], lacking: bool, hold: Duration) {
+        let until = Instant::now() + hold;
+        let mut known = self.methods.lock().unwrap();
+        for &method in methods {
+            match lacking {
+                true => known.insert(method.to_string(), until),
+                false => known.remove(method),
+            };
+        }
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Methods currently kept from the upstream.
+    pub fn count(&self) -> usize {
+        let now = Instant::now();
+        let known = self.methods.lock().unwrap();
+        known.values().filter(|&&until| until > now).count()
+    }
+
+    fn report(&self) -> Value {
+        let now = Instant::now();
+        let known = self.methods.lock().unwrap();
+        let mut methods: Vec<(&String, Duration)> = known
+            .iter()
+            .filter(|(_, &until)| until > now)
+            .map(|(method, &until)| (method, until - now))
+            .collect();
+        methods.sort_unstable();
+        let methods: Vec<Value> = methods
+            .into_iter()
+            .map(|(method, left)| json!({ "method": method, "expires_in_secs": left.as_secs() }))
+            .collect();
+        json!({
+            "methods": methods,
+            "version": *self.version.lock().unwrap(),
+            "skipped": self.skipped(),
+        })
+    }
+}
+
+/// Whether an answer says the node does not serve the method at all.
+pub fn is_unsupported(answer: &Value) -> bool {
+    let error = &answer["error"];
+    let code = error["code"].as_i64();
+    if code.is_some_and(|code| UNSUPPORTED_CODES.contains(&code)) {
+        return true;
+    }
+    // not just "not supported": unsupported transaction versions depend
+    // on the params
+    let message = error["message"].as_str().unwrap_or_default();
+    let message = message.to_lowercase();
+    message.contains("method")
+        && (message.contains("not supported") || message.contains("unsupported"))
+}
+
+/// Record that upstream `index` answered `method` as unsupported.
+pub fn observe(config: &ServerConfig, index: usize, method: &str) {
+    let Some(settings) = &config.settings.unsupported_methods else {
+        return;
+    };
+    let upstream = &config.servers[index];
+    if upstream.unsupported.record(method, settings.ttl) {
+        let message = format!(
+            "{} does not support {}, no longer sending it there",
+            upstream.name, method
+        );
+        println!("+ {}", message);
+        config.events.record(message);
+    }
+}
+
+/// `targets` without the upstreams known to lack one of `methods`, from
+/// their answers or from `discovery`, unless that leaves none, when they
+/// are all asked and answer for themselves.
+pub fn skip(
+    config: &ServerConfig,
+    targets: Vec<usize>,
+    methods: &[String],
+    request_id: &str,
+) -> Vec<usize> {
+    let (lacking, able): (Vec<usize>, Vec<usize>) = targets
+        .iter()
+        .partition(|&&index| config.servers[index].unsupported.lacks(methods));
+    if lacking.is_empty() || able.is_empty() {
+        return targets;
+    }
+    let names: Vec<&str> = lacking
+        .iter()
+        .map(|&index| config.servers[index].name.as_str())
+        .collect();
+    println!(
+        "[{}] + Not sending to {:?}, the method is unsupported there",
+        request_id, names
+    );
+    for index in lacking {
+        config.servers[index]
+            .unsupported
+            .skipped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    able
+}
+
+/// Ask every upstream `getVersion` every `interval`, forgetting the
+/// unsupported methods of those whose version changed.
+pub async fn check_versions(config: Arc<ServerConfig>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let body = r#"{"jsonrpc":"2.0","id":1,"method":"getVersion"}"#;
+    loop {
+        ticker.tick().await;
+        // last-resort upstreams are not asked more than they must, dead ones
+        // only by their circuit's probes, and another cluster's not at all
+        let upstreams = config
+            .servers
+            .iter()
+            .filter(|u| !u.is_last_resort() && u.circuit.dead().is_none())
+            .filter(|u| !u.cluster.is_wrong())
+            .filter(|u| u.take_token());
+        let versions = futures::future::join_all(upstreams.map(|upstream| {
+            crate::costs::charge(&config, upstream.index, ["getVersion"]);
+            let request = upstream
+                .post()
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send();
+            async move {
+                let response = request.await.and_then(|r| r.error_for_status());
+                let answer: Value = response.ok()?.json().await.ok()?;
+                Some((upstream.index, answer.get("result")?.clone()))
+            }
+        }))
+        .await;
+        for (index, version) in versions.into_iter().flatten() {
+            let upstream = &config.servers[index];
+            let state = &upstream.unsupported;
+            let previous = state.version.lock().unwrap().replace(version.clone());
+            if previous.is_none_or(|previous| previous == version) {
+                continue;
+            }
+            let forgotten = std::mem::take(&mut *state.methods.lock().unwrap()).len();
+            if forgotten == 0 {
+                continue;
+            }
+            let message = format!(
+                "{} now runs {}, forgetting {} unsupported methods",
+                upstream.name,
+                version["solana-core"].as_str().unwrap_or("another version"),
+                forgotten
+            );
+            println!("+ {}", message);
+            config.events.record(message);
+        }
+    }
+}
+
+/// `GET /admin/unsupported`: the methods kept from each upstream, with the
+/// version they were learned at and the requests not sent for them.
+pub async fn list_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    let upstreams: serde_json::Map<String, Value> = config
+        .servers
+        .iter()
+        .map(|upstream| (upstream.name.clone(), upstream.unsupported.report()))
+        .collect();
+    Json(json!({ "upstreams": upstreams }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+
+    /// A node serving `getVersion` alone, as version `version`, and the
+    /// number of requests it was sent.
+    async fn plain(version: Arc<AtomicU64>) -> (String, Arc<AtomicU64>) {
+        let hits = Arc::new(AtomicU64::new(0));
+        let url = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let hits = hits.clone();
+                move |Json(request): Json<Value>| async move {
+                    if request["method"] == "getVersion" {
+                        let version = format!("1.18.{}", version.load(Ordering::Relaxed));
+                        return Json(json!({
+                            "jsonrpc": "2.0", "id": 1, "result": { "solana-core": version },
+                        }));
+                    }
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    Json(json!({
+                        "jsonrpc": "2.0", "id": request["id"],
+                        "error": { "code": -32601, "message": "Method not found" },
+                    }))
+                }
+            }),
+        ))
+        .await;
+        (url, hits)
+    }
+
+    /// Wait up to two seconds for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        done()
+    }
+
+    #[test]
+    fn unsupported_answers_are_told_apart() {
+        let error =
+            |code: i64, message: &str| json!({ "error": { "code": code, "message": message } });
+        assert!(is_unsupported(&error(-32601, "Method not found")));
+        assert!(is_unsupported(&error(
+            -32011,
+            "Transaction history is not available"
+        )));
+        assert!(is_unsupported(&error(
+            -32000,
+            "Method getAsset not supported"
+        )));
+        assert!(!is_unsupported(&error(
+            -32015,
+            "Transaction version (0) is not supported"
+        )));
+        assert!(!is_unsupported(&json!({ "result": 1 })));
+    }
+
+    #[tokio::test]
+    async fn methods_a_node_lacks_are_kept_from_it_until_it_upgrades() {
+        let version = Arc::new(AtomicU64::new(0));
+        let (plain, plain_hits) = plain(version.clone()).await;
+        let full = crate::testing::upstream(crate::mock::Behavior {
+            latency: Duration::from_millis(50),
+            ..crate::mock::Behavior::default()
+        })
+        .await;
+        let (config, url) = crate::testing::proxy(&format!(
+            "[[upstreams]]\nname = \"plain\"\nurl = \"{}\"\n[[upstreams]]\nname = \"full\"\nurl = \"{}\"\n",
+            plain, full
+        ))
+        .await;
+        let call = || {
+            crate::testing::call(
+                &url,
+                json!({
+                    "jsonrpc": "2.0", "id": 1, "method": "getSignaturesForAddress",
+                    "params": ["11111111111111111111111111111111"],
+                }),
+            )
+        };
+        call().await;
+        assert_eq!(plain_hits.load(Ordering::Relaxed), 1);
+        // from then on only the node serving it is asked
+        let body: Value = call().await.json().await.unwrap();
+        assert!(body.get("result").is_some(), "{}", body);
+        assert_eq!(plain_hits.load(Ordering::Relaxed), 1);
+        let unsupported = &config.servers[0].unsupported;
+        assert_eq!(unsupported.skipped(), 1);
+        assert_eq!(config.servers[1].unsupported.count(), 0);
+        let Json(listed) = list_handler(State(config.clone())).await;
+        let plain_listed = &listed["upstreams"]["plain"];
+        assert_eq!(
+            plain_listed["methods"][0]["method"],
+            "getSignaturesForAddress"
+        );
+        assert_eq!(plain_listed["skipped"], 1);
+
+        tokio::spawn(check_versions(config.clone(), Duration::from_millis(20)));
+        assert!(eventually(|| unsupported.version.lock().unwrap().is_some()).await);
+        assert_eq!(unsupported.count(), 1);
+        version.store(1, Ordering::Relaxed);
+        assert!(eventually(|| unsupported.count() == 0).await);
+        let events = config.events.recent();
+        assert_eq!(
+            events.last().unwrap().1,
+            "plain now runs 1.18.1, forgetting 1 unsupported methods"
+        );
+        call().await;
+        assert_eq!(plain_hits.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::blockhash::BlockhashHealth;
+use crate::classify::ErrorClass;
+use crate::cluster::Membership;
+use crate::config::UpstreamConfig;
+use crate::credentials::Credentials;
+use crate::discovery::Discovered;
+use crate::identity::Identity;
+use crate::maintenance::Maintenance;
+use crate::provider_limits::ProviderLimits;
+use crate::quarantine::Circuit;
+use crate::ratelimit::TokenBucket;
+use crate::unsupported::UnsupportedMethods;
+use crate::versions::NodeVersion;
+use reqwest::Client;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Consecutive host failures after which responses from a host are ignored.
+pub const FAILURE_STREAK_THRESHOLD: u64 = 3;
+
+/// Longest window of the error rate, in seconds; the ring holds a bucket
+/// per second.
+pub const MAX_WINDOW_SECS: u64 = 300;
+
+/// Weight of the newest answer in the average latency.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+pub struct Upstream {
+    /// Position in `ServerConfig::servers`, the key of every per-upstream
+    /// table including the quarantine.
+    pub index: usize,
+    /// Canonical, see `config::upstreams::canonical_url`.
+    pub url: String,
+    pub name: String,
+    /// Keeps the full ledger history, see `history`.
+    pub archive: bool,
+    /// Mask of its capabilities, see `config::CapabilityTable`.
+    pub capabilities: u64,
+    /// Where it runs, see `config::RegionConfig`.
+    pub region: Option<String>,
+    /// The rate its provider's plan allows. Every request sent to it takes
+    /// a token; without one it is left out, see `Upstream::take_token`.
+    pub outbound: Option<TokenBucket>,
+    /// Requests not sent to it for want of a token.
+    pub outbound_skipped: AtomicU64,
+    /// The rate a last-resort upstream may be asked at; regular ones have
+    /// none. See `Dispatch::last_resort`.
+    pub last_resort: Option<TokenBucket>,
+    /// Replaced by a fresh pool when the host resolves elsewhere, see
+    /// `dns`, or its settings change; requests in flight keep the clone
+    /// they started with.
+    pub client: RwLock<Client>,
+    /// What `client` was built from.
+    pub client_settings: RwLock<ClientSettings>,
+    /// The host's addresses at the last resolution, sorted.
+    pub addresses: Mutex<Vec<IpAddr>>,
+    pub stats: UpstreamStats,
+    pub limits: ProviderLimits,
+    pub blockhash: BlockhashHealth,
+    pub circuit: Circuit,
+    /// Set by an admin ahead of maintenance, see `drain`.
+    pub drained: AtomicBool,
+    /// Where it is in its scheduled maintenance windows.
+    pub maintenance: Maintenance,
+    /// Methods it answered it does not serve.
+    pub unsupported: UnsupportedMethods,
+    /// What probing it found it serves, see `discovery`.
+    pub discovery: Discovered,
+    /// The node it reports being, see `identity`.
+    pub identity: Identity,
+    /// The cluster it reports serving, see `cluster`.
+    pub cluster: Membership,
+    /// The release it reports running, see `versions`.
+    pub version: NodeVersion,
+    /// Sent with every request, see `credentials`.
+    pub credentials: Credentials,
+    /// Traffic paused by an admin, see `pause`.
+    pub paused: tokio::sync::watch::Receiver<bool>,
+}
+
+/// The settings of an upstream its client is built from. Those of a
+/// running upstream can change on a config reload, see `reload`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ClientSettings {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+}
+
+impl ClientSettings {
+    pub fn of(settings: &UpstreamConfig) -> Self {
+        Self {
+            connect_timeout: settings.connect_timeout,
+            request_timeout: settings.request_timeout,
+        }
+    }
+
+    /// How `new` differs from these settings, for the log.
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.connect_timeout != new.connect_timeout {
+            changes.push(format!(
+                "connect timeout {:?} -> {:?}",
+                self.connect_timeout, new.connect_timeout
+            ));
+        }
+        if self.request_timeout != new.request_timeout {
+            changes.push(format!(
+                "request timeout {:?} -> {:?}",
+                self.request_timeout, new.request_timeout
+            ));
+        }
+        changes
+    }
+}
+
+/// A persistent client with its connection pool, one per upstream.
+pub fn new_client(settings: &ClientSettings) -> Client {
+    Client::builder()
+        .connect_timeout(settings.connect_timeout)
+        .timeout(settings.request_timeout)
+        .pool_max_idle_per_host(10) // Keep up to 10 idle connections per host
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .expect("Failed to create HTTP client")
+}
+
+impl Upstream {
+    pub fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    /// A request to the upstream, with its credentials.
+    pub fn post(&self) -> reqwest::RequestBuilder {
+        self.credentials.apply(self.client().post(&self.url))
+    }
+
+    pub fn client_settings(&self) -> ClientSettings {
+        *self.client_settings.read().unwrap()
+    }
+
+    /// Build a fresh client from `settings`, for the requests to come.
+    pub fn rebuild_client(&self, settings: ClientSettings) {
+        let mut client = self.client.write().unwrap();
+        *client = new_client(&settings);
+        *self.client_settings.write().unwrap() = settings;
+    }
+
+    /// Keeps the full ledger history, declared with `archive = true` or
+    /// discovered.
+    pub fn is_archive(&self) -> bool {
+        self.archive || self.discovery.is_archive()
+    }
+
+    /// Mask of its capabilities, declared or discovered.
+    pub fn capability_mask(&self) -> u64 {
+        self.capabilities | self.discovery.capabilities()
+    }
+
+    /// Only used while no regular upstream is available, and left out of
+    /// the quarantine and the health counts.
+    pub fn is_last_resort(&self) -> bool {
+        self.last_resort.is_some()
+    }
+
+    /// Whether a request could be sent to it now without going over its
+    /// plan's rate. Takes no token.
+    pub fn has_room(&self) -> bool {
+        self.outbound
+            .as_ref()
+            .is_none_or(|bucket| bucket.available() > 0)
+    }
+
+    /// Take a token for a request about to be sent. Without one the request
+    /// must not go, and is counted as skipped. None is given while traffic
+    /// is paused.
+    pub fn take_token(&self) -> bool {
+        if *self.paused.borrow() {
+            return false;
+        }
+        let taken = self
+            .outbound
+            .as_ref()
+            .is_none_or(|bucket| bucket.try_acquire().is_ok());
+        if !taken {
+            self.outbound_skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Gets no new requests, those in flight still being answered: drained
+    /// by an admin or for a maintenance window.
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::Relaxed) || self.maintenance.excludes()
+    }
+
+    /// Quarantined for lagging behind, for a run of failures, for serving
+    /// stale blockhashes, for running below the minimum version or for
+    /// good, for serving another cluster.
+    pub fn is_quarantined(&self, quarantine: &[usize]) -> bool {
+        self.quarantine_reason(quarantine).is_some()
+    }
+
+    /// Failing hosts until their circuit breaker closes again, see
+    /// `quarantine::Circuit`.
+    pub fn is_failing(&self) -> bool {
+        self.stats.is_failing() || !self.circuit.is_closed()
+    }
+
+    pub fn quarantine_reason(&self, quarantine: &[usize]) -> Option<&'static str> {
+        if self.cluster.is_wrong() {
+            Some("wrong cluster")
+        } else if self.is_last_resort() {
+            None
+        } else if quarantine.contains(&self.index) {
+            Some("slot lag")
+        } else if self.is_failing() {
+            Some("failures")
+        } else if self.blockhash.quarantines() {
+            Some("stale blockhash")
+        } else if self.version.quarantines() {
+            Some("old version")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct UpstreamStats {
+    successes: AtomicU64,
+    errors: [AtomicU64; ErrorClass::COUNT],
+    failure_streak: AtomicU64,
+    /// Answers rejected by strict freshness for trailing the tip.
+    stale_rejections: AtomicU64,
+    /// When a request was last sent, in Unix milliseconds.
+    last_request_ms: AtomicU64,
+    /// Time to headers of the last answer, plus one so 0 means none yet.
+    last_latency_us: AtomicU64,
+    /// Moving average of the time to headers, plus one so 0 means none yet.
+    average_latency_us: AtomicU64,
+    window: OutcomeWindow,
+    /// The hourly history scored by `[ranking]`.
+    pub quality: crate::ranking::Quality,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    /// Seconds since `OutcomeWindow::origin` the counts are for.
+    second: u64,
+    answered: u64,
+    failed: u64,
+}
+
+/// Answers and host failures per second, over the last `MAX_WINDOW_SECS`.
+struct OutcomeWindow {
+    origin: Instant,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl Default for OutcomeWindow {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            buckets: Mutex::new(vec![Bucket::default(); MAX_WINDOW_SECS as usize]),
+        }
+    }
+}
+
+impl OutcomeWindow {
+    fn record(&self, failed: bool) {
+        let now = self.origin.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[(now % MAX_WINDOW_SECS) as usize];
+        if bucket.second != now {
+            *bucket = Bucket {
+                second: now,
+                ..Bucket::default()
+            };
+        }
+        match failed {
+            true => bucket.failed += 1,
+            false => bucket.answered += 1,
+        }
+    }
+
+    /// Attempts and host failures over the last `secs` seconds, the
+    /// current one included.
+    fn totals(&self, secs: u64) -> (u64, u64) {
+        let now = self.origin.elapsed().as_secs();
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter()
+            .filter(|bucket| now - bucket.second < secs.min(MAX_WINDOW_SECS))
+            .fold((0, 0), |(attempts, failed), bucket| {
+                (
+                    attempts + bucket.answered + bucket.failed,
+                    failed + bucket.failed,
+                )
+            })
+    }
+
+    fn clear(&self) {
+        self.buckets.lock().unwrap().fill(Bucket::default());
+    }
+}
+
+impl UpstreamStats {
+    pub fn record_success(&self) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+        self.failure_streak.store(0, Ordering::Relaxed);
+        self.window.record(false);
+        self.quality.record_outcome(false);
+    }
+
+    pub fn record_error(&self, class: ErrorClass) {
+        self.errors[class.index()].fetch_add(1, Ordering::Relaxed);
+        self.window.record(class.is_host_failure());
+        self.quality.record_outcome(class.is_host_failure());
+        if class.is_host_failure() {
+            self.failure_streak
+                .fetch_add(class.streak_weight(), Ordering::Relaxed);
+        } else {
+            // the host answered, the request was the problem
+            self.failure_streak.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// An answer behind the tip, a host failure to the error rate though
+    /// the host answered.
+    pub fn record_stale(&self) {
+        self.stale_rejections.fetch_add(1, Ordering::Relaxed);
+        self.window.record(true);
+        self.quality.record_outcome(true);
+    }
+
+    pub fn successes(&self) -> u64 {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    pub fn stale_rejections(&self) -> u64 {
+        self.stale_rejections.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self, class: ErrorClass) -> u64 {
+        self.errors[class.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn failure_streak(&self) -> u64 {
+        self.failure_streak.load(Ordering::Relaxed)
+    }
+
+    pub fn record_request(&self) {
+        self.last_request_ms
+            .store(crate::clock::unix_ms(), Ordering::Relaxed);
+    }
+
+    /// Time since the last request, as long as possible before the first.
+    pub fn idle_for(&self) -> Duration {
+        let last = self.last_request_ms.load(Ordering::Relaxed);
+        Duration::from_millis(crate::clock::unix_ms().saturating_sub(last))
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        self.quality.record_latency(latency);
+        let micros = latency.as_micros().min(u64::MAX as u128 - 1) as u64;
+        self.last_latency_us.store(micros + 1, Ordering::Relaxed);
+        let _ =
+            self.average_latency_us
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                    Some(match average {
+                        0 => micros + 1,
+                        average => {
+                            let average = average - 1;
+                            let weighted = average as f64 * (1.0 - LATENCY_WEIGHT)
+                                + micros as f64 * LATENCY_WEIGHT;
+                            weighted as u64 + 1
+                        }
+                    })
+                });
+    }
+
+    /// Exponentially weighted moving average of the latency, `None` before
+    /// the first answer.
+    pub fn average_latency(&self) -> Option<Duration> {
+        match self.average_latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros - 1)),
+        }
+    }
+
+    pub fn last_latency(&self) -> Option<Duration> {
+        match self.last_latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros - 1)),
+        }
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.failure_streak() >= FAILURE_STREAK_THRESHOLD
+    }
+
+    /// Attempts and host failures over the last `secs` seconds.
+    pub fn window(&self, secs: u64) -> (u64, u64) {
+        self.window.totals(secs)
+    }
+
+    /// Forget the outcomes in the window, once the host is restored.
+    pub fn clear_window(&self) {
+        self.window.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::classify_transport;
+
+    #[tokio::test]
+    async fn unreachable_hosts_fail_at_the_connect_timeout() {
+        let blackhole = crate::testing::Blackhole::new().await;
+        let config = crate::config::Config::parse(&format!(
+            "[[upstreams]]\nurl = \"{}\"\nconnect_timeout_ms = 200\nrequest_timeout_ms = 5000\n",
+            blackhole.url()
+        ))
+        .unwrap();
+        let client = new_client(&ClientSettings::of(&config.upstreams[0]));
+        let started = std::time::Instant::now();
+        let err = client.post(blackhole.url()).send().await.unwrap_err();
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(classify_transport(&err), ErrorClass::ConnectTimeout);
+    }
+
+    #[test]
+    fn the_window_counts_the_last_seconds_only() {
+        let mut window = OutcomeWindow::default();
+        window.record(true);
+        window.record(false);
+        assert_eq!(window.totals(30), (2, 1));
+        // ten seconds on, the outcomes are in a 30s window but not a 10s one
+        window.origin = window.origin.checked_sub(Duration::from_secs(10)).unwrap();
+        window.record(false);
+        assert_eq!(window.totals(30), (3, 1));
+        assert_eq!(window.totals(10), (1, 0));
+        // a bucket reused a full ring later starts afresh
+        window.origin = window
+            .origin
+            .checked_sub(Duration::from_secs(MAX_WINDOW_SECS))
+            .unwrap();
+        window.record(true);
+        assert_eq!(window.totals(MAX_WINDOW_SECS * 2), (1, 1));
+        window.clear();
+        assert_eq!(window.totals(MAX_WINDOW_SECS), (0, 0));
+    }
+
+    #[test]
+    fn request_failures_do_not_count_against_the_host() {
+        let stats = UpstreamStats::default();
+        stats.record_error(ErrorClass::RpcInvalidParams);
+        stats.record_error(ErrorClass::Http5xx);
+        assert_eq!(stats.window(30), (2, 1));
+        assert_eq!(stats.failure_streak(), 1);
+        stats.record_error(ErrorClass::ConnectTimeout);
+        assert!(stats.is_failing());
+        stats.record_success();
+        assert_eq!(stats.failure_streak(), 0);
+        assert_eq!(stats.window(30), (4, 2));
+    }
+
+    #[test]
+    fn client_settings_changes_are_listed() {
+        let running = ClientSettings {
+            connect_timeout: Duration::from_millis(1000),
+            request_timeout: Duration::from_millis(5000),
+        };
+        assert!(running.changes(&running).is_empty());
+        let slower = ClientSettings {
+            request_timeout: Duration::from_secs(30),
+            ..running
+        };
+        assert_eq!(running.changes(&slower), ["request timeout 5s -> 30s"]);
+        let both = ClientSettings {
+            connect_timeout: Duration::from_millis(250),
+            ..slower
+        };
+        assert_eq!(
+            running.changes(&both),
+            ["connect timeout 1s -> 250ms", "request timeout 5s -> 30s"]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_stay_within_the_plan_rate() {
+        use axum::{routing::post, Router};
+        use std::sync::Arc;
+        let hits = Arc::new(AtomicU64::new(0));
+        let answer = r#"{"jsonrpc":"2.0","id":1,"result":{"context":{"slot":1},"value":1}}"#;
+        let limited = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    answer
+                }
+            }),
+        ))
+        .await;
+        let other = crate::testing::serve(Router::new().route(
+            "/",
+            post(move || async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                answer
+            }),
+        ))
+        .await;
+        let (config, url) = crate::testing::proxy(&format!(
+            "[[upstreams]]\nname = \"limited\"\nurl = \"{}\"\n[upstreams.rate_limit]\nrps = 0.01\nburst = 2\n[[upstreams]]\nname = \"other\"\nurl = \"{}\"\n",
+            limited, other
+        ))
+        .await;
+        let upstream = &config.servers[0];
+        // out of tokens, it is left out of races rather than waited for
+        for _ in 0..4 {
+            let response = crate::testing::call(
+                &url,
+                serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": ["x"] }),
+            )
+            .await;
+            assert_eq!(response.status(), 200);
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+        assert_eq!(upstream.outbound_skipped.load(Ordering::Relaxed), 2);
+        assert!(!upstream.has_room());
+        // background requests count against it too
+        let warmup = crate::config::WarmupConfig {
+            connections: 2,
+            keep_warm_after: None,
+        };
+        crate::warmup::warm_all(&config, &warmup).await;
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+        assert_eq!(upstream.outbound_skipped.load(Ordering::Relaxed), 4);
+        assert!(config.servers[1].has_room());
+
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("quarantier_upstream_outbound_tokens{upstream=\"limited\"} 0\n"));
+        assert!(metrics
+            .contains("quarantier_upstream_outbound_skipped_total{upstream=\"limited\"} 4\n"));
+        assert!(!metrics.contains("quarantier_upstream_outbound_tokens{upstream=\"other\"}"));
+    }
+}
@@ -0,0 +1,184 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Bucket that absorbs clients and methods beyond the tracking limits. It
+/// takes one of the entries a limit allows, so a table never grows past it.
+pub const OTHER: &str = "other";
+
+/// Methods tracked per client, counting the "other" that the rest fold into.
+const MAX_METHODS_PER_CLIENT: usize = 64;
+
+#[derive(Clone, Copy, Default)]
+pub struct Counts {
+    pub requests: u64,
+    /// Upstream credits: the method's cost times the upstreams contacted.
+    pub cost: f64,
+}
+
+impl Counts {
+    fn add(&mut self, cost: f64) {
+        self.requests += 1;
+        self.cost += cost;
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ClientUsage {
+    pub total: Counts,
+    pub methods: HashMap<String, Counts>,
+    /// Requests rejected by a rate limit, which never reached an upstream.
+    pub throttled: u64,
+    /// `sendTransaction` calls repeating a signature submitted moments ago.
+    pub send_duplicates: u64,
+    /// Upstream answers abandoned over the response size limit.
+    pub oversize: u64,
+}
+
+/// The entry of `table` that `key` is counted under: its own while fewer
+/// than `limit` entries exist, keeping the last for [`OTHER`].
+fn entry<'a, T: Default>(table: &'a mut HashMap<String, T>, key: &str, limit: usize) -> &'a mut T {
+    let named = table.len() - usize::from(table.contains_key(OTHER));
+    let key = if table.contains_key(key) || named + 1 < limit {
+        key
+    } else {
+        OTHER
+    };
+    table.entry(key.to_string()).or_default()
+}
+
+impl ClientUsage {
+    fn record(&mut self, method: &str, cost: f64) {
+        self.total.add(cost);
+        entry(&mut self.methods, method, MAX_METHODS_PER_CLIENT).add(cost);
+    }
+}
+
+/// Request and cost accounting per client identity.
+pub struct UsageTracker {
+    max_clients: usize,
+    clients: Mutex<HashMap<String, ClientUsage>>,
+}
+
+impl UsageTracker {
+    pub fn new(max_clients: usize) -> Self {
+        Self {
+            max_clients,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn with_client(&self, client: &str, update: impl FnOnce(&mut ClientUsage)) {
+        let mut clients = self.clients.lock().unwrap();
+        update(entry(&mut clients, client, self.max_clients));
+    }
+
+    pub fn record(&self, client: &str, method: &str, cost: f64) {
+        self.with_client(client, |usage| usage.record(method, cost));
+    }
+
+    pub fn record_throttled(&self, client: &str) {
+        self.with_client(client, |usage| usage.throttled += 1);
+    }
+
+    pub fn record_send_duplicate(&self, client: &str) {
+        self.with_client(client, |usage| usage.send_duplicates += 1);
+    }
+
+    pub fn record_oversize(&self, client: &str) {
+        self.with_client(client, |usage| usage.oversize += 1);
+    }
+
+    /// The `n` clients with the highest cost, most expensive first.
+    pub fn top(&self, n: usize) -> Vec<(String, ClientUsage)> {
+        let clients = self.clients.lock().unwrap();
+        let mut top: Vec<_> = clients
+            .iter()
+            .map(|(client, usage)| (client.clone(), usage.clone()))
+            .collect();
+        top.sort_by(|a, b| b.1.total.cost.total_cmp(&a.1.total.cost));
+        top.truncate(n);
+        top
+    }
+
+    pub fn reset(&self) {
+        self.clients.lock().unwrap().clear();
+    }
+
+    pub fn report(&self, n: usize) -> Value {
+        let clients: Vec<Value> = self
+            .top(n)
+            .into_iter()
+            .map(|(client, usage)| {
+                let mut methods = Map::new();
+                for (method, counts) in &usage.methods {
+                    methods.insert(
+                        method.clone(),
+                        json!({ "requests": counts.requests, "cost": counts.cost }),
+                    );
+                }
+                json!({
+                    "client": client,
+                    "requests": usage.total.requests,
+                    "cost": usage.total.cost,
+                    "throttled": usage.throttled,
+                    "send_duplicates": usage.send_duplicates,
+                    "oversize": usage.oversize,
+                    "methods": methods,
+                })
+            })
+            .collect();
+        json!({ "clients": clients })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_beyond_the_limit_share_the_last_entry() {
+        let usage = UsageTracker::new(3);
+        for client in ["a", "b", "c", "d", "e"] {
+            usage.record(client, "getSlot", 1.0);
+        }
+        usage.record("a", "getSlot", 1.0);
+        usage.record_throttled("f");
+        let top = usage.top(10);
+        assert_eq!(top.len(), 3);
+        let clients: HashMap<_, _> = top.into_iter().collect();
+        assert_eq!(clients["a"].total.requests, 2);
+        assert_eq!(clients["b"].total.requests, 1);
+        assert_eq!(clients[OTHER].total.requests, 3);
+        assert_eq!(clients[OTHER].throttled, 1);
+    }
+
+    #[test]
+    fn methods_beyond_the_limit_share_the_last_entry() {
+        let usage = UsageTracker::new(10);
+        for n in 0..MAX_METHODS_PER_CLIENT + 5 {
+            usage.record("a", &format!("method{}", n), 2.0);
+        }
+        let (_, client) = usage.top(1).remove(0);
+        assert_eq!(client.methods.len(), MAX_METHODS_PER_CLIENT);
+        assert_eq!(client.methods[OTHER].requests, 6);
+        assert_eq!(client.total.requests, MAX_METHODS_PER_CLIENT as u64 + 5);
+        assert_eq!(client.total.cost, 2.0 * client.total.requests as f64);
+    }
+
+    #[test]
+    fn the_top_clients_are_the_most_expensive() {
+        let usage = UsageTracker::new(10);
+        usage.record("cheap", "getSlot", 1.0);
+        usage.record("dear", "getProgramAccounts", 10.0);
+        usage.record("middling", "getBlock", 5.0);
+        let report = usage.report(2);
+        let clients = report["clients"].as_array().unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0]["client"], "dear");
+        assert_eq!(clients[0]["methods"]["getProgramAccounts"]["cost"], 10.0);
+        assert_eq!(clients[1]["client"], "middling");
+        usage.reset();
+        assert!(usage.top(10).is_empty());
+    }
+}
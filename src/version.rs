@@ -0,0 +1,173 @@
+//! Build metadata embedded by `build.rs`, for `--version`, the startup log,
+//! `GET /version` and the `quarantier_build_info` metric.
+
+use crate::config::Strategy;
+use crate::ServerConfig;
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of the commit built, `unknown` outside a git checkout.
+pub const COMMIT: &str = env!("QUARANTIER_GIT_COMMIT");
+const BUILD_TIME: &str = env!("QUARANTIER_BUILD_TIME");
+
+pub fn built_at() -> String {
+    crate::clock::rfc3339(BUILD_TIME.parse::<u64>().unwrap_or_default() * 1000)
+}
+
+/// `quarantier 0.1.0 (commit 1a2b3c4d5e6f, built 2024-05-01T12:00:00.000Z)`
+pub fn describe() -> String {
+    format!(
+        "quarantier {} (commit {}, built {})",
+        VERSION,
+        COMMIT,
+        built_at()
+    )
+}
+
+/// Optional subsystems the running config enables.
+fn features(config: &ServerConfig) -> Vec<&'static str> {
+    let settings = &config.settings;
+    [
+        ("api_keys", !settings.auth.keys.is_empty()),
+        ("jwt", settings.auth.jwt.is_some()),
+        ("ip_rate_limit", settings.ip_rate_limit.is_some()),
+        (
+            "acl",
+            !settings.acl.allow.is_empty() || !settings.acl.deny.is_empty(),
+        ),
+        ("method_policy", !settings.methods.is_empty()),
+        ("statsd", settings.statsd.is_some()),
+        ("access_log", settings.access_log.is_some()),
+        ("mirror", settings.mirror.is_some()),
+        ("state_file", settings.state_file.is_some()),
+        ("dns_refresh", settings.dns_refresh.is_some()),
+        ("debug_headers", settings.debug_headers),
+        ("aggregate_get_health", settings.aggregate_get_health),
+        ("epoch_cache", settings.epoch_cache.is_some()),
+        ("local_get_slot", settings.local_get_slot.is_some()),
+        ("coalesce", settings.coalesce.is_some()),
+        ("send_dedup", settings.send_dedup.is_some()),
+        (
+            "unsupported_methods",
+            settings.unsupported_methods.is_some(),
+        ),
+        ("tx_tracking", settings.tx_tracking.is_some()),
+        ("error_rate_quarantine", settings.error_rate.is_some()),
+        ("region_routing", settings.region.is_some()),
+        (
+            "maintenance_windows",
+            settings.upstreams.iter().any(|u| !u.maintenance.is_empty()),
+        ),
+        (
+            "last_resort",
+            settings.upstreams.iter().any(|u| u.last_resort.is_some()),
+        ),
+        (
+            "blockhash_quarantine",
+            settings.blockhash.as_ref().is_some_and(|b| b.quarantine),
+        ),
+        ("min_context_slot", settings.min_context_margin.is_some()),
+        (
+            "merge_routing",
+            settings
+                .routing
+                .methods
+                .values()
+                .any(|strategy| *strategy == Strategy::Merge),
+        ),
+        (
+            "hedging",
+            settings.routing.hedge.is_some()
+                || settings.routing.hedges.values().any(Option::is_some),
+        ),
+        (
+            "outbound_rate_limits",
+            settings.upstreams.iter().any(|u| u.outbound.is_some()),
+        ),
+        (
+            "capability_routing",
+            settings
+                .upstreams
+                .iter()
+                .any(|upstream| !upstream.capabilities.is_empty()),
+        ),
+        (
+            "ws_slot_feed",
+            settings
+                .upstreams
+                .iter()
+                .any(|upstream| upstream.ws_url.is_some()),
+        ),
+        ("warmup", settings.warmup.connections > 0),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+pub async fn version_handler(State(config): State<Arc<ServerConfig>>) -> Json<Value> {
+    Json(json!({
+        "version": VERSION,
+        "commit": COMMIT,
+        "built_at": built_at(),
+        "features": features(&config),
+        "routing": "race",
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_build_is_reported_everywhere_alike() {
+        let (_, url) = crate::testing::proxy(
+            "debug_headers = true\n\n[[upstreams]]\nurl = \"http://127.0.0.1:1\"\n",
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let version: Value = client
+            .get(format!("{}/version", url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(version["commit"], COMMIT);
+        assert!(!COMMIT.is_empty());
+        let built_at = version["built_at"].as_str().unwrap();
+        assert!(
+            built_at.ends_with('Z') && built_at.len() == 24,
+            "{}",
+            built_at
+        );
+        let features = version["features"].as_array().unwrap();
+        assert!(features.contains(&json!("debug_headers")), "{:?}", features);
+        assert!(!features.contains(&json!("api_keys")), "{:?}", features);
+        assert_eq!(
+            describe(),
+            format!(
+                "quarantier {} (commit {}, built {})",
+                VERSION, COMMIT, built_at
+            )
+        );
+
+        let metrics = client
+            .get(format!("{}/metrics", url))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let info = format!(
+            "quarantier_build_info{{version=\"{}\",commit=\"{}\"}} 1\n",
+            VERSION, COMMIT
+        );
+        assert!(metrics.contains(&info), "{}", metrics);
+    }
+}
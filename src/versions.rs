@@ -0,0 +1,495 @@
+//! The node versions of the upstreams, from the `getVersion` each is asked
+//! at startup and every `[versions] refresh_secs`. During a cluster upgrade
+//! nodes on the old release answer some calls differently, so the healthy
+//! upstreams running releases further apart than `allowed_skew` are logged
+//! with a warning, and so is any upstream below `min_version`; with
+//! `below_min_version = "quarantine"` such an upstream is also quarantined,
+//! softly, still asked when every other upstream is quarantined too. A
+//! probe that fails keeps the last version, marked stale, and changes
+//! nothing else.
+
+use crate::config::{BelowMinVersion, VersionSkew, VersionsConfig};
+use crate::ServerConfig;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest one `getVersion` call may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A `solana-core` release, any pre-release suffix dropped.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub fn parse(text: &str) -> Option<Self> {
+        let release = text.split(['-', '+']).next()?;
+        let mut parts = release.split('.').map(|part| part.parse::<u64>().ok());
+        let version = Self {
+            major: parts.next()??,
+            minor: parts.next()??,
+            patch: parts.next().unwrap_or(Some(0))?,
+        };
+        parts.next().is_none().then_some(version)
+    }
+
+    /// The part of the version hosts within `skew` of each other share.
+    fn release(self, skew: VersionSkew) -> Option<(u64, u64, u64)> {
+        match skew {
+            VersionSkew::None => Some((self.major, self.minor, self.patch)),
+            VersionSkew::Patch => Some((self.major, self.minor, 0)),
+            VersionSkew::Minor => Some((self.major, 0, 0)),
+            VersionSkew::Major => None,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+struct Reported {
+    core: String,
+    feature_set: Option<u64>,
+    at: Instant,
+}
+
+/// What an upstream reported running.
+#[derive(Default)]
+pub struct NodeVersion {
+    last: Mutex<Option<Reported>>,
+    /// Whether the last probe got no answer, leaving `last` as it was.
+    stale: AtomicBool,
+    below_minimum: AtomicBool,
+    /// Below the minimum under the `quarantine` policy.
+    quarantined: AtomicBool,
+}
+
+impl NodeVersion {
+    pub fn core(&self) -> Option<String> {
+        let last = self.last.lock().unwrap();
+        last.as_ref().map(|reported| reported.core.clone())
+    }
+
+    pub fn feature_set(&self) -> Option<u64> {
+        let last = self.last.lock().unwrap();
+        last.as_ref().and_then(|reported| reported.feature_set)
+    }
+
+    fn version(&self) -> Option<Version> {
+        Version::parse(&self.core()?)
+    }
+
+    /// Whether it is softly quarantined for running below the minimum.
+    pub fn quarantines(&self) -> bool {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
+    /// For `/status`.
+    pub fn report(&self) -> Value {
+        match &*self.last.lock().unwrap() {
+            Some(reported) => json!({
+                "solana_core": reported.core,
+                "feature_set": reported.feature_set,
+                "age_secs": reported.at.elapsed().as_secs(),
+                "stale": self.stale.load(Ordering::Relaxed),
+                "below_minimum": self.below_minimum.load(Ordering::Relaxed),
+            }),
+            None => Value::Null,
+        }
+    }
+}
+
+/// The versions last warned of as skewed, to log only changes.
+#[derive(Default)]
+pub struct Spread {
+    warned: Mutex<Option<Vec<Version>>>,
+}
+
+pub fn skew_name(skew: VersionSkew) -> &'static str {
+    match skew {
+        VersionSkew::None => "none",
+        VersionSkew::Patch => "patch",
+        VersionSkew::Minor => "minor",
+        VersionSkew::Major => "major",
+    }
+}
+
+fn log(config: &ServerConfig, message: String) {
+    println!("+ {}", message);
+    config.events.record(message);
+}
+
+/// Check upstream `index` against `[versions] min_version`, logging when
+/// it falls below or gets back above it.
+fn check_minimum(config: &ServerConfig, settings: &VersionsConfig, index: usize) {
+    let upstream = &config.servers[index];
+    let (Some(minimum), Some(version)) = (settings.min_version, upstream.version.version()) else {
+        return;
+    };
+    let below = version < minimum;
+    let quarantined = below && settings.below_min_version == BelowMinVersion::Quarantine;
+    upstream
+        .version
+        .quarantined
+        .store(quarantined, Ordering::Relaxed);
+    if upstream
+        .version
+        .below_minimum
+        .swap(below, Ordering::Relaxed)
+        == below
+    {
+        return;
+    }
+    let message = match (below, quarantined) {
+        (true, true) => format!(
+            "WARNING: {} runs {}, below the minimum version {}; quarantined until it is upgraded",
+            upstream.name, version, minimum
+        ),
+        (true, false) => format!(
+            "WARNING: {} runs {}, below the minimum version {}",
+            upstream.name, version, minimum
+        ),
+        (false, _) => format!(
+            "{} runs {}, no longer below the minimum version {}",
+            upstream.name, version, minimum
+        ),
+    };
+    log(config, message);
+}
+
+/// Compare the versions of the healthy regular upstreams, logging when
+/// they spread further apart than allowed and when they are back within.
+async fn check_skew(config: &ServerConfig, settings: &VersionsConfig) {
+    let quarantine = config.quarantine.read().await.clone();
+    let mut running: BTreeMap<Version, Vec<&str>> = BTreeMap::new();
+    for upstream in &config.servers {
+        if upstream.is_last_resort()
+            || upstream.circuit.dead().is_some()
+            || upstream.is_quarantined(&quarantine)
+        {
+            continue;
+        }
+        if let Some(version) = upstream.version.version() {
+            running.entry(version).or_default().push(&upstream.name);
+        }
+    }
+    let releases: Vec<_> = running
+        .keys()
+        .filter_map(|version| version.release(settings.allowed_skew))
+        .collect();
+    let skewed = releases.windows(2).any(|pair| pair[0] != pair[1]);
+    let skew = skewed.then(|| running.keys().copied().collect::<Vec<_>>());
+    let mut warned = config.version_spread.warned.lock().unwrap();
+    if *warned == skew {
+        return;
+    }
+    match &skew {
+        Some(_) => {
+            let versions: Vec<String> = running
+                .iter()
+                .map(|(version, names)| format!("{} ({})", version, names.join(", ")))
+                .collect();
+            let message = format!(
+                "WARNING: healthy upstreams run {}, further apart than the allowed {} skew",
+                versions.join(", "),
+                skew_name(settings.allowed_skew)
+            );
+            log(config, message);
+        }
+        None => log(
+            config,
+            format!(
+                "Healthy upstreams' versions are within the allowed {} skew again",
+                skew_name(settings.allowed_skew)
+            ),
+        ),
+    }
+    *warned = skew;
+}
+
+/// Ask every regular upstream `getVersion` now and every `refresh_secs`,
+/// forever.
+pub async fn follow(config: Arc<ServerConfig>, settings: VersionsConfig) {
+    let mut ticker = tokio::time::interval(settings.refresh);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let body = r#"{"jsonrpc":"2.0","id":1,"method":"getVersion"}"#;
+    loop {
+        ticker.tick().await;
+        // last-resort upstreams are not asked more than they must, dead ones
+        // only by their circuit's probes, and another cluster's not at all
+        let upstreams = config
+            .servers
+            .iter()
+            .filter(|u| !u.is_last_resort() && u.circuit.dead().is_none())
+            .filter(|u| !u.cluster.is_wrong());
+        let versions = futures::future::join_all(upstreams.map(|upstream| async {
+            if !upstream.take_token() {
+                return (upstream.index, None);
+            }
+            crate::costs::charge(&config, upstream.index, ["getVersion"]);
+            let request = upstream
+                .post()
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send();
+            let answer = tokio::time::timeout(PROBE_TIMEOUT, async {
+                request.await?.json::<Value>().await
+            })
+            .await;
+            let result = answer
+                .ok()
+                .and_then(Result::ok)
+                .map(|mut a| a["result"].take());
+            (upstream.index, result)
+        }))
+        .await;
+        for (index, result) in versions {
+            let state = &config.servers[index].version;
+            let core = result.as_ref().and_then(|r| r["solana-core"].as_str());
+            let Some(core) = core else {
+                state.stale.store(true, Ordering::Relaxed);
+                continue;
+            };
+            *state.last.lock().unwrap() = Some(Reported {
+                core: core.to_string(),
+                feature_set: result.as_ref().and_then(|r| r["feature-set"].as_u64()),
+                at: Instant::now(),
+            });
+            state.stale.store(false, Ordering::Relaxed);
+            check_minimum(&config, &settings, index);
+        }
+        check_skew(&config, &settings).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Behavior;
+    use axum::{routing::post, Router};
+
+    /// Wait up to three seconds for `done`.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..150 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        done()
+    }
+
+    fn events(config: &ServerConfig) -> Vec<String> {
+        config.events.recent().into_iter().map(|(_, e)| e).collect()
+    }
+
+    async fn node(version: &str) -> String {
+        crate::testing::upstream(Behavior {
+            node_version: Version::parse(version).unwrap(),
+            ..Behavior::default()
+        })
+        .await
+    }
+
+    /// A proxy for upstreams `urls`, named after their position, with the
+    /// `[versions]` table `versions`, asking their versions in the
+    /// background.
+    async fn proxy(urls: &[String], versions: &str) -> (Arc<ServerConfig>, String) {
+        let mut text = format!("[versions]\n{}\n", versions);
+        for (index, url) in urls.iter().enumerate() {
+            text += &format!("[[upstreams]]\nname = \"u{}\"\nurl = \"{}\"\n", index, url);
+        }
+        let (config, url) = crate::testing::proxy(&text).await;
+        let settings = config.settings.versions.clone().unwrap();
+        tokio::spawn(follow(config.clone(), settings));
+        (config, url)
+    }
+
+    /// Have upstream `index` report running `core`.
+    fn running(config: &ServerConfig, index: usize, core: &str) {
+        *config.servers[index].version.last.lock().unwrap() = Some(Reported {
+            core: core.to_string(),
+            feature_set: None,
+            at: Instant::now(),
+        });
+    }
+
+    #[test]
+    fn versions_are_their_releases() {
+        let version = |text| Version::parse(text).map(|v| v.to_string());
+        assert_eq!(version("1.18.22").as_deref(), Some("1.18.22"));
+        assert_eq!(version("2.0.15-beta.1").as_deref(), Some("2.0.15"));
+        assert_eq!(version("2.1").as_deref(), Some("2.1.0"));
+        assert_eq!(version("1.2.3.4"), None);
+        assert_eq!(version("agave"), None);
+        let v = |text| Version::parse(text).unwrap();
+        assert!(v("1.18.22") < v("1.18.23") && v("1.18.23") < v("2.0.0"));
+        assert_eq!(
+            v("1.18.22").release(VersionSkew::Patch),
+            v("1.18.3").release(VersionSkew::Patch)
+        );
+        assert_ne!(
+            v("1.18.22").release(VersionSkew::Patch),
+            v("1.17.22").release(VersionSkew::Patch)
+        );
+        assert_eq!(v("1.18.22").release(VersionSkew::Major), None);
+    }
+
+    #[tokio::test]
+    async fn versions_are_reported_in_status_and_metrics() {
+        let urls = [node("2.0.15").await, node("2.0.16").await];
+        let (config, url) = proxy(&urls, "").await;
+        assert!(eventually(|| config.servers.iter().all(|u| u.version.core().is_some())).await);
+        let status = crate::status::document(&config).await;
+        let version = &status["upstreams"][1]["version"];
+        assert_eq!(version["solana_core"], "2.0.16");
+        assert_eq!(version["feature_set"], (2 << 20 | 16) as u64);
+        assert_eq!(version["stale"], false);
+        assert_eq!(version["below_minimum"], false);
+        let metrics = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains(&format!(
+            "quarantier_upstream_version_info{{upstream=\"u0\",solana_core=\"2.0.15\",feature_set=\"{}\"}} 1",
+            2 << 20 | 15
+        )));
+        // versions within the default patch skew are not warned of
+        assert!(!events(&config).iter().any(|e| e.contains("skew")));
+    }
+
+    #[tokio::test]
+    async fn skews_are_warned_of_once_and_until_they_are_gone() {
+        let config = crate::testing::config(
+            "[versions]\n\
+             [[upstreams]]\nname = \"a\"\nurl = \"http://127.0.0.1:1\"\n\
+             [[upstreams]]\nname = \"b\"\nurl = \"http://127.0.0.1:2\"\n\
+             [[upstreams]]\nname = \"c\"\nurl = \"http://127.0.0.1:3\"\n",
+        );
+        let settings = config.settings.versions.clone().unwrap();
+        running(&config, 0, "2.0.15");
+        running(&config, 1, "2.0.15");
+        running(&config, 2, "2.1.0");
+        check_skew(&config, &settings).await;
+        check_skew(&config, &settings).await;
+        let warning = "WARNING: healthy upstreams run 2.0.15 (a, b), 2.1.0 (c), further apart than the allowed patch skew";
+        let warnings = events(&config);
+        assert_eq!(warnings.iter().filter(|e| e.contains(warning)).count(), 1);
+        running(&config, 2, "2.0.18");
+        check_skew(&config, &settings).await;
+        assert!(events(&config)
+            .iter()
+            .any(|e| e.contains("versions are within the allowed patch skew again")));
+
+        // only the healthy count
+        running(&config, 2, "2.1.0");
+        config.servers[2]
+            .version
+            .quarantined
+            .store(true, Ordering::Relaxed);
+        let before = events(&config).len();
+        check_skew(&config, &settings).await;
+        assert_eq!(events(&config).len(), before);
+        // and a wider allowance keeps quiet
+        config.servers[2]
+            .version
+            .quarantined
+            .store(false, Ordering::Relaxed);
+        let minor = VersionsConfig {
+            allowed_skew: VersionSkew::Minor,
+            ..settings
+        };
+        check_skew(&config, &minor).await;
+        assert_eq!(events(&config).len(), before);
+    }
+
+    #[tokio::test]
+    async fn old_versions_are_warned_of_or_quarantined() {
+        let urls = [node("2.0.15").await, node("2.0.16").await];
+        let (config, _) = proxy(&urls, "min_version = \"2.0.16\"").await;
+        assert!(eventually(|| config.servers[0].version.core().is_some()).await);
+        assert!(events(&config)
+            .iter()
+            .any(|e| e == "WARNING: u0 runs 2.0.15, below the minimum version 2.0.16"));
+        assert_eq!(config.servers[0].quarantine_reason(&[]), None);
+        let status = crate::status::document(&config).await;
+        assert_eq!(status["upstreams"][0]["version"]["below_minimum"], true);
+        assert_eq!(status["upstreams"][1]["version"]["below_minimum"], false);
+
+        let urls = [node("2.0.15").await, node("2.0.16").await];
+        let (config, url) = proxy(
+            &urls,
+            "min_version = \"2.0.16\"\nbelow_min_version = \"quarantine\"",
+        )
+        .await;
+        assert!(eventually(|| config.servers[0].version.quarantines()).await);
+        assert_eq!(
+            config.servers[0].quarantine_reason(&[]),
+            Some("old version")
+        );
+        assert!(events(&config)
+            .iter()
+            .any(|e| e.ends_with("quarantined until it is upgraded")));
+        // clients get the answers of the upgraded upstream
+        for _ in 0..6 {
+            let answer: Value = crate::testing::call(
+                &url,
+                json!({"jsonrpc": "2.0", "id": 1, "method": "getVersion"}),
+            )
+            .await
+            .json()
+            .await
+            .unwrap();
+            assert_eq!(answer["result"]["solana-core"], "2.0.16");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_probes_keep_the_last_version_marked_stale() {
+        let up = Arc::new(AtomicBool::new(true));
+        let answering = up.clone();
+        let url = crate::testing::serve(Router::new().route(
+            "/",
+            post(move || {
+                let up = answering.load(Ordering::Relaxed);
+                async move {
+                    match up {
+                        true => Ok(json!({
+                            "jsonrpc": "2.0",
+                            "id": 1,
+                            "result": { "solana-core": "2.0.15", "feature-set": 42 },
+                        })
+                        .to_string()),
+                        false => Err(axum::http::StatusCode::SERVICE_UNAVAILABLE),
+                    }
+                }
+            }),
+        ))
+        .await;
+        let (config, _) = proxy(&[url], "refresh_secs = 1").await;
+        let version = &config.servers[0].version;
+        assert!(eventually(|| version.core().is_some()).await);
+        up.store(false, Ordering::Relaxed);
+        assert!(eventually(|| version.stale.load(Ordering::Relaxed)).await);
+        let report = version.report();
+        assert_eq!(report["solana_core"], "2.0.15");
+        assert_eq!(report["feature_set"], 42);
+        assert_eq!(report["stale"], true);
+        // nor does it change the routing
+        assert_eq!(config.servers[0].quarantine_reason(&[]), None);
+        up.store(true, Ordering::Relaxed);
+        assert!(eventually(|| !version.stale.load(Ordering::Relaxed)).await);
+    }
+}
@@ -0,0 +1,243 @@
+//! Connection pool warmup: `getHealth` calls opening several connections to
+//! every upstream at startup, and a single one keeping a connection open to
+//! upstreams left idle long enough for their pooled connections to be
+//! closed.
+
+use crate::classify::{classify_response, classify_transport};
+use crate::config::WarmupConfig;
+use crate::upstream::Upstream;
+use crate::ServerConfig;
+use futures::future::join_all;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Open `connections` connections to `upstream` at once, logging how long
+/// each took. An open pool answers with one of its idle connections instead.
+async fn warm(config: &ServerConfig, upstream: &Upstream, connections: usize) {
+    let connections = (0..connections).filter(|_| upstream.take_token()).count();
+    let calls = std::iter::repeat_n("getHealth", connections);
+    crate::costs::charge(config, upstream.index, calls);
+    upstream.stats.record_request();
+    let calls = (0..connections).map(|_| async {
+        let started = Instant::now();
+        upstream
+            .post()
+            .header("Content-Type", "application/json")
+            .body(r#"{"jsonrpc":"2.0","id":1,"method":"getHealth"}"#)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| started.elapsed())
+    });
+    let results = join_all(calls).await;
+    let mut latencies: Vec<Duration> = results
+        .iter()
+        .flat_map(|r| r.as_ref().ok())
+        .copied()
+        .collect();
+    latencies.sort();
+    match (latencies.first(), latencies.last()) {
+        (Some(fastest), Some(slowest)) => println!(
+            "[warmup] + Warmed {}/{} connections to {} in {:?} to {:?}",
+            latencies.len(),
+            connections,
+            upstream.name,
+            fastest,
+            slowest
+        ),
+        _ => println!(
+            "[warmup] + Failed to warm connections to {}: {}",
+            upstream.name,
+            results
+                .into_iter()
+                .find_map(Result::err)
+                .map_or("no connection".to_string(), |err| err.to_string())
+        ),
+    }
+}
+
+/// Warm every upstream, waiting for all of them.
+pub async fn warm_all(config: &ServerConfig, settings: &WarmupConfig) {
+    let warmups = config
+        .servers
+        .iter()
+        .map(|upstream| warm(config, upstream, settings.connections));
+    join_all(warmups).await;
+}
+
+/// Send one `getHealth` to `upstream`, keeping a pooled connection open.
+/// The answer is tracked like proxied traffic: latency, success or error
+/// class, and the provider's rate-limit headers.
+async fn ping(config: &ServerConfig, upstream: &Upstream) {
+    if !upstream.take_token() {
+        return;
+    }
+    crate::costs::charge(config, upstream.index, ["getHealth"]);
+    upstream.stats.record_request();
+    let started = Instant::now();
+    let response = upstream
+        .post()
+        .header("Content-Type", "application/json")
+        .body(r#"{"jsonrpc":"2.0","id":1,"method":"getHealth"}"#)
+        .send()
+        .await;
+    let answer = match response {
+        Ok(response) => {
+            upstream
+                .limits
+                .observe(&upstream.name, response.headers(), &config.events);
+            let status = response.status().as_u16();
+            response.bytes().await.map(|body| (status, body))
+        }
+        Err(err) => Err(err),
+    };
+    let class = match &answer {
+        Ok((status, body)) => {
+            upstream.stats.record_latency(started.elapsed());
+            let json = serde_json::from_slice(body).ok();
+            classify_response(*status, body, json.as_ref())
+        }
+        Err(err) => Some(classify_transport(err)),
+    };
+    match class {
+        Some(class) => {
+            upstream.stats.record_error(class);
+            println!(
+                "[keep-warm] + Ping to {} failed ({})",
+                upstream.name,
+                class.as_str()
+            );
+        }
+        None => upstream.stats.record_success(),
+    }
+}
+
+/// Ping upstreams without proxied traffic for `keep_warm_after`, which
+/// stays below the pool idle timeout, at a random point of each round so
+/// they are not all pinged at once. Quarantined upstreams are skipped, as
+/// the slot poller watches them at its own pace, and so are drained ones
+/// and those short of requests left on their plan.
+pub async fn keep_warm(config: Arc<ServerConfig>, settings: WarmupConfig) {
+    let Some(idle) = settings.keep_warm_after else {
+        return;
+    };
+    let round = idle / 4;
+    let mut ticker = tokio::time::interval(round);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let quarantine = config.quarantine.read().await.clone();
+        let pings = config
+            .servers
+            .iter()
+            .filter(|upstream| !upstream.is_last_resort() && !upstream.is_drained())
+            .filter(|upstream| !upstream.is_quarantined(&quarantine))
+            .filter(|upstream| !upstream.limits.is_low())
+            .filter(|upstream| upstream.stats.idle_for() >= idle)
+            .map(|upstream| async {
+                tokio::time::sleep(round.mul_f64(crate::random::f64())).await;
+                // traffic may have come in meanwhile
+                if upstream.stats.idle_for() >= idle {
+                    ping(&config, upstream).await;
+                }
+            });
+        join_all(pings).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, routing::post, Router};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// An upstream answering `getHealth` after a moment, and the client
+    /// ports of the calls it answered.
+    async fn healthy() -> (String, Arc<Mutex<Vec<u16>>>) {
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let url = crate::testing::serve(Router::new().route(
+            "/",
+            post({
+                let peers = peers.clone();
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    peers.lock().unwrap().push(peer.port());
+                    r#"{"jsonrpc":"2.0","id":1,"result":"ok"}"#
+                }
+            }),
+        ))
+        .await;
+        (url, peers)
+    }
+
+    #[tokio::test]
+    async fn the_warmup_opens_several_connections() {
+        let (url, peers) = healthy().await;
+        let config = crate::testing::config(&format!("[[upstreams]]\nurl = \"{}\"\n", url));
+        let settings = WarmupConfig {
+            connections: 3,
+            keep_warm_after: None,
+        };
+        warm_all(&config, &settings).await;
+        let ports: HashSet<u16> = peers.lock().unwrap().iter().copied().collect();
+        assert_eq!(ports.len(), 3);
+        // opened connections are pooled, not closed
+        warm_all(&config, &settings).await;
+        let ports_after: HashSet<u16> = peers.lock().unwrap().iter().copied().collect();
+        assert_eq!(ports_after, ports);
+    }
+
+    #[tokio::test]
+    async fn idle_upstreams_are_kept_warm_and_the_rest_left_alone() {
+        let (idle, idle_peers) = healthy().await;
+        let (quarantined, quarantined_peers) = healthy().await;
+        let (busy, busy_peers) = healthy().await;
+        let failing = crate::testing::serve(Router::new().route(
+            "/",
+            post(|| async { axum::http::StatusCode::SERVICE_UNAVAILABLE }),
+        ))
+        .await;
+        let config = crate::testing::config(&format!(
+            "[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n[[upstreams]]\nurl = \"{}\"\n",
+            idle, quarantined, busy, failing
+        ));
+        config.quarantine.write().await.push(1);
+        let settings = WarmupConfig {
+            connections: 1,
+            keep_warm_after: Some(Duration::from_millis(200)),
+        };
+        let task = tokio::spawn(keep_warm(config.clone(), settings));
+        // proxied traffic keeps its connections open by itself
+        for _ in 0..10 {
+            config.servers[2].stats.record_request();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        task.abort();
+        let pings = |peers: &Mutex<Vec<u16>>| peers.lock().unwrap().len();
+        assert!(
+            (1..=3).contains(&pings(&idle_peers)),
+            "{}",
+            pings(&idle_peers)
+        );
+        assert_eq!(pings(&quarantined_peers), 0);
+        assert_eq!(pings(&busy_peers), 0);
+        // the pings count like any other request
+        let stats = &config.servers[0].stats;
+        assert!(stats.successes() >= 1);
+        assert!(stats.last_latency().is_some());
+        assert!(stats.idle_for() < Duration::from_millis(500));
+        let failing = &config.servers[3].stats;
+        assert!(failing.errors(crate::classify::ErrorClass::Http5xx) >= 1);
+
+        // without keep_warm_after there is nothing to do
+        let settings = WarmupConfig {
+            connections: 1,
+            keep_warm_after: None,
+        };
+        tokio::time::timeout(Duration::from_millis(100), keep_warm(config, settings))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Minimal WebSocket (RFC 6455) connections: the client side the slot feed
+//! needs and the server side of the mock upstream. Text messages, pings and
+//! closes only, without extensions.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::Url;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Appended to the client's key to derive the server's accept value.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest handshake accepted, headers included.
+const MAX_HANDSHAKE: u64 = 16 * 1024;
+/// Largest message accepted, fragments together.
+const MAX_MESSAGE: usize = 1 << 20;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub struct WebSocket {
+    io: BufReader<Box<dyn Io>>,
+    /// Clients mask what they send, servers must not.
+    client: bool,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn accept_value(key: &str) -> String {
+    STANDARD.encode(openssl::sha::sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Header lines up to the blank line ending a handshake, lowercased names
+/// with their values, after the first line.
+async fn read_head(io: &mut BufReader<Box<dyn Io>>) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut head = io.take(MAX_HANDSHAKE);
+    let mut first = String::new();
+    head.read_line(&mut first).await?;
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(invalid("handshake ended early"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((first, headers));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Open a WebSocket to a `ws://` or `wss://` URL.
+pub async fn connect(url: &str) -> io::Result<WebSocket> {
+    let url = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("no host"))?
+        .to_string();
+    let tls = url.scheme() == "wss";
+    let port = url
+        .port_or_known_default()
+        .unwrap_or(if tls { 443 } else { 80 });
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+    tcp.set_nodelay(true)?;
+    let io: Box<dyn Io> = if tls {
+        let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+        Box::new(
+            connector
+                .connect(&host, tcp)
+                .await
+                .map_err(io::Error::other)?,
+        )
+    } else {
+        Box::new(tcp)
+    };
+    let mut io = BufReader::new(io);
+    let key = STANDARD.encode(
+        [
+            crate::random::u64().to_le_bytes(),
+            crate::random::u64().to_le_bytes(),
+        ]
+        .concat(),
+    );
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        target, authority, key
+    );
+    io.write_all(request.as_bytes()).await?;
+    io.flush().await?;
+    let (status, headers) = read_head(&mut io).await?;
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(invalid(format!("upgrade refused: {}", status.trim_end())));
+    }
+    if header(&headers, "sec-websocket-accept") != Some(accept_value(&key).as_str()) {
+        return Err(invalid("wrong Sec-WebSocket-Accept"));
+    }
+    Ok(WebSocket { io, client: true })
+}
+
+/// Complete the handshake of a client connecting to `stream`.
+pub async fn accept(stream: TcpStream) -> io::Result<WebSocket> {
+    let mut io = BufReader::new(Box::new(stream) as Box<dyn Io>);
+    let (_, headers) = read_head(&mut io).await?;
+    let key = header(&headers, "sec-websocket-key").ok_or_else(|| invalid("not a WebSocket"))?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_value(key)
+    );
+    io.write_all(response.as_bytes()).await?;
+    io.flush().await?;
+    Ok(WebSocket { io, client: false })
+}
+
+impl WebSocket {
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.client {
+            let mask = (crate::random::u64() as u32).to_be_bytes();
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.io.write_all(&frame).await?;
+        self.io.flush().await
+    }
+
+    /// The next frame: whether it is final, its opcode and its payload.
+    async fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.io.read_exact(&mut head).await?;
+        let len = match head[1] & 0x7f {
+            126 => self.io.read_u16().await? as u64,
+            127 => self.io.read_u64().await?,
+            len => len as u64,
+        };
+        if len > MAX_MESSAGE as u64 {
+            return Err(invalid("frame too large"));
+        }
+        let mut mask = [0u8; 4];
+        let masked = head[1] & 0x80 != 0;
+        if masked {
+            self.io.read_exact(&mut mask).await?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        self.io.read_exact(&mut payload).await?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok((head[0] & 0x80 != 0, head[0] & 0x0f, payload))
+    }
+
+    pub async fn send(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(TEXT, text.as_bytes()).await
+    }
+
+    /// The next message, answering pings on the way; `None` once the peer
+    /// closed the connection.
+    pub async fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut message = Vec::new();
+        loop {
+            let (last, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                CLOSE => {
+                    // echo the status code, the peer may be waiting for it
+                    let _ = self
+                        .write_frame(CLOSE, &payload[..payload.len().min(2)])
+                        .await;
+                    return Ok(None);
+                }
+                PING => self.write_frame(PONG, &payload).await?,
+                PONG => {}
+                CONTINUATION | TEXT | BINARY => {
+                    message.extend_from_slice(&payload);
+                    if message.len() > MAX_MESSAGE {
+                        return Err(invalid("message too large"));
+                    }
+                    if last {
+                        return String::from_utf8(message)
+                            .map(Some)
+                            .map_err(|_| invalid("message is not UTF-8"));
+                    }
+                }
+                _ => return Err(invalid(format!("unknown opcode {}", opcode))),
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Driving the proxy as a library, through its public API only.
+
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::Request;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use ha_rpc::{Config, ProxyService};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tower::ServiceExt;
+
+/// An upstream answering every call with slot 300, and its URL.
+async fn upstream() -> String {
+    let router = Router::new().route(
+        "/",
+        post(|Json(call): Json<Value>| async move {
+            Json(json!({ "jsonrpc": "2.0", "id": call["id"], "result": 300 }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+async fn service() -> ProxyService {
+    let mut upstreams = String::new();
+    for name in ["a", "b"] {
+        let url = upstream().await;
+        upstreams += &format!("[[upstreams]]\nname = \"{}\"\nurl = \"{}\"\n", name, url);
+    }
+    ProxyService::new(Config::parse(&upstreams).unwrap())
+}
+
+fn get_slot() -> Value {
+    json!({ "jsonrpc": "2.0", "id": 1, "method": "getSlot" })
+}
+
+#[tokio::test]
+async fn an_embedded_proxy_serves_next_to_its_host_routes() {
+    let service = service().await;
+    service.start().await;
+    let app = service
+        .router()
+        .unwrap()
+        .route("/hello", get(|| async { "hello" }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let client = reqwest::Client::new();
+    let hello = client.get(format!("{}/hello", url)).send().await.unwrap();
+    assert_eq!(hello.text().await.unwrap(), "hello");
+    let answer: Value = client
+        .post(&url)
+        .json(&get_slot())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(answer["result"], 300, "{}", answer);
+}
+
+#[tokio::test]
+async fn the_router_answers_without_a_server() {
+    // the handlers want the client address a server would provide
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    let app = service()
+        .await
+        .router()
+        .unwrap()
+        .layer(MockConnectInfo(peer));
+    let request = Request::post("/")
+        .header("content-type", "application/json")
+        .body(Body::from(get_slot().to_string()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let answer: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(answer["id"], 1);
+    assert_eq!(answer["result"], 300);
+}
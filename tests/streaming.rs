@@ -0,0 +1,89 @@
+//! The winner's answer reaches the client as it arrives from the upstream.
+
+use axum::body::{Body, Bytes};
+use axum::routing::post;
+use axum::Router;
+use ha_rpc::{Config, ProxyService};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Before the upstream starts its answer.
+const FIRST_BYTE: Duration = Duration::from_millis(200);
+/// Between the start of the answer and its end.
+const REST: Duration = Duration::from_millis(1500);
+
+/// An upstream answering with a list of accounts it takes its time to
+/// stream, and its URL.
+async fn upstream() -> String {
+    let router = Router::new().route(
+        "/",
+        post(|| async {
+            tokio::time::sleep(FIRST_BYTE).await;
+            let chunks = futures::stream::unfold(0, |part| async move {
+                let chunk = match part {
+                    0 => r#"{"jsonrpc":"2.0","id":1,"result":["#.to_string(),
+                    1 => {
+                        tokio::time::sleep(REST).await;
+                        format!(r#""{}"]}}"#, "a".repeat(64 * 1024))
+                    }
+                    _ => return None,
+                };
+                Some((Ok::<_, Infallible>(Bytes::from(chunk)), part + 1))
+            });
+            Body::from_stream(chunks)
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+async fn proxy(upstream: &str) -> String {
+    let config = format!("[[upstreams]]\nurl = \"{}\"\n", upstream);
+    let app = ProxyService::new(Config::parse(&config).unwrap())
+        .router()
+        .unwrap()
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+/// The time to the first byte of the answer to a call to `url`, and the
+/// whole answer.
+async fn first_byte(url: &str) -> (Duration, Value) {
+    let call = json!({ "jsonrpc": "2.0", "id": 1, "method": "getProgramAccounts", "params": ["11111111111111111111111111111111"] });
+    let started = Instant::now();
+    let mut response = reqwest::Client::new()
+        .post(url)
+        .json(&call)
+        .send()
+        .await
+        .unwrap();
+    let mut body = response.chunk().await.unwrap().unwrap().to_vec();
+    let elapsed = started.elapsed();
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        body.extend_from_slice(&chunk);
+    }
+    (elapsed, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn the_first_byte_comes_as_soon_as_the_upstreams() {
+    let upstream = upstream().await;
+    let proxy = proxy(&upstream).await;
+    let (direct, _) = first_byte(&upstream).await;
+    let (proxied, answer) = first_byte(&proxy).await;
+    assert_eq!(answer["result"][0].as_str().unwrap().len(), 64 * 1024);
+    // nowhere near the time the whole body takes
+    assert!(
+        proxied < direct + Duration::from_millis(300),
+        "{:?} against {:?} from the upstream",
+        proxied,
+        direct
+    );
+}